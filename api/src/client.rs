@@ -0,0 +1,89 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Blocking JSON/HTTP client for our REST APIs, for the wallet and other
+//! command line tools talking to a node or to each other.
+
+use std::str::FromStr;
+
+use futures::{Future, Stream};
+use hyper::{self, Method, StatusCode, Uri};
+use hyper::client::Request;
+use hyper::header::ContentType;
+use serde::{Serialize, Deserialize};
+use serde_json;
+use tokio_core::reactor::Core;
+
+use rest::ApiError;
+
+/// Issues a GET request against the provided URL and parses the JSON object
+/// it returns.
+pub fn get<T>(url: &str) -> Result<T, ApiError>
+	where T: Deserialize
+{
+	let req = Request::new(Method::Get, parse_uri(url)?);
+	let body = send(req)?;
+	serde_json::from_str(&body).map_err(|e| ApiError::Internal(format!("invalid response: {}", e)))
+}
+
+/// Posts the provided object as JSON to the URL, returning the body of the
+/// response as is.
+pub fn post<IN>(url: &str, input: &IN) -> Result<String, ApiError>
+	where IN: Serialize
+{
+	let json = serde_json::to_string(input).map_err(|e| ApiError::Argument(e.to_string()))?;
+	let mut req = Request::new(Method::Post, parse_uri(url)?);
+	req.headers_mut().set(ContentType::json());
+	req.set_body(json);
+	send(req)
+}
+
+/// Posts the provided object as JSON to the URL and parses the JSON object
+/// returned.
+pub fn post_json<IN, OUT>(url: &str, input: &IN) -> Result<OUT, ApiError>
+	where IN: Serialize,
+	      OUT: Deserialize
+{
+	let body = post(url, input)?;
+	serde_json::from_str(&body).map_err(|e| ApiError::Internal(format!("invalid response: {}", e)))
+}
+
+fn parse_uri(url: &str) -> Result<Uri, ApiError> {
+	Uri::from_str(url).map_err(|e| ApiError::Argument(format!("invalid url {}: {}", url, e)))
+}
+
+// Runs the request to completion on its own event loop, failing on anything
+// but a success status.
+fn send(req: Request) -> Result<String, ApiError> {
+	let mut evtlp = Core::new().map_err(|e| ApiError::Internal(e.to_string()))?;
+	let handle = evtlp.handle();
+	let client = hyper::Client::new(&handle);
+
+	let work = client.request(req).and_then(|res| {
+		let status = res.status();
+		res.body().collect().map(move |chunks| {
+			let body = chunks.iter().fold(String::new(), |acc, chunk| {
+				acc + &String::from_utf8_lossy(&chunk[..])
+			});
+			(status, body)
+		})
+	});
+	let (status, body) = evtlp.run(work).map_err(|e| ApiError::Internal(e.to_string()))?;
+	match status {
+		StatusCode::NotFound => Err(ApiError::NotFound(body)),
+		StatusCode::BadRequest => Err(ApiError::Argument(body)),
+		s if s.is_success() => Ok(body),
+		s => Err(ApiError::Internal(format!("request failed with {}: {}", s, body))),
+	}
+}
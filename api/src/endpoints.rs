@@ -21,14 +21,28 @@
 //   }
 // }
 
-use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
 
+use iron::{Request, Response, IronResult, IronError, status};
 use iron::method::Method;
+use iron::middleware::Handler;
+use router::Router;
+use url::percent_encoding::percent_decode;
 
 use chain::{self, Tip};
+use core::core::{Output, Transaction, COINBASE_OUTPUT};
+use core::ser;
+use p2p;
+use pool;
 use rest::*;
+use secp::constants::PEDERSEN_COMMITMENT_SIZE;
+use secp::pedersen::Commitment;
+use serde_json;
+use util;
 
 /// ApiEndpoint implementation for the blockchain. Exposes the current chain
 /// state as a simple JSON object.
@@ -51,15 +65,488 @@ impl ApiEndpoint for ChainApi {
 	}
 }
 
+/// A peer we're connected to, as listed by the peer endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerView {
+	pub id: usize,
+	pub addr: String,
+	pub direction: String,
+	pub version: u32,
+	pub user_agent: String,
+	pub total_difficulty: String,
+	pub height: Option<u64>,
+	pub latency_ms: Option<u64>,
+	pub sent_bytes: u64,
+	pub received_bytes: u64,
+	pub sent_msgs: u64,
+	pub received_msgs: u64,
+	pub uptime_secs: u64,
+}
+
+impl PeerView {
+	fn from_stats(s: &p2p::PeerStats) -> PeerView {
+		let direction = match s.direction {
+			p2p::Direction::Inbound => "inbound",
+			p2p::Direction::Outbound => "outbound",
+		};
+		PeerView {
+			id: s.id.0,
+			addr: peer_addr_string(&s.addr),
+			direction: direction.to_string(),
+			version: s.version,
+			user_agent: s.user_agent.clone(),
+			total_difficulty: s.total_difficulty.to_string(),
+			height: s.height,
+			latency_ms: s.latency.map(|d| millis(&d)),
+			sent_bytes: s.sent_bytes,
+			received_bytes: s.received_bytes,
+			sent_msgs: s.sent_msgs,
+			received_msgs: s.received_msgs,
+			uptime_secs: s.uptime_secs,
+		}
+	}
+}
+
+/// A host kept away, as listed by the banned peers endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BanView {
+	pub ip: String,
+	pub severity: String,
+	pub reason: String,
+	pub since_secs: u64,
+	/// Never expires when not set.
+	pub expires_in_secs: Option<u64>,
+}
+
+impl BanView {
+	fn from_entry(b: &p2p::BanEntry) -> BanView {
+		let severity = match b.severity {
+			p2p::Severity::Ban => "ban",
+			p2p::Severity::Quarantine => "quarantine",
+		};
+		BanView {
+			ip: b.ip.to_string(),
+			severity: severity.to_string(),
+			reason: b.reason.clone(),
+			since_secs: b.since.as_secs(),
+			expires_in_secs: b.expires_in.map(|d| d.as_secs()),
+		}
+	}
+}
+
+/// State of our node, as returned by the status endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusView {
+	pub height: u64,
+	pub total_difficulty: String,
+	pub peer_count: u32,
+	/// One of synced, syncing or no_peers.
+	pub sync_status: String,
+	/// Rough fraction of the chain we have, from 0 to 1.
+	pub sync_progress: f64,
+	/// Whether many peers had their clock off ours the same way, pointing at
+	/// our own clock.
+	pub clock_suspect: bool,
+}
+
+/// An output of our chain, as returned by the utxo endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputView {
+	/// Hex of the output commitment.
+	pub commit: String,
+	pub coinbase: bool,
+}
+
+impl OutputView {
+	fn from_output(out: &Output) -> OutputView {
+		OutputView {
+			commit: util::to_hex(out.commit.bytes()),
+			coinbase: out.features.contains(COINBASE_OUTPUT),
+		}
+	}
+}
+
+/// A transaction in its binary serialization, hex encoded, as the pool push
+/// endpoint and the wallets exchanging transactions take them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxWrapper {
+	pub tx_hex: String,
+}
+
+impl TxWrapper {
+	/// Wraps the provided transaction.
+	pub fn new(tx: &Transaction) -> TxWrapper {
+		TxWrapper { tx_hex: util::to_hex(&ser::ser_vec(tx).unwrap()) }
+	}
+
+	/// The wrapped transaction, if it decodes.
+	pub fn tx(&self) -> ApiResult<Transaction> {
+		let bin = util::from_hex(&self.tx_hex).map_err(ApiError::Argument)?;
+		ser::deserialize(&mut &bin[..])
+			.map_err(|e| ApiError::Argument(format!("invalid transaction: {:?}", e)))
+	}
+}
+
+// Lists the peers we're connected to, at GET /v1/peers/connected.
+struct ConnectedPeersHandler {
+	p2p: Arc<p2p::Server>,
+}
+
+impl Handler for ConnectedPeersHandler {
+	fn handle(&self, _: &mut Request) -> IronResult<Response> {
+		let peers = self.p2p.peer_stats().iter().map(PeerView::from_stats).collect::<Vec<_>>();
+		json_response(&peers)
+	}
+}
+
+// Lists the banned and quarantined hosts, at GET /v1/peers/banned.
+struct BannedPeersHandler {
+	p2p: Arc<p2p::Server>,
+}
+
+impl Handler for BannedPeersHandler {
+	fn handle(&self, _: &mut Request) -> IronResult<Response> {
+		let bans = self.p2p.list_bans().iter().map(BanView::from_entry).collect::<Vec<_>>();
+		json_response(&bans)
+	}
+}
+
+// A single connected peer, at GET /v1/peers/:addr.
+struct PeerHandler {
+	p2p: Arc<p2p::Server>,
+}
+
+impl Handler for PeerHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let addr = peer_addr_param(req)?;
+		match self.p2p.peer_info(&addr) {
+			Some(stats) => json_response(&PeerView::from_stats(&stats)),
+			None => Err(ApiError::NotFound(format!("not connected to {}", addr)).into()),
+		}
+	}
+}
+
+// Bans the peer at POST /v1/peers/:addr/ban and lifts the ban or quarantine
+// of its host at POST /v1/peers/:addr/unban.
+struct BanHandler {
+	p2p: Arc<p2p::Server>,
+	ban: bool,
+}
+
+impl Handler for BanHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let addr = peer_addr_param(req)?;
+		let done = if self.ban {
+			self.p2p.ban_peer(addr, p2p::Severity::Ban, "API request")
+		} else {
+			self.p2p.unban(addr.ip())
+		};
+		// a host we're not connected to gets banned all the same
+		if done || self.ban {
+			Ok(Response::with(status::NoContent))
+		} else {
+			Err(ApiError::NotFound(format!("{} not banned", addr.ip())).into())
+		}
+	}
+}
+
+// Height, total difficulty, peers and sync state of our node, at GET
+// /v1/status.
+struct StatusHandler {
+	chain_store: Arc<chain::ChainStore>,
+	p2p: Arc<p2p::Server>,
+}
+
+impl Handler for StatusHandler {
+	fn handle(&self, _: &mut Request) -> IronResult<Response> {
+		let head = self.chain_store.head().map_err(|e| ApiError::Internal(e.to_string()))?;
+		let sync = self.p2p.sync_status();
+		let sync_status = match sync {
+			p2p::SyncStatus::Synced => "synced",
+			p2p::SyncStatus::Syncing { .. } => "syncing",
+			p2p::SyncStatus::NoPeers => "no_peers",
+		};
+		json_response(&StatusView {
+			height: head.height,
+			total_difficulty: head.total_difficulty.to_string(),
+			peer_count: self.p2p.peer_count(),
+			sync_status: sync_status.to_string(),
+			sync_progress: sync.progress(),
+			clock_suspect: self.p2p.clock_suspect(),
+		})
+	}
+}
+
+// An unspent output of our chain by its hex commitment, at GET
+// /v1/chain/utxos/:commit.
+struct UtxoHandler {
+	chain_store: Arc<chain::ChainStore>,
+}
+
+impl Handler for UtxoHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let param = url_param(req, "commit")?;
+		let bin = util::from_hex(&param).map_err(ApiError::Argument)?;
+		if bin.len() != PEDERSEN_COMMITMENT_SIZE {
+			return Err(ApiError::Argument(format!("invalid commitment {}", param)).into());
+		}
+		let mut commit = [0; PEDERSEN_COMMITMENT_SIZE];
+		commit.copy_from_slice(&bin[..]);
+		let commit = Commitment(commit);
+		let unspent = self.chain_store
+			.is_unspent(&commit)
+			.map_err(|e| ApiError::Internal(e.to_string()))?;
+		match self.chain_store.get_output_by_commit(&commit) {
+			Ok(ref out) if unspent => json_response(&OutputView::from_output(out)),
+			_ => Err(ApiError::NotFound(format!("no unspent output {}", param)).into()),
+		}
+	}
+}
+
+// Adds a transaction to our pool and relays it when the pool takes it, at
+// POST /v1/pool/push with a TxWrapper body. Relayed along the dandelion stem
+// if configured, unless broadcast right away with POST /v1/pool/push?fluff,
+// kept in the stem pool until then.
+struct PoolPushHandler<T> {
+	tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
+	p2p: Arc<p2p::Server>,
+}
+
+impl<T> Handler for PoolPushHandler<T>
+	where T: pool::BlockChain + 'static
+{
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let wrapper: TxWrapper = serde_json::from_reader(req.body.by_ref())
+			.map_err(|e| IronError::new(e, status::BadRequest))?;
+		let tx = wrapper.tx()?;
+		let fluff = req.url.query().map(|q| q.split('&').any(|p| p == "fluff")).unwrap_or(false);
+		let res = {
+			let mut tx_pool = self.tx_pool.write().unwrap();
+			if fluff {
+				tx_pool.add_to_memory_pool(tx.clone())
+			} else {
+				tx_pool.add_to_stempool(tx.clone())
+			}
+		};
+		match res {
+			Ok(_) => {
+				if fluff {
+					self.p2p.fluff_transaction(&tx);
+				} else {
+					self.p2p.stem_transaction(&tx);
+				}
+				Ok(Response::with(status::NoContent))
+			}
+			Err(e) => Err(ApiError::Argument(format!("refused by the pool: {:?}", e)).into()),
+		}
+	}
+}
+
+// Parameter of the URL of the request, percent-decoded.
+fn url_param(req: &mut Request, name: &str) -> IronResult<String> {
+	let param = req.extensions.get::<Router>().unwrap().find(name).unwrap_or("");
+	match percent_decode(param.as_bytes()).decode_utf8() {
+		Ok(decoded) => Ok(decoded.into_owned()),
+		Err(e) => Err(ApiError::Argument(format!("invalid {} {}: {}", name, param, e)).into()),
+	}
+}
+
+// Address of the peer in the URL of the request, IPv6 brackets and onion
+// services included.
+fn peer_addr_param(req: &mut Request) -> IronResult<SocketAddr> {
+	let param = url_param(req, "addr")?;
+	match p2p::parse_peer_addr(&param) {
+		Some(addr) => Ok(addr),
+		None => Err(IronError::from(ApiError::Argument(format!("invalid peer address {}", param)))),
+	}
+}
+
+// Address of a peer as shown, by name for onion services.
+fn peer_addr_string(addr: &SocketAddr) -> String {
+	match p2p::onion_host(addr) {
+		Some(host) => format!("{}:{}", host, addr.port()),
+		None => addr.to_string(),
+	}
+}
+
+fn millis(d: &Duration) -> u64 {
+	d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+// Registers the handlers listing and managing our peers under /peers.
+fn register_peer_handlers(apis: &mut ApiServer, p2p_server: Arc<p2p::Server>) {
+	apis.register_handler(Method::Get,
+	                      "/peers/connected",
+	                      ConnectedPeersHandler { p2p: p2p_server.clone() });
+	apis.register_handler(Method::Get,
+	                      "/peers/banned",
+	                      BannedPeersHandler { p2p: p2p_server.clone() });
+	apis.register_handler(Method::Get, "/peers/:addr", PeerHandler { p2p: p2p_server.clone() });
+	apis.register_handler(Method::Post,
+	                      "/peers/:addr/ban",
+	                      BanHandler {
+		                      p2p: p2p_server.clone(),
+		                      ban: true,
+	                      });
+	apis.register_handler(Method::Post,
+	                      "/peers/:addr/unban",
+	                      BanHandler {
+		                      p2p: p2p_server,
+		                      ban: false,
+	                      });
+}
+
 /// Start all server REST APIs. Just register all of them on a ApiServer
 /// instance and runs the corresponding HTTP server.
-pub fn start_rest_apis(addr: String, chain_store: Arc<chain::ChainStore>) {
+pub fn start_rest_apis<T>(addr: String,
+                          chain_store: Arc<chain::ChainStore>,
+                          p2p_server: Arc<p2p::Server>,
+                          tx_pool: Arc<RwLock<pool::TransactionPool<T>>>)
+	where T: pool::BlockChain + 'static
+{
 
 	thread::spawn(move || {
 		let mut apis = ApiServer::new("/v1".to_string());
-		apis.register_endpoint("/chain".to_string(), ChainApi { chain_store: chain_store });
+		apis.register_endpoint("/chain".to_string(), ChainApi { chain_store: chain_store.clone() });
+		apis.register_handler(Method::Get,
+		                      "/chain/utxos/:commit",
+		                      UtxoHandler { chain_store: chain_store.clone() });
+		apis.register_handler(Method::Post,
+		                      "/pool/push",
+		                      PoolPushHandler {
+			                      tx_pool: tx_pool,
+			                      p2p: p2p_server.clone(),
+		                      });
+		register_peer_handlers(&mut apis, p2p_server.clone());
+		apis.register_handler(Method::Get,
+		                      "/status",
+		                      StatusHandler {
+			                      chain_store: chain_store,
+			                      p2p: p2p_server,
+		                      });
 		apis.start(&addr[..]).unwrap_or_else(|e| {
 			error!("Failed to start API HTTP server: {}.", e);
 		});
 	});
 }
+
+#[cfg(test)]
+mod test {
+	use std::net::{SocketAddr, TcpStream};
+	use std::sync::Arc;
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use futures::{future, Future};
+	use tokio_core::reactor::Core;
+
+	use client;
+	use p2p;
+	use rest::*;
+	use super::*;
+
+	// Waits a couple seconds at most for the condition to hold.
+	fn wait_until<F: Fn() -> bool>(cond: F) -> bool {
+		let start = Instant::now();
+		while !cond() {
+			if start.elapsed() > Duration::from_secs(2) {
+				return false;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		true
+	}
+
+	// Serves the peer handlers at the provided port, for the provided p2p
+	// server, once it accepts connections.
+	fn serve_peers(port: u16, p2p_server: Arc<p2p::Server>) -> String {
+		let mut apis = ApiServer::new("/v1".to_string());
+		register_peer_handlers(&mut apis, p2p_server);
+		let addr = format!("127.0.0.1:{}", port);
+		let listen = addr.clone();
+		thread::spawn(move || apis.start(&listen[..]));
+		assert!(wait_until(|| TcpStream::connect(&addr[..]).is_ok()));
+		format!("http://{}/v1/peers", addr)
+	}
+
+	// A p2p server with the provided port running on its own event loop.
+	fn run_p2p(port: u16) -> Arc<p2p::Server> {
+		let config = p2p::P2PConfig { port: port, ..p2p::P2PConfig::default() };
+		let server = Arc::new(p2p::Server::new(p2p::UNKNOWN,
+		                                       config,
+		                                       Arc::new(p2p::DummyAdapter {})));
+		let running = server.clone();
+		thread::spawn(move || {
+			let mut evtlp = Core::new().unwrap();
+			let handle = evtlp.handle();
+			evtlp.run(running.start(handle)).unwrap();
+		});
+		server
+	}
+
+	#[test]
+	fn connected_peer_listed() {
+		let server = run_p2p(13795);
+		let url = serve_peers(13797, server.clone());
+
+		// another node connects to ours and keeps the connection going
+		let config = p2p::P2PConfig { port: 13796, ..p2p::P2PConfig::default() };
+		let client = p2p::Server::new(p2p::UNKNOWN, config, Arc::new(p2p::DummyAdapter {}));
+		thread::spawn(move || {
+			let mut evtlp = Core::new().unwrap();
+			let handle = evtlp.handle();
+			let addr = "127.0.0.1:13795".parse::<SocketAddr>().unwrap();
+			let connected = client.connect_peer(addr, handle);
+			evtlp.run(connected.and_then(|_| future::empty::<(), p2p::Error>())).unwrap();
+		});
+		assert!(wait_until(|| server.peer_count() == 1));
+
+		let peers = client::get::<Vec<PeerView>>(&format!("{}/connected", url)).unwrap();
+		assert_eq!(peers.len(), 1);
+		assert_eq!(peers[0].addr, "127.0.0.1:13796");
+		assert_eq!(peers[0].direction, "inbound");
+		let peer = client::get::<PeerView>(&format!("{}/127.0.0.1:13796", url)).unwrap();
+		assert_eq!(peer.id, peers[0].id);
+	}
+
+	#[test]
+	fn peer_addresses_decoded() {
+		let server = Arc::new(p2p::Server::new(p2p::UNKNOWN,
+		                                       p2p::P2PConfig::default(),
+		                                       Arc::new(p2p::DummyAdapter {})));
+		let url = serve_peers(13798, server);
+
+		assert!(client::get::<Vec<PeerView>>(&format!("{}/connected", url)).unwrap().is_empty());
+		// an encoded IPv6 address is a fine one, just not of a peer of ours
+		match client::get::<PeerView>(&format!("{}/%5B%3A%3A1%5D%3A13414", url)) {
+			Err(ApiError::NotFound(_)) => {}
+			res => panic!("expected an unknown peer, got {:?}", res.map(|p| p.addr)),
+		}
+		match client::get::<PeerView>(&format!("{}/not-an-address", url)) {
+			Err(ApiError::Argument(_)) => {}
+			res => panic!("expected an invalid address, got {:?}", res.map(|p| p.addr)),
+		}
+	}
+
+	#[test]
+	fn ban_lifted() {
+		let server = Arc::new(p2p::Server::new(p2p::UNKNOWN,
+		                                       p2p::P2PConfig::default(),
+		                                       Arc::new(p2p::DummyAdapter {})));
+		let url = serve_peers(13799, server.clone());
+
+		// hosts we're not connected to get banned too
+		client::post(&format!("{}/10.0.0.1:13414/ban", url), &()).unwrap();
+		let bans = client::get::<Vec<BanView>>(&format!("{}/banned", url)).unwrap();
+		assert_eq!(bans.len(), 1);
+		assert_eq!((&bans[0].ip[..], &bans[0].severity[..]), ("10.0.0.1", "ban"));
+		assert!(server.is_banned(&"10.0.0.1:13414".parse().unwrap()));
+
+		client::post(&format!("{}/10.0.0.1:13414/unban", url), &()).unwrap();
+		assert!(client::get::<Vec<BanView>>(&format!("{}/banned", url)).unwrap().is_empty());
+		match client::post(&format!("{}/10.0.0.1:13414/unban", url), &()) {
+			Err(ApiError::NotFound(_)) => {}
+			res => panic!("expected no ban to lift, got {:?}", res),
+		}
+	}
+}
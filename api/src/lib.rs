@@ -13,7 +13,14 @@
 // limitations under the License.
 
 extern crate grin_chain as chain;
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate grin_pool as pool;
+extern crate grin_util as util;
+extern crate secp256k1zkp as secp;
 
+extern crate futures;
+extern crate hyper;
 #[macro_use]
 extern crate log;
 extern crate iron;
@@ -22,8 +29,12 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate tokio_core;
+extern crate url;
 
+pub mod client;
 mod endpoints;
 mod rest;
 
-pub use endpoints::start_rest_apis;
+pub use endpoints::{start_rest_apis, OutputView, TxWrapper};
+pub use rest::{ApiServer, ApiError, ApiResult, json_response};
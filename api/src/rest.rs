@@ -39,6 +39,7 @@ use serde_json;
 pub enum ApiError {
 	Internal(String),
 	Argument(String),
+	NotFound(String),
 }
 
 impl Display for ApiError {
@@ -46,6 +47,7 @@ impl Display for ApiError {
 		match *self {
 			ApiError::Argument(ref s) => write!(f, "Bad arguments: {}", s),
 			ApiError::Internal(ref s) => write!(f, "Internal error: {}", s),
+			ApiError::NotFound(ref s) => write!(f, "Not found: {}", s),
 		}
 	}
 }
@@ -55,6 +57,7 @@ impl Error for ApiError {
 		match *self {
 			ApiError::Argument(_) => "Bad arguments.",
 			ApiError::Internal(_) => "Internal error.",
+			ApiError::NotFound(_) => "Not found.",
 		}
 	}
 }
@@ -64,6 +67,7 @@ impl From<ApiError> for IronError {
 		match e {
 			ApiError::Argument(_) => IronError::new(e, status::Status::BadRequest),
 			ApiError::Internal(_) => IronError::new(e, status::Status::InternalServerError),
+			ApiError::NotFound(_) => IronError::new(e, status::Status::NotFound),
 		}
 	}
 }
@@ -125,9 +129,7 @@ impl<E> Handler for ApiWrapper<E>
 		match req.method {
 			Method::Get => {
 				let res = self.0.get(extract_param(req, "id")?)?;
-				let res_json = serde_json::to_string(&res)
-          .map_err(|e| IronError::new(e, status::InternalServerError))?;
-				Ok(Response::with((status::Ok, res_json)))
+				json_response(&res)
 			}
 			Method::Put => {
 				let id = extract_param(req, "id")?;
@@ -152,6 +154,13 @@ impl<E> Handler for ApiWrapper<E>
 	}
 }
 
+/// Response with the provided object serialized as its JSON body.
+pub fn json_response<T: Serialize>(o: &T) -> IronResult<Response> {
+	let json = serde_json::to_string(o)
+		.map_err(|e| IronError::new(e, status::InternalServerError))?;
+	Ok(Response::with((status::Ok, json)))
+}
+
 fn extract_param<ID>(req: &mut Request, param: &'static str) -> IronResult<ID>
 	where ID: ToString + FromStr,
 	      <ID as FromStr>::Err: Debug + Send + Error + 'static
@@ -182,6 +191,14 @@ impl ApiServer {
 		Iron::new(self.router).http(addr).map(|_| ()).map_err(|e| e.to_string())
 	}
 
+	/// Registers a handler for a single method on a relative URL, for what
+	/// doesn't fit an ApiEndpoint like listings or actions on a resource.
+	pub fn register_handler<H: Handler>(&mut self, method: Method, subpath: &str, handler: H) {
+		assert_eq!(subpath.chars().nth(0).unwrap(), '/');
+		let route_id = method.to_string() + "_" + &subpath[1..];
+		self.router.route(method, self.root.clone() + subpath, handler, route_id);
+	}
+
 	/// Register a new API endpoint, providing a relative URL for the new
 	/// endpoint.
 	pub fn register_endpoint<E>(&mut self, subpath: String, endpoint: E)
@@ -237,6 +254,11 @@ impl ApiServer {
 
 #[cfg(test)]
 mod test {
+	use std::net::TcpStream;
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use client;
 	use super::*;
 	use rest::*;
 
@@ -272,4 +294,25 @@ mod test {
 		let mut apis = ApiServer::new("/v1".to_string());
 		apis.register_endpoint("/animal".to_string(), TestApi);
 	}
+
+	#[test]
+	fn handler_registered() {
+		let mut apis = ApiServer::new("/v1".to_string());
+		apis.register_endpoint("/animal".to_string(), TestApi);
+		apis.register_handler(Method::Get, "/animal/all", |_: &mut Request| {
+			json_response(&vec![TestApi.get("cat".to_string()).unwrap()])
+		});
+		thread::spawn(move || apis.start("127.0.0.1:13800"));
+		let start = Instant::now();
+		while TcpStream::connect("127.0.0.1:13800").is_err() {
+			assert!(start.elapsed() < Duration::from_secs(2));
+			thread::sleep(Duration::from_millis(10));
+		}
+
+		// the handler takes precedence over the endpoint getting "all"
+		let all = client::get::<Vec<Animal>>("http://127.0.0.1:13800/v1/animal/all").unwrap();
+		assert_eq!(all.iter().map(|a| &a.name[..]).collect::<Vec<_>>(), vec!["cat"]);
+		let dog = client::get::<Animal>("http://127.0.0.1:13800/v1/animal/dog").unwrap();
+		assert_eq!((&dog.name[..], dog.legs), ("dog", 4));
+	}
 }
@@ -34,6 +34,7 @@ extern crate grin_core as core;
 extern crate grin_store;
 extern crate secp256k1zkp as secp;
 
+pub mod orphan;
 pub mod pipe;
 pub mod store;
 pub mod types;
@@ -41,4 +42,6 @@ pub mod types;
 // Re-export the base interface
 
 pub use types::{ChainStore, Tip, ChainAdapter};
-pub use pipe::{SYNC, NONE, process_block, process_block_header, Error};
+pub use orphan::{Orphan, OrphanPool};
+pub use pipe::{SYNC, NONE, process_block, process_block_header, process_utxo_snapshot,
+               rebuild_utxos, utxo_root, utxo_snapshot, Error};
@@ -0,0 +1,134 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Blocks received ahead of their parent, kept around until the parent shows
+//! up so they don't have to be downloaded again. Those pushed to us and those
+//! downloaded out of order while syncing alike.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use core::core::Block;
+use core::core::hash::{Hash, Hashed};
+
+/// A block whose parent we don't have, along with the peer that sent it.
+pub struct Orphan {
+	/// The block itself
+	pub block: Block,
+	/// Address of the peer the block came from
+	pub src: SocketAddr,
+	/// When the block got added to the pool
+	pub added: Instant,
+}
+
+/// Pool of orphan blocks, bounded by count and age. Once full, the oldest
+/// orphan makes room for a new one.
+pub struct OrphanPool {
+	orphans: HashMap<Hash, Orphan>,
+	max_count: usize,
+	max_age: Duration,
+}
+
+impl OrphanPool {
+	/// Creates an empty pool holding at most max_count blocks, none of them
+	/// longer than max_age.
+	pub fn new(max_count: usize, max_age: Duration) -> OrphanPool {
+		OrphanPool {
+			orphans: HashMap::new(),
+			max_count: max_count,
+			max_age: max_age,
+		}
+	}
+
+	/// Adds a block received from the provided peer at the provided time,
+	/// returns false if the pool already had it.
+	pub fn add(&mut self, b: Block, src: SocketAddr, now: Instant) -> bool {
+		let h = b.hash();
+		if self.orphans.contains_key(&h) {
+			return false;
+		}
+		self.prune(now);
+		if self.max_count == 0 {
+			return false;
+		}
+		while self.orphans.len() >= self.max_count {
+			let oldest = self.orphans
+				.iter()
+				.min_by_key(|&(_, o)| o.added)
+				.map(|(h, _)| *h)
+				.unwrap();
+			self.orphans.remove(&oldest);
+		}
+		self.orphans.insert(h,
+		                    Orphan {
+			                    block: b,
+			                    src: src,
+			                    added: now,
+		                    });
+		true
+	}
+
+	/// Whether the pool holds the block with the provided hash.
+	pub fn contains(&self, h: &Hash) -> bool {
+		self.orphans.contains_key(h)
+	}
+
+	/// Number of orphans in the pool.
+	pub fn len(&self) -> usize {
+		self.orphans.len()
+	}
+
+	/// Removes and returns the orphans built on the provided block, oldest
+	/// first.
+	pub fn take_children(&mut self, parent: &Hash) -> Vec<Orphan> {
+		let hs = self.orphans
+			.iter()
+			.filter(|&(_, o)| o.block.header.previous == *parent)
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		let mut children = hs.iter().filter_map(|h| self.orphans.remove(h)).collect::<Vec<_>>();
+		children.sort_by_key(|o| o.added);
+		children
+	}
+
+	/// The first block up the ancestry of the provided one that isn't in the
+	/// pool, the block we're actually missing to connect them all.
+	pub fn missing_ancestor(&self, h: Hash) -> Hash {
+		let mut missing = h;
+		// bounded by the pool size should the orphans form a cycle
+		for _ in 0..(self.orphans.len() + 1) {
+			match self.orphans.get(&missing) {
+				Some(o) => missing = o.block.header.previous,
+				None => break,
+			}
+		}
+		missing
+	}
+
+	/// Drops the orphans older than the maximum age at the provided time,
+	/// returns how many were dropped.
+	pub fn prune(&mut self, now: Instant) -> usize {
+		let max_age = self.max_age;
+		let expired = self.orphans
+			.iter()
+			.filter(|&(_, o)| o.added + max_age <= now)
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		for h in &expired {
+			self.orphans.remove(h);
+		}
+		expired.len()
+	}
+}
@@ -14,16 +14,19 @@
 
 //! Implementation of the chain block acceptance (or refusal) pipeline.
 
+use std::collections::HashMap;
 use std::convert::From;
+use std::iter;
 use std::sync::{Arc, Mutex};
 
 use secp;
+use secp::pedersen::Commitment;
 use time;
 
 use core::consensus;
-use core::core::hash::{Hash, Hashed};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
 use core::core::target::Difficulty;
-use core::core::{BlockHeader, Block, Proof};
+use core::core::{BlockHeader, Block, Output, Proof, merkle_utxos};
 use core::pow;
 use core::ser;
 use grin_store;
@@ -69,6 +72,19 @@ pub enum Error {
 	InvalidBlockTime,
 	/// Block height is invalid (not previous + 1)
 	InvalidBlockHeight,
+	/// An input of the block spends an output that isn't unspent on the
+	/// chain the block extends
+	MissingInput(Commitment),
+	/// An output of the block already is an unspent output of the chain the
+	/// block extends
+	DuplicateOutput(Commitment),
+	/// The unspent outputs after the block don't match the root its header
+	/// commits to
+	InvalidUtxoRoot,
+	/// The fork the block is on branches off our chain below the oldest full
+	/// block we have, as when we synced the unspent outputs at a horizon, so
+	/// our chain can't be rewound down to it
+	ForkBelowHorizon,
 	/// Internal issue when trying to save or load data from store
 	StoreErr(grin_store::Error),
 	SerErr(ser::Error),
@@ -120,7 +136,13 @@ pub fn process_block(b: &Block,
 	      b.hash());
 	try!(add_block(b, &mut ctx));
 	// TODO a global lock should be set before that step or even earlier
-	update_head(b, &mut ctx)
+	let tip = update_head(b, &mut ctx)?;
+
+	// broadcast the block once on our chain
+	if tip.is_some() {
+		ctx.adapter.block_accepted(b);
+	}
+	Ok(tip)
 }
 
 pub fn process_block_header(bh: &BlockHeader,
@@ -148,6 +170,106 @@ pub fn process_block_header(bh: &BlockHeader,
 	update_header_head(bh, &mut ctx)
 }
 
+/// Merkle root of the unspent outputs our chain would have with the provided
+/// block on top of our head, for the header of a block we build. Fails when
+/// the block doesn't fit on our head.
+pub fn utxo_root(b: &Block, store: Arc<ChainStore>) -> Result<Hash, Error> {
+	let mut utxos = UtxoChanges::new(store);
+	utxos.apply(b)?;
+	utxos.root()
+}
+
+/// Unspent outputs of our chain right after the block with the provided
+/// hash, for peers syncing from them. Rebuilt from our unspent outputs by
+/// undoing the blocks after it, so the block has to be on our chain and we
+/// need to have those blocks.
+pub fn utxo_snapshot(horizon: Hash, store: Arc<ChainStore>) -> Result<Vec<Output>, Error> {
+	let bh = store.get_block_header(&horizon)?;
+	if store.get_header_by_height(bh.height)?.hash() != horizon {
+		return Err(Error::Unfit("not on our chain".to_string()));
+	}
+	let mut utxos = UtxoChanges::new(store.clone());
+	let mut h = store.head()?.last_block_h;
+	while h != horizon {
+		let b = store.get_block(&h)?;
+		utxos.rewind(&b)?;
+		h = b.header.previous;
+	}
+	utxos.utxos()
+}
+
+/// Rebuilds the unspent outputs of our chain from its blocks when the store
+/// has none while our head is past the genesis, as with a store that
+/// predates them. Returns how many got rebuilt.
+pub fn rebuild_utxos(store: Arc<ChainStore>) -> Result<usize, Error> {
+	let head = store.head()?;
+	if head.height == 0 || store.has_utxos()? {
+		return Ok(0);
+	}
+	info!("Rebuilding our unspent outputs from the {} blocks of our chain.",
+	      head.height);
+	let mut utxos = UtxoChanges::new(store.clone());
+	for height in 1..head.height + 1 {
+		let bh = store.get_header_by_height(height)?;
+		utxos.apply(&store.get_block(&bh.hash())?)?;
+		if height == head.height && bh.hash() != head.last_block_h {
+			return Err(Error::Unfit("heights out of step with our head".to_string()));
+		}
+	}
+	let header = store.get_block_header(&head.last_block_h)?;
+	if header.utxo_merkle != ZERO_HASH && utxos.root()? != header.utxo_merkle {
+		return Err(Error::InvalidUtxoRoot);
+	}
+	utxos.save(&head)?;
+	Ok(utxos.changes.values().filter(|out| out.is_some()).count())
+}
+
+/// Installs the unspent outputs after the provided block header, downloaded
+/// from a peer, as our chain state when they match the root the header
+/// commits to. The block becomes our head and only the full blocks after it
+/// are needed from then on. Returns our new head.
+pub fn process_utxo_snapshot(bh: &BlockHeader,
+                             outputs: Vec<Output>,
+                             store: Arc<ChainStore>)
+                             -> Result<Tip, Error> {
+	// the header has to be on our header chain already
+	store.get_block_header(&bh.hash())?;
+	let head = store.head()?;
+	if bh.total_difficulty <= head.total_difficulty {
+		// unless our chain forked away below the horizon it synced at
+		let header_head = store.get_header_head()?.last_block_h;
+		if on_chain(&store.head_header()?, header_head, &store)? ||
+		   !on_chain(bh, header_head, &store)? {
+			return Err(Error::Unfit("not ahead of our head".to_string()));
+		}
+	}
+	if bh.utxo_merkle == ZERO_HASH || merkle_utxos(&outputs) != bh.utxo_merkle {
+		return Err(Error::InvalidUtxoRoot);
+	}
+	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	for out in &outputs {
+		out.verify_proof(&curve).map_err(&Error::InvalidBlockProof)?;
+	}
+
+	store.save_utxo_snapshot(bh, &outputs)?;
+	store.setup_height(bh)?;
+	info!("Synced {} unspent outputs at {}, new head at {}.",
+	      outputs.len(),
+	      bh.hash(),
+	      bh.height);
+	Ok(Tip::from_block(bh))
+}
+
+// Whether the block with the provided header is on the chain ending at the
+// block with the provided hash.
+fn on_chain(bh: &BlockHeader, tip: Hash, store: &Arc<ChainStore>) -> Result<bool, Error> {
+	let mut h = store.get_block_header(&tip)?;
+	while h.height > bh.height {
+		h = store.get_block_header(&h.previous)?;
+	}
+	Ok(h.hash() == bh.hash())
+}
+
 /// Quick in-memory check to fast-reject any block we've already handled
 /// recently. Keeps duplicates from the network in check.
 fn check_known(bh: Hash, ctx: &mut BlockContext) -> Result<(), Error> {
@@ -215,20 +337,121 @@ fn validate_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
 	try!(b.validate(&curve).map_err(&Error::InvalidBlockProof));
 
-	if !ctx.opts.intersects(SYNC) {
-		// TODO check every input exists as a UTXO using the UXTO index
+	// the inputs and outputs get checked against the unspent outputs of the
+	// branch the block extends, once our head moves there
+	Ok(())
+}
+
+/// Changes to the unspent outputs of the chain ending at our head, as they
+/// are once some blocks get rewound or applied. Kept in memory to check the
+/// blocks along the way against them, and only saved, along with our new
+/// head, once all fit.
+struct UtxoChanges {
+	store: Arc<ChainStore>,
+	// the outputs becoming unspent, or spent when None, by commitment
+	changes: HashMap<Commitment, Option<Output>>,
+}
+
+impl UtxoChanges {
+	fn new(store: Arc<ChainStore>) -> UtxoChanges {
+		UtxoChanges {
+			store: store,
+			changes: HashMap::new(),
+		}
+	}
+
+	/// Whether the output with the provided commitment is unspent with the
+	/// changes.
+	fn is_unspent(&self, c: &Commitment) -> Result<bool, Error> {
+		match self.changes.get(c) {
+			Some(out) => Ok(out.is_some()),
+			None => self.store.is_unspent(c).map_err(&Error::StoreErr),
+		}
+	}
+
+	/// Checks the inputs of the block spend unspent outputs and none of its
+	/// outputs duplicates one, then applies it, spending its inputs and
+	/// adding its outputs.
+	fn apply(&mut self, b: &Block) -> Result<(), Error> {
+		for input in &b.inputs {
+			let c = input.commitment();
+			let own = b.outputs.iter().any(|o| o.commit == c);
+			if !own && !self.is_unspent(&c)? {
+				return Err(Error::MissingInput(c));
+			}
+		}
+		for out in &b.outputs {
+			if self.is_unspent(&out.commit)? {
+				return Err(Error::DuplicateOutput(out.commit));
+			}
+		}
+		for out in &b.outputs {
+			self.changes.insert(out.commit, Some(out.clone()));
+		}
+		for input in &b.inputs {
+			self.changes.insert(input.commitment(), None);
+		}
+		Ok(())
+	}
+
+	/// Undoes the application of the block, removing its outputs and
+	/// restoring what its inputs spent.
+	fn rewind(&mut self, b: &Block) -> Result<(), Error> {
+		for out in &b.outputs {
+			self.changes.insert(out.commit, None);
+		}
+		for input in &b.inputs {
+			let c = input.commitment();
+			if !b.outputs.iter().any(|o| o.commit == c) {
+				let out = self.store.get_output_by_commit(&c)?;
+				self.changes.insert(c, Some(out));
+			}
+		}
+		Ok(())
+	}
+
+	/// Merkle root of the unspent outputs with the changes.
+	fn root(&self) -> Result<Hash, Error> {
+		self.store.utxo_root(&self.changes).map_err(&Error::StoreErr)
+	}
+
+	/// All the unspent outputs with the changes.
+	fn utxos(&self) -> Result<Vec<Output>, Error> {
+		let mut utxos = self.store
+			.get_utxos()?
+			.into_iter()
+			.filter(|out| !self.changes.contains_key(&out.commit))
+			.collect::<Vec<_>>();
+		utxos.extend(self.changes.values().filter_map(|out| out.clone()));
+		Ok(utxos)
+	}
+
+	/// Saves the changes, the provided tip becoming our head at once.
+	fn save(&self, head: &Tip) -> Result<(), Error> {
+		self.store.save_utxos(&self.changes, head).map_err(&Error::StoreErr)
+	}
+
+	/// Saves the changes, the block with the provided header becoming the
+	/// head of our chain and header chain at once.
+	fn save_head(&self, bh: &BlockHeader) -> Result<(), Error> {
+		self.store.save_block_head(bh, &self.changes).map_err(&Error::StoreErr)
+	}
+}
+
+/// Applies the block to the unspent outputs, once checked it fits. The
+/// unspent outputs after the block get checked against the root its header
+/// commits to, when it commits to one.
+fn apply_utxos(b: &Block, utxos: &mut UtxoChanges) -> Result<(), Error> {
+	utxos.apply(b)?;
+	if b.header.utxo_merkle != ZERO_HASH && utxos.root()? != b.header.utxo_merkle {
+		return Err(Error::InvalidUtxoRoot);
 	}
 	Ok(())
 }
 
 /// Officially adds the block to our chain.
 fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
-	ctx.store.save_block(b).map_err(&Error::StoreErr)?;
-
-	// broadcast the block
-	let adapter = ctx.adapter.clone();
-	adapter.block_accepted(b);
-	Ok(())
+	ctx.store.save_block(b).map_err(&Error::StoreErr)
 }
 
 /// Officially adds the block header to our header chain.
@@ -243,16 +466,77 @@ fn update_head(b: &Block, ctx: &mut BlockContext) -> Result<Option<Tip>, Error>
 	// if we made a fork with more work than the head (which should also be true
 	// when extending the head), update it
 	let tip = Tip::from_block(&b.header);
-	if tip.total_difficulty > ctx.head.total_difficulty {
-		ctx.store.setup_height(&b.header).map_err(&Error::StoreErr)?;
-		ctx.store.save_head(&tip).map_err(&Error::StoreErr)?;
-
-		ctx.head = tip.clone();
-		info!("Updated head to {} at {}.", b.hash(), b.header.height);
-		Ok(Some(tip))
+	if tip.total_difficulty <= ctx.head.total_difficulty {
+		return Ok(None);
+	}
+	// nothing gets saved until the block, and the fork it ends, fit
+	let mut utxos = UtxoChanges::new(ctx.store.clone());
+	let reorged = if b.header.previous == ctx.head.last_block_h {
+		apply_utxos(b, &mut utxos)?;
+		None
 	} else {
-		Ok(None)
+		Some(reorg(b, ctx, &mut utxos)?)
+	};
+	utxos.save_head(&b.header)?;
+
+	ctx.head = tip.clone();
+	info!("Updated head to {} at {}.", b.hash(), b.header.height);
+	if let Some((rewound, applied)) = reorged {
+		ctx.adapter.chain_reorged(&rewound, &applied);
+	}
+	Ok(Some(tip))
+}
+
+/// Moves the unspent outputs over to the fork the provided block ends,
+/// rewinding the blocks of our chain down to the fork point and applying
+/// those of the fork, all of which have to fit. Returns the blocks rewound
+/// and those applied before the provided one.
+fn reorg(b: &Block,
+         ctx: &mut BlockContext,
+         utxos: &mut UtxoChanges)
+         -> Result<(Vec<Block>, Vec<Block>), Error> {
+	let store = ctx.store.clone();
+
+	// walk back both branches until they meet
+	let mut old_hdr = store.get_block_header(&ctx.head.last_block_h)?;
+	let mut new_hdr = store.get_block_header(&b.header.previous)?;
+	let mut rewound_hs = vec![];
+	let mut applied_hs = vec![];
+	while old_hdr.hash() != new_hdr.hash() {
+		if old_hdr.height >= new_hdr.height {
+			rewound_hs.push(old_hdr.hash());
+			old_hdr = store.get_block_header(&old_hdr.previous)?;
+		} else {
+			applied_hs.push(new_hdr.hash());
+			new_hdr = store.get_block_header(&new_hdr.previous)?;
+		}
+	}
+	applied_hs.reverse();
+	// a pruned chain only has the full blocks after the horizon it synced at
+	let rewound = match rewound_hs.iter()
+		.map(|h| store.get_block(h))
+		.collect::<Result<Vec<_>, _>>() {
+		Ok(rewound) => rewound,
+		Err(grin_store::Error::NotFoundErr) => return Err(Error::ForkBelowHorizon),
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
+	let applied = applied_hs.iter().map(|h| store.get_block(h)).collect::<Result<Vec<_>, _>>()?;
+	info!("Reorg at {}, rewinding {} blocks and applying {} to reach {}.",
+	      old_hdr.height,
+	      rewound.len(),
+	      applied.len() + 1,
+	      b.hash());
+
+	for old in &rewound {
+		utxos.rewind(old)?;
+	}
+	for blk in applied.iter().chain(iter::once(b)) {
+		if let Err(e) = apply_utxos(blk, utxos) {
+			warn!("Fork block {} doesn't fit, keeping our chain: {:?}", blk.hash(), e);
+			return Err(e);
+		}
 	}
+	Ok((rewound, applied))
 }
 
 /// Directly updates the head if we've just appended a new block to it or handle
@@ -14,10 +14,14 @@
 
 //! Implements storage primitives required by the chain
 
+use std::collections::HashMap;
+use std::sync::Mutex;
+
 use types::*;
 use core::core::hash::{Hash, Hashed};
-use core::core::{Block, BlockHeader};
-use grin_store::{self, Error, to_key, u64_to_key, option_to_not_found};
+use core::core::{Block, BlockHeader, Output, UtxoTree};
+use secp::pedersen::Commitment;
+use grin_store::{self, Batch, Error, to_key, u64_to_key, option_to_not_found};
 
 const STORE_SUBPATH: &'static str = "chain";
 
@@ -26,17 +30,98 @@ const BLOCK_PREFIX: u8 = 'b' as u8;
 const HEAD_PREFIX: u8 = 'H' as u8;
 const HEADER_HEAD_PREFIX: u8 = 'I' as u8;
 const HEADER_HEIGHT_PREFIX: u8 = '8' as u8;
+const OUTPUT_COMMIT_PREFIX: u8 = 'o' as u8;
+const UTXO_PREFIX: u8 = 'u' as u8;
+const HEADER_FORK_PREFIX: u8 = 'f' as u8;
 
 /// An implementation of the ChainStore trait backed by a simple key-value
 /// store.
 pub struct ChainKVStore {
 	db: grin_store::Store,
+	// tree over our unspent outputs, built the first time it's needed
+	utxo_tree: Mutex<Option<UtxoTree>>,
 }
 
 impl ChainKVStore {
 	pub fn new(root_path: String) -> Result<ChainKVStore, Error> {
 		let db = grin_store::Store::open(format!("{}/{}", root_path, STORE_SUBPATH).as_str())?;
-		Ok(ChainKVStore { db: db })
+		Ok(ChainKVStore {
+			db: db,
+			utxo_tree: Mutex::new(None),
+		})
+	}
+
+	// Runs the provided closure with the tree over our unspent outputs, kept
+	// locked so it stays in step with them.
+	fn with_utxo_tree<F, T>(&self, f: F) -> Result<T, Error>
+		where F: FnOnce(&mut UtxoTree) -> Result<T, Error>
+	{
+		let mut tree = self.utxo_tree.lock().unwrap_or_else(|e| e.into_inner());
+		if tree.is_none() {
+			let hs = self.db
+				.iter_prefix::<Output>(&[UTXO_PREFIX], &[UTXO_PREFIX])
+				.map(|out| out.hash())
+				.collect();
+			*tree = Some(UtxoTree::new(hs));
+		}
+		f(tree.as_mut().unwrap())
+	}
+
+	// Hashes of the outputs the provided changes add to our unspent outputs
+	// and of those they remove.
+	fn utxo_hashes(&self,
+	               changes: &HashMap<Commitment, Option<Output>>)
+	               -> Result<(Vec<Hash>, Vec<Hash>), Error> {
+		let mut added = vec![];
+		let mut removed = vec![];
+		for (commit, out) in changes {
+			if let Some(old) = self.db.get_ser::<Output>(&utxo_key(commit)[..])? {
+				removed.push(old.hash());
+			}
+			if let Some(ref out) = *out {
+				added.push(out.hash());
+			}
+		}
+		Ok((added, removed))
+	}
+
+	// Adds the provided changes to our unspent outputs to a new batch.
+	fn utxo_batch(&self, changes: &HashMap<Commitment, Option<Output>>) -> Result<Batch, Error> {
+		let mut batch = self.db.batch();
+		for (commit, out) in changes {
+			batch = match *out {
+				Some(ref out) => batch.put_ser(&utxo_key(commit)[..], out)?,
+				None => batch.delete(&utxo_key(commit)[..])?,
+			};
+		}
+		Ok(batch)
+	}
+
+	// Adds the provided block header at its height to the batch, along with
+	// the previous headers not at theirs yet.
+	fn height_batch<'a>(&self, batch: Batch<'a>, bh: &BlockHeader) -> Result<Batch<'a>, Error> {
+		let mut batch = batch.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height), bh)?;
+
+		let mut prev_h = bh.previous;
+		let mut prev_height = bh.height - 1;
+		while prev_height > 0 {
+			// heights below a snapshot we synced from may not be set at all
+			let prev = match self.get_header_by_height(prev_height) {
+				Ok(prev) => Some(prev),
+				Err(Error::NotFoundErr) => None,
+				Err(e) => return Err(e),
+			};
+			if prev.map(|p| p.hash()) != Some(prev_h) {
+				let real_prev = self.get_block_header(&prev_h)?;
+				batch = batch.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, real_prev.height),
+				                      &real_prev)?;
+				prev_h = real_prev.previous;
+				prev_height = real_prev.height - 1;
+			} else {
+				break;
+			}
+		}
+		Ok(batch)
 	}
 }
 
@@ -75,41 +160,119 @@ impl ChainStore for ChainKVStore {
 	}
 
 	fn save_block(&self, b: &Block) -> Result<(), Error> {
-		self.db
+		let mut batch = self.db
 			.batch()
 			.put_ser(&to_key(BLOCK_PREFIX, &mut b.hash().to_vec())[..], b)?
 			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec())[..],
 			         &b.header)?
-			.write()
+			.put_ser(&fork_key(&b.header)[..], &b.header)?;
+		for out in &b.outputs {
+			let key = to_key(OUTPUT_COMMIT_PREFIX, &mut out.commit.as_ref().to_vec());
+			batch = batch.put_ser(&key[..], out)?;
+		}
+		batch.write()
 	}
 
 	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
-		self.db.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut bh.hash().to_vec())[..],
-		                bh)
+		self.db
+			.batch()
+			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut bh.hash().to_vec())[..], bh)?
+			.put_ser(&fork_key(bh)[..], bh)?
+			.write()
 	}
 
-	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
-		option_to_not_found(self.db.get_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, height)))
+	fn get_output_by_commit(&self, commit: &Commitment) -> Result<Output, Error> {
+		option_to_not_found(self.db
+			.get_ser(&to_key(OUTPUT_COMMIT_PREFIX, &mut commit.as_ref().to_vec())))
 	}
 
-	fn setup_height(&self, bh: &BlockHeader) -> Result<(), Error> {
-		self.db.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height), bh)?;
+	fn is_unspent(&self, commit: &Commitment) -> Result<bool, Error> {
+		self.db.exists(&utxo_key(commit)[..])
+	}
 
-		let mut prev_h = bh.previous;
-		let mut prev_height = bh.height - 1;
-		while prev_height > 0 {
-			let prev = self.get_header_by_height(prev_height)?;
-			if prev.hash() != prev_h {
-				let real_prev = self.get_block_header(&prev_h)?;
-				self.db
-					.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, real_prev.height),
-					         &real_prev);
-				prev_h = real_prev.previous;
-				prev_height = real_prev.height - 1;
-			} else {
-				break;
-			}
+	fn save_utxos(&self,
+	              changes: &HashMap<Commitment, Option<Output>>,
+	              head: &Tip)
+	              -> Result<(), Error> {
+		self.with_utxo_tree(|tree| {
+			let (added, removed) = self.utxo_hashes(changes)?;
+			self.utxo_batch(changes)?.put_ser(&vec![HEAD_PREFIX], head)?.write()?;
+			tree.update(&added, &removed);
+			Ok(())
+		})
+	}
+
+	fn save_block_head(&self,
+	                   bh: &BlockHeader,
+	                   changes: &HashMap<Commitment, Option<Output>>)
+	                   -> Result<(), Error> {
+		let head = Tip::from_block(bh);
+		self.with_utxo_tree(|tree| {
+			let (added, removed) = self.utxo_hashes(changes)?;
+			let batch = self.utxo_batch(changes)?;
+			self.height_batch(batch, bh)?
+				.put_ser(&vec![HEAD_PREFIX], &head)?
+				.put_ser(&vec![HEADER_HEAD_PREFIX], &head)?
+				.write()?;
+			tree.update(&added, &removed);
+			Ok(())
+		})
+	}
+
+	fn utxo_root(&self, changes: &HashMap<Commitment, Option<Output>>) -> Result<Hash, Error> {
+		self.with_utxo_tree(|tree| {
+			let (added, removed) = self.utxo_hashes(changes)?;
+			Ok(tree.root_with(&added, &removed))
+		})
+	}
+
+	fn get_utxos(&self) -> Result<Vec<Output>, Error> {
+		Ok(self.db.iter_prefix(&[UTXO_PREFIX], &[UTXO_PREFIX]).collect())
+	}
+
+	fn has_utxos(&self) -> Result<bool, Error> {
+		Ok(self.db.iter_prefix::<Output>(&[UTXO_PREFIX], &[UTXO_PREFIX]).next().is_some())
+	}
+
+	fn save_utxo_snapshot(&self, bh: &BlockHeader, outputs: &Vec<Output>) -> Result<(), Error> {
+		let mut tree = self.utxo_tree.lock().unwrap_or_else(|e| e.into_inner());
+		let mut batch = self.db.batch();
+		for out in self.get_utxos()? {
+			batch = batch.delete(&utxo_key(&out.commit)[..])?;
 		}
+		// indexed like the outputs of our blocks, to be restored by rewinds
+		for out in outputs {
+			let key = to_key(OUTPUT_COMMIT_PREFIX, &mut out.commit.as_ref().to_vec());
+			batch = batch.put_ser(&key[..], out)?.put_ser(&utxo_key(&out.commit)[..], out)?;
+		}
+		batch.put_ser(&vec![HEAD_PREFIX], &Tip::from_block(bh))?.write()?;
+		*tree = Some(UtxoTree::new(outputs.iter().map(|out| out.hash()).collect()));
 		Ok(())
 	}
+
+	fn get_headers_at_height(&self, height: u64) -> Result<Vec<BlockHeader>, Error> {
+		let prefix = u64_to_key(HEADER_FORK_PREFIX, height);
+		Ok(self.db.iter_prefix(&prefix[..], &prefix[..]).collect())
+	}
+
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		option_to_not_found(self.db.get_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, height)))
+	}
+
+	fn setup_height(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let batch = self.db.batch();
+		self.height_batch(batch, bh)?.write()
+	}
+}
+
+fn utxo_key(commit: &Commitment) -> Vec<u8> {
+	to_key(UTXO_PREFIX, &mut commit.as_ref().to_vec())
+}
+
+// Headers are also indexed by height then hash, so we can list those of all
+// branches at any height.
+fn fork_key(bh: &BlockHeader) -> Vec<u8> {
+	let mut key = u64_to_key(HEADER_FORK_PREFIX, bh.height);
+	key.append(&mut bh.hash().to_vec());
+	key
 }
@@ -14,8 +14,11 @@
 
 //! Base types that the block chain pipeline requires.
 
+use std::collections::HashMap;
+
 use grin_store::Error;
-use core::core::{Block, BlockHeader};
+use secp::pedersen::Commitment;
+use core::core::{Block, BlockHeader, Output};
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
 use core::ser;
@@ -107,6 +110,50 @@ pub trait ChainStore: Send + Sync {
 	/// Save the provided block header in store
 	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error>;
 
+	/// Gets an output of a block we saved by its commitment, whether it's
+	/// spent or even on our chain or not.
+	fn get_output_by_commit(&self, commit: &Commitment) -> Result<Output, Error>;
+
+	/// Whether the output with the provided commitment is unspent on the
+	/// chain ending at our head.
+	fn is_unspent(&self, commit: &Commitment) -> Result<bool, Error>;
+
+	/// Saves the provided changes to our unspent outputs, adding those
+	/// provided and removing those without, along with the provided tip as
+	/// our new head, all at once.
+	fn save_utxos(&self,
+	              changes: &HashMap<Commitment, Option<Output>>,
+	              head: &Tip)
+	              -> Result<(), Error>;
+
+	/// Saves the block with the provided header as the head of our chain
+	/// and of our header chain, at its height along with the previous
+	/// headers not at theirs yet, with the provided changes to our unspent
+	/// outputs. Nothing gets saved unless everything is.
+	fn save_block_head(&self,
+	                   bh: &BlockHeader,
+	                   changes: &HashMap<Commitment, Option<Output>>)
+	                   -> Result<(), Error>;
+
+	/// All the unspent outputs of the chain ending at our head.
+	fn get_utxos(&self) -> Result<Vec<Output>, Error>;
+
+	/// Merkle root our unspent outputs would have with the provided changes,
+	/// as saved by save_utxos, without saving them.
+	fn utxo_root(&self, changes: &HashMap<Commitment, Option<Output>>) -> Result<Hash, Error>;
+
+	/// Whether we have any unspent output at all.
+	fn has_utxos(&self) -> Result<bool, Error>;
+
+	/// Replaces our unspent outputs with those provided, the unspent outputs
+	/// after the block with the provided header, which becomes our head. Our
+	/// header head stays as it is.
+	fn save_utxo_snapshot(&self, bh: &BlockHeader, outputs: &Vec<Output>) -> Result<(), Error>;
+
+	/// All the block headers we know of at the provided height, on every
+	/// branch of the chain.
+	fn get_headers_at_height(&self, height: u64) -> Result<Vec<BlockHeader>, Error>;
+
 	/// Get the tip of the header chain
 	fn get_header_head(&self) -> Result<Tip, Error>;
 
@@ -130,9 +177,16 @@ pub trait ChainAdapter {
 	/// The blockchain pipeline has accepted this block as valid and added
 	/// it to our chain.
 	fn block_accepted(&self, b: &Block);
+
+	/// Our chain switched to a fork with more work, rewinding the provided
+	/// blocks, our previous head first, and applying those of the fork from
+	/// the fork point on. The new head isn't part of the applied blocks, it
+	/// gets accepted right after.
+	fn chain_reorged(&self, rewound: &[Block], applied: &[Block]);
 }
 
 pub struct NoopAdapter { }
 impl ChainAdapter for NoopAdapter {
 	fn block_accepted(&self, b: &Block) {}
+	fn chain_reorged(&self, rewound: &[Block], applied: &[Block]) {}
 }
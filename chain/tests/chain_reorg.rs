@@ -0,0 +1,179 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use rand::os::OsRng;
+
+use grin_chain::types::*;
+use grin_core::core::build;
+use grin_core::core::hash::{Hash, Hashed};
+use grin_core::core::target::Difficulty;
+use grin_core::core;
+
+// Keeps track of the reorgs the chain tells about.
+struct ReorgAdapter {
+	reorgs: Mutex<Vec<(Vec<Hash>, Vec<Hash>)>>,
+}
+
+impl ChainAdapter for ReorgAdapter {
+	fn block_accepted(&self, b: &core::Block) {}
+
+	fn chain_reorged(&self, rewound: &[core::Block], applied: &[core::Block]) {
+		let hashes = |bs: &[core::Block]| bs.iter().map(|b| b.hash()).collect::<Vec<_>>();
+		self.reorgs.lock().unwrap().push((hashes(rewound), hashes(applied)));
+	}
+}
+
+// A block on top of the provided one with the given total difficulty and
+// transactions, along with the commitment of its reward output.
+fn block_on(prev: &core::BlockHeader,
+            diff: u64,
+            txs: Vec<&mut core::Transaction>)
+            -> core::Block {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut OsRng::new().unwrap());
+	let mut b = core::Block::new(prev, txs, reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	b.header.total_difficulty = Difficulty::from_num(diff as u32);
+	b
+}
+
+fn reward(b: &core::Block) -> secp::pedersen::Commitment {
+	b.outputs[0].commit
+}
+
+#[test]
+fn reorg_to_heavier_fork() {
+	let _ = fs::remove_dir_all(".grin_reorg");
+	let store = grin_chain::store::ChainKVStore::new(".grin_reorg".to_string()).unwrap();
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+
+	let store = Arc::new(store);
+	let adapter = Arc::new(ReorgAdapter { reorgs: Mutex::new(vec![]) });
+	let process = |b: &core::Block| {
+		grin_chain::pipe::process_block(b,
+		                                store.clone(),
+		                                adapter.clone(),
+		                                grin_chain::pipe::SKIP_POW)
+	};
+
+	// our chain, then a fork catching up and passing it
+	let a1 = block_on(&gen.header, 2, vec![]);
+	let a2 = block_on(&a1.header, 3, vec![]);
+	let b1 = block_on(&gen.header, 2, vec![]);
+	let b2 = block_on(&b1.header, 4, vec![]);
+	assert!(process(&a1).unwrap().is_some());
+	assert!(process(&a2).unwrap().is_some());
+	assert!(process(&b1).unwrap().is_none());
+	assert!(store.is_unspent(&reward(&a1)).unwrap());
+	assert!(!store.is_unspent(&reward(&b1)).unwrap());
+
+	let tip = process(&b2).unwrap().unwrap();
+	assert_eq!(tip.last_block_h, b2.hash());
+	assert_eq!(store.get_header_by_height(1).unwrap().hash(), b1.hash());
+	assert_eq!(store.get_headers_at_height(1).unwrap().len(), 2);
+	assert!(!store.is_unspent(&reward(&a1)).unwrap());
+	assert!(!store.is_unspent(&reward(&a2)).unwrap());
+	assert!(store.is_unspent(&reward(&b1)).unwrap());
+	assert!(store.is_unspent(&reward(&b2)).unwrap());
+	assert_eq!(*adapter.reorgs.lock().unwrap(),
+	           vec![(vec![a2.hash(), a1.hash()], vec![b1.hash()])]);
+
+	// a heavier fork spending an output nobody has doesn't make it
+	let (mut tx, _) = build::transaction(vec![build::input_rand(10), build::output_rand(10)])
+		.unwrap();
+	let c1 = block_on(&gen.header, 2, vec![]);
+	let c2 = block_on(&c1.header, 10, vec![&mut tx]);
+	assert!(process(&c1).unwrap().is_none());
+	match process(&c2) {
+		Err(grin_chain::Error::MissingInput(_)) => {}
+		r => panic!("unexpected result {:?}", r),
+	}
+	assert_eq!(store.head().unwrap().last_block_h, b2.hash());
+	assert!(store.is_unspent(&reward(&b1)).unwrap());
+	assert!(store.is_unspent(&reward(&b2)).unwrap());
+	assert!(!store.is_unspent(&reward(&c1)).unwrap());
+	assert_eq!(adapter.reorgs.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn utxos_rebuilt_on_open() {
+	let _ = fs::remove_dir_all(".grin_rebuild");
+	let store = grin_chain::store::ChainKVStore::new(".grin_rebuild".to_string()).unwrap();
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+
+	// a chain saved the way stores predating the unspent outputs did
+	let a1 = block_on(&gen.header, 2, vec![]);
+	let a2 = block_on(&a1.header, 3, vec![]);
+	for b in vec![&a1, &a2] {
+		store.save_block(b).unwrap();
+		store.setup_height(&b.header).unwrap();
+		store.save_head(&Tip::from_block(&b.header)).unwrap();
+	}
+	assert!(!store.has_utxos().unwrap());
+
+	let store = Arc::new(store);
+	assert_eq!(grin_chain::rebuild_utxos(store.clone()).unwrap(), 2);
+	assert!(store.is_unspent(&reward(&a1)).unwrap());
+	assert!(store.is_unspent(&reward(&a2)).unwrap());
+	assert_eq!(store.head().unwrap().last_block_h, a2.hash());
+
+	// nothing to do once rebuilt
+	assert_eq!(grin_chain::rebuild_utxos(store.clone()).unwrap(), 0);
+	assert_eq!(store.get_utxos().unwrap().len(), 2);
+}
+
+#[test]
+fn head_saved_at_once() {
+	let _ = fs::remove_dir_all(".grin_head_batch");
+	let store = grin_chain::store::ChainKVStore::new(".grin_head_batch".to_string()).unwrap();
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+
+	let a1 = block_on(&gen.header, 2, vec![]);
+	store.save_block(&a1).unwrap();
+	let mut changes = HashMap::new();
+	changes.insert(reward(&a1), Some(a1.outputs[0].clone()));
+	store.save_block_head(&a1.header, &changes).unwrap();
+
+	// a block on a parent we don't have, saving stops half way through
+	let b1 = block_on(&gen.header, 3, vec![]);
+	let b2 = block_on(&b1.header, 4, vec![]);
+	store.save_block(&b2).unwrap();
+	let mut changes = HashMap::new();
+	changes.insert(reward(&a1), None);
+	changes.insert(reward(&b2), Some(b2.outputs[0].clone()));
+	assert!(store.save_block_head(&b2.header, &changes).is_err());
+
+	// and none of it made it
+	assert_eq!(store.head().unwrap().last_block_h, a1.hash());
+	assert_eq!(store.get_header_head().unwrap().last_block_h, a1.hash());
+	assert_eq!(store.get_header_by_height(1).unwrap().hash(), a1.hash());
+	assert!(store.get_header_by_height(2).is_err());
+	assert!(store.is_unspent(&reward(&a1)).unwrap());
+	assert!(!store.is_unspent(&reward(&b2)).unwrap());
+}
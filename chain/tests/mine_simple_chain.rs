@@ -50,11 +50,12 @@ fn mine_empty_chain() {
 	// mine and add a few blocks
 	let mut prev = gen;
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
 	let arc_store = Arc::new(store);
 	let adapter = Arc::new(NoopAdapter {});
 
 	for n in 1..4 {
+		// a new reward output every block, the chain refuses duplicates
+		let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
 		let mut b = core::Block::new(&prev.header, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.header.timestamp + time::Duration::seconds(60);
 
@@ -100,11 +101,12 @@ fn mine_forks() {
 	// mine and add a few blocks
 	let mut prev = gen;
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
 	let arc_store = Arc::new(store);
 	let adapter = Arc::new(NoopAdapter {});
 
 	for n in 1..4 {
+		// a new reward output every block, the chain refuses duplicates
+		let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
 		let mut b = core::Block::new(&prev.header, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.header.timestamp + time::Duration::seconds(60);
     b.header.total_difficulty = Difficulty::from_num(2*n);
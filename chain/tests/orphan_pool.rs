@@ -0,0 +1,82 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use grin_chain::OrphanPool;
+use grin_core::core;
+use grin_core::core::hash::{Hash, Hashed};
+
+// A chain of n blocks on top of the provided parent, the same every time.
+fn chain_of(n: u64, parent: Hash) -> Vec<core::Block> {
+	let mut blocks: Vec<core::Block> = vec![];
+	for i in 0..n {
+		let mut b = core::Block::default();
+		b.header.height = i + 1;
+		b.header.previous = blocks.last().map(|b| b.hash()).unwrap_or(parent);
+		blocks.push(b);
+	}
+	blocks
+}
+
+#[test]
+fn orphans_wait_for_parent() {
+	let src = "127.0.0.1:13414".parse::<SocketAddr>().unwrap();
+	let mut blocks = chain_of(4, Hash([1; 32]));
+	let root = blocks.remove(0);
+
+	let now = Instant::now();
+	let mut pool = OrphanPool::new(10, Duration::from_secs(60));
+	for b in chain_of(4, Hash([1; 32])).into_iter().skip(1).rev() {
+		assert!(pool.add(b, src, now));
+	}
+	assert!(!pool.add(chain_of(2, Hash([1; 32])).pop().unwrap(), src, now));
+	assert_eq!(pool.len(), 3);
+
+	// the pool leads us down to the one block missing
+	assert_eq!(pool.missing_ancestor(blocks[2].header.previous), root.hash());
+
+	let children = pool.take_children(&root.hash());
+	assert_eq!(children.len(), 1);
+	assert_eq!(children[0].block.hash(), blocks[0].hash());
+	assert_eq!(children[0].src, src);
+	assert_eq!(pool.len(), 2);
+	assert!(pool.take_children(&root.hash()).is_empty());
+}
+
+#[test]
+fn orphans_bounded() {
+	let src = "127.0.0.1:13414".parse::<SocketAddr>().unwrap();
+	let blocks = chain_of(3, Hash([1; 32]));
+
+	let start = Instant::now();
+	let mut pool = OrphanPool::new(2, Duration::from_secs(60));
+	for (n, b) in chain_of(3, Hash([1; 32])).into_iter().enumerate() {
+		assert!(pool.add(b, src, start + Duration::from_secs(n as u64)));
+	}
+	// the oldest made room for the last one
+	assert_eq!(pool.len(), 2);
+	assert!(!pool.contains(&blocks[0].hash()));
+	assert!(pool.contains(&blocks[2].hash()));
+
+	// each expires a minute after it got in
+	assert_eq!(pool.prune(start + Duration::from_secs(61)), 1);
+	assert!(pool.contains(&blocks[2].hash()));
+	assert_eq!(pool.prune(start + Duration::from_secs(62)), 1);
+	assert_eq!(pool.len(), 0);
+}
@@ -0,0 +1,166 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+use std::fs;
+use std::sync::Arc;
+use rand::os::OsRng;
+
+use grin_chain::types::*;
+use grin_chain::store::ChainKVStore;
+use grin_core::core::hash::{Hash, Hashed};
+use grin_core::core::target::Difficulty;
+use grin_core::core;
+
+fn new_store(dir: &str) -> Arc<ChainKVStore> {
+	let _ = fs::remove_dir_all(dir);
+	let store = ChainKVStore::new(dir.to_string()).unwrap();
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+	Arc::new(store)
+}
+
+// A block on top of the provided one, committing to the unspent outputs the
+// chain of the store would have after it.
+fn block_on(prev: &core::BlockHeader, store: Arc<ChainKVStore>) -> core::Block {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut OsRng::new().unwrap());
+	let mut b = core::Block::new(prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	b.header.total_difficulty = Difficulty::from_num(prev.height as u32 + 2);
+	b.header.utxo_merkle = grin_chain::utxo_root(&b, store).unwrap();
+	b
+}
+
+fn process(b: &core::Block, store: Arc<ChainKVStore>) -> Result<Option<Tip>, grin_chain::Error> {
+	grin_chain::process_block(b, store, Arc::new(NoopAdapter {}), grin_chain::pipe::SKIP_POW)
+}
+
+#[test]
+fn utxo_root_checked() {
+	let store = new_store(".grin_utxo_root");
+	let gen = store.head_header().unwrap();
+	let b1 = block_on(&gen, store.clone());
+	assert!(process(&b1, store.clone()).unwrap().is_some());
+
+	let mut b2 = block_on(&b1.header, store.clone());
+	b2.header.utxo_merkle = Hash([1; 32]);
+	match process(&b2, store.clone()) {
+		Err(grin_chain::Error::InvalidUtxoRoot) => {}
+		r => panic!("unexpected result {:?}", r),
+	}
+	assert_eq!(store.head().unwrap().last_block_h, b1.hash());
+	assert!(!store.is_unspent(&b2.outputs[0].commit).unwrap());
+	assert_eq!(store.get_utxos().unwrap().len(), 1);
+}
+
+#[test]
+fn sync_from_snapshot() {
+	// an archival node with a few blocks
+	let full = new_store(".grin_utxo_full");
+	let mut blocks: Vec<core::Block> = vec![];
+	for _ in 0..3 {
+		let prev = full.head_header().unwrap();
+		let b = block_on(&prev, full.clone());
+		assert!(process(&b, full.clone()).unwrap().is_some());
+		blocks.push(b);
+	}
+	let horizon = blocks[1].hash();
+	let snapshot = grin_chain::utxo_snapshot(horizon, full.clone()).unwrap();
+	assert_eq!(snapshot.len(), 2);
+
+	// a new node with only the header chain
+	let fresh = new_store(".grin_utxo_fresh");
+	for b in &blocks {
+		fresh.save_block_header(&b.header).unwrap();
+	}
+	fresh.save_header_head(&Tip::from_block(&blocks[2].header)).unwrap();
+
+	// outputs missing from the snapshot don't match the header
+	let mut partial = snapshot.clone();
+	partial.pop();
+	match grin_chain::process_utxo_snapshot(&blocks[1].header, partial, fresh.clone()) {
+		Err(grin_chain::Error::InvalidUtxoRoot) => {}
+		r => panic!("unexpected result {:?}", r),
+	}
+
+	let tip = grin_chain::process_utxo_snapshot(&blocks[1].header, snapshot, fresh.clone())
+		.unwrap();
+	assert_eq!(tip.last_block_h, horizon);
+	assert_eq!(fresh.head().unwrap().last_block_h, horizon);
+	assert_eq!(fresh.get_header_head().unwrap().last_block_h, blocks[2].hash());
+	assert_eq!(fresh.get_header_by_height(1).unwrap().hash(), blocks[0].hash());
+	assert!(fresh.is_unspent(&blocks[0].outputs[0].commit).unwrap());
+
+	// full blocks follow from the horizon on
+	assert!(process(&blocks[2], fresh.clone()).unwrap().is_some());
+	assert_eq!(fresh.head().unwrap().last_block_h, blocks[2].hash());
+	assert_eq!(fresh.get_utxos().unwrap().len(), 3);
+}
+
+#[test]
+fn reorg_below_horizon() {
+	// a node synced from a snapshot, with no full block before its horizon
+	let full = new_store(".grin_horizon_full");
+	let mut ours: Vec<core::Block> = vec![];
+	for _ in 0..3 {
+		let prev = full.head_header().unwrap();
+		let b = block_on(&prev, full.clone());
+		assert!(process(&b, full.clone()).unwrap().is_some());
+		ours.push(b);
+	}
+	let pruned = new_store(".grin_horizon_pruned");
+	for b in &ours {
+		pruned.save_block_header(&b.header).unwrap();
+	}
+	pruned.save_header_head(&Tip::from_block(&ours[2].header)).unwrap();
+	let snapshot = grin_chain::utxo_snapshot(ours[1].hash(), full.clone()).unwrap();
+	grin_chain::process_utxo_snapshot(&ours[1].header, snapshot, pruned.clone()).unwrap();
+	assert!(process(&ours[2], pruned.clone()).unwrap().is_some());
+
+	// a heavier fork from the genesis can't be reorged to
+	let other = new_store(".grin_horizon_fork");
+	let mut fork: Vec<core::Block> = vec![];
+	for _ in 0..4 {
+		let prev = other.head_header().unwrap();
+		let b = block_on(&prev, other.clone());
+		assert!(process(&b, other.clone()).unwrap().is_some());
+		fork.push(b);
+	}
+	for b in &fork[..3] {
+		assert!(process(b, pruned.clone()).unwrap().is_none());
+	}
+	match process(&fork[3], pruned.clone()) {
+		Err(grin_chain::Error::ForkBelowHorizon) => {}
+		r => panic!("unexpected result {:?}", r),
+	}
+	assert_eq!(pruned.head().unwrap().last_block_h, ours[2].hash());
+
+	// but the UTXO set at a horizon on the fork is taken, however light
+	pruned.save_header_head(&Tip::from_block(&fork[3].header)).unwrap();
+	let snapshot = grin_chain::utxo_snapshot(fork[2].hash(), other.clone()).unwrap();
+	let tip = grin_chain::process_utxo_snapshot(&fork[2].header, snapshot, pruned.clone())
+		.unwrap();
+	assert_eq!(tip.last_block_h, fork[2].hash());
+	assert!(process(&fork[3], pruned.clone()).unwrap().is_some());
+	assert_eq!(pruned.head().unwrap().last_block_h, fork[3].hash());
+	assert!(!pruned.is_unspent(&ours[2].outputs[0].commit).unwrap());
+	assert_eq!(pruned.get_utxos().unwrap().len(), 4);
+}
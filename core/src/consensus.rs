@@ -101,6 +101,20 @@ pub fn next_target(ts: i64,
 /// easier to reason about.
 pub const CUT_THROUGH_HORIZON: u32 = 48 * 3600 / (BLOCK_TIME_SEC as u32);
 
+/// Weight of a transaction input, roughly its size in a block relative to
+/// outputs and kernels. Blocks and the transactions assembled in them are
+/// bounded by their weight.
+pub const INPUT_WEIGHT: usize = 1;
+
+/// Weight of a transaction output, carrying its range proof.
+pub const OUTPUT_WEIGHT: usize = 10;
+
+/// Weight of a transaction kernel.
+pub const KERNEL_WEIGHT: usize = 2;
+
+/// Maximum weight of all the inputs, outputs and kernels of a block.
+pub const MAX_BLOCK_WEIGHT: usize = 80_000;
+
 /// The maximum size we're willing to accept for any message. Enforced by the
 /// peer-to-peer networking layer only for DoS protection.
 pub const MAX_MSG_LEN: u64 = 20_000_000;
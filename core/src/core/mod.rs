@@ -30,7 +30,7 @@ use secp::pedersen::*;
 use consensus::PROOFSIZE;
 pub use self::block::{Block, BlockHeader, DEFAULT_BLOCK};
 pub use self::transaction::{Transaction, Input, Output, TxKernel, COINBASE_KERNEL,
-                            COINBASE_OUTPUT, DEFAULT_OUTPUT};
+                            COINBASE_OUTPUT, DEFAULT_OUTPUT, UtxoTree, merkle_utxos};
 use self::hash::{Hash, Hashed, ZERO_HASH};
 use ser::{Writeable, Writer, Reader, Readable, Error};
 
@@ -206,7 +206,7 @@ impl MerkleRow {
 #[cfg(test)]
 mod test {
 	use super::*;
-	use core::hash::ZERO_HASH;
+	use core::hash::{Hashed, ZERO_HASH};
 	use secp;
 	use secp::Secp256k1;
 	use secp::key::SecretKey;
@@ -375,6 +375,46 @@ mod test {
 		b.validate(&secp).unwrap();
 	}
 
+	#[test]
+	fn utxo_root_unordered() {
+		let mut outputs = tx2i1o().outputs;
+		outputs.append(&mut tx1i1o().outputs);
+		let root = merkle_utxos(&outputs);
+		outputs.reverse();
+		assert_eq!(merkle_utxos(&outputs), root);
+		outputs.pop();
+		assert!(merkle_utxos(&outputs) != root);
+		assert_eq!(merkle_utxos(&vec![]), [].hash());
+	}
+
+	#[test]
+	fn utxo_tree_updated() {
+		let mut outputs = tx2i1o().outputs;
+		outputs.append(&mut tx1i1o().outputs);
+		outputs.append(&mut tx2i1o().outputs);
+		outputs.append(&mut tx1i1o().outputs);
+		let hs = map_vec!(outputs, |out| out.hash());
+
+		// spending the first two and adding the last two matches a tree of
+		// the outputs left
+		let mut tree = UtxoTree::new(hs[..2].to_vec());
+		let root = tree.root();
+		let remaining = hs[2..].to_vec();
+		assert_eq!(tree.root_with(&hs[2..], &hs[..2]),
+		           UtxoTree::new(remaining.clone()).root());
+		assert_eq!(tree.root(), root);
+		tree.update(&hs[2..], &hs[..2]);
+		assert_eq!(tree.root(), UtxoTree::new(remaining).root());
+		assert_eq!(tree.len(), hs.len() - 2);
+
+		// back to the outputs we started with, then to none at all
+		tree.update(&hs[..2], &hs[2..]);
+		assert_eq!(tree.root(), root);
+		tree.update(&[], &hs[..2]);
+		assert_eq!(tree.root(), [].hash());
+		assert_eq!(tree.len(), 0);
+	}
+
 	// utility producing a transaction with 2 inputs and a single outputs
 	pub fn tx2i1o() -> Transaction {
 		build::transaction(vec![input_rand(10), input_rand(11), output_rand(20), with_fee(1)])
@@ -62,12 +62,25 @@ impl Difficulty {
 		Difficulty { num: max_target / h_num }
 	}
 
+	/// Largest difficulty fitting in the provided number of bits, useful as a
+	/// ceiling for implausible values.
+	pub fn max_with_bits(bits: usize) -> Difficulty {
+		let one = BigUint::new(vec![1]);
+		Difficulty { num: (one.clone() << bits) - one }
+	}
+
     /// Converts the difficulty into a bignum
     pub fn into_biguint(self) -> BigUint {
         self.num
     }
 }
 
+impl fmt::Display for Difficulty {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.num)
+	}
+}
+
 impl Add<Difficulty> for Difficulty {
 	type Output = Difficulty;
 	fn add(self, other: Difficulty) -> Difficulty {
@@ -14,13 +14,15 @@
 
 //! Transactions
 
+use std::collections::{BTreeSet, HashMap};
+
 use byteorder::{ByteOrder, BigEndian};
 use secp::{self, Secp256k1, Message, Signature};
 use secp::pedersen::{RangeProof, Commitment};
 
 use core::Committed;
-use core::MerkleRow;
-use core::hash::{Hash, Hashed};
+use core::{HPair, MerkleRow};
+use core::hash::{Hash, Hashed, ZERO_HASH};
 use ser::{self, Reader, Writer, Readable, Writeable};
 
 bitflags! {
@@ -343,6 +345,145 @@ pub fn merkle_inputs_outputs(inputs: &Vec<Input>, outputs: &Vec<Output>) -> Hash
 	MerkleRow::new(all_hs).root()
 }
 
+/// Merkle root of a set of unspent outputs, in whatever order they're
+/// provided, as committed to by the header of the block they're the unspent
+/// outputs after. The root of their UtxoTree.
+pub fn merkle_utxos(outputs: &Vec<Output>) -> Hash {
+	UtxoTree::new(map_vec!(outputs, |out| out.hash())).root()
+}
+
+/// Number of leading bits of the hash of an unspent output picking the
+/// bucket of the UtxoTree it goes in.
+pub const UTXO_BUCKET_BITS: usize = 12;
+
+/// Merkle tree over the hashes of a set of unspent outputs. The hashes get
+/// spread over a fixed number of buckets by their leading bits, the Merkle
+/// root of the sorted hashes of each bucket being a leaf of the tree. Adding
+/// or removing an output only changes its bucket and the nodes above it, so
+/// the root gets maintained as outputs get spent without going over all of
+/// them.
+#[derive(Clone)]
+pub struct UtxoTree {
+	// sorted hashes of the outputs in each bucket
+	buckets: Vec<Vec<Hash>>,
+	// the root at 1, the children of node n at 2n and 2n + 1, down to the
+	// roots of the buckets
+	nodes: Vec<Hash>,
+	len: usize,
+}
+
+impl UtxoTree {
+	/// Tree over the provided hashes of unspent outputs.
+	pub fn new(hs: Vec<Hash>) -> UtxoTree {
+		let n = 1 << UTXO_BUCKET_BITS;
+		let mut buckets = vec![vec![]; n];
+		for h in hs {
+			buckets[bucket(&h)].push(h);
+		}
+		let mut nodes = vec![ZERO_HASH; 2 * n];
+		for (i, b) in buckets.iter_mut().enumerate() {
+			b.sort();
+			b.dedup();
+			nodes[n + i] = MerkleRow::new(b.clone()).root();
+		}
+		for i in (1..n).rev() {
+			nodes[i] = HPair(nodes[2 * i], nodes[2 * i + 1]).hash();
+		}
+		let len = buckets.iter().map(|b| b.len()).sum();
+		UtxoTree {
+			buckets: buckets,
+			nodes: nodes,
+			len: len,
+		}
+	}
+
+	/// Root of the tree, the hash of nothing when there's no output at all.
+	pub fn root(&self) -> Hash {
+		if self.len == 0 { [].hash() } else { self.nodes[1] }
+	}
+
+	/// Number of unspent outputs in the tree.
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Root the tree would have with the provided hashes removed, then those
+	/// provided added, the tree staying as it is.
+	pub fn root_with(&self, added: &[Hash], removed: &[Hash]) -> Hash {
+		let (_, nodes, len) = self.changes(added, removed);
+		if len == 0 {
+			[].hash()
+		} else {
+			nodes.get(&1).cloned().unwrap_or(self.nodes[1])
+		}
+	}
+
+	/// Removes the provided hashes from the tree, then adds those provided.
+	pub fn update(&mut self, added: &[Hash], removed: &[Hash]) {
+		let (buckets, nodes, len) = self.changes(added, removed);
+		for (i, b) in buckets {
+			self.buckets[i] = b;
+		}
+		for (i, h) in nodes {
+			self.nodes[i] = h;
+		}
+		self.len = len;
+	}
+
+	// The buckets and nodes changing with the provided hashes removed then
+	// added, along with the number of outputs after.
+	fn changes(&self,
+	           added: &[Hash],
+	           removed: &[Hash])
+	           -> (HashMap<usize, Vec<Hash>>, HashMap<usize, Hash>, usize) {
+		let mut buckets = HashMap::new();
+		let mut len = self.len;
+		for h in removed {
+			let i = bucket(h);
+			let b = buckets.entry(i).or_insert_with(|| self.buckets[i].clone());
+			if let Ok(pos) = b.binary_search(h) {
+				b.remove(pos);
+				len -= 1;
+			}
+		}
+		for h in added {
+			let i = bucket(h);
+			let b = buckets.entry(i).or_insert_with(|| self.buckets[i].clone());
+			if let Err(pos) = b.binary_search(h) {
+				b.insert(pos, *h);
+				len += 1;
+			}
+		}
+
+		// up from the changed buckets, a level at a time
+		let n = self.buckets.len();
+		let mut nodes = HashMap::new();
+		let mut parents = BTreeSet::new();
+		for (i, b) in &buckets {
+			nodes.insert(n + i, MerkleRow::new(b.clone()).root());
+			parents.insert((n + i) / 2);
+		}
+		while !parents.is_empty() {
+			let mut next = BTreeSet::new();
+			for p in parents {
+				let left = nodes.get(&(2 * p)).cloned().unwrap_or(self.nodes[2 * p]);
+				let right = nodes.get(&(2 * p + 1)).cloned().unwrap_or(self.nodes[2 * p + 1]);
+				nodes.insert(p, HPair(left, right).hash());
+				if p > 1 {
+					next.insert(p / 2);
+				}
+			}
+			parents = next;
+		}
+		(buckets, nodes, len)
+	}
+}
+
+// The bucket of the UtxoTree an output with the provided hash goes in.
+fn bucket(h: &Hash) -> usize {
+	((h.0[0] as usize) << 8 | h.0[1] as usize) >> (16 - UTXO_BUCKET_BITS)
+}
+
 fn u64_to_32bytes(n: u64) -> [u8; 32] {
 	let mut bytes = [0; 32];
 	BigEndian::write_u64(&mut bytes[24..32], n);
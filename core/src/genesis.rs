@@ -20,23 +20,28 @@ use core;
 use consensus::DEFAULT_SIZESHIFT;
 use core::hash::Hashed;
 use core::target::Difficulty;
+use global::{ChainParams, ChainTypes};
 
-/// Genesis block definition. It has no rewards, no inputs, no outputs, no
-/// fees and a height of zero.
+/// Genesis block of the test network, see genesis_for.
 pub fn genesis() -> core::Block {
+	genesis_for(&ChainTypes::Testnet.params())
+}
+
+/// Genesis block definition for the network with the provided parameters.
+/// It has no rewards, no inputs, no outputs, no fees and a height of zero.
+pub fn genesis_for(params: &ChainParams) -> core::Block {
+	let difficulty = Difficulty::from_num(params.initial_difficulty);
 	core::Block {
 		header: core::BlockHeader {
 			height: 0,
 			previous: core::hash::Hash([0xff; 32]),
-			timestamp: time::Tm {
-				tm_year: 1997 - 1900,
-				tm_mon: 7,
-				tm_mday: 4,
-				..time::empty_tm()
-			},
+			timestamp: time::at_utc(time::Timespec {
+				sec: params.genesis_time,
+				nsec: 0,
+			}),
 			cuckoo_len: DEFAULT_SIZESHIFT,
-			difficulty: Difficulty::one(),
-			total_difficulty: Difficulty::one(),
+			difficulty: difficulty.clone(),
+			total_difficulty: difficulty,
 			utxo_merkle: [].hash(),
 			tx_merkle: [].hash(),
 			features: core::DEFAULT_BLOCK,
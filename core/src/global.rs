@@ -0,0 +1,126 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parameters of the networks we can run on, the main one, the test one or a
+//! private network of the user's own. They travel with the configurations of
+//! the servers running on a network: the magic bytes starting our messages
+//! keep peers on different networks from talking to each other and the
+//! genesis block each network starts from makes for distinct chains.
+
+/// Networks we know how to run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainTypes {
+	/// The main network.
+	Mainnet,
+	/// The public test network.
+	Testnet,
+	/// A private network of the user's own, its parameters overridable.
+	Usernet,
+}
+
+impl ChainTypes {
+	/// The network with the provided name, as in configurations and on the
+	/// command line.
+	pub fn from_name(name: &str) -> Option<ChainTypes> {
+		match name {
+			"mainnet" => Some(ChainTypes::Mainnet),
+			"testnet" => Some(ChainTypes::Testnet),
+			"usernet" => Some(ChainTypes::Usernet),
+			_ => None,
+		}
+	}
+
+	/// Name of the network, see from_name.
+	pub fn name(&self) -> &'static str {
+		match *self {
+			ChainTypes::Mainnet => "mainnet",
+			ChainTypes::Testnet => "testnet",
+			ChainTypes::Usernet => "usernet",
+		}
+	}
+
+	/// Default parameters of the network.
+	pub fn params(&self) -> ChainParams {
+		match *self {
+			ChainTypes::Mainnet => {
+				ChainParams {
+					chain_type: *self,
+					magic: [0x1e, 0xc6],
+					p2p_port: 3414,
+					initial_difficulty: 1000,
+					// 2017-06-01
+					genesis_time: 1496275200,
+				}
+			}
+			ChainTypes::Testnet => {
+				ChainParams {
+					chain_type: *self,
+					magic: [0x1e, 0xc5],
+					p2p_port: 13414,
+					initial_difficulty: 1,
+					// 1997-08-04
+					genesis_time: 870652800,
+				}
+			}
+			ChainTypes::Usernet => {
+				ChainParams {
+					chain_type: *self,
+					magic: [0x1e, 0xc7],
+					p2p_port: 23414,
+					initial_difficulty: 1,
+					// 2017-07-14
+					genesis_time: 1500000000,
+				}
+			}
+		}
+	}
+}
+
+/// Everything differing from one network to the other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+	/// Network the parameters are for, possibly overridden.
+	pub chain_type: ChainTypes,
+	/// Bytes every message we exchange with our peers starts with, those
+	/// sending others being on another network.
+	pub magic: [u8; 2],
+	/// Port the peer-to-peer server listens on by default.
+	pub p2p_port: u16,
+	/// Difficulty of the genesis block, the first ones following it.
+	pub initial_difficulty: u32,
+	/// Timestamp of the genesis block, in seconds since the epoch.
+	pub genesis_time: i64,
+}
+
+#[cfg(test)]
+mod test {
+	use core::hash::Hashed;
+	use genesis::genesis_for;
+	use super::*;
+
+	#[test]
+	fn distinct_networks() {
+		let all = vec![ChainTypes::Mainnet, ChainTypes::Testnet, ChainTypes::Usernet];
+		for (i, a) in all.iter().enumerate() {
+			assert_eq!(ChainTypes::from_name(a.name()), Some(*a));
+			for b in &all[i + 1..] {
+				let (pa, pb) = (a.params(), b.params());
+				assert!(pa.magic != pb.magic);
+				assert!(pa.p2p_port != pb.p2p_port);
+				assert!(genesis_for(&pa).hash() != genesis_for(&pb).hash());
+			}
+		}
+		assert_eq!(ChainTypes::from_name("regtest"), None);
+	}
+}
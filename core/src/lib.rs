@@ -40,5 +40,6 @@ pub mod macros;
 pub mod consensus;
 pub mod core;
 pub mod genesis;
+pub mod global;
 pub mod pow;
 pub mod ser;
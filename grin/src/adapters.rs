@@ -12,19 +12,54 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::SocketAddr;
+use std::cmp;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use chain::{self, ChainAdapter};
 use core::core;
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
-use p2p::{self, NetAdapter, Server, PeerStore, PeerData, Capabilities, State};
+use events::{Event, Events};
+use p2p::{self, NetAdapter, Server, PeerStore, PeerData, Capabilities, State, Checkpoint};
+use pool;
 use util::OneTime;
+use secp::pedersen::Commitment;
 use store;
 use sync;
+use time;
+
+// Invalid addresses a peer can send us at once before getting quarantined.
+const MAX_INVALID_ADDRS: usize = 10;
+
+// Interval, in heights, between the checkpoints we report to our peers.
+const CHECKPOINT_INTERVAL: u64 = 1000;
+
+// Orphan blocks we keep at most, and for how long, waiting for their parent.
+const MAX_ORPHANS: usize = 200;
+const MAX_ORPHAN_AGE_SECS: u64 = 600;
+
+// How far back from our head, in heights, we still rebuild the unspent
+// outputs at for peers syncing from them.
+const MAX_SNAPSHOT_DEPTH: u64 = 2 * p2p::PRUNED_BLOCKS_HORIZON;
+
+// Horizons we keep the unspent outputs at, and how long we wait after
+// rebuilding them at one before rebuilding them at another.
+const MAX_CACHED_SNAPSHOTS: usize = 2;
+const SNAPSHOT_REBUILD_SECS: u64 = 30;
+
+/// Unspent outputs at the horizons peers downloaded them at lately, rebuilt
+/// on their own thread, one horizon at a time.
+struct UtxoSnapshots {
+	// oldest first
+	ready: Vec<(Hash, Arc<Vec<core::Output>>)>,
+	building: Option<Hash>,
+	last_build: Option<Instant>,
+}
 
 /// Implementation of the NetAdapter for the blockchain. Gets notified when new
 /// blocks and transactions are received and forwards to the chain and pool
@@ -35,45 +70,150 @@ pub struct NetToChainAdapter {
 	chain_store: Arc<chain::ChainStore>,
 	chain_adapter: Arc<ChainToNetAdapter>,
 	peer_store: Arc<PeerStore>,
+	/// number of addresses we send per peer addresses request
+	max_gossip_addrs: usize,
+	/// whether we keep gossiped addresses in private ranges
+	allow_private_addrs: bool,
+	/// header hashes at given heights most of our peers agreed on
+	checkpoints: Mutex<HashMap<u64, Hash>>,
+	/// blocks received ahead of their parent, pushed to us or downloaded by
+	/// the syncer
+	orphans: Arc<Mutex<chain::OrphanPool>>,
+	/// whether we have all full blocks, not having synced from a snapshot
+	archive_mode: bool,
+	/// unspent outputs at the last horizons peers downloaded them at
+	utxo_snapshots: Arc<Mutex<UtxoSnapshots>>,
+	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	events: Arc<Events>,
 
 	syncer: OneTime<Arc<sync::Syncer>>,
 }
 
 impl NetAdapter for NetToChainAdapter {
 	fn total_difficulty(&self) -> Difficulty {
-		self.chain_head.lock().unwrap().clone().total_difficulty
+		self.chain_head.lock().unwrap_or_else(|e| e.into_inner()).clone().total_difficulty
 	}
 
-	fn transaction_received(&self, tx: core::Transaction) {
-		unimplemented!();
+	fn head_hash(&self) -> Hash {
+		self.chain_head.lock().unwrap_or_else(|e| e.into_inner()).last_block_h
 	}
 
-	fn block_received(&self, b: core::Block) {
-		debug!("Received block {} from network, going to process.",
-		       b.hash());
+	fn head_height(&self) -> u64 {
+		self.chain_head.lock().unwrap_or_else(|e| e.into_inner()).height
+	}
 
-		// pushing the new block through the chain pipeline
-		let store = self.chain_store.clone();
-		let chain_adapter = self.chain_adapter.clone();
-		let opts = if self.syncer.borrow().syncing() {
-			chain::SYNC
+	fn checkpoints(&self) -> Vec<Checkpoint> {
+		let head_height = self.chain_head.lock().unwrap_or_else(|e| e.into_inner()).height;
+		let mut checkpoints = vec![];
+		let mut height = head_height - head_height % CHECKPOINT_INTERVAL;
+		while height > 0 && checkpoints.len() < p2p::MAX_CHECKPOINTS as usize {
+			match self.chain_store.get_header_by_height(height) {
+				Ok(bh) => {
+					checkpoints.push(Checkpoint {
+						height: height,
+						hash: bh.hash(),
+					})
+				}
+				Err(e) => {
+					debug!("No header at checkpoint height {}: {:?}", height, e);
+					break;
+				}
+			}
+			height -= CHECKPOINT_INTERVAL;
+		}
+		checkpoints.reverse();
+		checkpoints
+	}
+
+	fn checkpoints_agreed(&self, checkpoints: Vec<Checkpoint>) {
+		info!("Peers agreed on {} checkpoints.", checkpoints.len());
+		let mut agreed = self.checkpoints.lock().unwrap_or_else(|e| e.into_inner());
+		for c in checkpoints {
+			agreed.insert(c.height, c.hash);
+		}
+	}
+
+	fn services(&self) -> p2p::Services {
+		// without the blocks before the snapshot we synced from, only recent
+		// ones can be asked of us
+		if self.archive_mode {
+			p2p::ALL_SERVICES
 		} else {
-			chain::NONE
-		};
-		let res = chain::process_block(&b, store, chain_adapter, opts);
+			p2p::ALL_SERVICES - p2p::ARCHIVAL
+		}
+	}
 
-		// log errors and update the shared head reference on success
+	/// A transaction got to us from the network. The ones the pool accepts
+	/// get relayed, the p2p server knows not to send it back to the peer it
+	/// came from.
+	fn transaction_received(&self, tx: core::Transaction) {
+		let h = tx.hash();
+		let res = self.tx_pool
+			.write()
+			.unwrap_or_else(|e| e.into_inner())
+			.add_to_memory_pool(tx.clone());
+		match res {
+			Ok(_) => self.chain_adapter.tx_accepted(&tx),
+			Err(e) => debug!("Transaction {} from network refused by the pool: {:?}", h, e),
+		}
+	}
+
+	/// Stemmed transactions are kept apart in the stem pool until fluffed,
+	/// not to be served to anyone asking or used in compact blocks before.
+	fn stem_transaction_received(&self, tx: core::Transaction) {
+		let h = tx.hash();
+		let res = self.tx_pool
+			.write()
+			.unwrap_or_else(|e| e.into_inner())
+			.add_to_stempool(tx.clone());
+		match res {
+			Ok(_) => self.chain_adapter.tx_stemmed(&tx),
+			Err(e) => debug!("Stemmed transaction {} refused by the pool: {:?}", h, e),
+		}
+	}
+
+	fn transaction_fluffed(&self, tx: &core::Transaction) {
+		let res = self.tx_pool
+			.write()
+			.unwrap_or_else(|e| e.into_inner())
+			.add_to_memory_pool(tx.clone());
 		if let Err(e) = res {
-			debug!("Block {} refused by chain: {:?}", b.hash(), e);
-		} else if let Ok(Some(tip)) = res {
-			let chain_head = self.chain_head.clone();
-			let mut head = chain_head.lock().unwrap();
-			*head = tip;
+			debug!("Fluffed transaction {} refused by the pool: {:?}", tx.hash(), e);
+		}
+	}
+
+	/// During sync, block bodies get downloaded in parallel and the ones
+	/// coming ahead of their parent wait for it in the orphan pool, following
+	/// it once it's in, like other orphans do. The sender gets asked for the
+	/// block missing to connect an orphan, not for those we're downloading,
+	/// which are reported unprocessed until they connect.
+	fn block_received(&self, b: core::Block, src: SocketAddr) -> p2p::BlockStatus {
+		let syncer = self.syncer.borrow().clone();
+		let h = b.hash();
+		if let Some(cp) = self.conflicting_checkpoint(&b.header) {
+			info!("Block {} at {} conflicts with checkpoint {}, rejecting it.",
+			      h,
+			      b.header.height,
+			      cp);
+			return p2p::BlockStatus::Invalid;
+		}
+		let prev = b.header.previous;
+		let parent_in = prev == self.head_hash() || self.has_block(prev);
+		if syncer.syncing() && syncer.downloading(h) && !parent_in {
+			debug!("Buffering block {} until its parent {} is in.", h, b.header.previous);
+			syncer.block_received(h);
+			self.add_orphan(b, src);
+			return p2p::BlockStatus::Unprocessed;
 		}
 
-		if self.syncer.borrow().syncing() {
-			self.syncer.borrow().block_received(b.hash());
+		let status = self.process_block(&b);
+		match status {
+			p2p::BlockStatus::Accepted => self.process_descendants(h),
+			p2p::BlockStatus::Orphan(_) => return self.add_orphan(b, src),
+			p2p::BlockStatus::Invalid |
+			p2p::BlockStatus::Unprocessed => {}
 		}
+		status
 	}
 
 	fn headers_received(&self, bhs: Vec<core::BlockHeader>) {
@@ -86,6 +226,16 @@ impl NetAdapter for NetToChainAdapter {
 		// try to add each header to our header chain
 		let mut added_hs = vec![];
 		for bh in bhs {
+			// the chain most peers agreed on doesn't go through it, nor through
+			// the headers following it
+			if let Some(cp) = self.conflicting_checkpoint(&bh) {
+				info!("Block header {} at {} conflicts with checkpoint {}, rejecting the rest.",
+				      bh.hash(),
+				      bh.height,
+				      cp);
+				break;
+			}
+
 			let store = self.chain_store.clone();
 			let chain_adapter = self.chain_adapter.clone();
 
@@ -161,30 +311,76 @@ impl NetAdapter for NetToChainAdapter {
 		}
 	}
 
-	/// Find good peers we know with the provided capability and return their
-	/// addresses.
+	/// The unspent outputs at the horizon get rebuilt once and kept while
+	/// peers go through their chunks. None until they're rebuilt.
+	fn utxo_chunk(&self, horizon: Hash, chunk: u32) -> Option<(u32, Vec<core::Output>)> {
+		let outputs = match self.utxo_snapshot(horizon) {
+			Some(outputs) => outputs,
+			None => return None,
+		};
+		let size = p2p::MAX_UTXO_CHUNK as usize;
+		let chunks = cmp::max(1, (outputs.len() + size - 1) / size);
+		if chunk as usize >= chunks {
+			return None;
+		}
+		let start = chunk as usize * size;
+		let end = cmp::min(start + size, outputs.len());
+		Some((chunks as u32, outputs[start..end].to_vec()))
+	}
+
+	/// Whether we already have the full block with the provided hash.
+	fn has_block(&self, h: Hash) -> bool {
+		self.chain_store.get_block(&h).is_ok()
+	}
+
+	/// Transactions get served from the pool.
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+		self.tx_pool.read().unwrap_or_else(|e| e.into_inner()).get(&h).cloned()
+	}
+
+	/// The pool transaction of a compact block kernel, if we have it.
+	fn get_kernel_transaction(&self, k: &core::TxKernel) -> Option<core::Transaction> {
+		self.tx_pool
+			.read()
+			.unwrap_or_else(|e| e.into_inner())
+			.find(|tx| p2p::is_kernel_of(k, tx))
+			.cloned()
+	}
+
+	/// Find good peers we saw lately with the provided capability and return
+	/// a random selection of their addresses, those we last saw most recently
+	/// first.
 	fn find_peer_addrs(&self, capab: p2p::Capabilities) -> Vec<SocketAddr> {
-		let peers = self.peer_store.find_peers(State::Healthy, capab, p2p::MAX_PEER_ADDRS as usize);
-		debug!("Got {} peer addrs to send.", peers.len());
-		map_vec!(peers, |p| p.addr)
+		let peers = self.peer_store.recent_peers(State::Healthy, capab, self.max_gossip_addrs);
+		let addrs = map_vec!(peers, |p| p.addr);
+		debug!("Got {} peer addrs to send.", addrs.len());
+		addrs
 	}
 
-	/// A list of peers has been received from one of our peers.
-	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>) {
+	/// A list of peers has been received from one of our peers. Addresses we
+	/// couldn't dial are dropped, a peer sending too many gets quarantined.
+	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>, src: SocketAddr) {
 		debug!("Received {} peer addrs, saving.", peer_addrs.len());
-		for pa in peer_addrs {
+		let allow_private = self.allow_private_addrs;
+		let (valid, invalid): (Vec<_>, Vec<_>) =
+			peer_addrs.into_iter().partition(|pa| p2p::valid_peer_addr(pa, allow_private));
+		if invalid.len() > 0 {
+			debug!("Dropped {} invalid peer addrs from {}.", invalid.len(), src);
+		}
+		if invalid.len() > MAX_INVALID_ADDRS {
+			warn!("Peer {} sent {} invalid addrs, quarantining.", src, invalid.len());
+			self.chain_adapter
+				.p2p
+				.borrow()
+				.ban_peer(src, p2p::Severity::Quarantine, "too many invalid addresses");
+		}
+		for pa in valid {
 			if let Ok(e) = self.peer_store.exists_peer(pa) {
 				if e {
 					continue;
 				}
 			}
-			let peer = PeerData {
-				addr: pa,
-				capabilities: p2p::UNKNOWN,
-				user_agent: "".to_string(),
-				flags: State::Healthy,
-			};
-			if let Err(e) = self.peer_store.save_peer(&peer) {
+			if let Err(e) = self.peer_store.save_peer(&PeerData::new(pa, src)) {
 				error!("Could not save received peer address: {:?}", e);
 			}
 		}
@@ -192,34 +388,93 @@ impl NetAdapter for NetToChainAdapter {
 
 	/// Network successfully connected to a peer.
 	fn peer_connected(&self, pi: &p2p::PeerInfo) {
-		debug!("Saving newly connected peer {}.", pi.addr);
-		let peer = PeerData {
+		self.events.publish(Event::PeerConnected {
 			addr: pi.addr,
-			capabilities: pi.capabilities,
+			direction: pi.direction,
 			user_agent: pi.user_agent.clone(),
-			flags: State::Healthy,
-		};
-		if let Err(e) = self.peer_store.save_peer(&peer) {
-			error!("Could not save connected peer: {:?}", e);
+		});
+		// the p2p server records it in the peer store
+		debug!("Connected to peer {}.", pi.addr);
+	}
+
+	/// A peer misbehaved or its connection failed while running.
+	fn peer_error(&self, pi: &p2p::PeerInfo, err: &p2p::Error) {
+		warn!("Peer {} ({}) errored: {:?}", pi.addr, pi.user_agent, err);
+	}
+
+	/// We're not connected to the peer anymore.
+	fn peer_disconnected(&self, pi: &p2p::PeerInfo) {
+		debug!("Peer {} disconnected.", pi.addr);
+	}
+
+	/// Saves the ban or quarantine, for it to outlive a restart.
+	fn peer_banned(&self, addr: SocketAddr, ban: &p2p::BanEntry) {
+		let now = time::now_utc().to_timespec().sec;
+		if let Err(e) = self.peer_store.save_ban(&p2p::BanData::new(ban, now)) {
+			error!("Could not save the ban of {}: {:?}", ban.ip, e);
+		}
+		self.events.publish(Event::PeerBanned {
+			addr: addr,
+			severity: ban.severity,
+		});
+	}
+
+	fn peer_unbanned(&self, ip: IpAddr) {
+		if let Err(e) = self.peer_store.delete_ban(ip) {
+			error!("Could not delete the ban of {}: {:?}", ip, e);
 		}
 	}
+
+	/// No reputation source to check against yet, all addresses are allowed.
+	fn address_allowed(&self, addr: &SocketAddr) -> bool {
+		true
+	}
+
+	/// No discovery service either, our seeds are all we bootstrap from.
+	fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+		vec![]
+	}
 }
 
 impl NetToChainAdapter {
 	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
 	           chain_store: Arc<chain::ChainStore>,
 	           chain_adapter: Arc<ChainToNetAdapter>,
-	           peer_store: Arc<PeerStore>)
+	           peer_store: Arc<PeerStore>,
+	           max_gossip_addrs: u32,
+	           allow_private_addrs: bool,
+	           archive_mode: bool,
+	           tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	           events: Arc<Events>)
 	           -> NetToChainAdapter {
+		let orphans = chain::OrphanPool::new(MAX_ORPHANS, Duration::from_secs(MAX_ORPHAN_AGE_SECS));
 		NetToChainAdapter {
 			chain_head: chain_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
 			peer_store: peer_store,
+			max_gossip_addrs: cmp::min(max_gossip_addrs, p2p::MAX_PEER_ADDRS) as usize,
+			allow_private_addrs: allow_private_addrs,
+			checkpoints: Mutex::new(HashMap::new()),
+			orphans: Arc::new(Mutex::new(orphans)),
+			archive_mode: archive_mode,
+			utxo_snapshots: Arc::new(Mutex::new(UtxoSnapshots {
+				ready: vec![],
+				building: None,
+				last_build: None,
+			})),
+			tx_pool: tx_pool,
+			events: events,
 			syncer: OneTime::new(),
 		}
 	}
 
+	/// The pool of blocks received ahead of their parent, where the syncer
+	/// finds how many of its downloads wait.
+	pub fn orphans(&self) -> Arc<Mutex<chain::OrphanPool>> {
+		self.orphans.clone()
+	}
+
 	pub fn start_sync(&self, sync: sync::Syncer) {
 		let arc_sync = Arc::new(sync);
 		self.syncer.init(arc_sync.clone());
@@ -227,26 +482,254 @@ impl NetToChainAdapter {
 			arc_sync.run();
 		});
 	}
+
+	// The unspent outputs at the provided horizon when we have them already.
+	// Otherwise they start getting rebuilt on their own thread, unless we're
+	// rebuilding them at another horizon or just did.
+	fn utxo_snapshot(&self, horizon: Hash) -> Option<Arc<Vec<core::Output>>> {
+		let mut snapshots = self.utxo_snapshots.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(&(_, ref outputs)) = snapshots.ready.iter().find(|s| s.0 == horizon) {
+			return Some(outputs.clone());
+		}
+		let now = Instant::now();
+		let rebuilt_lately = snapshots.last_build
+			.map(|t| now.duration_since(t) < Duration::from_secs(SNAPSHOT_REBUILD_SECS))
+			.unwrap_or(false);
+		if snapshots.building.is_some() || rebuilt_lately {
+			return None;
+		}
+		let head_height = self.chain_head.lock().unwrap_or_else(|e| e.into_inner()).height;
+		match self.chain_store.get_block_header(&horizon) {
+			Ok(ref bh) if head_height.saturating_sub(bh.height) <= MAX_SNAPSHOT_DEPTH => {}
+			_ => return None,
+		}
+
+		snapshots.building = Some(horizon);
+		snapshots.last_build = Some(now);
+		let store = self.chain_store.clone();
+		let cache = self.utxo_snapshots.clone();
+		let res = thread::Builder::new().name("utxo-snapshot".to_string()).spawn(move || {
+			let res = chain::utxo_snapshot(horizon, store);
+			let mut snapshots = cache.lock().unwrap_or_else(|e| e.into_inner());
+			snapshots.building = None;
+			match res {
+				Ok(outputs) => {
+					if snapshots.ready.len() >= MAX_CACHED_SNAPSHOTS {
+						snapshots.ready.remove(0);
+					}
+					snapshots.ready.push((horizon, Arc::new(outputs)));
+				}
+				Err(e) => debug!("No unspent outputs to send at {}: {:?}", horizon, e),
+			}
+		});
+		if let Err(e) = res {
+			error!("Could not start rebuilding the unspent outputs: {:?}", e);
+			snapshots.building = None;
+		}
+		None
+	}
+
+	// Syncs the unspent outputs again at a horizon of the heavier fork our
+	// pruned chain can't be rewound to, unless we're syncing already.
+	fn resync_utxos(&self) {
+		let syncer = self.syncer.borrow().clone();
+		if syncer.resync_utxos() {
+			warn!("A heavier fork branches off below our horizon, syncing the unspent outputs \
+			       again.");
+			thread::Builder::new().name("syncer".to_string()).spawn(move || {
+				syncer.run();
+			});
+		}
+	}
+
+	// The hash most of our peers agreed on at the height of the header, when
+	// it's another one, so the chain going through the header gets rejected.
+	fn conflicting_checkpoint(&self, bh: &core::BlockHeader) -> Option<Hash> {
+		let agreed = self.checkpoints
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.get(&bh.height)
+			.cloned();
+		agreed.and_then(|h| if h != bh.hash() { Some(h) } else { None })
+	}
+
+	// Processes the blocks that were waiting for the provided one in the
+	// orphan pool, then those waiting for them.
+	fn process_descendants(&self, h: Hash) {
+		let mut parents = vec![h];
+		while let Some(parent) = parents.pop() {
+			let children =
+				self.orphans.lock().unwrap_or_else(|e| e.into_inner()).take_children(&parent);
+			for o in children {
+				if self.process_block(&o.block) == p2p::BlockStatus::Accepted {
+					parents.push(o.block.hash());
+				}
+			}
+		}
+	}
+
+	// Keeps an orphan block until its parent is in, telling the block we miss
+	// to connect it: its parent, or the first block missing below it when
+	// we keep the parent as an orphan too.
+	fn add_orphan(&self, b: core::Block, src: SocketAddr) -> p2p::BlockStatus {
+		let (h, prev) = (b.hash(), b.header.previous);
+		let mut orphans = self.orphans.lock().unwrap_or_else(|e| e.into_inner());
+		if orphans.add(b, src, Instant::now()) {
+			debug!("Keeping orphan block {}, {} in the pool.", h, orphans.len());
+		}
+		p2p::BlockStatus::Orphan(orphans.missing_ancestor(prev))
+	}
+
+	// Pushes a block through the chain pipeline.
+	fn process_block(&self, b: &core::Block) -> p2p::BlockStatus {
+		debug!("Processing block {} from network.", b.hash());
+
+		let store = self.chain_store.clone();
+		let chain_adapter = self.chain_adapter.clone();
+		let opts = if self.syncer.borrow().syncing() {
+			chain::SYNC
+		} else {
+			chain::NONE
+		};
+		let res = chain::process_block(b, store, chain_adapter, opts);
+
+		// a block we can't find a parent for is an orphan, one failing
+		// validation is the sender's fault, unlike our store failing or the
+		// block not fitting on our chain
+		let status = match res {
+			Err(chain::Error::Unfit(ref s)) if s == "orphan" => {
+				p2p::BlockStatus::Orphan(b.header.previous)
+			}
+			Err(chain::Error::Unfit(ref s)) if s == "already known" => {
+				p2p::BlockStatus::Accepted
+			}
+			Err(chain::Error::StoreErr(store::Error::NotFoundErr)) => {
+				p2p::BlockStatus::Orphan(b.header.previous)
+			}
+			Err(chain::Error::ForkBelowHorizon) => {
+				self.resync_utxos();
+				p2p::BlockStatus::Unprocessed
+			}
+			Err(chain::Error::Unfit(_)) |
+			Err(chain::Error::StoreErr(_)) |
+			Err(chain::Error::SerErr(_)) => p2p::BlockStatus::Unprocessed,
+			Ok(_) => p2p::BlockStatus::Accepted,
+			Err(_) => p2p::BlockStatus::Invalid,
+		};
+
+		// log errors and update the shared head reference on success
+		if let Err(e) = res {
+			debug!("Block {} refused by chain: {:?}", b.hash(), e);
+		} else if let Ok(Some(tip)) = res {
+			let chain_head = self.chain_head.clone();
+			let mut head = chain_head.lock().unwrap_or_else(|e| e.into_inner());
+			*head = tip;
+		}
+
+		if self.syncer.borrow().syncing() {
+			self.syncer.borrow().block_received(b.hash());
+		}
+		status
+	}
 }
 
 /// Implementation of the ChainAdapter for the network. Gets notified when the
 /// blockchain accepted a new block and forwards it to the network for
-/// broadcast.
+/// broadcast, taking the transactions it includes out of the pool.
 pub struct ChainToNetAdapter {
 	p2p: OneTime<Arc<Server>>,
+	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	events: Arc<Events>,
 }
 
 impl ChainAdapter for ChainToNetAdapter {
 	fn block_accepted(&self, b: &core::Block) {
-		self.p2p.borrow().broadcast_block(b);
+		self.tx_pool.write().unwrap_or_else(|e| e.into_inner()).reconcile_block(b);
+		self.p2p.borrow().broadcast_header(&b.header);
+		self.events.publish(Event::BlockAccepted {
+			hash: b.hash(),
+			height: b.header.height,
+			total_difficulty: b.header.total_difficulty.clone(),
+		});
+	}
+
+	fn chain_reorged(&self, rewound: &[core::Block], applied: &[core::Block]) {
+		// the transactions of the blocks rewound go back to the pool, oldest
+		// block first, before those of the fork take theirs out
+		{
+			let mut tx_pool = self.tx_pool.write().unwrap_or_else(|e| e.into_inner());
+			for b in rewound.iter().rev() {
+				tx_pool.rewind_block(b);
+			}
+			for b in applied {
+				tx_pool.reconcile_block(b);
+			}
+		}
+		self.events.publish(Event::ChainReorg {
+			rewound: rewound.iter().map(|b| b.hash()).collect(),
+			applied: applied.iter().map(|b| b.hash()).collect(),
+		});
 	}
 }
 
 impl ChainToNetAdapter {
-	pub fn new() -> ChainToNetAdapter {
-		ChainToNetAdapter { p2p: OneTime::new() }
+	pub fn new(tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	           events: Arc<Events>)
+	           -> ChainToNetAdapter {
+		ChainToNetAdapter {
+			p2p: OneTime::new(),
+			tx_pool: tx_pool,
+			events: events,
+		}
 	}
 	pub fn init(&self, p2p: Arc<Server>) {
 		self.p2p.init(p2p);
 	}
+
+	/// Relays a transaction the pool accepted to our peers.
+	pub fn tx_accepted(&self, tx: &core::Transaction) {
+		self.p2p.borrow().broadcast_transaction(tx);
+	}
+
+	/// Passes on along the dandelion stem a transaction stemmed to us that
+	/// the pool accepted.
+	pub fn tx_stemmed(&self, tx: &core::Transaction) {
+		self.p2p.borrow().relay_stem_transaction(tx);
+	}
+}
+
+/// Implementation of the pool's view of the chain over our chain store,
+/// telling which outputs transactions can spend.
+pub struct PoolToChainAdapter {
+	chain_store: Arc<chain::ChainStore>,
+}
+
+impl PoolToChainAdapter {
+	pub fn new(chain_store: Arc<chain::ChainStore>) -> PoolToChainAdapter {
+		PoolToChainAdapter { chain_store: chain_store }
+	}
+}
+
+impl pool::BlockChain for PoolToChainAdapter {
+	fn is_unspent(&self, commit: &Commitment) -> bool {
+		self.chain_store.is_unspent(commit).unwrap_or(false)
+	}
+}
+
+/// Publishes the transactions the pool accepts, whether they came from a
+/// peer, the API or a reorg putting them back.
+pub struct PoolToEventsAdapter {
+	events: Arc<Events>,
+}
+
+impl PoolToEventsAdapter {
+	pub fn new(events: Arc<Events>) -> PoolToEventsAdapter {
+		PoolToEventsAdapter { events: events }
+	}
+}
+
+impl pool::PoolAdapter for PoolToEventsAdapter {
+	fn tx_accepted(&self, tx: &core::Transaction) {
+		self.events.publish(Event::TxAccepted { hash: tx.hash() });
+	}
 }
@@ -0,0 +1,169 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server configuration read from a TOML file, anything left out of it
+//! keeping its default. For example, to run on a private network of our own
+//! with a genesis block of its own:
+//!
+//! ```toml
+//! network = "usernet"
+//! db_root = ".grin_usernet"
+//! seeds = ["10.0.0.2:23414"]
+//! # or, resolving seed host names with addresses to fall back to
+//! # dns_seeds = ["seed.example.com"]
+//! # fallback_seeds = ["10.0.0.2:23414"]
+//! webhooks = ["http://127.0.0.1:8080/grin-events"]
+//!
+//! [chain]
+//! magic = [30, 208]
+//! genesis_time = 1504224000
+//! ```
+
+use std::fs::File;
+use std::io::Read;
+
+use toml;
+
+use core::global::ChainTypes;
+use server::{Error, Seeding, ServerConfig};
+
+/// What a configuration file may hold, the network first.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+	/// Name of the network to run on, see ChainTypes::from_name.
+	network: Option<String>,
+	db_root: Option<String>,
+	api_http_addr: Option<String>,
+	/// Address the peer-to-peer server listens on, by default on the
+	/// network's port.
+	p2p_host: Option<String>,
+	p2p_port: Option<u16>,
+	/// Whether our p2p port gets mapped on our gateway, see
+	/// P2PConfig::port_mapping.
+	port_mapping: Option<bool>,
+	/// Addresses of the peers to connect to first.
+	seeds: Option<Vec<String>>,
+	/// Host names resolving to seed addresses, in place of seeds.
+	dns_seeds: Option<Vec<String>>,
+	/// Addresses to fall back to when none of the DNS seeds resolves.
+	fallback_seeds: Option<Vec<String>>,
+	archive_mode: Option<bool>,
+	wallet_data_dir: Option<String>,
+	/// URLs our events get posted to.
+	webhooks: Option<Vec<String>>,
+	/// Overrides of the parameters of the network.
+	chain: Option<ChainOverrides>,
+}
+
+/// Parameters of the network overridden, mostly for a private one, see
+/// ChainParams.
+#[derive(Debug, Deserialize)]
+struct ChainOverrides {
+	magic: Option<Vec<u8>>,
+	p2p_port: Option<u16>,
+	initial_difficulty: Option<u32>,
+	genesis_time: Option<i64>,
+}
+
+impl ServerConfig {
+	/// Reads the configuration from the TOML file at the provided path, see
+	/// from_toml.
+	pub fn from_toml_file(path: &str, network: Option<ChainTypes>) -> Result<ServerConfig, Error> {
+		let mut s = String::new();
+		File::open(path)
+			.and_then(|mut f| f.read_to_string(&mut s))
+			.map_err(|e| Error::ConfigErr(format!("could not read {}: {}", path, e)))?;
+		ServerConfig::from_toml(&s, network)
+	}
+
+	/// Parses a TOML configuration, starting from the defaults of the network
+	/// it selects, or of the provided one instead if any. The rest of the
+	/// configuration, overrides of the network parameters included, applies
+	/// on top either way.
+	pub fn from_toml(s: &str, network: Option<ChainTypes>) -> Result<ServerConfig, Error> {
+		let file: ConfigFile = toml::from_str(s)
+			.map_err(|e| Error::ConfigErr(format!("invalid configuration: {}", e)))?;
+
+		let mut config = ServerConfig::default();
+		let chain_type = match (network, file.network) {
+			(Some(chain_type), _) => Some(chain_type),
+			(None, Some(name)) => {
+				match ChainTypes::from_name(&name) {
+					Some(chain_type) => Some(chain_type),
+					None => return Err(Error::ConfigErr(format!("unknown network {}", name))),
+				}
+			}
+			(None, None) => None,
+		};
+		if let Some(chain_type) = chain_type {
+			config.set_network(chain_type);
+		}
+		if let Some(chain) = file.chain {
+			let params = &mut config.chain_params;
+			if let Some(magic) = chain.magic {
+				if magic.len() != 2 {
+					return Err(Error::ConfigErr(format!("magic takes 2 bytes, not {}",
+					                                    magic.len())));
+				}
+				params.magic = [magic[0], magic[1]];
+			}
+			if let Some(port) = chain.p2p_port {
+				params.p2p_port = port;
+				config.p2p_config.port = port;
+			}
+			if let Some(diff) = chain.initial_difficulty {
+				params.initial_difficulty = diff;
+			}
+			if let Some(t) = chain.genesis_time {
+				params.genesis_time = t;
+			}
+		}
+
+		if let Some(db_root) = file.db_root {
+			config.db_root = db_root;
+		}
+		if let Some(addr) = file.api_http_addr {
+			config.api_http_addr = addr;
+		}
+		if let Some(host) = file.p2p_host {
+			config.p2p_config.host = host.parse()
+				.map_err(|_| Error::ConfigErr(format!("invalid host {}", host)))?;
+		}
+		if let Some(port) = file.p2p_port {
+			config.p2p_config.port = port;
+		}
+		if let Some(mapping) = file.port_mapping {
+			config.p2p_config.port_mapping = mapping;
+		}
+		if let Some(seeds) = file.seeds {
+			config.seeding_type = Seeding::List(seeds);
+		}
+		if let Some(names) = file.dns_seeds {
+			config.seeding_type = Seeding::Dns(names);
+		}
+		if file.fallback_seeds.is_some() {
+			config.fallback_seeds = file.fallback_seeds;
+		}
+		if let Some(archive) = file.archive_mode {
+			config.archive_mode = archive;
+		}
+		if file.wallet_data_dir.is_some() {
+			config.wallet_data_dir = file.wallet_data_dir;
+		}
+		if let Some(urls) = file.webhooks {
+			config.webhook_urls = urls;
+		}
+		Ok(config)
+	}
+}
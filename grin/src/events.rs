@@ -0,0 +1,537 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notifications of what happens on our node, for monitoring and other tools
+//! to get pushed rather than polling the API. The adapters gluing the chain,
+//! the pool and the peer-to-peer server together publish the events, to the
+//! subscribers registered in process and to the configured webhooks, which
+//! get them posted as JSON.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, future};
+use hyper::{self, Method};
+use hyper::client::{HttpConnector, Request};
+use hyper::header::ContentType;
+use serde_json;
+use tokio_core::reactor;
+
+use core::core::hash::Hash;
+use core::core::target::Difficulty;
+use p2p;
+
+// Number of events waiting to be handed to the subscribers, or posted to the
+// webhooks, the oldest getting dropped past it.
+const MAX_QUEUED_EVENTS: usize = 1000;
+// Seconds a webhook gets to answer the post of an event.
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Something that happened on our node.
+#[derive(Debug, Clone)]
+pub enum Event {
+	/// The block got added to our chain, mined by us or received.
+	BlockAccepted {
+		hash: Hash,
+		height: u64,
+		total_difficulty: Difficulty,
+	},
+	/// Our chain switched to a fork with more work, rewinding blocks, our
+	/// previous head first, and applying those of the fork.
+	ChainReorg {
+		rewound: Vec<Hash>,
+		applied: Vec<Hash>,
+	},
+	/// The pool accepted the transaction, from a peer or submitted to us.
+	TxAccepted { hash: Hash },
+	/// We connected to a peer or it connected to us.
+	PeerConnected {
+		addr: SocketAddr,
+		direction: p2p::Direction,
+		user_agent: String,
+	},
+	/// The host of a peer got banned or quarantined.
+	PeerBanned {
+		addr: SocketAddr,
+		severity: p2p::Severity,
+	},
+}
+
+/// Gets notified of all the events published, on the thread the events get
+/// handed out on, so should return quickly.
+pub trait Subscriber: Send + Sync {
+	/// Something happened. Returns whether to keep notifying the subscriber,
+	/// which gets unsubscribed otherwise.
+	fn notify(&self, event: &Event) -> bool;
+}
+
+// A channel subscriber, handing the events to the receiving end until it gets
+// dropped.
+impl Subscriber for Mutex<mpsc::Sender<Event>> {
+	fn notify(&self, event: &Event) -> bool {
+		self.lock().unwrap_or_else(|e| e.into_inner()).send(event.clone()).is_ok()
+	}
+}
+
+/// Identifies a subscription, to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionId(usize);
+
+/// Publishes events to all the subscribers registered. The events get handed
+/// out on a thread of their own, so publishers holding locks neither wait on
+/// the subscribers nor deadlock with those reading what they hold.
+pub struct Events {
+	subscribers: Arc<RwLock<Vec<(SubscriptionId, Arc<Subscriber>)>>>,
+	next_id: AtomicUsize,
+	queue: Arc<Queue<Event>>,
+}
+
+impl Events {
+	/// Events with no subscriber yet.
+	pub fn new() -> Events {
+		let subscribers = Arc::new(RwLock::new(vec![]));
+		let queue = Arc::new(Queue::new(MAX_QUEUED_EVENTS));
+		let (subs, events) = (subscribers.clone(), queue.clone());
+		let res = thread::Builder::new().name("events".to_string()).spawn(move || {
+			while let Some(event) = events.pop() {
+				notify_all(&subs, &event);
+			}
+		});
+		if let Err(e) = res {
+			warn!("Could not start handing out events: {:?}", e);
+		}
+		Events {
+			subscribers: subscribers,
+			next_id: AtomicUsize::new(0),
+			queue: queue,
+		}
+	}
+
+	/// Registers a subscriber, notified of all events published from now on.
+	pub fn subscribe(&self, subscriber: Arc<Subscriber>) -> SubscriptionId {
+		let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::SeqCst));
+		self.subscribers.write().unwrap_or_else(|e| e.into_inner()).push((id, subscriber));
+		id
+	}
+
+	/// Cancels a subscription, its subscriber getting no more events.
+	pub fn unsubscribe(&self, id: SubscriptionId) {
+		let mut subscribers = self.subscribers.write().unwrap_or_else(|e| e.into_inner());
+		subscribers.retain(|&(sid, _)| sid != id);
+	}
+
+	/// Registers a channel, receiving all events published from now on.
+	/// Dropping the receiver unsubscribes it.
+	pub fn channel(&self) -> mpsc::Receiver<Event> {
+		let (tx, rx) = mpsc::channel();
+		self.subscribe(Arc::new(Mutex::new(tx)));
+		rx
+	}
+
+	/// Queues the event for all our subscribers, returning right away. The
+	/// oldest events queued get dropped if the subscribers fall too far
+	/// behind.
+	pub fn publish(&self, event: Event) {
+		if self.queue.push(event) {
+			warn!("Event subscribers falling behind, dropped the oldest event.");
+		}
+	}
+}
+
+impl Drop for Events {
+	fn drop(&mut self) {
+		self.queue.close();
+	}
+}
+
+// Notifies all the subscribers of the event, without holding the lock on
+// them, unsubscribing those done with events.
+fn notify_all(subscribers: &RwLock<Vec<(SubscriptionId, Arc<Subscriber>)>>, event: &Event) {
+	let current = subscribers.read().unwrap_or_else(|e| e.into_inner()).clone();
+	let done = current.iter()
+		.filter(|&&(_, ref s)| !s.notify(event))
+		.map(|&(id, _)| id)
+		.collect::<Vec<_>>();
+	if !done.is_empty() {
+		let mut subscribers = subscribers.write().unwrap_or_else(|e| e.into_inner());
+		subscribers.retain(|&(id, _)| !done.contains(&id));
+	}
+}
+
+/// Posts the events as JSON to the provided URLs, from a thread of its own
+/// so slow or unreachable webhooks don't hold up the node. A webhook gets a
+/// few seconds to answer, an event failing to post is logged and dropped,
+/// as are the oldest events queued when the webhooks fall too far behind.
+pub struct Webhooks {
+	queue: Arc<Queue<EventView>>,
+}
+
+impl Webhooks {
+	/// Starts posting to the provided URLs.
+	pub fn start(urls: Vec<String>) -> Webhooks {
+		Webhooks::start_with_timeout(urls, Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+	}
+
+	// Starts posting to the provided URLs, each post given the provided time
+	// to complete.
+	fn start_with_timeout(urls: Vec<String>, timeout: Duration) -> Webhooks {
+		let queue = Arc::new(Queue::new(MAX_QUEUED_EVENTS));
+		let views = queue.clone();
+		let res = thread::Builder::new().name("webhooks".to_string()).spawn(move || {
+			let mut evtlp = match reactor::Core::new() {
+				Ok(evtlp) => evtlp,
+				Err(e) => {
+					warn!("Could not start posting to webhooks: {:?}", e);
+					return;
+				}
+			};
+			let handle = evtlp.handle();
+			let client = hyper::Client::new(&handle);
+			while let Some(view) = views.pop() {
+				// posting to all webhooks at once, a hung one only holding up
+				// the others until it times out
+				let json = serde_json::to_string(&view).unwrap();
+				let posts = urls.iter()
+					.map(|url| {
+						let url = url.clone();
+						post(&handle, &client, &url, &json, timeout).then(move |res| {
+							if let Err(e) = res {
+								warn!("Failed to post event to webhook {}: {}", url, e);
+							}
+							Ok::<(), ()>(())
+						})
+					})
+					.collect::<Vec<_>>();
+				let _ = evtlp.run(future::join_all(posts));
+			}
+		});
+		if let Err(e) = res {
+			warn!("Could not start posting to webhooks: {:?}", e);
+		}
+		Webhooks { queue: queue }
+	}
+}
+
+impl Drop for Webhooks {
+	fn drop(&mut self) {
+		self.queue.close();
+	}
+}
+
+impl Subscriber for Webhooks {
+	fn notify(&self, event: &Event) -> bool {
+		if self.queue.push(EventView::from_event(event)) {
+			debug!("Webhooks falling behind, dropped the oldest event.");
+		}
+		true
+	}
+}
+
+// Posts the JSON to the URL, failing on anything but a success status or on
+// no answer in time.
+fn post(handle: &reactor::Handle,
+        client: &hyper::Client<HttpConnector>,
+        url: &str,
+        json: &str,
+        timeout: Duration)
+        -> Box<Future<Item = (), Error = String>> {
+	let uri = match hyper::Uri::from_str(url) {
+		Ok(uri) => uri,
+		Err(e) => return Box::new(future::err(format!("invalid url: {}", e))),
+	};
+	let timeout = match reactor::Timeout::new(timeout, handle) {
+		Ok(timeout) => timeout,
+		Err(e) => return Box::new(future::err(e.to_string())),
+	};
+	let mut req = Request::new(Method::Post, uri);
+	req.headers_mut().set(ContentType::json());
+	req.set_body(json.to_string());
+	let answer = client.request(req)
+		.map(|res| Some(res.status()))
+		.map_err(|e| e.to_string())
+		.select(timeout.map(|_| None).map_err(|e| e.to_string()));
+	Box::new(answer.then(|res| match res {
+		Ok((Some(status), _)) if status.is_success() => Ok(()),
+		Ok((Some(status), _)) => Err(format!("answered {}", status)),
+		Ok((None, _)) => Err("no answer in time".to_string()),
+		Err((e, _)) => Err(e),
+	}))
+}
+
+// Queue of a bounded length, the oldest item getting dropped for a new one
+// when full. Once closed, what's left can be taken before it runs dry.
+struct Queue<T> {
+	items: Mutex<(VecDeque<T>, bool)>,
+	ready: Condvar,
+	max_len: usize,
+}
+
+impl<T> Queue<T> {
+	fn new(max_len: usize) -> Queue<T> {
+		Queue {
+			items: Mutex::new((VecDeque::new(), false)),
+			ready: Condvar::new(),
+			max_len: max_len,
+		}
+	}
+
+	// Queues the item, returning whether the oldest one got dropped for it.
+	fn push(&self, item: T) -> bool {
+		let mut items = self.items.lock().unwrap_or_else(|e| e.into_inner());
+		let full = items.0.len() >= self.max_len;
+		if full {
+			items.0.pop_front();
+		}
+		items.0.push_back(item);
+		self.ready.notify_one();
+		full
+	}
+
+	// The oldest item, waiting for one if none is queued, none once closed
+	// and empty.
+	fn pop(&self) -> Option<T> {
+		let mut items = self.items.lock().unwrap_or_else(|e| e.into_inner());
+		loop {
+			if let Some(item) = items.0.pop_front() {
+				return Some(item);
+			}
+			if items.1 {
+				return None;
+			}
+			items = self.ready.wait(items).unwrap_or_else(|e| e.into_inner());
+		}
+	}
+
+	fn close(&self) {
+		self.items.lock().unwrap_or_else(|e| e.into_inner()).1 = true;
+		self.ready.notify_all();
+	}
+}
+
+/// JSON payload of an event, as posted to webhooks. Each event is an object
+/// with a single field named after its type, the hashes in hex.
+#[derive(Debug, Clone, Serialize)]
+pub enum EventView {
+	/// See Event::BlockAccepted.
+	BlockAccepted {
+		hash: String,
+		height: u64,
+		total_difficulty: Difficulty,
+	},
+	/// See Event::ChainReorg.
+	ChainReorg {
+		rewound: Vec<String>,
+		applied: Vec<String>,
+	},
+	/// See Event::TxAccepted.
+	TxAccepted { hash: String },
+	/// See Event::PeerConnected.
+	PeerConnected {
+		addr: String,
+		direction: String,
+		user_agent: String,
+	},
+	/// See Event::PeerBanned.
+	PeerBanned { addr: String, severity: String },
+}
+
+impl EventView {
+	/// View of the provided event.
+	pub fn from_event(event: &Event) -> EventView {
+		let hex = |hs: &Vec<Hash>| -> Vec<String> { hs.iter().map(|h| h.to_string()).collect() };
+		match *event {
+			Event::BlockAccepted { hash, height, ref total_difficulty } => {
+				EventView::BlockAccepted {
+					hash: hash.to_string(),
+					height: height,
+					total_difficulty: total_difficulty.clone(),
+				}
+			}
+			Event::ChainReorg { ref rewound, ref applied } => {
+				EventView::ChainReorg {
+					rewound: hex(rewound),
+					applied: hex(applied),
+				}
+			}
+			Event::TxAccepted { hash } => EventView::TxAccepted { hash: hash.to_string() },
+			Event::PeerConnected { addr, direction, ref user_agent } => {
+				EventView::PeerConnected {
+					addr: addr.to_string(),
+					direction: format!("{:?}", direction),
+					user_agent: user_agent.clone(),
+				}
+			}
+			Event::PeerBanned { addr, severity } => {
+				EventView::PeerBanned {
+					addr: addr.to_string(),
+					severity: format!("{:?}", severity),
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+	use std::sync::{Arc, Mutex, mpsc};
+	use std::thread;
+	use std::time::Duration;
+
+	use core::core::hash::ZERO_HASH;
+	use serde_json;
+
+	use super::*;
+
+	fn tx_event() -> Event {
+		Event::TxAccepted { hash: ZERO_HASH }
+	}
+
+	fn received(rx: &mpsc::Receiver<Event>) -> bool {
+		rx.recv_timeout(Duration::from_secs(5)).is_ok()
+	}
+
+	#[test]
+	fn channel_receives() {
+		let events = Events::new();
+		let rx = events.channel();
+		events.publish(tx_event());
+		match rx.recv_timeout(Duration::from_secs(5)) {
+			Ok(Event::TxAccepted { hash }) => assert_eq!(hash, ZERO_HASH),
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn unsubscribed() {
+		let events = Events::new();
+		let (tx, rx) = mpsc::channel();
+		let id = events.subscribe(Arc::new(Mutex::new(tx)));
+		let rx_kept = events.channel();
+		events.publish(tx_event());
+		assert!(received(&rx));
+		assert!(received(&rx_kept));
+
+		events.unsubscribe(id);
+		events.publish(tx_event());
+		assert!(received(&rx_kept));
+		assert!(rx.try_recv().is_err());
+	}
+
+	#[test]
+	fn dropped_receiver_unsubscribed() {
+		let events = Events::new();
+		drop(events.channel());
+		let rx = events.channel();
+		events.publish(tx_event());
+		assert!(received(&rx));
+		assert_eq!(events.subscribers.read().unwrap().len(), 1);
+	}
+
+	// A subscriber taking a lock the publisher holds.
+	struct Locking {
+		lock: Arc<Mutex<()>>,
+		done: Mutex<mpsc::Sender<()>>,
+	}
+
+	impl Subscriber for Locking {
+		fn notify(&self, _: &Event) -> bool {
+			let _guard = self.lock.lock().unwrap();
+			self.done.lock().unwrap().send(()).is_ok()
+		}
+	}
+
+	#[test]
+	fn publish_holding_lock() {
+		let events = Events::new();
+		let lock = Arc::new(Mutex::new(()));
+		let (tx, rx) = mpsc::channel();
+		events.subscribe(Arc::new(Locking {
+			lock: lock.clone(),
+			done: Mutex::new(tx),
+		}));
+		{
+			let _guard = lock.lock().unwrap();
+			events.publish(tx_event());
+			assert!(rx.try_recv().is_err());
+		}
+		assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+	}
+
+	#[test]
+	fn queue_drops_oldest() {
+		let queue = Queue::new(2);
+		assert!(!queue.push(1));
+		assert!(!queue.push(2));
+		assert!(queue.push(3));
+		queue.close();
+		assert_eq!(queue.pop(), Some(2));
+		assert_eq!(queue.pop(), Some(3));
+		assert_eq!(queue.pop(), None);
+	}
+
+	#[test]
+	fn event_view_json() {
+		let json = serde_json::to_string(&EventView::from_event(&tx_event())).unwrap();
+		assert_eq!(json, format!("{{\"TxAccepted\":{{\"hash\":\"{}\"}}}}", "0".repeat(64)));
+	}
+
+	#[test]
+	fn hung_webhook_times_out() {
+		// the first webhook never answers, the second one gets the events
+		// anyway, the next one once the first timed out
+		let hung = TcpListener::bind("127.0.0.1:0").unwrap();
+		let answering = TcpListener::bind("127.0.0.1:0").unwrap();
+		let urls = vec![format!("http://{}/", hung.local_addr().unwrap()),
+		                format!("http://{}/", answering.local_addr().unwrap())];
+		let (tx, rx) = mpsc::channel();
+		thread::spawn(move || {
+			let mut held = vec![];
+			for conn in hung.incoming() {
+				held.push(conn);
+			}
+		});
+		thread::spawn(move || for conn in answering.incoming() {
+			let mut conn = conn.unwrap();
+			// headers and body may come in separate reads
+			let mut req = String::new();
+			let mut buf = [0; 4096];
+			while !req.contains("}}") {
+				let n = conn.read(&mut buf).unwrap();
+				if n == 0 {
+					break;
+				}
+				req.push_str(&String::from_utf8_lossy(&buf[..n]));
+			}
+			conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+			tx.send(req).unwrap();
+		});
+
+		let webhooks = Webhooks::start_with_timeout(urls, Duration::from_millis(500));
+		webhooks.notify(&tx_event());
+		webhooks.notify(&tx_event());
+		for _ in 0..2 {
+			let req = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+			assert!(req.starts_with("POST / HTTP/1.1"));
+			assert!(req.contains("TxAccepted"));
+		}
+	}
+}
@@ -28,24 +28,37 @@ extern crate futures;
 extern crate futures_cpupool as cpupool;
 extern crate hyper;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 extern crate tokio_core;
 extern crate tokio_timer;
+extern crate toml;
 
 extern crate grin_api as api;
 extern crate grin_chain as chain;
 #[macro_use]
 extern crate grin_core as core;
 extern crate grin_p2p as p2p;
+extern crate grin_pool as pool;
 extern crate grin_store as store;
 extern crate grin_util as util;
+extern crate grin_wallet as wallet;
 extern crate secp256k1zkp as secp;
 
 mod adapters;
+mod config;
+mod events;
 mod miner;
 mod server;
 mod seed;
+mod stratum;
 mod sync;
 mod types;
 
-pub use server::{Server, ServerConfig, Seeding};
+pub use core::global::{ChainParams, ChainTypes};
+pub use events::{Event, EventView, Events, Subscriber, SubscriptionId, Webhooks};
+pub use server::{Server, ServerConfig, Seeding, Error};
+pub use stratum::StratumServerConfig;
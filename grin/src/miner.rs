@@ -16,35 +16,56 @@
 //! block and mine the block to produce a valid header with its proof-of-work.
 
 use rand::{self, Rng};
-use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
 use time;
 
-use adapters::ChainToNetAdapter;
+use adapters::{ChainToNetAdapter, PoolToChainAdapter};
 use core::consensus;
 use core::core;
 use core::core::hash::{Hash, Hashed};
 use core::pow::cuckoo;
 use chain;
+use pool;
 use secp;
+use secp::key::SecretKey;
+use secp::pedersen::Commitment;
+use wallet;
+
+// How many of the reward keys of the blocks we built last we keep, to find
+// the one of a block once mined and record it in our wallet.
+const MAX_REWARD_KEYS: usize = 32;
 
 pub struct Miner {
 	chain_head: Arc<Mutex<chain::Tip>>,
 	chain_store: Arc<chain::ChainStore>,
 	/// chain adapter to net
 	chain_adapter: Arc<ChainToNetAdapter>,
+	/// the pool we get the transactions to mine from
+	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	/// data directory of the wallet our rewards go to
+	wallet_data_dir: Option<String>,
+	/// reward output commitments of the last blocks we built, with their key
+	reward_keys: Mutex<VecDeque<(Commitment, SecretKey)>>,
 }
 
 impl Miner {
 	/// Creates a new Miner. Needs references to the chain state and its
-	/// storage.
+	/// storage, and to the transaction pool. The rewards of the blocks mined
+	/// get recorded in the wallet of the provided data directory, if any.
 	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
 	           chain_store: Arc<chain::ChainStore>,
-	           chain_adapter: Arc<ChainToNetAdapter>)
+	           chain_adapter: Arc<ChainToNetAdapter>,
+	           tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	           wallet_data_dir: Option<String>)
 	           -> Miner {
 		Miner {
 			chain_head: chain_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
+			tx_pool: tx_pool,
+			wallet_data_dir: wallet_data_dir,
+			reward_keys: Mutex::new(VecDeque::new()),
 		}
 	}
 
@@ -92,21 +113,86 @@ impl Miner {
 			if let Some(proof) = sol {
 				info!("Found valid proof of work, adding block {}.", b.hash());
 				b.header.pow = proof;
-				let res = chain::process_block(&b,
-				                               self.chain_store.clone(),
-				                               self.chain_adapter.clone(),
-				                               chain::NONE);
-				if let Err(e) = res {
-					error!("Error validating mined block: {:?}", e);
-				} else if let Ok(Some(tip)) = res {
+				self.add_mined_block(&b);
+			} else {
+				debug!("No solution found after {} iterations, continuing...",
+				       iter_count)
+			}
+		}
+	}
+
+	/// Hash of the block our chain currently ends with.
+	pub fn head_hash(&self) -> Hash {
+		self.chain_head.lock().unwrap().last_block_h
+	}
+
+	/// Builds the block to mine next, on top of our chain head.
+	pub fn next_block(&self) -> core::Block {
+		let head = self.chain_store.head_header().unwrap();
+		self.build_block(&head)
+	}
+
+	/// Adds a block we found the proof of work for to our chain, which has
+	/// the chain adapter broadcast it. Returns whether the chain took it.
+	pub fn add_mined_block(&self, b: &core::Block) -> bool {
+		let res = chain::process_block(b,
+		                               self.chain_store.clone(),
+		                               self.chain_adapter.clone(),
+		                               chain::NONE);
+		match res {
+			Err(e) => {
+				error!("Error validating mined block: {:?}", e);
+				false
+			}
+			Ok(Some(tip)) => {
+				{
 					let chain_head = self.chain_head.clone();
 					let mut head = chain_head.lock().unwrap();
 					*head = tip;
 				}
-			} else {
-				debug!("No solution found after {} iterations, continuing...",
-				       iter_count)
+				self.record_reward(b);
+				true
+			}
+			Ok(None) => {
+				self.record_reward(b);
+				true
+			}
+		}
+	}
+
+	// Records the reward output of a block we mined in our wallet, when we
+	// have one and still know the key of the output.
+	fn record_reward(&self, b: &core::Block) {
+		let dir = match self.wallet_data_dir {
+			Some(ref dir) => dir,
+			None => return,
+		};
+		let skey = {
+			let mut reward_keys = self.reward_keys.lock().unwrap();
+			let pos = reward_keys.iter()
+				.position(|&(commit, _)| b.outputs.iter().any(|out| out.commit == commit));
+			match pos.and_then(|pos| reward_keys.remove(pos)) {
+				Some((_, skey)) => skey,
+				None => {
+					warn!("No reward key for mined block {}, not recording it.", b.hash());
+					return;
+				}
 			}
+		};
+
+		let secp_inst = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let res = wallet::OutputData::new(&secp_inst,
+		                                  consensus::REWARD,
+		                                  skey,
+		                                  wallet::OutputStatus::Unconfirmed)
+			.and_then(|out| {
+				wallet::WalletData::with_wallet(dir, |wallet_data| {
+					wallet_data.append_output(out);
+					Ok(())
+				})
+			});
+		if let Err(e) = res {
+			error!("Could not record the reward of block {}: {:?}", b.hash(), e);
 		}
 	}
 
@@ -123,15 +209,38 @@ impl Miner {
 
 		let mut rng = rand::OsRng::new().unwrap();
 		let secp_inst = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-		// TODO get a new key from the user's wallet or something
-		let skey = secp::key::SecretKey::new(&secp_inst, &mut rng);
+		let skey = SecretKey::new(&secp_inst, &mut rng);
+		if self.wallet_data_dir.is_some() {
+			let commit = secp_inst.commit(consensus::REWARD, skey).unwrap();
+			let mut reward_keys = self.reward_keys.lock().unwrap();
+			if reward_keys.len() >= MAX_REWARD_KEYS {
+				reward_keys.pop_front();
+			}
+			reward_keys.push_back((commit, skey));
+		}
 
-		// TODO populate inputs and outputs from pool transactions
-		let mut b = core::Block::new(head, vec![], skey).unwrap();
+		// the best paying pool transactions, leaving room for our reward
+		let max_weight = consensus::MAX_BLOCK_WEIGHT - consensus::OUTPUT_WEIGHT -
+		                 consensus::KERNEL_WEIGHT;
+		let mut txs = self.tx_pool.read().unwrap().prepare_mineable_transactions(max_weight);
+		debug!("Building block with {} transactions from the pool.", txs.len());
+		let mut b = match core::Block::new(head, txs.iter_mut().collect(), skey) {
+			Ok(b) => b,
+			Err(e) => {
+				// the pool validated them, still not worth stopping mining for
+				warn!("Could not build a block with the pool transactions, mining without: {:?}",
+				      e);
+				core::Block::new(head, vec![], skey).unwrap()
+			}
+		};
 		b.header.nonce = rng.gen();
 		b.header.cuckoo_len = cuckoo_len;
 		b.header.difficulty = difficulty;
 		b.header.timestamp = time::at(time::Timespec::new(now_sec, 0));
+		match chain::utxo_root(&b, self.chain_store.clone()) {
+			Ok(root) => b.header.utxo_merkle = root,
+			Err(e) => warn!("Could not commit to the unspent outputs of our block: {:?}", e),
+		}
 		b
 	}
 }
@@ -12,9 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use rand::{thread_rng, Rng};
 use std::cmp::min;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::ops::Deref;
 use std::str::{self, FromStr};
 use std::sync::Arc;
@@ -26,7 +26,7 @@ use futures::{self, future, Future, Stream};
 use futures::sync::mpsc;
 use hyper;
 use tokio_core::reactor;
-use tokio_timer::Timer;
+use tokio_timer::{Timer, TimerError};
 
 use p2p;
 
@@ -34,11 +34,98 @@ const PEER_MAX_COUNT: u32 = 25;
 const PEER_PREFERRED_COUNT: u32 = 8;
 const GIST_SEEDS_URL: &'static str = "";
 
+// Connected peer count under which our DNS seeds get queried again.
+const PEER_MIN_COUNT: u32 = 4;
+// Seconds before querying our DNS seeds again.
+const RESEED_INTERVAL_SECS: u64 = 300;
+// Seconds the proxy has to answer each step of resolving a seed.
+const PROXY_RESOLVE_TIMEOUT_SECS: u64 = 30;
+
+/// Host names resolving to the addresses of seed peers, along with the seeds
+/// to fall back to when none resolves to anything.
+#[derive(Debug, Clone)]
+pub struct DnsSeeds {
+	/// Host names of the seeds, with a port or listening on the default one.
+	pub names: Vec<String>,
+	/// Port the seeds listen on when their name doesn't tell.
+	pub port: u16,
+	/// Addresses of the seeds used when no name resolves.
+	pub fallback: Vec<String>,
+	/// Proxy our connections go through, which then resolves the names as
+	/// well so our lookups don't give away our address.
+	pub proxy: Option<p2p::ProxyConfig>,
+}
+
+impl DnsSeeds {
+	/// Resolves the addresses of all the seeds on the provided pool, as
+	/// resolving blocks.
+	pub fn resolve(&self,
+	               pool: &cpupool::CpuPool)
+	               -> Box<Future<Item = Vec<SocketAddr>, Error = String>> {
+		let seeds = self.clone();
+		Box::new(pool.spawn_fn(move || Ok(seeds.resolve_now())))
+	}
+
+	/// Resolves the addresses of all the seeds, blocking until done. The
+	/// fallback seeds are returned instead if none resolves.
+	pub fn resolve_now(&self) -> Vec<SocketAddr> {
+		let mut addrs = vec![];
+		for name in &self.names {
+			let res = match self.proxy {
+				Some(ref proxy) => self.resolve_through(proxy, name),
+				None if name.contains(':') => name.to_socket_addrs().map(|a| a.collect::<Vec<_>>()),
+				None => (name.as_str(), self.port).to_socket_addrs().map(|a| a.collect::<Vec<_>>()),
+			};
+			match res {
+				Ok(resolved) => {
+					for addr in resolved {
+						if !addrs.contains(&addr) {
+							addrs.push(addr);
+						}
+					}
+				}
+				Err(e) => debug!("Could not resolve seed {}: {}", name, e),
+			}
+		}
+		if addrs.is_empty() {
+			info!("No DNS seed resolved, falling back to {} others.", self.fallback.len());
+			addrs = self.fallback.iter().filter_map(|s| p2p::parse_peer_addr(s)).collect();
+		}
+		addrs
+	}
+
+	// Resolves the seed name through the proxy, which only answers with one
+	// of its addresses. Those already IP ones need no lookup.
+	fn resolve_through(&self,
+	                   proxy: &p2p::ProxyConfig,
+	                   name: &str)
+	                   -> io::Result<Vec<SocketAddr>> {
+		let mut parts = name.rsplitn(2, ':');
+		let (host, port) = match (parts.next(), parts.next()) {
+			(Some(port), Some(host)) => {
+				let port = port.parse::<u16>()
+					.map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port"))?;
+				(host, port)
+			}
+			_ => (name, self.port),
+		};
+		if let Ok(ip) = host.parse::<IpAddr>() {
+			return Ok(vec![SocketAddr::new(ip, port)]);
+		}
+		let timeout = time::Duration::from_secs(PROXY_RESOLVE_TIMEOUT_SECS);
+		p2p::resolve_through_proxy(proxy, host, port, timeout).map(|addr| vec![addr])
+	}
+}
+
 pub struct Seeder {
 	peer_store: Arc<p2p::PeerStore>,
 	p2p: Arc<p2p::Server>,
 
 	capabilities: p2p::Capabilities,
+	dns_seeds: Option<DnsSeeds>,
+	// runs the db queries and DNS resolutions, so we don't block the event
+	// loop with them
+	pool: cpupool::CpuPool,
 }
 
 impl Seeder {
@@ -50,6 +137,21 @@ impl Seeder {
 			peer_store: peer_store,
 			p2p: p2p,
 			capabilities: capabilities,
+			dns_seeds: None,
+			pool: cpupool::CpuPool::new(1),
+		}
+	}
+
+	/// Queries the provided DNS seeds again whenever we run low on peers.
+	pub fn with_dns_seeds(self, seeds: DnsSeeds) -> Seeder {
+		Seeder { dns_seeds: Some(seeds), ..self }
+	}
+
+	/// Resolves our DNS seeds to bootstrap from, none if we have none.
+	pub fn dns_seed_list(&self) -> Box<Future<Item = Vec<SocketAddr>, Error = String>> {
+		match self.dns_seeds {
+			Some(ref seeds) => seeds.resolve(&self.pool),
+			None => Box::new(future::ok(vec![])),
 		}
 	}
 
@@ -61,80 +163,161 @@ impl Seeder {
 		let (tx, rx) = futures::sync::mpsc::unbounded();
 		h.spawn(self.listen_for_addrs(h.clone(), rx));
 
-		// check seeds and start monitoring connections
-		let seeder = self.connect_to_seeds(tx.clone(), seed_list)
-			.join(self.monitor_peers(tx.clone()));
-
-		h.spawn(seeder.map(|_| ()).map_err(|_| ()));
+		// check seeds and start monitoring connections, which keeps going
+		// without any seed to start from as it queries our DNS seeds again
+		h.spawn(self.connect_to_seeds(tx.clone(), seed_list).map_err(|e| {
+			warn!("Could not bootstrap: {}", e);
+			()
+		}));
+		h.spawn(self.monitor_peers(h.clone(), tx.clone()).map_err(|_| ()));
 	}
 
 	fn monitor_peers(&self,
+	                 h: reactor::Handle,
 	                 tx: mpsc::UnboundedSender<SocketAddr>)
 	                 -> Box<Future<Item = (), Error = String>> {
 		let peer_store = self.peer_store.clone();
 		let p2p_server = self.p2p.clone();
+		let dns_seeds = self.dns_seeds.clone();
+		let pool = self.pool.clone();
+		let mut last_reseed: Option<time::Instant> = None;
 
 		// now spawn a new future to regularly check if we need to acquire more peers
 		// and if so, gets them from db
 		let mon_loop = Timer::default()
 			.interval(time::Duration::from_secs(10))
-			.for_each(move |_| {
-
-				// maintenance step first, clean up p2p server peers and mark bans
-				// if needed
-				let disconnected = p2p_server.clean_peers();
-				for p in disconnected {
-					if p.is_banned() {
-						debug!("Marking peer {} as banned.", p.info.addr);
-						peer_store.update_state(p.info.addr, p2p::State::Banned);
+			.for_each(move |_| -> Box<Future<Item = (), Error = TimerError>> {
+
+				// maintenance step first, clean up p2p server peers, their bans
+				// being saved and lifted in time through the adapter
+				p2p_server.clean_peers();
+
+				// peers that stayed silent since connecting get pinged, only those
+				// that answered count
+				for p in p2p_server.connected_peers() {
+					if !p.is_verified() {
+						if let Err(e) = p.send_ping() {
+							debug!("Error pinging peer {}: {:?}", p.info.addr, e);
+						}
 					}
 				}
 
-				// we don't have enough peers, getting more from db
-				if p2p_server.peer_count() < PEER_PREFERRED_COUNT {
-					let mut peers = peer_store.find_peers(p2p::State::Healthy,
-					                                      p2p::UNKNOWN,
-					                                      (2 * PEER_MAX_COUNT) as usize);
-					debug!("Got {} more peers from db, trying to connect.", peers.len());
-					thread_rng().shuffle(&mut peers[..]);
-					let sz = min(PEER_PREFERRED_COUNT as usize, peers.len());
-					for p in &peers[0..sz] {
-						tx.send(p.addr).unwrap();
+				// we don't have enough peers, or they're in too few subnets, getting
+				// more from db
+				let short = p2p_server.verified_peer_count() < PEER_PREFERRED_COUNT;
+				let clustered = p2p_server.lacks_subnet_diversity();
+				if short || clustered {
+					// spread across the sources of the addresses to resist poisoning,
+					// sampling more than needed to find some in other subnets
+					let peers = peer_store.select_peers(p2p::State::Healthy,
+					                                    p2p::UNKNOWN,
+					                                    4 * PEER_PREFERRED_COUNT as usize);
+					let addrs = peers.iter().map(|p| p.addr).collect();
+					let (addrs, max) = if short {
+						(addrs, PEER_PREFERRED_COUNT as usize)
+					} else {
+						// only what the missing subnets need
+						(p2p_server.new_subnet_candidates(addrs), p2p_server.diversity_dials())
+					};
+					if clustered {
+						warn!("Outbound peers in only {} subnets, looking for more.",
+						      p2p_server.outbound_subnets());
+					}
+					// the most diverse first, each once the scheduler paced it
+					let plan = p2p_server.plan_dials(addrs, max);
+					debug!("Got {} more peers from db, trying to connect.", plan.len());
+					for action in plan {
+						match action {
+							p2p::DialAction::Dial { addr, delay } => {
+								send_after(&h, &tx, addr, delay);
+							}
+							p2p::DialAction::Backoff { addr, remaining } => {
+								debug!("Backing off from flaky peer {} for another {}s.",
+								       addr,
+								       remaining.as_secs());
+							}
+						}
 					}
 				}
-				Ok(())
+
+				// left with too few peers, our DNS seeds may know of new ones
+				let interval = time::Duration::from_secs(RESEED_INTERVAL_SECS);
+				let due = last_reseed.map(|t| t.elapsed() >= interval).unwrap_or(true);
+				let seeds = match dns_seeds {
+					Some(ref seeds) if due && p2p_server.peer_count() < PEER_MIN_COUNT => seeds,
+					_ => return Box::new(future::ok(())),
+				};
+				last_reseed = Some(time::Instant::now());
+				info!("Down to {} peers, querying our DNS seeds again.",
+				      p2p_server.peer_count());
+				let p2p_server = p2p_server.clone();
+				let tx = tx.clone();
+				Box::new(seeds.resolve(&pool).then(move |res| {
+					match res {
+						Ok(addrs) => {
+							for addr in p2p_server.dial_candidates(addrs) {
+								tx.send(addr).unwrap();
+							}
+						}
+						Err(e) => debug!("DNS seeds unavailable: {}", e),
+					}
+					Ok(())
+				}))
 			})
 			.map_err(|e| e.to_string());
 		Box::new(mon_loop)
 	}
 
 	// Check if we have any pre-existing peer in db. If so, start with those,
-	// otherwise use the seeds provided.
+	// the ones we did best with before first, and only fill the gaps with
+	// the seeds provided and those our adapter supplies.
 	fn connect_to_seeds(&self,
 	                    tx: mpsc::UnboundedSender<SocketAddr>,
 	                    seed_list: Box<Future<Item = Vec<SocketAddr>, Error = String>>)
 	                    -> Box<Future<Item = (), Error = String>> {
 		let peer_store = self.peer_store.clone();
+		let p2p_server = self.p2p.clone();
+		let known_server = self.p2p.clone();
 
-		// a thread pool is required so we don't block the event loop with a
-		// db query
-		let thread_pool = cpupool::CpuPool::new(1);
-		let seeder = thread_pool.spawn_fn(move || {
+		// on our pool so we don't block the event loop with a db query
+		let seeder = self.pool.spawn_fn(move || {
 				// check if we have some peers in db
-				Ok(peer_store.find_peers(p2p::State::Healthy,
+				Ok(peer_store.best_peers(p2p::State::Healthy,
 				                         p2p::FULL_HIST,
 				                         (2 * PEER_MAX_COUNT) as usize))
 			})
-			.and_then(|mut peers| {
-				// if so, get their addresses, otherwise use our seeds
-				if peers.len() > 0 {
-					thread_rng().shuffle(&mut peers[..]);
-					Box::new(future::ok(peers.iter().map(|p| p.addr).collect::<Vec<_>>()))
-				} else {
-					seed_list
+			.and_then(move |peers| -> Box<Future<Item = Vec<SocketAddr>, Error = String>> {
+				// if so, get their addresses ranked by how they did before, the
+				// peers we never tried in random order
+				let known = peers.iter().map(|p| p.addr).collect::<Vec<_>>();
+				if known.len() >= PEER_PREFERRED_COUNT as usize {
+					return Box::new(future::ok(known));
 				}
+				// short of known peers, our seeds and those the adapter supplies
+				// fill up what's left
+				Box::new(seed_list.then(move |res| {
+					let seeds = match res {
+						Ok(seeds) => seeds,
+						Err(e) => {
+							debug!("Seed list unavailable: {}", e);
+							vec![]
+						}
+					};
+					let mut addrs = known;
+					for addr in known_server.bootstrap_candidates(seeds) {
+						if !addrs.contains(&addr) {
+							addrs.push(addr);
+						}
+					}
+					if addrs.is_empty() {
+						return Err("No peer nor seed to bootstrap from.".to_string());
+					}
+					Ok(addrs)
+				}))
 			})
 			.and_then(move |peer_addrs| {
+				// dialing ourselves would only loop home
+				let peer_addrs = p2p_server.dial_candidates(peer_addrs);
 				// connect to this first set of addresses
 				let sz = min(PEER_PREFERRED_COUNT as usize, peer_addrs.len());
 				for addr in &peer_addrs[0..sz] {
@@ -147,9 +330,10 @@ impl Seeder {
 	}
 
 	/// Builds a future to continuously listen on a channel receiver for new
-	/// addresses to and initiate a connection if the max peer count isn't
-	/// exceeded. A request for more peers is also automatically sent after
-	/// connection.
+	/// addresses to and initiate a connection if the p2p server has an
+	/// outbound slot left, or the address makes a better peer than its worst
+	/// outbound one. A request for more peers is also automatically sent
+	/// after connection.
 	fn listen_for_addrs(&self,
 	                    h: reactor::Handle,
 	                    rx: mpsc::UnboundedReceiver<SocketAddr>)
@@ -157,14 +341,13 @@ impl Seeder {
 		let capab = self.capabilities;
 		let p2p_server = self.p2p.clone();
 
+		// dials run side by side, the p2p server bounds how many are in flight
 		let listener = rx.for_each(move |peer_addr| {
 			debug!("New peer address to connect to: {}.", peer_addr);
-			let inner_h = h.clone();
-			if p2p_server.peer_count() < PEER_MAX_COUNT {
-				connect_and_req(capab, p2p_server.clone(), inner_h, peer_addr)
-			} else {
-				Box::new(future::ok(()))
+			if p2p_server.outbound_slot_for(&peer_addr) {
+				h.spawn(connect_and_req(capab, p2p_server.clone(), h.clone(), peer_addr));
 			}
+			Ok(())
 		});
 		Box::new(listener)
 	}
@@ -207,10 +390,29 @@ pub fn gist_seeds(h: reactor::Handle) -> Box<Future<Item = Vec<SocketAddr>, Erro
 pub fn predefined_seeds(addrs_str: Vec<String>)
                         -> Box<Future<Item = Vec<SocketAddr>, Error = String>> {
 	let seeds = future::ok(())
-		.and_then(move |_| Ok(addrs_str.iter().map(|s| s.parse().unwrap()).collect::<Vec<_>>()));
+		.and_then(move |_| {
+			Ok(addrs_str.iter().map(|s| p2p::parse_peer_addr(s).unwrap()).collect::<Vec<_>>())
+		});
 	Box::new(seeds)
 }
 
+// Sends the address to dial once the delay passed.
+fn send_after(h: &reactor::Handle,
+              tx: &mpsc::UnboundedSender<SocketAddr>,
+              addr: SocketAddr,
+              delay: time::Duration) {
+	let tx = tx.clone();
+	match reactor::Timeout::new(delay, h) {
+		Ok(timeout) => {
+			h.spawn(timeout.then(move |_| {
+				let _ = tx.send(addr);
+				Ok(())
+			}))
+		}
+		Err(e) => debug!("Could not delay dialing {}: {:?}", addr, e),
+	}
+}
+
 fn connect_and_req(capab: p2p::Capabilities,
                    p2p: Arc<p2p::Server>,
                    h: reactor::Handle,
@@ -219,13 +421,104 @@ fn connect_and_req(capab: p2p::Capabilities,
 	let fut = p2p.connect_peer(addr, h)
 		.and_then(move |p| {
 			if let Some(p) = p {
-				p.send_peer_request(capab);
+				// while syncing, only gossip for addresses when short of peers
+				if !p2p.sync_mode() || p2p.peer_count() < PEER_PREFERRED_COUNT {
+					p.send_peer_request(capab);
+				}
 			}
 			Ok(())
 		})
-		.map_err(|e| {
-			error!("Peer request error {:?}", e);
+		.map_err(move |e| {
+			// resets are common and not worth more than a retry later on, running
+			// out of file descriptors got warned about once already
+			if e.is_transient() {
+				debug!("Peer request to {} interrupted: {:?}", addr, e);
+			} else if let p2p::Error::TooManyOpenFiles = e {
+				debug!("Not requesting peers from {}, out of file descriptors.", addr);
+			} else {
+				error!("Peer request error {:?}", e);
+			}
 			()
 		});
 	Box::new(fut)
 }
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Write};
+	use std::net::{self, SocketAddr};
+	use std::thread;
+
+	use cpupool;
+	use futures::Future;
+
+	use super::*;
+
+	fn seeds(names: Vec<&str>, fallback: Vec<&str>) -> DnsSeeds {
+		DnsSeeds {
+			names: names.iter().map(|s| s.to_string()).collect(),
+			port: 13414,
+			fallback: fallback.iter().map(|s| s.to_string()).collect(),
+			proxy: None,
+		}
+	}
+
+	#[test]
+	fn dns_seeds_resolved() {
+		// names taking our port unless they tell one, each address once
+		let resolved = seeds(vec!["127.0.0.1", "127.0.0.1:13414", "127.0.0.2:13500"],
+		                     vec!["10.0.0.1:13414"])
+			.resolve_now();
+		let expected: Vec<SocketAddr> = vec!["127.0.0.1:13414".parse().unwrap(),
+		                                     "127.0.0.2:13500".parse().unwrap()];
+		assert_eq!(resolved, expected);
+
+		// the same on a pool, which any number of resolves share
+		let pool = cpupool::CpuPool::new(1);
+		let dns = seeds(vec!["127.0.0.1"], vec![]);
+		for _ in 0..3 {
+			assert_eq!(dns.resolve(&pool).wait().unwrap(), expected[0..1].to_vec());
+		}
+	}
+
+	#[test]
+	fn dns_seeds_fall_back() {
+		// none resolving, only the valid fallback seeds are left
+		let fallback = vec!["10.0.0.1:13414", "not an address"];
+		let resolved = seeds(vec!["seed:notaport"], fallback.clone()).resolve_now();
+		assert_eq!(resolved, vec!["10.0.0.1:13414".parse::<SocketAddr>().unwrap()]);
+
+		// only used when nothing resolves
+		let resolved = seeds(vec!["seed:notaport", "127.0.0.1"], fallback).resolve_now();
+		assert_eq!(resolved, vec!["127.0.0.1:13414".parse::<SocketAddr>().unwrap()]);
+		assert!(seeds(vec![], vec![]).resolve_now().is_empty());
+	}
+
+	#[test]
+	fn dns_seeds_resolved_through_proxy() {
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let proxy_addr = listener.local_addr().unwrap();
+		let proxy = thread::spawn(move || {
+			let (mut conn, _) = listener.accept().unwrap();
+			let mut greeting = [0u8; 3];
+			conn.read_exact(&mut greeting).unwrap();
+			conn.write_all(&[5, 0]).unwrap();
+			let mut req = vec![0u8; 5 + "localhost".len() + 2];
+			conn.read_exact(&mut req).unwrap();
+			assert_eq!(&req[5..5 + "localhost".len()], b"localhost");
+			conn.write_all(&[5, 0, 0, 1, 10, 0, 0, 7, 0, 0]).unwrap();
+		});
+
+		// the proxy answering for the names, IP ones needing no lookup
+		let mut dns = seeds(vec!["localhost:13500", "127.0.0.2"], vec![]);
+		dns.proxy = Some(p2p::ProxyConfig {
+			addr: proxy_addr,
+			auth: None,
+			onion: None,
+		});
+		let expected: Vec<SocketAddr> = vec!["10.0.0.7:13500".parse().unwrap(),
+		                                     "127.0.0.2:13414".parse().unwrap()];
+		assert_eq!(dns.resolve_now(), expected);
+		proxy.join().unwrap();
+	}
+}
@@ -17,22 +17,28 @@
 //! as a facade.
 
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
 use futures::{future, Future};
 use tokio_core::reactor;
 
-use adapters::{NetToChainAdapter, ChainToNetAdapter};
+use adapters::{NetToChainAdapter, ChainToNetAdapter, PoolToChainAdapter,
+               PoolToEventsAdapter};
 use api;
 use chain;
 use chain::ChainStore;
 use core;
+use core::global::{ChainParams, ChainTypes};
+use events::{Events, Subscriber, SubscriptionId, Webhooks};
 use miner;
 use p2p;
+use pool;
 use seed;
 use store;
+use stratum;
 use sync;
+use time;
 
 /// Errors than can be reported by a server implementation, mostly wraps
 /// underlying components errors.
@@ -44,6 +50,8 @@ pub enum Error {
 	PeerErr(core::ser::Error),
 	/// Data store error
 	StoreErr(store::Error),
+	/// Configuration file that couldn't be read or parsed
+	ConfigErr(String),
 }
 
 impl From<store::Error> for Error {
@@ -61,6 +69,25 @@ pub enum Seeding {
 	List(Vec<String>),
 	/// Automatically download a gist with a list of server addresses
 	Gist,
+	/// Host names resolving to seed addresses, each name with a port or
+	/// using ours. The network's hardcoded seeds are used if none resolves.
+	Dns(Vec<String>),
+}
+
+// Seeds to fall back to when none of the DNS seeds resolves. None are known
+// to stay up yet, fallback_seeds in the configuration provides some
+// meanwhile.
+const MAINNET_SEEDS: &'static [&'static str] = &[];
+const TESTNET_SEEDS: &'static [&'static str] = &[];
+
+/// Addresses of the hardcoded seeds of the network, none for a private one.
+pub fn fallback_seeds(chain_type: ChainTypes) -> Vec<String> {
+	let seeds = match chain_type {
+		ChainTypes::Mainnet => MAINNET_SEEDS,
+		ChainTypes::Testnet => TESTNET_SEEDS,
+		ChainTypes::Usernet => &[],
+	};
+	seeds.iter().map(|s| s.to_string()).collect()
 }
 
 /// Full server configuration, aggregating configurations required for the
@@ -82,8 +109,36 @@ pub struct ServerConfig {
 
 	pub seeding_type: Seeding,
 
+	/// Addresses of the seeds to fall back to when none of our DNS seeds
+	/// resolves, instead of those hardcoded for the network
+	pub fallback_seeds: Option<Vec<String>>,
+
+	/// Network we run on and its parameters, the genesis block and magic
+	/// bytes among them, see set_network
+	pub chain_params: ChainParams,
+
 	/// Configuration for the peer-to-peer server
 	pub p2p_config: p2p::P2PConfig,
+
+	/// Configuration for the transaction pool
+	pub pool_config: pool::PoolConfig,
+
+	/// Configuration for the mining server external miners connect to, if
+	/// it runs
+	pub stratum_config: Option<stratum::StratumServerConfig>,
+
+	/// Data directory of the wallet the rewards of the blocks we mine go
+	/// to, forgotten when not set
+	pub wallet_data_dir: Option<String>,
+
+	/// Whether we download and keep every full block from genesis. A new
+	/// node not in archive mode only gets the header chain, the UTXO set at
+	/// a recent horizon block and the full blocks after it.
+	pub archive_mode: bool,
+
+	/// URLs the events of our node get posted to as JSON, see
+	/// events::EventView
+	pub webhook_urls: Vec<String>,
 }
 
 impl Default for ServerConfig {
@@ -94,11 +149,28 @@ impl Default for ServerConfig {
 			cuckoo_size: 0,
 			capabilities: p2p::FULL_NODE,
 			seeding_type: Seeding::None,
-			p2p_config: p2p::P2PConfig::default(),
+			fallback_seeds: None,
+			chain_params: ChainTypes::Testnet.params(),
+			// our transaction pool lets us rebuild compact blocks
+			p2p_config: p2p::P2PConfig { features: p2p::ALL_FEATURES, ..p2p::P2PConfig::default() },
+			pool_config: pool::PoolConfig::default(),
+			stratum_config: None,
+			wallet_data_dir: None,
+			archive_mode: true,
+			webhook_urls: vec![],
 		}
 	}
 }
 
+impl ServerConfig {
+	/// Switches to the provided network with its default parameters,
+	/// listening on its default port.
+	pub fn set_network(&mut self, chain_type: ChainTypes) {
+		self.chain_params = chain_type.params();
+		self.p2p_config.port = self.chain_params.p2p_port;
+	}
+}
+
 /// Grin server holding internal structures.
 pub struct Server {
 	pub config: ServerConfig,
@@ -112,6 +184,10 @@ pub struct Server {
 	/// chain adapter to net, required for miner and anything that submits
 	/// blocks
 	chain_adapter: Arc<ChainToNetAdapter>,
+	/// the transactions waiting to be mined
+	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	/// what happens on our node, published to subscribers
+	events: Arc<Events>,
 }
 
 impl Server {
@@ -125,19 +201,53 @@ impl Server {
 
 	/// Instantiates a new server associated with the provided future reactor.
 	pub fn future(config: ServerConfig, evt_handle: &reactor::Handle) -> Result<Server, Error> {
-		let (chain_store, head) = try!(store_head(&config));
+		info!("Running on {}: {:?}", config.chain_params.chain_type.name(), config.chain_params);
+		let genesis = genesis_block(&config);
+		let (chain_store, head) = try!(store_head(&config, &genesis));
 		let shared_head = Arc::new(Mutex::new(head));
 
 		let peer_store = Arc::new(p2p::PeerStore::new(config.db_root.clone())?);
 
-		let chain_adapter = Arc::new(ChainToNetAdapter::new());
+		let events = Arc::new(Events::new());
+		if !config.webhook_urls.is_empty() {
+			events.subscribe(Arc::new(Webhooks::start(config.webhook_urls.clone())));
+		}
+
+		let pool_adapter = Arc::new(PoolToChainAdapter::new(chain_store.clone()));
+		let pool_events = Arc::new(PoolToEventsAdapter::new(events.clone()));
+		let tx_pool = Arc::new(RwLock::new(pool::TransactionPool::new(config.pool_config.clone(),
+		                                                              pool_adapter,
+		                                                              pool_events)));
+
+		let chain_adapter = Arc::new(ChainToNetAdapter::new(tx_pool.clone(), events.clone()));
 		let net_adapter = Arc::new(NetToChainAdapter::new(shared_head.clone(),
 		                                                  chain_store.clone(),
 		                                                  chain_adapter.clone(),
-		                                                  peer_store.clone()));
-		let server =
-			Arc::new(p2p::Server::new(config.capabilities, config.p2p_config, net_adapter.clone()));
+		                                                  peer_store.clone(),
+		                                                  config.p2p_config.max_gossip_addrs,
+		                                                  config.p2p_config.allow_private_addrs,
+		                                                  config.archive_mode,
+		                                                  tx_pool.clone(),
+		                                                  events.clone()));
+		let mut p2p_config = config.p2p_config.clone();
+		p2p_config.magic = config.chain_params.magic;
+		p2p_config.genesis = genesis.hash();
+		// without all full blocks, we've got no full history to offer
+		let capabilities = if config.archive_mode {
+			config.capabilities
+		} else {
+			config.capabilities - p2p::FULL_HIST
+		};
+		let server = Arc::new(p2p::Server::new(capabilities, p2p_config, net_adapter.clone())
+			.with_peer_store(peer_store.clone()));
 		chain_adapter.init(server.clone());
+		restore_bans(&peer_store, &server);
+
+		if let Some(path) = config.p2p_config.control_socket.clone() {
+			if let Err(e) = p2p::start_control(server.clone(), path, evt_handle.remote().clone()) {
+				error!("Failed to start P2P control listener: {:?}", e);
+			}
+		}
 
 		let seed = seed::Seeder::new(config.capabilities, peer_store.clone(), server.clone());
 		match config.seeding_type.clone() {
@@ -146,16 +256,59 @@ impl Server {
 				seed.connect_and_monitor(evt_handle.clone(), seed::predefined_seeds(seeds));
 			}
 			Seeding::Gist => {
-				seed.connect_and_monitor(evt_handle.clone(), seed::gist_seeds(evt_handle.clone()));
+				// the gist would get fetched around our proxy, giving away our address
+				let seeds = if config.p2p_config.proxy.is_some() {
+					warn!("Not fetching the seeds gist with a proxy configured, use DNS seeds.");
+					seed::predefined_seeds(vec![])
+				} else {
+					seed::gist_seeds(evt_handle.clone())
+				};
+				seed.connect_and_monitor(evt_handle.clone(), seeds);
+			}
+			Seeding::Dns(names) => {
+				let fallback = config.fallback_seeds
+					.clone()
+					.unwrap_or_else(|| fallback_seeds(config.chain_params.chain_type));
+				if fallback.is_empty() {
+					warn!("No seeds to fall back to if no DNS seed resolves, see fallback_seeds.");
+				}
+				let seeds = seed::DnsSeeds {
+					names: names,
+					port: config.p2p_config.port,
+					fallback: fallback,
+					proxy: config.p2p_config.proxy.clone(),
+				};
+				let seed = seed.with_dns_seeds(seeds);
+				let resolved = seed.dns_seed_list();
+				seed.connect_and_monitor(evt_handle.clone(), resolved);
 			}
 		}
 
-		let sync = sync::Syncer::new(chain_store.clone(), server.clone());
+		let sync = sync::Syncer::new(chain_store.clone(),
+		                             server.clone(),
+		                             shared_head.clone(),
+		                             net_adapter.orphans(),
+		                             !config.archive_mode);
 		net_adapter.start_sync(sync);
 
 		evt_handle.spawn(server.start(evt_handle.clone()).map_err(|_| ()));
 
-		api::start_rest_apis(config.api_http_addr.clone(), chain_store.clone());
+		api::start_rest_apis(config.api_http_addr.clone(),
+		                     chain_store.clone(),
+		                     server.clone(),
+		                     tx_pool.clone());
+
+		if let Some(stratum_config) = config.stratum_config.clone() {
+			let miner = miner::Miner::new(shared_head.clone(),
+			                              chain_store.clone(),
+			                              chain_adapter.clone(),
+			                              tx_pool.clone(),
+			                              config.wallet_data_dir.clone());
+			let stratum = stratum::StratumServer::new(stratum_config, miner);
+			if let Err(e) = stratum.start() {
+				error!("Failed to start mining server: {:?}", e);
+			}
+		}
 
 		warn!("Grin server started.");
 		Ok(Server {
@@ -165,6 +318,8 @@ impl Server {
 			chain_head: shared_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
+			tx_pool: tx_pool,
+			events: events,
 		})
 	}
 
@@ -184,12 +339,24 @@ impl Server {
 	pub fn start_miner(&self) {
 		let miner = miner::Miner::new(self.chain_head.clone(),
 		                              self.chain_store.clone(),
-		                              self.chain_adapter.clone());
+		                              self.chain_adapter.clone(),
+		                              self.tx_pool.clone(),
+		                              self.config.wallet_data_dir.clone());
 		thread::spawn(move || {
 			miner.run_loop();
 		});
 	}
 
+	/// Registers a subscriber to the events of our node, see events::Event.
+	pub fn subscribe(&self, subscriber: Arc<Subscriber>) -> SubscriptionId {
+		self.events.subscribe(subscriber)
+	}
+
+	/// Cancels a subscription to the events of our node.
+	pub fn unsubscribe(&self, id: SubscriptionId) {
+		self.events.unsubscribe(id);
+	}
+
 	pub fn head(&self) -> chain::Tip {
 		let head = self.chain_head.clone();
 		let h = head.lock().unwrap();
@@ -197,9 +364,36 @@ impl Server {
 	}
 }
 
+// Genesis block of the network we run on, mined if we use our own cuckoo size.
+fn genesis_block(config: &ServerConfig) -> core::Block {
+	let mut gen = core::genesis::genesis_for(&config.chain_params);
+	if config.cuckoo_size > 0 {
+		gen.header.cuckoo_len = config.cuckoo_size;
+		let diff = gen.header.difficulty.clone();
+		core::pow::pow(&mut gen.header, diff).unwrap();
+	}
+	gen
+}
+
+// Restores the bans and quarantines we had before restarting, dropping those
+// that ran out in the meantime.
+fn restore_bans(peer_store: &p2p::PeerStore, server: &p2p::Server) {
+	let now = time::now_utc().to_timespec().sec;
+	for ban in peer_store.all_bans() {
+		if ban.expired(now) {
+			if let Err(e) = peer_store.delete_ban(ban.ip) {
+				error!("Could not delete the expired ban of {}: {:?}", ban.ip, e);
+			}
+		} else {
+			server.restore_ban(ban.ip, ban.severity, &ban.reason, ban.expires_in(now));
+		}
+	}
+}
+
 // Helper function to create the chain storage and check if it already has a
-// genesis block
-fn store_head(config: &ServerConfig)
+// genesis block, saving the provided one otherwise
+fn store_head(config: &ServerConfig,
+              gen: &core::Block)
               -> Result<(Arc<chain::store::ChainKVStore>, chain::Tip), Error> {
 	let chain_store = try!(chain::store::ChainKVStore::new(config.db_root.clone())
 		.map_err(&Error::StoreErr));
@@ -208,19 +402,20 @@ fn store_head(config: &ServerConfig)
 	let head = match chain_store.head() {
 		Ok(tip) => tip,
 		Err(store::Error::NotFoundErr) => {
-			debug!("No genesis block found, creating and saving one.");
-			let mut gen = core::genesis::genesis();
-			if config.cuckoo_size > 0 {
-				gen.header.cuckoo_len = config.cuckoo_size;
-				let diff = gen.header.difficulty.clone();
-				core::pow::pow(&mut gen.header, diff).unwrap();
-			}
-			try!(chain_store.save_block(&gen).map_err(&Error::StoreErr));
+			debug!("No genesis block found, saving ours.");
+			try!(chain_store.save_block(gen).map_err(&Error::StoreErr));
 			let tip = chain::types::Tip::new(gen.hash());
 			try!(chain_store.save_head(&tip).map_err(&Error::StoreErr));
 			tip
 		}
 		Err(e) => return Err(Error::StoreErr(e)),
 	};
-	Ok((Arc::new(chain_store), head))
+	let chain_store = Arc::new(chain_store);
+
+	// stores predating the unspent outputs index have to rebuild it
+	let rebuilt = try!(chain::rebuild_utxos(chain_store.clone()).map_err(&Error::ChainErr));
+	if rebuilt > 0 {
+		info!("Rebuilt {} unspent outputs up to our head.", rebuilt);
+	}
+	Ok((chain_store, head))
 }
@@ -0,0 +1,755 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mining server for external miners, speaking a stratum-like protocol of
+//! JSON-RPC messages, one per line, over TCP. A worker logs in and gets sent
+//! jobs: the header of the block to mine without its proof of work, along
+//! with the difficulty of the shares it should submit. Shares get checked
+//! against the worker's difficulty, adjusted so each worker submits one
+//! about every share interval, and a share reaching the block difficulty has
+//! its block added to our chain and broadcast.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+
+use core::consensus;
+use core::core::{self, BlockHeader, Proof};
+use core::core::hash::{Hash, HashWriter, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::pow::cuckoo::Cuckoo;
+use core::ser;
+use miner::Miner;
+use util::to_hex;
+
+// Recent jobs we still take shares for, as long as our chain head is the
+// same.
+const MAX_JOBS: usize = 8;
+
+// Interval at which we check whether jobs need to be rebuilt.
+const JOB_CHECK_MS: u64 = 500;
+
+// Shares a worker's difficulty gets adjusted after, or as many share
+// intervals without that many shares.
+const VARDIFF_SHARES: u32 = 10;
+
+// Seconds a write to a worker can take before we give up on the worker, so
+// one that stopped reading doesn't hold up the jobs of the others.
+const WRITE_TIMEOUT_SECS: u64 = 5;
+
+// Error codes, the JSON-RPC ones and ours.
+const PARSE_ERROR: i32 = -32700;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const NOT_LOGGED_IN: i32 = -32500;
+const INVALID_PROOF: i32 = -32501;
+const LOW_DIFFICULTY: i32 = -32502;
+const STALE_JOB: i32 = -32503;
+const NO_JOB: i32 = -32504;
+const DUPLICATE_SHARE: i32 = -32505;
+
+/// Configuration of the mining server.
+#[derive(Debug, Clone)]
+pub struct StratumServerConfig {
+	/// Address the mining server listens on for workers
+	pub addr: String,
+	/// Share difficulty workers start at, before it gets adjusted
+	pub initial_difficulty: u32,
+	/// Seconds between the shares of a worker its difficulty aims for
+	pub share_interval_secs: u64,
+	/// Seconds after which the job gets rebuilt with the latest pool
+	/// transactions, even if our chain head didn't move
+	pub job_refresh_secs: u64,
+}
+
+impl Default for StratumServerConfig {
+	fn default() -> StratumServerConfig {
+		StratumServerConfig {
+			addr: "127.0.0.1:13416".to_string(),
+			initial_difficulty: 1,
+			share_interval_secs: 10,
+			job_refresh_secs: 30,
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcRequest {
+	id: String,
+	method: String,
+	params: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcResponse {
+	id: String,
+	method: String,
+	result: Option<Value>,
+	error: Option<RpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcError {
+	code: i32,
+	message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LoginParams {
+	login: String,
+	agent: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SubmitParams {
+	job_id: u64,
+	nonce: u64,
+	pow: Vec<u32>,
+}
+
+/// A job for a worker: the header bytes the proof of work hashes, split
+/// around the nonce so the worker goes through nonces itself, the header
+/// hash being of pre_pow, the nonce as a big endian u64 and post_pow.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobTemplate {
+	/// Height of the block to mine
+	pub height: u64,
+	/// Id to submit the shares of the job with
+	pub job_id: u64,
+	/// Difficulty the shares of the worker have to reach
+	pub difficulty: u32,
+	/// Size of the Cuckoo Cycle graph to look for a cycle in
+	pub cuckoo_size: u8,
+	/// Hex of the header bytes before the nonce
+	pub pre_pow: String,
+	/// Hex of the header bytes after the nonce
+	pub post_pow: String,
+}
+
+// A block we hand out to workers to mine, with the header bytes around the
+// nonce and the nonces of the shares submitted for it.
+struct Job {
+	id: u64,
+	block: core::Block,
+	pre: Vec<u8>,
+	post: Vec<u8>,
+	shares: HashSet<u64>,
+}
+
+// What a share for a job gets checked against, copied out of the job so the
+// proof gets verified without holding on to the jobs.
+struct ShareTarget {
+	pre: Vec<u8>,
+	post: Vec<u8>,
+	cuckoo_len: u8,
+	difficulty: Difficulty,
+}
+
+// The jobs workers can submit shares for, the latest last, all building on
+// the same chain head.
+struct Jobs {
+	recent: VecDeque<Job>,
+	next_id: u64,
+	tip: Hash,
+	built_at: Instant,
+}
+
+impl Jobs {
+	// Adds a job mining the provided block, dropping those that would only
+	// make forks as well as the oldest ones. Returns the id of the job.
+	fn push(&mut self, b: core::Block) -> u64 {
+		if self.tip != b.header.previous {
+			self.recent.clear();
+		}
+		let id = self.next_id;
+		let (pre, post) = pre_pow(&b.header);
+		self.next_id += 1;
+		self.tip = b.header.previous;
+		self.built_at = Instant::now();
+		self.recent.push_back(Job {
+			id: id,
+			block: b,
+			pre: pre,
+			post: post,
+			shares: HashSet::new(),
+		});
+		while self.recent.len() > MAX_JOBS {
+			self.recent.pop_front();
+		}
+		id
+	}
+
+	// What a share with the provided nonce for the job gets checked against,
+	// unless the job is gone or already got a share with that nonce.
+	fn share_target(&self, job_id: u64, nonce: u64) -> Result<ShareTarget, RpcError> {
+		match self.recent.iter().find(|j| j.id == job_id) {
+			Some(job) if job.shares.contains(&nonce) => {
+				Err(rpc_error(DUPLICATE_SHARE, "duplicate share".to_string()))
+			}
+			Some(job) => {
+				Ok(ShareTarget {
+					pre: job.pre.clone(),
+					post: job.post.clone(),
+					cuckoo_len: job.block.header.cuckoo_len,
+					difficulty: job.block.header.difficulty.clone(),
+				})
+			}
+			None => Err(rpc_error(STALE_JOB, "stale job".to_string())),
+		}
+	}
+
+	// Records a verified share for the job, which is over and gives back
+	// its block with the share's proof of work when the share found it.
+	fn record_share(&mut self,
+	                job_id: u64,
+	                nonce: u64,
+	                pow: Proof,
+	                found: bool)
+	                -> Result<Option<core::Block>, RpcError> {
+		let pos = match self.recent.iter().position(|j| j.id == job_id) {
+			Some(pos) => pos,
+			None => return Err(rpc_error(STALE_JOB, "stale job".to_string())),
+		};
+		// another worker may have sent the same share meanwhile
+		if !self.recent[pos].shares.insert(nonce) {
+			return Err(rpc_error(DUPLICATE_SHARE, "duplicate share".to_string()));
+		}
+		if !found {
+			return Ok(None);
+		}
+		let mut b = self.recent.remove(pos).unwrap().block;
+		b.header.nonce = nonce;
+		b.header.pow = pow;
+		Ok(Some(b))
+	}
+}
+
+// Share difficulty of a worker and the shares it submitted at that
+// difficulty since when.
+struct Vardiff {
+	difficulty: u32,
+	shares: u32,
+	since: Instant,
+}
+
+impl Vardiff {
+	// Counts a share accepted at the provided time, retargeting the share
+	// difficulty every VARDIFF_SHARES shares, or once that many intervals
+	// passed, when the worker is more than twice too fast or too slow.
+	// Returns the new difficulty when it changed.
+	fn share(&mut self, interval: Duration, now: Instant) -> Option<u32> {
+		self.shares += 1;
+		let elapsed = now.duration_since(self.since);
+		if self.shares < VARDIFF_SHARES && elapsed < interval * VARDIFF_SHARES {
+			return None;
+		}
+		let expected = interval * self.shares;
+		let old = self.difficulty;
+		if elapsed < expected / 2 {
+			self.difficulty = self.difficulty.saturating_mul(2);
+		} else if elapsed > expected * 2 && self.difficulty > 1 {
+			self.difficulty /= 2;
+		}
+		self.shares = 0;
+		self.since = now;
+		if self.difficulty != old {
+			Some(self.difficulty)
+		} else {
+			None
+		}
+	}
+}
+
+// An external miner connected to us.
+struct Worker {
+	id: usize,
+	login: Mutex<Option<String>>,
+	stream: Mutex<TcpStream>,
+	vardiff: Mutex<Vardiff>,
+	accepted: AtomicUsize,
+	rejected: AtomicUsize,
+	connected: AtomicBool,
+}
+
+impl Worker {
+	fn difficulty(&self) -> u32 {
+		self.vardiff.lock().unwrap().difficulty
+	}
+
+	// Writes a message on its own line, failing the worker on error. A
+	// write that timed out may have left half a line, the worker gets
+	// disconnected.
+	fn send<T: ::serde::Serialize>(&self, msg: &T) -> io::Result<()> {
+		let mut line = serde_json::to_string(msg)
+			.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+		line.push('\n');
+		let mut stream = self.stream.lock().unwrap();
+		let res = stream.write_all(line.as_bytes());
+		if res.is_err() {
+			self.connected.store(false, Ordering::Relaxed);
+			let _ = stream.shutdown(Shutdown::Both);
+		}
+		res
+	}
+
+	// Counts an accepted share, returning the new difficulty of the worker
+	// when it got retargeted.
+	fn share_accepted(&self, interval: Duration) -> Option<u32> {
+		self.accepted.fetch_add(1, Ordering::Relaxed);
+		self.vardiff.lock().unwrap().share(interval, Instant::now())
+	}
+}
+
+/// Mining server handing out jobs built from our chain head and the
+/// transaction pool to external miners.
+pub struct StratumServer {
+	config: StratumServerConfig,
+	miner: Miner,
+	jobs: Mutex<Jobs>,
+	workers: Mutex<Vec<Arc<Worker>>>,
+	next_worker: AtomicUsize,
+}
+
+impl StratumServer {
+	/// Creates a mining server building its jobs with the provided miner.
+	pub fn new(config: StratumServerConfig, miner: Miner) -> StratumServer {
+		StratumServer {
+			config: config,
+			miner: miner,
+			jobs: Mutex::new(Jobs {
+				recent: VecDeque::new(),
+				next_id: 0,
+				tip: ZERO_HASH,
+				built_at: Instant::now(),
+			}),
+			workers: Mutex::new(vec![]),
+			next_worker: AtomicUsize::new(0),
+		}
+	}
+
+	/// Listens for workers on the configured address and keeps their jobs
+	/// up to date, on threads of their own.
+	pub fn start(self) -> io::Result<()> {
+		let listener = TcpListener::bind(self.config.addr.as_str())?;
+		info!("Mining server listening on {}.", self.config.addr);
+		let server = Arc::new(self);
+
+		let jobs_server = server.clone();
+		thread::Builder::new().name("stratum-jobs".to_string()).spawn(move || {
+				jobs_server.refresh_jobs();
+			})?;
+		thread::Builder::new().name("stratum".to_string()).spawn(move || {
+			for stream in listener.incoming() {
+				match stream {
+					Ok(stream) => {
+						if let Err(e) = accept(server.clone(), stream) {
+							warn!("Could not accept worker: {:?}", e);
+						}
+					}
+					Err(e) => warn!("Mining server accept failed: {:?}", e),
+				}
+			}
+		})?;
+		Ok(())
+	}
+
+	// Reads the requests of a worker until it disconnects, answering each.
+	fn handle_worker(&self, worker: Arc<Worker>, stream: TcpStream) {
+		for line in BufReader::new(stream).lines() {
+			let line = match line {
+				Ok(line) => line,
+				Err(_) => break,
+			};
+			if line.trim().is_empty() {
+				continue;
+			}
+			let (resp, push_job) = match serde_json::from_str::<RpcRequest>(&line) {
+				Ok(req) => self.handle_request(&worker, req),
+				Err(e) => {
+					let err = rpc_error(PARSE_ERROR, format!("{}", e));
+					(response(String::new(), String::new(), Err(err)), false)
+				}
+			};
+			if worker.send(&resp).is_err() {
+				break;
+			}
+			if push_job {
+				self.send_job(&worker);
+			}
+		}
+		worker.connected.store(false, Ordering::Relaxed);
+		self.workers.lock().unwrap().retain(|w| w.id != worker.id);
+		debug!("Worker {} disconnected, {} shares accepted, {} rejected.",
+		       worker.id,
+		       worker.accepted.load(Ordering::Relaxed),
+		       worker.rejected.load(Ordering::Relaxed));
+	}
+
+	// Answers a request of a worker, telling whether it should get sent the
+	// latest job right after.
+	fn handle_request(&self, worker: &Worker, req: RpcRequest) -> (RpcResponse, bool) {
+		let logged_in = worker.login.lock().unwrap().is_some();
+		let (res, push_job) = match req.method.as_str() {
+			"login" => {
+				match params::<LoginParams>(req.params) {
+					Ok(p) => {
+						info!("Worker {} logged in as {} ({}).", worker.id, p.login, p.agent);
+						*worker.login.lock().unwrap() = Some(p.login);
+						(Ok(Value::String("ok".to_string())), true)
+					}
+					Err(e) => (Err(e), false),
+				}
+			}
+			"keepalive" => (Ok(Value::String("ok".to_string())), false),
+			_ if !logged_in => (Err(rpc_error(NOT_LOGGED_IN, "login first".to_string())), false),
+			"getjobtemplate" => {
+				match self.job_template(worker) {
+					Some(t) => (to_value(t), false),
+					None => (Err(rpc_error(NO_JOB, "no job yet".to_string())), false),
+				}
+			}
+			"submit" => {
+				match params::<SubmitParams>(req.params).and_then(|p| self.submit(worker, p)) {
+					Ok(retargeted) => (Ok(Value::String("ok".to_string())), retargeted),
+					Err(e) => {
+						worker.rejected.fetch_add(1, Ordering::Relaxed);
+						(Err(e), false)
+					}
+				}
+			}
+			_ => (Err(rpc_error(METHOD_NOT_FOUND, req.method.clone())), false),
+		};
+		(response(req.id, req.method, res), push_job)
+	}
+
+	// Checks a share against the job it's for and the worker's difficulty,
+	// adding the block to our chain if the share reaches the block
+	// difficulty. Tells whether the worker's difficulty changed.
+	fn submit(&self, worker: &Worker, p: SubmitParams) -> Result<bool, RpcError> {
+		if p.pow.len() != consensus::PROOFSIZE {
+			return Err(rpc_error(INVALID_PARAMS, "wrong proof size".to_string()));
+		}
+		let mut pow = [0u32; consensus::PROOFSIZE];
+		pow.copy_from_slice(&p.pow);
+		let pow = Proof(pow);
+
+		// the proof is verified without holding up the other workers
+		let target = self.jobs.lock().unwrap().share_target(p.job_id, p.nonce)?;
+		let min = Difficulty::from_num(worker.difficulty());
+		let found = check_share(&target, p.nonce, pow, &min)?;
+		let mined = self.jobs.lock().unwrap().record_share(p.job_id, p.nonce, pow, found)?;
+		if let Some(b) = mined {
+			let h = b.hash();
+			info!("Worker {} found block {} at {}.", worker.id, h, b.header.height);
+			if !self.miner.add_mined_block(&b) {
+				warn!("Block {} found by worker {} refused by the chain.", h, worker.id);
+			}
+		}
+		let interval = Duration::from_secs(self.config.share_interval_secs);
+		Ok(worker.share_accepted(interval).is_some())
+	}
+
+	// Rebuilds the job whenever our chain head moves or the job gets too old
+	// to have the latest pool transactions, pushing it to all workers.
+	fn refresh_jobs(&self) {
+		let refresh = Duration::from_secs(self.config.job_refresh_secs);
+		loop {
+			let head = self.miner.head_hash();
+			let (moved, stale) = {
+				let jobs = self.jobs.lock().unwrap();
+				(jobs.tip != head || jobs.recent.is_empty(), jobs.built_at.elapsed() >= refresh)
+			};
+			if moved || stale {
+				let b = self.miner.next_block();
+				let height = b.header.height;
+				let id = self.jobs.lock().unwrap().push(b);
+				debug!("New mining job {} at {}.", id, height);
+				let workers = self.workers.lock().unwrap().clone();
+				for w in workers {
+					if w.connected.load(Ordering::Relaxed) && w.login.lock().unwrap().is_some() {
+						self.send_job(&w);
+					}
+				}
+			}
+			thread::sleep(Duration::from_millis(JOB_CHECK_MS));
+		}
+	}
+
+	// The latest job, at the worker's difficulty.
+	fn job_template(&self, worker: &Worker) -> Option<JobTemplate> {
+		let jobs = self.jobs.lock().unwrap();
+		jobs.recent.back().map(|job| {
+			JobTemplate {
+				height: job.block.header.height,
+				job_id: job.id,
+				difficulty: worker.difficulty(),
+				cuckoo_size: job.block.header.cuckoo_len,
+				pre_pow: to_hex(&job.pre),
+				post_pow: to_hex(&job.post),
+			}
+		})
+	}
+
+	// Notifies the worker of the latest job, if there's one.
+	fn send_job(&self, worker: &Worker) {
+		if let Some(t) = self.job_template(worker) {
+			if let Ok(params) = serde_json::to_value(t) {
+				let notif = RpcRequest {
+					id: "Stratum".to_string(),
+					method: "job".to_string(),
+					params: Some(params),
+				};
+				if let Err(e) = worker.send(&notif) {
+					debug!("Could not send job to worker {}: {:?}", worker.id, e);
+				}
+			}
+		}
+	}
+}
+
+// Registers a worker that just connected, reading its requests on a thread
+// of its own.
+fn accept(server: Arc<StratumServer>, stream: TcpStream) -> io::Result<()> {
+	stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
+	let reader = stream.try_clone()?;
+	let id = server.next_worker.fetch_add(1, Ordering::Relaxed);
+	debug!("Worker {} connected from {:?}.", id, stream.peer_addr());
+	let worker = Arc::new(Worker {
+		id: id,
+		login: Mutex::new(None),
+		stream: Mutex::new(stream),
+		vardiff: Mutex::new(Vardiff {
+			difficulty: server.config.initial_difficulty,
+			shares: 0,
+			since: Instant::now(),
+		}),
+		accepted: AtomicUsize::new(0),
+		rejected: AtomicUsize::new(0),
+		connected: AtomicBool::new(true),
+	});
+	server.workers.lock().unwrap().push(worker.clone());
+	thread::Builder::new().name(format!("stratum-worker-{}", id)).spawn(move || {
+			server.handle_worker(worker, reader);
+		})?;
+	Ok(())
+}
+
+// Checks the proof of work of a share against its target and the provided
+// minimum difficulty, telling whether it also reaches the block difficulty.
+fn check_share(target: &ShareTarget,
+               nonce: u64,
+               pow: Proof,
+               min: &Difficulty)
+               -> Result<bool, RpcError> {
+	let h = pow_hash(&target.pre, nonce, &target.post);
+	let cuckoo = Cuckoo::new(&h[..], target.cuckoo_len as u32);
+	if !cuckoo.verify(pow, consensus::EASINESS as u64) {
+		return Err(rpc_error(INVALID_PROOF, "invalid proof".to_string()));
+	}
+	let share_diff = pow.to_difficulty();
+	if share_diff < *min {
+		return Err(rpc_error(LOW_DIFFICULTY, "share below difficulty".to_string()));
+	}
+	Ok(share_diff >= target.difficulty)
+}
+
+// The header hash the proof of work is for, from the bytes around the nonce.
+fn pow_hash(pre: &[u8], nonce: u64, post: &[u8]) -> Hash {
+	let mut hasher = HashWriter::default();
+	ser::Writer::write_fixed_bytes(&mut hasher, &pre).unwrap();
+	ser::Writer::write_u64(&mut hasher, nonce).unwrap();
+	ser::Writer::write_fixed_bytes(&mut hasher, &post).unwrap();
+	hasher.into_hash()
+}
+
+// Writer collecting the header bytes the proof of work hashes.
+struct HashedBytes(Vec<u8>);
+
+impl ser::Writer for HashedBytes {
+	fn serialization_mode(&self) -> ser::SerializationMode {
+		ser::SerializationMode::Hash
+	}
+
+	fn write_fixed_bytes<T: ser::AsFixedBytes>(&mut self, fixed: &T) -> Result<(), ser::Error> {
+		self.0.extend_from_slice(fixed.as_ref());
+		Ok(())
+	}
+}
+
+// The header bytes the proof of work hashes split around the nonce, found
+// as the bytes changing with it.
+fn pre_pow(bh: &BlockHeader) -> (Vec<u8>, Vec<u8>) {
+	let mut flipped = ser::ser_vec(bh)
+		.and_then(|data| ser::deserialize::<BlockHeader>(&mut &data[..]))
+		.unwrap();
+	flipped.nonce = !bh.nonce;
+	let (data, other) = (hashed_bytes(bh), hashed_bytes(&flipped));
+	let at = data.iter().zip(other.iter()).position(|(a, b)| a != b).unwrap();
+	(data[..at].to_vec(), data[at + 8..].to_vec())
+}
+
+fn hashed_bytes(bh: &BlockHeader) -> Vec<u8> {
+	let mut bytes = HashedBytes(vec![]);
+	ser::Writeable::write(bh, &mut bytes).unwrap();
+	bytes.0
+}
+
+fn params<T: ::serde::Deserialize>(params: Option<Value>) -> Result<T, RpcError> {
+	let params = params.unwrap_or(Value::Null);
+	serde_json::from_value(params).map_err(|e| rpc_error(INVALID_PARAMS, format!("{}", e)))
+}
+
+fn to_value<T: ::serde::Serialize>(v: T) -> Result<Value, RpcError> {
+	serde_json::to_value(v).map_err(|e| rpc_error(INVALID_PARAMS, format!("{}", e)))
+}
+
+fn rpc_error(code: i32, message: String) -> RpcError {
+	RpcError {
+		code: code,
+		message: message,
+	}
+}
+
+fn response(id: String, method: String, res: Result<Value, RpcError>) -> RpcResponse {
+	let (result, error) = match res {
+		Ok(v) => (Some(v), None),
+		Err(e) => (None, Some(e)),
+	};
+	RpcResponse {
+		id: id,
+		method: method,
+		result: result,
+		error: error,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::{Duration, Instant};
+
+	use core::core::{self, Proof};
+	use core::core::hash::{Hashed, ZERO_HASH};
+	use core::core::target::Difficulty;
+	use core::pow;
+	use super::*;
+
+	fn jobs() -> Jobs {
+		Jobs {
+			recent: VecDeque::new(),
+			next_id: 0,
+			tip: ZERO_HASH,
+			built_at: Instant::now(),
+		}
+	}
+
+	#[test]
+	fn pre_pow_hashed() {
+		let mut b = core::Block::default();
+		b.header.height = 12;
+		b.header.nonce = 0x0102030405060708;
+		b.header.difficulty = Difficulty::from_num(300);
+		let (pre, post) = pre_pow(&b.header);
+		assert_eq!(pow_hash(&pre, b.header.nonce, &post), b.header.hash());
+
+		// workers going through nonces hash the same header
+		b.header.nonce = 42;
+		assert_eq!(pre_pow(&b.header), (pre.clone(), post.clone()));
+		assert_eq!(pow_hash(&pre, 42, &post), b.header.hash());
+	}
+
+	#[test]
+	fn shares_checked() {
+		let mut b = core::Block::default();
+		pow::pow20(&mut b.header, Difficulty::one()).unwrap();
+		let (nonce, proof) = (b.header.nonce, b.header.pow);
+		let mut jobs = jobs();
+		let id = jobs.push(b);
+		let one = Difficulty::one();
+
+		// a share for a job we don't have anymore
+		let stale = jobs.share_target(id + 1, nonce).err().map(|e| e.code);
+		assert_eq!(stale, Some(STALE_JOB));
+
+		// a proof that doesn't go with the nonce, or below the worker's
+		// difficulty
+		let target = jobs.share_target(id, nonce).unwrap();
+		let invalid = check_share(&target, nonce + 1, proof, &one).err().map(|e| e.code);
+		assert_eq!(invalid, Some(INVALID_PROOF));
+		let high = Difficulty::from_num(u32::max_value());
+		let low = check_share(&target, nonce, proof, &high).err().map(|e| e.code);
+		assert_eq!(low, Some(LOW_DIFFICULTY));
+		let zero = Proof([0; consensus::PROOFSIZE]);
+		let invalid = check_share(&target, nonce, zero, &one).err().map(|e| e.code);
+		assert_eq!(invalid, Some(INVALID_PROOF));
+
+		// a share at the worker's difficulty, not yet reaching the block's
+		let target = ShareTarget { difficulty: Difficulty::from_num(u32::max_value()), ..target };
+		assert_eq!(check_share(&target, nonce, proof, &one).unwrap(), false);
+		assert!(jobs.record_share(id, nonce, proof, false).unwrap().is_none());
+
+		// sent again, it's turned down
+		let dup = jobs.share_target(id, nonce).err().map(|e| e.code);
+		assert_eq!(dup, Some(DUPLICATE_SHARE));
+		let dup = jobs.record_share(id, nonce, proof, false).err().map(|e| e.code);
+		assert_eq!(dup, Some(DUPLICATE_SHARE));
+
+		// the share finding the block ends the job
+		let mut other = core::Block::default();
+		pow::pow20(&mut other.header, Difficulty::one()).unwrap();
+		let (nonce, proof) = (other.header.nonce, other.header.pow);
+		let h = other.header.hash();
+		let id = jobs.push(core::Block::default());
+		let target = jobs.share_target(id, nonce).unwrap();
+		assert!(check_share(&target, nonce, proof, &one).unwrap());
+		let mined = jobs.record_share(id, nonce, proof, true).unwrap().unwrap();
+		assert_eq!(mined.hash(), h);
+		assert!(pow::verify(&mined.header));
+		let gone = jobs.share_target(id, nonce + 1).err().map(|e| e.code);
+		assert_eq!(gone, Some(STALE_JOB));
+	}
+
+	#[test]
+	fn vardiff_retargets() {
+		let start = Instant::now();
+		let interval = Duration::from_secs(10);
+		let mut v = Vardiff {
+			difficulty: 4,
+			shares: 0,
+			since: start,
+		};
+
+		// at the expected pace nothing changes
+		for n in 1..VARDIFF_SHARES + 1 {
+			assert_eq!(v.share(interval, start + interval * n), None);
+		}
+		assert_eq!(v.difficulty, 4);
+
+		// ten shares in a few seconds double it
+		let start = v.since;
+		for n in 1..VARDIFF_SHARES {
+			assert_eq!(v.share(interval, start + Duration::from_millis(100 * n as u64)), None);
+		}
+		assert_eq!(v.share(interval, start + Duration::from_secs(1)), Some(8));
+
+		// a single share after many intervals halves it
+		let start = v.since;
+		assert_eq!(v.share(interval, start + interval * VARDIFF_SHARES * 3), Some(4));
+	}
+}
@@ -20,35 +20,102 @@
 /// How many block bodies to download in parallel
 const MAX_BODY_DOWNLOADS: usize = 8;
 
+/// Seconds a peer gets to send a block body we asked for before the request
+/// is retried with another peer
+const BODY_REQUEST_TIMEOUT_SECS: u64 = 20;
+
+/// How many blocks waiting for their parent in the orphan pool we let in
+/// before only the retries and the block the chain waits on get requested,
+/// waiting for the chain to catch up with them
+const MAX_BUFFERED_BLOCKS: usize = 64;
+
+/// How many times we go through our peers for the UTXO set, and how long we
+/// wait in between, giving them time to rebuild it at our horizon
+const UTXO_SYNC_ATTEMPTS: usize = 3;
+const UTXO_SYNC_RETRY_SECS: u64 = 30;
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Instant, Duration};
 
+use futures::Future;
+
+use core::core;
 use core::core::hash::{Hash, Hashed};
 use chain;
 use p2p;
 use types::Error;
 
+// Keeps our p2p server in sync mode until dropped, however syncing ends,
+// forgetting the peer we synced with.
+struct SyncMode<'a> {
+	p2p: &'a p2p::Server,
+}
+
+impl<'a> SyncMode<'a> {
+	fn on(p2p: &'a p2p::Server) -> SyncMode<'a> {
+		p2p.set_sync_mode(true);
+		SyncMode { p2p: p2p }
+	}
+}
+
+impl<'a> Drop for SyncMode<'a> {
+	fn drop(&mut self) {
+		self.p2p.set_sync_peer(None);
+		self.p2p.set_sync_mode(false);
+	}
+}
+
+// A block body requested from one of our peers.
+struct Download {
+	hash: Hash,
+	peer: SocketAddr,
+	since: Instant,
+}
+
 pub struct Syncer {
 	chain_store: Arc<chain::ChainStore>,
 	p2p: Arc<p2p::Server>,
+	chain_head: Arc<Mutex<chain::Tip>>,
 
 	sync: Mutex<bool>,
+	// whether we're still to sync the UTXO set at a horizon block instead of
+	// downloading the full blocks before it
+	fast_sync: Mutex<bool>,
+	// whether the header chain forked off our chain below the horizon we
+	// synced the UTXO set at, so we have to sync it again on the fork
+	forked: Mutex<bool>,
 	last_header_req: Mutex<Instant>,
 	blocks_to_download: Mutex<Vec<Hash>>,
-	blocks_downloading: Mutex<Vec<(Hash, Instant)>>,
+	blocks_downloading: Mutex<Vec<Download>>,
+	// peers a request for the block already failed with, by block hash
+	failed_peers: Mutex<HashMap<Hash, HashSet<SocketAddr>>>,
+	// blocks that came before their parent, bodies we downloaded included
+	orphans: Arc<Mutex<chain::OrphanPool>>,
 }
 
 impl Syncer {
-	pub fn new(chain_store: Arc<chain::ChainStore>, p2p: Arc<p2p::Server>) -> Syncer {
+	pub fn new(chain_store: Arc<chain::ChainStore>,
+	           p2p: Arc<p2p::Server>,
+	           chain_head: Arc<Mutex<chain::Tip>>,
+	           orphans: Arc<Mutex<chain::OrphanPool>>,
+	           fast_sync: bool)
+	           -> Syncer {
 		Syncer {
 			chain_store: chain_store,
 			p2p: p2p,
+			chain_head: chain_head,
 			sync: Mutex::new(true),
+			fast_sync: Mutex::new(fast_sync),
+			forked: Mutex::new(false),
 			last_header_req: Mutex::new(Instant::now() - Duration::from_secs(2)),
 			blocks_to_download: Mutex::new(vec![]),
 			blocks_downloading: Mutex::new(vec![]),
+			failed_peers: Mutex::new(HashMap::new()),
+			orphans: orphans,
 		}
 	}
 
@@ -56,6 +123,21 @@ impl Syncer {
 		*self.sync.lock().unwrap()
 	}
 
+	/// Gets ready to sync the UTXO set again at a horizon of the header chain,
+	/// when it forked off our chain below the horizon we synced it at, our
+	/// chain not having the full blocks to be rewound to the fork. False
+	/// when we're syncing already, run has to be called again otherwise.
+	pub fn resync_utxos(&self) -> bool {
+		let mut sync = self.sync.lock().unwrap_or_else(|e| e.into_inner());
+		if *sync {
+			return false;
+		}
+		*sync = true;
+		*self.fast_sync.lock().unwrap_or_else(|e| e.into_inner()) = true;
+		*self.forked.lock().unwrap_or_else(|e| e.into_inner()) = true;
+		true
+	}
+
 	/// Checks the local chain state, comparing it with our peers and triggers
 	/// syncing if required.
 	pub fn run(&self) -> Result<(), Error> {
@@ -72,31 +154,53 @@ impl Syncer {
 			thread::sleep(Duration::from_millis(200));
 		}
 
-		// check if we have missing full blocks for which we already have a header
-		self.init_download()?;
+		// forks most of our peers disagree with get rejected from the start,
+		// and the peers on them aren't synced with
+		if let Err(e) = self.p2p.collect_checkpoints().wait() {
+			debug!("Could not collect checkpoints: {:?}", e);
+		}
+
+		// check if we have missing full blocks for which we already have a header,
+		// unless the UTXO set sync decides which ones we need
+		if !*self.fast_sync.lock().unwrap() {
+			self.init_download()?;
+		}
 
 		// main syncing loop, requests more headers and bodies periodically as long
 		// as a peer with higher difficulty exists and we're not fully caught up
 		info!("Starting sync loop.");
+		let _sync_mode = SyncMode::on(&self.p2p);
 		loop {
 			let tip = self.chain_store.get_header_head()?;
-			// TODO do something better (like trying to get more) if we lose peers
-			let peer = self.p2p.most_work_peer().unwrap();
+			let peer = match self.p2p.most_work_peer() {
+				Some(peer) => peer,
+				None => {
+					debug!("No peer to sync with, waiting for one.");
+					thread::sleep(Duration::from_secs(2));
+					continue;
+				}
+			};
 
-			let more_headers = peer.info.total_difficulty > tip.total_difficulty;
+			let more_headers = peer.total_difficulty() > tip.total_difficulty;
+			let fast_sync = *self.fast_sync.lock().unwrap();
 			let more_bodies = {
 				let blocks_to_download = self.blocks_to_download.lock().unwrap();
 				let blocks_downloading = self.blocks_downloading.lock().unwrap();
 				blocks_to_download.len() > 0 || blocks_downloading.len() > 0
 			};
 
-			{
+			// the header chain gets validated first, then the UTXO set at its
+			// horizon when fast syncing, its bodies are then downloaded from all
+			// our peers
+			if more_headers {
 				let last_header_req = self.last_header_req.lock().unwrap().clone();
-				if more_headers && (Instant::now() - Duration::from_secs(2) > last_header_req) {
+				if Instant::now() - Duration::from_secs(2) > last_header_req {
 					self.request_headers()?;
 				}
-			}
-			if more_bodies {
+			} else if fast_sync {
+				self.sync_utxos()?;
+				continue;
+			} else if more_bodies {
 				self.request_bodies();
 			}
 			if !more_headers && !more_bodies {
@@ -135,28 +239,204 @@ impl Syncer {
 		Ok(())
 	}
 
+	/// Syncs the UTXO set at a recent enough horizon block from a peer able to
+	/// send it, once we have the header chain, so only the full blocks after
+	/// the horizon have to be downloaded. A peer sending outputs not matching
+	/// the horizon header gets quarantined and the next one asked, and peers
+	/// get asked again a few times when none sent it. When we're close
+	/// enough already, or no peer got it right, all the full blocks we're
+	/// missing get downloaded instead. Unless we forked off below our horizon,
+	/// then we have to keep trying.
+	fn sync_utxos(&self) -> Result<(), Error> {
+		let header_head = self.chain_store.get_header_head()?;
+		let head = self.chain_store.head()?;
+		let forked = *self.forked.lock().unwrap_or_else(|e| e.into_inner());
+		let mut synced = false;
+		if forked || header_head.height > head.height + p2p::PRUNED_BLOCKS_HORIZON {
+			let horizon = self.horizon_header(&header_head)?;
+			info!("Syncing the UTXO set at {} at {}.", horizon.hash(), horizon.height);
+			'attempts: for attempt in 0..UTXO_SYNC_ATTEMPTS {
+				if attempt > 0 {
+					debug!("No peer sent the UTXO set, asking again.");
+					thread::sleep(Duration::from_secs(UTXO_SYNC_RETRY_SECS));
+				}
+				for peer in self.p2p.peers_with_capabilities(p2p::UTXO_SNAPSHOT) {
+					let outputs = match self.download_utxos(&peer, horizon.hash()) {
+						Some(outputs) => outputs,
+						None => continue,
+					};
+					let res =
+						chain::process_utxo_snapshot(&horizon, outputs, self.chain_store.clone());
+					match res {
+						Ok(tip) => {
+							*self.chain_head.lock().unwrap() = tip;
+							synced = true;
+							break 'attempts;
+						}
+						Err(chain::Error::InvalidUtxoRoot) |
+						Err(chain::Error::InvalidBlockProof(_)) => {
+							warn!("Peer {} sent an invalid UTXO set, quarantining.",
+							      peer.info.addr);
+							self.p2p.ban_peer(peer.info.addr,
+							                  p2p::Severity::Quarantine,
+							                  "invalid UTXO set");
+						}
+						Err(e) => warn!("Could not sync the UTXO set: {:?}", e),
+					}
+				}
+			}
+		}
+		if forked {
+			if !synced {
+				warn!("Could not sync the UTXO set on the fork, trying again.");
+				return Ok(());
+			}
+			*self.forked.lock().unwrap_or_else(|e| e.into_inner()) = false;
+		}
+
+		// headers received meanwhile wait for us to be done here
+		let mut fast_sync = self.fast_sync.lock().unwrap();
+		self.init_download()?;
+		*fast_sync = false;
+		Ok(())
+	}
+
+	/// Header of the block we sync the UTXO set at, far enough behind the
+	/// head of the header chain not to be reorged away.
+	fn horizon_header(&self, header_head: &chain::Tip) -> Result<core::BlockHeader, Error> {
+		let mut bh = self.chain_store.get_block_header(&header_head.last_block_h)?;
+		while bh.height + p2p::PRUNED_BLOCKS_HORIZON > header_head.height {
+			bh = self.chain_store.get_block_header(&bh.previous)?;
+		}
+		Ok(bh)
+	}
+
+	/// Downloads the chunks of the UTXO set at the horizon block from the
+	/// provided peer, one after the other. None if the peer fails to send
+	/// one of them.
+	fn download_utxos(&self, peer: &p2p::Peer, horizon: Hash) -> Option<Vec<core::Output>> {
+		let mut outputs = vec![];
+		let (mut chunk, mut chunks) = (0, 1);
+		while chunk < chunks {
+			let resp = match peer.request_utxo_chunk(horizon, chunk).and_then(|r| r.wait()) {
+				Ok(resp) => resp,
+				Err(e) => {
+					debug!("Could not get chunk {} from {}: {:?}", chunk, peer.info.addr, e);
+					return None;
+				}
+			};
+			// the number of chunks can't change along the way
+			if resp.horizon != horizon || resp.chunk != chunk || resp.chunks == 0 ||
+			   (chunk > 0 && resp.chunks != chunks) {
+				debug!("Peer {} has no UTXO set at {} to send.", peer.info.addr, horizon);
+				return None;
+			}
+			chunks = resp.chunks;
+			outputs.extend(resp.outputs);
+			chunk += 1;
+		}
+		Some(outputs)
+	}
+
 	/// Asks for the blocks we haven't downloaded yet and place them in the
-	/// downloading structure.
+	/// downloading structure. Requests are spread over the peers that can
+	/// serve them, those timing out get retried with another peer.
 	fn request_bodies(&self) {
 		let mut blocks_downloading = self.blocks_downloading.lock().unwrap();
-		if blocks_downloading.len() > MAX_BODY_DOWNLOADS {
-			// clean up potentially dead downloads
-			let twenty_sec_ago = Instant::now() - Duration::from_secs(20);
-			blocks_downloading.iter()
-				.position(|&h| h.1 < twenty_sec_ago)
-				.map(|n| blocks_downloading.remove(n));
-		} else {
-			// consume hashes from blocks to download, place them in downloading and
-			// request them from the network
-			let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
-			while blocks_to_download.len() > 0 && blocks_downloading.len() < MAX_BODY_DOWNLOADS {
-				let h = blocks_to_download.pop().unwrap();
-				let peer = self.p2p.random_peer().unwrap();
-				peer.send_block_request(h);
-				blocks_downloading.push((h, Instant::now()));
+		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
+
+		// timed out requests are next in line, not to the same peer
+		let timeout = Duration::from_secs(BODY_REQUEST_TIMEOUT_SECS);
+		let mut retried = HashSet::new();
+		for d in take_expired(&mut blocks_downloading, timeout) {
+			debug!("Request for block {} to {} timed out, retrying.", d.hash, d.peer);
+			self.peer_failed(d.hash, d.peer);
+			blocks_to_download.push(d.hash);
+			retried.insert(d.hash);
+		}
+
+		// the chain can only take bodies in order, those ahead of a slow one
+		// wait for it in the orphan pool
+		let buffered = self.orphans.lock().unwrap_or_else(|e| e.into_inner()).len();
+		let buffer_full = buffered >= MAX_BUFFERED_BLOCKS;
+		if buffer_full {
+			debug!("Waiting on buffered blocks, only retrying and asking for the next one.");
+		}
+		let awaited = self.chain_head.lock().unwrap().height + 1;
+		let max = MAX_BODY_DOWNLOADS.saturating_sub(blocks_downloading.len());
+		let mut next = next_requests(&mut blocks_to_download, max, buffer_full, |h| {
+			retried.contains(h) ||
+			self.chain_store.get_block_header(h).map(|bh| bh.height == awaited).unwrap_or(false)
+		});
+
+		// request them from the network and place them in downloading, those
+		// left going back in line when there's no one to ask
+		while let Some(h) = next.pop() {
+			// old blocks may have been pruned by some of our peers, and those
+			// busy with our previous requests get a break
+			let needed = self.services_for_block(h);
+			let peer = match self.peer_for_block(h, needed) {
+				Some(peer) => peer,
+				None => {
+					debug!("No peer offering {:?} free to download {}.", needed, h);
+					next.push(h);
+					break;
+				}
+			};
+			if let Err(e) = peer.send_block_request(h) {
+				debug!("Could not ask {} for block {}: {:?}", peer.info.addr, h, e);
+				self.peer_failed(h, peer.info.addr);
+				next.push(h);
+				break;
+			}
+			blocks_downloading.push(Download {
+				hash: h,
+				peer: peer.info.addr,
+				since: Instant::now(),
+			});
+		}
+		blocks_to_download.extend(next);
+		debug!("Requesting more full block hashes to download, total: {}.",
+		       blocks_to_download.len());
+	}
+
+	/// Peer to download the block with the provided hash from, picked among
+	/// those offering the needed services and not already failing to send
+	/// it, unless they all did.
+	fn peer_for_block(&self, h: Hash, needed: p2p::Services) -> Option<Arc<p2p::Peer>> {
+		let mut failed_peers = self.failed_peers.lock().unwrap();
+		let exhausted = match failed_peers.get(&h) {
+			Some(failed) => {
+				if let Some(peer) = self.p2p.random_peer_for_blocks_excluding(needed, failed) {
+					return Some(peer);
+				}
+				self.p2p.peers_offering(needed).iter().all(|p| failed.contains(&p.info.addr))
 			}
-			debug!("Requesting more full block hashes to download, total: {}.",
-			       blocks_to_download.len());
+			None => return self.p2p.random_peer_for_blocks(needed),
+		};
+		if !exhausted {
+			return None;
+		}
+		// every peer failed us already, they all get another try
+		failed_peers.remove(&h);
+		self.p2p.random_peer_for_blocks(needed)
+	}
+
+	// Remembers a request for the block with the provided hash failed with
+	// the peer at the provided address.
+	fn peer_failed(&self, h: Hash, addr: SocketAddr) {
+		let mut failed_peers = self.failed_peers.lock().unwrap();
+		failed_peers.entry(h).or_insert_with(HashSet::new).insert(addr);
+	}
+
+	/// Services a peer needs to offer for us to download the full block with
+	/// the provided hash.
+	fn services_for_block(&self, h: Hash) -> p2p::Services {
+		let header = self.chain_store.get_block_header(&h);
+		let head = self.chain_store.get_header_head();
+		match (header, head) {
+			(Ok(bh), Ok(head)) => p2p::Services::for_block(bh.height, head.height),
+			_ => p2p::SERVES_BLOCKS,
 		}
 	}
 
@@ -164,7 +444,14 @@ impl Syncer {
 	pub fn block_received(&self, bh: Hash) {
 		// just clean up the downloading list
 		let mut bds = self.blocks_downloading.lock().unwrap();
-		bds.iter().position(|&h| h.0 == bh).map(|n| bds.remove(n));
+		bds.iter().position(|d| d.hash == bh).map(|n| bds.remove(n));
+		self.failed_peers.lock().unwrap().remove(&bh);
+	}
+
+	/// Whether the block with the provided hash is one we asked for and are
+	/// still waiting on.
+	pub fn downloading(&self, bh: Hash) -> bool {
+		self.blocks_downloading.lock().unwrap().iter().any(|d| d.hash == bh)
 	}
 
 	/// Request some block headers from a peer to advance us
@@ -175,10 +462,11 @@ impl Syncer {
 		}
 
 		let tip = self.chain_store.get_header_head()?;
-		let peer = self.p2p.most_work_peer();
+		let peer = self.p2p.most_work_peer_offering(p2p::SERVES_HEADERS);
 		let locator = self.get_locator(&tip)?;
 		if let Some(p) = peer {
 			debug!("Asking peer {} for more block headers.", p.info.addr);
+			self.p2p.set_sync_peer(Some(p.info.addr));
 			p.send_header_request(locator)?;
 		} else {
 			warn!("Could not get most worked peer to request headers.");
@@ -188,12 +476,18 @@ impl Syncer {
 
 	/// We added a header, add it to the full block download list
 	pub fn headers_received(&self, bhs: Vec<Hash>) {
-		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
 		let hs_len = bhs.len();
-		for h in bhs {
-			// enlist for full block download
-			blocks_to_download.insert(0, h);
+		// when syncing the UTXO set, it's only known which full blocks we need
+		// once it's done
+		let fast_sync = self.fast_sync.lock().unwrap();
+		if !*fast_sync {
+			let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
+			for h in bhs {
+				// enlist for full block download
+				blocks_to_download.insert(0, h);
+			}
 		}
+		drop(fast_sync);
 		// ask for more headers if we got as many as required
 		if hs_len == (p2p::MAX_BLOCK_HEADERS as usize) {
 			self.request_headers();
@@ -232,3 +526,98 @@ impl Syncer {
 		Ok(locator)
 	}
 }
+
+// Takes the downloads requested longer than the provided timeout ago out of
+// those in progress.
+fn take_expired(downloading: &mut Vec<Download>, timeout: Duration) -> Vec<Download> {
+	let (expired, pending): (Vec<_>, Vec<_>) =
+		downloading.drain(..).partition(|d| d.since.elapsed() > timeout);
+	*downloading = pending;
+	expired
+}
+
+// Takes the next hashes to request off those to download, at most the
+// provided number and in the same order, the last one next in line. A full
+// buffer only lets through those the predicate accepts.
+fn next_requests<F>(to_download: &mut Vec<Hash>,
+                    max: usize,
+                    buffer_full: bool,
+                    accepted: F)
+                    -> Vec<Hash>
+	where F: Fn(&Hash) -> bool
+{
+	if !buffer_full {
+		let at = to_download.len().saturating_sub(max);
+		return to_download.split_off(at);
+	}
+	let mut next = vec![];
+	let mut n = to_download.len();
+	while n > 0 && next.len() < max {
+		n -= 1;
+		if accepted(&to_download[n]) {
+			next.insert(0, to_download.remove(n));
+		}
+	}
+	next
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::{Duration, Instant};
+
+	use core::core::hash::Hash;
+	use super::*;
+
+	fn hash(n: u8) -> Hash {
+		Hash([n; 32])
+	}
+
+	fn download(n: u8, secs_ago: u64) -> Download {
+		Download {
+			hash: hash(n),
+			peer: "127.0.0.1:13414".parse().unwrap(),
+			since: Instant::now() - Duration::from_secs(secs_ago),
+		}
+	}
+
+	#[test]
+	fn expired_downloads() {
+		let timeout = Duration::from_secs(BODY_REQUEST_TIMEOUT_SECS);
+		let mut downloading = vec![download(1, 0),
+		                           download(2, BODY_REQUEST_TIMEOUT_SECS + 1),
+		                           download(3, 1)];
+		let expired = take_expired(&mut downloading, timeout);
+		assert_eq!(expired.iter().map(|d| d.hash).collect::<Vec<_>>(), vec![hash(2)]);
+		assert_eq!(downloading.iter().map(|d| d.hash).collect::<Vec<_>>(),
+		           vec![hash(1), hash(3)]);
+		assert!(take_expired(&mut downloading, timeout).is_empty());
+	}
+
+	#[test]
+	fn requests_in_line() {
+		// the last ones are next, a retry pushed after the others
+		let mut to_download = vec![hash(5), hash(4), hash(3), hash(2), hash(9)];
+		let next = next_requests(&mut to_download, 2, false, |_| false);
+		assert_eq!(next, vec![hash(2), hash(9)]);
+		assert_eq!(to_download, vec![hash(5), hash(4), hash(3)]);
+		let next = next_requests(&mut to_download, MAX_BODY_DOWNLOADS, false, |_| false);
+		assert_eq!(next, vec![hash(5), hash(4), hash(3)]);
+		assert!(to_download.is_empty());
+	}
+
+	#[test]
+	fn full_buffer_requests() {
+		// only the retry and the block the chain waits on get through
+		let mut to_download = vec![hash(5), hash(4), hash(3), hash(2), hash(9)];
+		let accepted = |h: &Hash| *h == hash(9) || *h == hash(2);
+		let next = next_requests(&mut to_download, MAX_BODY_DOWNLOADS, true, &accepted);
+		assert_eq!(next, vec![hash(2), hash(9)]);
+		assert_eq!(to_download, vec![hash(5), hash(4), hash(3)]);
+
+		// still no more than we can download at once
+		let mut to_download = vec![hash(2), hash(9)];
+		assert_eq!(next_requests(&mut to_download, 1, true, &accepted), vec![hash(9)]);
+		assert_eq!(to_download, vec![hash(2)]);
+		assert!(next_requests(&mut to_download, 0, true, &accepted).is_empty());
+	}
+}
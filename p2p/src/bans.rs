@@ -0,0 +1,390 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hosts we keep away for a while, banned or only quarantined, keyed by the
+//! IP their connections came from.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use types::Severity;
+
+/// A host currently banned or quarantined, see Server::list_bans.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanEntry {
+	/// Host kept away.
+	pub ip: IpAddr,
+	/// Whether the host is banned or only quarantined.
+	pub severity: Severity,
+	/// Why the host got restricted.
+	pub reason: String,
+	/// How long ago the host got restricted, or the restriction restored
+	/// after a restart.
+	pub since: Duration,
+	/// How long until the restriction gets lifted, never if none.
+	pub expires_in: Option<Duration>,
+}
+
+/// How long hosts are kept away for, depending on their severity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Terms {
+	/// Length of a quarantine.
+	pub quarantine: Duration,
+	/// Length of a ban, until lifted by hand if none.
+	pub ban: Option<Duration>,
+}
+
+impl Terms {
+	/// Terms from the configured lengths in seconds, a ban of zero seconds
+	/// lasting until lifted by hand.
+	pub fn new(quarantine_secs: u64, ban_secs: u64) -> Terms {
+		Terms {
+			quarantine: Duration::from_secs(quarantine_secs),
+			ban: if ban_secs > 0 {
+				Some(Duration::from_secs(ban_secs))
+			} else {
+				None
+			},
+		}
+	}
+
+	fn length(&self, severity: Severity) -> Option<Duration> {
+		match severity {
+			Severity::Ban => self.ban,
+			Severity::Quarantine => Some(self.quarantine),
+		}
+	}
+}
+
+/// Ban or quarantine of a host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restriction {
+	/// Whether the host is banned or only quarantined.
+	pub severity: Severity,
+	/// Why the host got restricted.
+	pub reason: String,
+	/// When the host got restricted.
+	pub since: Instant,
+	/// When the restriction gets lifted, never if none.
+	pub expires: Option<Instant>,
+}
+
+impl Restriction {
+	fn expired(&self, now: Instant) -> bool {
+		self.expires.map(|e| e <= now).unwrap_or(false)
+	}
+
+	fn entry(&self, ip: IpAddr, now: Instant) -> BanEntry {
+		BanEntry {
+			ip: ip,
+			severity: self.severity,
+			reason: self.reason.clone(),
+			since: elapsed(self.since, now),
+			expires_in: self.expires.map(|e| elapsed(now, e)),
+		}
+	}
+}
+
+/// The hosts currently restricted, up to a cap. Expired restrictions are
+/// forgotten whenever looked up.
+pub struct Restrictions {
+	hosts: HashMap<IpAddr, Restriction>,
+	cap: usize,
+}
+
+impl Restrictions {
+	/// No host restricted yet, keeping up to cap of them.
+	pub fn new(cap: usize) -> Restrictions {
+		Restrictions {
+			hosts: HashMap::new(),
+			cap: cap,
+		}
+	}
+
+	/// Restricts the host for as long as the terms say, replacing any
+	/// restriction it had. Returns its entry along with the host forgotten
+	/// to make room for it, if any.
+	pub fn restrict(&mut self,
+	                ip: IpAddr,
+	                severity: Severity,
+	                reason: &str,
+	                terms: Terms,
+	                now: Instant)
+	                -> (BanEntry, Option<IpAddr>) {
+		let r = Restriction {
+			severity: severity,
+			reason: reason.to_string(),
+			since: now,
+			expires: terms.length(severity).map(|l| now + l),
+		};
+		let entry = r.entry(ip, now);
+		(entry, self.insert(ip, r, now))
+	}
+
+	/// Restricts the host until provided, to restore a restriction we had
+	/// before restarting. Returns the host forgotten to make room for it.
+	pub fn restore(&mut self,
+	               ip: IpAddr,
+	               severity: Severity,
+	               reason: &str,
+	               expires_in: Option<Duration>,
+	               now: Instant)
+	               -> Option<IpAddr> {
+		let r = Restriction {
+			severity: severity,
+			reason: reason.to_string(),
+			since: now,
+			expires: expires_in.map(|d| now + d),
+		};
+		self.insert(ip, r, now)
+	}
+
+	// Once at the cap, forgets the expired restrictions and, if that's not
+	// enough, the one to be lifted the soonest, quarantines first and bans
+	// lasting until lifted by hand last.
+	fn insert(&mut self, ip: IpAddr, r: Restriction, now: Instant) -> Option<IpAddr> {
+		let mut evicted = None;
+		if self.hosts.len() >= self.cap && !self.hosts.contains_key(&ip) {
+			self.forget_expired(now);
+			if self.hosts.len() >= self.cap {
+				evicted = self.hosts
+					.iter()
+					.min_by_key(|&(_, r)| (r.expires.is_none(), r.expires, r.since))
+					.map(|(ip, _)| *ip);
+				if let Some(ref ip) = evicted {
+					self.hosts.remove(ip);
+				}
+			}
+		}
+		self.hosts.insert(ip, r);
+		evicted
+	}
+
+	/// The current restriction of the host, forgetting it if expired.
+	pub fn get(&mut self, ip: &IpAddr, now: Instant) -> Option<Restriction> {
+		let current = match self.hosts.get(ip) {
+			None => return None,
+			Some(r) if r.expired(now) => None,
+			Some(r) => Some(r.clone()),
+		};
+		if current.is_none() {
+			self.hosts.remove(ip);
+		}
+		current
+	}
+
+	/// Number of hosts restricted, expired restrictions not forgotten yet
+	/// included.
+	pub fn len(&self) -> usize {
+		self.hosts.len()
+	}
+
+	/// Whether the host is currently restricted.
+	pub fn contains(&mut self, ip: &IpAddr, now: Instant) -> bool {
+		self.get(ip, now).is_some()
+	}
+
+	/// Lifts the restriction of the host, returns whether it still had one.
+	pub fn remove(&mut self, ip: &IpAddr, now: Instant) -> bool {
+		match self.hosts.remove(ip) {
+			Some(r) => !r.expired(now),
+			None => false,
+		}
+	}
+
+	/// Forgets all the restrictions that expired.
+	pub fn forget_expired(&mut self, now: Instant) {
+		let expired = self.hosts
+			.iter()
+			.filter(|&(_, r)| r.expired(now))
+			.map(|(ip, _)| *ip)
+			.collect::<Vec<_>>();
+		for ip in expired {
+			self.hosts.remove(&ip);
+		}
+	}
+
+	/// The current restrictions, most recent first.
+	pub fn entries(&mut self, now: Instant) -> Vec<BanEntry> {
+		self.forget_expired(now);
+		let mut entries = self.hosts
+			.iter()
+			.map(|(ip, r)| r.entry(*ip, now))
+			.collect::<Vec<_>>();
+		entries.sort_by_key(|e| e.since);
+		entries
+	}
+}
+
+/// Misbehavior scores of hosts, up to a cap. The score of a host is
+/// forgotten once it didn't add to it for a while.
+pub struct HostScores {
+	scores: HashMap<IpAddr, (u32, Instant)>,
+	cap: usize,
+	memory: Duration,
+}
+
+impl HostScores {
+	/// No host scored yet, keeping up to cap of them, each for the provided
+	/// time after it last added to its score.
+	pub fn new(cap: usize, memory: Duration) -> HostScores {
+		HostScores {
+			scores: HashMap::new(),
+			cap: cap,
+			memory: memory,
+		}
+	}
+
+	/// Adds to the score of the host, returning its new score. Once at the
+	/// cap, the scores forgotten go first, then the lowest one.
+	pub fn add(&mut self, ip: IpAddr, points: u32, now: Instant) -> u32 {
+		if self.scores.len() >= self.cap && !self.scores.contains_key(&ip) {
+			self.forget_stale(now);
+			if self.scores.len() >= self.cap {
+				let lowest = self.scores
+					.iter()
+					.min_by_key(|&(_, &(score, last))| (score, last))
+					.map(|(ip, _)| *ip);
+				if let Some(ref ip) = lowest {
+					self.scores.remove(ip);
+				}
+			}
+		}
+		let memory = self.memory;
+		let entry = self.scores.entry(ip).or_insert((0, now));
+		if elapsed(entry.1, now) >= memory {
+			entry.0 = 0;
+		}
+		*entry = (entry.0 + points, now);
+		entry.0
+	}
+
+	/// Current score of the host, zero if forgotten.
+	pub fn get(&self, ip: &IpAddr, now: Instant) -> u32 {
+		match self.scores.get(ip) {
+			Some(&(score, last)) if elapsed(last, now) < self.memory => score,
+			_ => 0,
+		}
+	}
+
+	/// Forgets the score of the host.
+	pub fn remove(&mut self, ip: &IpAddr) {
+		self.scores.remove(ip);
+	}
+
+	fn forget_stale(&mut self, now: Instant) {
+		let memory = self.memory;
+		let stale = self.scores
+			.iter()
+			.filter(|&(_, &(_, last))| elapsed(last, now) >= memory)
+			.map(|(ip, _)| *ip)
+			.collect::<Vec<_>>();
+		for ip in stale {
+			self.scores.remove(&ip);
+		}
+	}
+}
+
+// Time from start to end, zero if end comes first.
+fn elapsed(start: Instant, end: Instant) -> Duration {
+	if end > start {
+		end.duration_since(start)
+	} else {
+		Duration::new(0, 0)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::net::IpAddr;
+	use std::time::{Duration, Instant};
+
+	use types::Severity;
+	use super::*;
+
+	fn ip(n: u8) -> IpAddr {
+		format!("10.0.0.{}", n).parse().unwrap()
+	}
+
+	#[test]
+	fn restrictions_expire() {
+		let now = Instant::now();
+		let terms = Terms::new(10, 100);
+		let mut r = Restrictions::new(10);
+		r.restrict(ip(1), Severity::Quarantine, "test", terms, now);
+		r.restrict(ip(2), Severity::Ban, "test", terms, now);
+		r.restrict(ip(3), Severity::Ban, "test", Terms::new(10, 0), now);
+
+		let later = now + Duration::from_secs(50);
+		assert!(!r.contains(&ip(1), later));
+		assert!(r.contains(&ip(2), later));
+		assert_eq!(r.entries(later).len(), 2);
+
+		let much_later = now + Duration::from_secs(1000);
+		assert!(!r.contains(&ip(2), much_later));
+		let lasting = r.entries(much_later);
+		assert_eq!(lasting.len(), 1);
+		assert_eq!(lasting[0].ip, ip(3));
+		assert_eq!(lasting[0].expires_in, None);
+		assert_eq!(lasting[0].reason, "test");
+		assert!(r.remove(&ip(3), much_later));
+		assert!(!r.contains(&ip(3), much_later));
+	}
+
+	#[test]
+	fn restrictions_capped() {
+		let now = Instant::now();
+		let terms = Terms::new(10, 100);
+		let mut r = Restrictions::new(3);
+		r.restrict(ip(1), Severity::Ban, "test", Terms::new(10, 0), now);
+		r.restrict(ip(2), Severity::Ban, "test", terms, now);
+		r.restrict(ip(3), Severity::Quarantine, "test", terms, now);
+
+		// the quarantine is the first to go, the lasting ban the last
+		let soon = now + Duration::from_secs(1);
+		let (_, evicted) = r.restrict(ip(4), Severity::Ban, "test", terms, soon);
+		assert_eq!(evicted, Some(ip(3)));
+		let (_, evicted) = r.restrict(ip(5), Severity::Ban, "test", terms, soon);
+		assert_eq!(evicted, Some(ip(2)));
+		assert!(r.contains(&ip(1), soon));
+
+		// expired restrictions make room first
+		let later = now + Duration::from_secs(200);
+		let (_, evicted) = r.restrict(ip(6), Severity::Quarantine, "test", terms, later);
+		assert_eq!(evicted, None);
+		assert_eq!(r.entries(later).len(), 2);
+	}
+
+	#[test]
+	fn scores_forgotten() {
+		let now = Instant::now();
+		let mut scores = HostScores::new(2, Duration::from_secs(60));
+		assert_eq!(scores.add(ip(1), 10, now), 10);
+		assert_eq!(scores.add(ip(1), 10, now + Duration::from_secs(30)), 20);
+		assert_eq!(scores.add(ip(2), 5, now), 5);
+
+		// the lowest score makes room once at the cap
+		assert_eq!(scores.add(ip(3), 1, now), 1);
+		assert_eq!(scores.get(&ip(2), now), 0);
+		assert_eq!(scores.get(&ip(1), now + Duration::from_secs(60)), 20);
+
+		// a score not added to for a while is forgotten, and starts over
+		let later = now + Duration::from_secs(100);
+		assert_eq!(scores.get(&ip(1), later), 0);
+		assert_eq!(scores.add(ip(1), 10, later), 10);
+		assert_eq!(scores.add(ip(4), 1, later), 1);
+		assert_eq!(scores.get(&ip(1), later), 10);
+	}
+}
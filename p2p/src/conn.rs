@@ -15,52 +15,118 @@
 //! Provides a connection wrapper that handles the lower level tasks in sending
 //! or receiving data from the TCP socket, as well as dealing with timeouts.
 
-use std::iter;
-use std::ops::Deref;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
 use std::time::{Instant, Duration};
 
 use futures;
-use futures::{Stream, Future};
-use futures::stream;
-use futures::sync::mpsc::{Sender, UnboundedSender, UnboundedReceiver};
-use tokio_core::io::{Io, WriteHalf, ReadHalf, write_all, read_exact};
-use tokio_core::net::TcpStream;
+use futures::{Async, Poll, Stream, Future};
+use futures::future::{self, Loop};
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use futures::sync::oneshot;
+use tokio_core::io::{Io, WriteHalf, ReadHalf, write_all, read, read_exact};
 use tokio_timer::{Timer, TimerError};
 
-use core::core::hash::{Hash, ZERO_HASH};
 use core::ser;
 use msg::*;
+use num::FromPrimitive;
+use stream::PeerStream;
+use throttle::{self, ReadLimit, Throttle};
 use types::Error;
 
+/// Maximum number of requests to a peer waiting for their response. A peer
+/// letting more pile up gets disconnected, like one letting a request time
+/// out.
+pub const MAX_PENDING_REQUESTS: usize = 128;
+
+/// Future a handler can return to hold off reading the next message from the
+/// peer until it resolves.
+pub type Pause = Box<Future<Item = (), Error = Error>>;
+
 /// Handler to provide to the connection, will be called back anytime a message
 /// is received. The provided sender can be use to immediately send back
 /// another message.
 pub trait Handler: Sync + Send {
 	/// Handle function to implement to process incoming messages. A sender to
 	/// reply immediately as well as the message header and its unparsed body
-	/// are provided.
+	/// are provided. A returned pause delays reading further messages.
 	fn handle(&self,
 	          sender: UnboundedSender<Vec<u8>>,
 	          header: MsgHeader,
 	          body: Vec<u8>)
-	          -> Result<Option<Hash>, ser::Error>;
+	          -> Result<Option<Pause>, ser::Error>;
 }
 
 impl<F> Handler for F
-	where F: Fn(UnboundedSender<Vec<u8>>, MsgHeader, Vec<u8>) -> Result<Option<Hash>, ser::Error>,
+	where F: Fn(UnboundedSender<Vec<u8>>, MsgHeader, Vec<u8>) -> Result<Option<Pause>, ser::Error>,
 	      F: Sync + Send
 {
 	fn handle(&self,
 	          sender: UnboundedSender<Vec<u8>>,
 	          header: MsgHeader,
 	          body: Vec<u8>)
-	          -> Result<Option<Hash>, ser::Error> {
+	          -> Result<Option<Pause>, ser::Error> {
 		self(sender, header, body)
 	}
 }
 
-/// A higher level connection wrapping the TcpStream. Maintains the amount of
+/// Count of the bytes and messages sent and received, over a connection or
+/// any number of them, outliving them. Atomic so counting doesn't lock on
+/// every message.
+#[derive(Debug)]
+pub struct Traffic {
+	sent_bytes: AtomicUsize,
+	received_bytes: AtomicUsize,
+	sent_msgs: AtomicUsize,
+	received_msgs: AtomicUsize,
+}
+
+impl Traffic {
+	pub fn new() -> Traffic {
+		Traffic {
+			sent_bytes: AtomicUsize::new(0),
+			received_bytes: AtomicUsize::new(0),
+			sent_msgs: AtomicUsize::new(0),
+			received_msgs: AtomicUsize::new(0),
+		}
+	}
+
+	/// Bytes sent and received so far.
+	pub fn totals(&self) -> (u64, u64) {
+		(self.sent_bytes.load(atomic::Ordering::Relaxed) as u64,
+		 self.received_bytes.load(atomic::Ordering::Relaxed) as u64)
+	}
+
+	/// Messages sent and received so far.
+	pub fn msgs(&self) -> (u64, u64) {
+		(self.sent_msgs.load(atomic::Ordering::Relaxed) as u64,
+		 self.received_msgs.load(atomic::Ordering::Relaxed) as u64)
+	}
+
+	/// Starts counting from zero again.
+	pub fn reset(&self) {
+		self.sent_bytes.store(0, atomic::Ordering::Relaxed);
+		self.received_bytes.store(0, atomic::Ordering::Relaxed);
+		self.sent_msgs.store(0, atomic::Ordering::Relaxed);
+		self.received_msgs.store(0, atomic::Ordering::Relaxed);
+	}
+
+	// Counts a message of the provided length sent.
+	fn sent(&self, len: usize) {
+		self.sent_bytes.fetch_add(len, atomic::Ordering::Relaxed);
+		self.sent_msgs.fetch_add(1, atomic::Ordering::Relaxed);
+	}
+
+	// Counts a message of the provided length received.
+	fn received(&self, len: usize) {
+		self.received_bytes.fetch_add(len, atomic::Ordering::Relaxed);
+		self.received_msgs.fetch_add(1, atomic::Ordering::Relaxed);
+	}
+}
+
+/// A higher level connection wrapping the stream to a peer. Maintains the amount of
 /// data transmitted and deals with the low-level task of sending and
 /// receiving data, parsing message headers and timeouts.
 pub struct Connection {
@@ -68,134 +134,260 @@ pub struct Connection {
 	outbound_chan: UnboundedSender<Vec<u8>>,
 
 	// Close the connection with the remote peer
-	close_chan: Sender<()>,
+	close_chan: UnboundedSender<()>,
+
+	// Set once closing gracefully, no new messages get queued from then on
+	closing: Arc<AtomicBool>,
 
-	// Bytes we've sent.
-	sent_bytes: Arc<Mutex<u64>>,
+	// Starts the deadline of a graceful close, taken by the first one
+	graceful_chan: Mutex<Option<oneshot::Sender<Duration>>>,
 
-	// Bytes we've received.
-	received_bytes: Arc<Mutex<u64>>,
+	// Bytes and messages sent and received over this connection.
+	stats: Arc<Traffic>,
+
+	// Same counted along with those of other connections.
+	traffic: Arc<Traffic>,
 
 	// Counter for read errors.
 	error_count: Mutex<u64>,
+
+	// Magic bytes starting every message, those of the network we run on.
+	magic: [u8; 2],
 }
 
 impl Connection {
 	/// Start listening on the provided connection and wraps it. Does not hang
 	/// the current thread, instead just returns a future and the Connection
-	/// itself.
-	pub fn listen<F>(conn: TcpStream,
+	/// itself. Every message starts with the provided magic bytes both ways.
+	/// With checksums, every message body is followed by its checksum both
+	/// ways. With dumps, the messages we can't decode get logged in hex. The
+	/// traffic gets counted in the provided stats of the connection as well
+	/// as in the provided total.
+	pub fn listen<F>(conn: PeerStream,
+	                 magic: [u8; 2],
+	                 mut throttle: Throttle,
+	                 stats: Arc<Traffic>,
+	                 traffic: Arc<Traffic>,
+	                 checksums: bool,
+	                 dumps: bool,
 	                 handler: F)
 	                 -> (Connection, Box<Future<Item = (), Error = Error>>)
 		where F: Handler + 'static
 	{
 
 		let (reader, writer) = conn.split();
+		let read_limit = throttle.take_read_limit();
 
 		// prepare the channel that will transmit data to the connection writer
 		let (tx, rx) = futures::sync::mpsc::unbounded();
 
-		// same for closing the connection
-		let (close_tx, close_rx) = futures::sync::mpsc::channel(1);
-		let close_conn = close_rx.for_each(|_| Ok(())).map_err(|_| Error::ConnectionClose);
+		// same for closing the connection, the first signal (or dropping the
+		// sender) resolves the connection future
+		let (close_tx, close_rx) = futures::sync::mpsc::unbounded();
+		let close_conn = close_rx.into_future().map(|_| ()).map_err(|_| Error::ConnectionClose);
+
+		// a graceful close is over once what was queued went out or its deadline
+		// passed, whichever comes first
+		let (graceful_tx, graceful_rx) = oneshot::channel();
+		let (flushed_tx, flushed_rx) = oneshot::channel();
+		let closing = Arc::new(AtomicBool::new(false));
+		let graceful_conn = graceful_rx.then(|res| -> Box<Future<Item = (), Error = Error>> {
+			let timeout = match res {
+				Ok(timeout) => timeout,
+				Err(_) => return Box::new(future::empty()),
+			};
+			let deadline = throttle::sleep(&Timer::default(), timeout)
+				.then(|_| -> Result<(), Error> { Ok(()) });
+			let flushed = flushed_rx.then(|res| -> Box<Future<Item = (), Error = Error>> {
+				match res {
+					Ok(()) => Box::new(future::ok(())),
+					Err(_) => Box::new(future::empty()),
+				}
+			});
+			Box::new(deadline.select(flushed).map(|_| ()).map_err(|(e, _)| e))
+		});
 
 		let me = Connection {
 			outbound_chan: tx.clone(),
 			close_chan: close_tx,
-			sent_bytes: Arc::new(Mutex::new(0)),
-			received_bytes: Arc::new(Mutex::new(0)),
+			closing: closing.clone(),
+			graceful_chan: Mutex::new(Some(graceful_tx)),
+			stats: stats,
+			traffic: traffic,
 			error_count: Mutex::new(0),
+			magic: magic,
 		};
 
 		// setup the reading future, getting messages from the peer and processing them
-		let read_msg = me.read_msg(tx, reader, handler).map(|_| ());
+		// until it closes its write half, in which case we finish sending what's
+		// already queued, the empty end marker going out last
+		let end_tx = tx.clone();
+		let read_msg = me.read_msg(tx, reader, read_limit, checksums, dumps, handler).map(move |_| {
+			debug!("Remote peer closed its write half, finishing our writes.");
+			let _ = end_tx.send(vec![]);
+		});
 
 		// setting the writing future, getting messages from our system and sending
 		// them out
-		let write_msg = me.write_msg(rx, writer).map(|_| ());
+		let write_msg = me.write_msg(rx, writer, throttle, checksums).map(move |_| {
+			if closing.load(atomic::Ordering::Relaxed) {
+				let _ = flushed_tx.send(());
+			}
+		});
 
-		// select between our different futures and return them
-		let fut =
-			Box::new(close_conn.select(read_msg.select(write_msg).map(|_| ()).map_err(|(e, _)| e))
-				.map(|_| ())
-				.map_err(|(e, _)| e));
+		// any error on either side tears down right away, dropping both halves
+		// closes the socket altogether
+		let fut = Box::new(close_conn.select(graceful_conn)
+			.map(|_| ())
+			.map_err(|(e, _)| e)
+			.select(read_msg.join(write_msg).map(|_| ()))
+			.map(|_| ())
+			.map_err(|(e, _)| e));
 
 		(me, fut)
 	}
 
 	/// Prepares the future that gets message data produced by our system and
-	/// sends it to the peer connection
+	/// sends it to the peer connection, as fast as the throttle allows
 	fn write_msg(&self,
 	             rx: UnboundedReceiver<Vec<u8>>,
-	             writer: WriteHalf<TcpStream>)
-	             -> Box<Future<Item = WriteHalf<TcpStream>, Error = Error>> {
+	             writer: WriteHalf<PeerStream>,
+	             throttle: Throttle,
+	             checksums: bool)
+	             -> Box<Future<Item = WriteHalf<PeerStream>, Error = Error>> {
 
-		let sent_bytes = self.sent_bytes.clone();
-		let send_data = rx
+		let stats = self.stats.clone();
+		let traffic = self.traffic.clone();
+		let deadline = throttle.write_deadline();
+		let send_data = PriorityQueue::new(rx)
 			.map_err(|_| Error::ConnectionClose)
-      .map(move |data| {
+			.take_while(|data| Ok(!data.is_empty()))
+      .map(move |mut data| {
+				if checksums && data.len() >= HEADER_LEN as usize {
+					let sum = checksum(&data[HEADER_LEN as usize..]);
+					data.extend_from_slice(&sum);
+				}
         // add the count of bytes sent
-				let mut sent_bytes = sent_bytes.lock().unwrap();
-				*sent_bytes += data.len() as u64;
+				stats.sent(data.len());
+				traffic.sent(data.len());
 				data
 			})
       // write the data and make sure the future returns the right types
-			.fold(writer, |writer, data| {
-        write_all(writer, data).map_err(|e| Error::Connection(e)).map(|(writer, buf)| writer)
+			.fold(writer, move |writer, data| {
+        let deadline = deadline.clone();
+        throttle.wait(data.len() as u64).and_then(move |_| {
+          let write =
+            write_all(writer, data).map_err(|e| Error::Connection(e)).map(|(writer, buf)| writer);
+          with_deadline(write, deadline)
+        })
       });
 		Box::new(send_data)
 	}
 
 	/// Prepares the future reading from the peer connection, parsing each
-	/// message and forwarding them appropriately based on their type
+	/// message and forwarding them appropriately based on their type, as fast
+	/// as the read limit allows
 	fn read_msg<F>(&self,
 	               sender: UnboundedSender<Vec<u8>>,
-	               reader: ReadHalf<TcpStream>,
+	               reader: ReadHalf<PeerStream>,
+	               read_limit: Option<ReadLimit>,
+	               checksums: bool,
+	               dumps: bool,
 	               handler: F)
-	               -> Box<Future<Item = ReadHalf<TcpStream>, Error = Error>>
+	               -> Box<Future<Item = ReadHalf<PeerStream>, Error = Error>>
 		where F: Handler + 'static
 	{
 
-		// infinite iterator stream so we repeat the message reading logic until the
-		// peer is stopped
-		let iter = stream::iter(iter::repeat(()).map(Ok::<(), Error>));
-
 		// setup the reading future, getting messages from the peer and processing them
-		let recv_bytes = self.received_bytes.clone();
+		let stats = self.stats.clone();
+		let traffic = self.traffic.clone();
 		let handler = Arc::new(handler);
+		let read_limit = Arc::new(read_limit);
+		let magic = self.magic;
 
-		let read_msg = iter.fold(reader, move |reader, _| {
-			let recv_bytes = recv_bytes.clone();
+		// repeat the message reading logic until the peer is stopped or closes
+		// its write half
+		let read_msg = future::loop_fn(reader, move |reader| {
+			let stats = stats.clone();
+			let read_limit = read_limit.clone();
+			let traffic = traffic.clone();
 			let handler = handler.clone();
 			let sender_inner = sender.clone();
 
 			// first read the message header
-			read_exact(reader, vec![0u8; HEADER_LEN as usize])
-				.from_err()
-				.and_then(move |(reader, buf)| {
-					let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
-					Ok((reader, header))
-				})
-				.and_then(move |(reader, header)| {
-					// now that we have a size, proceed with the body
-					read_exact(reader, vec![0u8; header.msg_len as usize])
-						.map(|(reader, buf)| (reader, header, buf))
-						.from_err()
-				})
-				.and_then(move |(reader, header, buf)| {
-					// add the count of bytes received
-					let mut recv_bytes = recv_bytes.lock().unwrap();
-					*recv_bytes += header.serialized_len() + header.msg_len;
-
-					// and handle the different message types
-					let msg_type = header.msg_type;
-					if let Err(e) = handler.handle(sender_inner.clone(), header, buf) {
-						debug!("Invalid {:?} message: {}", msg_type, e);
-						return Err(Error::Serialization(e));
-					}
-
-					Ok(reader)
-				})
+			read_header(reader, magic, dumps).and_then(move |(reader, header)| -> ReadLoopFuture {
+				let header = match header {
+					Some(header) => header,
+					None => return Box::new(future::ok(Loop::Break(reader))),
+				};
+				// now that we have a size, proceed with the body and its checksum
+				let sum_len = if checksums { CHECKSUM_LEN } else { 0 };
+				let read_body = read_exact(reader, vec![0u8; header.msg_len as usize + sum_len])
+					.from_err()
+					.and_then(move |(reader, mut buf)| -> ReadLoopFuture {
+						// add the count of bytes received
+						let len = header.serialized_len() + buf.len() as u64;
+						stats.received(len as usize);
+						traffic.received(len as usize);
+
+						// a peer over the limits gets disconnected or has to wait
+						let throttled = match *read_limit {
+							Some(ref limit) => {
+								match limit.wait(len) {
+									Ok(throttled) => throttled,
+									Err(e) => {
+										debug!("Peer over the inbound limits, disconnecting.");
+										return Box::new(future::err(e));
+									}
+								}
+							}
+							None => None,
+						};
+
+						// a mismatch means corruption in transit, not a bad message
+						if checksums {
+							let sum = buf.split_off(header.msg_len as usize);
+							if sum[..] != checksum(&buf)[..] {
+								debug!("Checksum mismatch on a {:?} message.", header.msg_type);
+								return Box::new(future::err(Error::Checksum));
+							}
+						}
+
+						// and handle the different message types, waiting for the
+						// handler to be ready again if it asks us to
+						let msg_type = header.msg_type;
+						// the handler takes the body, only the start of the frame
+						// that would get dumped is kept
+						let frame = if dumps {
+							let mut frame = ser::ser_vec(&header).unwrap_or(vec![]);
+							let len = frame.len() + buf.len();
+							let room = MAX_DUMP_LEN.saturating_sub(frame.len());
+							let kept = ::std::cmp::min(buf.len(), room);
+							frame.extend_from_slice(&buf[..kept]);
+							Some((frame, len))
+						} else {
+							None
+						};
+						match handler.handle(sender_inner.clone(), header, buf) {
+							Ok(pause) => {
+								match join_pauses(pause, throttled) {
+									Some(p) => Box::new(p.map(move |_| Loop::Continue(reader))),
+									None => Box::new(future::ok(Loop::Continue(reader))),
+								}
+							}
+							Err(e) => {
+								debug!("Invalid {:?} message: {}", msg_type, e);
+								if let Some((frame, len)) = frame {
+									info!("Undecodable message of type {}: {}",
+									      msg_type as u8,
+									      hex_dump_of(&frame, len));
+								}
+								Box::new(future::err(Error::Serialization(e)))
+							}
+						}
+					});
+				Box::new(read_body)
+			})
 		});
 		Box::new(read_msg)
 	}
@@ -203,71 +395,355 @@ impl Connection {
 	/// Utility function to send any Writeable. Handles adding the header and
 	/// serialization.
 	pub fn send_msg<W: ser::Writeable> (&self, t: Type, body: &W) -> Result<(), Error> {
+		self.send_msg_with_id(t, body, 0)
+	}
 
+	/// Same as send_msg, but identifying the message with the provided id in
+	/// its header.
+	pub fn send_msg_with_id<W: ser::Writeable>(&self,
+	                                           t: Type,
+	                                           body: &W,
+	                                           id: u32)
+	                                           -> Result<(), Error> {
 		let mut body_data = vec![];
 		try!(ser::serialize(&mut body_data, body));
 		let mut data = vec![];
-		try!(ser::serialize(&mut data, &MsgHeader::new(t, body_data.len() as u64)));
+		let header = MsgHeader::with_id(self.magic, t, body_data.len() as u64, id);
+		try!(ser::serialize(&mut data, &header));
 		data.append(&mut body_data);
 
+		if self.closing.load(atomic::Ordering::Relaxed) {
+			return Err(Error::ConnectionClose);
+		}
 		self.outbound_chan.send(data).map_err(|_| Error::ConnectionClose)
 	}
 
+	/// Closes the connection to the remote peer, the future returned by
+	/// listen will resolve once the close signal is processed.
+	pub fn close(&self) {
+		let _ = self.close_chan.send(());
+	}
+
+	/// Closes the connection to the remote peer once the messages already
+	/// queued went out, refusing to queue new ones. Closes anyway after the
+	/// timeout, should the peer be too slow to take them.
+	pub fn close_gracefully(&self, timeout: Duration) {
+		let graceful = self.graceful_chan.lock().unwrap_or_else(|e| e.into_inner()).take();
+		if let Some(graceful) = graceful {
+			self.closing.store(true, atomic::Ordering::Relaxed);
+			// the empty end marker makes the writer stop after what's queued
+			let _ = self.outbound_chan.send(vec![]);
+			let _ = graceful.send(timeout);
+		}
+	}
+
 	/// Bytes sent and received by this peer to the remote peer.
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
-		let sent = *self.sent_bytes.lock().unwrap();
-		let recv = *self.received_bytes.lock().unwrap();
-		(sent, recv)
+		self.stats.totals()
+	}
+
+	/// Messages sent and received by this peer to the remote peer.
+	pub fn transmitted_msgs(&self) -> (u64, u64) {
+		self.stats.msgs()
+	}
+
+	/// Zeroes the bytes and messages sent and received as well as the error
+	/// count, the connection itself is left alone.
+	pub fn reset_stats(&self) {
+		self.stats.reset();
+		*self.error_count.lock().unwrap_or_else(|e| e.into_inner()) = 0;
+	}
+}
+
+// Pause lasting until both provided ones are over, if any.
+fn join_pauses(a: Option<Pause>, b: Option<Pause>) -> Option<Pause> {
+	match (a, b) {
+		(Some(a), Some(b)) => Some(Box::new(a.join(b).map(|_| ()))),
+		(Some(p), None) | (None, Some(p)) => Some(p),
+		(None, None) => None,
+	}
+}
+
+// Fails the provided write with a timeout if it doesn't complete before the
+// deadline, so a peer not reading what we send can't hold on to us forever.
+fn with_deadline<F>(write: F,
+                    deadline: Option<(Duration, Timer)>)
+                    -> Box<Future<Item = F::Item, Error = Error>>
+	where F: Future<Error = Error> + 'static,
+	      F::Item: 'static
+{
+	match deadline {
+		Some((timeout, timer)) => {
+			let expired = throttle::sleep(&timer, timeout)
+				.then(|_| -> Result<F::Item, Error> { Err(Error::Timeout) });
+			Box::new(write.select(expired).map(|(w, _)| w).map_err(|(e, _)| e))
+		}
+		None => Box::new(write),
+	}
+}
+
+type ReadLoopFuture = Box<Future<Item = Loop<ReadHalf<PeerStream>, ReadHalf<PeerStream>>,
+                                  Error = Error>>;
+
+type HeaderFuture = Box<Future<Item = (ReadHalf<PeerStream>, Option<MsgHeader>), Error = Error>>;
+
+/// Reads a message header, resolving to None if the peer cleanly closed its
+/// write half instead of starting a new message. Closing in the middle of a
+/// header is an error like any other, as is a header starting with other
+/// magic bytes than the provided ones. With dumps, a header we can't decode
+/// gets logged in hex.
+fn read_header(reader: ReadHalf<PeerStream>, magic: [u8; 2], dumps: bool) -> HeaderFuture {
+	let header = read(reader, vec![0u8; HEADER_LEN as usize])
+		.from_err()
+		.and_then(move |(reader, mut buf, n)| -> HeaderFuture {
+			if n == 0 {
+				return Box::new(future::ok((reader, None)));
+			}
+			// got the beginning of a header, the rest has to follow
+			let rest = buf.split_off(n);
+			Box::new(read_exact(reader, rest).from_err().and_then(move |(reader, rest)| {
+				buf.extend_from_slice(&rest);
+				let header = ser::deserialize::<MsgHeader>(&mut &buf[..]).map_err(|e| {
+						if dumps {
+							// the type directly follows the 2 bytes of magic number
+							info!("Undecodable message header of type {}: {}",
+							      buf[2],
+							      hex_dump(&buf));
+						}
+						e
+					})?;
+				if header.magic != magic {
+					return Err(Error::WrongNetwork);
+				}
+				Ok((reader, Some(header)))
+			}))
+		});
+	Box::new(header)
+}
+
+/// A message waiting to be sent, ranked by the priority of its type aged by
+/// its position in the queue.
+struct Queued {
+	rank: i64,
+	seq: u64,
+	data: Vec<u8>,
+}
+
+impl Queued {
+	fn new(seq: u64, data: Vec<u8>) -> Queued {
+		// the message type directly follows the 2 bytes of magic number
+		let prio = if data.len() > 2 {
+			Type::from_u8(data[2]).map(|t| t.priority()).unwrap_or(0)
+		} else {
+			0
+		};
+		Queued {
+			rank: seq as i64 - (prio * PRIORITY_AGING) as i64,
+			seq: seq,
+			data: data,
+		}
+	}
+}
+
+impl PartialEq for Queued {
+	fn eq(&self, other: &Queued) -> bool {
+		self.seq == other.seq
+	}
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+	fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Queued {
+	// reversed as BinaryHeap pops the greatest first and we want the lowest
+	// rank, falling back to the queuing order
+	fn cmp(&self, other: &Queued) -> Ordering {
+		match other.rank.cmp(&self.rank) {
+			Ordering::Equal => other.seq.cmp(&self.seq),
+			ord => ord,
+		}
+	}
+}
+
+/// Stream adapter over the outbound channel, reordering the messages already
+/// queued by priority each time the writer asks for the next one.
+struct PriorityQueue<S> {
+	inner: S,
+	heap: BinaryHeap<Queued>,
+	seq: u64,
+	done: bool,
+}
+
+impl<S> PriorityQueue<S> {
+	fn new(inner: S) -> PriorityQueue<S> {
+		PriorityQueue {
+			inner: inner,
+			heap: BinaryHeap::new(),
+			seq: 0,
+			done: false,
+		}
+	}
+}
+
+impl<S> Stream for PriorityQueue<S>
+	where S: Stream<Item = Vec<u8>>
+{
+	type Item = Vec<u8>;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> Poll<Option<Vec<u8>>, S::Error> {
+		// drain everything that's been queued so far
+		while !self.done {
+			match self.inner.poll()? {
+				Async::Ready(Some(data)) => {
+					self.heap.push(Queued::new(self.seq, data));
+					self.seq += 1;
+				}
+				Async::Ready(None) => self.done = true,
+				Async::NotReady => break,
+			}
+		}
+		match self.heap.pop() {
+			Some(q) => Ok(Async::Ready(Some(q.data))),
+			None if self.done => Ok(Async::Ready(None)),
+			None => Ok(Async::NotReady),
+		}
+	}
+}
+
+/// Requests sent to the remote peer still waiting for their response, keyed
+/// by the id the response has to echo.
+struct PendingRequests {
+	next_id: u32,
+	pending: HashMap<u32, (Type, Instant, oneshot::Sender<Vec<u8>>)>,
+	// whether the remote peer let too many requests pile up
+	overflowed: bool,
+}
+
+impl PendingRequests {
+	fn new() -> PendingRequests {
+		PendingRequests {
+			next_id: 1,
+			pending: HashMap::new(),
+			overflowed: false,
+		}
+	}
+
+	/// Whether no more requests can be registered, the remote peer having let
+	/// too many of them pile up. All pending requests then get dropped, their
+	/// receivers getting canceled.
+	fn full(&mut self) -> bool {
+		if !self.overflowed && self.pending.len() >= MAX_PENDING_REQUESTS {
+			self.pending.clear();
+			self.overflowed = true;
+		}
+		self.overflowed
+	}
+
+	/// Registers a new request expecting a response of the provided type.
+	/// Returns the request id and a receiver for the response body.
+	fn register(&mut self, rt: Type, now: Instant) -> (u32, oneshot::Receiver<Vec<u8>>) {
+		let id = self.next_id;
+		// zero is reserved for messages that aren't requests or responses
+		self.next_id = match self.next_id.wrapping_add(1) {
+			0 => 1,
+			n => n,
+		};
+		let (tx, rx) = oneshot::channel();
+		self.pending.insert(id, (rt, now, tx));
+		(id, rx)
+	}
+
+	/// Completes the pending request the received message responds to, if
+	/// any, returning whether there was one. An error echoing the id of a
+	/// request fails it, the remote peer not answering it otherwise.
+	fn complete(&mut self, header: &MsgHeader, body: &[u8]) -> bool {
+		if header.msg_type == Type::Error && header.id != 0 {
+			return self.pending.remove(&header.id).is_some();
+		}
+		let matched = match self.pending.get(&header.id) {
+			Some(&(rt, _, _)) => rt == header.msg_type,
+			None => false,
+		};
+		if matched {
+			let (_, _, tx) = self.pending.remove(&header.id).unwrap();
+			// only bother copying the body if someone's waiting for it
+			if !tx.is_canceled() {
+				let _ = tx.send(body.to_vec());
+			}
+		}
+		matched
+	}
+
+	/// Number of pending requests expecting a response of the provided type.
+	fn count(&self, rt: Type) -> usize {
+		self.pending.values().filter(|&&(t, _, _)| t == rt).count()
+	}
+
+	/// Drops the requests that have been waiting for longer than the timeout,
+	/// their receivers getting canceled. Returns how many timed out.
+	fn expire(&mut self, now: Instant, timeout: Duration) -> usize {
+		let expired = self.pending
+			.iter()
+			.filter(|&(_, &(_, t, _))| now.duration_since(t) > timeout)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		for id in &expired {
+			self.pending.remove(id);
+		}
+		expired.len()
 	}
 }
 
 /// Connection wrapper that handles a request/response oriented interaction with
-/// a timeout.
+/// a timeout. Requests are identified so their responses can come back in any
+/// order.
 pub struct TimeoutConnection {
 	underlying: Connection,
 
-	expected_responses: Arc<Mutex<Vec<(Type, Option<Hash>, Instant)>>>,
+	expected_responses: Arc<Mutex<PendingRequests>>,
 }
 
 impl TimeoutConnection {
 	/// Same as Connection
-	pub fn listen<F>(conn: TcpStream,
+	pub fn listen<F>(conn: PeerStream,
+	                 magic: [u8; 2],
+	                 throttle: Throttle,
+	                 stats: Arc<Traffic>,
+	                 traffic: Arc<Traffic>,
+	                 checksums: bool,
+	                 dumps: bool,
 	                 handler: F)
 	                 -> (TimeoutConnection, Box<Future<Item = (), Error = Error>>)
 		where F: Handler + 'static
 	{
 
-		let expects = Arc::new(Mutex::new(vec![]));
+		let expects = Arc::new(Mutex::new(PendingRequests::new()));
 
-		// Decorates the handler to remove the "subscription" from the expected
-		// responses. We got our replies, so no timeout should occur.
+		// Decorates the handler to complete the request the message responds to.
+		// We got our reply, so no timeout should occur.
 		let exp = expects.clone();
-		let (conn, fut) = Connection::listen(conn, move |sender, header: MsgHeader, data| {
-			let msg_type = header.msg_type;
-			let recv_h = try!(handler.handle(sender, header, data));
-
-			let mut expects = exp.lock().unwrap();
-			let filtered = expects.iter()
-				.filter(|&&(typ, h, _): &&(Type, Option<Hash>, Instant)| {
-					msg_type != typ || h.is_some() && recv_h != h
-				})
-				.map(|&x| x)
-				.collect::<Vec<_>>();
-			*expects = filtered;
-
-			Ok(recv_h)
-		});
+		let complete = move |sender: UnboundedSender<Vec<u8>>, header: MsgHeader, data: Vec<u8>| {
+			exp.lock().unwrap_or_else(|e| e.into_inner()).complete(&header, &data);
+			handler.handle(sender, header, data)
+		};
+		let (conn, fut) =
+			Connection::listen(conn, magic, throttle, stats, traffic, checksums, dumps, complete);
 
 		// Registers a timer with the event loop to regularly check for timeouts.
 		let exp = expects.clone();
 		let timer = Timer::default()
 			.interval(Duration::new(2, 0))
 			.fold((), move |_, _| {
-				let exp = exp.lock().unwrap();
-				for &(_, _, t) in exp.deref() {
-					if Instant::now() - t > Duration::new(2, 0) {
-						return Err(TimerError::TooLong);
-					}
+				let mut exp = exp.lock().unwrap_or_else(|e| e.into_inner());
+				let expired = exp.expire(Instant::now(), Duration::new(2, 0));
+				if expired > 0 || exp.overflowed {
+					return Err(TimerError::TooLong);
 				}
 				Ok(())
 			})
@@ -280,19 +756,41 @@ impl TimeoutConnection {
 		(me, Box::new(fut.select(timer).map(|_| ()).map_err(|(e1, e2)| e1)))
 	}
 
-	/// Sends a request and registers a timer on the provided message type and
-	/// optionally the hash of the sent data.
-	pub fn send_request<W: ser::Writeable>(&self,
-	                    t: Type,
-	                    rt: Type,
-	                    body: &W,
-	                    expect_h: Option<(Hash)>)
-	                    -> Result<(), Error> {
-		let sent = try!(self.underlying.send_msg(t, body));
+	/// Sends a request and registers a timer on it, expecting a response of
+	/// the provided type.
+	pub fn send_request<W: ser::Writeable>(&self, t: Type, rt: Type, body: &W) -> Result<(), Error> {
+		self.request(t, rt, body).map(|_| ())
+	}
+
+	/// Sends a request expecting a response of the provided type, returning a
+	/// future resolving to the body of that response. Several requests can be
+	/// in flight at once, each getting its own response whatever the order
+	/// they come back in, up to MAX_PENDING_REQUESTS.
+	pub fn request<W: ser::Writeable>(&self,
+	                                  t: Type,
+	                                  rt: Type,
+	                                  body: &W)
+	                                  -> Result<Box<Future<Item = Vec<u8>, Error = Error>>, Error> {
+		let (id, resp) = {
+			let mut expected = self.expected_responses.lock().unwrap_or_else(|e| e.into_inner());
+			if expected.full() {
+				debug!("Too many requests left unanswered, not sending {:?}.", t);
+				return Err(Error::TooManyRequests);
+			}
+			expected.register(rt, Instant::now())
+		};
+		if let Err(e) = self.underlying.send_msg_with_id(t, body, id) {
+			self.expected_responses.lock().unwrap_or_else(|e| e.into_inner()).pending.remove(&id);
+			return Err(e);
+		}
+		// canceled if the request timed out or the connection went away
+		Ok(Box::new(resp.map_err(|_| Error::Timeout)))
+	}
 
-		let mut expects = self.expected_responses.lock().unwrap();
-		expects.push((rt, expect_h, Instant::now()));
-		Ok(())
+	/// Number of requests sent still waiting for a response of the provided
+	/// type.
+	pub fn pending_requests(&self, rt: Type) -> usize {
+		self.expected_responses.lock().unwrap_or_else(|e| e.into_inner()).count(rt)
 	}
 
 	/// Same as Connection
@@ -300,8 +798,229 @@ impl TimeoutConnection {
 		self.underlying.send_msg(t, body)
 	}
 
+	/// Same as Connection
+	pub fn close(&self) {
+		self.underlying.close()
+	}
+
+	/// Same as Connection
+	pub fn close_gracefully(&self, timeout: Duration) {
+		self.underlying.close_gracefully(timeout)
+	}
+
 	/// Same as Connection
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
 		self.underlying.transmitted_bytes()
 	}
+
+	/// Same as Connection
+	pub fn transmitted_msgs(&self) -> (u64, u64) {
+		self.underlying.transmitted_msgs()
+	}
+
+	/// Same as Connection
+	pub fn reset_stats(&self) {
+		self.underlying.reset_stats()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Write};
+	use std::net::{self, Shutdown};
+	use std::sync::Arc;
+	use std::time::{Duration, Instant};
+
+	use futures::{Future, Stream};
+	use futures::sync::mpsc;
+	use tokio_core::net::TcpListener;
+	use tokio_core::reactor::Core;
+
+	use core::ser;
+	use msg::*;
+	use super::{Connection, MAX_PENDING_REQUESTS, PendingRequests, PriorityQueue,
+	            TimeoutConnection, Traffic};
+	use stream::PeerStream;
+	use throttle::Throttle;
+	use types::{Error, ProtocolConfig};
+
+	fn magic() -> [u8; 2] {
+		ProtocolConfig::default().magic
+	}
+
+	fn msg(t: Type) -> Vec<u8> {
+		ser::ser_vec(&MsgHeader::new(magic(), t, 0)).unwrap()
+	}
+
+	#[test]
+	fn checksum_known_value() {
+		assert_eq!(checksum(b"123456789"), [0xcb, 0xf4, 0x39, 0x26]);
+		assert_eq!(checksum(&[]), [0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn block_before_gossip() {
+		let (tx, rx) = mpsc::unbounded();
+		tx.send(msg(Type::PeerAddrs)).unwrap();
+		tx.send(msg(Type::Transaction)).unwrap();
+		tx.send(msg(Type::Block)).unwrap();
+		drop(tx);
+
+		let sent = PriorityQueue::new(rx).wait().map(|d| d.unwrap()[2]).collect::<Vec<_>>();
+		assert_eq!(sent,
+		           vec![Type::Block as u8, Type::Transaction as u8, Type::PeerAddrs as u8]);
+	}
+
+	#[test]
+	fn gossip_not_starved() {
+		let (tx, rx) = mpsc::unbounded();
+		tx.send(msg(Type::PeerAddrs)).unwrap();
+		for _ in 0..(3 * PRIORITY_AGING) {
+			tx.send(msg(Type::Block)).unwrap();
+		}
+		drop(tx);
+
+		// the gossip message ages and eventually goes out before later blocks
+		let sent = PriorityQueue::new(rx).wait().map(|d| d.unwrap()[2]).collect::<Vec<_>>();
+		let pos = sent.iter().position(|t| *t == Type::PeerAddrs as u8).unwrap();
+		assert!(pos <= (2 * PRIORITY_AGING) as usize);
+		assert!(pos < sent.len() - 1);
+	}
+
+	// Accepts a single connection and answers pings with pongs, returning the
+	// client side as a regular blocking socket after it wrote the provided data
+	// and closed its write half. Runs the connection on our end to completion.
+	fn half_closed(data: &[u8]) -> (net::TcpStream, Result<(), ::types::Error>) {
+		let mut core = Core::new().unwrap();
+		let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+		let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		client.write_all(data).unwrap();
+		client.shutdown(Shutdown::Write).unwrap();
+
+		let (conn, _) = core.run(listener.incoming().into_future().map_err(|(e, _)| e))
+			.unwrap()
+			.0
+			.unwrap();
+		let pong = |sender: mpsc::UnboundedSender<Vec<u8>>, header: MsgHeader, _: Vec<u8>| {
+			if header.msg_type == Type::Ping {
+				sender.send(msg(Type::Pong)).unwrap();
+			}
+			Ok(None)
+		};
+		let (stats, traffic) = (Arc::new(Traffic::new()), Arc::new(Traffic::new()));
+		let conn = PeerStream::from(conn);
+		let (_conn, fut) = Connection::listen(conn,
+		                                      magic(),
+		                                      Throttle::unlimited(),
+		                                      stats,
+		                                      traffic,
+		                                      false,
+		                                      false,
+		                                      pong);
+		let res = core.run(fut);
+		(client, res)
+	}
+
+	#[test]
+	fn half_closed_finishes_writes() {
+		let (mut client, res) = half_closed(&msg(Type::Ping));
+		assert!(res.is_ok());
+
+		// our pong still went out before the connection got closed
+		let mut resp = vec![];
+		client.read_to_end(&mut resp).unwrap();
+		assert_eq!(resp, msg(Type::Pong));
+	}
+
+	#[test]
+	fn half_closed_mid_message() {
+		let (_, res) = half_closed(&msg(Type::Ping)[0..4]);
+		assert!(res.is_err());
+	}
+
+	#[test]
+	fn responses_matched_by_id() {
+		let now = Instant::now();
+		let mut pending = PendingRequests::new();
+		let (headers_id, headers_rx) = pending.register(Type::Headers, now);
+		let (block_id, block_rx) = pending.register(Type::Block, now);
+		let (other_id, _) = pending.register(Type::Block, now);
+		assert!(headers_id != block_id && block_id != other_id);
+		assert_eq!(pending.count(Type::Block), 2);
+
+		// responses coming back out of order, a mismatched type and an unknown id
+		assert!(!pending.complete(&MsgHeader::with_id(magic(), Type::Block, 1, headers_id), &[1]));
+		assert!(!pending.complete(&MsgHeader::with_id(magic(), Type::Block, 1, 0), &[1]));
+		assert!(pending.complete(&MsgHeader::with_id(magic(), Type::Block, 1, block_id), &[2]));
+		assert!(pending.complete(&MsgHeader::with_id(magic(), Type::Headers, 1, headers_id), &[3]));
+		assert!(!pending.complete(&MsgHeader::with_id(magic(), Type::Block, 1, block_id), &[4]));
+		assert_eq!(pending.count(Type::Block), 1);
+
+		assert_eq!(block_rx.wait().unwrap(), vec![2]);
+		assert_eq!(headers_rx.wait().unwrap(), vec![3]);
+
+		// a request the peer doesn't support, failed by the error it sends back
+		let (unsupported_id, unsupported_rx) = pending.register(Type::PeerInfoResp, now);
+		let error = MsgHeader::with_id(magic(), Type::Error, 1, unsupported_id);
+		assert!(pending.complete(&error, &[5]));
+		assert!(unsupported_rx.wait().is_err());
+		assert_eq!(pending.count(Type::PeerInfoResp), 0);
+	}
+
+	#[test]
+	fn unanswered_requests_expire() {
+		let now = Instant::now();
+		let mut pending = PendingRequests::new();
+		let (_, old_rx) = pending.register(Type::Pong, now);
+		let (recent_id, _recent_rx) = pending.register(Type::Pong, now + Duration::from_secs(2));
+
+		assert_eq!(pending.expire(now + Duration::from_secs(3), Duration::from_secs(2)), 1);
+		assert!(old_rx.wait().is_err());
+		assert!(pending.complete(&MsgHeader::with_id(magic(), Type::Pong, 0, recent_id), &[]));
+	}
+
+	#[test]
+	fn pending_requests_bounded() {
+		let mut core = Core::new().unwrap();
+		let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &core.handle()).unwrap();
+		// the peer never answers anything
+		let _silent = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (conn, _) = core.run(listener.incoming().into_future().map_err(|(e, _)| e))
+			.unwrap()
+			.0
+			.unwrap();
+		let ignore = |_: mpsc::UnboundedSender<Vec<u8>>, _: MsgHeader, _: Vec<u8>| Ok(None);
+		let (stats, traffic) = (Arc::new(Traffic::new()), Arc::new(Traffic::new()));
+		let conn = PeerStream::from(conn);
+		let (conn, fut) = TimeoutConnection::listen(conn,
+		                                            magic(),
+		                                            Throttle::unlimited(),
+		                                            stats,
+		                                            traffic,
+		                                            false,
+		                                            false,
+		                                            ignore);
+
+		let first = conn.request(Type::Ping, Type::Pong, &Empty {}).unwrap();
+		for _ in 1..MAX_PENDING_REQUESTS {
+			conn.request(Type::Ping, Type::Pong, &Empty {}).unwrap();
+		}
+		assert_eq!(conn.expected_responses.lock().unwrap().pending.len(), MAX_PENDING_REQUESTS);
+
+		// one more is refused, everything pending gets dropped
+		match conn.request(Type::Ping, Type::Pong, &Empty {}) {
+			Err(Error::TooManyRequests) => {}
+			_ => panic!("request over the limit accepted"),
+		}
+		assert!(conn.expected_responses.lock().unwrap().pending.is_empty());
+		match core.run(first) {
+			Err(Error::Timeout) => {}
+			_ => panic!("dropped request didn't time out"),
+		}
+
+		// and the connection goes down like on a timeout
+		let start = Instant::now();
+		assert!(core.run(fut).is_err());
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
 }
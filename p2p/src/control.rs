@@ -0,0 +1,255 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local admin surface for the p2p server over a Unix domain socket. Accepts
+//! simple line-based commands and replies with one or more lines of text,
+//! terminated by an empty line:
+//!
+//! * list: one line per connected peer
+//! * stats [<peer>]: traffic, latency and uptime of every connected peer, or
+//!   of the provided one
+//! * connect <addr>: asks the server to connect to a new peer
+//! * disconnect <peer> [force]: disconnects from a peer, once what's queued
+//!   for it went out unless forced
+//! * ban <peer>: bans and disconnects a peer
+//! * quarantine <peer>: keeps a peer away for a while and disconnects it
+//! * bans: one line per banned or quarantined host
+//! * unban <ip>: lifts the ban or quarantine of a host
+//!
+//! Connected peers can be referred to by address, onion ones included, or by
+//! id, as shown by list. Each client is served on its own thread, up to
+//! MAX_CONTROL_CLIENTS at once.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use futures::Future;
+use tokio_core::reactor;
+
+use proxy::parse_peer_addr;
+use server::{PeerStats, Server};
+use types::{PeerId, Severity};
+
+/// Control clients served at once, those connecting past it are told so and
+/// disconnected.
+pub const MAX_CONTROL_CLIENTS: usize = 16;
+
+/// Starts listening for admin commands on the Unix socket at the provided
+/// path. Every client gets a thread of its own processing its commands,
+/// futures that need to run on the event loop (like connecting) are sent
+/// there through the provided remote. Only the owner of the process can
+/// connect to the socket, and anything but a socket already at the path is
+/// left alone, failing to start.
+pub fn start_control(p2p: Arc<Server>, path: String, remote: reactor::Remote) -> io::Result<()> {
+	// a socket file left over by a previous run would prevent the bind
+	match fs::symlink_metadata(&path) {
+		Ok(meta) => {
+			if !meta.file_type().is_socket() {
+				return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+				                          format!("{} exists and isn't a socket", path)));
+			}
+			fs::remove_file(&path)?;
+		}
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+		Err(e) => return Err(e),
+	}
+	let listener = UnixListener::bind(&path)?;
+	fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+	warn!("P2P control listener started on {}", path);
+
+	let clients = Arc::new(AtomicUsize::new(0));
+	thread::Builder::new().name("p2p-control".to_string()).spawn(move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => serve_client(stream, p2p.clone(), remote.clone(), clients.clone()),
+				Err(e) => error!("Control listener error: {:?}", e),
+			}
+		}
+	})?;
+	Ok(())
+}
+
+// Serves a control client on a thread of its own, unless we're already
+// serving as many as we can.
+fn serve_client(mut stream: UnixStream,
+                p2p: Arc<Server>,
+                remote: reactor::Remote,
+                clients: Arc<AtomicUsize>) {
+	if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CONTROL_CLIENTS {
+		clients.fetch_sub(1, Ordering::SeqCst);
+		let _ = stream.write_all(b"too many control clients\n\n");
+		return;
+	}
+	let served = clients.clone();
+	let res = thread::Builder::new().name("p2p-control-client".to_string()).spawn(move || {
+		if let Err(e) = handle_client(stream, &p2p, &remote) {
+			debug!("Control client error: {:?}", e);
+		}
+		served.fetch_sub(1, Ordering::SeqCst);
+	});
+	if let Err(e) = res {
+		error!("Could not serve control client: {:?}", e);
+		clients.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+// Reads commands line by line from a control client until it disconnects.
+fn handle_client(stream: UnixStream,
+                 p2p: &Arc<Server>,
+                 remote: &reactor::Remote)
+                 -> io::Result<()> {
+	let mut writer = stream.try_clone()?;
+	let reader = BufReader::new(stream);
+	for line in reader.lines() {
+		let line = line?;
+		for reply in exec_command(line.trim(), p2p, remote) {
+			writer.write_all(reply.as_bytes())?;
+			writer.write_all(b"\n")?;
+		}
+		writer.write_all(b"\n")?;
+	}
+	Ok(())
+}
+
+// Executes a single command, returning the lines to reply with.
+fn exec_command(cmd: &str, p2p: &Arc<Server>, remote: &reactor::Remote) -> Vec<String> {
+	let mut parts = cmd.split_whitespace();
+	let verb = parts.next().unwrap_or("");
+	let arg = parts.next();
+	let addr = arg.and_then(|a| peer_addr(a, p2p));
+	let force = parts.next() == Some("force");
+
+	match (verb, addr) {
+		("list", _) => {
+			p2p.connected_peers()
+				.iter()
+				.map(|p| {
+					format!("{} id={} version={} capabilities={:b} services={:b} \
+					         total_difficulty={} orphans={} violations={} user_agent={}",
+					        p.info.addr,
+					        p.info.id,
+					        p.info.version,
+					        p.info.capabilities.bits(),
+					        p.info.services.bits(),
+					        p.info.total_difficulty,
+					        p.orphan_count(),
+					        p.violation_count(),
+					        p.info.user_agent)
+				})
+				.collect()
+		}
+		("stats", None) if arg.is_none() => p2p.peer_stats().iter().map(stats_line).collect(),
+		("stats", Some(addr)) => {
+			match p2p.peer_info(&addr) {
+				Some(stats) => vec![stats_line(&stats)],
+				None => vec![format!("not connected to {}", addr)],
+			}
+		}
+		("connect", Some(addr)) => {
+			let p2p = p2p.clone();
+			remote.spawn(move |h| {
+				p2p.connect_peer(addr, h.clone()).map(|_| ()).map_err(move |e| {
+					debug!("Control connect to {} failed: {:?}", addr, e);
+					()
+				})
+			});
+			vec![format!("connecting to {}", addr)]
+		}
+		("disconnect", Some(addr)) => {
+			if p2p.disconnect_peer(addr, force) {
+				vec![format!("disconnected from {}", addr)]
+			} else {
+				vec![format!("not connected to {}", addr)]
+			}
+		}
+		("ban", Some(addr)) => {
+			if p2p.ban_peer(addr, Severity::Ban, "control socket") {
+				vec![format!("banned {}", addr)]
+			} else {
+				vec![format!("banned {} (was not connected)", addr)]
+			}
+		}
+		("quarantine", Some(addr)) => {
+			if p2p.ban_peer(addr, Severity::Quarantine, "control socket") {
+				vec![format!("quarantined {}", addr)]
+			} else {
+				vec![format!("quarantined {} (was not connected)", addr)]
+			}
+		}
+		("bans", _) => {
+			p2p.list_bans()
+				.iter()
+				.map(|b| {
+					let expires = match b.expires_in {
+						Some(d) => format!("{}s", d.as_secs()),
+						None => "never".to_string(),
+					};
+					format!("{} severity={:?} reason=\"{}\" since={}s expires={}",
+					        b.ip,
+					        b.severity,
+					        b.reason,
+					        b.since.as_secs(),
+					        expires)
+				})
+				.collect()
+		}
+		("unban", _) => {
+			match arg.and_then(|a| a.parse::<IpAddr>().ok()) {
+				Some(ip) if p2p.unban(ip) => vec![format!("unbanned {}", ip)],
+				Some(ip) => vec![format!("{} not banned", ip)],
+				None => vec![format!("unknown command: {}", cmd)],
+			}
+		}
+		_ => vec![format!("unknown command: {}", cmd)],
+	}
+}
+
+// Line of the stats command for a peer.
+fn stats_line(s: &PeerStats) -> String {
+	let latency = match s.latency {
+		Some(d) => format!("{}ms", d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64),
+		None => "unknown".to_string(),
+	};
+	let height = s.height.map(|h| h.to_string()).unwrap_or("unknown".to_string());
+	format!("{} id={} direction={:?} height={} latency={} sent={}B/{} received={}B/{} uptime={}s",
+	        s.addr,
+	        s.id,
+	        s.direction,
+	        height,
+	        latency,
+	        s.sent_bytes,
+	        s.sent_msgs,
+	        s.received_bytes,
+	        s.received_msgs,
+	        s.uptime_secs)
+}
+
+// Address of the peer a command argument refers to, either directly or by the
+// id of a connected peer.
+fn peer_addr(arg: &str, p2p: &Server) -> Option<SocketAddr> {
+	if let Some(addr) = parse_peer_addr(arg) {
+		return Some(addr);
+	}
+	let id = match arg.parse::<usize>() {
+		Ok(id) => PeerId(id),
+		Err(_) => return None,
+	};
+	p2p.peer_by_id(id).map(|p| p.info.addr)
+}
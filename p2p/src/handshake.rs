@@ -12,132 +12,385 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::{future, Future};
 use rand::Rng;
 use rand::os::OsRng;
+use time;
 use tokio_core::net::TcpStream;
 
+use core::core::hash::Hash;
 use core::core::target::Difficulty;
-use core::ser;
+use core::genesis;
+use conn::Traffic;
 use msg::*;
 use types::*;
 use protocol::ProtocolV1;
 
 const NONCES_CAP: usize = 100;
 
+/// Maximum number of nonces received from other peers we remember.
+const SEEN_NONCES_CAP: usize = 1000;
+
+/// How long we remember the nonces received from other peers for.
+const SEEN_NONCES_SECS: u64 = 600;
+
+/// Default duration above which a successful handshake is considered slow.
+pub const SLOW_HANDSHAKE_MS: u64 = 2000;
+
+/// Default maximum difference in seconds between the clock of a peer and
+/// ours.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 600;
+
+/// Number of recent peer clocks compared with ours to tell whether ours is
+/// off.
+const CLOCK_SAMPLES: usize = 20;
+
+/// Number of recent peers whose clock must be off ours the same way, beyond
+/// the tolerance, for us to suspect our own clock.
+const CLOCK_SUSPECT_PEERS: usize = 5;
+
+/// Byte stream a handshake runs over. Live connections use their socket,
+/// anything readable and writable knowing its remote address will do to
+/// replay a handshake.
+pub trait HandshakeStream: Read + Write + 'static {
+	/// Address of the other side of the stream.
+	fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl HandshakeStream for TcpStream {
+	fn peer_addr(&self) -> io::Result<SocketAddr> {
+		TcpStream::peer_addr(self)
+	}
+}
+
 /// Handles the handshake negotiation when two peers connect and decides on
 /// protocol.
 pub struct Handshake {
 	/// Ring buffer of nonces sent to detect self connections without requiring
 	/// a node id.
 	nonces: Arc<RwLock<VecDeque<u64>>>,
+	/// Services we advertise to the other side.
+	services: Services,
+	/// Handshakes taking longer than this get the peer flagged as slow.
+	slow_threshold: Duration,
+	/// Optional features we negotiate with the other side.
+	features: Features,
+	/// Nonces recently received from other peers, along with the address
+	/// they came from and when, oldest first.
+	seen_nonces: Arc<Mutex<VecDeque<(u64, SocketAddr, Instant)>>>,
+	/// What to do when a nonce comes again from another address.
+	duplicate_nonce: DuplicateNonce,
+	/// How the protocols of the peers frame their messages and handle what
+	/// they send us.
+	protocol: ProtocolConfig,
+	/// Hash of the genesis block of our chain, peers on another one get
+	/// refused.
+	genesis: Hash,
+	/// Peers whose clock differs from ours by more seconds get refused, zero
+	/// to accept any clock.
+	clock_tolerance: u64,
+	/// How far ahead of ours the clocks of the latest peers were, behind if
+	/// negative, oldest first.
+	clock_skews: Arc<Mutex<VecDeque<i64>>>,
 }
 
-unsafe impl Sync for Handshake {}
-unsafe impl Send for Handshake {}
-
 impl Handshake {
 	/// Creates a new handshake handler
 	pub fn new() -> Handshake {
-		Handshake { nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))) }
+		Handshake::configured(ALL_SERVICES,
+		                      Duration::from_millis(SLOW_HANDSHAKE_MS),
+		                      ALL_FEATURES,
+		                      DuplicateNonce::Flag)
+	}
+
+	/// Creates a new handshake handler advertising the provided services and
+	/// features, flagging peers whose handshake took longer than the provided
+	/// threshold and handling peers reusing nonces per the provided policy.
+	pub fn configured(services: Services,
+	                  slow_threshold: Duration,
+	                  features: Features,
+	                  duplicate_nonce: DuplicateNonce)
+	                  -> Handshake {
+		Handshake {
+			nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))),
+			services: services,
+			slow_threshold: slow_threshold,
+			features: features,
+			seen_nonces: Arc::new(Mutex::new(VecDeque::new())),
+			duplicate_nonce: duplicate_nonce,
+			protocol: ProtocolConfig::default(),
+			genesis: genesis::genesis().hash(),
+			clock_tolerance: MAX_CLOCK_SKEW_SECS,
+			clock_skews: Arc::new(Mutex::new(VecDeque::with_capacity(CLOCK_SAMPLES))),
+		}
+	}
+
+	/// Same handshake handler, for the network whose messages start with the
+	/// provided magic bytes and whose chain starts with the provided genesis
+	/// block, the test network otherwise.
+	pub fn with_network(mut self, magic: [u8; 2], genesis: Hash) -> Handshake {
+		self.protocol.magic = magic;
+		self.genesis = genesis;
+		self
+	}
+
+	/// Same handshake handler, with the protocols of the peers applying the
+	/// provided policy to the blocks they push to us.
+	pub fn with_unsolicited_blocks(mut self, policy: UnsolicitedBlocks) -> Handshake {
+		self.protocol.unsolicited_blocks = policy;
+		self
+	}
+
+	/// Same handshake handler, with the protocols of the peers applying the
+	/// provided policy to peers sending too many addresses.
+	pub fn with_oversized_addrs(mut self, policy: OversizedAddrs) -> Handshake {
+		self.protocol.oversized_addrs = policy;
+		self
 	}
 
-	/// Handles connecting to a new remote peer, starting the version handshake.
-	pub fn connect(&self,
-	               capab: Capabilities,
-	               total_difficulty: Difficulty,
-	               self_addr: SocketAddr,
-	               conn: TcpStream)
-	               -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
+	/// Same handshake handler, with the protocols of the peers applying the
+	/// provided policy to the blocks whose parent we don't know.
+	pub fn with_orphan_blocks(mut self, policy: OrphanBlocks) -> Handshake {
+		self.protocol.orphan_blocks = policy;
+		self
+	}
+
+	/// Same handshake handler, with the protocols of the peers logging the
+	/// messages they can't decode in hex if asked to.
+	pub fn with_dumps(mut self, dumps: bool) -> Handshake {
+		self.protocol.dumps = dumps;
+		self
+	}
+
+	/// Same handshake handler, with the protocols of the peers banning them
+	/// once their misbehavior score reaches the provided one, zero for never.
+	pub fn with_ban_score(mut self, score: u32) -> Handshake {
+		self.protocol.ban_score = score;
+		self
+	}
+
+	/// Same handshake handler, refusing peers whose clock differs from ours by
+	/// more than the provided number of seconds, zero accepting any clock.
+	pub fn with_clock_tolerance(mut self, secs: u64) -> Handshake {
+		self.clock_tolerance = secs;
+		self
+	}
+
+	/// Whether many of our latest peers had their clock off ours the same
+	/// way, beyond the tolerance, in which case our own clock is likely off.
+	pub fn clock_suspect(&self) -> bool {
+		let skews = self.clock_skews.lock().unwrap_or_else(|e| e.into_inner());
+		clock_off(&skews, self.clock_tolerance)
+	}
+
+	/// Handles connecting to a new remote peer at the provided address,
+	/// starting the version handshake. The address is the one we dialed, the
+	/// other side of the stream can be a proxy. The services advertised are
+	/// those provided that are also configured.
+	pub fn connect<S>(&self,
+	                  capab: Capabilities,
+	                  total_difficulty: Difficulty,
+	                  services: Services,
+	                  self_addr: SocketAddr,
+	                  addr: SocketAddr,
+	                  log_id: PeerLogId,
+	                  conn: S)
+	                  -> Box<Future<Item = (S, ProtocolV1, PeerInfo), Error = Error>>
+		where S: HandshakeStream
+	{
 		// prepare the first part of the hanshake
+		let start = Instant::now();
+		let threshold = self.slow_threshold;
+		let features = self.features;
+		let protocol = self.protocol;
+		let magic = protocol.magic;
+		let genesis = self.genesis;
+		let tolerance = self.clock_tolerance;
+		let skews = self.clock_skews.clone();
 		let nonce = self.next_nonce();
 		let hand = Hand {
 			version: PROTOCOL_VERSION,
 			capabilities: capab,
+			services: services & self.services,
 			nonce: nonce,
 			total_difficulty: total_difficulty,
 			sender_addr: SockAddr(self_addr),
-			receiver_addr: SockAddr(conn.peer_addr().unwrap()),
+			receiver_addr: SockAddr(addr),
 			user_agent: USER_AGENT.to_string(),
+			timestamp: Some(now_secs()),
+			min_version: MIN_PROTOCOL_VERSION,
+			genesis: Some(genesis),
 		};
 
 		// write and read the handshake response
-		Box::new(write_msg(conn, hand, Type::Hand)
-			.and_then(|conn| read_msg::<Shake>(conn))
-			.and_then(|(conn, shake)| {
-				if shake.version != 1 {
-					Err(Error::Serialization(ser::Error::UnexpectedData {
-						expected: vec![PROTOCOL_VERSION as u8],
-						received: vec![shake.version as u8],
-					}))
-				} else {
+		Box::new(write_msg(conn, hand, Type::Hand, magic)
+			.and_then(move |conn| read_msg::<S, Shake>(conn, Type::Shake, magic))
+			.and_then(move |(conn, shake)| {
+				if let Some(negotiated) = negotiate_version(shake.min_version, shake.version) {
+					if let Err(e) = check_genesis(&log_id, shake.genesis, genesis) {
+						return Err(e);
+					}
+					let skew = match check_clock(&skews, &log_id, shake.timestamp, tolerance) {
+						Ok(skew) => skew,
+						Err(e) => return Err(e),
+					};
 					let peer_info = PeerInfo {
+						id: log_id.peer_id(),
 						capabilities: shake.capabilities,
+						services: shake.services,
 						user_agent: shake.user_agent,
-						addr: conn.peer_addr().unwrap(),
+						addr: addr,
+						remote_addr: conn.peer_addr().ok(),
 						version: shake.version,
+						negotiated_version: negotiated,
 						total_difficulty: shake.total_difficulty,
+						direction: Direction::Outbound,
+						slow_handshake: is_slow(&log_id, start, threshold),
+						log_id: log_id,
+						reachable: true,
+						features: NO_FEATURES,
+						duplicate_nonce: false,
+						observed_addr: observed_addr(shake.observed_addr, self_addr),
+						clock_skew: skew,
+						verified: Arc::new(AtomicBool::new(false)),
+						chain_status: Arc::new(Mutex::new(None)),
+						traffic: Arc::new(Traffic::new()),
 					};
-
-					info!("Connected to peer {:?}", peer_info);
-					// when more than one protocol version is supported, choosing should go here
-					Ok((conn, ProtocolV1::new(), peer_info))
+					Ok((conn, peer_info))
+				} else {
+					Err(Error::ProtocolVersion(shake.version))
 				}
+			})
+			.and_then(move |(conn, mut peer_info)| {
+				// we then tell our features first and get the other side's
+				negotiate_features(conn, magic, peer_info.negotiated_version, features, true)
+					.map(move |(conn, negotiated)| {
+						peer_info.features = negotiated;
+						debug!("{} Success handshake with {}.", peer_info.log_id, peer_info.addr);
+						let proto = ProtocolV1::new(&peer_info, capab, protocol);
+						(conn, proto, peer_info)
+					})
 			}))
 	}
 
 	/// Handles receiving a connection from a new remote peer that started the
 	/// version handshake.
-	pub fn handshake(&self,
-	                 capab: Capabilities,
-	                 total_difficulty: Difficulty,
-	                 conn: TcpStream)
-	                 -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
+	pub fn handshake<S>(&self,
+	                    capab: Capabilities,
+	                    total_difficulty: Difficulty,
+	                    services: Services,
+	                    log_id: PeerLogId,
+	                    conn: S)
+	                    -> Box<Future<Item = (S, ProtocolV1, PeerInfo), Error = Error>>
+		where S: HandshakeStream
+	{
 		let nonces = self.nonces.clone();
-		Box::new(read_msg::<Hand>(conn)
+		let seen_nonces = self.seen_nonces.clone();
+		let policy = self.duplicate_nonce;
+		let services = services & self.services;
+		let features = self.features;
+		let protocol = self.protocol;
+		let magic = protocol.magic;
+		let genesis = self.genesis;
+		let start = Instant::now();
+		let threshold = self.slow_threshold;
+		let tolerance = self.clock_tolerance;
+		let skews = self.clock_skews.clone();
+		Box::new(read_msg::<S, Hand>(conn, Type::Hand, magic)
 			.and_then(move |(conn, hand)| {
-				if hand.version != 1 {
-					return Err(Error::Serialization(ser::Error::UnexpectedData {
-						expected: vec![PROTOCOL_VERSION as u8],
-						received: vec![hand.version as u8],
-					}));
+				let negotiated = match negotiate_version(hand.min_version, hand.version) {
+					Some(v) => v,
+					None => return Err(Error::ProtocolVersion(hand.version)),
+				};
+				if let Err(e) = check_genesis(&log_id, hand.genesis, genesis) {
+					return Err(e);
 				}
 				{
 					// check the nonce to see if we could be trying to connect to ourselves
-					let nonces = nonces.read().unwrap();
+					let nonces = nonces.read().unwrap_or_else(|e| e.into_inner());
 					if nonces.contains(&hand.nonce) {
-						return Err(Error::Serialization(ser::Error::UnexpectedData {
-							expected: vec![],
-							received: vec![],
-						}));
+						return Err(Error::SelfConnection);
+					}
+				}
+				// all good, keep peer info, a peer not listening anywhere advertises
+				// a zero port and is only known by its connection
+				let reachable = hand.sender_addr.0.port() != 0;
+				let addr = if reachable {
+					hand.sender_addr.0
+				} else {
+					conn.peer_addr().unwrap_or(hand.sender_addr.0)
+				};
+				// another peer using the same nonce is most likely the same node
+				let duplicate = seen_elsewhere(&seen_nonces, hand.nonce, addr);
+				if duplicate {
+					warn!("{} Nonce already used by another peer than {}.", log_id, addr);
+					if policy == DuplicateNonce::Reject {
+						return Err(Error::DuplicateNonce);
 					}
 				}
-				// all good, keep peer info
+				let skew = match check_clock(&skews, &log_id, hand.timestamp, tolerance) {
+					Ok(skew) => skew,
+					Err(e) => return Err(e),
+				};
 				let peer_info = PeerInfo {
+					id: log_id.peer_id(),
 					capabilities: hand.capabilities,
+					services: hand.services,
 					user_agent: hand.user_agent,
-					addr: hand.sender_addr.0,
+					addr: addr,
+					remote_addr: conn.peer_addr().ok(),
 					version: hand.version,
+					negotiated_version: negotiated,
 					total_difficulty: hand.total_difficulty,
+					direction: Direction::Inbound,
+					slow_handshake: is_slow(&log_id, start, threshold),
+					log_id: log_id,
+					reachable: reachable,
+					features: NO_FEATURES,
+					duplicate_nonce: duplicate,
+					observed_addr: Some(hand.receiver_addr.0),
+					clock_skew: skew,
+					verified: Arc::new(AtomicBool::new(false)),
+					chain_status: Arc::new(Mutex::new(None)),
+					traffic: Arc::new(Traffic::new()),
 				};
 				// send our reply with our info
 				let shake = Shake {
 					version: PROTOCOL_VERSION,
 					capabilities: capab,
+					services: services,
 					total_difficulty: total_difficulty,
 					user_agent: USER_AGENT.to_string(),
+					timestamp: Some(now_secs()),
+					min_version: MIN_PROTOCOL_VERSION,
+					genesis: Some(genesis),
+					observed_addr: conn.peer_addr().ok(),
 				};
 				Ok((conn, shake, peer_info))
 			})
-			.and_then(|(conn, shake, peer_info)| {
-				debug!("Success handshake with {}.", peer_info.addr);
-				write_msg(conn, shake, Type::Shake)
-				  // when more than one protocol version is supported, choosing should go here
-					.map(|conn| (conn, ProtocolV1::new(), peer_info))
+			.and_then(move |(conn, shake, peer_info)| {
+				debug!("{} Success handshake with {}.", peer_info.log_id, peer_info.addr);
+				write_msg(conn, shake, Type::Shake, magic).map(|conn| (conn, peer_info))
+			})
+			.and_then(move |(conn, mut peer_info)| {
+				// the other side tells its features first, we reply with ours
+				negotiate_features(conn, magic, peer_info.negotiated_version, features, false)
+					.map(move |(conn, negotiated)| {
+						peer_info.features = negotiated;
+						(conn, peer_info)
+					})
+					.map(move |(conn, peer_info)| {
+						let proto = ProtocolV1::new(&peer_info, capab, protocol);
+						(conn, proto, peer_info)
+					})
 			}))
 	}
 
@@ -146,7 +399,7 @@ impl Handshake {
 		let mut rng = OsRng::new().unwrap();
 		let nonce = rng.next_u64();
 
-		let mut nonces = self.nonces.write().unwrap();
+		let mut nonces = self.nonces.write().unwrap_or_else(|e| e.into_inner());
 		nonces.push_back(nonce);
 		if nonces.len() >= NONCES_CAP {
 			nonces.pop_front();
@@ -154,3 +407,604 @@ impl Handshake {
 		nonce
 	}
 }
+
+// Exchanges our optional features with the peer's after the version
+// handshake, the side that connected telling its own first, then confirms
+// the upgrades among them. Peers speaking a version predating the exchange
+// would never answer it, nothing gets negotiated with them.
+fn negotiate_features<S>(conn: S,
+                         magic: [u8; 2],
+                         version: u32,
+                         features: Features,
+                         initiator: bool)
+                         -> Box<Future<Item = (S, Features), Error = Error>>
+	where S: HandshakeStream
+{
+	if version < Type::Features.since_version() {
+		return Box::new(future::ok((conn, NO_FEATURES)));
+	}
+	let ours = Negotiation { features: features };
+	if initiator {
+		Box::new(write_msg(conn, ours, Type::Features, magic)
+			.and_then(move |conn| read_msg::<S, Negotiation>(conn, Type::Features, magic))
+			.and_then(move |(conn, theirs)| {
+				confirm_upgrades(conn, magic, features & theirs.features, true)
+			}))
+	} else {
+		let read = read_msg::<S, Negotiation>(conn, Type::Features, magic);
+		Box::new(read.and_then(move |(conn, theirs)| {
+			let negotiated = features & theirs.features;
+			write_msg(conn, ours, Type::Features, magic)
+				.and_then(move |conn| confirm_upgrades(conn, magic, negotiated, false))
+		}))
+	}
+}
+
+// Confirms the negotiated upgrades with the peer: each side tells in an
+// upgrade message which ones it switches to, the side that connected going
+// first. Until then both still speak the old framing. A peer that doesn't
+// support an upgrade never advertised it, so no confirmation is expected.
+// Resolves with the features to use, the upgrades both sides confirmed
+// included.
+fn confirm_upgrades<S>(conn: S,
+                       magic: [u8; 2],
+                       negotiated: Features,
+                       initiator: bool)
+                       -> Box<Future<Item = (S, Features), Error = Error>>
+	where S: HandshakeStream
+{
+	let upgrades = negotiated & UPGRADES;
+	if upgrades.is_empty() {
+		return Box::new(future::ok((conn, negotiated)));
+	}
+	let ours = Negotiation { features: upgrades };
+	let exchange: Box<Future<Item = (S, Negotiation), Error = Error>> = if initiator {
+		Box::new(write_msg(conn, ours, Type::Upgrade, magic)
+			.and_then(move |conn| read_msg(conn, Type::Upgrade, magic)))
+	} else {
+		let read = read_msg::<S, Negotiation>(conn, Type::Upgrade, magic);
+		Box::new(read.and_then(move |(conn, theirs)| {
+			write_msg(conn, ours, Type::Upgrade, magic).map(move |conn| (conn, theirs))
+		}))
+	};
+	Box::new(exchange.map(move |(conn, theirs)| {
+		(conn, (negotiated - UPGRADES) | (upgrades & theirs.features))
+	}))
+}
+
+// Remembers a nonce received from the peer at the provided address, returning
+// whether it was recently received from another address as well.
+fn seen_elsewhere(seen: &Mutex<VecDeque<(u64, SocketAddr, Instant)>>,
+                  nonce: u64,
+                  addr: SocketAddr)
+                  -> bool {
+	let mut seen = seen.lock().unwrap_or_else(|e| e.into_inner());
+	let now = Instant::now();
+	let memory = Duration::from_secs(SEEN_NONCES_SECS);
+	while seen.front().map_or(false, |&(_, _, at)| now.duration_since(at) > memory) {
+		seen.pop_front();
+	}
+	let elsewhere = seen.iter().any(|&(n, a, _)| n == nonce && a != addr);
+	seen.push_back((nonce, addr, now));
+	if seen.len() > SEEN_NONCES_CAP {
+		seen.pop_front();
+	}
+	elsewhere
+}
+
+// Refuses a peer whose chain starts with another genesis block than ours,
+// whatever magic bytes it uses. Peers whose version doesn't tell are given
+// the benefit of the doubt.
+fn check_genesis(log_id: &PeerLogId, theirs: Option<Hash>, ours: Hash) -> Result<(), Error> {
+	match theirs {
+		Some(h) if h != ours => {
+			debug!("{} Genesis block {} isn't ours, refusing the peer.", log_id, h);
+			Err(Error::WrongNetwork)
+		}
+		_ => Ok(()),
+	}
+}
+
+// Remembers how many seconds the clock of a peer is ahead of ours, from the
+// time it sent, refusing the peer if further off than the tolerance. Many
+// peers off the same way rather point at our own clock, we then accept them
+// and warn about ours. Returns the skew, zero for peers whose version
+// doesn't tell their time.
+fn check_clock(skews: &Mutex<VecDeque<i64>>,
+               log_id: &PeerLogId,
+               timestamp: Option<i64>,
+               tolerance: u64)
+               -> Result<i64, Error> {
+	let skew = match timestamp {
+		Some(t) => t - now_secs(),
+		None => return Ok(0),
+	};
+	if tolerance == 0 {
+		return Ok(skew);
+	}
+	let mut skews = skews.lock().unwrap_or_else(|e| e.into_inner());
+	skews.push_back(skew);
+	if skews.len() > CLOCK_SAMPLES {
+		skews.pop_front();
+	}
+	if skew.abs() as u64 <= tolerance {
+		return Ok(skew);
+	}
+	if clock_off(&skews, tolerance) {
+		warn!("{} Clock off ours by {}s like many other peers, accepting it, check our clock.",
+		      log_id,
+		      skew);
+		return Ok(skew);
+	}
+	debug!("{} Clock off ours by {}s, refusing the peer.", log_id, skew);
+	Err(Error::ClockSkew(skew))
+}
+
+// Whether enough of the provided peer clock skews are beyond the tolerance
+// in the same direction to suspect our own clock.
+fn clock_off(skews: &VecDeque<i64>, tolerance: u64) -> bool {
+	let tolerance = tolerance as i64;
+	let ahead = skews.iter().filter(|&&s| s > tolerance).count();
+	let behind = skews.iter().filter(|&&s| s < -tolerance).count();
+	tolerance > 0 && cmp::max(ahead, behind) >= CLOCK_SUSPECT_PEERS
+}
+
+// Latest protocol version both we and a peer speaking the provided range of
+// versions support, if any. The range of a peer that doesn't send its oldest
+// version is just the version it advertised.
+fn negotiate_version(min: u32, max: u32) -> Option<u32> {
+	let version = cmp::min(max, PROTOCOL_VERSION);
+	if version < cmp::max(min, MIN_PROTOCOL_VERSION) {
+		None
+	} else {
+		Some(version)
+	}
+}
+
+// Current time in seconds since the epoch.
+fn now_secs() -> i64 {
+	time::now_utc().to_timespec().sec
+}
+
+// Where the peer we connected to sees us at, the IP our connection comes
+// from with the port we advertised, the one of the connection being
+// ephemeral. Unknown when we don't accept connections.
+fn observed_addr(seen: Option<SocketAddr>, self_addr: SocketAddr) -> Option<SocketAddr> {
+	match seen {
+		Some(seen) if self_addr.port() != 0 => Some(SocketAddr::new(seen.ip(), self_addr.port())),
+		_ => None,
+	}
+}
+
+// Whether a handshake started at the provided instant took longer than the
+// threshold, logging it if so.
+fn is_slow(log_id: &PeerLogId, start: Instant, threshold: Duration) -> bool {
+	let elapsed = start.elapsed();
+	if elapsed > threshold {
+		warn!("{} Slow handshake, took {}.{:03}s.",
+		      log_id,
+		      elapsed.as_secs(),
+		      elapsed.subsec_nanos() / 1_000_000);
+		true
+	} else {
+		false
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::cmp;
+	use std::io::{self, Cursor, Read, Write};
+	use std::net::SocketAddr;
+
+	use futures::Future;
+	use time;
+
+	use core::consensus::MAX_MSG_LEN;
+	use core::core::hash::ZERO_HASH;
+	use core::core::target::Difficulty;
+	use core::ser;
+	use msg::*;
+	use protocol::ProtocolV1;
+	use types::*;
+	use super::*;
+
+	// Magic bytes of the network the replayed handshakes run on.
+	fn magic() -> [u8; 2] {
+		ProtocolConfig::default().magic
+	}
+
+	// Replays crafted bytes to a handshake, keeping what it writes back.
+	struct Replay {
+		input: Cursor<Vec<u8>>,
+		output: Vec<u8>,
+		addr: SocketAddr,
+	}
+
+	impl Replay {
+		fn new(input: Vec<u8>) -> Replay {
+			Replay {
+				input: Cursor::new(input),
+				output: vec![],
+				addr: addr("10.0.0.1:13414"),
+			}
+		}
+	}
+
+	impl Read for Replay {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.input.read(buf)
+		}
+	}
+
+	impl Write for Replay {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.output.write(buf)
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl HandshakeStream for Replay {
+		fn peer_addr(&self) -> io::Result<SocketAddr> {
+			Ok(self.addr)
+		}
+	}
+
+	// How replaying a byte sequence ended.
+	#[derive(Debug, PartialEq)]
+	enum Outcome {
+		Accepted,
+		Eof,
+		WrongNetwork,
+		WrongType,
+		Malformed,
+		TooLarge,
+		OldVersion,
+		SelfConnection,
+		ClockSkew,
+		Other,
+	}
+
+	type Replayed = Result<(Replay, ProtocolV1, PeerInfo), Error>;
+
+	fn outcome(res: &Replayed) -> Outcome {
+		match *res {
+			Ok(_) => Outcome::Accepted,
+			Err(Error::Connection(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+				Outcome::Eof
+			}
+			Err(Error::WrongNetwork) => Outcome::WrongNetwork,
+			Err(Error::Serialization(ser::Error::UnexpectedData { .. })) => Outcome::WrongType,
+			Err(Error::Serialization(ser::Error::CorruptedData)) |
+			Err(Error::Serialization(ser::Error::IOErr(_))) => Outcome::Malformed,
+			Err(Error::Serialization(ser::Error::TooLargeReadErr)) => Outcome::TooLarge,
+			Err(Error::ProtocolVersion(_)) => Outcome::OldVersion,
+			Err(Error::SelfConnection) => Outcome::SelfConnection,
+			Err(Error::ClockSkew(_)) => Outcome::ClockSkew,
+			Err(_) => Outcome::Other,
+		}
+	}
+
+	fn addr(s: &str) -> SocketAddr {
+		s.parse().unwrap()
+	}
+
+	fn frame<W: ser::Writeable>(t: Type, body: &W) -> Vec<u8> {
+		let mut body_data = ser::ser_vec(body).unwrap();
+		let mut data = ser::ser_vec(&MsgHeader::new(magic(), t, body_data.len() as u64)).unwrap();
+		data.append(&mut body_data);
+		data
+	}
+
+	fn hand(version: u32) -> Hand {
+		Hand {
+			version: version,
+			capabilities: UNKNOWN,
+			services: ALL_SERVICES,
+			nonce: 42,
+			total_difficulty: Difficulty::one(),
+			sender_addr: SockAddr(addr("10.0.0.1:13414")),
+			receiver_addr: SockAddr(addr("10.0.0.2:13414")),
+			user_agent: "replay".to_string(),
+			timestamp: Some(time::now_utc().to_timespec().sec),
+			min_version: cmp::min(version, MIN_PROTOCOL_VERSION),
+			genesis: Some(genesis::genesis().hash()),
+		}
+	}
+
+	fn shake(version: u32) -> Shake {
+		Shake {
+			version: version,
+			capabilities: UNKNOWN,
+			services: ALL_SERVICES,
+			total_difficulty: Difficulty::one(),
+			user_agent: "replay".to_string(),
+			timestamp: Some(time::now_utc().to_timespec().sec),
+			min_version: cmp::min(version, MIN_PROTOCOL_VERSION),
+			genesis: Some(genesis::genesis().hash()),
+			observed_addr: Some(addr("1.2.3.4:52000")),
+		}
+	}
+
+	// Hand speaking the provided range of versions.
+	fn ranged_hand(min: u32, max: u32) -> Hand {
+		Hand { min_version: min, ..hand(max) }
+	}
+
+	// Frame of a hand from a peer predating version ranges, which ends with
+	// its user agent.
+	fn legacy_frame(hand: &Hand) -> Vec<u8> {
+		assert!(hand.version < VERSION_RANGES_VERSION);
+		let framed = frame(Type::Hand, hand);
+		assert!(framed.ends_with(hand.user_agent.as_bytes()));
+		framed
+	}
+
+	fn skewed_hand(skew: i64) -> Hand {
+		let hand = hand(PROTOCOL_VERSION);
+		Hand { timestamp: hand.timestamp.map(|t| t + skew), ..hand }
+	}
+
+	fn features(f: Features) -> Negotiation {
+		Negotiation { features: f }
+	}
+
+	fn concat(frames: Vec<Vec<u8>>) -> Vec<u8> {
+		frames.into_iter().flat_map(|f| f.into_iter()).collect()
+	}
+
+	fn accept(hs: &Handshake, input: Vec<u8>) -> Replayed {
+		let log_id = PeerLogId::new(addr("10.0.0.1:13414"));
+		hs.handshake(UNKNOWN, Difficulty::one(), ALL_SERVICES, log_id, Replay::new(input)).wait()
+	}
+
+	fn connect(hs: &Handshake, input: Vec<u8>) -> Replayed {
+		let log_id = PeerLogId::new(addr("10.0.0.1:13414"));
+		let self_addr = addr("10.0.0.2:13414");
+		hs.connect(UNKNOWN,
+		           Difficulty::one(),
+		           ALL_SERVICES,
+		           self_addr,
+		           addr("10.0.0.1:13414"),
+		           log_id,
+		           Replay::new(input))
+			.wait()
+	}
+
+	#[test]
+	fn accept_replayed() {
+		let hand_frame = frame(Type::Hand, &hand(PROTOCOL_VERSION));
+		let len = hand_frame.len();
+		let mut wrong_magic = hand_frame.clone();
+		wrong_magic[0] ^= 0xff;
+		let mut unknown_type = hand_frame.clone();
+		unknown_type[2] = 0xff;
+		let mut short_len = ser::ser_vec(&MsgHeader::new(magic(), Type::Hand, 8)).unwrap();
+		short_len.extend_from_slice(&hand_frame[15..]);
+		let header = MsgHeader::new(magic(), Type::Hand, MAX_MSG_LEN + 1);
+		let oversized = ser::ser_vec(&header).unwrap();
+		let other_chain = Hand { genesis: Some(ZERO_HASH), ..hand(PROTOCOL_VERSION) };
+
+		let cases = vec![
+			("complete",
+			 concat(vec![hand_frame.clone(), frame(Type::Features, &features(NO_FEATURES))]),
+			 Outcome::Accepted),
+			("upgrade confirmed",
+			 concat(vec![hand_frame.clone(),
+			             frame(Type::Features, &features(UPGRADES)),
+			             frame(Type::Upgrade, &features(UPGRADES))]),
+			 Outcome::Accepted),
+			("empty", vec![], Outcome::Eof),
+			("truncated header", hand_frame[..5].to_vec(), Outcome::Eof),
+			("truncated body", hand_frame[..len - 3].to_vec(), Outcome::Eof),
+			("no negotiation", hand_frame.clone(), Outcome::Eof),
+			("upgrade unconfirmed",
+			 concat(vec![hand_frame.clone(), frame(Type::Features, &features(UPGRADES))]),
+			 Outcome::Eof),
+			("wrong magic", wrong_magic, Outcome::WrongNetwork),
+			("other genesis", frame(Type::Hand, &other_chain), Outcome::WrongNetwork),
+			("unknown type", unknown_type, Outcome::Malformed),
+			("length too short", short_len, Outcome::Malformed),
+			("oversized", oversized, Outcome::TooLarge),
+			("negotiation first",
+			 concat(vec![frame(Type::Features, &features(NO_FEATURES)), hand_frame.clone()]),
+			 Outcome::WrongType),
+			("shake instead of hand",
+			 frame(Type::Shake, &shake(PROTOCOL_VERSION)),
+			 Outcome::WrongType),
+			("negotiation replaced",
+			 concat(vec![hand_frame.clone(), hand_frame.clone()]),
+			 Outcome::WrongType),
+			("old version", frame(Type::Hand, &hand(0)), Outcome::OldVersion),
+			("versions above ours",
+			 frame(Type::Hand, &ranged_hand(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2)),
+			 Outcome::OldVersion),
+			("clock ahead", frame(Type::Hand, &skewed_hand(3600)), Outcome::ClockSkew),
+		];
+		for (name, input, expected) in cases {
+			let res = accept(&Handshake::new(), input);
+			assert_eq!((name, outcome(&res)), (name, expected));
+		}
+	}
+
+	#[test]
+	fn accepted_peer_info() {
+		let input = concat(vec![frame(Type::Hand, &hand(PROTOCOL_VERSION)),
+		                        frame(Type::Features, &features(UPGRADES)),
+		                        frame(Type::Upgrade, &features(UPGRADES))]);
+		let (replay, _, info) = accept(&Handshake::new(), input).unwrap();
+		assert_eq!(info.addr, addr("10.0.0.1:13414"));
+		assert_eq!(info.observed_addr, Some(addr("10.0.0.2:13414")));
+		assert_eq!(info.direction, Direction::Inbound);
+		assert_eq!(info.user_agent, "replay");
+		assert_eq!(info.features, UPGRADES);
+		assert!(info.reachable);
+
+		// we replied with our shake, then our features and upgrades
+		let mut out = &replay.output[..];
+		for t in vec![Type::Shake, Type::Features, Type::Upgrade] {
+			let header = ser::deserialize::<MsgHeader>(&mut out).unwrap();
+			assert_eq!(header.msg_type, t);
+			out = &out[header.msg_len as usize..];
+		}
+		assert!(out.is_empty());
+	}
+
+	#[test]
+	fn observed_addr_exchanged() {
+		let no_features = frame(Type::Features, &features(NO_FEATURES));
+
+		// we tell the peer connecting to us where its connection comes from
+		let input = concat(vec![frame(Type::Hand, &hand(PROTOCOL_VERSION)), no_features.clone()]);
+		let (replay, _, _) = accept(&Handshake::new(), input).unwrap();
+		let reply = ser::deserialize::<Shake>(&mut &replay.output[HEADER_LEN as usize..]).unwrap();
+		assert_eq!(reply.observed_addr, Some(addr("10.0.0.1:13414")));
+
+		// and learn our public IP from the one we connect to, with our port
+		let input = concat(vec![frame(Type::Shake, &shake(PROTOCOL_VERSION)), no_features.clone()]);
+		let (_, _, info) = connect(&Handshake::new(), input).unwrap();
+		assert_eq!(info.observed_addr, Some(addr("1.2.3.4:13414")));
+
+		// or not, if it doesn't know
+		let unknown = Shake { observed_addr: None, ..shake(PROTOCOL_VERSION) };
+		let input = concat(vec![frame(Type::Shake, &unknown), no_features.clone()]);
+		let (_, _, info) = connect(&Handshake::new(), input).unwrap();
+		assert_eq!(info.observed_addr, None);
+
+		// version 2 peers end their shake with it or not, only the end of the
+		// shake means they didn't tell
+		let v2 = Shake { genesis: None, ..shake(2) };
+		let input = concat(vec![frame(Type::Shake, &v2), no_features.clone()]);
+		let (_, _, info) = connect(&Handshake::new(), input).unwrap();
+		assert_eq!(info.observed_addr, Some(addr("1.2.3.4:13414")));
+		let legacy = Shake { observed_addr: None, ..v2 };
+		let input = concat(vec![frame(Type::Shake, &legacy), no_features.clone()]);
+		let (_, _, info) = connect(&Handshake::new(), input).unwrap();
+		assert_eq!(info.observed_addr, None);
+		let mut truncated = ser::ser_vec(&Shake { genesis: None, ..shake(2) }).unwrap();
+		let len = truncated.len();
+		truncated.truncate(len - 2);
+		let header = MsgHeader::new(magic(), Type::Shake, len as u64 - 2);
+		let mut input = ser::ser_vec(&header).unwrap();
+		input.append(&mut truncated);
+		input.extend_from_slice(&no_features);
+		assert_eq!(outcome(&connect(&Handshake::new(), input)), Outcome::Malformed);
+	}
+
+	#[test]
+	fn connect_replayed() {
+		let shake_frame = frame(Type::Shake, &shake(PROTOCOL_VERSION));
+		let cases = vec![
+			("complete",
+			 concat(vec![shake_frame.clone(), frame(Type::Features, &features(NO_FEATURES))]),
+			 Outcome::Accepted),
+			("no reply", vec![], Outcome::Eof),
+			("no negotiation", shake_frame.clone(), Outcome::Eof),
+			("hand instead of shake",
+			 frame(Type::Hand, &hand(PROTOCOL_VERSION)),
+			 Outcome::WrongType),
+			("old version", frame(Type::Shake, &shake(0)), Outcome::OldVersion),
+			("other genesis",
+			 frame(Type::Shake, &Shake { genesis: Some(ZERO_HASH), ..shake(PROTOCOL_VERSION) }),
+			 Outcome::WrongNetwork),
+		];
+		for (name, input, expected) in cases {
+			let res = connect(&Handshake::new(), input);
+			if let Ok((_, _, ref info)) = res {
+				assert_eq!(info.direction, Direction::Outbound);
+				assert_eq!(info.addr, addr("10.0.0.1:13414"));
+			}
+			assert_eq!((name, outcome(&res)), (name, expected));
+		}
+	}
+
+	#[test]
+	fn version_negotiated() {
+		let no_features = frame(Type::Features, &features(NO_FEATURES));
+
+		// a peer speaking newer versions too settles on our latest
+		let input = concat(vec![frame(Type::Hand, &ranged_hand(1, PROTOCOL_VERSION + 3)),
+		                        no_features.clone()]);
+		let (replay, _, info) = accept(&Handshake::new(), input).unwrap();
+		assert_eq!((info.version, info.negotiated_version),
+		           (PROTOCOL_VERSION + 3, PROTOCOL_VERSION));
+		let shake = ser::deserialize::<Shake>(&mut &replay.output[HEADER_LEN as usize..]).unwrap();
+		assert_eq!((shake.min_version, shake.version),
+		           (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+
+		// one that doesn't tell its oldest only speaks the version it advertised
+		let input = concat(vec![legacy_frame(&hand(MIN_PROTOCOL_VERSION)), no_features.clone()]);
+		let (replay, _, info) = accept(&Handshake::new(), input).unwrap();
+		assert_eq!(info.negotiated_version, MIN_PROTOCOL_VERSION);
+		// nor negotiates features, it wouldn't answer, our shake is all we send
+		let header = ser::deserialize::<MsgHeader>(&mut &replay.output[..]).unwrap();
+		assert_eq!(replay.output.len() as u64, HEADER_LEN + header.msg_len);
+		assert_eq!(info.features, NO_FEATURES);
+
+		// whichever side connected
+		let input = concat(vec![frame(Type::Shake, &shake(MIN_PROTOCOL_VERSION)), no_features]);
+		let (_, _, info) = connect(&Handshake::new(), input).unwrap();
+		assert_eq!(info.negotiated_version, MIN_PROTOCOL_VERSION);
+	}
+
+	#[test]
+	fn own_hand_replayed() {
+		// the hand we sent coming back to the same handshake handler
+		let hs = Handshake::new();
+		let input = concat(vec![frame(Type::Shake, &shake(PROTOCOL_VERSION)),
+		                        frame(Type::Features, &features(NO_FEATURES))]);
+		let (replay, _, _) = connect(&hs, input).unwrap();
+		let res = accept(&hs, replay.output);
+		assert_eq!(outcome(&res), Outcome::SelfConnection);
+	}
+
+	#[test]
+	fn clock_skew_tolerated() {
+		let hs = Handshake::new().with_clock_tolerance(600);
+		let no_features = frame(Type::Features, &features(NO_FEATURES));
+
+		// a clock a minute ahead is fine and recorded
+		let input = concat(vec![frame(Type::Hand, &skewed_hand(60)), no_features.clone()]);
+		let (_, _, info) = accept(&hs, input).unwrap();
+		assert!(info.clock_skew >= 59 && info.clock_skew <= 61);
+
+		// an hour behind isn't, whichever side connected
+		let input = concat(vec![frame(Type::Hand, &skewed_hand(-3600)), no_features.clone()]);
+		assert_eq!(outcome(&accept(&hs, input)), Outcome::ClockSkew);
+		let mut late = shake(PROTOCOL_VERSION);
+		late.timestamp = late.timestamp.map(|t| t - 3600);
+		let input = concat(vec![frame(Type::Shake, &late), no_features.clone()]);
+		assert_eq!(outcome(&connect(&hs, input)), Outcome::ClockSkew);
+
+		// unless we accept any clock
+		let lenient = Handshake::new().with_clock_tolerance(0);
+		let input = concat(vec![frame(Type::Hand, &skewed_hand(-3600)), no_features]);
+		assert_eq!(outcome(&accept(&lenient, input)), Outcome::Accepted);
+	}
+
+	#[test]
+	fn own_clock_suspected() {
+		let hs = Handshake::new().with_clock_tolerance(600);
+		let skewed = |skew| frame(Type::Hand, &skewed_hand(skew));
+
+		// peers off both ways don't point at our clock
+		for _ in 0..CLOCK_SUSPECT_PEERS - 1 {
+			let _ = accept(&hs, skewed(3600));
+			let _ = accept(&hs, skewed(-3600));
+		}
+		assert!(!hs.clock_suspect());
+
+		// one more all ahead does, skewed peers getting accepted from then on
+		let _ = accept(&hs, skewed(3600));
+		assert!(hs.clock_suspect());
+		let input = concat(vec![skewed(3600), frame(Type::Features, &features(NO_FEATURES))]);
+		assert_eq!(outcome(&accept(&hs, input)), Outcome::Accepted);
+
+		// peers predating clocks in the handshake are taken as in sync
+		let strict = Handshake::new().with_clock_tolerance(1);
+		let (_, _, info) = accept(&strict, legacy_frame(&hand(MIN_PROTOCOL_VERSION))).unwrap();
+		assert_eq!(info.clock_skew, 0);
+	}
+}
@@ -30,25 +30,64 @@ extern crate grin_store;
 extern crate grin_util as util;
 #[macro_use]
 extern crate log;
+extern crate byteorder;
 extern crate futures;
+extern crate native_tls;
+extern crate net2;
+extern crate openssl;
 #[macro_use]
 extern crate tokio_core;
 extern crate tokio_timer;
+extern crate tokio_tls;
 extern crate rand;
+#[cfg(test)]
+extern crate secp256k1zkp as secp;
+#[macro_use]
+extern crate lazy_static;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 extern crate num;
 
+mod bans;
 mod conn;
+mod control;
 pub mod handshake;
 mod msg;
+mod nat;
 mod peer;
+mod pool;
 mod protocol;
+mod proxy;
+mod schedule;
 mod server;
 mod store;
+mod stream;
+#[cfg(test)]
+mod test_network;
+mod throttle;
 mod types;
 
-pub use server::{Server, DummyAdapter};
+pub use bans::BanEntry;
+pub use server::{Server, DummyAdapter, PeerLookup, SyncStatus, PeerSnapshot, SnapshotPeer,
+                 PeerDiff, PeerStats};
+pub use schedule::DialAction;
+pub use control::start_control;
+pub use proxy::{onion_addr, onion_host, parse_peer_addr, resolve as resolve_through_proxy};
 pub use peer::Peer;
-pub use types::{P2PConfig, NetAdapter, MAX_LOCATORS, MAX_BLOCK_HEADERS, MAX_PEER_ADDRS,
-                Capabilities, UNKNOWN, FULL_NODE, FULL_HIST, PeerInfo, Error};
-pub use store::{PeerStore, PeerData, State};
+pub use stream::PeerStream;
+pub use msg::{ChainStatus, PeerInfoResp, Checkpoint, UtxoChunk, is_kernel_of};
+pub use types::{P2PConfig, P2PConfigRuntime, TlsConfig, DandelionConfig, NetAdapter,
+                MAX_LOCATORS, MAX_BLOCK_HEADERS, MAX_PEER_ADDRS, MAX_UTXO_CHUNK, Capabilities,
+                UNKNOWN, FULL_NODE, FULL_HIST, UTXO_SNAPSHOT, Services, NO_SERVICES,
+                SERVES_HEADERS, SERVES_BLOCKS, SERVES_MEMPOOL, ARCHIVAL, ALL_SERVICES,
+                PRUNED_BLOCKS_HORIZON, Features, NO_FEATURES, BLOCK_BATCHES, TX_INV, CHECKSUMS,
+                COMPACT_BLOCKS, DANDELION, ALL_FEATURES, UPGRADES, PeerInfo, PeerId, Direction,
+                Severity, DuplicateNonce, DialPreference, UnsolicitedBlocks, OversizedAddrs,
+                OrphanBlocks, ProtocolConfig, MAX_CHECKPOINTS, Error, HandshakeFailure, SendOutcome,
+                BroadcastStats,
+                Violation, ViolationRecord, BlockStatus, InboundLimits, ExcessInbound,
+                ProxyConfig};
+pub use store::{PeerStore, PeerData, BanData, State, private_ip, valid_peer_addr};
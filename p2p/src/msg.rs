@@ -14,36 +14,64 @@
 
 //! Message types that transit over the network and related serialization code.
 
+use std::cmp;
+use std::io::{self, Read, Write};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
 use num::FromPrimitive;
 
 use futures::future::{Future, ok};
-use tokio_core::net::TcpStream;
 use tokio_core::io::{write_all, read_exact};
 
 use core::consensus::MAX_MSG_LEN;
-use core::core::BlockHeader;
-use core::core::hash::Hash;
+use core::core::{Block, BlockHeader, Output, Transaction, TxKernel, COINBASE_KERNEL,
+                 COINBASE_OUTPUT};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
 use core::core::target::Difficulty;
 use core::ser::{self, Writeable, Readable, Writer, Reader};
 
 use types::*;
 
-/// Current latest version of the protocol
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Current latest version of the protocol. Version 2 has the handshake
+/// carry the range of versions the sender speaks, pings and pongs the status
+/// of the sender's chain, and adds the messages since_version tells about.
+/// Version 3 has the handshake carry the genesis block of the sender's chain.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Protocol version from which the hand and shake end with the current time
+/// of the sender and the oldest version it speaks.
+pub const VERSION_RANGES_VERSION: u32 = 2;
+
+/// Protocol version from which pings and pongs carry the status of the
+/// sender's chain.
+pub const PING_STATUS_VERSION: u32 = 2;
+
+/// Protocol version from which the hand and shake carry the hash of the
+/// genesis block of the sender's chain, followed in the shake by whether the
+/// address the sender sees the connection coming from is told.
+pub const GENESIS_VERSION: u32 = 3;
+
+/// Oldest version of the protocol we still speak.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
 
 /// Grin's user agent with current version (TODO externalize)
 pub const USER_AGENT: &'static str = "MW/Grin 0.1";
 
-/// Magic number expected in the header of every message
-const MAGIC: [u8; 2] = [0x1e, 0xc5];
-
 /// Size in bytes of a message header
-pub const HEADER_LEN: u64 = 11;
+pub const HEADER_LEN: u64 = 15;
+
+/// Size in bytes of the checksum following each message body, when checksums
+/// are negotiated.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Maximum number of bytes of a message we can't decode dumped in the logs.
+pub const MAX_DUMP_LEN: usize = 256;
 
 /// Codes for each error that can be produced reading a message.
 pub enum ErrCodes {
 	UnsupportedVersion = 100,
+	/// The request isn't part of what we speak with the requester, answered
+	/// so it doesn't wait for a response until it times out.
+	UnsupportedMessage = 101,
 }
 
 /// Types of messages
@@ -62,19 +90,155 @@ enum_from_primitive! {
     GetBlock,
     Block,
     Transaction,
+    Inv,
+    GetData,
+    Blocks,
+    Features,
+    GetPeerInfo,
+    PeerInfoResp,
+    GetCheckpoints,
+    Checkpoints,
+    Upgrade,
+    CompactBlock,
+    GetUtxoChunk,
+    UtxoChunk,
+    StemTransaction,
+  }
+}
+
+/// How far ahead of previously queued messages a message can jump per level
+/// of priority. Bounds how long a low priority message can be starved.
+pub const PRIORITY_AGING: u64 = 16;
+
+impl Type {
+	/// Priority of the message type when queued for sending, higher gets sent
+	/// first. Connection control comes first, then chain data, transactions
+	/// and finally address gossip and snapshots for syncing peers.
+	pub fn priority(&self) -> u64 {
+		match *self {
+			Type::Error | Type::Hand | Type::Shake | Type::Features | Type::Upgrade |
+			Type::Ping | Type::Pong => 3,
+			Type::GetHeaders | Type::Headers | Type::GetBlock | Type::Block | Type::Inv |
+			Type::GetData | Type::Blocks | Type::GetCheckpoints | Type::Checkpoints |
+			Type::CompactBlock => 2,
+			Type::Transaction | Type::StemTransaction => 1,
+			Type::GetPeerAddrs | Type::PeerAddrs | Type::GetPeerInfo | Type::PeerInfoResp |
+			Type::GetUtxoChunk | Type::UtxoChunk => 0,
+		}
+	}
+
+	/// Protocol version the message type was introduced in. Messages of a
+	/// type newer than the version negotiated with a peer are neither sent to
+	/// it nor handled when it sends them anyway, requests getting an error
+	/// back.
+	pub fn since_version(&self) -> u32 {
+		match *self {
+			Type::Error | Type::Hand | Type::Shake | Type::Ping | Type::Pong |
+			Type::GetPeerAddrs | Type::PeerAddrs | Type::GetHeaders | Type::Headers |
+			Type::GetBlock | Type::Block | Type::Transaction => 1,
+			Type::Inv | Type::GetData | Type::Blocks | Type::Features | Type::GetPeerInfo |
+			Type::PeerInfoResp | Type::GetCheckpoints | Type::Checkpoints | Type::Upgrade |
+			Type::CompactBlock | Type::GetUtxoChunk | Type::UtxoChunk |
+			Type::StemTransaction => 2,
+		}
+	}
+
+	/// Whether the message type asks for a response.
+	pub fn is_request(&self) -> bool {
+		match *self {
+			Type::Ping | Type::GetPeerAddrs | Type::GetHeaders | Type::GetBlock |
+			Type::GetData | Type::GetPeerInfo | Type::GetCheckpoints | Type::GetUtxoChunk => true,
+			_ => false,
+		}
+	}
+
+	/// Capabilities the side handling a message of the type has to have
+	/// advertised in its handshake, like providing peers to be asked for
+	/// them. Other messages are sent to and handled by anyone.
+	pub fn required_capabilities(&self) -> Capabilities {
+		match *self {
+			Type::GetPeerAddrs => PEER_LIST,
+			Type::GetUtxoChunk => UTXO_SNAPSHOT,
+			_ => UNKNOWN,
+		}
+	}
+}
+
+/// Types of objects an inventory can refer to
+enum_from_primitive! {
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum InvType {
+    Block,
+    Transaction,
   }
 }
 
+lazy_static! {
+	// CRC32 of every byte, computed once for all the messages we checksum
+	static ref CRC_TABLE: [u32; 256] = {
+		let mut table = [0u32; 256];
+		for (n, entry) in table.iter_mut().enumerate() {
+			let mut c = n as u32;
+			for _ in 0..8 {
+				c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+			}
+			*entry = c;
+		}
+		table
+	};
+}
+
+/// CRC32 of a message body, big-endian. Only catches corruption in transit,
+/// a peer sending bad messages on purpose can checksum them just as well.
+pub fn checksum(body: &[u8]) -> [u8; 4] {
+	let crc = !body.iter().fold(!0u32, |crc, b| {
+		CRC_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+	});
+	[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]
+}
+
+/// Hex dump of the provided message data for the logs, cut after
+/// MAX_DUMP_LEN bytes.
+pub fn hex_dump(data: &[u8]) -> String {
+	hex_dump_of(data, data.len())
+}
+
+/// Same as hex_dump, given only the start of data of the provided length.
+pub fn hex_dump_of(start: &[u8], len: usize) -> String {
+	let mut dump =
+		start.iter().take(MAX_DUMP_LEN).map(|b| format!("{:02x}", b)).collect::<String>();
+	if len > MAX_DUMP_LEN {
+		dump.push_str(&format!("... ({} bytes)", len));
+	}
+	dump
+}
+
 /// Future combinator to read any message where the body is a Readable. Reads
 /// the  header first, handles its validation and then reads the Readable body,
-/// allocating buffers of the right size.
-pub fn read_msg<T>(conn: TcpStream) -> Box<Future<Item = (TcpStream, T), Error = Error>>
-	where T: Readable + 'static
+/// allocating buffers of the right size. A message of another type than the
+/// expected one, or starting with other magic bytes than the provided ones,
+/// is rejected before its body is read.
+pub fn read_msg<S, T>(conn: S,
+                      msg_type: Type,
+                      magic: [u8; 2])
+                      -> Box<Future<Item = (S, T), Error = Error>>
+	where S: Read + 'static,
+	      T: Readable + 'static
 {
 	let read_header = read_exact(conn, vec![0u8; HEADER_LEN as usize])
 		.from_err()
-		.and_then(|(reader, buf)| {
+		.and_then(move |(reader, buf)| {
+			// magic of another network
+			if buf[0] != magic[0] || buf[1] != magic[1] {
+				return Err(Error::WrongNetwork);
+			}
 			let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
+			if header.msg_type != msg_type {
+				return Err(Error::Serialization(ser::Error::UnexpectedData {
+					expected: vec![msg_type as u8],
+					received: vec![header.msg_type as u8],
+				}));
+			}
 			if header.msg_len > MAX_MSG_LEN {
 				// TODO add additional restrictions on a per-message-type basis to avoid 20MB
 				// pings
@@ -94,13 +258,15 @@ pub fn read_msg<T>(conn: TcpStream) -> Box<Future<Item = (TcpStream, T), Error =
 }
 
 /// Future combinator to write a full message from a Writeable payload.
-/// Serializes the payload first and then sends the message header and that
-/// payload.
-pub fn write_msg<T>(conn: TcpStream,
-                    msg: T,
-                    msg_type: Type)
-                    -> Box<Future<Item = TcpStream, Error = Error>>
-	where T: Writeable + 'static
+/// Serializes the payload first and then sends the message header, starting
+/// with the provided magic bytes, and that payload.
+pub fn write_msg<S, T>(conn: S,
+                       msg: T,
+                       msg_type: Type,
+                       magic: [u8; 2])
+                       -> Box<Future<Item = S, Error = Error>>
+	where S: Write + 'static,
+	      T: Writeable + 'static
 {
 	let write_msg = ok((conn)).and_then(move |conn| {
 		// prepare the body first so we know its serialized length
@@ -110,7 +276,7 @@ pub fn write_msg<T>(conn: TcpStream,
 		// build and serialize the header using the body size
 		let mut header_buf = vec![];
 		let blen = body_buf.len() as u64;
-		ser::serialize(&mut header_buf, &MsgHeader::new(msg_type, blen));
+		ser::serialize(&mut header_buf, &MsgHeader::new(magic, msg_type, blen));
 
 		// send the whole thing
 		write_all(conn, header_buf)
@@ -123,23 +289,34 @@ pub fn write_msg<T>(conn: TcpStream,
 
 /// Header of any protocol message, used to identify incoming messages.
 pub struct MsgHeader {
-	magic: [u8; 2],
+	/// Magic bytes of the network the message is for.
+	pub magic: [u8; 2],
 	/// Type of the message.
 	pub msg_type: Type,
 	/// Tota length of the message in bytes.
 	pub msg_len: u64,
+	/// Id of the request, echoed by its response. Zero for messages that are
+	/// neither.
+	pub id: u32,
 }
 
 impl MsgHeader {
-	/// Creates a new message header.
-	pub fn new(msg_type: Type, len: u64) -> MsgHeader {
+	/// Creates a new message header for the network with the provided magic
+	/// bytes.
+	pub fn new(magic: [u8; 2], msg_type: Type, len: u64) -> MsgHeader {
 		MsgHeader {
-			magic: MAGIC,
+			magic: magic,
 			msg_type: msg_type,
 			msg_len: len,
+			id: 0,
 		}
 	}
 
+	/// Creates a new message header for a request with the provided id.
+	pub fn with_id(magic: [u8; 2], msg_type: Type, len: u64, id: u32) -> MsgHeader {
+		MsgHeader { id: id, ..MsgHeader::new(magic, msg_type, len) }
+	}
+
 	/// Serialized length of the header in bytes
 	pub fn serialized_len(&self) -> u64 {
 		HEADER_LEN
@@ -152,22 +329,25 @@ impl Writeable for MsgHeader {
 		                [write_u8, self.magic[0]],
 		                [write_u8, self.magic[1]],
 		                [write_u8, self.msg_type as u8],
-		                [write_u64, self.msg_len]);
+		                [write_u64, self.msg_len],
+		                [write_u32, self.id]);
 		Ok(())
 	}
 }
 
+// Any magic bytes are read, whoever reads the header knowing which network
+// it expects.
 impl Readable for MsgHeader {
 	fn read(reader: &mut Reader) -> Result<MsgHeader, ser::Error> {
-		try!(reader.expect_u8(MAGIC[0]));
-		try!(reader.expect_u8(MAGIC[1]));
-		let (t, len) = ser_multiread!(reader, read_u8, read_u64);
+		let (m0, m1, t, len, id) =
+			ser_multiread!(reader, read_u8, read_u8, read_u8, read_u64, read_u32);
 		match Type::from_u8(t) {
 			Some(ty) => {
 				Ok(MsgHeader {
-					magic: MAGIC,
+					magic: [m0, m1],
 					msg_type: ty,
 					msg_len: len,
+					id: id,
 				})
 			}
 			None => Err(ser::Error::CorruptedData),
@@ -182,6 +362,8 @@ pub struct Hand {
 	pub version: u32,
 	/// capabilities of the sender
 	pub capabilities: Capabilities,
+	/// services offered by the sender
+	pub services: Services,
 	/// randomly generated for each handshake, helps detect self
 	pub nonce: u64,
 	/// total difficulty accumulated by the sender, used to check whether sync
@@ -194,6 +376,13 @@ pub struct Hand {
 	pub receiver_addr: SockAddr,
 	/// name of version of the software
 	pub user_agent: String,
+	/// current time of the sender, in seconds since the epoch, if its version
+	/// tells
+	pub timestamp: Option<i64>,
+	/// oldest protocol version the sender speaks, version being the latest
+	pub min_version: u32,
+	/// hash of the genesis block of the sender's chain, if its version tells
+	pub genesis: Option<Hash>,
 }
 
 impl Writeable for Hand {
@@ -201,35 +390,94 @@ impl Writeable for Hand {
 		ser_multiwrite!(writer,
 		                [write_u32, self.version],
 		                [write_u32, self.capabilities.bits()],
+		                [write_u32, self.services.bits()],
 		                [write_u64, self.nonce]);
 		self.total_difficulty.write(writer);
 		self.sender_addr.write(writer);
 		self.receiver_addr.write(writer);
-		writer.write_bytes(&self.user_agent)
+		writer.write_bytes(&self.user_agent);
+		write_clock_and_range(writer, self.version, self.timestamp, self.min_version)?;
+		write_genesis(writer, self.version, self.genesis)
 	}
 }
 
 impl Readable for Hand {
 	fn read(reader: &mut Reader) -> Result<Hand, ser::Error> {
-		let (version, capab, nonce) = ser_multiread!(reader, read_u32, read_u32, read_u64);
+		let (version, capab, services, nonce) =
+			ser_multiread!(reader, read_u32, read_u32, read_u32, read_u64);
 		let total_diff = try!(Difficulty::read(reader));
 		let sender_addr = try!(SockAddr::read(reader));
 		let receiver_addr = try!(SockAddr::read(reader));
 		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
 		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		let (timestamp, min_version) = try!(read_clock_and_range(reader, version));
+		let genesis = try!(read_genesis(reader, version));
 		Ok(Hand {
 			version: version,
 			capabilities: capabilities,
+			services: Services::from_bits_truncate(services),
 			nonce: nonce,
 			total_difficulty: total_diff,
 			sender_addr: sender_addr,
 			receiver_addr: receiver_addr,
 			user_agent: user_agent,
+			timestamp: timestamp,
+			min_version: min_version,
+			genesis: genesis,
 		})
 	}
 }
 
+// Writes the current time of the sender of a hand or shake and the oldest
+// protocol version it speaks, ending it, if its version has them.
+fn write_clock_and_range<W: Writer>(writer: &mut W,
+                                    version: u32,
+                                    timestamp: Option<i64>,
+                                    min_version: u32)
+                                    -> Result<(), ser::Error> {
+	if version < VERSION_RANGES_VERSION {
+		return Ok(());
+	}
+	writer.write_i64(timestamp.unwrap_or(0))?;
+	writer.write_u32(min_version)
+}
+
+// Reads the current time of the sender of a hand or shake and the oldest
+// protocol version it speaks. Peers predating version ranges tell neither,
+// only speaking the version they advertised.
+fn read_clock_and_range(reader: &mut Reader,
+                        version: u32)
+                        -> Result<(Option<i64>, u32), ser::Error> {
+	if version < VERSION_RANGES_VERSION {
+		return Ok((None, version));
+	}
+	let timestamp = try!(reader.read_i64());
+	let min_version = try!(reader.read_u32());
+	Ok((Some(timestamp), min_version))
+}
+
+// Writes the hash of the genesis block of the sender of a hand or shake, if
+// its version has it.
+fn write_genesis<W: Writer>(writer: &mut W,
+                            version: u32,
+                            genesis: Option<Hash>)
+                            -> Result<(), ser::Error> {
+	if version < GENESIS_VERSION {
+		return Ok(());
+	}
+	genesis.unwrap_or(ZERO_HASH).write(writer)
+}
+
+// Reads the hash of the genesis block of the sender of a hand or shake, peers
+// predating it not telling.
+fn read_genesis(reader: &mut Reader, version: u32) -> Result<Option<Hash>, ser::Error> {
+	if version < GENESIS_VERSION {
+		return Ok(None);
+	}
+	Hash::read(reader).map(Some)
+}
+
 /// Second part of a handshake, receiver of the first part replies with its own
 /// version and characteristics.
 pub struct Shake {
@@ -237,41 +485,88 @@ pub struct Shake {
 	pub version: u32,
 	/// sender capabilities
 	pub capabilities: Capabilities,
+	/// services offered by the sender
+	pub services: Services,
 	/// total difficulty accumulated by the sender, used to check whether sync
 	/// may
 	/// be needed
 	pub total_difficulty: Difficulty,
 	/// name of version of the software
 	pub user_agent: String,
+	/// current time of the sender, in seconds since the epoch, if its version
+	/// tells
+	pub timestamp: Option<i64>,
+	/// oldest protocol version the sender speaks, version being the latest
+	pub min_version: u32,
+	/// hash of the genesis block of the sender's chain, if its version tells
+	pub genesis: Option<Hash>,
+	/// address the sender sees the connection coming from, telling the
+	/// receiver its public IP when behind a NAT
+	pub observed_addr: Option<SocketAddr>,
 }
 
 impl Writeable for Shake {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		ser_multiwrite!(writer,
 		                [write_u32, self.version],
-		                [write_u32, self.capabilities.bits()]);
+		                [write_u32, self.capabilities.bits()],
+		                [write_u32, self.services.bits()]);
 		self.total_difficulty.write(writer);
 		writer.write_bytes(&self.user_agent);
-		Ok(())
+		write_clock_and_range(writer, self.version, self.timestamp, self.min_version)?;
+		write_genesis(writer, self.version, self.genesis)?;
+		if self.version >= GENESIS_VERSION {
+			writer.write_u8(self.observed_addr.is_some() as u8)?;
+		}
+		match self.observed_addr {
+			Some(addr) => SockAddr(addr).write(writer),
+			None => Ok(()),
+		}
 	}
 }
 
 impl Readable for Shake {
 	fn read(reader: &mut Reader) -> Result<Shake, ser::Error> {
-		let (version, capab) = ser_multiread!(reader, read_u32, read_u32);
+		let (version, capab, services) = ser_multiread!(reader, read_u32, read_u32, read_u32);
 		let total_diff = try!(Difficulty::read(reader));
 		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
 		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		let (timestamp, min_version) = try!(read_clock_and_range(reader, version));
+		let genesis = try!(read_genesis(reader, version));
+		let observed_addr = if version >= GENESIS_VERSION {
+			match try!(reader.read_u8()) {
+				0 => None,
+				1 => Some(try!(SockAddr::read(reader)).0),
+				_ => return Err(ser::Error::CorruptedData),
+			}
+		} else {
+			try!(read_optional_addr(reader))
+		};
 		Ok(Shake {
 			version: version,
 			capabilities: capabilities,
+			services: Services::from_bits_truncate(services),
 			total_difficulty: total_diff,
 			user_agent: user_agent,
+			timestamp: timestamp,
+			min_version: min_version,
+			genesis: genesis,
+			observed_addr: observed_addr,
 		})
 	}
 }
 
+// Reads the address ending a version 2 shake, absent when the shake ends
+// before it. Anything else wrong with it is, as with any other field.
+fn read_optional_addr(reader: &mut Reader) -> Result<Option<SocketAddr>, ser::Error> {
+	match reader.read_u8() {
+		Ok(v4_or_v6) => read_addr(reader, v4_or_v6).map(|addr| Some(addr.0)),
+		Err(ser::Error::IOErr(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
 /// Ask for other peers addresses, required for network discovery.
 pub struct GetPeerAddrs {
 	/// Filters on the capabilities we'd like the peers to have
@@ -292,6 +587,133 @@ impl Readable for GetPeerAddrs {
 	}
 }
 
+/// Sent by both sides right after the handshake, the optional features the
+/// sender supports. Features we don't know of yet are ignored. Also the body
+/// of the upgrade message confirming the negotiated upgrades.
+pub struct Negotiation {
+	pub features: Features,
+}
+
+impl Writeable for Negotiation {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.features.bits())
+	}
+}
+
+impl Readable for Negotiation {
+	fn read(reader: &mut Reader) -> Result<Negotiation, ser::Error> {
+		let features = try!(reader.read_u32());
+		Ok(Negotiation { features: Features::from_bits_truncate(features) })
+	}
+}
+
+/// What a node reports about itself in response to GetPeerInfo, mostly for
+/// diagnostics. The total difficulty stands in for the height of the tip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfoResp {
+	/// hash of the tip of the chain
+	pub tip: Hash,
+	/// total difficulty at the tip
+	pub total_difficulty: Difficulty,
+	/// how many peers the node is connected to
+	pub peer_count: u32,
+	/// seconds since the node started
+	pub uptime: u64,
+}
+
+impl Writeable for PeerInfoResp {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.tip.write(writer)?;
+		self.total_difficulty.write(writer)?;
+		ser_multiwrite!(writer, [write_u32, self.peer_count], [write_u64, self.uptime]);
+		Ok(())
+	}
+}
+
+impl Readable for PeerInfoResp {
+	fn read(reader: &mut Reader) -> Result<PeerInfoResp, ser::Error> {
+		let tip = Hash::read(reader)?;
+		let total_difficulty = Difficulty::read(reader)?;
+		let (peer_count, uptime) = ser_multiread!(reader, read_u32, read_u64);
+		Ok(PeerInfoResp {
+			tip: tip,
+			total_difficulty: total_difficulty,
+			peer_count: peer_count,
+			uptime: uptime,
+		})
+	}
+}
+
+/// Where a node's chain stands, carried by pings and pongs so peers keep
+/// track of each other's progress between blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainStatus {
+	/// total difficulty at the tip
+	pub total_difficulty: Difficulty,
+	/// height of the tip
+	pub height: u64,
+}
+
+impl Writeable for ChainStatus {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.total_difficulty.write(writer)?;
+		writer.write_u64(self.height)
+	}
+}
+
+impl Readable for ChainStatus {
+	fn read(reader: &mut Reader) -> Result<ChainStatus, ser::Error> {
+		let total_difficulty = Difficulty::read(reader)?;
+		let height = reader.read_u64()?;
+		Ok(ChainStatus {
+			total_difficulty: total_difficulty,
+			height: height,
+		})
+	}
+}
+
+/// Hash of the block a node has at a given height of its chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+	pub height: u64,
+	pub hash: Hash,
+}
+
+/// Checkpoints along the chain of a node, in response to GetCheckpoints.
+pub struct Checkpoints {
+	pub checkpoints: Vec<Checkpoint>,
+}
+
+impl Writeable for Checkpoints {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.checkpoints.len() as u32)?;
+		for cp in &self.checkpoints {
+			writer.write_u64(cp.height)?;
+			cp.hash.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Checkpoints {
+	fn read(reader: &mut Reader) -> Result<Checkpoints, ser::Error> {
+		let len = reader.read_u32()?;
+		if len > MAX_CHECKPOINTS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut checkpoints = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			let height = reader.read_u64()?;
+			let hash = Hash::read(reader)?;
+			checkpoints.push(Checkpoint {
+				height: height,
+				hash: hash,
+			});
+		}
+		Ok(Checkpoints { checkpoints: checkpoints })
+	}
+}
+
 /// Peer addresses we know of that are fresh enough, in response to
 /// GetPeerAddrs.
 pub struct PeerAddrs {
@@ -325,6 +747,29 @@ impl Readable for PeerAddrs {
 	}
 }
 
+/// Peer addresses read leniently: past MAX_PEER_ADDRS, the extra addresses
+/// are dropped instead of failing the read.
+pub struct TruncatedPeerAddrs {
+	pub peers: Vec<SockAddr>,
+	/// Number of addresses the message announced.
+	pub sent: u32,
+}
+
+impl Readable for TruncatedPeerAddrs {
+	fn read(reader: &mut Reader) -> Result<TruncatedPeerAddrs, ser::Error> {
+		let peer_count = try!(reader.read_u32());
+		let kept = cmp::min(peer_count, MAX_PEER_ADDRS);
+		let mut peers = Vec::with_capacity(kept as usize);
+		for _ in 0..kept {
+			peers.push(SockAddr::read(reader)?);
+		}
+		Ok(TruncatedPeerAddrs {
+			peers: peers,
+			sent: peer_count,
+		})
+	}
+}
+
 /// We found some issue in the communication, sending an error back, usually
 /// followed by closing the connection.
 pub struct PeerError {
@@ -381,29 +826,34 @@ impl Writeable for SockAddr {
 impl Readable for SockAddr {
 	fn read(reader: &mut Reader) -> Result<SockAddr, ser::Error> {
 		let v4_or_v6 = try!(reader.read_u8());
-		if v4_or_v6 == 0 {
-			let ip = try!(reader.read_fixed_bytes(4));
-			let port = try!(reader.read_u16());
-			Ok(SockAddr(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(ip[0],
-			                                                           ip[1],
-			                                                           ip[2],
-			                                                           ip[3]),
-			                                             port))))
-		} else {
-			let ip = try_map_vec!([0..8], |_| reader.read_u16());
-			let port = try!(reader.read_u16());
-			Ok(SockAddr(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(ip[0],
-			                                                           ip[1],
-			                                                           ip[2],
-			                                                           ip[3],
-			                                                           ip[4],
-			                                                           ip[5],
-			                                                           ip[6],
-			                                                           ip[7]),
-			                                             port,
-			                                             0,
-			                                             0))))
-		}
+		read_addr(reader, v4_or_v6)
+	}
+}
+
+// Reads the rest of an address once the byte telling its kind was read.
+pub fn read_addr(reader: &mut Reader, v4_or_v6: u8) -> Result<SockAddr, ser::Error> {
+	if v4_or_v6 == 0 {
+		let ip = try!(reader.read_fixed_bytes(4));
+		let port = try!(reader.read_u16());
+		Ok(SockAddr(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(ip[0],
+		                                                           ip[1],
+		                                                           ip[2],
+		                                                           ip[3]),
+		                                             port))))
+	} else {
+		let ip = try_map_vec!([0..8], |_| reader.read_u16());
+		let port = try!(reader.read_u16());
+		Ok(SockAddr(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(ip[0],
+		                                                           ip[1],
+		                                                           ip[2],
+		                                                           ip[3],
+		                                                           ip[4],
+		                                                           ip[5],
+		                                                           ip[6],
+		                                                           ip[7]),
+		                                             port,
+		                                             0,
+		                                             0))))
 	}
 }
 
@@ -448,6 +898,17 @@ impl Writeable for Headers {
 	}
 }
 
+/// A single header announcing a new block, serialized as a headers message
+/// with just that header.
+pub struct HeaderAnnouncement<'a>(pub &'a BlockHeader);
+
+impl<'a> Writeable for HeaderAnnouncement<'a> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u16(1)?;
+		self.0.write(writer)
+	}
+}
+
 impl Readable for Headers {
 	fn read(reader: &mut Reader) -> Result<Headers, ser::Error> {
 		let len = reader.read_u16()?;
@@ -459,8 +920,243 @@ impl Readable for Headers {
 	}
 }
 
-/// Placeholder for messages like Ping and Pong that don't send anything but
-/// the header.
+/// A block relayed without the transactions the remote peer should already
+/// have in its pool, leaving out their inputs and outputs (and range proofs)
+/// that make up most of a block. Only the reward outputs are kept, along with
+/// all the kernels, which identify the pool transactions to rebuild the block
+/// from and are needed whole in the rebuilt block anyway.
+pub struct CompactBlock {
+	pub header: BlockHeader,
+	/// Outputs of the block reward.
+	pub outputs: Vec<Output>,
+	/// All the kernels of the block, in block order.
+	pub kernels: Vec<TxKernel>,
+}
+
+/// A full block serialized as a compact block, without copying it.
+pub struct Compacted<'a>(pub &'a Block);
+
+impl<'a> Writeable for Compacted<'a> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		let reward = self.0
+			.outputs
+			.iter()
+			.filter(|out| out.features.contains(COINBASE_OUTPUT))
+			.collect::<Vec<_>>();
+		self.0.header.write(writer)?;
+		ser_multiwrite!(writer,
+		                [write_u64, reward.len() as u64],
+		                [write_u64, self.0.kernels.len() as u64]);
+		for out in reward {
+			out.write(writer)?
+		}
+		for k in &self.0.kernels {
+			k.write(writer)?
+		}
+		Ok(())
+	}
+}
+
+impl Readable for CompactBlock {
+	fn read(reader: &mut Reader) -> Result<CompactBlock, ser::Error> {
+		let header = BlockHeader::read(reader)?;
+		let (output_len, kernel_len) = ser_multiread!(reader, read_u64, read_u64);
+		let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
+		let kernels = try!((0..kernel_len).map(|_| TxKernel::read(reader)).collect());
+		Ok(CompactBlock {
+			header: header,
+			outputs: outputs,
+			kernels: kernels,
+		})
+	}
+}
+
+impl CompactBlock {
+	/// Rebuilds the full block from the transactions the provided lookup
+	/// finds for each kernel that isn't a reward one. Fails if a transaction
+	/// is missing or if the rebuilt inputs and outputs don't match the header.
+	pub fn reconstruct<F>(self, lookup: F) -> Option<Block>
+		where F: Fn(&TxKernel) -> Option<Transaction>
+	{
+		let mut inputs = vec![];
+		let mut outputs = self.outputs;
+		for k in self.kernels.iter().filter(|k| !k.features.contains(COINBASE_KERNEL)) {
+			match lookup(k) {
+				Some(tx) => {
+					inputs.extend(tx.inputs);
+					outputs.extend(tx.outputs);
+				}
+				None => return None,
+			}
+		}
+		// same ordering and cut-through as when the block was built
+		inputs.sort_by_key(|inp| inp.hash());
+		outputs.sort_by_key(|out| out.hash());
+		let tx_merkle = self.header.tx_merkle;
+		let b = Block {
+				header: self.header,
+				inputs: inputs,
+				outputs: outputs,
+				kernels: self.kernels,
+			}
+			.compact();
+		if b.header.tx_merkle != tx_merkle {
+			return None;
+		}
+		Some(b)
+	}
+}
+
+/// Whether the kernel is the one of the provided transaction, which signed
+/// the same fee with the same excess.
+pub fn is_kernel_of(k: &TxKernel, tx: &Transaction) -> bool {
+	k.fee == tx.fee && k.excess_sig == tx.excess_sig
+}
+
+/// Several blocks sent back-to-back in a single message, in response to a
+/// getdata for multiple blocks.
+pub struct Blocks {
+	pub blocks: Vec<Block>,
+}
+
+impl Writeable for Blocks {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u16(self.blocks.len() as u16)?;
+		for b in &self.blocks {
+			b.write(writer)?
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Blocks {
+	fn read(reader: &mut Reader) -> Result<Blocks, ser::Error> {
+		let len = reader.read_u16()?;
+		if len as u32 > MAX_INV_HASHES {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut blocks = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			blocks.push(Block::read(reader)?);
+		}
+		Ok(Blocks { blocks: blocks })
+	}
+}
+
+/// Inventory of objects of a given type, identified by their hashes. Used
+/// both to announce what we have (Inv) and to ask for what we're missing
+/// (GetData).
+pub struct Inventory {
+	/// Type of all the objects in the inventory
+	pub inv_type: InvType,
+	/// Hashes of the objects
+	pub hashes: Vec<Hash>,
+}
+
+impl Writeable for Inventory {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		ser_multiwrite!(writer,
+		                [write_u8, self.inv_type as u8],
+		                [write_u16, self.hashes.len() as u16]);
+		for h in &self.hashes {
+			h.write(writer)?
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Inventory {
+	fn read(reader: &mut Reader) -> Result<Inventory, ser::Error> {
+		let (t, len) = ser_multiread!(reader, read_u8, read_u16);
+		if len as u32 > MAX_INV_HASHES {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let inv_type = InvType::from_u8(t).ok_or(ser::Error::CorruptedData)?;
+		let mut hashes = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			hashes.push(Hash::read(reader)?);
+		}
+		Ok(Inventory {
+			inv_type: inv_type,
+			hashes: hashes,
+		})
+	}
+}
+
+/// Request for a chunk of the UTXO set snapshot at a horizon block.
+pub struct GetUtxoChunk {
+	/// Hash of the block the unspent outputs are those right after
+	pub horizon: Hash,
+	/// Index of the chunk, from 0
+	pub chunk: u32,
+}
+
+impl Writeable for GetUtxoChunk {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.horizon.write(writer)?;
+		writer.write_u32(self.chunk)
+	}
+}
+
+impl Readable for GetUtxoChunk {
+	fn read(reader: &mut Reader) -> Result<GetUtxoChunk, ser::Error> {
+		let horizon = Hash::read(reader)?;
+		let chunk = reader.read_u32()?;
+		Ok(GetUtxoChunk {
+			horizon: horizon,
+			chunk: chunk,
+		})
+	}
+}
+
+/// Chunk of the UTXO set snapshot at a horizon block, in response to
+/// GetUtxoChunk. A node without a snapshot at the horizon sends no chunks
+/// at all.
+pub struct UtxoChunk {
+	pub horizon: Hash,
+	pub chunk: u32,
+	/// Number of chunks the whole snapshot takes
+	pub chunks: u32,
+	pub outputs: Vec<Output>,
+}
+
+impl Writeable for UtxoChunk {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.horizon.write(writer)?;
+		writer.write_u32(self.chunk)?;
+		writer.write_u32(self.chunks)?;
+		writer.write_u32(self.outputs.len() as u32)?;
+		for out in &self.outputs {
+			out.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for UtxoChunk {
+	fn read(reader: &mut Reader) -> Result<UtxoChunk, ser::Error> {
+		let horizon = Hash::read(reader)?;
+		let chunk = reader.read_u32()?;
+		let chunks = reader.read_u32()?;
+		let len = reader.read_u32()?;
+		if len > MAX_UTXO_CHUNK {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut outputs = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			outputs.push(Output::read(reader)?);
+		}
+		Ok(UtxoChunk {
+			horizon: horizon,
+			chunk: chunk,
+			chunks: chunks,
+			outputs: outputs,
+		})
+	}
+}
+
+/// Placeholder for messages that don't send anything but the header, like
+/// pings and pongs from peers predating ChainStatus.
 pub struct Empty {}
 
 impl Writeable for Empty {
@@ -474,3 +1170,55 @@ impl Readable for Empty {
 		Ok(Empty {})
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use rand::os::OsRng;
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+
+	use core::core::{Block, BlockHeader, Transaction};
+	use core::core::build::{self, input_rand, output_rand, with_fee};
+	use core::ser;
+	use super::*;
+
+	fn new_tx() -> Transaction {
+		build::transaction(vec![input_rand(10), output_rand(9), with_fee(1)])
+			.map(|(tx, _)| tx)
+			.unwrap()
+	}
+
+	#[test]
+	fn compact_block_reconstructed() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let skey = SecretKey::new(&secp, &mut OsRng::new().unwrap());
+		let mut tx1 = new_tx();
+		let mut tx2 = new_tx();
+		let pool = vec![tx1.clone(), tx2.clone()];
+		let b = Block::new(&BlockHeader::default(), vec![&mut tx1, &mut tx2], skey).unwrap();
+
+		// the range proofs of the transactions stay behind
+		let full = ser::ser_vec(&b).unwrap();
+		let compact = ser::ser_vec(&Compacted(&b)).unwrap();
+		assert!(compact.len() * 2 < full.len());
+
+		let cb = ser::deserialize::<CompactBlock>(&mut &compact[..]).unwrap();
+		assert_eq!(cb.outputs.len(), 1);
+		assert_eq!(cb.kernels.len(), 3);
+		let rebuilt = cb.reconstruct(|k| pool.iter().find(|tx| is_kernel_of(k, tx)).cloned());
+		assert_eq!(ser::ser_vec(&rebuilt.unwrap()).unwrap(), full);
+
+		// missing a transaction, the block can't be rebuilt
+		let cb = ser::deserialize::<CompactBlock>(&mut &compact[..]).unwrap();
+		assert!(cb.reconstruct(|k| pool[..1].iter().find(|tx| is_kernel_of(k, tx)).cloned())
+			.is_none());
+	}
+
+	#[test]
+	fn dump_of_start() {
+		let data = vec![0xab; MAX_DUMP_LEN * 4];
+		assert_eq!(hex_dump(&data), hex_dump_of(&data[..MAX_DUMP_LEN], data.len()));
+		assert!(hex_dump(&data).ends_with(&format!("... ({} bytes)", MAX_DUMP_LEN * 4)));
+		assert_eq!(hex_dump_of(&[0xab, 0xcd], 2), "abcd");
+	}
+}
@@ -0,0 +1,482 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mapping of our listening port on the gateway of the local network, so
+//! peers outside of it can connect to us. NAT-PMP gets tried first, being
+//! the simplest, then UPnP. All of it is blocking and best effort, nodes
+//! behind gateways supporting neither only make outbound connections.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+const NATPMP_PORT: u16 = 5351;
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OP_EXTERNAL_ADDR: u8 = 0;
+const NATPMP_OP_MAP_TCP: u8 = 2;
+// Responses have the opcode of the request with the high bit set.
+const NATPMP_RESPONSE: u8 = 128;
+// Attempts at a NAT-PMP request, the timeout doubling after each.
+const NATPMP_ATTEMPTS: u32 = 3;
+const NATPMP_TIMEOUT_MS: u64 = 250;
+
+const SSDP_ADDR: &'static str = "239.255.255.250:1900";
+const SSDP_TIMEOUT_MS: u64 = 2000;
+const UPNP_TIMEOUT_MS: u64 = 3000;
+const IGD_DEVICE: &'static str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_SERVICES: [&'static str; 2] = ["urn:schemas-upnp-org:service:WANIPConnection:1",
+                                         "urn:schemas-upnp-org:service:WANPPPConnection:1"];
+// Error code of the gateways only mapping ports without a lease duration.
+const UPNP_ONLY_PERMANENT: &'static str = "<errorCode>725</errorCode>";
+const MAPPING_NAME: &'static str = "grin";
+
+/// A port on our gateway forwarded to our listener.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortMapping {
+	/// Address peers outside of our network reach us at.
+	pub external: SocketAddr,
+	/// Port of our listener the gateway forwards to.
+	pub internal_port: u16,
+	/// How long the gateway keeps the mapping, we renew it before.
+	pub lifetime: Duration,
+	via: Gateway,
+}
+
+// How the mapping was made, to remove it the same way.
+#[derive(Clone, Debug, PartialEq)]
+enum Gateway {
+	NatPmp(SocketAddr),
+	Upnp(UpnpControl),
+}
+
+// Where to control the port mappings of an UPnP gateway.
+#[derive(Clone, Debug, PartialEq)]
+struct UpnpControl {
+	addr: SocketAddr,
+	path: String,
+	service: String,
+}
+
+/// Maps the port of our listener on the gateway, the provided one or the
+/// default route of the system when not provided, for the provided amount
+/// of time. The gateway may keep it for less, see the lifetime of the
+/// mapping.
+pub fn map_port(gateway: Option<IpAddr>,
+                port: u16,
+                lifetime: Duration)
+                -> io::Result<PortMapping> {
+	let natpmp = match gateway.or_else(default_gateway) {
+		Some(ip) => natpmp_map(SocketAddr::new(ip, NATPMP_PORT), port, lifetime),
+		None => Err(other("no default gateway")),
+	};
+	natpmp.or_else(|e| {
+		debug!("NAT-PMP port mapping failed: {}, trying UPnP.", e);
+		upnp_map(port, lifetime)
+	})
+}
+
+/// Removes a mapping made by map_port, the gateway not forwarding to us
+/// anymore.
+pub fn unmap_port(mapping: &PortMapping) -> io::Result<()> {
+	match mapping.via {
+		Gateway::NatPmp(gateway) => natpmp_unmap(gateway, mapping.internal_port),
+		Gateway::Upnp(ref control) => {
+			let args = vec![("NewRemoteHost", String::new()),
+			                ("NewExternalPort", mapping.external.port().to_string()),
+			                ("NewProtocol", "TCP".to_string())];
+			soap(control, "DeletePortMapping", &args).map(|_| ())
+		}
+	}
+}
+
+// NAT-PMP, RFC 6886, asking the gateway for our external address then for a
+// mapping of the port.
+fn natpmp_map(gateway: SocketAddr, port: u16, lifetime: Duration) -> io::Result<PortMapping> {
+	let socket = UdpSocket::bind("0.0.0.0:0")?;
+	socket.connect(gateway)?;
+	let addr = natpmp_request(&socket, &[NATPMP_VERSION, NATPMP_OP_EXTERNAL_ADDR], 12)?;
+	let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+
+	let lifetime = lifetime.as_secs() as u32;
+	let mapped = natpmp_request(&socket, &natpmp_map_request(port, port, lifetime), 16)?;
+	let external_port = BigEndian::read_u16(&mapped[2..4]);
+	let granted = BigEndian::read_u32(&mapped[4..8]);
+	if granted == 0 {
+		// nothing is mapped, renewing right away wouldn't change it
+		return Err(other("no lifetime granted by the NAT-PMP gateway"));
+	}
+	Ok(PortMapping {
+		external: SocketAddr::new(IpAddr::V4(ip), external_port),
+		internal_port: port,
+		lifetime: Duration::from_secs(granted as u64),
+		via: Gateway::NatPmp(gateway),
+	})
+}
+
+// Removes the mapping of our port, a request with no suggested external
+// port and no lifetime as RFC 6886 requires for deletions.
+fn natpmp_unmap(gateway: SocketAddr, port: u16) -> io::Result<()> {
+	let socket = UdpSocket::bind("0.0.0.0:0")?;
+	socket.connect(gateway)?;
+	natpmp_request(&socket, &natpmp_map_request(port, 0, 0), 16).map(|_| ())
+}
+
+// Request mapping a TCP port to the suggested external one, the gateway
+// picking another if taken.
+fn natpmp_map_request(port: u16, external_port: u16, lifetime: u32) -> Vec<u8> {
+	let mut req = vec![NATPMP_VERSION, NATPMP_OP_MAP_TCP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+	BigEndian::write_u16(&mut req[4..6], port);
+	BigEndian::write_u16(&mut req[6..8], external_port);
+	BigEndian::write_u32(&mut req[8..12], lifetime);
+	req
+}
+
+// Sends the request until the gateway answers, returning what follows the
+// result code and the epoch in its response.
+fn natpmp_request(socket: &UdpSocket, req: &[u8], len: usize) -> io::Result<Vec<u8>> {
+	let mut buf = [0; 16];
+	let mut timeout = NATPMP_TIMEOUT_MS;
+	for _ in 0..NATPMP_ATTEMPTS {
+		socket.set_read_timeout(Some(Duration::from_millis(timeout)))?;
+		socket.send(req)?;
+		match socket.recv(&mut buf) {
+			Ok(n) => return parse_natpmp_response(req[1], &buf[..n], len),
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+			              e.kind() == io::ErrorKind::TimedOut => timeout *= 2,
+			Err(e) => return Err(e),
+		}
+	}
+	Err(io::Error::new(io::ErrorKind::TimedOut, "no NAT-PMP response"))
+}
+
+fn parse_natpmp_response(op: u8, resp: &[u8], len: usize) -> io::Result<Vec<u8>> {
+	if resp.len() < len || resp[0] != NATPMP_VERSION || resp[1] != op + NATPMP_RESPONSE {
+		return Err(other("malformed NAT-PMP response"));
+	}
+	match BigEndian::read_u16(&resp[2..4]) {
+		0 => Ok(resp[8..len].to_vec()),
+		code => Err(other(&format!("NAT-PMP result code {}", code))),
+	}
+}
+
+// The gateway of the default route, from the routing table on Linux.
+fn default_gateway() -> Option<IpAddr> {
+	let mut routes = String::new();
+	match File::open("/proc/net/route").and_then(|mut f| f.read_to_string(&mut routes)) {
+		Ok(_) => parse_default_route(&routes).map(IpAddr::V4),
+		Err(_) => None,
+	}
+}
+
+// Lines of the routing table have the interface, then the destination and
+// the gateway in hexadecimal, the bytes in the order of the address in
+// memory.
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+	for line in routes.lines().skip(1) {
+		let fields = line.split_whitespace().collect::<Vec<_>>();
+		if fields.len() < 3 || fields[1] != "00000000" {
+			continue;
+		}
+		if let Ok(gw) = u32::from_str_radix(fields[2], 16) {
+			if gw != 0 {
+				let (a, b, c, d) = (gw as u8, (gw >> 8) as u8, (gw >> 16) as u8, (gw >> 24) as u8);
+				return Some(Ipv4Addr::new(a, b, c, d));
+			}
+		}
+	}
+	None
+}
+
+// UPnP, finding the gateway with SSDP, then asking its WAN connection
+// service for the mapping and our external address.
+fn upnp_map(port: u16, lifetime: Duration) -> io::Result<PortMapping> {
+	let control = upnp_discover()?;
+	let local_ip = {
+		let socket = UdpSocket::bind("0.0.0.0:0")?;
+		socket.connect(control.addr)?;
+		socket.local_addr()?.ip()
+	};
+	let mut lease = lifetime.as_secs();
+	let mut args = vec![("NewRemoteHost", String::new()),
+	                    ("NewExternalPort", port.to_string()),
+	                    ("NewProtocol", "TCP".to_string()),
+	                    ("NewInternalPort", port.to_string()),
+	                    ("NewInternalClient", local_ip.to_string()),
+	                    ("NewEnabled", "1".to_string()),
+	                    ("NewPortMappingDescription", MAPPING_NAME.to_string()),
+	                    ("NewLeaseDuration", lease.to_string())];
+	if let Err(e) = soap(&control, "AddPortMapping", &args) {
+		if !e.to_string().contains(UPNP_ONLY_PERMANENT) {
+			return Err(e);
+		}
+		// mapped until removed, still renewed like the others
+		lease = 0;
+		args[7].1 = lease.to_string();
+		soap(&control, "AddPortMapping", &args)?;
+	}
+	let resp = soap(&control, "GetExternalIPAddress", &[])?;
+	let ip = xml_value(&resp, "NewExternalIPAddress")
+		.and_then(|ip| ip.parse::<IpAddr>().ok())
+		.ok_or(other("no external address from the UPnP gateway"))?;
+	Ok(PortMapping {
+		external: SocketAddr::new(ip, port),
+		internal_port: port,
+		lifetime: if lease > 0 {
+			Duration::from_secs(lease)
+		} else {
+			lifetime
+		},
+		via: Gateway::Upnp(control),
+	})
+}
+
+// Asks the local network for an internet gateway, then its description for
+// where to control its WAN connection.
+fn upnp_discover() -> io::Result<UpnpControl> {
+	let socket = UdpSocket::bind("0.0.0.0:0")?;
+	socket.set_read_timeout(Some(Duration::from_millis(SSDP_TIMEOUT_MS)))?;
+	let search = format!("M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\n\
+	                      MX: 2\r\n\r\n",
+	                     SSDP_ADDR,
+	                     IGD_DEVICE);
+	socket.send_to(search.as_bytes(), SSDP_ADDR)?;
+	let mut buf = [0; 2048];
+	let (n, _) = socket.recv_from(&mut buf)?;
+	let location = header_value(&String::from_utf8_lossy(&buf[..n]), "location")
+		.ok_or(other("no location in the SSDP response"))?;
+	let (addr, path) = parse_http_url(&location).ok_or(other("unsupported UPnP location"))?;
+	let desc = http(addr, &format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr))?;
+	control_url(&desc, addr).ok_or(other("no WAN connection service on the UPnP gateway"))
+}
+
+// The address and path of an http URL, its host being an IP address as
+// gateways advertise them.
+fn parse_http_url(url: &str) -> Option<(SocketAddr, String)> {
+	if !url.starts_with("http://") {
+		return None;
+	}
+	let rest = &url["http://".len()..];
+	let (host, path) = match rest.find('/') {
+		Some(i) => (&rest[..i], rest[i..].to_string()),
+		None => (rest, "/".to_string()),
+	};
+	let addr = host.parse::<SocketAddr>()
+		.ok()
+		.or_else(|| host.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 80)));
+	addr.map(|addr| (addr, path))
+}
+
+// Where to control the first WAN connection service of the device
+// description, relative to the device.
+fn control_url(desc: &str, device: SocketAddr) -> Option<UpnpControl> {
+	for service in WAN_SERVICES.iter() {
+		let tag = format!("<serviceType>{}</serviceType>", service);
+		let start = match desc.find(&tag) {
+			Some(i) => i + tag.len(),
+			None => continue,
+		};
+		let url = match xml_value(&desc[start..], "controlURL") {
+			Some(url) => url,
+			None => continue,
+		};
+		let (addr, path) = if url.starts_with("http://") {
+			match parse_http_url(&url) {
+				Some(parsed) => parsed,
+				None => continue,
+			}
+		} else if url.starts_with('/') {
+			(device, url)
+		} else {
+			(device, format!("/{}", url))
+		};
+		return Some(UpnpControl {
+			addr: addr,
+			path: path,
+			service: service.to_string(),
+		});
+	}
+	None
+}
+
+// Calls the action on the WAN connection service, returning the response.
+fn soap(control: &UpnpControl, action: &str, args: &[(&str, String)]) -> io::Result<String> {
+	let mut body = format!("<?xml version=\"1.0\"?>\r\n<s:Envelope \
+	                        xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+	                        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+	                        <s:Body><u:{} xmlns:u=\"{}\">",
+	                       action,
+	                       control.service);
+	for &(ref name, ref value) in args {
+		body.push_str(&format!("<{}>{}</{}>", name, value, name));
+	}
+	body.push_str(&format!("</u:{}></s:Body></s:Envelope>", action));
+	let req = format!("POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; \
+	                   charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\nContent-Length: \
+	                   {}\r\n\r\n{}",
+	                  control.path,
+	                  control.addr,
+	                  control.service,
+	                  action,
+	                  body.len(),
+	                  body);
+	http(control.addr, &req)
+}
+
+// Sends the HTTP request, returning the body of the response if it
+// succeeded, the whole response as error otherwise.
+fn http(addr: SocketAddr, req: &str) -> io::Result<String> {
+	let mut stream = TcpStream::connect(addr)?;
+	stream.set_read_timeout(Some(Duration::from_millis(UPNP_TIMEOUT_MS)))?;
+	stream.set_write_timeout(Some(Duration::from_millis(UPNP_TIMEOUT_MS)))?;
+	stream.write_all(req.as_bytes())?;
+	let mut resp = String::new();
+	stream.read_to_string(&mut resp)?;
+	let status_ok = resp.lines().next().map(|l| l.contains(" 200 ")).unwrap_or(false);
+	match resp.find("\r\n\r\n") {
+		Some(i) if status_ok => Ok(resp[i + 4..].to_string()),
+		_ => Err(other(&resp)),
+	}
+}
+
+// Value of a header of an HTTP like response, named in any case.
+fn header_value(resp: &str, name: &str) -> Option<String> {
+	for line in resp.lines() {
+		if let Some(i) = line.find(':') {
+			if line[..i].trim().to_lowercase() == name {
+				return Some(line[i + 1..].trim().to_string());
+			}
+		}
+	}
+	None
+}
+
+// Content of the first element with the provided tag.
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+	let open = format!("<{}>", tag);
+	let close = format!("</{}>", tag);
+	let start = match xml.find(&open) {
+		Some(i) => i + open.len(),
+		None => return None,
+	};
+	xml[start..].find(&close).map(|end| xml[start..start + end].trim().to_string())
+}
+
+fn other(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, msg.to_string())
+}
+
+#[cfg(test)]
+mod test {
+	use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+	use std::thread;
+	use std::time::Duration;
+
+	use byteorder::{BigEndian, ByteOrder};
+
+	use super::*;
+
+	#[test]
+	fn default_route_gateway() {
+		let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+		              eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+		              eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+		assert_eq!(parse_default_route(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+		assert_eq!(parse_default_route("Iface\tDestination\tGateway\n"), None);
+	}
+
+	#[test]
+	fn upnp_description() {
+		let device: SocketAddr = "192.168.1.1:5000".parse().unwrap();
+		assert_eq!(parse_http_url("http://192.168.1.1:5000/rootDesc.xml"),
+		           Some((device, "/rootDesc.xml".to_string())));
+		assert_eq!(parse_http_url("http://192.168.1.1"),
+		           Some(("192.168.1.1:80".parse().unwrap(), "/".to_string())));
+		assert_eq!(parse_http_url("https://192.168.1.1/"), None);
+
+		let desc = "<root><service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1\
+		            </serviceType><controlURL>/ctl/L3F</controlURL></service><service>\
+		            <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+		            <controlURL>ctl/IPConn</controlURL></service></root>";
+		let control = control_url(desc, device).unwrap();
+		assert_eq!(control.addr, device);
+		assert_eq!(control.path, "/ctl/IPConn");
+		assert_eq!(control.service, WAN_SERVICES[0]);
+		assert_eq!(control_url("<root></root>", device), None);
+
+		let resp = "HTTP/1.1 200 OK\r\nCache-Control: max-age=120\r\n\
+		            LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+		assert_eq!(header_value(resp, "location"),
+		           Some("http://192.168.1.1:5000/rootDesc.xml".to_string()));
+	}
+
+	// Answers NAT-PMP requests like a gateway with the provided external
+	// address, mapping to the next port up.
+	fn natpmp_gateway(socket: UdpSocket, external: [u8; 4], requests: usize) {
+		let mut buf = [0; 12];
+		for _ in 0..requests {
+			let (n, from) = socket.recv_from(&mut buf).unwrap();
+			let mut resp = vec![0, buf[1] + 128, 0, 0, 0, 0, 0, 42];
+			if buf[1] == 0 {
+				resp.extend(external.iter().cloned());
+			} else {
+				assert_eq!(n, 12);
+				if BigEndian::read_u32(&buf[8..12]) == 0 {
+					// deletions suggest no external port
+					assert_eq!(BigEndian::read_u16(&buf[6..8]), 0);
+				}
+				let port = BigEndian::read_u16(&buf[4..6]);
+				let mut mapping = [0; 8];
+				BigEndian::write_u16(&mut mapping[0..2], port);
+				BigEndian::write_u16(&mut mapping[2..4], port + 1);
+				mapping[4..8].copy_from_slice(&buf[8..12]);
+				resp.extend(mapping.iter().cloned());
+			}
+			socket.send_to(&resp, from).unwrap();
+		}
+	}
+
+	#[test]
+	fn natpmp_mapping() {
+		let gateway: SocketAddr = "127.0.0.1:13770".parse().unwrap();
+		let socket = UdpSocket::bind(gateway).unwrap();
+		let responder = thread::spawn(move || natpmp_gateway(socket, [1, 2, 3, 4], 3));
+
+		let mapping = natpmp_map(gateway, 13414, Duration::from_secs(3600)).unwrap();
+		assert_eq!(mapping.external, "1.2.3.4:13415".parse().unwrap());
+		assert_eq!(mapping.internal_port, 13414);
+		assert_eq!(mapping.lifetime, Duration::from_secs(3600));
+		assert!(unmap_port(&mapping).is_ok());
+		responder.join().unwrap();
+
+		// gateways refusing to map, or answering something else
+		let req = natpmp_map_request(13414, 13414, 3600);
+		assert_eq!(&req[..], &[0, 2, 0, 0, 0x34, 0x66, 0x34, 0x66, 0, 0, 0x0e, 0x10]);
+		let req = natpmp_map_request(13414, 0, 0);
+		assert_eq!(&req[..], &[0, 2, 0, 0, 0x34, 0x66, 0, 0, 0, 0, 0, 0]);
+		let refused = [0, 130, 0, 2, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0, 0];
+		assert!(parse_natpmp_response(2, &refused, 16).is_err());
+		assert!(parse_natpmp_response(0, &refused[..12], 12).is_err());
+		assert!(parse_natpmp_response(2, &refused[..12], 16).is_err());
+
+		// a gateway granting no lifetime maps nothing
+		let gateway: SocketAddr = "127.0.0.1:13775".parse().unwrap();
+		let socket = UdpSocket::bind(gateway).unwrap();
+		let responder = thread::spawn(move || natpmp_gateway(socket, [1, 2, 3, 4], 2));
+		assert!(natpmp_map(gateway, 13414, Duration::from_secs(0)).is_err());
+		responder.join().unwrap();
+	}
+}
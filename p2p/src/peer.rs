@@ -13,15 +13,22 @@
 // limitations under the License.
 
 use std::net::SocketAddr;
-use std::sync::{RwLock, Arc};
+use std::sync::{Mutex, RwLock, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use futures::Future;
-use tokio_core::net::TcpStream;
+use futures::{future, Future};
 
 use core::core;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
+use conn::Traffic;
 use handshake::Handshake;
+use msg::{ChainStatus, Checkpoint, PeerInfoResp, UtxoChunk};
+use pool::BlockPool;
+use server::LocalStatus;
+use stream::PeerStream;
+use throttle::Throttle;
 use types::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -29,50 +36,78 @@ enum State {
 	Connected,
 	Disconnected,
 	Banned,
+	Quarantined,
 }
 
 pub struct Peer {
 	pub info: PeerInfo,
 	proto: Box<Protocol>,
 	state: Arc<RwLock<State>>,
+	connected_at: Instant,
+	// whether the peer reported checkpoints the majority disagrees with
+	conflicting: AtomicBool,
+	// round trip time of our latest ping
+	latency: Mutex<Option<Duration>>,
 }
 
-unsafe impl Sync for Peer {}
-unsafe impl Send for Peer {}
-
 impl Peer {
-	/// Initiates the handshake with another peer.
-	pub fn connect(conn: TcpStream,
-	               capab: Capabilities,
-	               total_difficulty: Difficulty,
-	               self_addr: SocketAddr,
-	               hs: &Handshake)
-	               -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let connect_peer = hs.connect(capab, total_difficulty, self_addr, conn)
+	/// Initiates the handshake with another peer at the address we dialed,
+	/// over a plain socket or an already secured stream.
+	pub fn connect<S>(conn: S,
+	                  addr: SocketAddr,
+	                  capab: Capabilities,
+	                  total_difficulty: Difficulty,
+	                  services: Services,
+	                  self_addr: SocketAddr,
+	                  hs: &Handshake)
+	                  -> Box<Future<Item = (PeerStream, Peer), Error = Error>>
+		where S: Into<PeerStream>
+	{
+		let conn = conn.into();
+		let log_id = PeerLogId::new(addr);
+		debug!("{} Connecting.", log_id);
+		let connect_peer = hs
+			.connect(capab, total_difficulty, services, self_addr, addr, log_id, conn)
 			.and_then(|(conn, proto, info)| {
 				Ok((conn,
 				    Peer {
 					info: info,
 					proto: Box::new(proto),
 					state: Arc::new(RwLock::new(State::Connected)),
+					connected_at: Instant::now(),
+					conflicting: AtomicBool::new(false),
+					latency: Mutex::new(None),
 				}))
 			});
 		Box::new(connect_peer)
 	}
 
-	/// Accept a handshake initiated by another peer.
-	pub fn accept(conn: TcpStream,
-	              capab: Capabilities,
-	              total_difficulty: Difficulty,
-	              hs: &Handshake)
-	              -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let hs_peer = hs.handshake(capab, total_difficulty, conn)
+	/// Accept a handshake initiated by another peer, over a plain socket or
+	/// an already secured stream.
+	pub fn accept<S>(conn: S,
+	                 capab: Capabilities,
+	                 total_difficulty: Difficulty,
+	                 services: Services,
+	                 hs: &Handshake)
+	                 -> Box<Future<Item = (PeerStream, Peer), Error = Error>>
+		where S: Into<PeerStream>
+	{
+		let conn = conn.into();
+		let log_id = match conn.peer_addr() {
+			Ok(addr) => PeerLogId::new(addr),
+			Err(e) => return Box::new(future::err(Error::Connection(e))),
+		};
+		debug!("{} Accepting.", log_id);
+		let hs_peer = hs.handshake(capab, total_difficulty, services, log_id, conn)
 			.and_then(|(conn, proto, info)| {
 				Ok((conn,
 				    Peer {
 					info: info,
 					proto: Box::new(proto),
 					state: Arc::new(RwLock::new(State::Connected)),
+					connected_at: Instant::now(),
+					conflicting: AtomicBool::new(false),
+					latency: Mutex::new(None),
 				}))
 			});
 		Box::new(hs_peer)
@@ -81,61 +116,285 @@ impl Peer {
 	/// Main peer loop listening for messages and forwarding to the rest of the
 	/// system.
 	pub fn run(&self,
-	           conn: TcpStream,
+	           conn: PeerStream,
 	           na: Arc<NetAdapter>)
 	           -> Box<Future<Item = (), Error = Error>> {
+		let local = Arc::new(LocalStatus::new(Arc::new(RwLock::new(vec![]))));
+		self.run_throttled(conn, na, Throttle::unlimited(), None, Arc::new(Traffic::new()), local)
+	}
+
+	/// Same as run, with our writes to the peer limited by the provided
+	/// throttle and the blocks it sends handed to the block pool, if any. The
+	/// bytes exchanged with the peer also count towards the provided traffic,
+	/// and peers asking about us get told the provided local status.
+	pub fn run_throttled(&self,
+	                     conn: PeerStream,
+	                     na: Arc<NetAdapter>,
+	                     throttle: Throttle,
+	                     blocks: Option<Arc<BlockPool>>,
+	                     traffic: Arc<Traffic>,
+	                     local: Arc<LocalStatus>)
+	                     -> Box<Future<Item = (), Error = Error>> {
 
-		let addr = self.info.addr;
+		let log_id = self.info.log_id.clone();
 		let state = self.state.clone();
-		Box::new(self.proto.handle(conn, na).then(move |res| {
+		debug!("{} Running.", log_id);
+		Box::new(self.proto.handle(conn, na, throttle, blocks, traffic, local).then(move |res| {
 			// handle disconnection, standard disconnections aren't considered an error
-			let mut state = state.write().unwrap();
+			let mut state = state.write().unwrap_or_else(|e| e.into_inner());
 			match res {
+				Ok(_) if *state == State::Banned => {
+					info!("{} Client banned, disconnected.", log_id);
+					Ok(())
+				}
+				Ok(_) if *state == State::Quarantined => {
+					info!("{} Client quarantined, disconnected.", log_id);
+					Ok(())
+				}
 				Ok(res) => {
 					*state = State::Disconnected;
-					info!("Client {} disconnected.", addr);
+					info!("{} Client disconnected.", log_id);
 					Ok(())
 				}
 				Err(Error::Serialization(e)) => {
 					*state = State::Banned;
-					info!("Client {} corrupted, ban.", addr);
+					info!("{} Client corrupted, ban.", log_id);
 					Err(Error::Serialization(e))
 				}
+				Err(Error::Misbehaving) => {
+					*state = State::Banned;
+					info!("{} Client misbehaved, ban.", log_id);
+					Err(Error::Misbehaving)
+				}
+				Err(Error::AdapterPanic) => {
+					*state = State::Disconnected;
+					info!("{} Adapter failed on the client messages, disconnected.", log_id);
+					Err(Error::AdapterPanic)
+				}
+				Err(Error::Checksum) => {
+					// likely the link rather than the peer, no reason to ban
+					*state = State::Disconnected;
+					info!("{} Client message corrupted in transit, disconnected.", log_id);
+					Err(Error::Checksum)
+				}
 				Err(_) => {
 					*state = State::Disconnected;
-					info!("Client {} connection lost.", addr);
+					info!("{} Client connection lost.", log_id);
 					Ok(())
 				}
 			}
 		}))
 	}
 
+	/// When we connected to the peer.
+	pub fn connected_at(&self) -> Instant {
+		self.connected_at
+	}
+
+	/// How long since we connected to the peer.
+	pub fn uptime(&self) -> Duration {
+		self.connected_at.elapsed()
+	}
+
+	/// Latest total difficulty of the peer, as advertised in the handshake or
+	/// its pongs, or shown by the blocks and headers it sent since. What a
+	/// pong claimed stops counting once its headers fall short of it.
+	pub fn total_difficulty(&self) -> Difficulty {
+		let (seen, _) = self.proto.total_difficulty();
+		let mut diff = if seen > self.info.total_difficulty {
+			seen
+		} else {
+			self.info.total_difficulty.clone()
+		};
+		if let Some(status) = self.chain_status() {
+			if status.total_difficulty > diff {
+				diff = status.total_difficulty;
+			}
+		}
+		diff
+	}
+
+	/// Status of its chain the peer sent in its latest pong, see
+	/// PeerInfo::chain_status.
+	pub fn chain_status(&self) -> Option<ChainStatus> {
+		self.info.chain_status.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+
+	/// Height of the tip of the peer, as sent in its latest pong.
+	pub fn height(&self) -> Option<u64> {
+		self.chain_status().map(|s| s.height)
+	}
+
+	/// Round trip time of our latest ping the peer answered.
+	pub fn latency(&self) -> Option<Duration> {
+		*self.latency.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Records the pong of the peer to our latest ping, with the round trip
+	/// time and the status of its chain if it sent one.
+	pub fn pong_received(&self, rtt: Duration, status: Option<ChainStatus>) {
+		*self.latency.lock().unwrap_or_else(|e| e.into_inner()) = Some(rtt);
+		if status.is_some() {
+			*self.info.chain_status.lock().unwrap_or_else(|e| e.into_inner()) = status;
+		}
+	}
+
+	/// Hash and total difficulty of the tip of the peer, as shown by the
+	/// blocks and headers it sent, none until it sent any.
+	pub fn tip(&self) -> Option<(Hash, Difficulty)> {
+		self.proto.tip()
+	}
+
+	/// How long the total difficulty of the peer hasn't increased for, since
+	/// the handshake if it never did.
+	pub fn difficulty_stale_for(&self) -> Duration {
+		let (_, increased_at) = self.proto.total_difficulty();
+		increased_at.elapsed()
+	}
+
 	/// Whether this peer is still connected.
 	pub fn is_connected(&self) -> bool {
-		let state = self.state.read().unwrap();
+		let state = self.state.read().unwrap_or_else(|e| e.into_inner());
 		*state == State::Connected
 	}
 
+	/// Whether the peer sent us anything since the handshake, see
+	/// PeerInfo::verified.
+	pub fn is_verified(&self) -> bool {
+		self.info.verified.load(Ordering::Relaxed)
+	}
+
 	/// Whether this peer has been banned.
 	pub fn is_banned(&self) -> bool {
-		let state = self.state.read().unwrap();
+		let state = self.state.read().unwrap_or_else(|e| e.into_inner());
 		*state == State::Banned
 	}
 
+	/// Whether this peer has been quarantined.
+	pub fn is_quarantined(&self) -> bool {
+		let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+		*state == State::Quarantined
+	}
+
 	/// Bytes sent and received by this peer to the remote peer.
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
 		self.proto.transmitted_bytes()
 	}
 
+	/// Messages sent and received by this peer to the remote peer.
+	pub fn transmitted_msgs(&self) -> (u64, u64) {
+		self.proto.transmitted_msgs()
+	}
+
+	/// Number of orphan blocks received from the remote peer.
+	pub fn orphan_count(&self) -> u64 {
+		self.proto.orphan_count()
+	}
+
+	/// Latest protocol violations of the remote peer, oldest first, to know
+	/// why it got banned.
+	pub fn violations(&self) -> Vec<ViolationRecord> {
+		self.proto.violations()
+	}
+
+	/// Number of protocol violations of the remote peer, including those too
+	/// old to still be listed.
+	pub fn violation_count(&self) -> u64 {
+		self.proto.violation_count()
+	}
+
+	/// Misbehavior score of the remote peer, adding up the weights of its
+	/// protocol violations.
+	pub fn misbehavior_score(&self) -> u32 {
+		self.proto.misbehavior_score()
+	}
+
+	/// Starts counting the bytes transmitted and the orphan blocks received
+	/// from zero again, staying connected.
+	pub fn reset_stats(&self) {
+		self.proto.reset_stats()
+	}
+
 	pub fn send_ping(&self) -> Result<(), Error> {
 		self.proto.send_ping()
 	}
 
-	/// Sends the provided block to the remote peer. The request may be dropped
-	/// if the remote peer is known to already have the block.
-	pub fn send_block(&self, b: &core::Block) -> Result<(), Error> {
-		// TODO do not send if the peer sent us the block in the first place
-		self.proto.send_block(b)
+	/// Pings the remote peer with the status of our chain, resolving once it
+	/// answers to the round trip time and the status of its chain.
+	pub fn ping(&self, status: ChainStatus) -> Result<PingFuture, Error> {
+		self.proto.ping(status)
+	}
+
+	/// Sends the block to the remote peer, unless it's known to have it
+	/// already. Peers that negotiated compact blocks get it without the
+	/// transactions they should have in their pool.
+	pub fn send_block(&self, b: &core::Block) -> SendOutcome {
+		if self.proto.knows_block(b.hash()) {
+			return SendOutcome::SkippedAlreadyHave;
+		}
+		let res = if self.info.features.contains(COMPACT_BLOCKS) {
+			self.proto.send_compact_block(b)
+		} else {
+			self.proto.send_block(b)
+		};
+		match res {
+			Ok(()) => SendOutcome::Sent,
+			Err(e) => SendOutcome::Failed(e),
+		}
+	}
+
+	/// Relays the transaction to the remote peer, unless it's known to have
+	/// it already. Peers that negotiated transaction inventories only get its
+	/// hash, asking for the full transaction if they miss it.
+	pub fn send_transaction(&self, tx: &core::Transaction) -> SendOutcome {
+		let h = tx.hash();
+		if self.proto.knows_transaction(h) {
+			return SendOutcome::SkippedAlreadyHave;
+		}
+		let res = if self.info.features.contains(TX_INV) {
+			self.proto.send_transaction_inv(h)
+		} else {
+			self.proto.send_transaction(tx)
+		};
+		match res {
+			Ok(()) => SendOutcome::Sent,
+			Err(e) => SendOutcome::Failed(e),
+		}
+	}
+
+	/// Passes the transaction on to the remote peer along a dandelion stem,
+	/// unless it's known to have it already.
+	pub fn send_stem_transaction(&self, tx: &core::Transaction) -> SendOutcome {
+		if self.proto.knows_transaction(tx.hash()) {
+			return SendOutcome::SkippedAlreadyHave;
+		}
+		match self.proto.send_stem_transaction(tx) {
+			Ok(()) => SendOutcome::Sent,
+			Err(e) => SendOutcome::Failed(e),
+		}
+	}
+
+	/// Whether the remote peer is known to have the transaction, because it
+	/// sent or announced it to us or because we already sent it.
+	pub fn knows_transaction(&self, h: Hash) -> bool {
+		self.proto.knows_transaction(h)
+	}
+
+	/// Announces a block to the remote peer by its header, unless it's known
+	/// to have the block already.
+	pub fn send_header(&self, bh: &core::BlockHeader) -> SendOutcome {
+		if self.proto.knows_block(bh.hash()) {
+			return SendOutcome::SkippedAlreadyHave;
+		}
+		match self.proto.send_header(bh) {
+			Ok(()) => SendOutcome::Sent,
+			Err(e) => SendOutcome::Failed(e),
+		}
+	}
+
+	/// Announces the block with the provided hash to the remote peer.
+	pub fn send_block_inv(&self, h: Hash) -> Result<(), Error> {
+		self.proto.send_block_inv(h)
 	}
 
 	pub fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
@@ -143,16 +402,84 @@ impl Peer {
 	}
 
 	pub fn send_block_request(&self, h: Hash) -> Result<(), Error> {
-		debug!("Requesting block {} from peer {}.", h, self.info.addr);
+		debug!("{} Requesting block {}.", self.info.log_id, h);
 		self.proto.send_block_request(h)
 	}
 
+	/// Number of blocks we asked the peer for and are still waiting for.
+	pub fn blocks_in_flight(&self) -> usize {
+		self.proto.blocks_in_flight()
+	}
+
 	pub fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error> {
-		debug!("Asking {} for more peers.", self.info.addr);
+		debug!("{} Asking for more peers.", self.info.log_id);
 		self.proto.send_peer_request(capab)
 	}
 
+	/// Asks the remote peer about its chain tip, peers and uptime, resolving
+	/// to what it reports. Asked again too soon, the peer repeats its previous
+	/// answer.
+	pub fn request_peer_info(&self)
+	                         -> Result<Box<Future<Item = PeerInfoResp, Error = Error>>, Error> {
+		debug!("{} Asking for peer info.", self.info.log_id);
+		self.proto.send_peer_info_request()
+	}
+
+	/// Asks the remote peer for checkpoints along its chain, resolving to
+	/// those it reports.
+	pub fn request_checkpoints(&self)
+		-> Result<Box<Future<Item = Vec<Checkpoint>, Error = Error>>, Error> {
+		debug!("{} Asking for checkpoints.", self.info.log_id);
+		self.proto.send_checkpoints_request()
+	}
+
+	/// Asks the remote peer for a chunk of its UTXO set snapshot at the
+	/// horizon block, resolving to the chunk it sends.
+	pub fn request_utxo_chunk(&self,
+	                          horizon: Hash,
+	                          chunk: u32)
+	                          -> Result<Box<Future<Item = UtxoChunk, Error = Error>>, Error> {
+		debug!("{} Asking for chunk {} of the UTXO set at {}.", self.info.log_id, chunk, horizon);
+		self.proto.send_utxo_chunk_request(horizon, chunk)
+	}
+
+	/// Flags the peer as having reported checkpoints most other peers
+	/// disagree with.
+	pub fn flag_conflicting(&self) {
+		self.conflicting.store(true, Ordering::Relaxed);
+	}
+
+	/// Whether the peer reported checkpoints most other peers disagree with.
+	pub fn is_conflicting(&self) -> bool {
+		self.conflicting.load(Ordering::Relaxed)
+	}
+
 	pub fn stop(&self) {
 		self.proto.close();
 	}
+
+	/// Closes the connection once the messages already queued for the remote
+	/// peer went out, no new ones being queued. Closes anyway after the
+	/// timeout.
+	pub fn stop_gracefully(&self, timeout: Duration) {
+		self.proto.close_gracefully(timeout);
+	}
+
+	/// Marks the peer as banned and closes the connection with it.
+	pub fn ban(&self) {
+		{
+			let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+			*state = State::Banned;
+		}
+		self.stop();
+	}
+
+	/// Marks the peer as quarantined and closes the connection with it.
+	pub fn quarantine(&self) {
+		{
+			let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+			*state = State::Quarantined;
+		}
+		self.stop();
+	}
 }
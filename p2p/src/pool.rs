@@ -0,0 +1,124 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool of worker threads handing the blocks received from peers over to the
+//! adapter, so slow validation holds up neither the event loop nor the other
+//! peers. Each peer gets one worker, so its blocks reach the adapter in the
+//! order they were received, and peers are spread over the workers in turn.
+//! The queues feeding the workers are bounded: a peer sending blocks while
+//! its worker's queue is full has its reads paused until there's room again.
+
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc::{self, Sender, UnboundedSender};
+
+use core::core;
+use protocol::{Remote, receive_block};
+use types::{Error, NetAdapter};
+
+// Blocks received together from a peer, with what's needed to reply to it.
+struct Job {
+	blocks: Vec<core::Block>,
+	remote: Arc<Remote>,
+	src: SocketAddr,
+	sender: UnboundedSender<Vec<u8>>,
+}
+
+/// Handle to the block workers, they stop once it and all the lanes taken
+/// from it are dropped.
+pub struct BlockPool {
+	queues: Vec<Sender<Job>>,
+	next: AtomicUsize,
+}
+
+impl BlockPool {
+	/// Starts the provided number of workers, each taking its blocks off a
+	/// queue of the provided size.
+	pub fn new(adapter: Arc<NetAdapter>, workers: u32, queue_size: usize) -> BlockPool {
+		let mut queues = vec![];
+		for n in 0..workers {
+			let (tx, rx) = mpsc::channel(queue_size);
+			let adapter = adapter.clone();
+			thread::Builder::new()
+				.name(format!("p2p-blocks-{}", n))
+				.spawn(move || for job in rx.wait() {
+					match job {
+						Ok(job) => receive_job(adapter.as_ref(), job),
+						Err(_) => break,
+					}
+				})
+				.expect("failed to start block worker");
+			queues.push(tx);
+		}
+		BlockPool {
+			queues: queues,
+			next: AtomicUsize::new(0),
+		}
+	}
+
+	/// Lane to the worker next in turn, for a newly connected peer to queue
+	/// all its blocks through.
+	pub fn lane(&self) -> BlockLane {
+		let n = self.next.fetch_add(1, Ordering::Relaxed) % self.queues.len();
+		BlockLane { queue: self.queues[n].clone() }
+	}
+}
+
+/// Queue to the worker handing the blocks of a peer to the adapter.
+pub struct BlockLane {
+	queue: Sender<Job>,
+}
+
+impl BlockLane {
+	/// Queues blocks received together from the peer, resolves once they're
+	/// in the queue.
+	pub fn queue(&self,
+	             blocks: Vec<core::Block>,
+	             remote: Arc<Remote>,
+	             src: SocketAddr,
+	             sender: UnboundedSender<Vec<u8>>)
+	             -> Box<Future<Item = (), Error = Error>> {
+		let job = Job {
+			blocks: blocks,
+			remote: remote,
+			src: src,
+			sender: sender,
+		};
+		Box::new(self.queue.clone().send(job).map(|_| ()).map_err(|_| Error::ConnectionClose))
+	}
+}
+
+// Hands the blocks of a job to the adapter. Should it panic, the worker
+// survives and the peer gets dropped.
+fn receive_job(adapter: &NetAdapter, job: Job) {
+	let (remote, src, sender) = (&job.remote, job.src, &job.sender);
+	for b in job.blocks {
+		let receive = AssertUnwindSafe(|| receive_block(adapter, remote, src, sender, b));
+		match panic::catch_unwind(receive) {
+			Ok(Ok(_)) => {}
+			Ok(Err(e)) => debug!("Failed to reply after receiving a block: {:?}", e),
+			Err(_) => {
+				error!("{} Adapter panicked receiving a block, dropping the peer.",
+				       remote.log_id());
+				remote.adapter_panicked();
+				break;
+			}
+		}
+	}
+}
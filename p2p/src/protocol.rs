@@ -12,52 +12,494 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
 
 use futures;
-use futures::Future;
+use futures::{future, Future};
 use futures::stream;
 use futures::sync::mpsc::UnboundedSender;
-use tokio_core::net::TcpStream;
+use futures::sync::oneshot;
+use time;
 
 use core::core;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed};
+use core::core::target::Difficulty;
 use core::ser;
-use conn::TimeoutConnection;
+use conn::{Pause, TimeoutConnection, Traffic};
 use msg::*;
+use pool::BlockPool;
+use server::LocalStatus;
+use stream::PeerStream;
+use throttle::Throttle;
 use types::*;
 use util::OneTime;
 
+// Number of block hashes remembered as known to the remote peer.
+const KNOWN_BLOCKS_CAP: usize = 500;
+
+// Number of transaction hashes remembered as known to the remote peer.
+const KNOWN_TXS_CAP: usize = 1000;
+
+// Seconds during which a peer asking for our peer info again gets the same
+// answer as the first time.
+const PEER_INFO_INTERVAL_SECS: u64 = 10;
+
+// Number of protocol violations of the remote peer listed, the older ones
+// only being counted.
+const MAX_VIOLATIONS: usize = 16;
+
 pub struct ProtocolV1 {
 	conn: OneTime<TimeoutConnection>,
 
+	// Address of the remote peer, reported as the source of the addresses it
+	// sends us.
+	addr: SocketAddr,
+
 	expected_responses: Mutex<Vec<(Type, Hash)>>,
+
+	// What we learned of the remote peer from its messages.
+	remote: Arc<Remote>,
+
+	// Traffic with the remote peer, shared with its info.
+	stats: Arc<Traffic>,
 }
 
 impl ProtocolV1 {
-	pub fn new() -> ProtocolV1 {
+	/// Protocol with the peer we just completed the handshake with, as
+	/// provided, advertising it our own capabilities and handling what it
+	/// sends per the provided configuration.
+	pub fn new(info: &PeerInfo,
+	           own_capabilities: Capabilities,
+	           config: ProtocolConfig)
+	           -> ProtocolV1 {
+		let mut remote = Remote::new(info.features, info.total_difficulty.clone());
+		remote.version = info.negotiated_version;
+		remote.capabilities = info.capabilities;
+		remote.own_capabilities = own_capabilities;
+		remote.unsolicited_blocks = config.unsolicited_blocks;
+		remote.oversized_addrs = config.oversized_addrs;
+		remote.orphan_blocks = config.orphan_blocks;
+		remote.dumps = config.dumps;
+		remote.magic = config.magic;
+		remote.ban_score = config.ban_score;
+		remote.verified = info.verified.clone();
+		remote.claimed = info.chain_status.clone();
+		remote.log_id = info.log_id.clone();
+		remote.node_addr = info.node_addr();
 		ProtocolV1 {
 			conn: OneTime::new(),
+			addr: info.addr,
 			expected_responses: Mutex::new(vec![]),
+			remote: Arc::new(remote),
+			stats: info.traffic.clone(),
 		}
 	}
 }
 
+/// State of the remote peer as shown by the messages it sends us.
+pub struct Remote {
+	// Orphan blocks the remote peer sent us.
+	orphans: Mutex<u64>,
+	// Highest total difficulty the remote peer showed us in accepted blocks or
+	// headers, and when it last increased.
+	difficulty: Mutex<(Difficulty, Instant)>,
+	// Hash of the block or header the remote peer showed that difficulty with.
+	tip: Mutex<Option<Hash>>,
+	// Optional features negotiated with the remote peer.
+	features: Features,
+	// Protocol version negotiated with the remote peer.
+	version: u32,
+	// Capabilities the remote peer advertised, and those we advertised to it.
+	capabilities: Capabilities,
+	own_capabilities: Capabilities,
+	// Hash of the last header of the previous page of headers the remote peer
+	// sent us, if that page was full and more are expected to follow.
+	last_page: Mutex<Option<Hash>>,
+	// Locator of our latest request for headers, if it asked for the page
+	// following the previous one.
+	page_request: Mutex<Option<Vec<Hash>>>,
+	// Last peer info we sent the remote peer and when.
+	info_answered: Mutex<Option<(Instant, PeerInfoResp)>>,
+	// Whether the adapter panicked on a block the remote peer sent us, while
+	// handed over to the block workers, and the signal dropping the remote
+	// peer right away when it does.
+	adapter_failed: AtomicBool,
+	adapter_failed_tx: Mutex<Option<oneshot::Sender<()>>>,
+	// Identifies the remote peer in our logs.
+	log_id: PeerLogId,
+	// Address of the remote peer with the IP its connection comes from, the
+	// source of the addresses it gossips whatever it advertises.
+	node_addr: SocketAddr,
+	// What to do with the blocks the remote peer pushes to us.
+	unsolicited_blocks: UnsolicitedBlocks,
+	// What to do when the remote peer sends us too many addresses.
+	oversized_addrs: OversizedAddrs,
+	// What to do when the remote peer sends us a block whose parent we don't
+	// know.
+	orphan_blocks: OrphanBlocks,
+	// Whether the messages from the remote peer we can't decode get logged in
+	// hex.
+	dumps: bool,
+	// Magic bytes starting every message we exchange with the remote peer.
+	magic: [u8; 2],
+	// Latest blocks the remote peer sent us or got from us.
+	known_blocks: Mutex<VecDeque<Hash>>,
+	// Latest transactions the remote peer sent or announced us, or got from
+	// us.
+	known_txs: Mutex<VecDeque<Hash>>,
+	// Latest blocks we asked the remote peer for and didn't get yet.
+	requested_blocks: Mutex<VecDeque<Hash>>,
+	// Unsolicited blocks the remote peer pushed in the current minute, and
+	// when that minute started.
+	unsolicited: Mutex<(u32, Instant)>,
+	// Set once the remote peer sends us its first message after the
+	// handshake.
+	verified: Arc<AtomicBool>,
+	// Status of its chain the remote peer claimed in its latest pong.
+	claimed: Arc<Mutex<Option<ChainStatus>>>,
+	// Latest protocol violations of the remote peer, oldest first.
+	violations: Mutex<VecDeque<ViolationRecord>>,
+	// Protocol violations of the remote peer in all.
+	violation_count: AtomicUsize,
+	// Misbehavior score of the remote peer, adding up its violations.
+	score: AtomicUsize,
+	// Score at which the remote peer gets banned, zero for never.
+	ban_score: u32,
+	// Set once we failed to handle a message of the remote peer, the
+	// violation being recorded already.
+	handling_failed: AtomicBool,
+}
+
+impl Remote {
+	// Remote peer with the provided features, starting from the total
+	// difficulty it advertised in the handshake.
+	fn new(features: Features, difficulty: Difficulty) -> Remote {
+		Remote {
+			orphans: Mutex::new(0),
+			difficulty: Mutex::new((difficulty, Instant::now())),
+			tip: Mutex::new(None),
+			features: features,
+			version: PROTOCOL_VERSION,
+			capabilities: UNKNOWN,
+			own_capabilities: UNKNOWN,
+			last_page: Mutex::new(None),
+			page_request: Mutex::new(None),
+			info_answered: Mutex::new(None),
+			adapter_failed: AtomicBool::new(false),
+			adapter_failed_tx: Mutex::new(None),
+			log_id: PeerLogId::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)),
+			node_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+			unsolicited_blocks: UnsolicitedBlocks::Accept,
+			oversized_addrs: OversizedAddrs::Truncate,
+			orphan_blocks: OrphanBlocks::RequestParent,
+			dumps: false,
+			magic: ProtocolConfig::default().magic,
+			known_blocks: Mutex::new(VecDeque::with_capacity(KNOWN_BLOCKS_CAP)),
+			known_txs: Mutex::new(VecDeque::with_capacity(KNOWN_TXS_CAP)),
+			requested_blocks: Mutex::new(VecDeque::new()),
+			unsolicited: Mutex::new((0, Instant::now())),
+			verified: Arc::new(AtomicBool::new(false)),
+			claimed: Arc::new(Mutex::new(None)),
+			violations: Mutex::new(VecDeque::with_capacity(MAX_VIOLATIONS)),
+			violation_count: AtomicUsize::new(0),
+			score: AtomicUsize::new(0),
+			ban_score: 0,
+			handling_failed: AtomicBool::new(false),
+		}
+	}
+
+	// Whether messages of the provided type are part of what we speak with the
+	// remote peer, given the version we negotiated and the capabilities of
+	// the side handling them, ours for those the peer sends.
+	fn speaks(&self, t: Type, incoming: bool) -> bool {
+		let handler = if incoming {
+			self.own_capabilities
+		} else {
+			self.capabilities
+		};
+		t.since_version() <= self.version && handler.contains(t.required_capabilities())
+	}
+
+	// Records a protocol violation the remote peer committed just now.
+	fn violation(&self, v: Violation) {
+		let mut violations = self.violations.lock().unwrap_or_else(|e| e.into_inner());
+		if violations.len() >= MAX_VIOLATIONS {
+			violations.pop_front();
+		}
+		violations.push_back(ViolationRecord {
+			violation: v,
+			at: time::now_utc().to_timespec().sec,
+		});
+		self.violation_count.fetch_add(1, Ordering::Relaxed);
+		self.score.fetch_add(v.score() as usize, Ordering::Relaxed);
+	}
+
+	// Whether the remote peer reached the ban score.
+	fn misbehaving(&self) -> bool {
+		self.ban_score > 0 && self.score.load(Ordering::Relaxed) >= self.ban_score as usize
+	}
+
+	// Whether we asked the remote peer for the block, forgetting about the
+	// request if so.
+	fn requested(&self, h: Hash) -> bool {
+		let mut requested = self.requested_blocks.lock().unwrap_or_else(|e| e.into_inner());
+		match requested.iter().position(|r| *r == h) {
+			Some(i) => {
+				requested.remove(i);
+				true
+			}
+			None => false,
+		}
+	}
+
+	// Counts an unsolicited block pushed now, returning whether it's still
+	// within the provided number per minute.
+	fn unsolicited_allowed(&self, per_minute: u32, now: Instant) -> bool {
+		let mut unsolicited = self.unsolicited.lock().unwrap_or_else(|e| e.into_inner());
+		if now.duration_since(unsolicited.1) >= Duration::from_secs(60) {
+			*unsolicited = (0, now);
+		}
+		unsolicited.0 += 1;
+		unsolicited.0 <= per_minute
+	}
+
+	/// Records the adapter panicked on a message of the remote peer, which
+	/// gets disconnected right away.
+	pub fn adapter_panicked(&self) {
+		self.adapter_failed.store(true, Ordering::Relaxed);
+		let failed = self.adapter_failed_tx.lock().unwrap_or_else(|e| e.into_inner()).take();
+		if let Some(failed) = failed {
+			let _ = failed.send(());
+		}
+	}
+
+	/// Identifies the remote peer in our logs.
+	pub fn log_id(&self) -> &PeerLogId {
+		&self.log_id
+	}
+
+	// Peer info to answer a request received now with. Requests coming in
+	// too fast get the previous answer again instead of a fresh one, which
+	// still spares the remote peer a timeout.
+	fn peer_info<F>(&self, now: Instant, fresh: F) -> PeerInfoResp
+		where F: FnOnce() -> PeerInfoResp
+	{
+		let mut answered = self.info_answered.lock().unwrap_or_else(|e| e.into_inner());
+		let interval = Duration::from_secs(PEER_INFO_INTERVAL_SECS);
+		let previous = match *answered {
+			Some((t, ref info)) if now.duration_since(t) < interval => Some(info.clone()),
+			_ => None,
+		};
+		if let Some(info) = previous {
+			debug!("Peer info asked again too soon, sending the previous answer.");
+			return info;
+		}
+		let info = fresh();
+		*answered = Some((now, info.clone()));
+		info
+	}
+
+	// Records we're asking the remote peer for headers from the provided
+	// locator, those following its previous page if the locator starts from
+	// its end.
+	fn page_requested(&self, locator: &[Hash]) {
+		let last_page = self.last_page.lock().unwrap_or_else(|e| e.into_inner());
+		let continues = last_page.is_some() && locator.first() == last_page.as_ref();
+		*self.page_request.lock().unwrap_or_else(|e| e.into_inner()) = if continues {
+			Some(locator.to_vec())
+		} else {
+			None
+		};
+	}
+
+	// Checks a page of headers asked as the one following the previous full
+	// page picks up where that one left off, so a peer can't splice several
+	// forks together over multiple pages. Pages asked from anywhere else are
+	// fresh starts, as is any page after a break.
+	fn headers_page(&self, headers: &[core::BlockHeader]) -> PageCheck {
+		let mut last_page = self.last_page.lock().unwrap_or_else(|e| e.into_inner());
+		let request = self.page_request.lock().unwrap_or_else(|e| e.into_inner()).take();
+		let check = match (request, headers.first()) {
+			(Some(ref locator), Some(first)) if first.previous != locator[0] => {
+				if locator.contains(&first.previous) {
+					PageCheck::Forked
+				} else {
+					PageCheck::Unrelated
+				}
+			}
+			_ => PageCheck::Continues,
+		};
+		*last_page = if check == PageCheck::Continues &&
+		                headers.len() == MAX_BLOCK_HEADERS as usize {
+			headers.last().map(|h| h.hash())
+		} else {
+			None
+		};
+		check
+	}
+
+	// Checks the total difficulty the remote peer claimed in its latest pong
+	// against the headers it sent, once those reached the tip of its chain.
+	// A claim they fall short of was a lie, dropped and held against it.
+	fn claim_backed(&self) {
+		let mut claimed = self.claimed.lock().unwrap_or_else(|e| e.into_inner());
+		let shown = self.difficulty.lock().unwrap_or_else(|e| e.into_inner()).0.clone();
+		let unbacked = match *claimed {
+			Some(ref status) => status.total_difficulty > shown,
+			None => false,
+		};
+		if unbacked {
+			info!("{} Headers fell short of the total difficulty claimed, dropping it.",
+			      self.log_id);
+			*claimed = None;
+			self.violation(Violation::UnbackedDifficulty);
+		}
+	}
+
+	// Records a block or header the remote peer showed us, its tip if its
+	// total difficulty is the highest yet.
+	fn tip_seen(&self, h: Hash, diff: &Difficulty) {
+		let mut difficulty = self.difficulty.lock().unwrap_or_else(|e| e.into_inner());
+		if *diff > difficulty.0 {
+			*difficulty = (diff.clone(), Instant::now());
+			*self.tip.lock().unwrap_or_else(|e| e.into_inner()) = Some(h);
+		}
+	}
+}
+
+// How a page of headers from the remote peer relates to what we asked for,
+// see Remote::headers_page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PageCheck {
+	// The first page, a fresh start or the one following the previous page.
+	Continues,
+	// Asked as the page following the previous one, it picks up from further
+	// back in the locator.
+	Forked,
+	// Asked as the page following the previous one, it connects to nothing
+	// in the locator.
+	Unrelated,
+}
+
 impl Protocol for ProtocolV1 {
 	/// Sets up the protocol reading, writing and closing logic.
 	fn handle(&self,
-	          conn: TcpStream,
-	          adapter: Arc<NetAdapter>)
+	          conn: PeerStream,
+	          adapter: Arc<NetAdapter>,
+	          throttle: Throttle,
+	          blocks: Option<Arc<BlockPool>>,
+	          traffic: Arc<Traffic>,
+	          local: Arc<LocalStatus>)
 	          -> Box<Future<Item = (), Error = Error>> {
 
-		let (conn, listener) = TimeoutConnection::listen(conn, move |sender, header, data| {
-			let adapt = adapter.as_ref();
-			handle_payload(adapt, sender, header, data)
-		});
+		let remote = self.remote.clone();
+		let addr = self.addr;
+		// all the blocks of the peer go through the same worker, in order
+		let blocks = blocks.map(|pool| pool.lane());
+		let handler = move |sender: UnboundedSender<Vec<u8>>,
+		                    header: MsgHeader,
+		                    data: Vec<u8>|
+		                    -> Result<Option<Pause>, ser::Error> {
+			let msg_type = header.msg_type;
+			if remote.adapter_failed.load(Ordering::Relaxed) {
+				return Ok(Some(Box::new(future::err(Error::AdapterPanic))));
+			}
+			// blocks handed to the workers may have got the peer there since
+			if remote.misbehaving() {
+				return Ok(Some(Box::new(future::err(Error::Misbehaving))));
+			}
+			remote.verified.store(true, Ordering::Relaxed);
+			let committed = remote.violation_count.load(Ordering::Relaxed);
+			// a panicking adapter only takes this peer down, failing its
+			// connection instead of unwinding through the event loop
+			let res = panic::catch_unwind(AssertUnwindSafe(|| -> Result<Option<Pause>, ser::Error> {
+				let adapt = adapter.as_ref();
+				if let Some(ref lane) = blocks {
+					if let Some(received) = read_blocks(&header, &data)? {
+						for b in &received {
+							add_known(&remote.known_blocks, b.hash(), KNOWN_BLOCKS_CAP);
+						}
+						let admitted = screen_blocks(adapt, &remote, &sender, received)?;
+						return Ok(Some(lane.queue(admitted, remote.clone(), addr, sender)));
+					}
+				}
+				if header.msg_type == Type::GetPeerInfo {
+					let id = header.id;
+					return reply_peer_info(adapt, &remote, &local, &sender, id).map(|_| None);
+				}
+				let res = handle_payload(adapt, &remote, addr, sender, header, data);
+				if let Ok(Some(h)) = res {
+					add_known(&remote.known_blocks, h, KNOWN_BLOCKS_CAP);
+				}
+				res.map(|_| None)
+			}));
+			match res {
+				Ok(Err(e)) => {
+					// unless handling the message recorded something more precise
+					if remote.violation_count.load(Ordering::Relaxed) == committed {
+						remote.violation(violation_of(&e));
+					}
+					remote.handling_failed.store(true, Ordering::Relaxed);
+					Err(e)
+				}
+				Ok(_) if remote.misbehaving() => {
+					info!("{} Reached the ban score with a {:?} message, disconnecting.",
+					      remote.log_id,
+					      msg_type);
+					Ok(Some(Box::new(future::err(Error::Misbehaving))))
+				}
+				Ok(res) => res,
+				Err(_) => {
+					error!("{} Adapter panicked handling a {:?} message, disconnecting.",
+					       remote.log_id,
+					       msg_type);
+					Ok(Some(Box::new(future::err(Error::AdapterPanic))))
+				}
+			}
+		};
+		let checksums = self.remote.features.contains(CHECKSUMS);
+		let dumps = self.remote.dumps;
+		let magic = self.remote.magic;
+		let (conn, listener) = TimeoutConnection::listen(conn,
+		                                                 magic,
+		                                                 throttle,
+		                                                 self.stats.clone(),
+		                                                 traffic,
+		                                                 checksums,
+		                                                 dumps,
+		                                                 handler);
 
 		self.conn.init(conn);
 
-		listener
+		// the block workers drop the peer as soon as the adapter panics on one
+		// of its blocks, rather than on its next message
+		let (failed_tx, failed_rx) = oneshot::channel();
+		*self.remote.adapter_failed_tx.lock().unwrap_or_else(|e| e.into_inner()) = Some(failed_tx);
+		let failed = failed_rx.then(|res| -> Box<Future<Item = (), Error = Error>> {
+			match res {
+				Ok(()) => Box::new(future::err(Error::AdapterPanic)),
+				Err(_) => Box::new(future::empty()),
+			}
+		});
+		let listener = listener.select(failed).map(|_| ()).map_err(|(e, _)| e);
+
+		// messages we couldn't even read are violations as well
+		let remote = self.remote.clone();
+		Box::new(listener.map_err(move |e| {
+			match e {
+				Error::Checksum => remote.violation(Violation::BadChecksum),
+				Error::Serialization(ref e) if !remote.handling_failed.load(Ordering::Relaxed) => {
+					remote.violation(violation_of(e))
+				}
+				_ => {}
+			}
+			e
+		}))
 	}
 
 	/// Bytes sent and received.
@@ -65,98 +507,354 @@ impl Protocol for ProtocolV1 {
 		self.conn.borrow().transmitted_bytes()
 	}
 
+	/// Messages sent and received.
+	fn transmitted_msgs(&self) -> (u64, u64) {
+		self.conn.borrow().transmitted_msgs()
+	}
+
+	/// Orphan blocks received.
+	fn orphan_count(&self) -> u64 {
+		*self.remote.orphans.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn reset_stats(&self) {
+		self.conn.borrow().reset_stats();
+		*self.remote.orphans.lock().unwrap_or_else(|e| e.into_inner()) = 0;
+	}
+
+	fn violations(&self) -> Vec<ViolationRecord> {
+		self.remote.violations.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+	}
+
+	fn violation_count(&self) -> u64 {
+		self.remote.violation_count.load(Ordering::Relaxed) as u64
+	}
+
+	fn misbehavior_score(&self) -> u32 {
+		self.remote.score.load(Ordering::Relaxed) as u32
+	}
+
+	/// Highest total difficulty seen, zero until the remote peer sends us an
+	/// accepted block or headers.
+	fn total_difficulty(&self) -> (Difficulty, Instant) {
+		self.remote.difficulty.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+
+	fn tip(&self) -> Option<(Hash, Difficulty)> {
+		let difficulty = self.remote.difficulty.lock().unwrap_or_else(|e| e.into_inner());
+		self.remote.tip.lock().unwrap_or_else(|e| e.into_inner()).map(|h| (h, difficulty.0.clone()))
+	}
+
+	fn knows_block(&self, h: Hash) -> bool {
+		self.remote.known_blocks.lock().unwrap_or_else(|e| e.into_inner()).contains(&h)
+	}
+
+	fn knows_transaction(&self, h: Hash) -> bool {
+		self.remote.known_txs.lock().unwrap_or_else(|e| e.into_inner()).contains(&h)
+	}
+
 	/// Sends a ping message to the remote peer. Will panic if handle has never
 	/// been called on this protocol.
 	fn send_ping(&self) -> Result<(), Error> {
-		self.send_request(Type::Ping, Type::Pong, &Empty {}, None)
+		self.send_request(Type::Ping, Type::Pong, &Empty {})
+	}
+
+	fn ping(&self, status: ChainStatus) -> Result<PingFuture, Error> {
+		let sent = Instant::now();
+		// our status only goes to peers speaking a version knowing of it
+		let resp = if self.remote.version >= PING_STATUS_VERSION {
+			self.request(Type::Ping, Type::Pong, &status)?
+		} else {
+			self.request(Type::Ping, Type::Pong, &Empty {})?
+		};
+		Ok(Box::new(resp.and_then(move |body| -> Result<(Duration, Option<ChainStatus>), Error> {
+			let rtt = sent.elapsed();
+			// peers predating ChainStatus answer with an empty pong
+			if body.is_empty() {
+				return Ok((rtt, None));
+			}
+			let status = ser::deserialize::<ChainStatus>(&mut &body[..])?;
+			Ok((rtt, Some(status)))
+		})))
 	}
 
 	/// Serializes and sends a block to our remote peer
 	fn send_block(&self, b: &core::Block) -> Result<(), Error> {
-		self.send_msg(Type::Block, b)
+		self.send_msg(Type::Block, b)?;
+		add_known(&self.remote.known_blocks, b.hash(), KNOWN_BLOCKS_CAP);
+		Ok(())
+	}
+
+	/// Sends a block to our remote peer without its pool transactions
+	fn send_compact_block(&self, b: &core::Block) -> Result<(), Error> {
+		self.send_msg(Type::CompactBlock, &Compacted(b))?;
+		add_known(&self.remote.known_blocks, b.hash(), KNOWN_BLOCKS_CAP);
+		Ok(())
 	}
 
 	/// Serializes and sends a transaction to our remote peer
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
-		self.send_msg(Type::Transaction, tx)
+		self.send_msg(Type::Transaction, tx)?;
+		add_known(&self.remote.known_txs, tx.hash(), KNOWN_TXS_CAP);
+		Ok(())
+	}
+
+	/// Passes a transaction on to our remote peer along the dandelion stem
+	fn send_stem_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
+		self.send_msg(Type::StemTransaction, tx)?;
+		add_known(&self.remote.known_txs, tx.hash(), KNOWN_TXS_CAP);
+		Ok(())
+	}
+
+	/// Announces a transaction hash to our remote peer
+	fn send_transaction_inv(&self, h: Hash) -> Result<(), Error> {
+		self.send_msg(Type::Inv,
+		              &Inventory {
+			              inv_type: InvType::Transaction,
+			              hashes: vec![h],
+		              })?;
+		add_known(&self.remote.known_txs, h, KNOWN_TXS_CAP);
+		Ok(())
+	}
+
+	/// Announces a block hash to our remote peer
+	fn send_block_inv(&self, h: Hash) -> Result<(), Error> {
+		self.send_msg(Type::Inv,
+		              &Inventory {
+			              inv_type: InvType::Block,
+			              hashes: vec![h],
+		              })
+	}
+
+	/// Announces a block header to our remote peer, unasked
+	fn send_header(&self, bh: &core::BlockHeader) -> Result<(), Error> {
+		self.send_msg(Type::Headers, &HeaderAnnouncement(bh))?;
+		add_known(&self.remote.known_blocks, bh.hash(), KNOWN_BLOCKS_CAP);
+		Ok(())
 	}
 
 	fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
-		self.send_request(Type::GetHeaders,
-		                  Type::Headers,
-		                  &Locator { hashes: locator },
-		                  None)
+		self.remote.page_requested(&locator);
+		self.send_request(Type::GetHeaders, Type::Headers, &Locator { hashes: locator })
 	}
 
 	fn send_block_request(&self, h: Hash) -> Result<(), Error> {
-		self.send_request(Type::GetBlock, Type::Block, &h, Some(h))
+		self.send_request(Type::GetBlock, Type::Block, &h)?;
+		add_known(&self.remote.requested_blocks, h, KNOWN_BLOCKS_CAP);
+		Ok(())
+	}
+
+	fn blocks_in_flight(&self) -> usize {
+		self.conn.borrow().pending_requests(Type::Block)
 	}
 
 	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error> {
 		self.send_request(Type::GetPeerAddrs,
 		                  Type::PeerAddrs,
-		                  &GetPeerAddrs { capabilities: capab },
-		                  None)
+		                  &GetPeerAddrs { capabilities: capab })
+	}
+
+	fn send_peer_info_request(&self)
+	                          -> Result<Box<Future<Item = PeerInfoResp, Error = Error>>, Error> {
+		let resp = self.request(Type::GetPeerInfo, Type::PeerInfoResp, &Empty {})?;
+		Ok(Box::new(resp.and_then(|body| {
+			ser::deserialize::<PeerInfoResp>(&mut &body[..]).map_err(Error::Serialization)
+		})))
+	}
+
+	fn send_checkpoints_request(&self)
+		-> Result<Box<Future<Item = Vec<Checkpoint>, Error = Error>>, Error> {
+		let resp = self.request(Type::GetCheckpoints, Type::Checkpoints, &Empty {})?;
+		Ok(Box::new(resp.and_then(|body| {
+			ser::deserialize::<Checkpoints>(&mut &body[..])
+				.map(|cps| cps.checkpoints)
+				.map_err(Error::Serialization)
+		})))
+	}
+
+	fn send_utxo_chunk_request(&self,
+	                           horizon: Hash,
+	                           chunk: u32)
+	                           -> Result<Box<Future<Item = UtxoChunk, Error = Error>>, Error> {
+		let req = GetUtxoChunk {
+			horizon: horizon,
+			chunk: chunk,
+		};
+		let resp = self.request(Type::GetUtxoChunk, Type::UtxoChunk, &req)?;
+		Ok(Box::new(resp.and_then(|body| {
+			ser::deserialize::<UtxoChunk>(&mut &body[..]).map_err(Error::Serialization)
+		})))
 	}
 
 	/// Close the connection to the remote peer
 	fn close(&self) {
-		// TODO some kind of shutdown signal
+		self.conn.borrow().close()
+	}
+
+	fn close_gracefully(&self, timeout: Duration) {
+		self.conn.borrow().close_gracefully(timeout)
 	}
 }
 
 impl ProtocolV1 {
 	fn send_msg<W: ser::Writeable>(&self, t: Type, body: &W) -> Result<(), Error> {
+		if !self.remote.speaks(t, false) {
+			return Err(Error::UnsupportedMessage(t));
+		}
 		self.conn.borrow().send_msg(t, body)
 	}
 
-	fn send_request<W: ser::Writeable>(&self,
-	                t: Type,
-	                rt: Type,
-	                body: &W,
-	                expect_resp: Option<Hash>)
-	                -> Result<(), Error> {
-		self.conn.borrow().send_request(t, rt, body, expect_resp)
+	fn send_request<W: ser::Writeable>(&self, t: Type, rt: Type, body: &W) -> Result<(), Error> {
+		if !self.remote.speaks(t, false) {
+			return Err(Error::UnsupportedMessage(t));
+		}
+		self.conn.borrow().send_request(t, rt, body)
+	}
+
+	fn request<W: ser::Writeable>(&self,
+	                              t: Type,
+	                              rt: Type,
+	                              body: &W)
+	                              -> Result<Box<Future<Item = Vec<u8>, Error = Error>>, Error> {
+		if !self.remote.speaks(t, false) {
+			return Err(Error::UnsupportedMessage(t));
+		}
+		self.conn.borrow().request(t, rt, body)
 	}
 }
 
+// Hands headers the remote peer announced new blocks with to the adapter,
+// asking the peer for the blocks we don't have. Returns the hash of the last
+// one, which the peer has.
+fn receive_announced(adapter: &NetAdapter,
+                     remote: &Remote,
+                     sender: &UnboundedSender<Vec<u8>>,
+                     headers: Vec<core::BlockHeader>)
+                     -> Result<Option<Hash>, ser::Error> {
+	let last = match headers.last() {
+		Some(bh) => {
+			let h = bh.hash();
+			remote.tip_seen(h, &bh.total_difficulty);
+			h
+		}
+		None => return Ok(None),
+	};
+	let missing = headers.iter()
+		.map(|bh| bh.hash())
+		.filter(|h| !adapter.has_block(*h))
+		.collect::<Vec<_>>();
+	adapter.headers_received(headers);
+	if missing.len() > 0 {
+		for h in &missing {
+			add_known(&remote.requested_blocks, *h, KNOWN_BLOCKS_CAP);
+		}
+		try!(send_reply(sender,
+		                remote.magic,
+		                Type::GetData,
+		                0,
+		                &Inventory {
+			                inv_type: InvType::Block,
+			                hashes: missing,
+		                }));
+	}
+	Ok(Some(last))
+}
+
 fn handle_payload(adapter: &NetAdapter,
+                  remote: &Remote,
+                  src: SocketAddr,
                   sender: UnboundedSender<Vec<u8>>,
                   header: MsgHeader,
                   buf: Vec<u8>)
                   -> Result<Option<Hash>, ser::Error> {
+	if !remote.speaks(header.msg_type, true) {
+		debug!("{} Ignoring {:?}, not part of what we speak with the peer.",
+		       remote.log_id,
+		       header.msg_type);
+		// so the peer doesn't wait for a response until it times out
+		if header.msg_type.is_request() && header.id != 0 {
+			let err = PeerError {
+				code: ErrCodes::UnsupportedMessage as u32,
+				message: format!("unsupported {:?}", header.msg_type),
+			};
+			try!(send_reply(&sender, remote.magic, Type::Error, header.id, &err));
+		}
+		return Ok(None);
+	}
 	match header.msg_type {
 		Type::Ping => {
-			let data = ser::ser_vec(&MsgHeader::new(Type::Pong, 0))?;
-			sender.send(data);
+			// only answer with our status to peers sending theirs, the others
+			// expect an empty pong
+			if buf.is_empty() {
+				let pong = MsgHeader::with_id(remote.magic, Type::Pong, 0, header.id);
+				sender.send(ser::ser_vec(&pong)?);
+				return Ok(None);
+			}
+			ser::deserialize::<ChainStatus>(&mut &buf[..])?;
+			let status = ChainStatus {
+				total_difficulty: adapter.total_difficulty(),
+				height: adapter.head_height(),
+			};
+			try!(send_reply(&sender, remote.magic, Type::Pong, header.id, &status));
 			Ok(None)
 		}
-		Type::Pong => Ok(None),
+		Type::Pong | Type::PeerInfoResp | Type::Checkpoints | Type::UtxoChunk => Ok(None),
 		Type::Transaction => {
 			let tx = ser::deserialize::<core::Transaction>(&mut &buf[..])?;
+			add_known(&remote.known_txs, tx.hash(), KNOWN_TXS_CAP);
 			adapter.transaction_received(tx);
 			Ok(None)
 		}
+		Type::StemTransaction => {
+			let tx = ser::deserialize::<core::Transaction>(&mut &buf[..])?;
+			if !remote.features.contains(DANDELION) {
+				debug!("Ignoring stem transaction, not negotiated.");
+				return Ok(None);
+			}
+			add_known(&remote.known_txs, tx.hash(), KNOWN_TXS_CAP);
+			adapter.stem_transaction_received(tx);
+			Ok(None)
+		}
 		Type::GetBlock => {
 			let h = ser::deserialize::<Hash>(&mut &buf[..])?;
 			let bo = adapter.get_block(h);
 			if let Some(b) = bo {
 				// serialize and send the block over
-				let mut body_data = vec![];
-				try!(ser::serialize(&mut body_data, &b));
-				let mut data = vec![];
-				try!(ser::serialize(&mut data,
-				                    &MsgHeader::new(Type::Block, body_data.len() as u64)));
-				data.append(&mut body_data);
-				sender.send(data);
+				try!(send_reply(&sender, remote.magic, Type::Block, header.id, &b));
 			}
 			Ok(None)
 		}
-		Type::Block => {
-			let b = ser::deserialize::<core::Block>(&mut &buf[..])?;
-			let bh = b.hash();
-			adapter.block_received(b);
-			Ok(Some(bh))
+		Type::Block | Type::Blocks => {
+			// consumed in the order they were sent, so parents come first
+			let blocks = read_blocks(&header, &buf)?.unwrap_or(vec![]);
+			let mut last = None;
+			for b in screen_blocks(adapter, remote, &sender, blocks)? {
+				last = Some(receive_block(adapter, remote, src, &sender, b)?);
+			}
+			Ok(last)
+		}
+		Type::CompactBlock => {
+			let cb = ser::deserialize::<CompactBlock>(&mut &buf[..])?;
+			let h = cb.header.hash();
+			if adapter.has_block(h) {
+				return Ok(None);
+			}
+			match cb.reconstruct(|k| adapter.get_kernel_transaction(k)) {
+				Some(b) => {
+					let mut last = None;
+					for b in screen_blocks(adapter, remote, &sender, vec![b])? {
+						last = Some(receive_block(adapter, remote, src, &sender, b)?);
+					}
+					Ok(last)
+				}
+				None => {
+					// missing some of its transactions, the full block it is
+					debug!("Could not rebuild compact block {}, asking for it in full.", h);
+					add_known(&remote.requested_blocks, h, KNOWN_BLOCKS_CAP);
+					try!(send_reply(&sender, remote.magic, Type::GetBlock, 0, &h));
+					Ok(None)
+				}
+			}
 		}
 		Type::GetHeaders => {
 			// load headers from the locator
@@ -164,42 +862,212 @@ fn handle_payload(adapter: &NetAdapter,
 			let headers = adapter.locate_headers(loc.hashes);
 
 			// serialize and send all the headers over
-			let mut body_data = vec![];
-			try!(ser::serialize(&mut body_data, &Headers { headers: headers }));
-			let mut data = vec![];
-			try!(ser::serialize(&mut data,
-			                    &MsgHeader::new(Type::Headers, body_data.len() as u64)));
-			data.append(&mut body_data);
-			sender.send(data);
+			try!(send_reply(&sender,
+			                remote.magic,
+			                Type::Headers,
+			                header.id,
+			                &Headers { headers: headers }));
 
 			Ok(None)
 		}
 		Type::Headers => {
 			let headers = ser::deserialize::<Headers>(&mut &buf[..])?;
+			// a batch that doesn't chain is junk, erroring out gets the peer banned
+			if !headers_connected(&headers.headers) {
+				debug!("Received a batch of {} disconnected headers, rejecting.",
+				       headers.headers.len());
+				remote.violation(Violation::BrokenHeaderChain);
+				return Err(ser::Error::CorruptedData);
+			}
+			// headers we didn't ask for announce new blocks, they're no page
+			if header.id == 0 {
+				return receive_announced(adapter, remote, &sender, headers.headers);
+			}
+			// the next request, to this peer or another, starts over from our
+			// own header chain. A page picking up from further back in what we
+			// asked from may be an honest peer's reorg, one connecting to none
+			// of it isn't an answer at all.
+			match remote.headers_page(&headers.headers) {
+				PageCheck::Continues => {}
+				PageCheck::Forked => {
+					info!("{} Received headers forking off the previous page, dropping them.",
+					      remote.log_id);
+					return Ok(None);
+				}
+				PageCheck::Unrelated => {
+					info!("{} Received headers not following what we asked, dropping them.",
+					      remote.log_id);
+					remote.violation(Violation::HeadersPageBreak);
+					return Ok(None);
+				}
+			}
+			if let Some(last) = headers.headers.last() {
+				remote.tip_seen(last.hash(), &last.total_difficulty);
+			}
+			// short of a full page, the last header is the tip of the peer
+			let count = headers.headers.len();
+			if count > 0 && count < MAX_BLOCK_HEADERS as usize {
+				remote.claim_backed();
+			}
 			adapter.headers_received(headers.headers);
 			Ok(None)
 		}
+		Type::GetCheckpoints => {
+			let mut checkpoints = adapter.checkpoints();
+			checkpoints.truncate(MAX_CHECKPOINTS as usize);
+			try!(send_reply(&sender,
+			                remote.magic,
+			                Type::Checkpoints,
+			                header.id,
+			                &Checkpoints { checkpoints: checkpoints }));
+			Ok(None)
+		}
+		Type::GetUtxoChunk => {
+			let req = ser::deserialize::<GetUtxoChunk>(&mut &buf[..])?;
+			let (chunks, mut outputs) = adapter.utxo_chunk(req.horizon, req.chunk)
+				.unwrap_or((0, vec![]));
+			outputs.truncate(MAX_UTXO_CHUNK as usize);
+			try!(send_reply(&sender,
+			                remote.magic,
+			                Type::UtxoChunk,
+			                header.id,
+			                &UtxoChunk {
+				                horizon: req.horizon,
+				                chunk: req.chunk,
+				                chunks: chunks,
+				                outputs: outputs,
+			                }));
+			Ok(None)
+		}
 		Type::GetPeerAddrs => {
 			let get_peers = ser::deserialize::<GetPeerAddrs>(&mut &buf[..])?;
 			let peer_addrs = adapter.find_peer_addrs(get_peers.capabilities);
 
 			// serialize and send all the headers over
-			let mut body_data = vec![];
-			try!(ser::serialize(&mut body_data,
-			                    &PeerAddrs {
-				                    peers: peer_addrs.iter().map(|sa| SockAddr(*sa)).collect(),
-			                    }));
-			let mut data = vec![];
-			try!(ser::serialize(&mut data,
-			                    &MsgHeader::new(Type::PeerAddrs, body_data.len() as u64)));
-			data.append(&mut body_data);
-			sender.send(data);
+			try!(send_reply(&sender,
+			                remote.magic,
+			                Type::PeerAddrs,
+			                header.id,
+			                &PeerAddrs {
+				                peers: peer_addrs.iter().map(|sa| SockAddr(*sa)).collect(),
+			                }));
 
 			Ok(None)
 		}
 		Type::PeerAddrs => {
-			let peer_addrs = ser::deserialize::<PeerAddrs>(&mut &buf[..])?;
-			adapter.peer_addrs_received(peer_addrs.peers.iter().map(|pa| pa.0).collect());
+			// too many addresses fail the strict read, getting the peer banned
+			let peer_addrs = match remote.oversized_addrs {
+				OversizedAddrs::Disconnect => ser::deserialize::<PeerAddrs>(&mut &buf[..])?.peers,
+				OversizedAddrs::Truncate => {
+					let addrs = ser::deserialize::<TruncatedPeerAddrs>(&mut &buf[..])?;
+					if addrs.sent > MAX_PEER_ADDRS {
+						debug!("Keeping {} of the {} peer addresses from {}.",
+						       addrs.peers.len(),
+						       addrs.sent,
+						       src);
+						remote.violation(Violation::OversizedAddrs);
+					}
+					addrs.peers
+				}
+			};
+			adapter.peer_addrs_received(peer_addrs.iter().map(|pa| pa.0).collect(),
+			                            remote.node_addr);
+			Ok(None)
+		}
+		Type::Inv => {
+			let inv = ser::deserialize::<Inventory>(&mut &buf[..])?;
+			if inv.inv_type == InvType::Transaction && !remote.features.contains(TX_INV) {
+				debug!("Ignoring transaction inventory, not negotiated.");
+				return Ok(None);
+			}
+			if inv.inv_type == InvType::Transaction {
+				// no point announcing those back to the peer
+				for h in &inv.hashes {
+					add_known(&remote.known_txs, *h, KNOWN_TXS_CAP);
+				}
+			}
+			let missing = match inv.inv_type {
+				InvType::Block => {
+					let missing = inv.hashes
+						.into_iter()
+						.filter(|h| !adapter.has_block(*h))
+						.collect::<Vec<_>>();
+					for h in &missing {
+						add_known(&remote.requested_blocks, *h, KNOWN_BLOCKS_CAP);
+					}
+					missing
+				}
+				InvType::Transaction => {
+					inv.hashes
+						.into_iter()
+						.filter(|h| adapter.get_transaction(*h).is_none())
+						.collect::<Vec<_>>()
+				}
+			};
+			// only ask for what we don't already have
+			if missing.len() > 0 {
+				try!(send_reply(&sender,
+				                remote.magic,
+				                Type::GetData,
+				                0,
+				                &Inventory {
+					                inv_type: inv.inv_type,
+					                hashes: missing,
+				                }));
+			}
+			Ok(None)
+		}
+		Type::GetData => {
+			let inv = ser::deserialize::<Inventory>(&mut &buf[..])?;
+			match inv.inv_type {
+				InvType::Block if !remote.features.contains(BLOCK_BATCHES) => {
+					for h in inv.hashes {
+						if let Some(b) = adapter.get_block(h) {
+							try!(send_reply(&sender, remote.magic, Type::Block, header.id, &b));
+						}
+					}
+				}
+				InvType::Block => {
+					// stream the blocks back-to-back, starting a new response whenever
+					// the current one would get too large
+					let mut blocks = vec![];
+					let mut size = 0;
+					for h in inv.hashes {
+						if let Some(b) = adapter.get_block(h) {
+							let b_size = ser::ser_vec(&b)?.len();
+							if !blocks.is_empty() && size + b_size > MAX_BLOCKS_RESPONSE_BYTES {
+								let full = Blocks { blocks: blocks };
+								try!(send_reply(&sender,
+								                remote.magic,
+								                Type::Blocks,
+								                header.id,
+								                &full));
+								blocks = vec![];
+								size = 0;
+							}
+							blocks.push(b);
+							size += b_size;
+						}
+					}
+					if !blocks.is_empty() {
+						let last = Blocks { blocks: blocks };
+						try!(send_reply(&sender, remote.magic, Type::Blocks, header.id, &last));
+					}
+				}
+				InvType::Transaction => {
+					// transactions are small, each goes back as a regular transaction
+					// message the requester handles as if it had been broadcast
+					for h in inv.hashes {
+						if let Some(tx) = adapter.get_transaction(h) {
+							try!(send_reply(&sender,
+							                remote.magic,
+							                Type::Transaction,
+							                header.id,
+							                &tx));
+						}
+					}
+				}
+			}
 			Ok(None)
 		}
 		_ => {
@@ -208,3 +1076,870 @@ fn handle_payload(adapter: &NetAdapter,
 		}
 	}
 }
+
+// Answers a peer info request with what we know of ourselves.
+fn reply_peer_info(adapter: &NetAdapter,
+                   remote: &Remote,
+                   local: &LocalStatus,
+                   sender: &UnboundedSender<Vec<u8>>,
+                   id: u32)
+                   -> Result<(), ser::Error> {
+	let info = remote.peer_info(Instant::now(), || {
+		PeerInfoResp {
+			tip: adapter.head_hash(),
+			total_difficulty: adapter.total_difficulty(),
+			peer_count: local.peer_count(),
+			uptime: local.uptime(),
+		}
+	});
+	send_reply(sender, remote.magic, Type::PeerInfoResp, id, &info)
+}
+
+// Remembers a block or transaction as known to the remote peer, forgetting
+// the oldest one when at the provided cap.
+fn add_known(known_hashes: &Mutex<VecDeque<Hash>>, h: Hash, cap: usize) {
+	let mut known = known_hashes.lock().unwrap_or_else(|e| e.into_inner());
+	if !known.contains(&h) {
+		if known.len() >= cap {
+			known.pop_front();
+		}
+		known.push_back(h);
+	}
+}
+
+// Blocks carried by a Block or Blocks message, none for other messages.
+fn read_blocks(header: &MsgHeader, buf: &[u8]) -> Result<Option<Vec<core::Block>>, ser::Error> {
+	match header.msg_type {
+		Type::Block => Ok(Some(vec![ser::deserialize::<core::Block>(&mut &buf[..])?])),
+		Type::Blocks => Ok(Some(ser::deserialize::<Blocks>(&mut &buf[..])?.blocks)),
+		_ => Ok(None),
+	}
+}
+
+// Keeps the blocks we asked the remote peer for, applying the configured
+// policy to those it pushed unsolicited.
+fn screen_blocks(adapter: &NetAdapter,
+                 remote: &Remote,
+                 sender: &UnboundedSender<Vec<u8>>,
+                 blocks: Vec<core::Block>)
+                 -> Result<Vec<core::Block>, ser::Error> {
+	let mut admitted = vec![];
+	for b in blocks {
+		let h = b.hash();
+		if remote.requested(h) {
+			admitted.push(b);
+			continue;
+		}
+		match remote.unsolicited_blocks {
+			UnsolicitedBlocks::Accept => admitted.push(b),
+			UnsolicitedBlocks::RateLimited(per_minute) => {
+				if remote.unsolicited_allowed(per_minute, Instant::now()) {
+					admitted.push(b);
+				} else {
+					debug!("Dropping unsolicited block {}, over {} per minute.", h, per_minute);
+					remote.violation(Violation::UnsolicitedFlood);
+				}
+			}
+			UnsolicitedBlocks::HeadersFirst => {
+				adapter.headers_received(vec![b.header]);
+				if !adapter.has_block(h) {
+					debug!("Unsolicited block {}, asking for it after its header.", h);
+					add_known(&remote.requested_blocks, h, KNOWN_BLOCKS_CAP);
+					try!(send_reply(sender, remote.magic, Type::GetBlock, 0, &h));
+				}
+			}
+		}
+	}
+	Ok(admitted)
+}
+
+// Violation committed by a peer sending a message we fail to read or handle
+// with the provided error.
+fn violation_of(e: &ser::Error) -> Violation {
+	match *e {
+		ser::Error::TooLargeReadErr => Violation::Oversized,
+		_ => Violation::Undecodable,
+	}
+}
+
+/// Hands a received block to the adapter. An orphan gets counted and, per
+/// the orphan policy, the block the adapter misses to connect it or the
+/// headers following our head are requested from the sender. An invalid
+/// block is a violation.
+pub fn receive_block(adapter: &NetAdapter,
+                     remote: &Remote,
+                     src: SocketAddr,
+                     sender: &UnboundedSender<Vec<u8>>,
+                     b: core::Block)
+                     -> Result<Hash, ser::Error> {
+	let bh = b.hash();
+	let prev = b.header.previous;
+	let diff = b.header.total_difficulty.clone();
+	match adapter.block_received(b, src) {
+		BlockStatus::Accepted => remote.tip_seen(bh, &diff),
+		BlockStatus::Invalid => {
+			debug!("Received invalid block {}.", bh);
+			remote.violation(Violation::InvalidBlock);
+		}
+		BlockStatus::Unprocessed => debug!("Received block {}, not processed.", bh),
+		BlockStatus::Orphan(missing) => {
+			*remote.orphans.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+			match remote.orphan_blocks {
+				OrphanBlocks::RequestParent => {
+					debug!("Received orphan block {} on {}, requesting {}.", bh, prev, missing);
+					add_known(&remote.requested_blocks, missing, KNOWN_BLOCKS_CAP);
+					try!(send_reply(sender, remote.magic, Type::GetBlock, 0, &missing));
+				}
+				OrphanBlocks::RequestHeaders => {
+					// unrequested, the headers come back like an announcement and
+					// the blocks we miss get asked for
+					debug!("Received orphan block {}, requesting headers from our head.", bh);
+					let locator = Locator { hashes: vec![adapter.head_hash()] };
+					try!(send_reply(sender, remote.magic, Type::GetHeaders, 0, &locator));
+				}
+				OrphanBlocks::Ignore => debug!("Received orphan block {}, ignoring it.", bh),
+			}
+		}
+	}
+	Ok(bh)
+}
+
+// Serializes a message with its header and pushes it directly to the sender,
+// an id other than zero identifying the request it responds to.
+fn send_reply<W: ser::Writeable>(sender: &UnboundedSender<Vec<u8>>,
+                                 magic: [u8; 2],
+                                 t: Type,
+                                 id: u32,
+                                 body: &W)
+                                 -> Result<(), ser::Error> {
+	let mut body_data = vec![];
+	try!(ser::serialize(&mut body_data, body));
+	let mut data = vec![];
+	try!(ser::serialize(&mut data, &MsgHeader::with_id(magic, t, body_data.len() as u64, id)));
+	data.append(&mut body_data);
+	sender.send(data);
+	Ok(())
+}
+
+/// Checks that a batch of block headers is internally connected, each header
+/// pointing to the hash of the header preceding it in the batch.
+fn headers_connected(headers: &[core::BlockHeader]) -> bool {
+	headers.windows(2).all(|pair| pair[1].previous == pair[0].hash())
+}
+
+#[cfg(test)]
+mod test {
+	use std::net::SocketAddr;
+	use std::sync::atomic::Ordering;
+
+	use futures::Stream;
+	use futures::sync::mpsc;
+
+	use core::core;
+	use core::core::hash::{Hash, Hashed, ZERO_HASH};
+	use core::core::target::Difficulty;
+	use core::genesis;
+	use core::ser;
+	use bans::BanEntry;
+	use msg::*;
+	use server::DummyAdapter;
+	use super::*;
+
+	fn magic() -> [u8; 2] {
+		ProtocolConfig::default().magic
+	}
+
+	fn genesis_difficulty() -> Difficulty {
+		genesis::genesis().header.total_difficulty
+	}
+
+	fn header_chain(len: u64) -> Vec<core::BlockHeader> {
+		header_chain_from(ZERO_HASH, 0, len, 1)
+	}
+
+	// Chain of headers following the provided previous hash and height, each
+	// showing the provided total difficulty.
+	fn header_chain_from(previous: Hash,
+	                     height: u64,
+	                     len: u64,
+	                     diff: u32)
+	                     -> Vec<core::BlockHeader> {
+		let mut headers: Vec<core::BlockHeader> = vec![];
+		for n in height..(height + len) {
+			let mut bh = core::BlockHeader::default();
+			bh.height = n;
+			bh.total_difficulty = Difficulty::from_num(diff);
+			bh.previous = headers.last().map(|prev| prev.hash()).unwrap_or(previous);
+			headers.push(bh);
+		}
+		headers
+	}
+
+	#[test]
+	fn connected_headers() {
+		assert!(headers_connected(&vec![]));
+		assert!(headers_connected(&header_chain(1)));
+		assert!(headers_connected(&header_chain(4)));
+
+		let mut broken = header_chain(4);
+		broken[2].previous = broken[0].hash();
+		assert!(!headers_connected(&broken));
+	}
+
+	fn test_addr() -> SocketAddr {
+		"127.0.0.1:13414".parse().unwrap()
+	}
+
+	#[test]
+	fn broken_headers_rejected() {
+		let mut headers = header_chain(3);
+		headers[1].height = 42;
+		let body = ser::ser_vec(&Headers { headers: headers }).unwrap();
+
+		let (tx, _rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::Headers, body.len() as u64);
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		let res = handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body);
+		assert!(res.is_err());
+		assert_eq!(remote.score.load(Ordering::Relaxed),
+		           Violation::BrokenHeaderChain.score() as usize);
+	}
+
+	// Has the remote send a page of headers.
+	fn receive_headers(remote: &Remote, headers: Vec<core::BlockHeader>) {
+		let body = ser::ser_vec(&Headers { headers: headers }).unwrap();
+		let (tx, _rx) = mpsc::unbounded();
+		// a response, unlike announcements
+		let header = MsgHeader::with_id(magic(), Type::Headers, body.len() as u64, 1);
+		let res = handle_payload(&DummyAdapter {}, remote, test_addr(), tx, header, body);
+		assert!(res.is_ok());
+	}
+
+	#[test]
+	fn violations_recorded() {
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		let (tx, _rx) = mpsc::unbounded();
+		let addrs = (0..MAX_PEER_ADDRS + 1).map(|_| SockAddr(test_addr())).collect();
+		let body = ser::ser_vec(&PeerAddrs { peers: addrs }).unwrap();
+		let header = MsgHeader::new(magic(), Type::PeerAddrs, body.len() as u64);
+		let res = handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body);
+		assert!(res.is_ok());
+
+		let page = MAX_BLOCK_HEADERS as u64;
+		let first = header_chain_from(ZERO_HASH, 0, page, 10);
+		receive_headers(&remote, first.clone());
+		remote.page_requested(&[first.last().unwrap().hash()]);
+		receive_headers(&remote, header_chain_from(first[100].hash(), 101, 3, 20));
+
+		let mut broken = header_chain(3);
+		broken[1].height = 42;
+		let body = ser::ser_vec(&Headers { headers: broken }).unwrap();
+		let (tx, _rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::Headers, body.len() as u64);
+		let res = handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body);
+		assert!(res.is_err());
+
+		let kinds = |remote: &Remote| {
+			remote.violations.lock().unwrap().iter().map(|v| v.violation).collect::<Vec<_>>()
+		};
+		assert_eq!(kinds(&remote),
+		           vec![Violation::OversizedAddrs,
+		                Violation::HeadersPageBreak,
+		                Violation::BrokenHeaderChain]);
+
+		// past the cap the oldest ones are only counted
+		for _ in 0..MAX_VIOLATIONS {
+			remote.violation(Violation::Undecodable);
+		}
+		assert_eq!(kinds(&remote), vec![Violation::Undecodable; MAX_VIOLATIONS]);
+		assert_eq!(remote.violation_count.load(Ordering::Relaxed), MAX_VIOLATIONS + 3);
+	}
+
+	#[test]
+	fn ban_score_reached() {
+		let mut remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		remote.ban_score = 100;
+		remote.violation(Violation::InvalidBlock);
+		remote.violation(Violation::OversizedAddrs);
+		assert!(!remote.misbehaving());
+		remote.violation(Violation::InvalidBlock);
+		assert!(remote.misbehaving());
+		assert_eq!(remote.score.load(Ordering::Relaxed), 110);
+
+		// without a ban score nobody gets banned
+		remote.ban_score = 0;
+		assert!(!remote.misbehaving());
+	}
+
+	#[test]
+	fn spliced_header_pages_dropped() {
+		let page = MAX_BLOCK_HEADERS as u64;
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		let first = header_chain_from(ZERO_HASH, 0, page, 10);
+		remote.page_requested(&[ZERO_HASH]);
+		receive_headers(&remote, first.clone());
+
+		// asked for the page following the first, one from another fork that
+		// picks up further back in the locator is dropped, without a score
+		let end = first.last().unwrap().hash();
+		remote.page_requested(&[end, first[100].hash(), ZERO_HASH]);
+		let fork = header_chain_from(first[100].hash(), 101, page, 20);
+		receive_headers(&remote, fork.clone());
+		assert_eq!(remote.difficulty.lock().unwrap().0, Difficulty::from_num(10));
+		assert_eq!(remote.score.load(Ordering::Relaxed), 0);
+
+		// asked again from our own chain, the page is a fresh start
+		remote.page_requested(&[first[100].hash(), ZERO_HASH]);
+		receive_headers(&remote, fork);
+		assert_eq!(remote.difficulty.lock().unwrap().0, Difficulty::from_num(20));
+
+		// while pages following each other are fine
+		let last = remote.last_page.lock().unwrap().clone().unwrap();
+		remote.page_requested(&[last]);
+		receive_headers(&remote, header_chain_from(last, 101 + page, 3, 30));
+		assert_eq!(remote.difficulty.lock().unwrap().0, Difficulty::from_num(30));
+		assert!(remote.last_page.lock().unwrap().is_none());
+		assert_eq!(remote.score.load(Ordering::Relaxed), 0);
+	}
+
+	#[test]
+	fn difficulty_increase_from_handshake() {
+		// a peer only showing us blocks below what it advertised in the
+		// handshake isn't advancing, its difficulty stays stale
+		let remote = Remote::new(ALL_FEATURES, Difficulty::from_num(50));
+		let (_, since) = remote.difficulty.lock().unwrap().clone();
+		receive_headers(&remote, header_chain_from(ZERO_HASH, 0, 3, 20));
+		assert_eq!(remote.difficulty.lock().unwrap().clone(), (Difficulty::from_num(50), since));
+		assert!(remote.tip.lock().unwrap().is_none());
+
+		receive_headers(&remote, header_chain_from(ZERO_HASH, 0, 3, 60));
+		let (diff, increased) = remote.difficulty.lock().unwrap().clone();
+		assert_eq!(diff, Difficulty::from_num(60));
+		assert!(increased > since);
+	}
+
+	#[test]
+	fn unbacked_claims_dropped() {
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		let claim = |diff: u32| {
+			*remote.claimed.lock().unwrap() = Some(ChainStatus {
+				total_difficulty: Difficulty::from_num(diff),
+				height: 3,
+			});
+		};
+
+		// an empty page doesn't tell, a full one doesn't get to the tip yet
+		let page = MAX_BLOCK_HEADERS as u64;
+		claim(40);
+		receive_headers(&remote, vec![]);
+		receive_headers(&remote, header_chain_from(ZERO_HASH, 0, page, 20));
+		assert!(remote.claimed.lock().unwrap().is_some());
+
+		// ending at what was claimed backs the claim
+		let last = remote.last_page.lock().unwrap().clone().unwrap();
+		remote.page_requested(&[last]);
+		receive_headers(&remote, header_chain_from(last, page, 3, 40));
+		assert!(remote.claimed.lock().unwrap().is_some());
+		assert_eq!(remote.score.load(Ordering::Relaxed), 0);
+
+		// while falling short of it shows the peer lied
+		claim(90);
+		receive_headers(&remote, header_chain_from(ZERO_HASH, 0, 3, 50));
+		assert!(remote.claimed.lock().unwrap().is_none());
+		assert_eq!(remote.score.load(Ordering::Relaxed),
+		           Violation::UnbackedDifficulty.score() as usize);
+	}
+
+	#[test]
+	fn header_pages_checked_on_request() {
+		let page = MAX_BLOCK_HEADERS as u64;
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		let first = header_chain_from(ZERO_HASH, 0, page, 10);
+		receive_headers(&remote, first.clone());
+
+		// the next request starting from elsewhere, like after another peer
+		// got us further, takes whatever follows that
+		let elsewhere = header_chain_from(ZERO_HASH, 0, 3, 5)[2].hash();
+		remote.page_requested(&[elsewhere, ZERO_HASH]);
+		receive_headers(&remote, header_chain_from(elsewhere, 3, page, 20));
+		assert_eq!(remote.difficulty.lock().unwrap().0, Difficulty::from_num(20));
+
+		// asked for the following page, one connecting to nothing we asked
+		// from gets dropped and scored
+		let end = remote.last_page.lock().unwrap().clone().unwrap();
+		remote.page_requested(&[end, elsewhere]);
+		receive_headers(&remote, header_chain_from(first[100].hash(), 101, 3, 30));
+		assert_eq!(remote.difficulty.lock().unwrap().0, Difficulty::from_num(20));
+		let violations = remote.violations.lock().unwrap();
+		assert_eq!(violations.iter().map(|v| v.violation).collect::<Vec<_>>(),
+		           vec![Violation::HeadersPageBreak]);
+	}
+
+	/// Adapter that can consider every block it receives an orphan and
+	/// knows of a fixed set of blocks and pool transactions. Orphans miss
+	/// their parent, or the provided block further down like when keeping
+	/// the ones in between.
+	struct TestAdapter {
+		orphans: bool,
+		missing: Option<Hash>,
+		known: Vec<Hash>,
+		txs: Vec<core::Transaction>,
+	}
+	impl NetAdapter for TestAdapter {
+		fn total_difficulty(&self) -> Difficulty {
+			Difficulty::one()
+		}
+		fn head_hash(&self) -> Hash {
+			ZERO_HASH
+		}
+		fn head_height(&self) -> u64 {
+			0
+		}
+		fn checkpoints(&self) -> Vec<Checkpoint> {
+			vec![]
+		}
+		fn checkpoints_agreed(&self, checkpoints: Vec<Checkpoint>) {}
+		fn services(&self) -> Services {
+			ALL_SERVICES
+		}
+		fn transaction_received(&self, tx: core::Transaction) {}
+		fn block_received(&self, b: core::Block, src: SocketAddr) -> BlockStatus {
+			if self.orphans {
+				BlockStatus::Orphan(self.missing.unwrap_or(b.header.previous))
+			} else {
+				BlockStatus::Accepted
+			}
+		}
+		fn headers_received(&self, bh: Vec<core::BlockHeader>) {}
+		fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
+			vec![]
+		}
+		fn get_block(&self, h: Hash) -> Option<core::Block> {
+			self.known.iter().position(|k| *k == h).map(|i| test_block(i as u64))
+		}
+		fn utxo_chunk(&self, horizon: Hash, chunk: u32) -> Option<(u32, Vec<core::Output>)> {
+			None
+		}
+		fn stem_transaction_received(&self, tx: core::Transaction) {}
+		fn transaction_fluffed(&self, tx: &core::Transaction) {}
+		fn peer_banned(&self, addr: SocketAddr, ban: &BanEntry) {}
+		fn peer_unbanned(&self, ip: IpAddr) {}
+		fn has_block(&self, h: Hash) -> bool {
+			self.known.contains(&h)
+		}
+		fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+			self.txs.iter().find(|tx| tx.hash() == h).cloned()
+		}
+		fn get_kernel_transaction(&self, k: &core::TxKernel) -> Option<core::Transaction> {
+			self.txs.iter().find(|tx| is_kernel_of(k, tx)).cloned()
+		}
+		fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr> {
+			vec![]
+		}
+		fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>, src: SocketAddr) {}
+		fn peer_connected(&self, pi: &PeerInfo) {}
+		fn peer_error(&self, pi: &PeerInfo, err: &Error) {}
+		fn peer_disconnected(&self, pi: &PeerInfo) {}
+		fn address_allowed(&self, addr: &SocketAddr) -> bool {
+			true
+		}
+		fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+			vec![]
+		}
+	}
+
+	// The known blocks of a TestAdapter, indexed by height.
+	fn test_block(height: u64) -> core::Block {
+		let mut b = core::Block::default();
+		b.header.height = height;
+		b
+	}
+
+	#[test]
+	fn orphan_parent_requested() {
+		let mut b = core::Block::default();
+		b.header.previous = core::BlockHeader::default().hash();
+		let body = ser::ser_vec(&b).unwrap();
+
+		let (tx, rx) = mpsc::unbounded();
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		let header = MsgHeader::new(magic(), Type::Block, body.len() as u64);
+		let adapter = TestAdapter {
+			orphans: true,
+			missing: None,
+			known: vec![],
+			txs: vec![],
+		};
+		handle_payload(&adapter, &remote, test_addr(), tx, header, body).unwrap();
+		assert_eq!(*remote.orphans.lock().unwrap(), 1);
+
+		// the sender should have been asked for the parent
+		let data = rx.wait().next().unwrap().unwrap();
+		let (head, req) = data.split_at(HEADER_LEN as usize);
+		let req_header = ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap();
+		assert_eq!(req_header.msg_type, Type::GetBlock);
+		let req_h = ser::deserialize::<Hash>(&mut &req[..]).unwrap();
+		assert_eq!(req_h, b.header.previous);
+	}
+
+	#[test]
+	fn orphan_policies() {
+		// the orphan misses a block below its parent, the adapter keeping the
+		// parent as an orphan too
+		let missing = Hash([7; 32]);
+		let push_orphan = |policy: OrphanBlocks| -> (Remote, Vec<(MsgHeader, Vec<u8>)>) {
+			let mut b = core::Block::default();
+			b.header.previous = core::BlockHeader::default().hash();
+			let body = ser::ser_vec(&b).unwrap();
+			let adapter = TestAdapter {
+				orphans: true,
+				missing: Some(missing),
+				known: vec![],
+				txs: vec![],
+			};
+			let (tx, rx) = mpsc::unbounded();
+			let mut remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+			remote.orphan_blocks = policy;
+			let header = MsgHeader::new(magic(), Type::Block, body.len() as u64);
+			handle_payload(&adapter, &remote, test_addr(), tx, header, body).unwrap();
+			assert_eq!(*remote.orphans.lock().unwrap(), 1);
+			let requests = rx.wait()
+				.map(|data| {
+					let data = data.unwrap();
+					let (head, req) = data.split_at(HEADER_LEN as usize);
+					(ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap(), req.to_vec())
+				})
+				.collect();
+			(remote, requests)
+		};
+
+		// the missing block gets asked for, its arrival then being solicited
+		let (remote, requests) = push_orphan(OrphanBlocks::RequestParent);
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].0.msg_type, Type::GetBlock);
+		assert_eq!(ser::deserialize::<Hash>(&mut &requests[0].1[..]).unwrap(), missing);
+		assert!(remote.requested_blocks.lock().unwrap().contains(&missing));
+
+		let (remote, requests) = push_orphan(OrphanBlocks::RequestHeaders);
+		assert_eq!(requests.len(), 1);
+		assert_eq!(requests[0].0.msg_type, Type::GetHeaders);
+		let locator = ser::deserialize::<Locator>(&mut &requests[0].1[..]).unwrap();
+		assert_eq!(locator.hashes, vec![ZERO_HASH]);
+		assert!(remote.requested_blocks.lock().unwrap().is_empty());
+
+		let (remote, requests) = push_orphan(OrphanBlocks::Ignore);
+		assert!(requests.is_empty());
+		assert!(remote.requested_blocks.lock().unwrap().is_empty());
+	}
+
+	// Has the remote push the block at the provided height, returning the
+	// hash of the block if it got handed to the adapter.
+	fn push_block(remote: &Remote,
+	              height: u64,
+	              tx: &mpsc::UnboundedSender<Vec<u8>>)
+	              -> Option<Hash> {
+		let body = ser::ser_vec(&test_block(height)).unwrap();
+		let header = MsgHeader::new(magic(), Type::Block, body.len() as u64);
+		let adapter = TestAdapter {
+			orphans: false,
+			missing: None,
+			known: vec![],
+			txs: vec![],
+		};
+		handle_payload(&adapter, remote, test_addr(), tx.clone(), header, body).unwrap()
+	}
+
+	#[test]
+	fn unsolicited_blocks_rate_limited() {
+		let (tx, _rx) = mpsc::unbounded();
+		let mut remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		remote.unsolicited_blocks = UnsolicitedBlocks::RateLimited(2);
+		assert!(push_block(&remote, 1, &tx).is_some());
+		assert!(push_block(&remote, 2, &tx).is_some());
+		assert!(push_block(&remote, 3, &tx).is_none());
+		assert!(push_block(&remote, 4, &tx).is_none());
+
+		// blocks we asked for don't count
+		add_known(&remote.requested_blocks, test_block(5).hash(), KNOWN_BLOCKS_CAP);
+		assert!(push_block(&remote, 5, &tx).is_some());
+		assert!(push_block(&remote, 5, &tx).is_none());
+	}
+
+	#[test]
+	fn unsolicited_blocks_headers_first() {
+		let (tx, rx) = mpsc::unbounded();
+		let mut remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		remote.unsolicited_blocks = UnsolicitedBlocks::HeadersFirst;
+		assert!(push_block(&remote, 1, &tx).is_none());
+
+		// the block got asked for, and gets through once it comes back
+		drop(tx);
+		let requests = rx.wait().map(|d| d.unwrap()).collect::<Vec<_>>();
+		assert_eq!(requests.len(), 1);
+		let (head, req) = requests[0].split_at(HEADER_LEN as usize);
+		assert_eq!(ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap().msg_type, Type::GetBlock);
+		assert_eq!(ser::deserialize::<Hash>(&mut &req[..]).unwrap(), test_block(1).hash());
+		let (tx, _rx) = mpsc::unbounded();
+		assert_eq!(push_block(&remote, 1, &tx), Some(test_block(1).hash()));
+	}
+
+	// Address message with more addresses than allowed.
+	fn oversized_addrs() -> Vec<u8> {
+		let peers = (0..(MAX_PEER_ADDRS + 44))
+			.map(|n| SockAddr(SocketAddr::new("10.0.0.1".parse().unwrap(), n as u16)))
+			.collect();
+		ser::ser_vec(&PeerAddrs { peers: peers }).unwrap()
+	}
+
+	#[test]
+	fn oversized_addrs_truncated() {
+		let body = oversized_addrs();
+		let addrs = ser::deserialize::<TruncatedPeerAddrs>(&mut &body[..]).unwrap();
+		assert_eq!(addrs.sent, MAX_PEER_ADDRS + 44);
+		assert_eq!(addrs.peers.len(), MAX_PEER_ADDRS as usize);
+		assert_eq!(addrs.peers[0].0.port(), 0);
+
+		let (tx, _rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::PeerAddrs, body.len() as u64);
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		assert!(handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body).is_ok());
+	}
+
+	#[test]
+	fn oversized_addrs_disconnect() {
+		let body = oversized_addrs();
+		let (tx, _rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::PeerAddrs, body.len() as u64);
+		let mut remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		remote.oversized_addrs = OversizedAddrs::Disconnect;
+		match handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			_ => panic!("oversized addresses accepted"),
+		}
+	}
+
+	// Feeds an inventory to a fresh handler, returning the GetData request it
+	// replied with, if any.
+	fn getdata_for(adapter: &TestAdapter, hashes: Vec<Hash>) -> Option<Inventory> {
+		let body = ser::ser_vec(&Inventory {
+				inv_type: InvType::Block,
+				hashes: hashes,
+			})
+			.unwrap();
+		let (tx, rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::Inv, body.len() as u64);
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		handle_payload(adapter, &remote, test_addr(), tx, header, body).unwrap();
+
+		rx.wait().next().map(|data| {
+			let data = data.unwrap();
+			let (head, req) = data.split_at(HEADER_LEN as usize);
+			let req_header = ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap();
+			assert_eq!(req_header.msg_type, Type::GetData);
+			ser::deserialize::<Inventory>(&mut &req[..]).unwrap()
+		})
+	}
+
+	#[test]
+	fn block_inv_known() {
+		let h = core::BlockHeader::default().hash();
+		let adapter = TestAdapter {
+			orphans: false,
+			missing: None,
+			known: vec![h],
+			txs: vec![],
+		};
+		assert!(getdata_for(&adapter, vec![h]).is_none());
+	}
+
+	#[test]
+	fn block_inv_unknown() {
+		let known = core::BlockHeader::default().hash();
+		let mut bh = core::BlockHeader::default();
+		bh.height = 1;
+		let unknown = bh.hash();
+		let adapter = TestAdapter {
+			orphans: false,
+			missing: None,
+			known: vec![known],
+			txs: vec![],
+		};
+
+		let req = getdata_for(&adapter, vec![known, unknown]).unwrap();
+		assert_eq!(req.hashes, vec![unknown]);
+	}
+
+	#[test]
+	fn getdata_streams_blocks() {
+		let hashes = (0..3).map(|i| test_block(i).hash()).collect::<Vec<_>>();
+		let adapter = TestAdapter {
+			orphans: false,
+			missing: None,
+			known: hashes.clone(),
+			txs: vec![],
+		};
+		let body = ser::ser_vec(&Inventory {
+				inv_type: InvType::Block,
+				hashes: hashes.clone(),
+			})
+			.unwrap();
+		let (tx, rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::GetData, body.len() as u64);
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		handle_payload(&adapter, &remote, test_addr(), tx, header, body).unwrap();
+
+		// all three blocks come back in order in a single response
+		let responses = rx.wait().map(|d| d.unwrap()).collect::<Vec<_>>();
+		assert_eq!(responses.len(), 1);
+		let (head, resp) = responses[0].split_at(HEADER_LEN as usize);
+		let resp_header = ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap();
+		assert_eq!(resp_header.msg_type, Type::Blocks);
+		let blocks = ser::deserialize::<Blocks>(&mut &resp[..]).unwrap();
+		assert_eq!(blocks.blocks.iter().map(|b| b.hash()).collect::<Vec<_>>(), hashes);
+	}
+
+	#[test]
+	fn getdata_unbatched_blocks() {
+		let hashes = (0..3).map(|i| test_block(i).hash()).collect::<Vec<_>>();
+		let adapter = TestAdapter {
+			orphans: false,
+			missing: None,
+			known: hashes.clone(),
+			txs: vec![],
+		};
+		let body = ser::ser_vec(&Inventory {
+				inv_type: InvType::Block,
+				hashes: hashes.clone(),
+			})
+			.unwrap();
+		let (tx, rx) = mpsc::unbounded();
+		let header = MsgHeader::new(magic(), Type::GetData, body.len() as u64);
+		let remote = Remote::new(NO_FEATURES, genesis_difficulty());
+		handle_payload(&adapter, &remote, test_addr(), tx, header, body).unwrap();
+
+		// without batches negotiated, each block comes back on its own
+		let responses = rx.wait().map(|d| d.unwrap()).collect::<Vec<_>>();
+		assert_eq!(responses.len(), 3);
+		for (data, h) in responses.iter().zip(hashes) {
+			let (head, resp) = data.split_at(HEADER_LEN as usize);
+			let resp_header = ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap();
+			assert_eq!(resp_header.msg_type, Type::Block);
+			assert_eq!(ser::deserialize::<core::Block>(&mut &resp[..]).unwrap().hash(), h);
+		}
+	}
+
+	// Asks a TestAdapter holding the provided transactions for the given hash,
+	// returning the responses it sent back.
+	fn tx_getdata(txs: Vec<core::Transaction>, h: Hash) -> Vec<Vec<u8>> {
+		let adapter = TestAdapter {
+			orphans: false,
+			missing: None,
+			known: vec![],
+			txs: txs,
+		};
+		let body = ser::ser_vec(&Inventory {
+				inv_type: InvType::Transaction,
+				hashes: vec![h],
+			})
+			.unwrap();
+		let (tx, rx) = mpsc::unbounded();
+		let header = MsgHeader::with_id(magic(), Type::GetData, body.len() as u64, 7);
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		handle_payload(&adapter, &remote, test_addr(), tx, header, body).unwrap();
+		rx.wait().map(|d| d.unwrap()).collect()
+	}
+
+	#[test]
+	fn getdata_present_transaction() {
+		let mut pool_tx = core::Transaction::empty();
+		pool_tx.fee = 5;
+		let h = pool_tx.hash();
+
+		let responses = tx_getdata(vec![pool_tx], h);
+		assert_eq!(responses.len(), 1);
+		let (head, resp) = responses[0].split_at(HEADER_LEN as usize);
+		let resp_header = ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap();
+		assert_eq!(resp_header.msg_type, Type::Transaction);
+		assert_eq!(resp_header.id, 7);
+		let served = ser::deserialize::<core::Transaction>(&mut &resp[..]).unwrap();
+		assert_eq!(served.hash(), h);
+	}
+
+	#[test]
+	fn getdata_absent_transaction() {
+		let mut pool_tx = core::Transaction::empty();
+		pool_tx.fee = 5;
+		let responses = tx_getdata(vec![pool_tx], core::Transaction::empty().hash());
+		assert!(responses.is_empty());
+	}
+
+	// Replies of the remote to a request for peers.
+	fn peers_request_replies(remote: &Remote) -> Vec<Vec<u8>> {
+		let (tx, rx) = mpsc::unbounded();
+		let body = ser::ser_vec(&GetPeerAddrs { capabilities: UNKNOWN }).unwrap();
+		let header = MsgHeader::new(magic(), Type::GetPeerAddrs, body.len() as u64);
+		let res = handle_payload(&DummyAdapter {}, remote, test_addr(), tx, header, body);
+		assert_eq!(res.unwrap(), None);
+		rx.wait().map(|d| d.unwrap()).collect()
+	}
+
+	#[test]
+	fn messages_gated() {
+		// we only answer requests for peers if we advertised we provide them
+		let mut remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		assert!(peers_request_replies(&remote).is_empty());
+		remote.own_capabilities = PEER_LIST;
+		assert_eq!(peers_request_replies(&remote).len(), 1);
+
+		// and only ask peers that did
+		assert!(!remote.speaks(Type::GetPeerAddrs, false));
+		remote.capabilities = FULL_NODE;
+		assert!(remote.speaks(Type::GetPeerAddrs, false));
+
+		// messages newer than the version we speak are ignored
+		remote.version = Type::GetPeerAddrs.since_version() - 1;
+		assert!(peers_request_replies(&remote).is_empty());
+		assert!(!remote.speaks(Type::Ping, false));
+
+		// requests waiting for a response get an error back instead
+		let (tx, rx) = mpsc::unbounded();
+		let body = ser::ser_vec(&GetPeerAddrs { capabilities: UNKNOWN }).unwrap();
+		let header = MsgHeader::with_id(magic(), Type::GetPeerAddrs, body.len() as u64, 7);
+		handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body).unwrap();
+		let replies = rx.wait().map(|d| d.unwrap()).collect::<Vec<_>>();
+		assert_eq!(replies.len(), 1);
+		let reply = ser::deserialize::<MsgHeader>(&mut &replies[0][..]).unwrap();
+		assert_eq!((reply.msg_type, reply.id), (Type::Error, 7));
+	}
+
+	// Pong sent back to a ping with the provided body.
+	fn pong_to(body: Vec<u8>) -> (MsgHeader, Vec<u8>) {
+		let (tx, rx) = mpsc::unbounded();
+		let header = MsgHeader::with_id(magic(), Type::Ping, body.len() as u64, 3);
+		let remote = Remote::new(ALL_FEATURES, genesis_difficulty());
+		handle_payload(&DummyAdapter {}, &remote, test_addr(), tx, header, body).unwrap();
+		let data = rx.wait().next().unwrap().unwrap();
+		let (head, body) = data.split_at(HEADER_LEN as usize);
+		(ser::deserialize::<MsgHeader>(&mut &head[..]).unwrap(), body.to_vec())
+	}
+
+	#[test]
+	fn pong_carries_chain_status() {
+		let status = ChainStatus {
+			total_difficulty: Difficulty::from_num(9),
+			height: 4,
+		};
+		let (header, body) = pong_to(ser::ser_vec(&status).unwrap());
+		assert_eq!((header.msg_type, header.id), (Type::Pong, 3));
+		assert_eq!(ser::deserialize::<ChainStatus>(&mut &body[..]).unwrap(),
+		           ChainStatus {
+			           total_difficulty: Difficulty::one(),
+			           height: 0,
+		           });
+
+		// peers pinging without their status get an empty pong
+		let (header, body) = pong_to(vec![]);
+		assert_eq!((header.msg_type, header.id), (Type::Pong, 3));
+		assert!(body.is_empty());
+	}
+}
@@ -0,0 +1,457 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Outbound connections through a SOCKS5 proxy, like the one Tor runs. Onion
+//! services get exchanged between peers as the IPv6 addresses OnionCat maps
+//! them to, so they fit in our peer address messages, and are asked of the
+//! proxy by name when connecting.
+
+use std::io::{self, Read, Write};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::{future, Future};
+use tokio_core::io::{read_exact, write_all};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor;
+
+use types::ProxyConfig;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS_AUTH: u8 = 2;
+const USER_PASS_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+// Tor's extension resolving a host name, answered with its address.
+const CMD_RESOLVE: u8 = 0xf0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// OnionCat range, the 80 bits of a version 2 onion service name follow.
+const ONION_PREFIX: [u8; 6] = [0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43];
+const ONION_NAME_LEN: usize = 16;
+const BASE32: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+type IoFuture<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// Connects to the provided address through the proxy, resolving once the
+/// proxy connected us to it.
+pub fn connect(proxy: &ProxyConfig, addr: SocketAddr, h: &reactor::Handle) -> IoFuture<TcpStream> {
+	let auth = proxy.auth.clone();
+	let request = connect_request(&addr);
+	let connect = TcpStream::connect(&proxy.addr, h)
+		.and_then(move |conn| authenticate(conn, auth))
+		.and_then(move |conn| write_all(conn, request))
+		.and_then(|(conn, _)| read_reply(conn));
+	Box::new(connect)
+}
+
+/// Resolves the host name through the proxy, with the RESOLVE command Tor
+/// adds to SOCKS5, so our lookups don't give our IP away either. Blocks
+/// until the proxy answers, each read or write taking at most the provided
+/// timeout. Only the one address the proxy answers with comes back.
+pub fn resolve(proxy: &ProxyConfig,
+               host: &str,
+               port: u16,
+               timeout: Duration)
+               -> io::Result<SocketAddr> {
+	if host.len() > 255 {
+		return Err(proxy_err("host name too long"));
+	}
+	let mut conn = net::TcpStream::connect(&proxy.addr)?;
+	conn.set_read_timeout(Some(timeout))?;
+	conn.set_write_timeout(Some(timeout))?;
+
+	conn.write_all(&greeting(&proxy.auth))?;
+	let mut reply = [0u8; 2];
+	conn.read_exact(&mut reply)?;
+	if reply[0] != SOCKS_VERSION {
+		return Err(proxy_err("not a SOCKS5 proxy"));
+	}
+	match (reply[1], &proxy.auth) {
+		(NO_AUTH, _) => {}
+		(USER_PASS_AUTH, &Some((ref user, ref pass))) => {
+			conn.write_all(&user_pass_request(user, pass)?)?;
+			conn.read_exact(&mut reply)?;
+			if reply[1] != 0 {
+				return Err(proxy_err("authentication refused"));
+			}
+		}
+		_ => return Err(proxy_err("no acceptable authentication method")),
+	}
+
+	let mut req = vec![SOCKS_VERSION, CMD_RESOLVE, 0, ATYP_DOMAIN, host.len() as u8];
+	req.extend_from_slice(host.as_bytes());
+	req.extend_from_slice(&[0, 0]);
+	conn.write_all(&req)?;
+	let mut head = [0u8; 4];
+	conn.read_exact(&mut head)?;
+	if head[0] != SOCKS_VERSION {
+		return Err(proxy_err("not a SOCKS5 proxy"));
+	}
+	if head[1] != 0 {
+		return Err(proxy_err(&format!("could not resolve {}: {}", host, reply_msg(head[1]))));
+	}
+	let ip = match head[3] {
+		ATYP_IPV4 => {
+			let mut octets = [0u8; 4];
+			conn.read_exact(&mut octets)?;
+			IpAddr::V4(Ipv4Addr::from(octets))
+		}
+		ATYP_IPV6 => {
+			let mut octets = [0u8; 16];
+			conn.read_exact(&mut octets)?;
+			IpAddr::V6(Ipv6Addr::from(octets))
+		}
+		_ => return Err(proxy_err("unknown address type")),
+	};
+	Ok(SocketAddr::new(ip, port))
+}
+
+/// The address an onion service maps to, from its name.onion host name.
+/// Only the 16 characters names of version 2 services fit.
+pub fn onion_addr(host: &str, port: u16) -> Option<SocketAddr> {
+	let name = host.as_bytes();
+	if name.len() != ONION_NAME_LEN + 6 || !host.ends_with(".onion") {
+		return None;
+	}
+	let decoded = match base32_decode(&name[..ONION_NAME_LEN]) {
+		Some(decoded) => decoded,
+		None => return None,
+	};
+	let mut octets = [0u8; 16];
+	octets[..6].copy_from_slice(&ONION_PREFIX);
+	octets[6..].copy_from_slice(&decoded);
+	Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+}
+
+/// The host name of the onion service the provided address maps to, if any.
+pub fn onion_host(addr: &SocketAddr) -> Option<String> {
+	match addr.ip() {
+		IpAddr::V6(ip) if ip.octets()[..6] == ONION_PREFIX[..] => {
+			Some(format!("{}.onion", base32_encode(&ip.octets()[6..])))
+		}
+		_ => None,
+	}
+}
+
+/// Parses the address of a peer, either an IP one or the name.onion:port
+/// one of an onion service.
+pub fn parse_peer_addr(s: &str) -> Option<SocketAddr> {
+	if let Ok(addr) = s.parse::<SocketAddr>() {
+		return Some(addr);
+	}
+	let mut parts = s.rsplitn(2, ':');
+	let port = parts.next().and_then(|p| p.parse::<u16>().ok());
+	match (parts.next(), port) {
+		(Some(host), Some(port)) => onion_addr(host, port),
+		_ => None,
+	}
+}
+
+// Greets the proxy with the authentication methods we have, going through
+// the user name and password one if the proxy picks it.
+fn authenticate(conn: TcpStream, auth: Option<(String, String)>) -> IoFuture<TcpStream> {
+	let authenticated = write_all(conn, greeting(&auth))
+		.and_then(|(conn, _)| read_exact(conn, [0u8; 2]))
+		.and_then(move |(conn, reply)| -> IoFuture<TcpStream> {
+			if reply[0] != SOCKS_VERSION {
+				return Box::new(future::err(proxy_err("not a SOCKS5 proxy")));
+			}
+			match (reply[1], auth) {
+				(NO_AUTH, _) => Box::new(future::ok(conn)),
+				(USER_PASS_AUTH, Some((user, pass))) => user_pass(conn, &user, &pass),
+				_ => Box::new(future::err(proxy_err("no acceptable authentication method"))),
+			}
+		});
+	Box::new(authenticated)
+}
+
+// Greeting offering the proxy the authentication methods we have.
+fn greeting(auth: &Option<(String, String)>) -> Vec<u8> {
+	match *auth {
+		Some(_) => vec![SOCKS_VERSION, 2, NO_AUTH, USER_PASS_AUTH],
+		None => vec![SOCKS_VERSION, 1, NO_AUTH],
+	}
+}
+
+// Authenticates with a user name and password, as in RFC 1929.
+fn user_pass(conn: TcpStream, user: &str, pass: &str) -> IoFuture<TcpStream> {
+	let req = match user_pass_request(user, pass) {
+		Ok(req) => req,
+		Err(e) => return Box::new(future::err(e)),
+	};
+	let authenticated = write_all(conn, req)
+		.and_then(|(conn, _)| read_exact(conn, [0u8; 2]))
+		.and_then(|(conn, reply)| {
+			if reply[1] == 0 {
+				Ok(conn)
+			} else {
+				Err(proxy_err("authentication refused"))
+			}
+		});
+	Box::new(authenticated)
+}
+
+// Request to connect to the provided address, by name for onion services so
+// the proxy resolves them.
+fn connect_request(addr: &SocketAddr) -> Vec<u8> {
+	let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+	match (onion_host(addr), addr.ip()) {
+		(Some(host), _) => {
+			req.push(ATYP_DOMAIN);
+			req.push(host.len() as u8);
+			req.extend_from_slice(host.as_bytes());
+		}
+		(None, IpAddr::V4(ip)) => {
+			req.push(ATYP_IPV4);
+			req.extend_from_slice(&ip.octets());
+		}
+		(None, IpAddr::V6(ip)) => {
+			req.push(ATYP_IPV6);
+			req.extend_from_slice(&ip.octets());
+		}
+	}
+	req.push((addr.port() >> 8) as u8);
+	req.push(addr.port() as u8);
+	req
+}
+
+// Reads the reply to our connect request, skipping the address the proxy
+// connected from which we have no use for.
+fn read_reply(conn: TcpStream) -> IoFuture<TcpStream> {
+	let reply = read_exact(conn, [0u8; 4]).and_then(|(conn, reply)| -> IoFuture<TcpStream> {
+		if reply[0] != SOCKS_VERSION {
+			return Box::new(future::err(proxy_err("not a SOCKS5 proxy")));
+		}
+		if reply[1] != 0 {
+			let msg = format!("connection refused by the proxy: {}", reply_msg(reply[1]));
+			return Box::new(future::err(proxy_err(&msg)));
+		}
+		// bound address and port
+		let bound: IoFuture<(TcpStream, usize)> = match reply[3] {
+			ATYP_IPV4 => Box::new(future::ok((conn, 4 + 2))),
+			ATYP_IPV6 => Box::new(future::ok((conn, 16 + 2))),
+			ATYP_DOMAIN => {
+				Box::new(read_exact(conn, [0u8; 1]).map(|(conn, len)| (conn, len[0] as usize + 2)))
+			}
+			_ => return Box::new(future::err(proxy_err("unknown address type"))),
+		};
+		Box::new(bound.and_then(|(conn, len)| read_exact(conn, vec![0u8; len]))
+			.map(|(conn, _)| conn))
+	});
+	Box::new(reply)
+}
+
+// Request authenticating with the provided user name and password.
+fn user_pass_request(user: &str, pass: &str) -> io::Result<Vec<u8>> {
+	if user.len() > 255 || pass.len() > 255 {
+		return Err(proxy_err("user name or password too long"));
+	}
+	let mut req = vec![USER_PASS_VERSION, user.len() as u8];
+	req.extend_from_slice(user.as_bytes());
+	req.push(pass.len() as u8);
+	req.extend_from_slice(pass.as_bytes());
+	Ok(req)
+}
+
+fn reply_msg(code: u8) -> &'static str {
+	match code {
+		1 => "general failure",
+		2 => "not allowed by ruleset",
+		3 => "network unreachable",
+		4 => "host unreachable",
+		5 => "connection refused",
+		6 => "TTL expired",
+		7 => "command not supported",
+		8 => "address type not supported",
+		_ => "unknown error",
+	}
+}
+
+fn proxy_err(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::Other, format!("SOCKS5 proxy: {}", msg))
+}
+
+fn base32_encode(data: &[u8]) -> String {
+	let mut s = String::new();
+	let (mut acc, mut bits) = (0u32, 0);
+	for &b in data {
+		acc = (acc << 8) | b as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			s.push(BASE32[((acc >> bits) & 31) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		s.push(BASE32[((acc << (5 - bits)) & 31) as usize] as char);
+	}
+	s
+}
+
+fn base32_decode(s: &[u8]) -> Option<Vec<u8>> {
+	let mut data = vec![];
+	let (mut acc, mut bits) = (0u32, 0);
+	for &c in s {
+		let v = match c {
+			b'a'...b'z' => c - b'a',
+			b'A'...b'Z' => c - b'A',
+			b'2'...b'7' => c - b'2' + 26,
+			_ => return None,
+		};
+		acc = (acc << 5) | v as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			data.push((acc >> bits) as u8);
+		}
+	}
+	Some(data)
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{Read, Write};
+	use std::net::{self, SocketAddr};
+	use std::thread;
+
+	use tokio_core::io::read_exact;
+	use tokio_core::reactor;
+
+	use types::ProxyConfig;
+	use super::*;
+
+	const ONION: &'static str = "expyuzz4wqqyqhjn.onion";
+
+	#[test]
+	fn onion_mapped() {
+		let addr = onion_addr(ONION, 13414).unwrap();
+		assert_eq!(addr.to_string(), "[fd87:d87e:eb43:25df:8a67:3cb4:2188:1d2d]:13414");
+		assert_eq!(onion_host(&addr), Some(ONION.to_string()));
+		assert_eq!(onion_addr("EXPYUZZ4WQQYQHJN.onion", 13414), Some(addr));
+		assert_eq!(parse_peer_addr(&format!("{}:13414", ONION)), Some(addr));
+
+		assert_eq!(onion_addr("expyuzz4wqqyqhj.onion", 13414), None);
+		assert_eq!(onion_addr("expyuzz4wqqyqhj1.onion", 13414), None);
+		assert_eq!(onion_addr("expyuzz4wqqyqhjn.onion.com", 13414), None);
+		assert_eq!(parse_peer_addr(ONION), None);
+
+		let ip = "10.0.0.1:13414".parse::<SocketAddr>().unwrap();
+		assert_eq!(onion_host(&ip), None);
+		assert_eq!(parse_peer_addr("10.0.0.1:13414"), Some(ip));
+	}
+
+	#[test]
+	fn connects_through_proxy() {
+		let mut evtlp = reactor::Core::new().unwrap();
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let proxy_addr = listener.local_addr().unwrap();
+		let proxy = thread::spawn(move || {
+			let (mut conn, _) = listener.accept().unwrap();
+			let mut greeting = [0u8; 4];
+			conn.read_exact(&mut greeting).unwrap();
+			assert_eq!(greeting, [5, 2, 0, 2]);
+			conn.write_all(&[5, 2]).unwrap();
+
+			let mut auth = [0u8; 11];
+			conn.read_exact(&mut auth).unwrap();
+			assert_eq!(&auth[..], b"\x01\x04user\x04pass");
+			conn.write_all(&[1, 0]).unwrap();
+
+			// the onion service gets asked for by name
+			let mut req = vec![0u8; 5 + ONION.len() + 2];
+			conn.read_exact(&mut req).unwrap();
+			assert_eq!(&req[..5], &[5, 1, 0, 3, ONION.len() as u8]);
+			assert_eq!(&req[5..5 + ONION.len()], ONION.as_bytes());
+			assert_eq!(&req[5 + ONION.len()..], &[0x34, 0x66]);
+			conn.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x34, 0x66, 42]).unwrap();
+		});
+
+		let config = ProxyConfig {
+			addr: proxy_addr,
+			auth: Some(("user".to_string(), "pass".to_string())),
+			onion: None,
+		};
+		let addr = onion_addr(ONION, 13414).unwrap();
+		let handle = evtlp.handle();
+		let conn = evtlp.run(connect(&config, addr, &handle)).unwrap();
+		proxy.join().unwrap();
+
+		// what follows the reply is the peer's
+		let (_, data) = evtlp.run(read_exact(conn, [0u8; 1])).unwrap();
+		assert_eq!(data, [42]);
+	}
+
+	#[test]
+	fn resolves_through_proxy() {
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let proxy_addr = listener.local_addr().unwrap();
+		let proxy = thread::spawn(move || {
+			for answer in vec![vec![5, 0, 0, 1, 10, 0, 0, 7, 0, 0], vec![5, 4, 0, 1]] {
+				let (mut conn, _) = listener.accept().unwrap();
+				let mut greeting = [0u8; 3];
+				conn.read_exact(&mut greeting).unwrap();
+				conn.write_all(&[5, 0]).unwrap();
+				let host = "seed.grin.example";
+				let mut req = vec![0u8; 5 + host.len() + 2];
+				conn.read_exact(&mut req).unwrap();
+				assert_eq!(&req[..5], &[5, 0xf0, 0, 3, host.len() as u8]);
+				assert_eq!(&req[5..5 + host.len()], host.as_bytes());
+				conn.write_all(&answer).unwrap();
+			}
+		});
+
+		let config = ProxyConfig {
+			addr: proxy_addr,
+			auth: None,
+			onion: None,
+		};
+		let timeout = Duration::from_secs(5);
+		let addr = resolve(&config, "seed.grin.example", 13414, timeout).unwrap();
+		assert_eq!(addr, "10.0.0.7:13414".parse::<SocketAddr>().unwrap());
+		// the proxy couldn't reach the name server
+		assert!(resolve(&config, "seed.grin.example", 13414, timeout).is_err());
+		proxy.join().unwrap();
+	}
+
+	#[test]
+	fn proxy_refusal() {
+		let mut evtlp = reactor::Core::new().unwrap();
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let proxy_addr = listener.local_addr().unwrap();
+		let proxy = thread::spawn(move || {
+			let (mut conn, _) = listener.accept().unwrap();
+			let mut greeting = [0u8; 3];
+			conn.read_exact(&mut greeting).unwrap();
+			conn.write_all(&[5, 0]).unwrap();
+			let mut req = [0u8; 10];
+			conn.read_exact(&mut req).unwrap();
+			assert_eq!(req, [5, 1, 0, 1, 10, 0, 0, 1, 0x34, 0x66]);
+			conn.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+		});
+
+		let config = ProxyConfig {
+			addr: proxy_addr,
+			auth: None,
+			onion: None,
+		};
+		let addr = "10.0.0.1:13414".parse().unwrap();
+		let handle = evtlp.handle();
+		let res = evtlp.run(connect(&config, addr, &handle));
+		proxy.join().unwrap();
+		assert!(res.is_err());
+	}
+}
@@ -0,0 +1,395 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scheduling of our outbound dials: which addresses to dial, in what order
+//! and when. Decisions only depend on the time and the state they're given,
+//! never on the clock of the event loop, which merely carries them out.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use store::subnet;
+use types::P2PConfig;
+
+// Number of flaky peers remembered, the one whose last session ended the
+// longest ago being forgotten first.
+const MAX_FLAKY_PEERS: usize = 1000;
+
+/// What to do with a dial candidate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DialAction {
+	/// Dial the address once the delay passed.
+	Dial { addr: SocketAddr, delay: Duration },
+	/// Leave the address alone, we're backing off from it for that long.
+	Backoff { addr: SocketAddr, remaining: Duration },
+}
+
+/// Decides when our outbound dials go out, pacing them apart and backing off
+/// from flaky peers.
+pub struct DialScheduler {
+	interval: Duration,
+	// earliest the next dial can go out
+	next_dial: Option<Instant>,
+	flaky: FlakyPeers,
+}
+
+impl DialScheduler {
+	pub fn new(config: &P2PConfig) -> DialScheduler {
+		DialScheduler {
+			interval: Duration::from_millis(config.min_dial_interval_ms),
+			next_dial: None,
+			flaky: FlakyPeers::new(config),
+		}
+	}
+
+	/// Takes the turn of a dial about to go out at the provided time,
+	/// returning how long it should wait to be the configured interval after
+	/// the previous one.
+	pub fn pace(&mut self, now: Instant) -> Duration {
+		let at = self.start_at(now);
+		self.next_dial = Some(at + self.interval);
+		at - now
+	}
+
+	/// Plans dialing the provided candidates at the provided time, given the
+	/// number of our outbound peers in each subnet. Candidates we're backing
+	/// off from are left alone. Up to max of the others get dialed, those in
+	/// the subnets with the fewest of our peers first, each paced after the
+	/// previous one. Nothing is taken until the dials actually go out.
+	pub fn plan(&self,
+	            candidates: Vec<SocketAddr>,
+	            outbound: &HashMap<Vec<u8>, u32>,
+	            max: usize,
+	            now: Instant)
+	            -> Vec<DialAction> {
+		let mut actions = vec![];
+		let mut dials = vec![];
+		for addr in candidates {
+			match self.backoff(&addr, now) {
+				Some(remaining) => {
+					actions.push(DialAction::Backoff {
+						addr: addr,
+						remaining: remaining,
+					})
+				}
+				None => dials.push(addr),
+			}
+		}
+		let mut at = self.start_at(now);
+		for addr in by_diversity(dials, outbound.clone()).into_iter().take(max) {
+			actions.push(DialAction::Dial {
+				addr: addr,
+				delay: at - now,
+			});
+			at = at + self.interval;
+		}
+		actions
+	}
+
+	/// Records a session with the peer that ended at the provided time after
+	/// lasting the provided duration. Returns whether the peer should be
+	/// quarantined for failing soon after connecting too many times.
+	pub fn session_ended(&mut self, addr: SocketAddr, lasted: Duration, now: Instant) -> bool {
+		self.flaky.ended(addr, lasted, now)
+	}
+
+	/// How long we still back off from the peer at the provided time, if at
+	/// all.
+	pub fn backoff(&self, addr: &SocketAddr, now: Instant) -> Option<Duration> {
+		self.flaky.backoff(addr, now)
+	}
+
+	// When the next dial can start, no earlier than the provided time.
+	fn start_at(&self, now: Instant) -> Instant {
+		match self.next_dial {
+			Some(next) => cmp::max(next, now),
+			None => now,
+		}
+	}
+}
+
+/// Exponential backoff between our attempts to dial a peer we want to stay
+/// connected to, doubling from the configured delay up to the max one.
+pub struct RetryBackoff {
+	base: Duration,
+	max: Duration,
+	next: Duration,
+}
+
+impl RetryBackoff {
+	pub fn new(config: &P2PConfig) -> RetryBackoff {
+		let base = Duration::from_millis(config.persistent_retry_ms);
+		RetryBackoff {
+			base: base,
+			max: cmp::max(base, Duration::from_millis(config.persistent_retry_max_ms)),
+			next: base,
+		}
+	}
+
+	/// How long to wait before the next attempt. Each call waits twice as
+	/// long as the previous one, until reset.
+	pub fn delay(&mut self) -> Duration {
+		let delay = self.next;
+		self.next = cmp::min(self.next * 2, self.max);
+		delay
+	}
+
+	/// Starts over from the configured delay, once a connection held.
+	pub fn reset(&mut self) {
+		self.next = self.base;
+	}
+}
+
+/// Orders the provided dial candidates so those in subnets with the fewest
+/// of our outbound peers, as counted by subnet, come first. Candidates of a
+/// same subnet get spread out.
+pub fn by_diversity(addrs: Vec<SocketAddr>,
+                    mut outbound: HashMap<Vec<u8>, u32>)
+                    -> Vec<SocketAddr> {
+	// each candidate counts as one more peer in its subnet for the next
+	let mut ranked = addrs.into_iter()
+		.map(|addr| {
+			let count = outbound.entry(subnet(&addr.ip())).or_insert(0);
+			*count += 1;
+			(addr, *count)
+		})
+		.collect::<Vec<_>>();
+	ranked.sort_by_key(|&(_, count)| count);
+	ranked.into_iter().map(|(addr, _)| addr).collect()
+}
+
+/// Of the provided dial candidates, one per subnet none of our outbound
+/// peers are in, as counted by subnet.
+pub fn in_new_subnets(addrs: Vec<SocketAddr>,
+                      mut outbound: HashMap<Vec<u8>, u32>)
+                      -> Vec<SocketAddr> {
+	addrs.into_iter()
+		.filter(|addr| outbound.insert(subnet(&addr.ip()), 1).is_none())
+		.collect()
+}
+
+/// Tracks the peers failing soon after connecting, unlike those we can't
+/// connect to at all. Past a number of short sessions in a row we back off
+/// from a peer, longer with each further one, until it gets quarantined.
+struct FlakyPeers {
+	short: Duration,
+	threshold: u32,
+	backoff: Duration,
+	quarantine: u32,
+	// short sessions in a row of each peer, with when the last one ended
+	peers: HashMap<SocketAddr, (u32, Instant)>,
+}
+
+impl FlakyPeers {
+	fn new(config: &P2PConfig) -> FlakyPeers {
+		FlakyPeers {
+			short: Duration::from_secs(config.flaky_session_secs),
+			threshold: config.flaky_threshold,
+			backoff: Duration::from_secs(config.flaky_backoff_secs),
+			quarantine: config.flaky_quarantine,
+			peers: HashMap::new(),
+		}
+	}
+
+	/// Records a session with the peer that ended now after lasting the
+	/// provided time. Returns whether the peer should be quarantined.
+	fn ended(&mut self, addr: SocketAddr, lasted: Duration, now: Instant) -> bool {
+		if self.threshold == 0 || lasted >= self.short {
+			self.peers.remove(&addr);
+			return false;
+		}
+		if self.peers.len() >= MAX_FLAKY_PEERS && !self.peers.contains_key(&addr) {
+			let oldest = self.peers.iter().min_by_key(|&(_, &(_, t))| t).map(|(a, _)| *a);
+			if let Some(oldest) = oldest {
+				self.peers.remove(&oldest);
+			}
+		}
+		let count = self.peers.get(&addr).map_or(0, |&(n, _)| n) + 1;
+		if count >= cmp::max(self.quarantine, self.threshold) {
+			self.peers.remove(&addr);
+			return true;
+		}
+		self.peers.insert(addr, (count, now));
+		false
+	}
+
+	/// How long we still back off from the peer, if at all.
+	fn backoff(&self, addr: &SocketAddr, now: Instant) -> Option<Duration> {
+		let (count, last) = match self.peers.get(addr) {
+			Some(&(count, last)) if count >= self.threshold => (count, last),
+			_ => return None,
+		};
+		// doubling with each short session past the threshold
+		let backoff = self.backoff * (1 << cmp::min(count - self.threshold, 16));
+		let elapsed = now.duration_since(last);
+		if elapsed < backoff {
+			Some(backoff - elapsed)
+		} else {
+			None
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+	use std::net::SocketAddr;
+	use std::time::{Duration, Instant};
+
+	use types::P2PConfig;
+	use super::*;
+
+	fn addr(s: &str) -> SocketAddr {
+		s.parse().unwrap()
+	}
+
+	fn ms(n: u64) -> Duration {
+		Duration::from_millis(n)
+	}
+
+	fn dial(a: &str, delay: u64) -> DialAction {
+		DialAction::Dial {
+			addr: addr(a),
+			delay: ms(delay),
+		}
+	}
+
+	fn scheduler(interval_ms: u64) -> DialScheduler {
+		let config = P2PConfig {
+			min_dial_interval_ms: interval_ms,
+			flaky_threshold: 2,
+			flaky_backoff_secs: 10,
+			flaky_quarantine: 5,
+			..P2PConfig::default()
+		};
+		DialScheduler::new(&config)
+	}
+
+	#[test]
+	fn flaky_peer_reset() {
+		let config = P2PConfig {
+			flaky_threshold: 1,
+			flaky_backoff_secs: 10,
+			..P2PConfig::default()
+		};
+		let mut flaky = FlakyPeers::new(&config);
+		let addr: SocketAddr = "10.0.0.1:13414".parse().unwrap();
+		let start = Instant::now();
+		assert!(!flaky.ended(addr, Duration::from_secs(1), start));
+		assert_eq!(flaky.backoff(&addr, start + Duration::from_secs(4)),
+		           Some(Duration::from_secs(6)));
+		assert_eq!(flaky.backoff(&addr, start + Duration::from_secs(10)), None);
+
+		// a long enough session clears the peer
+		assert!(!flaky.ended(addr, Duration::from_secs(60), start));
+		assert_eq!(flaky.backoff(&addr, start), None);
+	}
+
+	#[test]
+	fn dials_paced() {
+		let mut sched = scheduler(200);
+		let start = Instant::now();
+		assert_eq!(sched.pace(start), ms(0));
+		assert_eq!(sched.pace(start), ms(200));
+		assert_eq!(sched.pace(start + ms(50)), ms(350));
+
+		// the plan picks up after the dials already paced, taking nothing
+		let candidates = vec![addr("10.0.0.1:1"), addr("10.1.0.1:1")];
+		let plan = sched.plan(candidates.clone(), &HashMap::new(), 10, start + ms(100));
+		assert_eq!(plan, vec![dial("10.0.0.1:1", 500), dial("10.1.0.1:1", 700)]);
+		assert_eq!(sched.plan(candidates, &HashMap::new(), 10, start + ms(100)), plan);
+
+		// once idle long enough, dials go out right away again
+		assert_eq!(sched.pace(start + Duration::from_secs(1)), ms(0));
+
+		// without an interval nothing waits
+		let mut sched = scheduler(0);
+		assert_eq!(sched.pace(start), ms(0));
+		assert_eq!(sched.pace(start), ms(0));
+	}
+
+	#[test]
+	fn flaky_peers_backed_off() {
+		let mut sched = scheduler(0);
+		let start = Instant::now();
+		let (flaky, steady) = (addr("10.0.0.1:1"), addr("10.1.0.1:1"));
+		let candidates = vec![flaky, steady];
+
+		// below the threshold the peer still gets dialed
+		assert!(!sched.session_ended(flaky, Duration::from_secs(1), start));
+		assert_eq!(sched.plan(candidates.clone(), &HashMap::new(), 10, start).len(), 2);
+
+		// then we back off, twice as long with each further short session
+		assert!(!sched.session_ended(flaky, Duration::from_secs(1), start));
+		let plan = sched.plan(candidates.clone(), &HashMap::new(), 10, start + ms(4000));
+		assert_eq!(plan,
+		           vec![DialAction::Backoff {
+			                addr: flaky,
+			                remaining: ms(6000),
+		                },
+		                dial("10.1.0.1:1", 0)]);
+		assert!(!sched.session_ended(flaky, Duration::from_secs(1), start));
+		assert_eq!(sched.backoff(&flaky, start + ms(15000)), Some(ms(5000)));
+		let plan = sched.plan(candidates.clone(), &HashMap::new(), 10, start + ms(20000));
+		assert_eq!(plan, vec![dial("10.0.0.1:1", 0), dial("10.1.0.1:1", 0)]);
+
+		// until it gets quarantined, which is left to the caller
+		assert!(!sched.session_ended(flaky, Duration::from_secs(1), start));
+		assert!(sched.session_ended(flaky, Duration::from_secs(1), start));
+		assert_eq!(sched.backoff(&flaky, start), None);
+	}
+
+	#[test]
+	fn new_subnets_dialed_first() {
+		let sched = scheduler(100);
+		let start = Instant::now();
+		let mut outbound = HashMap::new();
+		outbound.insert(vec![10, 0], 2);
+		outbound.insert(vec![10, 1], 1);
+
+		let candidates = vec![addr("10.0.0.1:1"),
+		                      addr("10.1.0.1:1"),
+		                      addr("10.2.0.1:1"),
+		                      addr("10.2.0.2:1")];
+		let plan = sched.plan(candidates.clone(), &outbound, 10, start);
+		assert_eq!(plan,
+		           vec![dial("10.2.0.1:1", 0),
+		                dial("10.1.0.1:1", 100),
+		                dial("10.2.0.2:1", 200),
+		                dial("10.0.0.1:1", 300)]);
+
+		// only as many as asked for, the most diverse ones
+		let plan = sched.plan(candidates.clone(), &outbound, 2, start);
+		assert_eq!(plan, vec![dial("10.2.0.1:1", 0), dial("10.1.0.1:1", 100)]);
+
+		assert_eq!(in_new_subnets(candidates, outbound), vec![addr("10.2.0.1:1")]);
+	}
+
+	#[test]
+	fn retries_backed_off() {
+		let config = P2PConfig {
+			persistent_retry_ms: 100,
+			persistent_retry_max_ms: 500,
+			..P2PConfig::default()
+		};
+		let mut backoff = RetryBackoff::new(&config);
+		let delays = (0..5).map(|_| backoff.delay()).collect::<Vec<_>>();
+		assert_eq!(delays, vec![ms(100), ms(200), ms(400), ms(500), ms(500)]);
+
+		backoff.reset();
+		assert_eq!(backoff.delay(), ms(100));
+	}
+}
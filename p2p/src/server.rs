@@ -15,34 +15,285 @@
 //! Grin server implementation, accepts incoming connections and connects to
 //! other peers in the network.
 
-use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use futures;
 use futures::{Future, Stream};
-use futures::future::{self, IntoFuture};
+use futures::future::{self, IntoFuture, Loop};
+use futures::sync::oneshot;
+use net2;
+use num::ToPrimitive;
 use rand::{self, Rng};
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor;
+use tokio_timer::Timer;
 
 use core::core;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
 use core::core::target::Difficulty;
+use bans::{BanEntry, HostScores, Restrictions, Terms};
+use conn::Traffic;
 use handshake::Handshake;
+use msg::{ChainStatus, Checkpoint};
+use nat;
 use peer::Peer;
+use pool::BlockPool;
+use proxy::{self, onion_host};
+use schedule::{self, DialAction, DialScheduler, RetryBackoff};
+use store::{PeerStore, private_ip, subnet, valid_peer_addr};
+use stream::{accept_known, secure, secure_known, PeerStream, TlsContext};
+use throttle::{self, ReadLimit, Throttle, TokenBucket};
 use types::*;
 
+// Number of peers we got disconnected from remembered as last seen, and for
+// how long in seconds.
+const MAX_DEPARTED: usize = 1000;
+const DEPARTED_SECS: u64 = 24 * 3600;
+
+// Number of updates for the peer store waiting to be written, new ones being
+// dropped past it.
+const MAX_STORE_UPDATES: usize = 1000;
+
+// Number of dials waiting for a dial slot, new ones failing past it.
+const MAX_WAITING_DIALS: usize = 100;
+
+// Number of hosts whose view of our address we keep track of.
+const MAX_ADDR_OBSERVERS: usize = 1000;
+
+// Number of distinct hosts that have to agree on our address before we take
+// it as our public one, so that a single peer can't make us advertise any.
+const MIN_ADDR_OBSERVERS: usize = 3;
+
+// Number of hosts whose misbehavior in handshakes we keep score of, and the
+// time in seconds after its last malformed handshake a score is forgotten.
+const MAX_SCORED_HOSTS: usize = 1000;
+const SCORE_MEMORY_SECS: u64 = 3600;
+
+// Number of hosts banned or quarantined at once, those to be lifted the
+// soonest making room for new ones.
+const MAX_RESTRICTED_HOSTS: usize = 10000;
+
+// Number of banned peers pruned automatically kept for the next explicit
+// clean_peers.
+const MAX_PRUNED_BANNED: usize = 1000;
+
+// Minimum number of peers agreeing on a checkpoint for it to be trusted.
+const MIN_CHECKPOINT_PEERS: usize = 2;
+
+// Number of most worked peers always getting block and header broadcasts on
+// top of the fanout, new blocks mattering most to them.
+const BROADCAST_BEST_PEERS: usize = 2;
+
+// Head start of the IPv6 addresses when racing both address families, in
+// milliseconds.
+const HAPPY_EYEBALLS_DELAY_MS: u64 = 250;
+
+// Lifetime of the mapping of our port on the gateway asked for, renewed
+// halfway through, and the pause before trying again when it failed, in
+// seconds.
+const PORT_MAPPING_SECS: u64 = 3600;
+const PORT_MAPPING_RETRY_SECS: u64 = 300;
+// Shortest wait before renewing a mapping, whatever the gateway granted.
+const PORT_MAPPING_MIN_RENEW_SECS: u64 = 60;
+
+// Attempts at binding a listener before giving up, and the pause after the
+// first failed one in milliseconds, doubling after each.
+const BIND_ATTEMPTS: u32 = 4;
+const BIND_RETRY_MS: u64 = 50;
+
+// Prompt pongs in a row after which the ping interval of a peer widens.
+const PING_PROMPT_STREAK: u32 = 3;
+
+// Pause in accepting connections after running out of file descriptors, in
+// milliseconds.
+const FD_ACCEPT_PAUSE_MS: u64 = 1000;
+
+// Pause in accepting connections after another transient accept error, in
+// milliseconds.
+const ACCEPT_ERROR_PAUSE_MS: u64 = 100;
+
+// Time after running out of file descriptors before we open connections
+// again, unless we lost peers in the meantime.
+const FD_RESUME_SECS: u64 = 60;
+
+// Errors telling the process or the whole system ran out of file
+// descriptors, the same on Linux and the BSDs.
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+// Errors telling a listening socket is closed or not listening anymore.
+const EBADF: i32 = 9;
+const EINVAL: i32 = 22;
+
+// Milliseconds between checks for peers still connected while stopping.
+const DRAIN_CHECK_MS: u64 = 50;
+
+// How many of our own transactions get queued at most in sync mode.
+const MAX_QUEUED_TXS: usize = 100;
+
+/// What the server knows of a peer, see Server::find_peer.
+pub enum PeerLookup {
+	/// We're connected to the peer.
+	Connected(Arc<Peer>),
+	/// We got disconnected from the peer, last seen that long ago.
+	Disconnected { last_seen: Duration },
+	/// The host of the peer is banned or quarantined, for that long.
+	Banned { severity: Severity, since: Duration },
+	/// We never connected to the peer.
+	Unknown,
+}
+
+/// A peer we were connected to when a snapshot was taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotPeer {
+	pub id: PeerId,
+	pub addr: SocketAddr,
+	/// When we connected to the peer.
+	pub connected_at: Instant,
+}
+
+/// What a connected peer did so far, see Server::peer_stats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+	pub id: PeerId,
+	pub addr: SocketAddr,
+	pub direction: Direction,
+	/// Protocol version and user agent the peer advertised in the handshake.
+	pub version: u32,
+	pub user_agent: String,
+	/// Total difficulty the peer last advertised.
+	pub total_difficulty: Difficulty,
+	/// Height of the tip of the peer and round trip time of our pings, once
+	/// it answered one.
+	pub height: Option<u64>,
+	pub latency: Option<Duration>,
+	/// Bytes sent to and received from the peer since the handshake, or
+	/// since the last reset.
+	pub sent_bytes: u64,
+	pub received_bytes: u64,
+	/// Messages sent to and received from the peer, over the same period.
+	pub sent_msgs: u64,
+	pub received_msgs: u64,
+	/// Seconds since we connected to the peer.
+	pub uptime_secs: u64,
+}
+
+/// The peers we were connected to at some point, see Server::peer_snapshot.
+/// Comparing two snapshots shows the churn in between.
+#[derive(Debug, Clone)]
+pub struct PeerSnapshot {
+	/// When the snapshot was taken.
+	pub taken_at: Instant,
+	/// The connected peers, by increasing id.
+	pub peers: Vec<SnapshotPeer>,
+}
+
+/// Peers that joined and left between two snapshots, by increasing id. A
+/// peer that reconnected in between both left and joined, as a new
+/// connection gets a new id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerDiff {
+	pub joined: Vec<SnapshotPeer>,
+	pub left: Vec<SnapshotPeer>,
+}
+
+impl PeerSnapshot {
+	/// The peers that joined and left between this snapshot and the provided
+	/// newer one.
+	pub fn diff(&self, newer: &PeerSnapshot) -> PeerDiff {
+		let old_ids = self.peers.iter().map(|p| p.id).collect::<HashSet<_>>();
+		let new_ids = newer.peers.iter().map(|p| p.id).collect::<HashSet<_>>();
+		PeerDiff {
+			joined: newer.peers.iter().filter(|p| !old_ids.contains(&p.id)).cloned().collect(),
+			left: self.peers.iter().filter(|p| !new_ids.contains(&p.id)).cloned().collect(),
+		}
+	}
+}
+
+/// How far our chain is behind those of our peers, see Server::sync_status.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncStatus {
+	/// None of our peers claims more work than us.
+	Synced,
+	/// The peer with the most work claims that much more than us, progress
+	/// being our total difficulty as a rough fraction of theirs.
+	Syncing { behind_by: Difficulty, progress: f64 },
+	/// We have no peer to compare with.
+	NoPeers,
+}
+
+impl SyncStatus {
+	/// Rough fraction of the chain we have, from 0 with no peers to 1 once
+	/// synced.
+	pub fn progress(&self) -> f64 {
+		match *self {
+			SyncStatus::Synced => 1.0,
+			SyncStatus::Syncing { progress, .. } => progress,
+			SyncStatus::NoPeers => 0.0,
+		}
+	}
+}
+
+/// What we report about ourselves to the peers asking for it, shared by all
+/// the peers of a server.
+pub struct LocalStatus {
+	started: Instant,
+	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+}
+
+impl LocalStatus {
+	/// Status of a server with the provided peers, starting now.
+	pub fn new(peers: Arc<RwLock<Vec<Arc<Peer>>>>) -> LocalStatus {
+		LocalStatus {
+			started: Instant::now(),
+			peers: peers,
+		}
+	}
+
+	/// Number of peers we're connected to.
+	pub fn peer_count(&self) -> u32 {
+		connected_count(&self.peers)
+	}
+
+	/// Seconds since we started.
+	pub fn uptime(&self) -> u64 {
+		self.started.elapsed().as_secs()
+	}
+}
+
 /// A no-op network adapter used for testing.
 pub struct DummyAdapter {}
 impl NetAdapter for DummyAdapter {
 	fn total_difficulty(&self) -> Difficulty {
 		Difficulty::one()
 	}
+	fn head_hash(&self) -> Hash {
+		ZERO_HASH
+	}
+	fn head_height(&self) -> u64 {
+		0
+	}
+	fn checkpoints(&self) -> Vec<Checkpoint> {
+		vec![]
+	}
+	fn checkpoints_agreed(&self, checkpoints: Vec<Checkpoint>) {}
+	fn services(&self) -> Services {
+		ALL_SERVICES
+	}
 	fn transaction_received(&self, tx: core::Transaction) {}
-	fn block_received(&self, b: core::Block) {}
+	fn stem_transaction_received(&self, tx: core::Transaction) {}
+	fn transaction_fluffed(&self, tx: &core::Transaction) {}
+	fn block_received(&self, b: core::Block, src: SocketAddr) -> BlockStatus {
+		BlockStatus::Accepted
+	}
 	fn headers_received(&self, bh: Vec<core::BlockHeader>) {}
 	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
 		vec![]
@@ -50,11 +301,33 @@ impl NetAdapter for DummyAdapter {
 	fn get_block(&self, h: Hash) -> Option<core::Block> {
 		None
 	}
+	fn utxo_chunk(&self, horizon: Hash, chunk: u32) -> Option<(u32, Vec<core::Output>)> {
+		None
+	}
+	fn has_block(&self, h: Hash) -> bool {
+		false
+	}
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+		None
+	}
+	fn get_kernel_transaction(&self, k: &core::TxKernel) -> Option<core::Transaction> {
+		None
+	}
 	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr> {
 		vec![]
 	}
-	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>) {}
+	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>, src: SocketAddr) {}
 	fn peer_connected(&self, pi: &PeerInfo) {}
+	fn peer_error(&self, pi: &PeerInfo, err: &Error) {}
+	fn peer_disconnected(&self, pi: &PeerInfo) {}
+	fn peer_banned(&self, addr: SocketAddr, ban: &BanEntry) {}
+	fn peer_unbanned(&self, ip: IpAddr) {}
+	fn address_allowed(&self, addr: &SocketAddr) -> bool {
+		true
+	}
+	fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+		vec![]
+	}
 }
 
 /// P2P server implementation, handling bootstrapping to find and connect to
@@ -64,231 +337,7483 @@ pub struct Server {
 	capabilities: Capabilities,
 	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
 	adapter: Arc<NetAdapter>,
-	stop: RefCell<Option<futures::sync::oneshot::Sender<()>>>,
+	stop: Mutex<Option<oneshot::Sender<()>>>,
+	// set once stopped, stopping again doing nothing
+	stopped: Arc<AtomicBool>,
+	handshake_failures: Arc<Mutex<HashMap<HandshakeFailure, u64>>>,
+	// bucket limiting our combined writes to all peers, if configured
+	outbound_bucket: Option<Arc<TokenBucket>>,
+	// only needed when some outbound limit is configured
+	throttle_timer: Option<Timer>,
+	churn: Arc<Mutex<Churn>>,
+	// peers dropping soon after connecting, backed off from
+	scheduler: Arc<Mutex<DialScheduler>>,
+	// when each peer is due for a ping
+	pings: Arc<Mutex<Pings>>,
+	// number of inbound handshakes currently in progress
+	handshakes: Arc<Mutex<usize>>,
+	// handshakes in progress holding a peer slot, counted toward the limits
+	pending: Arc<Mutex<PendingPeers>>,
+	// hosts we won't connect to nor accept, with when they were restricted
+	restrictions: Arc<Mutex<Restrictions>>,
+	// misbehavior score of the hosts whose handshakes we couldn't decode
+	misbehavior: Arc<Mutex<HostScores>>,
+	// workers handing received blocks to the adapter, if configured
+	block_pool: Option<Arc<BlockPool>>,
+	// when the peers we got disconnected from were last seen
+	departed: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+	// peer store we record what we learn of peers in, if we were given one
+	book: Arc<PeerBook>,
+	// outbound dials in flight, up to the configured limit
+	dials: Arc<Mutex<DialLimit>>,
+	// cancel the outbound dials not done yet when stopping
+	dial_cancels: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+	// limits that can be changed while running
+	runtime: Arc<RwLock<P2PConfigRuntime>>,
+	// bytes exchanged with all peers since we started
+	traffic: Arc<Traffic>,
+	// reported to peers asking about us
+	local: Arc<LocalStatus>,
+	// banned peers pruned automatically since the last clean_peers
+	pruned: Arc<Mutex<Vec<Arc<Peer>>>>,
+	// notified once we have at least the given number of peers
+	peer_waiters: Arc<Mutex<Vec<(u32, oneshot::Sender<()>)>>>,
+	// the address each host we're connected with sees us at
+	observed_addrs: Arc<Mutex<HashMap<IpAddr, SocketAddr>>>,
+	// the external address our gateway forwards to our listener, if we mapped
+	// our port on it
+	mapped_addr: Arc<Mutex<Option<SocketAddr>>>,
+	// IPs of ours, as the local end of the connections we made or accepted
+	local_ips: Arc<Mutex<HashSet<IpAddr>>>,
+	// whether we're catching up with the chain, holding off transaction relay
+	sync_mode: AtomicBool,
+	// the peer we're catching up with, see set_sync_peer
+	sync_peer: Mutex<Option<SocketAddr>>,
+	// whether new inbound connections get closed right away
+	inbound_paused: Arc<AtomicBool>,
+	// whether we ran out of file descriptors, holding off new connections
+	fds: Arc<FdExhaustion>,
+	// what new connections get secured with, if configured, or why our TLS
+	// setup failed to load
+	tls: Result<Option<Arc<TlsContext>>, String>,
+	// handshakes of all our connections, so self connections get detected and
+	// peer clocks compared across all of them
+	handshake: Arc<Handshake>,
+	// which peers the latest broadcasts went to, when limited to a fanout
+	rotation: Mutex<BroadcastRotation>,
+	// the peer our dandelion stem goes through and since when
+	stem: Mutex<Option<(SocketAddr, Instant)>>,
+	// transactions we stemmed, broadcast by us once their embargo ends
+	embargoes: Arc<Mutex<HashMap<Hash, Embargo>>>,
+	// our own transactions pushed while in sync mode, relayed once done
+	// syncing, each with whether to broadcast it rather than stem it
+	queued_txs: Mutex<VecDeque<(core::Transaction, bool)>>,
 }
 
-unsafe impl Sync for Server {}
-unsafe impl Send for Server {}
-
-// TODO TLS
 impl Server {
 	/// Creates a new idle p2p server with no peers
 	pub fn new(capab: Capabilities, config: P2PConfig, adapter: Arc<NetAdapter>) -> Server {
+		let outbound_bucket = if config.max_outbound_rate > 0 {
+			Some(Arc::new(TokenBucket::new(config.max_outbound_rate, Instant::now())))
+		} else {
+			None
+		};
+		let throttle_timer = if config.max_outbound_rate > 0 || config.max_peer_outbound_rate > 0 ||
+		                        config.send_timeout_secs > 0 ||
+		                        !config.inbound_limits.unlimited() {
+			Some(Timer::default())
+		} else {
+			None
+		};
+		let block_pool = if config.block_workers > 0 {
+			Some(Arc::new(BlockPool::new(adapter.clone(),
+			                             config.block_workers,
+			                             config.block_queue_size as usize)))
+		} else {
+			None
+		};
+		let runtime = Arc::new(RwLock::new(config.runtime()));
+		let peers = Arc::new(RwLock::new(Vec::new()));
+		let tls = match config.tls {
+			Some(ref tls) => {
+				TlsContext::new(tls).map(|ctx| Some(Arc::new(ctx))).map_err(|e| {
+					error!("Could not set up TLS, refusing all connections: {:?}", e);
+					format!("{:?}", e)
+				})
+			}
+			None => Ok(None),
+		};
+		// telling our peers we can encrypt, for them to secure the connection
+		let capab = if config.tls.is_some() { capab | ENCRYPTED } else { capab };
+		let handshake = Arc::new(new_handshake(&config));
+		let scores = HostScores::new(MAX_SCORED_HOSTS, Duration::from_secs(SCORE_MEMORY_SECS));
 		Server {
 			config: config,
 			capabilities: capab,
-			peers: Arc::new(RwLock::new(Vec::new())),
+			peers: peers.clone(),
 			adapter: adapter,
-			stop: RefCell::new(None),
+			stop: Mutex::new(None),
+			stopped: Arc::new(AtomicBool::new(false)),
+			handshake_failures: Arc::new(Mutex::new(HashMap::new())),
+			outbound_bucket: outbound_bucket,
+			throttle_timer: throttle_timer,
+			churn: Arc::new(Mutex::new(Churn::new(Duration::from_secs(config.churn_window),
+			                                      config.churn_alarm))),
+			scheduler: Arc::new(Mutex::new(DialScheduler::new(&config))),
+			pings: Arc::new(Mutex::new(Pings::new(&config))),
+			handshakes: Arc::new(Mutex::new(0)),
+			pending: Arc::new(Mutex::new(PendingPeers::default())),
+			restrictions: Arc::new(Mutex::new(Restrictions::new(MAX_RESTRICTED_HOSTS))),
+			misbehavior: Arc::new(Mutex::new(scores)),
+			block_pool: block_pool,
+			departed: Arc::new(Mutex::new(HashMap::new())),
+			book: Arc::new(PeerBook::new(None)),
+			dials: Arc::new(Mutex::new(DialLimit::new(config.max_concurrent_dials))),
+			dial_cancels: Arc::new(Mutex::new(vec![])),
+			runtime: runtime,
+			traffic: Arc::new(Traffic::new()),
+			local: Arc::new(LocalStatus::new(peers.clone())),
+			pruned: Arc::new(Mutex::new(vec![])),
+			peer_waiters: Arc::new(Mutex::new(vec![])),
+			observed_addrs: Arc::new(Mutex::new(HashMap::new())),
+			mapped_addr: Arc::new(Mutex::new(None)),
+			local_ips: Arc::new(Mutex::new(HashSet::new())),
+			sync_mode: AtomicBool::new(false),
+			sync_peer: Mutex::new(None),
+			inbound_paused: Arc::new(AtomicBool::new(false)),
+			fds: Arc::new(FdExhaustion::new(peers)),
+			tls: tls,
+			handshake: handshake,
+			rotation: Mutex::new(BroadcastRotation::new()),
+			stem: Mutex::new(None),
+			embargoes: Arc::new(Mutex::new(HashMap::new())),
+			queued_txs: Mutex::new(VecDeque::new()),
 		}
 	}
 
+	/// Records what we learn of peers as we connect to them, and fail to, in
+	/// the provided store: when they were last seen and how many times we
+	/// connected. Peers it has doing well make better candidates for a slot.
+	pub fn with_peer_store(mut self, store: Arc<PeerStore>) -> Server {
+		self.book = Arc::new(PeerBook::new(Some(store)));
+		self
+	}
+
+	/// Applies new limits to the running server, in effect for what happens
+	/// from now on. Changing any other part of the configuration requires a
+	/// restart.
+	pub fn update_config(&self, new: P2PConfigRuntime) {
+		info!("Updating P2P limits to {:?}.", new);
+		DialSlot::resize(&self.dials, new.max_concurrent_dials);
+		self.churn.lock().unwrap_or_else(|e| e.into_inner()).alarm = new.churn_alarm;
+		*self.runtime.write().unwrap_or_else(|e| e.into_inner()) = new;
+	}
+
+	/// The limits currently in effect.
+	pub fn runtime_config(&self) -> P2PConfigRuntime {
+		self.runtime().clone()
+	}
+
+	// What new connections get secured with, failing them all if our TLS
+	// setup is broken rather than falling back to plaintext.
+	fn tls(&self) -> Result<Option<Arc<TlsContext>>, Error> {
+		self.tls.clone().map_err(Error::Tls)
+	}
+
 	/// Starts the p2p server. Opens a TCP port to allow incoming
 	/// connections and starts the bootstrapping process to find peers.
 	pub fn start(&self, h: reactor::Handle) -> Box<Future<Item = (), Error = Error>> {
-		let addr = SocketAddr::new(self.config.host, self.config.port);
-		let socket = TcpListener::bind(&addr, &h.clone()).unwrap();
-		warn!("P2P server started on {}", addr);
+		if self.stopped.load(Ordering::SeqCst) {
+			return Box::new(future::ok(()));
+		}
+		for addr in &self.config.persistent_peers {
+			self.maintain_connection(*addr, h.clone());
+		}
+		if !self.config.inbound_enabled {
+			warn!("P2P server started, inbound connections disabled.");
+			return self.until_stopped(self.upkeep(h.clone()), h);
+		}
 
-		let hs = Arc::new(Handshake::new());
+		let tls = match self.tls() {
+			Ok(tls) => tls,
+			Err(e) => return Box::new(future::err(e)),
+		};
+		// accepting on a listener starts once it's bound, a listener we can't
+		// bind failing the whole server
+		let mut listeners = vec![];
+		for addr in self.listen_addrs() {
+			let (fds, hl) = (self.fds.clone(), h.clone());
+			let accepted = bind_with_retry(&addr, &self.config, &h).map(move |socket| {
+				warn!("P2P server started on {}", addr);
+				isolate_listener(addr, socket.incoming(), fds, hl)
+			});
+			let listener: Box<Stream<Item = (TcpStream, SocketAddr), Error = Error>> =
+				Box::new(accepted.flatten_stream());
+			listeners.push(listener);
+		}
+		if self.config.port_mapping {
+			self.map_port();
+		}
+
+		let hs = self.handshake.clone();
 		let peers = self.peers.clone();
+		let churn = self.churn.clone();
+		let scheduler = self.scheduler.clone();
+		let waiters = self.peer_waiters.clone();
 		let adapter = self.adapter.clone();
 		let capab = self.capabilities.clone();
+		let failures = self.handshake_failures.clone();
+		let runtime = self.runtime.clone();
+		let mut inbound_rate = InboundRate::new(Instant::now());
+		let outbound_bucket = self.outbound_bucket.clone();
+		let timer = self.throttle_timer.clone();
+		let send_timeout = self.config.send_timeout_secs;
+		let inbound_limits = self.config.inbound_limits;
+		let handshake_timeout = Duration::from_secs(self.config.handshake_timeout_secs);
+		let max_diff = Difficulty::max_with_bits(self.config.max_difficulty_bits);
+		let handshakes = self.handshakes.clone();
+		let pending = self.pending.clone();
+		let max_handshakes = cmp::max(self.config.max_handshakes, 1);
+		let preferred = self.config.preferred_peers.clone();
+		let class_limits = self.config.inbound_capability_limits.clone();
+		let own_services = self.config.services;
+		let restrictions = self.restrictions.clone();
+		let block_pool = self.block_pool.clone();
+		let departed = self.departed.clone();
+		let book = self.book.clone();
+		let traffic = self.traffic.clone();
+		let local = self.local.clone();
+		let observed = self.observed_addrs.clone();
+		let local_ips = self.local_ips.clone();
+		let paused = self.inbound_paused.clone();
+		let pruned = self.pruned.clone();
+		let misbehavior = self.misbehavior.clone();
+		let ban_score = self.config.ban_score;
 
 		// main peer acceptance future handling handshake
 		let hp = h.clone();
-		let peers = socket.incoming().map_err(From::from).map(move |(conn, addr)| {
+		let peers = merge_listeners(listeners).map(move |(conn, addr)| -> HandshakeFuture {
+			// read the limits for every connection, they can change while running
+			let limits = runtime.read().unwrap_or_else(|e| e.into_inner()).clone();
+			let terms = Terms::new(limits.quarantine_secs, limits.ban_secs);
+			if paused.load(Ordering::Relaxed) {
+				debug!("Inbound connections paused, closing connection from {}.", addr);
+				let noop: PeerFuture = Box::new(future::ok(()));
+				return Box::new(future::ok(Ok(noop)));
+			}
+			if !adapter.address_allowed(&addr) {
+				debug!("Refusing connection from {}, not allowed by the adapter.", addr);
+				let noop: PeerFuture = Box::new(future::ok(()));
+				return Box::new(future::ok(Ok(noop)));
+			}
+			if is_restricted(&restrictions, &addr.ip()) {
+				debug!("Refusing connection from banned or quarantined {}.", addr);
+				let noop: PeerFuture = Box::new(future::ok(()));
+				return Box::new(future::ok(Ok(noop)));
+			}
+			// the handshakes in progress count along with the connected peers, at
+			// the limit a peer that could stand better than one of ours goes
+			// through the handshake, the worse peer being evicted once it's done
+			let (max_inbound, reserved) = (limits.max_inbound_peers, limits.reserved_slots);
+			let regular = !preferred.contains(&addr.ip());
+			let slot = PendingSlot::reserve_if(&pending, Direction::Inbound, regular, |p| {
+				admit_inbound(&peers, p, addr, &preferred, max_inbound, reserved)
+			});
+			if slot.is_none() &&
+			   worse_peer(&peers, &book, &misbehavior, Direction::Inbound, &preferred, addr)
+				.is_none() {
+				debug!("No inbound slot left for {}, dropping connection.", addr);
+				let noop: PeerFuture = Box::new(future::ok(()));
+				return Box::new(future::ok(Ok(noop)));
+			}
+			let evict: Option<Box<Fn(&PeerInfo) -> bool>> = if slot.is_some() {
+				None
+			} else {
+				let (peers, book, misbehavior) = (peers.clone(), book.clone(), misbehavior.clone());
+				let (pending, preferred) = (pending.clone(), preferred.clone());
+				Some(Box::new(move |info: &PeerInfo| {
+					let admitted = {
+						let p = pending.lock().unwrap_or_else(|e| e.into_inner());
+						admit_inbound(&peers, &p, addr, &preferred, max_inbound, reserved)
+					};
+					admitted ||
+					evict_worse(&peers,
+					            &book,
+					            &misbehavior,
+					            Direction::Inbound,
+					            &preferred,
+					            info.node_addr())
+				}))
+			};
+
 			let adapter = adapter.clone();
 			let total_diff = adapter.total_difficulty();
+			let services = adapter.services();
 			let peers = peers.clone();
+			let peers2 = peers.clone();
+			let pruned = pruned.clone();
+			let failures = failures.clone();
+			let hs = hs.clone();
+			let tls = tls.clone();
+			let tls1 = tls.clone();
+			let churn = churn.clone();
+			let scheduler = scheduler.clone();
+			let restrictions = restrictions.clone();
+			let restrictions2 = restrictions.clone();
+			let misbehavior = misbehavior.clone();
+			let waiters = waiters.clone();
+			let throttle = new_throttle(&outbound_bucket,
+			                            limits.max_peer_outbound_rate,
+			                            send_timeout,
+			                            inbound_limits,
+			                            &timer);
+			let block_pool = block_pool.clone();
+			let departed = departed.clone();
+			let book = book.clone();
+			let traffic = traffic.clone();
+			let local = local.clone();
+			let observed = observed.clone();
+			let local_ips = local_ips.clone();
+			let handshakes = handshakes.clone();
+			*handshakes.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+
+			// when too many peers connect at once, hold off a little before greeting
+			let rate = inbound_rate.record(Instant::now());
+			let delay = greeting_delay(rate, limits.greeting_delay_rate, limits.greeting_delay_max);
+			let wait: Box<Future<Item = (), Error = Error>> = if delay > Duration::new(0, 0) {
+				debug!("Inbound rate at {}/s, delaying greeting {} by {:?}.", rate, addr, delay);
+				Box::new(reactor::Timeout::new(delay, &hp).unwrap().from_err())
+			} else {
+				Box::new(future::ok(()))
+			};
 
 			// accept the peer and add it to the server map
-			let accept = Peer::accept(conn, capab, total_diff, &hs.clone());
-			let added = add_to_peers(peers, adapter.clone(), accept);
+			let accept = wait
+				.and_then(move |_| accept_known(&tls1, conn))
+				.and_then(move |conn| Peer::accept(conn, capab, total_diff, services, &hs))
+				.and_then(move |(conn, peer)| {
+					secure(&tls, conn, peer.info.capabilities, None).map(|conn| (conn, peer))
+				});
+			let added = add_to_peers(peers,
+			                         waiters,
+			                         adapter.clone(),
+			                         churn.clone(),
+			                         max_diff.clone(),
+			                         services & own_services,
+			                         preferred.clone(),
+			                         class_limits.clone(),
+			                         evict,
+			                         accept);
 
-			// wire in a future to timeout the accept after 5 secs
-			let timed_peer = with_timeout(Box::new(added), &hp);
+			// wire in a future to timeout the accept
+			let timed = with_timeout(Box::new(added), handshake_timeout, &hp);
+			let timed_peer = timed.map_err(move |e| {
+				record_failure(&failures, &e);
+				score_handshake(&misbehavior, &restrictions2, terms, addr.ip(), &e, ban_score);
+				e
+			});
 
-			// run the main peer protocol
-			timed_peer.and_then(move |(conn, peer)| peer.clone().run(conn, adapter))
+			// the main peer protocol, to run once the handshake is done
+			let run_peer = timed_peer.map(move |(conn, peer)| -> PeerFuture {
+				// a duplicate of a peer we're already running, dropped
+				let conn = match conn {
+					Some(conn) => conn,
+					None => return Box::new(future::ok(())),
+				};
+				record_connected(&book, &peer.info);
+				record_local_ip(&local_ips, &conn);
+				if let (Ok(observer), Some(seen)) = (conn.peer_addr(), peer.info.observed_addr) {
+					record_observed(&observed, observer.ip(), seen);
+				}
+				let run = peer.run_throttled(conn,
+				                             adapter.clone(),
+				                             throttle,
+				                             block_pool,
+				                             traffic,
+				                             local);
+				Box::new(run.then(move |res| {
+					record_churn(&churn);
+					record_departure(&departed, &book, &peer.info);
+					record_session(&scheduler, &restrictions, terms, &peer);
+					if let Err(ref e) = res {
+						adapter.peer_error(&peer.info, e);
+						remove_errored(&peers2, &pruned, &peer);
+						if let Some(ban) = restrict_misbehaving(&restrictions, terms, &peer, e) {
+							adapter.peer_banned(peer.info.addr, &ban);
+						}
+					}
+					adapter.peer_disconnected(&peer.info);
+					res
+				}))
+			});
+			// a failed handshake shouldn't stop us from accepting other peers, the
+			// peer holds its own slot once added
+			Box::new(run_peer.then(move |res| -> Result<Result<PeerFuture, Error>, Error> {
+				drop(slot);
+				*handshakes.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+				Ok(res)
+			}))
 		});
 
-		// spawn each peer future to its own task
+		// bound the handshakes in progress, pausing acceptance when at the limit,
+		// and spawn each peer future to its own task
 		let hs = h.clone();
-		let server = peers.for_each(move |peer| {
-			hs.spawn(peer.then(|res| {
-				match res {
-					Err(e) => info!("Client error: {:?}", e),
-					_ => {}
+		let server = peers.buffer_unordered(max_handshakes).for_each(move |res| {
+			match res {
+				Ok(peer) => {
+					hs.spawn(peer.then(|res| {
+						match res {
+							Err(e) => info!("Client error: {:?}", e),
+							_ => {}
+						}
+						futures::finished(())
+					}))
 				}
-				futures::finished(())
-			}));
+				// peers resetting the connection during the handshake are common
+				Err(ref e) if e.is_transient() => debug!("Client handshake reset: {:?}", e),
+				Err(e) => info!("Client error: {:?}", e),
+			}
 			Ok(())
 		});
 
-		// setup the stopping oneshot on the server and join it with the peer future
+		let upkeep = self.upkeep(h.clone());
+		self.until_stopped(Box::new(server.select(upkeep).map(|_| ()).map_err(|(e, _)| e)), h)
+	}
+
+	// What runs along with the server, whether it accepts connections or not.
+	fn upkeep(&self, h: reactor::Handle) -> PeerFuture {
+		Box::new(self.clean_periodically()
+			.join3(self.ping_periodically(h), self.fluff_periodically())
+			.map(|_| ()))
+	}
+
+	// Regularly pings the peers due for it, if configured, adapting their
+	// interval to how promptly they answer and keeping the status of their
+	// chain their pongs carry.
+	fn ping_periodically(&self, h: reactor::Handle) -> PeerFuture {
+		if self.config.ping_interval_min_secs == 0 {
+			return Box::new(future::empty());
+		}
+		let peers = self.peers.clone();
+		let pings = self.pings.clone();
+		let adapter = self.adapter.clone();
+		let max_diff = Difficulty::max_with_bits(self.config.max_difficulty_bits);
+		let pinging = Timer::default()
+			.interval(Duration::from_secs(1))
+			.for_each(move |_| {
+				let now = Instant::now();
+				let ours = ChainStatus {
+					total_difficulty: adapter.total_difficulty(),
+					height: adapter.head_height(),
+				};
+				let connected = peers.read()
+					.unwrap_or_else(|e| e.into_inner())
+					.iter()
+					.filter(|p| p.is_connected())
+					.cloned()
+					.collect::<Vec<_>>();
+				let mut schedule = pings.lock().unwrap_or_else(|e| e.into_inner());
+				schedule.keep(&connected.iter().map(|p| p.info.id).collect::<Vec<_>>());
+				for p in connected {
+					let id = p.info.id;
+					if !schedule.due(id, now) {
+						continue;
+					}
+					let ping = match p.ping(ours.clone()) {
+						Ok(ping) => ping,
+						Err(e) => {
+							debug!("{} Failed to ping: {:?}", p.info.log_id, e);
+							continue;
+						}
+					};
+					schedule.sent(id, now);
+					let pings = pings.clone();
+					let max_diff = max_diff.clone();
+					h.spawn(ping.then(move |res| -> Result<(), ()> {
+						let mut schedule = pings.lock().unwrap_or_else(|e| e.into_inner());
+						match res {
+							Ok((rtt, status)) => {
+								schedule.pong(id, Instant::now());
+								let status = status.map(|s| {
+									ChainStatus {
+										total_difficulty: cap_difficulty(s.total_difficulty,
+										                                 &max_diff),
+										height: s.height,
+									}
+								});
+								p.pong_received(rtt, status);
+							}
+							Err(_) => schedule.missed(id),
+						}
+						Ok(())
+					}));
+				}
+				Ok(())
+			})
+			.from_err();
+		Box::new(pinging)
+	}
+
+	// Regularly prunes the peers we lost connection to, if configured. The
+	// banned ones are kept for the next clean_peers, whose caller may want to
+	// know about them.
+	fn clean_periodically(&self) -> PeerFuture {
+		let interval = self.config.clean_peers_interval_secs;
+		if interval == 0 {
+			return Box::new(future::empty());
+		}
+		let peers = self.peers.clone();
+		let pruned = self.pruned.clone();
+		let pings = self.pings.clone();
+		let max_missed = self.config.ping_max_missed;
+		let cleaning = throttle::interval(&Timer::default(), Duration::from_secs(interval))
+			.for_each(move |_| {
+				drop_unresponsive(&peers, &pings, max_missed);
+				let rm = prune_peers(&peers);
+				if !rm.is_empty() {
+					info!("Pruned {} peers we lost connection to.", rm.len());
+				}
+				let mut pruned = pruned.lock().unwrap_or_else(|e| e.into_inner());
+				pruned.extend(rm.into_iter().filter(|p| p.is_banned()));
+				let excess = pruned.len().saturating_sub(MAX_PRUNED_BANNED);
+				pruned.drain(..excess);
+				Ok(())
+			});
+		Box::new(cleaning)
+	}
+
+	// Regularly broadcasts the transactions we stemmed whose embargo ended,
+	// if dandelion is configured.
+	fn fluff_periodically(&self) -> PeerFuture {
+		if self.config.dandelion.is_none() {
+			return Box::new(future::empty());
+		}
+		let peers = self.peers.clone();
+		let embargoes = self.embargoes.clone();
+		let adapter = self.adapter.clone();
+		let fluffing = Timer::default()
+			.interval(Duration::from_secs(1))
+			.for_each(move |_| {
+				let fluffed = fluff_expired(&peers, &embargoes, adapter.as_ref());
+				if !fluffed.is_empty() {
+					debug!("Broadcast {} stemmed transactions at the end of their embargo.",
+					       fluffed.len());
+				}
+				Ok(())
+			})
+			.from_err();
+		Box::new(fluffing)
+	}
+
+	// Maps our listening port on the gateway from a thread of its own, the
+	// gateway taking its time to answer, if it does. The mapping gets renewed
+	// before it expires, then removed once we're stopped.
+	fn map_port(&self) {
+		let gateway = self.config.nat_gateway;
+		let port = self.config.port;
+		let mapped_addr = self.mapped_addr.clone();
+		let stopped = self.stopped.clone();
+		let res = thread::Builder::new().name("p2p-nat".to_string()).spawn(move || {
+			let mut mapping: Option<nat::PortMapping> = None;
+			while !stopped.load(Ordering::SeqCst) {
+				let lifetime = Duration::from_secs(PORT_MAPPING_SECS);
+				let renew = match nat::map_port(gateway, port, lifetime) {
+					Ok(m) => {
+						if mapping.as_ref().map(|prev| prev.external) != Some(m.external) {
+							info!("Port {} mapped on our gateway, reachable at {}.",
+							      port,
+							      m.external);
+						}
+						*mapped_addr.lock().unwrap_or_else(|e| e.into_inner()) = Some(m.external);
+						let renew = cmp::max(m.lifetime / 2,
+						                     Duration::from_secs(PORT_MAPPING_MIN_RENEW_SECS));
+						mapping = Some(m);
+						renew
+					}
+					Err(e) => {
+						warn!("Could not map port {} on our gateway: {}", port, e);
+						*mapped_addr.lock().unwrap_or_else(|e| e.into_inner()) = None;
+						Duration::from_secs(PORT_MAPPING_RETRY_SECS)
+					}
+				};
+				let since = Instant::now();
+				while since.elapsed() < renew && !stopped.load(Ordering::SeqCst) {
+					thread::sleep(Duration::from_secs(1));
+				}
+			}
+			*mapped_addr.lock().unwrap_or_else(|e| e.into_inner()) = None;
+			if let Some(m) = mapping {
+				if let Err(e) = nat::unmap_port(&m) {
+					debug!("Could not remove the mapping of port {}: {}", port, e);
+				}
+			}
+		});
+		if let Err(e) = res {
+			warn!("Could not start mapping port {}: {}", port, e);
+		}
+	}
+
+	// Sets up the stopping oneshot on the server and joins it with the provided
+	// future. Once stopped, resolves when all our peers are disconnected.
+	fn until_stopped(&self, fut: PeerFuture, h: reactor::Handle) -> PeerFuture {
 		let (stop, stop_rx) = futures::sync::oneshot::channel();
 		{
-			let mut stop_mut = self.stop.borrow_mut();
+			let mut stop_mut = self.stop.lock().unwrap_or_else(|e| e.into_inner());
 			*stop_mut = Some(stop);
 		}
-		Box::new(server.select(stop_rx.map_err(|_| Error::ConnectionClose)).then(|res| {
+		// stopped while starting
+		if self.stopped.load(Ordering::SeqCst) {
+			self.signal_stop();
+		}
+		let peers = self.peers.clone();
+		let flush = Duration::from_millis(self.config.close_flush_ms);
+		let stopped = self.stopped.clone();
+		let done = fut.select(stop_rx.map_err(|_| Error::ConnectionClose)).then(|res| {
 			match res {
 				Ok((_, _)) => Ok(()),
 				Err((e, _)) => Err(e),
 			}
+		});
+		Box::new(done.and_then(move |_| -> PeerFuture {
+			if stopped.load(Ordering::SeqCst) {
+				drain_peers(peers, flush, h)
+			} else {
+				Box::new(future::ok(()))
+			}
 		}))
 	}
 
+	// Completes the stopping oneshot, if still there.
+	fn signal_stop(&self) {
+		let stop = self.stop.lock().unwrap_or_else(|e| e.into_inner()).take();
+		if let Some(stop) = stop {
+			stop.complete(());
+		}
+	}
+
 	/// Asks the server to connect to a new peer.
 	pub fn connect_peer(&self,
 	                    addr: SocketAddr,
 	                    h: reactor::Handle)
 	                    -> Box<Future<Item = Option<Arc<Peer>>, Error = Error>> {
-		for p in self.peers.read().unwrap().deref() {
-			// if we're already connected to the addr, just return the peer
-			if p.info.addr == addr {
-				return Box::new(future::ok(Some((*p).clone())));
+		if self.stopped.load(Ordering::SeqCst) {
+			return Box::new(future::err(Error::ConnectionClose));
+		}
+		self.dialer().connect(addr, h)
+	}
+
+	/// Keeps us connected to the provided address, dialing it again whenever
+	/// the connection fails or drops. Failed dials are retried with an
+	/// exponential backoff, which starts over once a connection made it past
+	/// the handshake. Banned, disallowed and own addresses are given up on
+	/// right away. Stops along with the server.
+	pub fn maintain_connection(&self, addr: SocketAddr, h: reactor::Handle) {
+		let (cancel, cancelled) = oneshot::channel();
+		{
+			let mut cancels = self.dial_cancels.lock().unwrap_or_else(|e| e.into_inner());
+			cancels.retain(|tx| !tx.is_canceled());
+			cancels.push(cancel);
+		}
+		let backoff = RetryBackoff::new(&self.config);
+		let maintained = maintain(self.dialer(), addr, backoff, h.clone());
+		let stopped = cancelled.then(|_| -> Result<(), Error> { Ok(()) });
+		h.spawn(maintained.select(stopped).then(|_| Ok(())));
+	}
+
+	/// Tries to connect to the provided addresses one after the other, moving
+	/// on to the next one on failure, and resolves with the first peer we
+	/// connect to. Fails with the last error if none of them worked out.
+	/// With addresses of both families, the configured dial preference picks
+	/// the family tried first, or races them.
+	pub fn connect_any(&self,
+	                   addrs: Vec<SocketAddr>,
+	                   h: reactor::Handle)
+	                   -> Box<Future<Item = Arc<Peer>, Error = Error>> {
+		let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+		if v6.is_empty() || v4.is_empty() {
+			v6.append(&mut v4);
+			return self.connect_in_turn(v6, h);
+		}
+		match self.config.dial_preference {
+			DialPreference::PreferV4 => {
+				v4.append(&mut v6);
+				self.connect_in_turn(v4, h)
+			}
+			DialPreference::PreferV6 => {
+				v6.append(&mut v4);
+				self.connect_in_turn(v6, h)
+			}
+			DialPreference::HappyEyeballs => {
+				type Attempt = Box<Future<Item = Arc<Peer>, Error = Error>>;
+
+				let head_start = Duration::from_millis(HAPPY_EYEBALLS_DELAY_MS);
+				let preferred = self.connect_in_turn(v6, h.clone());
+				let fallback = self.connect_in_turn(v4, h.clone());
+				let timeout = reactor::Timeout::new(head_start, &h).unwrap();
+				let delayed = timeout.from_err().and_then(|_| fallback);
+				// whichever family connects first wins, the other one is dropped
+				// unless it's the one left to wait for
+				Box::new(preferred.select(delayed).then(|res| -> Attempt {
+					match res {
+						Ok((peer, _)) => Box::new(future::ok(peer)),
+						Err((e, other)) => {
+							debug!("Failed on one address family, waiting on the other: {:?}", e);
+							Box::new(other)
+						}
+					}
+				}))
 			}
 		}
-		// asked to connect to ourselves
-		if addr.ip() == self.config.host && addr.port() == self.config.port {
-			return Box::new(future::ok(None));
+	}
+
+	// Connects to the provided addresses in turn, see connect_any.
+	fn connect_in_turn(&self,
+	                   addrs: Vec<SocketAddr>,
+	                   h: reactor::Handle)
+	                   -> Box<Future<Item = Arc<Peer>, Error = Error>> {
+		type Attempts = Vec<Box<Future<Item = Option<Arc<Peer>>, Error = Error>>>;
+		type Next = Box<Future<Item = Loop<Arc<Peer>, (Attempts, Option<Error>)>, Error = Error>>;
+
+		// connecting only starts once the previous attempt is over, the next
+		// attempt is kept last
+		let attempts = addrs.iter()
+			.rev()
+			.map(|addr| self.connect_peer(*addr, h.clone()))
+			.collect::<Vec<_>>();
+		let first = future::loop_fn((attempts, None), |(mut attempts, last_err)| -> Next {
+			let attempt = match attempts.pop() {
+				Some(attempt) => attempt,
+				None => return Box::new(future::err(last_err.unwrap_or(Error::ConnectionClose))),
+			};
+			Box::new(attempt.then(move |res| -> Result<_, Error> {
+				match res {
+					Ok(Some(peer)) => Ok(Loop::Break(peer)),
+					// the address was our own
+					Ok(None) => Ok(Loop::Continue((attempts, Some(Error::SelfConnection)))),
+					Err(e) => {
+						debug!("Connection attempt failed, trying the next address: {:?}", e);
+						Ok(Loop::Continue((attempts, Some(e))))
+					}
+				}
+			}))
+		});
+		Box::new(first)
+	}
+
+	/// Connects to a peer synchronously, driving a short-lived reactor of its
+	/// own until the handshake completes or the timeout expires. Purely for
+	/// tooling and tests: the connection isn't driven anymore once this
+	/// returns, so the peer is only good to report on and isn't kept in our
+	/// peer list. Must never be called from within the main event loop.
+	pub fn connect_peer_blocking(&self,
+	                             addr: SocketAddr,
+	                             timeout: Duration)
+	                             -> Result<Arc<Peer>, Error> {
+		if let Some(p) = self.read_peers().iter().find(|p| p.info.addr == addr) {
+			return Ok(p.clone());
 		}
-		let peers = self.peers.clone();
-		let adapter1 = self.adapter.clone();
-		let adapter2 = self.adapter.clone();
-		let capab = self.capabilities.clone();
-		let self_addr = SocketAddr::new(self.config.host, self.config.port);
 
-		debug!("{} connecting to {}", self_addr, addr);
+		let mut core = reactor::Core::new().map_err(Error::Connection)?;
+		let h = core.handle();
+		let timer = reactor::Timeout::new(timeout, &h)
+			.map_err(Error::Connection)?
+			.from_err()
+			.and_then(|_| Err(Error::Timeout));
+		let connect = self.connect_peer(addr, h).select(timer).map(|(p, _)| p).map_err(|(e, _)| e);
 
-		let socket = TcpStream::connect(&addr, &h).map_err(|e| Error::Connection(e));
-		let h2 = h.clone();
-		let request = socket.and_then(move |socket| {
-				let peers = peers.clone();
-				let total_diff = adapter1.clone().total_difficulty();
-
-				// connect to the peer and add it to the server map, wiring it a timeout for
-				// the handhake
-				let connect =
-					Peer::connect(socket, capab, total_diff, self_addr, &Handshake::new());
-				let added = add_to_peers(peers, adapter1, connect);
-				with_timeout(Box::new(added), &h)
-			})
-			.and_then(move |(socket, peer)| {
-				h2.spawn(peer.run(socket, adapter2).map_err(|e| {
-					error!("Peer error: {:?}", e);
-					()
-				}));
-				Ok(Some(peer))
-			});
-		Box::new(request)
+		match core.run(connect)? {
+			Some(peer) => {
+				self.write_peers().retain(|p| p.info.addr != peer.info.addr);
+				Ok(peer)
+			}
+			None => Err(Error::SelfConnection),
+		}
 	}
 
 	/// Have the server iterate over its peer list and prune all peers we have
 	/// lost connection to or have been deemed problematic. The removed peers
-	/// are returned.
+	/// are returned, along with the banned peers pruned automatically since
+	/// the last call. Quarantines that ran out get forgotten as well, and
+	/// peers that missed too many pongs in a row get disconnected, to be
+	/// pruned once their connection is closed.
 	pub fn clean_peers(&self) -> Vec<Arc<Peer>> {
-		let mut peers = self.peers.write().unwrap();
+		self.restrictions().forget_expired(Instant::now());
+		drop_unresponsive(&self.peers, &self.pings, self.config.ping_max_missed);
+		let mut pruned = self.pruned.lock().unwrap_or_else(|e| e.into_inner());
+		let mut rm = pruned.drain(..).collect::<Vec<_>>();
+		drop(pruned);
+		rm.extend(prune_peers(&self.peers));
+		rm
+	}
 
-		let (keep, rm) = peers.iter().fold((vec![], vec![]), |mut acc, ref p| {
-			if p.clone().is_connected() {
-				acc.0.push((*p).clone());
-			} else {
-				acc.1.push((*p).clone());
+	/// Snapshot of all the peers we're currently connected to.
+	pub fn connected_peers(&self) -> Vec<Arc<Peer>> {
+		let peers = self.read_peers();
+		peers.iter().filter(|p| p.is_connected()).map(|p| p.clone()).collect()
+	}
+
+	/// Calls the provided function with each of the peers we're currently
+	/// connected to, without collecting them first. Our peers stay locked for
+	/// reading in the meantime, so the function shouldn't take long nor call
+	/// back to the server to change its peers.
+	pub fn for_each_peer<F>(&self, mut f: F)
+		where F: FnMut(&Arc<Peer>)
+	{
+		for p in self.read_peers().iter().filter(|p| p.is_connected()) {
+			f(p);
+		}
+	}
+
+	/// Disconnects from the peer at the provided address, returns whether we
+	/// were connected to it. Unless forced, the messages already queued for
+	/// the peer get to go out first, for up to the configured flush time.
+	pub fn disconnect_peer(&self, addr: SocketAddr, force: bool) -> bool {
+		let peers = self.read_peers();
+		match peers.iter().find(|p| p.info.addr == addr) {
+			Some(p) if force => {
+				p.stop();
+				true
 			}
-			acc
-		});
-		*peers = keep;
-		rm
+			Some(p) => {
+				p.stop_gracefully(Duration::from_millis(self.config.close_flush_ms));
+				true
+			}
+			None => false,
+		}
 	}
 
-	/// Returns the peer with the most worked branch, showing the highest total
-	/// difficulty.
-	pub fn most_work_peer(&self) -> Option<Arc<Peer>> {
-		let peers = self.peers.read().unwrap();
-		if peers.len() == 0 {
-			return None;
+	/// Bans or quarantines the host of the provided address, disconnecting
+	/// from the peers it connected from. The host is the one the connection
+	/// of the peer at that address comes from, whatever address it
+	/// advertises, the IP of the address itself if we're not connected to
+	/// it. A quarantine and a ban are lifted after their configured time,
+	/// a ban lasting until lifted by hand if configured so. The reason is
+	/// listed along with the ban. Returns whether we were connected to the
+	/// peer.
+	pub fn ban_peer(&self, addr: SocketAddr, severity: Severity, reason: &str) -> bool {
+		let peer = self.read_peers().iter().find(|p| p.info.addr == addr).cloned();
+		let ip = peer.as_ref().map(|p| remote_ip(&p.info)).unwrap_or(addr.ip());
+		let (ban, evicted) = self.restrictions()
+			.restrict(ip, severity, reason, self.terms(), Instant::now());
+		if let Some(evicted) = evicted {
+			self.adapter.peer_unbanned(evicted);
 		}
-		let mut res = peers[0].clone();
-		for p in peers.deref() {
-			if p.is_connected() && res.info.total_difficulty < p.info.total_difficulty {
-				res = (*p).clone();
+		self.adapter.peer_banned(addr, &ban);
+		for p in self.read_peers().iter().filter(|p| remote_ip(&p.info) == ip) {
+			match severity {
+				Severity::Ban => p.ban(),
+				Severity::Quarantine => p.quarantine(),
 			}
 		}
-		Some(res)
+		peer.is_some()
 	}
 
-	/// Returns a random peer we're connected to.
-	pub fn random_peer(&self) -> Option<Arc<Peer>> {
-		let peers = self.peers.read().unwrap();
-		if peers.len() == 0 {
-			None
-		} else {
-			let idx = rand::thread_rng().gen_range(0, peers.len());
-			Some(peers[idx].clone())
+	/// Restores a ban or quarantine of the provided host from before we
+	/// restarted, as reported to the adapter through peer_banned, lifted
+	/// once the provided time is over if any.
+	pub fn restore_ban(&self,
+	                   ip: IpAddr,
+	                   severity: Severity,
+	                   reason: &str,
+	                   expires_in: Option<Duration>) {
+		let evicted = self.restrictions()
+			.restore(ip, severity, reason, expires_in, Instant::now());
+		if let Some(evicted) = evicted {
+			self.adapter.peer_unbanned(evicted);
 		}
 	}
 
-	/// Broadcasts the provided block to all our peers. A peer implementation
-	/// may drop the broadcast request if it knows the remote peer already has
-	/// the block.
-	pub fn broadcast_block(&self, b: &core::Block) {
-		let peers = self.peers.write().unwrap();
-		for p in peers.deref() {
-			if p.is_connected() {
-				if let Err(e) = p.send_block(b) {
-					debug!("Error sending block to peer: {:?}", e);
-				}
+	/// Everything we know about the peer at the provided address: whether
+	/// we're connected to it, banned its host, or got disconnected from it.
+	pub fn find_peer(&self, addr: &SocketAddr) -> PeerLookup {
+		if let Some(p) = self.get_peer(*addr) {
+			return PeerLookup::Connected(p);
+		}
+		let restriction = self.restrictions().get(&addr.ip(), Instant::now());
+		if let Some(r) = restriction {
+			return PeerLookup::Banned {
+				severity: r.severity,
+				since: r.since.elapsed(),
+			};
+		}
+		let departed = self.departed.lock().unwrap_or_else(|e| e.into_inner());
+		match departed.get(addr) {
+			Some(seen) if seen.elapsed() < Duration::from_secs(DEPARTED_SECS) => {
+				PeerLookup::Disconnected { last_seen: seen.elapsed() }
 			}
+			_ => PeerLookup::Unknown,
 		}
 	}
 
-	/// Number of peers we're currently connected to.
-	pub fn peer_count(&self) -> u32 {
-		self.peers.read().unwrap().len() as u32
+	/// Captures the peers we're currently connected to, to compare with a
+	/// later snapshot.
+	pub fn peer_snapshot(&self) -> PeerSnapshot {
+		let mut peers = self.connected_peers()
+			.iter()
+			.map(|p| {
+				SnapshotPeer {
+					id: p.info.id,
+					addr: p.info.addr,
+					connected_at: p.connected_at(),
+				}
+			})
+			.collect::<Vec<_>>();
+		peers.sort_by_key(|p| p.id);
+		PeerSnapshot {
+			taken_at: Instant::now(),
+			peers: peers,
+		}
 	}
 
-	/// Stops the server. Disconnect from all peers at the same time.
-	pub fn stop(self) {
-		let peers = self.peers.write().unwrap();
-		for p in peers.deref() {
-			p.stop();
-		}
-		self.stop.into_inner().unwrap().complete(());
+	/// Traffic and uptime of each of our connected peers, by increasing id,
+	/// to tell which ones are doing the work.
+	pub fn peer_stats(&self) -> Vec<PeerStats> {
+		let mut stats = self.connected_peers().iter().map(|p| peer_stats(p)).collect::<Vec<_>>();
+		stats.sort_by_key(|s| s.id);
+		stats
 	}
-}
 
-// Adds the peer built by the provided future in the peers map
-fn add_to_peers<A>(peers: Arc<RwLock<Vec<Arc<Peer>>>>,
-                   adapter: Arc<NetAdapter>,
-                   peer_fut: A)
-                   -> Box<Future<Item = Result<(TcpStream, Arc<Peer>), ()>, Error = Error>>
-	where A: IntoFuture<Item = (TcpStream, Peer), Error = Error> + 'static
-{
-	let peer_add = peer_fut.into_future().map(move |(conn, peer)| {
-		adapter.peer_connected(&peer.info);
-		let apeer = Arc::new(peer);
-		let mut peers = peers.write().unwrap();
-		peers.push(apeer.clone());
-		Ok((conn, apeer))
-	});
-	Box::new(peer_add)
-}
+	/// What the peer at the provided address did so far, if we're connected
+	/// to it.
+	pub fn peer_info(&self, addr: &SocketAddr) -> Option<PeerStats> {
+		self.get_peer(*addr).map(|p| peer_stats(&p))
+	}
 
-// Adds a timeout to a future
-fn with_timeout<T: 'static>(fut: Box<Future<Item = Result<T, ()>, Error = Error>>,
-                            h: &reactor::Handle)
-                            -> Box<Future<Item = T, Error = Error>> {
-	let timeout = reactor::Timeout::new(Duration::new(5, 0), h).unwrap();
-	let timed = fut.select(timeout.map(Err).from_err())
-		.then(|res| {
-			match res {
-				Ok((Ok(inner), _timeout)) => Ok(inner),
-				Ok((_, _accept)) => Err(Error::Timeout),
-				Err((e, _other)) => Err(e),
+	/// The peer at the provided address, if we're connected to it.
+	pub fn get_peer(&self, addr: SocketAddr) -> Option<Arc<Peer>> {
+		connected_peer(&self.peers, addr)
+	}
+
+	/// The peer with the provided id, if we're still connected to it.
+	pub fn peer_by_id(&self, id: PeerId) -> Option<Arc<Peer>> {
+		self.read_peers().iter().find(|p| p.is_connected() && p.info.id == id).cloned()
+	}
+
+	/// Our best guess at the address peers can reach us at: the one our
+	/// gateway forwards to us if we mapped our port on it, else the one most
+	/// of the hosts we're connected with told they see us at. Can differ
+	/// from the address we listen on, behind a NAT for example.
+	pub fn public_addr(&self) -> Option<SocketAddr> {
+		public_addr(&self.observed_addrs, &self.mapped_addr)
+	}
+
+	/// Whether many of the peers we connected with lately had their clock off
+	/// ours the same way, in which case our own clock is likely off and
+	/// skewed peers get accepted anyway.
+	pub fn clock_suspect(&self) -> bool {
+		self.handshake.clock_suspect()
+	}
+
+	/// Whether dialing the provided address would get us back to ourselves:
+	/// it's our public address, one we listen on, or the port of a listener
+	/// bound to all interfaces on one of our IPs.
+	pub fn is_own_addr(&self, addr: &SocketAddr) -> bool {
+		own_addr(&self.config, &self.observed_addrs, &self.mapped_addr, &self.local_ips, addr)
+	}
+
+	/// Leaves out of the provided dial candidates those that are ourselves,
+	/// so they're not even tried.
+	pub fn dial_candidates(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+		addrs.into_iter().filter(|addr| !self.is_own_addr(addr)).collect()
+	}
+
+	/// Addresses to bootstrap from: the provided seeds followed by those the
+	/// adapter supplies, without duplicates nor ourselves.
+	pub fn bootstrap_candidates(&self, seeds: Vec<SocketAddr>) -> Vec<SocketAddr> {
+		let mut addrs = vec![];
+		for addr in seeds.into_iter().chain(self.adapter.bootstrap_addrs()) {
+			if !addrs.contains(&addr) {
+				addrs.push(addr);
 			}
-		});
-	Box::new(timed)
+		}
+		self.dial_candidates(addrs)
+	}
+
+	/// Number of connected inbound peers in each capability class with an
+	/// inbound limit, in the configured order.
+	pub fn capability_counts(&self) -> Vec<(Capabilities, u32)> {
+		let peers = self.read_peers();
+		self.config
+			.inbound_capability_limits
+			.iter()
+			.map(|&(class, _)| (class, class_count(&peers, class)))
+			.collect()
+	}
+
+	/// Number of distinct subnets our connected outbound peers are in.
+	pub fn outbound_subnets(&self) -> u32 {
+		let subnets = outbound_by_subnet(&self.read_peers());
+		subnets.len() as u32
+	}
+
+	/// Whether our outbound peers come from fewer distinct subnets than
+	/// configured, leaving us easier to eclipse.
+	pub fn lacks_subnet_diversity(&self) -> bool {
+		self.outbound_subnets() < self.config.min_outbound_subnets
+	}
+
+	/// How many more peers to dial to make up for the subnets our outbound
+	/// peers lack, leaving out dials already in flight. A clustered node
+	/// only needs one new peer per missing subnet, not a whole new set.
+	pub fn diversity_dials(&self) -> usize {
+		let missing = self.config.min_outbound_subnets.saturating_sub(self.outbound_subnets());
+		(missing as usize).saturating_sub(self.dials_in_progress())
+	}
+
+	/// Orders the provided dial candidates so those in subnets with the
+	/// fewest of our outbound peers come first, spreading the candidates of
+	/// a same subnet out. Our own addresses are left out.
+	pub fn diverse_candidates(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+		let counts = outbound_by_subnet(&self.read_peers());
+		schedule::by_diversity(self.dial_candidates(addrs), counts)
+	}
+
+	/// Of the provided dial candidates, one per subnet none of our outbound
+	/// peers are in, to improve diversity without dialing more of the same.
+	pub fn new_subnet_candidates(&self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+		let counts = outbound_by_subnet(&self.read_peers());
+		schedule::in_new_subnets(self.dial_candidates(addrs), counts)
+	}
+
+	/// What the dial scheduler would do now with the provided candidates:
+	/// dial up to max of them, the most diverse first and paced apart, and
+	/// leave alone those we back off from. Our own addresses are left out.
+	pub fn plan_dials(&self, addrs: Vec<SocketAddr>, max: usize) -> Vec<DialAction> {
+		let counts = outbound_by_subnet(&self.read_peers());
+		let scheduler = self.scheduler.lock().unwrap_or_else(|e| e.into_inner());
+		scheduler.plan(self.dial_candidates(addrs), &counts, max, Instant::now())
+	}
+
+	/// The hosts currently banned or quarantined, most recently restricted
+	/// first. Restrictions that ran out are left out, and forgotten.
+	pub fn list_bans(&self) -> Vec<BanEntry> {
+		self.restrictions().entries(Instant::now())
+	}
+
+	/// Whether the host of the provided address is still banned or
+	/// quarantined, so it's neither accepted nor dialed.
+	pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+		is_restricted(&self.restrictions, &addr.ip())
+	}
+
+	/// Lifts the ban or quarantine of the provided host, which can connect
+	/// again and be connected to. Returns whether it was restricted.
+	pub fn unban(&self, ip: IpAddr) -> bool {
+		let restricted = self.restrictions().remove(&ip, Instant::now());
+		self.misbehavior.lock().unwrap_or_else(|e| e.into_inner()).remove(&ip);
+		self.adapter.peer_unbanned(ip);
+		restricted
+	}
+
+	/// Misbehavior score of the peer at the provided address: that of its
+	/// host's handshakes we couldn't decode, plus that of its protocol
+	/// violations if we're connected to it. It gets quarantined once the
+	/// configured ban score is reached.
+	pub fn misbehavior_score(&self, addr: &SocketAddr) -> u32 {
+		let handshakes = self.misbehavior
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.get(&addr.ip(), Instant::now());
+		handshakes + self.get_peer(*addr).map(|p| p.misbehavior_score()).unwrap_or(0)
+	}
+
+	/// Asks all our peers for checkpoints along their chain and hands those a
+	/// majority of them agree on to the adapter, resolving to them as well.
+	/// The peers disagreeing with an agreed checkpoint get flagged.
+	pub fn collect_checkpoints(&self) -> Box<Future<Item = Vec<Checkpoint>, Error = Error>> {
+		let requests = self.connected_peers()
+			.into_iter()
+			.filter_map(|p| {
+				let req = match p.request_checkpoints() {
+					Ok(req) => req,
+					Err(e) => {
+						debug!("{} Could not ask for checkpoints: {:?}", p.info.log_id, e);
+						return None;
+					}
+				};
+				// a peer not answering just doesn't get a say
+				Some(req.then(move |res| -> Result<_, Error> { Ok((p, res.unwrap_or(vec![]))) }))
+			})
+			.collect::<Vec<_>>();
+
+		let adapter = self.adapter.clone();
+		Box::new(future::join_all(requests).map(move |reports| {
+			let by_addr = reports.iter()
+				.map(|&(ref p, ref cps)| (p.info.addr, cps.clone()))
+				.collect::<Vec<_>>();
+			let (agreed, conflicting) = majority_checkpoints(&by_addr);
+			for &(ref p, _) in &reports {
+				if conflicting.contains(&p.info.addr) {
+					warn!("{} Reported checkpoints disagreeing with most peers.", p.info.log_id);
+					p.flag_conflicting();
+				}
+			}
+			if !agreed.is_empty() {
+				adapter.checkpoints_agreed(agreed.clone());
+			}
+			agreed
+		}))
+	}
+
+	/// Returns the peer with the most worked branch, showing the highest total
+	/// difficulty.
+	pub fn most_work_peer(&self) -> Option<Arc<Peer>> {
+		self.most_work_peers(1).into_iter().next()
+	}
+
+	/// Up to count of our connected peers, the most worked first, to spread
+	/// sync requests over them or fail over to the next one. Peers whose
+	/// difficulty hasn't increased for a while come after the fresher ones.
+	pub fn most_work_peers(&self, count: usize) -> Vec<Arc<Peer>> {
+		let mut peers = self.rank_by_work(self.connected_peers());
+		peers.truncate(count);
+		peers
+	}
+
+	/// Hash and total difficulty of the tip of the peer with the most worked
+	/// branch, as far as its blocks and headers showed. None if that peer
+	/// hasn't sent any yet.
+	pub fn best_tip(&self) -> Option<(Hash, Difficulty)> {
+		self.most_work_peer().and_then(|p| p.tip())
+	}
+
+	/// How far behind we are, comparing our total difficulty to the one of
+	/// the peer we sync with, or the highest our peers advertise when not
+	/// syncing with any.
+	pub fn sync_status(&self) -> SyncStatus {
+		let ours = self.adapter.total_difficulty();
+		let sync_peer = *self.sync_peer.lock().unwrap_or_else(|e| e.into_inner());
+		let peer = sync_peer.and_then(|addr| self.get_peer(addr)).or_else(|| self.most_work_peer());
+		let theirs = match peer {
+			Some(p) => p.total_difficulty(),
+			None => return SyncStatus::NoPeers,
+		};
+		if theirs <= ours {
+			return SyncStatus::Synced;
+		}
+		SyncStatus::Syncing {
+			progress: difficulty_ratio(&ours, &theirs),
+			behind_by: Difficulty::from_biguint(theirs.into_biguint() - ours.into_biguint()),
+		}
+	}
+
+	/// Bytes sent to and received from all our peers since we started,
+	/// including the peers we're not connected to anymore.
+	pub fn traffic_totals(&self) -> (u64, u64) {
+		self.traffic.totals()
+	}
+
+	/// Zeroes the traffic totals along with the counters of all our peers,
+	/// to measure from now on. Our peers stay locked meanwhile so none comes
+	/// or goes with its counters left out.
+	pub fn reset_stats(&self) {
+		let peers = self.write_peers();
+		self.traffic.reset();
+		for p in peers.iter() {
+			p.reset_stats();
+		}
+	}
+
+	/// Same as most_work_peer, passing over the peers at the provided
+	/// addresses, like those already busy with or failing our requests.
+	pub fn most_work_peer_excluding(&self, exclude: &HashSet<SocketAddr>) -> Option<Arc<Peer>> {
+		let peers = self.connected_peers()
+			.into_iter()
+			.filter(|p| !exclude.contains(&p.info.addr))
+			.collect();
+		self.most_work_among(peers)
+	}
+
+	/// Number of inbound handshakes currently in progress.
+	pub fn handshakes_in_progress(&self) -> usize {
+		*self.handshakes.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Number of outbound dials currently in flight, from opening the
+	/// connection to the end of the handshake.
+	pub fn dials_in_progress(&self) -> usize {
+		self.dials.lock().unwrap_or_else(|e| e.into_inner()).in_flight
+	}
+
+	/// Returns a random peer we're connected to.
+	pub fn random_peer(&self) -> Option<Arc<Peer>> {
+		let peers = self.read_peers();
+		if peers.len() == 0 {
+			None
+		} else {
+			let idx = rand::thread_rng().gen_range(0, peers.len());
+			Some(peers[idx].clone())
+		}
+	}
+
+	/// Returns the connected peers offering all the provided services.
+	pub fn peers_offering(&self, needed: Services) -> Vec<Arc<Peer>> {
+		self.connected_peers().into_iter().filter(|p| p.info.services.offers(needed)).collect()
+	}
+
+	/// Same as most_work_peer, only considering peers offering all the
+	/// provided services.
+	pub fn most_work_peer_offering(&self, needed: Services) -> Option<Arc<Peer>> {
+		self.most_work_among(self.peers_offering(needed))
+	}
+
+	/// Returns the connected peers advertising all the provided capabilities.
+	pub fn peers_with_capabilities(&self, capab: Capabilities) -> Vec<Arc<Peer>> {
+		self.connected_peers().into_iter().filter(|p| p.info.capabilities.contains(capab)).collect()
+	}
+
+	/// Returns the connected peers we negotiated at least the provided
+	/// protocol version with, for requests only those support. What a peer
+	/// advertises past the version we speak with it doesn't count.
+	pub fn peers_with_min_version(&self, v: u32) -> Vec<Arc<Peer>> {
+		self.connected_peers().into_iter().filter(|p| p.info.negotiated_version >= v).collect()
+	}
+
+	/// Same as most_work_peer, only considering peers we negotiated at least
+	/// the provided protocol version with.
+	pub fn most_work_peer_with_min_version(&self, v: u32) -> Option<Arc<Peer>> {
+		self.most_work_among(self.peers_with_min_version(v))
+	}
+
+	// Peer with the highest total difficulty, passing over those whose
+	// difficulty hasn't increased for a while if there are fresher ones so a
+	// stuck peer can't monopolize sync.
+	fn most_work_among(&self, peers: Vec<Arc<Peer>>) -> Option<Arc<Peer>> {
+		self.rank_by_work(peers).into_iter().next()
+	}
+
+	// Orders the peers by decreasing total difficulty, those whose difficulty
+	// hasn't increased for a while last. Peers on a fork the checkpoints of
+	// most others disagree with are left out.
+	fn rank_by_work(&self, peers: Vec<Arc<Peer>>) -> Vec<Arc<Peer>> {
+		let window = Duration::from_secs(self.runtime().stale_difficulty_secs);
+		let (mut fresh, mut stale): (Vec<_>, Vec<_>) = peers.into_iter()
+			.filter(|p| !p.is_conflicting())
+			.partition(|p| window == Duration::from_secs(0) || p.difficulty_stale_for() <= window);
+		fresh.sort_by(|a, b| b.total_difficulty().cmp(&a.total_difficulty()));
+		stale.sort_by(|a, b| b.total_difficulty().cmp(&a.total_difficulty()));
+		fresh.extend(stale);
+		fresh
+	}
+
+	/// Same as random_peer, only considering peers offering all the provided
+	/// services.
+	pub fn random_peer_offering(&self, needed: Services) -> Option<Arc<Peer>> {
+		let peers = self.peers_offering(needed);
+		if peers.len() == 0 {
+			None
+		} else {
+			let idx = rand::thread_rng().gen_range(0, peers.len());
+			Some(peers[idx].clone())
+		}
+	}
+
+	/// Same as random_peer_offering, only considering peers with fewer block
+	/// requests in flight than the configured maximum, to download blocks
+	/// from.
+	pub fn random_peer_for_blocks(&self, needed: Services) -> Option<Arc<Peer>> {
+		self.random_peer_for_blocks_excluding(needed, &HashSet::new())
+	}
+
+	/// Same as random_peer_for_blocks, passing over the peers at the provided
+	/// addresses, like those a block request to already timed out.
+	pub fn random_peer_for_blocks_excluding(&self,
+	                                        needed: Services,
+	                                        exclude: &HashSet<SocketAddr>)
+	                                        -> Option<Arc<Peer>> {
+		let max = self.config.max_block_requests as usize;
+		let peers = self.peers_offering(needed)
+			.into_iter()
+			.filter(|p| !exclude.contains(&p.info.addr))
+			.filter(|p| max == 0 || p.blocks_in_flight() < max)
+			.collect::<Vec<_>>();
+		if peers.len() == 0 {
+			None
+		} else {
+			let idx = rand::thread_rng().gen_range(0, peers.len());
+			Some(peers[idx].clone())
+		}
+	}
+
+	/// Broadcasts the provided block to our peers, all of them or the
+	/// configured fanout. A peer implementation may drop the broadcast request
+	/// if it knows the remote peer already has the block, the returned stats
+	/// tell how many did. Peers that negotiated it get the block compact.
+	pub fn broadcast_block(&self, b: &core::Block) -> BroadcastStats {
+		let mut stats = BroadcastStats::default();
+		for p in self.broadcast_targets(&mut stats, true) {
+			match p.send_block(b) {
+				SendOutcome::Sent => stats.sent += 1,
+				SendOutcome::SkippedAlreadyHave => stats.skipped += 1,
+				SendOutcome::Failed(e) => {
+					debug!("{} Error sending block: {:?}", p.info.log_id, e);
+					stats.failed += 1;
+				}
+			}
+		}
+		debug!("Broadcast block {}: {:?}", b.hash(), stats);
+		stats
+	}
+
+	/// Announces a new block to our peers with just its header, skipping
+	/// those known to have the block. Peers missing the block then ask for
+	/// it, sparing a full block to those that already have it.
+	pub fn broadcast_header(&self, bh: &core::BlockHeader) -> BroadcastStats {
+		let mut stats = BroadcastStats::default();
+		for p in self.broadcast_targets(&mut stats, true) {
+			match p.send_header(bh) {
+				SendOutcome::Sent => stats.sent += 1,
+				SendOutcome::SkippedAlreadyHave => stats.skipped += 1,
+				SendOutcome::Failed(e) => {
+					debug!("{} Error sending header: {:?}", p.info.log_id, e);
+					stats.failed += 1;
+				}
+			}
+		}
+		debug!("Broadcast header {}: {:?}", bh.hash(), stats);
+		stats
+	}
+
+	/// Relays the provided transaction to our peers, skipping those known to
+	/// have it already. Nothing gets relayed in sync mode.
+	pub fn broadcast_transaction(&self, tx: &core::Transaction) -> BroadcastStats {
+		let mut stats = BroadcastStats::default();
+		if self.sync_mode() {
+			debug!("Not relaying transaction {} while syncing.", tx.hash());
+			return stats;
+		}
+		for p in self.broadcast_targets(&mut stats, false) {
+			match p.send_transaction(tx) {
+				SendOutcome::Sent => stats.sent += 1,
+				SendOutcome::SkippedAlreadyHave => stats.skipped += 1,
+				SendOutcome::Failed(e) => {
+					debug!("{} Error sending transaction: {:?}", p.info.log_id, e);
+					stats.failed += 1;
+				}
+			}
+		}
+		debug!("Broadcast transaction {}: {:?}", tx.hash(), stats);
+		stats
+	}
+
+	/// Relays a new transaction of ours along the dandelion stem, hiding
+	/// where it comes from, or broadcasts it if dandelion isn't configured.
+	/// Anything going wrong along the stem falls back to a broadcast. Queued
+	/// in sync mode, to be relayed once done syncing.
+	pub fn stem_transaction(&self, tx: &core::Transaction) -> BroadcastStats {
+		if self.queue_while_syncing(tx, false) {
+			return BroadcastStats::default();
+		}
+		self.stem_or_fluff(tx, false)
+	}
+
+	/// Broadcasts a new transaction of ours right away, skipping the
+	/// dandelion stem. Queued in sync mode, to be broadcast once done syncing.
+	pub fn fluff_transaction(&self, tx: &core::Transaction) -> BroadcastStats {
+		if self.queue_while_syncing(tx, true) {
+			return BroadcastStats::default();
+		}
+		self.broadcast_transaction(tx)
+	}
+
+	// Queues a transaction of ours in sync mode, dropping the oldest one
+	// queued when too many are. Tells whether it got queued.
+	fn queue_while_syncing(&self, tx: &core::Transaction, fluff: bool) -> bool {
+		if !self.sync_mode() {
+			return false;
+		}
+		let mut queued = self.queued_txs.lock().unwrap_or_else(|e| e.into_inner());
+		if queued.len() >= MAX_QUEUED_TXS {
+			if let Some((old, _)) = queued.pop_front() {
+				debug!("Dropping queued transaction {}, too many queued.", old.hash());
+			}
+		}
+		debug!("Queueing transaction {} until done syncing.", tx.hash());
+		queued.push_back((tx.clone(), fluff));
+		true
+	}
+
+	// Relays our transactions queued while in sync mode.
+	fn relay_queued(&self) {
+		let queued = self.queued_txs
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.drain(..)
+			.collect::<Vec<_>>();
+		for (tx, fluff) in queued {
+			if fluff {
+				self.broadcast_transaction(&tx);
+			} else {
+				self.stem_or_fluff(&tx, false);
+			}
+		}
+	}
+
+	/// Passes on a transaction stemmed to us by a peer, along our own stem or,
+	/// with the configured fluff chance, broadcasting it.
+	pub fn relay_stem_transaction(&self, tx: &core::Transaction) -> BroadcastStats {
+		let fluff = match self.config.dandelion {
+			Some(ref d) => rand::thread_rng().gen_range(0, 100) < d.fluff_percent,
+			None => true,
+		};
+		self.stem_or_fluff(tx, fluff)
+	}
+
+	fn stem_or_fluff(&self, tx: &core::Transaction, fluff: bool) -> BroadcastStats {
+		let d = match self.config.dandelion {
+			Some(ref d) if !fluff => d.clone(),
+			_ => return self.fluff(tx),
+		};
+		let h = tx.hash();
+		if self.sync_mode() {
+			debug!("Not relaying transaction {} while syncing.", h);
+			return BroadcastStats::default();
+		}
+		let p = match self.stem_peer(&d) {
+			Some(p) => p,
+			None => {
+				debug!("No peer to stem transaction {} to, broadcasting it.", h);
+				return self.fluff(tx);
+			}
+		};
+		let mut stats = BroadcastStats::default();
+		match p.send_stem_transaction(tx) {
+			SendOutcome::Sent => stats.sent += 1,
+			// the stem looped back, time to broadcast
+			SendOutcome::SkippedAlreadyHave => return self.fluff(tx),
+			SendOutcome::Failed(e) => {
+				debug!("{} Error stemming transaction: {:?}", p.info.log_id, e);
+				return self.fluff(tx);
+			}
+		}
+		// the peers along the stem know of the transaction, any other one
+		// having it then means it got broadcast
+		let through = self.connected_peers()
+			.into_iter()
+			.filter(|p| p.knows_transaction(h))
+			.map(|p| p.info.addr)
+			.collect();
+		let wait = rand::thread_rng().gen_range(d.embargo_secs, 2 * d.embargo_secs + 1);
+		let embargo = Embargo {
+			tx: tx.clone(),
+			until: Instant::now() + Duration::from_secs(wait),
+			through: through,
+		};
+		self.embargoes.lock().unwrap_or_else(|e| e.into_inner()).insert(h, embargo);
+		debug!("{} Stemmed transaction {}, embargo of {}s.", p.info.log_id, h, wait);
+		stats
+	}
+
+	// Broadcasts a transaction at the end of its stem, letting the adapter
+	// know it's not to be kept from the public anymore.
+	fn fluff(&self, tx: &core::Transaction) -> BroadcastStats {
+		self.adapter.transaction_fluffed(tx);
+		self.broadcast_transaction(tx)
+	}
+
+	// The peer our dandelion stem goes through, picked at random among our
+	// outbound peers that negotiated it. Picked again once the epoch ends or
+	// if we lost it.
+	fn stem_peer(&self, d: &DandelionConfig) -> Option<Arc<Peer>> {
+		let mut stem = self.stem.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some((addr, since)) = *stem {
+			if since.elapsed() < Duration::from_secs(d.epoch_secs) {
+				if let Some(p) = connected_peer(&self.peers, addr) {
+					return Some(p);
+				}
+			}
+		}
+		let peers = self.connected_peers()
+			.into_iter()
+			.filter(|p| p.info.direction == Direction::Outbound)
+			.filter(|p| p.info.features.contains(DANDELION))
+			.collect::<Vec<_>>();
+		if peers.is_empty() {
+			*stem = None;
+			return None;
+		}
+		let p = peers[rand::thread_rng().gen_range(0, peers.len())].clone();
+		debug!("{} New dandelion stem.", p.info.log_id);
+		*stem = Some((p.info.addr, Instant::now()));
+		Some(p)
+	}
+
+	// The connected peers a broadcast goes to, counting those left out as
+	// they only just connected. With a fanout, preferred peers always get
+	// the broadcast while the others take turns, as do our most worked peers
+	// for critical broadcasts of blocks.
+	fn broadcast_targets(&self, stats: &mut BroadcastStats, critical: bool) -> Vec<Arc<Peer>> {
+		let (warm, cold): (Vec<_>, Vec<_>) =
+			self.connected_peers().into_iter().partition(|p| self.warmed_up(p));
+		stats.warming_up = cold.len() as u32;
+		let fanout = self.config.broadcast_fanout as usize;
+		if fanout == 0 {
+			return warm;
+		}
+		let ids = warm.iter().map(|p| p.info.id).collect::<Vec<_>>();
+		let mut always = warm.iter()
+			.filter(|p| is_preferred(&self.config.preferred_peers, &p.info))
+			.map(|p| p.info.id)
+			.collect::<Vec<_>>();
+		if critical {
+			let best = self.rank_by_work(warm.clone());
+			for p in best.iter().take(BROADCAST_BEST_PEERS) {
+				if !always.contains(&p.info.id) {
+					always.push(p.info.id);
+				}
+			}
+		}
+		let mut rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+		let picked = rotation.pick(&ids, &always, fanout);
+		warm.into_iter().filter(|p| picked.contains(&p.info.id)).collect()
+	}
+
+	// Whether the peer connected long enough ago to be broadcast to.
+	fn warmed_up(&self, p: &Peer) -> bool {
+		p.uptime() >= Duration::from_millis(self.config.broadcast_warmup_ms) ||
+		is_preferred(&self.config.preferred_peers, &p.info)
+	}
+
+	/// Turns sync mode on or off. While catching up with the chain, sync mode
+	/// leaves the bandwidth to the block and header exchange: transactions
+	/// aren't relayed and we only ask new peers for addresses when short of
+	/// peers. Our own transactions get queued meanwhile, relayed once sync
+	/// mode is off.
+	pub fn set_sync_mode(&self, on: bool) {
+		if self.sync_mode.swap(on, Ordering::Relaxed) != on {
+			info!("Sync mode {}.", if on { "on" } else { "off" });
+			if !on {
+				self.relay_queued();
+			}
+		}
+	}
+
+	/// Records the peer we're catching up with, the one sync_status compares
+	/// our chain with. None once we're not syncing with any.
+	pub fn set_sync_peer(&self, addr: Option<SocketAddr>) {
+		*self.sync_peer.lock().unwrap_or_else(|e| e.into_inner()) = addr;
+	}
+
+	/// Whether we're in sync mode, see set_sync_mode.
+	pub fn sync_mode(&self) -> bool {
+		self.sync_mode.load(Ordering::Relaxed)
+	}
+
+	/// Stops accepting new inbound peers, closing their connections as soon
+	/// as they're open. The listeners, the peers already connected and our
+	/// outbound connections are left alone.
+	pub fn pause_inbound(&self) {
+		if !self.inbound_paused.swap(true, Ordering::Relaxed) {
+			info!("Inbound connections paused.");
+		}
+	}
+
+	/// Accepts new inbound peers again after pause_inbound.
+	pub fn resume_inbound(&self) {
+		if self.inbound_paused.swap(false, Ordering::Relaxed) {
+			info!("Inbound connections resumed.");
+		}
+	}
+
+	/// Announces the provided block to all our peers by its hash, peers that
+	/// don't have it yet will ask for the full block. Cheaper than
+	/// broadcasting the whole block to peers that may already have it.
+	pub fn announce_block(&self, b: &core::Block) {
+		let h = b.hash();
+		let peers = self.read_peers();
+		for p in peers.deref() {
+			if p.is_connected() {
+				if let Err(e) = p.send_block_inv(h) {
+					debug!("{} Error announcing block: {:?}", p.info.log_id, e);
+				}
+			}
+		}
+	}
+
+	/// Number of failed handshakes, inbound and outbound, broken down by
+	/// reason.
+	pub fn handshake_failures(&self) -> HashMap<HandshakeFailure, u64> {
+		self.handshake_failures.lock().unwrap_or_else(|e| e.into_inner()).clone()
+	}
+
+	/// How long we still back off from connecting to the peer at the provided
+	/// address, as it kept dropping soon after connecting.
+	pub fn backoff(&self, addr: &SocketAddr) -> Option<Duration> {
+		self.scheduler.lock().unwrap_or_else(|e| e.into_inner()).backoff(addr, Instant::now())
+	}
+
+	/// Peer churn, the number of peer connections and disconnections over the
+	/// configured churn window.
+	pub fn churn(&self) -> u32 {
+		self.churn.lock().unwrap_or_else(|e| e.into_inner()).count(Instant::now())
+	}
+
+	/// Number of peers we're currently connected to, those we lost
+	/// connection to but didn't prune yet left out.
+	pub fn peer_count(&self) -> u32 {
+		connected_count(&self.peers)
+	}
+
+	/// Whether dialing the provided address would get it an outbound slot:
+	/// one is free, or the address scores better than our worst outbound
+	/// peer which connect_peer would then evict.
+	pub fn outbound_slot_for(&self, addr: &SocketAddr) -> bool {
+		let pending = pending_count(&self.pending, Direction::Outbound);
+		if outbound_count(&self.peers) + pending < self.runtime().max_outbound_peers {
+			return true;
+		}
+		worse_peer(&self.peers,
+		           &self.book,
+		           &self.misbehavior,
+		           Direction::Outbound,
+		           &self.config.preferred_peers,
+		           *addr)
+			.is_some()
+	}
+
+	/// Number of connected peers that sent us something since the handshake,
+	/// the others may have gone silent.
+	pub fn verified_peer_count(&self) -> u32 {
+		self.read_peers().iter().filter(|p| p.is_connected() && p.is_verified()).count() as u32
+	}
+
+	/// Resolves to true once we have at least the provided number of peers,
+	/// or to false if that doesn't happen within the timeout. Woken up as
+	/// peers get added, no polling involved.
+	pub fn await_min_peers(&self,
+	                       n: u32,
+	                       timeout: Duration)
+	                       -> Box<Future<Item = bool, Error = Error>> {
+		let rx = {
+			// checked under the lock peers are notified with, so we can't miss one
+			let mut waiters = self.peer_waiters.lock().unwrap_or_else(|e| e.into_inner());
+			if self.peer_count() >= n {
+				return Box::new(future::ok(true));
+			}
+			waiters.retain(|&(_, ref tx)| !tx.is_canceled());
+			let (tx, rx) = oneshot::channel();
+			waiters.push((n, tx));
+			rx
+		};
+		let reached = rx.then(|res| -> Result<bool, Error> { Ok(res.is_ok()) });
+		let expired = throttle::sleep(&Timer::default(), timeout).map(|_| false);
+		Box::new(reached.select(expired).map(|(reached, _)| reached).map_err(|(e, _)| e))
+	}
+
+	/// Stops the server, which can be shared. Every peer gets disconnected
+	/// once what's queued for it went out, for up to the configured flush
+	/// time, and the future returned by start resolves once they all are. The
+	/// dials still in progress fail right away and no new ones get made.
+	/// Stopping again does nothing.
+	pub fn stop(&self) {
+		if self.stopped.swap(true, Ordering::SeqCst) {
+			return;
+		}
+		let flush = Duration::from_millis(self.config.close_flush_ms);
+		for p in self.read_peers().iter() {
+			p.stop_gracefully(flush);
+		}
+		for cancel in self.dial_cancels.lock().unwrap_or_else(|e| e.into_inner()).drain(..) {
+			let _ = cancel.send(());
+		}
+		self.dials.lock().unwrap_or_else(|e| e.into_inner()).waiting.clear();
+		self.signal_stop();
+	}
+
+	// Read access to our peers. A thread panicking while holding the lock
+	// shouldn't take the whole server down with it so poisoning is ignored,
+	// the peers vector is always left in a consistent state.
+	fn read_peers(&self) -> RwLockReadGuard<Vec<Arc<Peer>>> {
+		self.peers.read().unwrap_or_else(|e| e.into_inner())
+	}
+
+	// Write access to our peers, ignoring poisoning (see read_peers).
+	fn write_peers(&self) -> RwLockWriteGuard<Vec<Arc<Peer>>> {
+		self.peers.write().unwrap_or_else(|e| e.into_inner())
+	}
+
+	// The limits currently in effect, ignoring poisoning as well.
+	fn runtime(&self) -> RwLockReadGuard<P2PConfigRuntime> {
+		self.runtime.read().unwrap_or_else(|e| e.into_inner())
+	}
+
+	// How long hosts get banned and quarantined for.
+	fn terms(&self) -> Terms {
+		let runtime = self.runtime();
+		Terms::new(runtime.quarantine_secs, runtime.ban_secs)
+	}
+
+	fn restrictions(&self) -> MutexGuard<Restrictions> {
+		self.restrictions.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	// Addresses we listen on when accepting connections.
+	fn listen_addrs(&self) -> Vec<SocketAddr> {
+		listen_addrs(&self.config)
+	}
+
+	// What dialing takes, to dial from futures outliving our borrow.
+	fn dialer(&self) -> Dialer {
+		Dialer {
+			config: self.config.clone(),
+			capabilities: self.capabilities,
+			peers: self.peers.clone(),
+			adapter: self.adapter.clone(),
+			handshake_failures: self.handshake_failures.clone(),
+			outbound_bucket: self.outbound_bucket.clone(),
+			throttle_timer: self.throttle_timer.clone(),
+			churn: self.churn.clone(),
+			scheduler: self.scheduler.clone(),
+			restrictions: self.restrictions.clone(),
+			misbehavior: self.misbehavior.clone(),
+			block_pool: self.block_pool.clone(),
+			departed: self.departed.clone(),
+			book: self.book.clone(),
+			dials: self.dials.clone(),
+			dial_cancels: self.dial_cancels.clone(),
+			runtime: self.runtime.clone(),
+			traffic: self.traffic.clone(),
+			local: self.local.clone(),
+			pruned: self.pruned.clone(),
+			peer_waiters: self.peer_waiters.clone(),
+			observed_addrs: self.observed_addrs.clone(),
+			mapped_addr: self.mapped_addr.clone(),
+			local_ips: self.local_ips.clone(),
+			fds: self.fds.clone(),
+			tls: self.tls.clone(),
+			handshake: self.handshake.clone(),
+			pending: self.pending.clone(),
+		}
+	}
+}
+
+type PeerFuture = Box<Future<Item = (), Error = Error>>;
+
+type HandshakeFuture = Box<Future<Item = Result<PeerFuture, Error>, Error = Error>>;
+
+// The parts of the server dialing a peer takes, shared with it. Unlike the
+// server, it can be moved into a future to dial again later.
+struct Dialer {
+	config: P2PConfig,
+	capabilities: Capabilities,
+	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+	adapter: Arc<NetAdapter>,
+	handshake_failures: Arc<Mutex<HashMap<HandshakeFailure, u64>>>,
+	outbound_bucket: Option<Arc<TokenBucket>>,
+	throttle_timer: Option<Timer>,
+	churn: Arc<Mutex<Churn>>,
+	scheduler: Arc<Mutex<DialScheduler>>,
+	restrictions: Arc<Mutex<Restrictions>>,
+	misbehavior: Arc<Mutex<HostScores>>,
+	block_pool: Option<Arc<BlockPool>>,
+	departed: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+	book: Arc<PeerBook>,
+	dials: Arc<Mutex<DialLimit>>,
+	dial_cancels: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+	runtime: Arc<RwLock<P2PConfigRuntime>>,
+	traffic: Arc<Traffic>,
+	local: Arc<LocalStatus>,
+	pruned: Arc<Mutex<Vec<Arc<Peer>>>>,
+	peer_waiters: Arc<Mutex<Vec<(u32, oneshot::Sender<()>)>>>,
+	observed_addrs: Arc<Mutex<HashMap<IpAddr, SocketAddr>>>,
+	mapped_addr: Arc<Mutex<Option<SocketAddr>>>,
+	local_ips: Arc<Mutex<HashSet<IpAddr>>>,
+	fds: Arc<FdExhaustion>,
+	tls: Result<Option<Arc<TlsContext>>, String>,
+	handshake: Arc<Handshake>,
+	pending: Arc<Mutex<PendingPeers>>,
+}
+
+impl Dialer {
+	// Whether the provided address is ours, see Server::is_own_addr.
+	fn is_own(&self, addr: &SocketAddr) -> bool {
+		own_addr(&self.config, &self.observed_addrs, &self.mapped_addr, &self.local_ips, addr)
+	}
+
+	// Connects to a new peer, see Server::connect_peer.
+	fn connect(&self,
+	           addr: SocketAddr,
+	           h: reactor::Handle)
+	           -> Box<Future<Item = Option<Arc<Peer>>, Error = Error>> {
+		// if we're already connected to the addr, just return the peer
+		if let Some(p) = connected_peer(&self.peers, addr) {
+			return Box::new(future::ok(Some(p)));
+		}
+		let limits = self.runtime.read().unwrap_or_else(|e| e.into_inner()).clone();
+		let terms = Terms::new(limits.quarantine_secs, limits.ban_secs);
+		if !self.adapter.address_allowed(&addr) {
+			debug!("Not connecting to {}, not allowed by the adapter.", addr);
+			return Box::new(future::err(Error::NotAllowed));
+		}
+		if is_restricted(&self.restrictions, &addr.ip()) {
+			return Box::new(future::err(Error::Banned));
+		}
+		let backoff = self.scheduler
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.backoff(&addr, Instant::now());
+		if let Some(wait) = backoff {
+			debug!("Backing off from flaky peer {} for another {}s.", addr, wait.as_secs());
+			return Box::new(future::err(Error::Backoff));
+		}
+		if !self.fds.available() {
+			debug!("Not connecting to {}, out of file descriptors.", addr);
+			return Box::new(future::err(Error::TooManyOpenFiles));
+		}
+		if self.config.proxy.is_none() && onion_host(&addr).is_some() {
+			debug!("Not connecting to onion service {}, no proxy to reach it.", addr);
+			return Box::new(future::err(Error::NoProxy));
+		}
+		// asked to connect to ourselves
+		if self.is_own(&addr) {
+			return Box::new(future::ok(None));
+		}
+		// the dials in progress count along with the connected peers, at the
+		// limit a peer that could stand better than one of ours gets dialed,
+		// the worse peer being evicted once the handshake is done
+		let max_outbound = limits.max_outbound_peers;
+		let slot = PendingSlot::reserve_if(&self.pending, Direction::Outbound, true, |p| {
+			outbound_count(&self.peers) + p.outbound < max_outbound
+		});
+		let full = slot.is_none();
+		if full &&
+		   worse_peer(&self.peers,
+		              &self.book,
+		              &self.misbehavior,
+		              Direction::Outbound,
+		              &self.config.preferred_peers,
+		              addr)
+			.is_none() {
+			debug!("Not connecting to {}, already at {} outbound peers.", addr, max_outbound);
+			return Box::new(future::ok(None));
+		}
+		let evict: Option<Box<Fn(&PeerInfo) -> bool>> = if full {
+			let (peers, book, misbehavior) =
+				(self.peers.clone(), self.book.clone(), self.misbehavior.clone());
+			let (pending, preferred) = (self.pending.clone(), self.config.preferred_peers.clone());
+			Some(Box::new(move |info: &PeerInfo| {
+				outbound_count(&peers) + pending_count(&pending, Direction::Outbound) <
+				max_outbound ||
+				evict_worse(&peers,
+				            &book,
+				            &misbehavior,
+				            Direction::Outbound,
+				            &preferred,
+				            info.node_addr())
+			}))
+		} else {
+			None
+		};
+		let peers = self.peers.clone();
+		let waiters = self.peer_waiters.clone();
+		let adapter1 = self.adapter.clone();
+		let adapter2 = self.adapter.clone();
+		let capab = self.capabilities.clone();
+		// when not listening, a zero port tells we can't be connected to
+		let self_port = if self.config.inbound_enabled {
+			self.config.port
+		} else {
+			0
+		};
+		// advertise where peers see us at when they agree on it, nothing but
+		// our onion service when going through a proxy to stay hidden
+		let public = public_addr(&self.observed_addrs, &self.mapped_addr);
+		let self_addr = match (&self.config.proxy, public) {
+			(&Some(ref proxy), _) => {
+				proxy.onion.unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0))
+			}
+			(&None, Some(public)) if self.config.inbound_enabled => public,
+			(&None, _) => SocketAddr::new(self.config.host, self_port),
+		};
+		let failures = self.handshake_failures.clone();
+		let throttle = new_throttle(&self.outbound_bucket,
+		                            limits.max_peer_outbound_rate,
+		                            self.config.send_timeout_secs,
+		                            self.config.inbound_limits,
+		                            &self.throttle_timer);
+		let max_diff = Difficulty::max_with_bits(self.config.max_difficulty_bits);
+		let churn1 = self.churn.clone();
+		let churn2 = self.churn.clone();
+		let scheduler = self.scheduler.clone();
+		let restrictions = self.restrictions.clone();
+		let hs = self.handshake.clone();
+		let block_pool = self.block_pool.clone();
+		let departed = self.departed.clone();
+		let bind_addr = self.config.bind_addr;
+		let proxy = self.config.proxy.clone();
+		let own_services = self.config.services;
+		let preferred = self.config.preferred_peers.clone();
+		let book = self.book.clone();
+		let book2 = self.book.clone();
+		let traffic = self.traffic.clone();
+		let local = self.local.clone();
+		let local_ips = self.local_ips.clone();
+		// through a proxy, peers see the proxy rather than us
+		let observed = if self.config.proxy.is_none() {
+			Some(self.observed_addrs.clone())
+		} else {
+			None
+		};
+		let peers2 = self.peers.clone();
+		let pruned = self.pruned.clone();
+		let fds = self.fds.clone();
+		let pacer = self.scheduler.clone();
+		let tls = match self.tls.clone().map_err(Error::Tls) {
+			Ok(tls) => tls,
+			Err(e) => return Box::new(future::err(e)),
+		};
+		let handshake_timeout = Duration::from_secs(self.config.handshake_timeout_secs);
+		let misbehavior = self.misbehavior.clone();
+		let restrictions2 = self.restrictions.clone();
+		let ban_score = self.config.ban_score;
+		let (cancel, cancelled) = oneshot::channel();
+		{
+			let mut cancels = self.dial_cancels.lock().unwrap_or_else(|e| e.into_inner());
+			cancels.retain(|tx| !tx.is_canceled());
+			cancels.push(cancel);
+		}
+
+		debug!("{} connecting to {}", self_addr, addr);
+
+		let h2 = h.clone();
+		let h3 = h.clone();
+		// wait for a dial slot before even opening the socket, holding it until
+		// the handshake is over, and for our turn after the previous dial
+		let pending_slot = slot;
+		let request = DialSlot::acquire(&self.dials)
+			.and_then(move |slot| {
+				pace_dial(&pacer, &h3).map(|_| slot)
+			})
+			.and_then(move |slot| {
+				let socket = open_socket(&addr, bind_addr, &proxy, &h).map_err(move |e| {
+					if out_of_fds(&e) {
+						fds.exhausted("dialing");
+						Error::TooManyOpenFiles
+					} else {
+						Error::Connection(e)
+					}
+				});
+				socket.and_then(move |socket| {
+						let total_diff = adapter1.total_difficulty();
+						let services = adapter1.services();
+
+						// connect to the peer, securing the connection before the handshake
+						// if we did last time or once it's done if we both can, and add it to
+						// the server map, wiring it a timeout for the handhake
+						let tls1 = tls.clone();
+						let connect = secure_known(&tls, socket, addr)
+							.and_then(move |conn| {
+								let diff = total_diff;
+								Peer::connect(conn, addr, capab, diff, services, self_addr, &hs)
+							})
+							.and_then(move |(conn, peer)| {
+								let theirs = peer.info.capabilities;
+								secure(&tls1, conn, theirs, Some(addr)).map(|conn| (conn, peer))
+							});
+						let added = add_to_peers(peers,
+						                         waiters,
+						                         adapter1,
+						                         churn1,
+						                         max_diff,
+						                         services & own_services,
+						                         preferred,
+						                         vec![],
+						                         evict,
+						                         connect);
+						with_timeout(Box::new(added), handshake_timeout, &h).map_err(move |e| {
+							record_failure(&failures, &e);
+							score_handshake(&misbehavior,
+							                &restrictions2,
+							                terms,
+							                addr.ip(),
+							                &e,
+							                ban_score);
+							e
+						})
+					})
+					.then(move |res| {
+						drop(slot);
+						drop(pending_slot);
+						if let Err(Error::Connection(_)) = res {
+							record_unreachable(&book2, addr);
+						} else if let Err(Error::Timeout) = res {
+							record_unreachable(&book2, addr);
+						}
+						res
+					})
+			})
+			.and_then(move |(socket, peer)| {
+				// already connected to it, through another connection
+				let socket = match socket {
+					Some(socket) => socket,
+					None => return Ok(Some(peer)),
+				};
+				let err_peer = peer.clone();
+				record_connected(&book, &peer.info);
+				record_local_ip(&local_ips, &socket);
+				if let (Some(observed), Some(seen)) = (observed, peer.info.observed_addr) {
+					if let Ok(observer) = socket.peer_addr() {
+						record_observed(&observed, observer.ip(), seen);
+					}
+				}
+				let run = peer.run_throttled(socket,
+				                             adapter2.clone(),
+				                             throttle,
+				                             block_pool,
+				                             traffic,
+				                             local);
+				h2.spawn(run.then(move |res| {
+					record_churn(&churn2);
+					record_departure(&departed, &book, &err_peer.info);
+					record_session(&scheduler, &restrictions, terms, &err_peer);
+					if let Err(e) = res {
+						adapter2.peer_error(&err_peer.info, &e);
+						error!("{} Peer error: {:?}", err_peer.info.log_id, e);
+						remove_errored(&peers2, &pruned, &err_peer);
+						let ban = restrict_misbehaving(&restrictions, terms, &err_peer, &e);
+						if let Some(ban) = ban {
+							adapter2.peer_banned(err_peer.info.addr, &ban);
+						}
+					}
+					adapter2.peer_disconnected(&err_peer.info);
+					Ok(())
+				}));
+				Ok(Some(peer))
+			});
+		// stopping the server cancels the dial, whatever stage it's at
+		let cancelled = cancelled.then(|_| -> Result<Option<Arc<Peer>>, Error> {
+			Err(Error::ConnectionClose)
+		});
+		Box::new(request.select(cancelled).map(|(peer, _)| peer).map_err(|(e, _)| e))
+	}
+}
+
+// Keeps dialing the provided address, see Server::maintain_connection.
+fn maintain(dialer: Dialer,
+            addr: SocketAddr,
+            backoff: RetryBackoff,
+            h: reactor::Handle)
+            -> PeerFuture {
+	type Round = Box<Future<Item = Loop<(), (Dialer, RetryBackoff)>, Error = Error>>;
+
+	let rounds = future::loop_fn((dialer, backoff), move |(dialer, mut backoff)| -> Round {
+		let h = h.clone();
+		let dial = dialer.connect(addr, h.clone());
+		Box::new(dial.then(move |res| -> Round {
+			let connected: PeerFuture = match res {
+				Ok(Some(peer)) => {
+					backoff.reset();
+					until_disconnected(peer, &h)
+				}
+				Ok(None) if dialer.is_own(&addr) => {
+					info!("Not maintaining a connection to {}, our own address.", addr);
+					return Box::new(future::ok(Loop::Break(())));
+				}
+				Err(Error::Banned) |
+				Err(Error::NotAllowed) => {
+					info!("Not maintaining a connection to {}, not allowed.", addr);
+					return Box::new(future::ok(Loop::Break(())));
+				}
+				// at our outbound limit
+				Ok(None) => Box::new(future::ok(())),
+				Err(e) => {
+					debug!("Failed to connect to maintained peer {}: {:?}", addr, e);
+					Box::new(future::ok(()))
+				}
+			};
+			let delay = backoff.delay();
+			Box::new(connected.and_then(move |_| {
+				debug!("Connecting to {} again in {:?}.", addr, delay);
+				reactor::Timeout::new(delay, &h)
+					.unwrap()
+					.from_err()
+					.map(move |_| Loop::Continue((dialer, backoff)))
+			}))
+		}))
+	});
+	Box::new(rounds)
+}
+
+// Resolves once none of the peers is connected anymore, closing the
+// connections of those still there after the timeout.
+fn drain_peers(peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+               timeout: Duration,
+               h: reactor::Handle)
+               -> PeerFuture {
+	type Round = Box<Future<Item = Loop<(), ()>, Error = Error>>;
+
+	let deadline = Instant::now() + timeout;
+	let drained = future::loop_fn((), move |_| -> Round {
+		let left = peers.read()
+			.unwrap_or_else(|e| e.into_inner())
+			.iter()
+			.filter(|p| p.is_connected())
+			.cloned()
+			.collect::<Vec<_>>();
+		if left.is_empty() {
+			return Box::new(future::ok(Loop::Break(())));
+		}
+		if Instant::now() >= deadline {
+			debug!("{} peers still connected after flushing, closing.", left.len());
+			for p in left {
+				p.stop();
+			}
+			return Box::new(future::ok(Loop::Break(())));
+		}
+		match reactor::Timeout::new(Duration::from_millis(DRAIN_CHECK_MS), &h) {
+			Ok(wait) => Box::new(wait.from_err().map(|_| Loop::Continue(()))),
+			Err(e) => Box::new(future::err(Error::Connection(e))),
+		}
+	});
+	Box::new(drained)
+}
+
+// Resolves once we lost connection to the provided peer, checking every
+// second on the reactor.
+fn until_disconnected(peer: Arc<Peer>, h: &reactor::Handle) -> PeerFuture {
+	let ticks = match reactor::Interval::new(Duration::from_secs(1), h) {
+		Ok(ticks) => ticks,
+		Err(e) => return Box::new(future::err(Error::Connection(e))),
+	};
+	Box::new(ticks.take_while(move |_| Ok(peer.is_connected())).for_each(|_| Ok(())).from_err())
+}
+
+// The address our gateway forwards to us, unless it's itself behind another
+// NAT, or the one most of the hosts we're connected with see us at, as long
+// as enough of them do.
+fn public_addr(observed: &Mutex<HashMap<IpAddr, SocketAddr>>,
+               mapped: &Mutex<Option<SocketAddr>>)
+               -> Option<SocketAddr> {
+	if let Some(mapped) = *mapped.lock().unwrap_or_else(|e| e.into_inner()) {
+		let unspecified = match mapped.ip() {
+			IpAddr::V4(ip) => ip.is_unspecified(),
+			IpAddr::V6(ip) => ip.is_unspecified(),
+		};
+		if !unspecified && !private_ip(&mapped.ip()) {
+			return Some(mapped);
+		}
+	}
+	let observed = observed.lock().unwrap_or_else(|e| e.into_inner());
+	let mut votes: HashMap<SocketAddr, usize> = HashMap::new();
+	for addr in observed.values() {
+		*votes.entry(*addr).or_insert(0) += 1;
+	}
+	votes.into_iter()
+		.filter(|&(_, n)| n >= MIN_ADDR_OBSERVERS)
+		.max_by_key(|&(_, n)| n)
+		.map(|(addr, _)| addr)
+}
+
+// Whether the provided address is one of ours, see Server::is_own_addr.
+fn own_addr(config: &P2PConfig,
+            observed: &Mutex<HashMap<IpAddr, SocketAddr>>,
+            mapped: &Mutex<Option<SocketAddr>>,
+            local_ips: &Mutex<HashSet<IpAddr>>,
+            addr: &SocketAddr)
+            -> bool {
+	let mapped_addr = *mapped.lock().unwrap_or_else(|e| e.into_inner());
+	if public_addr(observed, mapped) == Some(*addr) || mapped_addr == Some(*addr) {
+		return true;
+	}
+	if !config.inbound_enabled {
+		return false;
+	}
+	let local_ips = local_ips.lock().unwrap_or_else(|e| e.into_inner());
+	let ours = |ip: &IpAddr| ip.is_loopback() || local_ips.contains(ip);
+	listen_addrs(config).iter().any(|l| {
+		l.port() == addr.port() &&
+		(l.ip() == addr.ip() || l.ip().is_unspecified() && ours(&addr.ip()))
+	})
+}
+
+// Addresses we listen on when accepting connections.
+fn listen_addrs(config: &P2PConfig) -> Vec<SocketAddr> {
+	let mut addrs = vec![SocketAddr::new(config.host, config.port)];
+	addrs.extend(config.extra_listeners.iter().cloned());
+	addrs
+}
+
+// Whether two peers are the same node, connecting from the same IP as their
+// connections show and listening on the same port. Going by the advertised
+// address alone, anyone could claim the one of a peer to knock it off.
+fn same_node(a: &PeerInfo, b: &PeerInfo) -> bool {
+	remote_ip(a) == remote_ip(b) && a.addr.port() == b.addr.port()
+}
+
+// The IP the connection of a peer comes from, the one it advertises if we
+// couldn't tell.
+fn remote_ip(info: &PeerInfo) -> IpAddr {
+	info.remote_addr.map(|r| r.ip()).unwrap_or(info.addr.ip())
+}
+
+// The peer at the provided address, if connected, cloned out so the lock
+// isn't held.
+fn connected_peer(peers: &RwLock<Vec<Arc<Peer>>>, addr: SocketAddr) -> Option<Arc<Peer>> {
+	let peers = peers.read().unwrap_or_else(|e| e.into_inner());
+	peers.iter().find(|p| p.is_connected() && p.info.addr == addr).cloned()
+}
+
+// Removes the peers we lost connection to or have been deemed problematic,
+// returning them.
+fn prune_peers(peers: &RwLock<Vec<Arc<Peer>>>) -> Vec<Arc<Peer>> {
+	let mut peers = peers.write().unwrap_or_else(|e| e.into_inner());
+	let (keep, rm) = peers.iter().fold((vec![], vec![]), |mut acc, ref p| {
+		if p.clone().is_connected() {
+			acc.0.push((*p).clone());
+		} else {
+			acc.1.push((*p).clone());
+		}
+		acc
+	});
+	*peers = keep;
+	rm
+}
+
+// Disconnects the peers that missed max pongs in a row, if max isn't zero.
+// They're pruned like the others we lost connection to once their run ends.
+fn drop_unresponsive(peers: &RwLock<Vec<Arc<Peer>>>, pings: &Mutex<Pings>, max: u32) {
+	if max == 0 {
+		return;
+	}
+	let unresponsive = pings.lock().unwrap_or_else(|e| e.into_inner()).unresponsive(max);
+	let peers = peers.read().unwrap_or_else(|e| e.into_inner());
+	for p in peers.iter().filter(|p| unresponsive.contains(&p.info.id)) {
+		info!("{} Disconnecting, missed {} pongs in a row.", p.info.log_id, max);
+		p.stop();
+	}
+}
+
+// Lifts the embargoes that ended, broadcasting their transactions to all
+// our connected peers unless one not along the stem knows of them already,
+// meaning they got broadcast. The adapter is told of those we broadcast,
+// whose hashes get returned.
+fn fluff_expired(peers: &RwLock<Vec<Arc<Peer>>>,
+                 embargoes: &Mutex<HashMap<Hash, Embargo>>,
+                 adapter: &NetAdapter)
+                 -> Vec<Hash> {
+	let now = Instant::now();
+	let expired = {
+		let mut embargoes = embargoes.lock().unwrap_or_else(|e| e.into_inner());
+		let ended = embargoes.iter()
+			.filter(|&(_, e)| e.until <= now)
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		ended.into_iter().filter_map(|h| embargoes.remove(&h)).collect::<Vec<_>>()
+	};
+	if expired.is_empty() {
+		return vec![];
+	}
+	let connected = peers.read()
+		.unwrap_or_else(|e| e.into_inner())
+		.iter()
+		.filter(|p| p.is_connected())
+		.cloned()
+		.collect::<Vec<_>>();
+	let mut fluffed = vec![];
+	for e in expired {
+		let h = e.tx.hash();
+		if connected.iter().any(|p| !e.through.contains(&p.info.addr) && p.knows_transaction(h)) {
+			continue;
+		}
+		adapter.transaction_fluffed(&e.tx);
+		for p in &connected {
+			if let SendOutcome::Failed(err) = p.send_transaction(&e.tx) {
+				debug!("{} Error sending transaction: {:?}", p.info.log_id, err);
+			}
+		}
+		fluffed.push(h);
+	}
+	fluffed
+}
+
+// Removes a peer whose run errored out from our peers right away, rather
+// than on the next pruning. Kept for the next clean_peers if banned.
+fn remove_errored(peers: &RwLock<Vec<Arc<Peer>>>,
+                  pruned: &Mutex<Vec<Arc<Peer>>>,
+                  peer: &Arc<Peer>) {
+	peers.write().unwrap_or_else(|e| e.into_inner()).retain(|p| p.info.id != peer.info.id);
+	if peer.is_banned() {
+		let mut pruned = pruned.lock().unwrap_or_else(|e| e.into_inner());
+		pruned.push(peer.clone());
+		let excess = pruned.len().saturating_sub(MAX_PRUNED_BANNED);
+		pruned.drain(..excess);
+	}
+}
+
+// Whether the host is still banned or quarantined.
+fn is_restricted(restrictions: &Mutex<Restrictions>, ip: &IpAddr) -> bool {
+	restrictions.lock().unwrap_or_else(|e| e.into_inner()).contains(ip, Instant::now())
+}
+
+// Quarantines the host for as long as the terms say.
+fn quarantine_host(restrictions: &Mutex<Restrictions>,
+                   terms: Terms,
+                   ip: IpAddr,
+                   reason: &str,
+                   now: Instant)
+                   -> BanEntry {
+	let mut restrictions = restrictions.lock().unwrap_or_else(|e| e.into_inner());
+	restrictions.restrict(ip, Severity::Quarantine, reason, terms, now).0
+}
+
+// How a peer or a candidate stands given its misbehavior score and how good a
+// bet the peer store has its address as, lower being better.
+fn standing(score: u32, quality: f64) -> (u32, f64) {
+	(score, -quality)
+}
+
+// The connected peer in the provided direction standing the worst, if the
+// candidate at the provided address stands strictly better. A candidate is
+// only known by what we learned of its host so far, preferred peers are never
+// picked.
+fn worse_peer(peers: &RwLock<Vec<Arc<Peer>>>,
+              book: &PeerBook,
+              misbehavior: &Mutex<HostScores>,
+              direction: Direction,
+              preferred: &Vec<IpAddr>,
+              candidate: SocketAddr)
+              -> Option<Arc<Peer>> {
+	let score = misbehavior.lock()
+		.unwrap_or_else(|e| e.into_inner())
+		.get(&candidate.ip(), Instant::now());
+	let theirs = standing(score, book.quality(&candidate));
+	let peers = peers.read().unwrap_or_else(|e| e.into_inner());
+	let worst = peers.iter()
+		.filter(|p| {
+			p.is_connected() && p.info.direction == direction &&
+			!is_preferred(preferred, &p.info)
+		})
+		.map(|p| (p, standing(p.misbehavior_score(), book.quality(&p.info.node_addr()))))
+		.fold(None, |worst: Option<(&Arc<Peer>, (u32, f64))>, (p, s)| match worst {
+			Some((_, ws)) if ws >= s => worst,
+			_ => Some((p, s)),
+		});
+	match worst {
+		Some((p, s)) if theirs < s => Some(p.clone()),
+		_ => None,
+	}
+}
+
+// Evicts the peer worse_peer picks to make room for the candidate, once the
+// candidate's handshake is done, returning whether there was one.
+fn evict_worse(peers: &RwLock<Vec<Arc<Peer>>>,
+               book: &PeerBook,
+               misbehavior: &Mutex<HostScores>,
+               direction: Direction,
+               preferred: &Vec<IpAddr>,
+               candidate: SocketAddr)
+               -> bool {
+	match worse_peer(peers, book, misbehavior, direction, preferred, candidate) {
+		Some(p) => {
+			debug!("{} Evicting to make room for better peer {}.", p.info.log_id, candidate);
+			p.stop();
+			true
+		}
+		None => false,
+	}
+}
+
+// Number of connected peers.
+fn connected_count(peers: &RwLock<Vec<Arc<Peer>>>) -> u32 {
+	let peers = peers.read().unwrap_or_else(|e| e.into_inner());
+	peers.iter().filter(|p| p.is_connected()).count() as u32
+}
+
+// Number of connected outbound peers.
+fn outbound_count(peers: &RwLock<Vec<Arc<Peer>>>) -> u32 {
+	peers.read()
+		.unwrap_or_else(|e| e.into_inner())
+		.iter()
+		.filter(|p| p.is_connected() && p.info.direction == Direction::Outbound)
+		.count() as u32
+}
+
+// Number of handshakes in progress holding a slot in the provided direction.
+fn pending_count(pending: &Mutex<PendingPeers>, direction: Direction) -> u32 {
+	let pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+	match direction {
+		Direction::Inbound => pending.inbound,
+		Direction::Outbound => pending.outbound,
+	}
+}
+
+// Whether a new inbound connection from the provided address can get in,
+// given the inbound handshakes already in progress, evicting a random
+// non-preferred inbound peer if a preferred one needs its slot.
+fn admit_inbound(peers: &RwLock<Vec<Arc<Peer>>>,
+                 pending: &PendingPeers,
+                 addr: SocketAddr,
+                 preferred: &Vec<IpAddr>,
+                 max_inbound: u32,
+                 reserved: u32)
+                 -> bool {
+	let peers = peers.read().unwrap_or_else(|e| e.into_inner());
+	let inbound = peers.iter()
+		.filter(|p| p.is_connected() && p.info.direction == Direction::Inbound)
+		.collect::<Vec<_>>();
+	let evictable = inbound.iter()
+		.filter(|p| !is_preferred(preferred, &p.info))
+		.collect::<Vec<_>>();
+	let count = inbound.len() as u32 + pending.inbound;
+
+	// regular peers can't take more than the unreserved slots
+	if !preferred.contains(&addr.ip()) {
+		let regular = evictable.len() as u32 + pending.regular_inbound;
+		return count < max_inbound && regular < max_inbound.saturating_sub(reserved);
+	}
+	if count < max_inbound {
+		return true;
+	}
+	if evictable.is_empty() {
+		return false;
+	}
+	let evicted = evictable[rand::thread_rng().gen_range(0, evictable.len())];
+	debug!("{} Evicting to make room for preferred peer {}.", evicted.info.log_id, addr);
+	evicted.stop();
+	true
+}
+
+// Keeps accepting on a listener through transient errors, only ending its
+// stream once the socket itself is unusable, so the failure of one listener
+// doesn't take down the whole accept pipeline. Running out of file
+// descriptors makes accepting pause for a while instead of spinning on the
+// connection we can't take, other transient errors pause it briefly.
+fn isolate_listener<S, T>(addr: SocketAddr,
+                          incoming: S,
+                          fds: Arc<FdExhaustion>,
+                          h: reactor::Handle)
+                          -> Box<Stream<Item = T, Error = Error>>
+	where S: Stream<Item = T, Error = io::Error> + 'static,
+	      T: 'static
+{
+	let accepted = incoming.then(move |res| {
+		let next: Box<Future<Item = Option<Option<T>>, Error = Error>> = match res {
+			Ok(conn) => Box::new(future::ok(Some(Some(conn)))),
+			Err(ref e) if out_of_fds(e) => {
+				fds.exhausted("accepting");
+				let pause = Duration::from_millis(FD_ACCEPT_PAUSE_MS);
+				match reactor::Timeout::new(pause, &h) {
+					Ok(t) => Box::new(t.then(|_| Ok(Some(None)))),
+					Err(_) => Box::new(future::ok(Some(None))),
+				}
+			}
+			Err(ref e) if listener_broken(e) => {
+				error!("Listener on {} failed, dropping it: {:?}", addr, e);
+				Box::new(future::ok(None))
+			}
+			Err(ref e) if connection_failed(e) => {
+				debug!("Failed accepting on {}: {:?}", addr, e);
+				Box::new(future::ok(Some(None)))
+			}
+			Err(e) => {
+				warn!("Failed accepting on {}, going on: {:?}", addr, e);
+				let pause = Duration::from_millis(ACCEPT_ERROR_PAUSE_MS);
+				match reactor::Timeout::new(pause, &h) {
+					Ok(t) => Box::new(t.then(|_| Ok(Some(None)))),
+					Err(_) => Box::new(future::ok(Some(None))),
+				}
+			}
+		};
+		next
+	});
+	Box::new(accepted.take_while(|conn| Ok(conn.is_some())).filter_map(|conn| conn.unwrap()))
+}
+
+// Whether the error tells we ran out of file descriptors.
+fn out_of_fds(e: &io::Error) -> bool {
+	match e.raw_os_error() {
+		Some(EMFILE) | Some(ENFILE) => true,
+		_ => false,
+	}
+}
+
+// Whether the accept error tells the listening socket itself is unusable.
+fn listener_broken(e: &io::Error) -> bool {
+	match e.raw_os_error() {
+		Some(EBADF) | Some(EINVAL) => true,
+		_ => false,
+	}
+}
+
+// Whether the accept error is only about the connection being accepted.
+fn connection_failed(e: &io::Error) -> bool {
+	match e.kind() {
+		io::ErrorKind::ConnectionAborted |
+		io::ErrorKind::ConnectionReset |
+		io::ErrorKind::Interrupted => true,
+		_ => false,
+	}
+}
+
+// Merges the accept streams of all our listeners, the result only ending
+// once all of them have.
+fn merge_listeners<T: 'static>(listeners: Vec<Box<Stream<Item = T, Error = Error>>>)
+                               -> Box<Stream<Item = T, Error = Error>> {
+	let mut listeners = listeners.into_iter();
+	let mut merged: Box<Stream<Item = T, Error = Error>> = match listeners.next() {
+		Some(l) => l,
+		None => Box::new(futures::stream::empty()),
+	};
+	for l in listeners {
+		merged = Box::new(merged.select(l));
+	}
+	merged
+}
+
+// Binds a listener, trying again a few times should the address be taken,
+// like right after a restart. The first attempt is made right away, the next
+// ones wait on the reactor rather than blocking it.
+fn bind_with_retry(addr: &SocketAddr,
+                   config: &P2PConfig,
+                   h: &reactor::Handle)
+                   -> Box<Future<Item = TcpListener, Error = Error>> {
+	type Next = Box<Future<Item = Loop<TcpListener, (u32, Duration)>, Error = Error>>;
+
+	let (addr, config, h) = (*addr, config.clone(), h.clone());
+	let first = (1, Duration::from_millis(BIND_RETRY_MS));
+	Box::new(future::loop_fn(first, move |(attempt, pause)| -> Next {
+		let e = match bind_listener(&addr, &config, &h) {
+			Ok(listener) => return Box::new(future::ok(Loop::Break(listener))),
+			Err(e) => e,
+		};
+		if attempt >= BIND_ATTEMPTS {
+			error!("Could not listen on {}: {:?}", addr, e);
+			return Box::new(future::err(Error::Bind(addr, e)));
+		}
+		debug!("Failed to listen on {}, retrying in {:?}: {:?}", addr, pause, e);
+		match reactor::Timeout::new(pause, &h) {
+			Ok(t) => {
+				Box::new(t.map(move |_| Loop::Continue((attempt + 1, pause * 2)))
+					.map_err(move |e| Error::Bind(addr, e)))
+			}
+			Err(e) => Box::new(future::err(Error::Bind(addr, e))),
+		}
+	}))
+}
+
+// Builds the listener socket, setting the reuse options from our config
+// before binding.
+fn bind_listener(addr: &SocketAddr,
+                 config: &P2PConfig,
+                 h: &reactor::Handle)
+                 -> io::Result<TcpListener> {
+	let builder = match *addr {
+		SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+		SocketAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+	};
+	builder.reuse_address(config.reuse_addr)?;
+	if config.reuse_port {
+		set_reuse_port(&builder)?;
+	}
+	builder.bind(addr)?;
+	let listener = builder.listen(1024)?;
+	TcpListener::from_listener(listener, addr, h)
+}
+
+// Opens an outbound connection, through the proxy if we have one.
+fn open_socket(addr: &SocketAddr,
+               bind_addr: Option<IpAddr>,
+               proxy: &Option<ProxyConfig>,
+               h: &reactor::Handle)
+               -> Box<Future<Item = TcpStream, Error = io::Error>> {
+	match *proxy {
+		Some(ref proxy) => proxy::connect(proxy, *addr, h),
+		None => connect_socket(addr, bind_addr, h),
+	}
+}
+
+// Opens an outbound connection, from the provided local address if any.
+fn connect_socket(addr: &SocketAddr,
+                  bind_addr: Option<IpAddr>,
+                  h: &reactor::Handle)
+                  -> Box<Future<Item = TcpStream, Error = io::Error>> {
+	let bind_ip = match bind_addr {
+		Some(ip) => ip,
+		None => return Box::new(TcpStream::connect(addr, h)),
+	};
+	let stream = {
+		let builder = match bind_ip {
+			IpAddr::V4(_) => net2::TcpBuilder::new_v4(),
+			IpAddr::V6(_) => net2::TcpBuilder::new_v6(),
+		};
+		builder.and_then(|b| {
+			b.bind(SocketAddr::new(bind_ip, 0))?;
+			b.to_tcp_stream()
+		})
+	};
+	match stream {
+		Ok(stream) => Box::new(TcpStream::connect_stream(stream, addr, h)),
+		Err(e) => Box::new(future::err(e)),
+	}
+}
+
+#[cfg(unix)]
+fn set_reuse_port(builder: &net2::TcpBuilder) -> io::Result<()> {
+	use net2::unix::UnixTcpBuilderExt;
+	builder.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(builder: &net2::TcpBuilder) -> io::Result<()> {
+	warn!("SO_REUSEPORT isn't supported on this platform, ignoring.");
+	Ok(())
+}
+
+// Adds the peer built by the provided future in the peers map
+fn add_to_peers<A>(peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+                   waiters: Arc<Mutex<Vec<(u32, oneshot::Sender<()>)>>>,
+                   adapter: Arc<NetAdapter>,
+                   churn: Arc<Mutex<Churn>>,
+                   max_diff: Difficulty,
+                   offered: Services,
+                   preferred: Vec<IpAddr>,
+                   class_limits: Vec<(Capabilities, u32)>,
+                   evict: Option<Box<Fn(&PeerInfo) -> bool>>,
+                   peer_fut: A)
+                   -> Box<Future<Item = Result<(Option<PeerStream>, Arc<Peer>), ()>, Error = Error>>
+	where A: IntoFuture<Item = (PeerStream, Peer), Error = Error> + 'static
+{
+	let peer_add = peer_fut.into_future().and_then(move |(conn, mut peer)| {
+		// don't hold a slot for a peer we have no use for, the connection gets
+		// closed when dropped
+		if !useful_peer(&peer.info, offered, &preferred) {
+			debug!("{} No services in common, disconnecting.", peer.info.log_id);
+			return Err(Error::NoCommonServices);
+		}
+		if peer.info.direction == Direction::Inbound {
+			let peers = peers.read().unwrap_or_else(|e| e.into_inner());
+			if let Some(class) = full_class(&peers, &peer.info, &class_limits) {
+				debug!("{} No inbound slot left for capabilities {:b}, disconnecting.",
+				       peer.info.log_id,
+				       class.bits());
+				return Err(Error::CapabilityLimit(class));
+			}
+		}
+		// admitted while at the limit, there has to be room by now
+		if let Some(ref evict) = evict {
+			if !evict(&peer.info) {
+				debug!("{} No slot left and no worse peer to evict, disconnecting.",
+				       peer.info.log_id);
+				return Err(Error::NoSlot);
+			}
+		}
+		let advertised = peer.info.total_difficulty.clone();
+		peer.info.total_difficulty = cap_difficulty(advertised.clone(), &max_diff);
+		let apeer = Arc::new(peer);
+		let count = {
+			// checked under the lock we add with, an inbound and an outbound
+			// connection to the same peer can complete their handshakes at once
+			let mut peers = peers.write().unwrap_or_else(|e| e.into_inner());
+			// checked again under the lock, in case another peer of the class
+			// got added since
+			if apeer.info.direction == Direction::Inbound {
+				if let Some(class) = full_class(&peers, &apeer.info, &class_limits) {
+					debug!("{} No inbound slot left for capabilities {:b}, disconnecting.",
+					       apeer.info.log_id,
+					       class.bits());
+					return Err(Error::CapabilityLimit(class));
+				}
+			}
+			let addr = apeer.info.addr;
+			let existing = peers.iter()
+				.find(|p| p.is_connected() && same_node(&p.info, &apeer.info))
+				.cloned();
+			if let Some(p) = existing {
+				debug!("{} Already connected to {} as peer {}, closing the new connection.",
+				       apeer.info.log_id,
+				       addr,
+				       p.info.id);
+				let existing: Result<(Option<PeerStream>, Arc<Peer>), ()> = Ok((None, p));
+				return Ok(existing);
+			}
+			peers.push(apeer.clone());
+			peers.iter().filter(|p| p.is_connected()).count() as u32
+		};
+		log_connected(&apeer.info, &advertised);
+		adapter.peer_connected(&apeer.info);
+		record_churn(&churn);
+		notify_waiters(&waiters, count);
+		let added: Result<(Option<PeerStream>, Arc<Peer>), ()> = Ok((Some(conn), apeer));
+		Ok(added)
+	});
+	Box::new(peer_add)
+}
+
+// Rough ratio of two difficulties, the first being lower. Both get shifted
+// down to fit in a u64 without losing much precision.
+fn difficulty_ratio(lower: &Difficulty, higher: &Difficulty) -> f64 {
+	let (lower, higher) = (lower.clone().into_biguint(), higher.clone().into_biguint());
+	let shift = higher.bits().saturating_sub(53);
+	let lower = (lower >> shift).to_u64().unwrap_or(0);
+	let higher = (higher >> shift).to_u64().unwrap_or(0);
+	if higher == 0 {
+		return 1.0;
+	}
+	lower as f64 / higher as f64
+}
+
+// Records the local IP of a connection, one of ours.
+fn record_local_ip(local_ips: &Mutex<HashSet<IpAddr>>, conn: &PeerStream) {
+	if let Ok(local) = conn.local_addr() {
+		local_ips.lock().unwrap_or_else(|e| e.into_inner()).insert(local.ip());
+	}
+}
+
+// Records the address a host we're connected with sees us at, each host
+// counting once towards our public address.
+fn record_observed(observed: &Mutex<HashMap<IpAddr, SocketAddr>>,
+                   observer: IpAddr,
+                   seen: SocketAddr) {
+	if seen.ip().is_unspecified() || seen.port() == 0 {
+		return;
+	}
+	let mut observed = observed.lock().unwrap_or_else(|e| e.into_inner());
+	if observed.len() < MAX_ADDR_OBSERVERS || observed.contains_key(&observer) {
+		observed.insert(observer, seen);
+	}
+}
+
+// Wakes up those waiting for no more than the provided number of peers.
+fn notify_waiters(waiters: &Mutex<Vec<(u32, oneshot::Sender<()>)>>, count: u32) {
+	let mut waiters = waiters.lock().unwrap_or_else(|e| e.into_inner());
+	let waiting = waiters.drain(..).collect::<Vec<_>>();
+	for (n, tx) in waiting {
+		if n <= count {
+			let _ = tx.send(());
+		} else {
+			waiters.push((n, tx));
+		}
+	}
+}
+
+// Checkpoints agreed on by a strict majority of the peers reporting a
+// checkpoint at the same height, and at least MIN_CHECKPOINT_PEERS of them,
+// given what each peer reported. Also returns the peers reporting another
+// hash at the height of an agreed checkpoint.
+fn majority_checkpoints(reports: &[(SocketAddr, Vec<Checkpoint>)])
+                        -> (Vec<Checkpoint>, HashSet<SocketAddr>) {
+	let mut by_height: HashMap<u64, HashMap<Hash, Vec<SocketAddr>>> = HashMap::new();
+	for &(addr, ref checkpoints) in reports {
+		for cp in checkpoints {
+			let voters = by_height.entry(cp.height)
+				.or_insert_with(HashMap::new)
+				.entry(cp.hash)
+				.or_insert_with(Vec::new);
+			if !voters.contains(&addr) {
+				voters.push(addr);
+			}
+		}
+	}
+
+	let mut agreed = vec![];
+	let mut conflicting = HashSet::new();
+	for (height, votes) in by_height {
+		let total = votes.values().map(|v| v.len()).sum::<usize>();
+		let best = votes.iter().max_by_key(|&(_, v)| v.len()).map(|(h, v)| (*h, v.len()));
+		if let Some((hash, count)) = best {
+			if count >= MIN_CHECKPOINT_PEERS && count * 2 > total {
+				agreed.push(Checkpoint {
+					height: height,
+					hash: hash,
+				});
+				for (_, voters) in votes.iter().filter(|&(h, _)| *h != hash) {
+					conflicting.extend(voters.iter().cloned());
+				}
+			}
+		}
+	}
+	agreed.sort_by_key(|cp| cp.height);
+	(agreed, conflicting)
+}
+
+// Number of connected inbound peers advertising exactly the provided
+// capabilities, whether they can encrypt aside.
+fn class_count(peers: &Vec<Arc<Peer>>, class: Capabilities) -> u32 {
+	peers.iter()
+		.filter(|p| {
+			p.is_connected() && p.info.direction == Direction::Inbound &&
+			p.info.capabilities - ENCRYPTED == class
+		})
+		.count() as u32
+}
+
+// The capability class of the peer, if it has a limit that's already reached.
+fn full_class(peers: &Vec<Arc<Peer>>,
+              info: &PeerInfo,
+              limits: &Vec<(Capabilities, u32)>)
+              -> Option<Capabilities> {
+	limits.iter()
+		.find(|&&(class, max)| {
+			info.capabilities - ENCRYPTED == class && class_count(peers, class) >= max
+		})
+		.map(|&(class, _)| class)
+}
+
+// Whether a peer is worth keeping after the handshake, given the services we
+// offered it: either side needs to serve something to the other. Preferred
+// peers are always kept.
+fn useful_peer(info: &PeerInfo, offered: Services, preferred: &Vec<IpAddr>) -> bool {
+	is_preferred(preferred, info) || !info.services.is_empty() || !offered.is_empty()
+}
+
+// Whether the peer is one of our preferred ones, going by the IP its
+// connection comes from so that no peer can pass for one.
+fn is_preferred(preferred: &Vec<IpAddr>, info: &PeerInfo) -> bool {
+	preferred.contains(&remote_ip(info))
+}
+
+// Logs what a newly connected peer told about itself in the handshake, all in
+// one line to make it easy to analyze what the network is made of.
+fn log_connected(info: &PeerInfo, advertised: &Difficulty) {
+	info!("{} Peer connected: addr={} direction={:?} version={} capabilities={:b} services={:b} \
+	       features={:b} user_agent={} total_difficulty={}",
+	      info.log_id,
+	      info.addr,
+	      info.direction,
+	      info.version,
+	      info.capabilities.bits(),
+	      info.services.bits(),
+	      info.features.bits(),
+	      info.user_agent,
+	      advertised);
+}
+
+// Clamps an advertised difficulty to the provided ceiling, so a peer can't
+// claim an arbitrarily large amount of work.
+fn cap_difficulty(diff: Difficulty, max: &Difficulty) -> Difficulty {
+	if diff > *max {
+		debug!("Clamping implausible advertised difficulty {} to {}.", diff, max);
+		max.clone()
+	} else {
+		diff
+	}
+}
+
+// Counts a peer connection or disconnection towards churn.
+fn record_churn(churn: &Mutex<Churn>) {
+	churn.lock().unwrap_or_else(|e| e.into_inner()).record(Instant::now());
+}
+
+// Counts a session with the peer that just ended towards it being flaky,
+// quarantining the host its connection came from once it failed soon after
+// connecting too many times.
+fn record_session(scheduler: &Mutex<DialScheduler>,
+                  restrictions: &Mutex<Restrictions>,
+                  terms: Terms,
+                  peer: &Peer) {
+	let now = Instant::now();
+	let mut scheduler = scheduler.lock().unwrap_or_else(|e| e.into_inner());
+	if scheduler.session_ended(peer.info.addr, peer.uptime(), now) {
+		warn!("{} Keeps dropping soon after connecting, quarantined.", peer.info.log_id);
+		quarantine_host(restrictions, terms, remote_ip(&peer.info), "flaky connections", now);
+	}
+}
+
+// Number of connected outbound peers in each of their subnets.
+fn outbound_by_subnet(peers: &Vec<Arc<Peer>>) -> HashMap<Vec<u8>, u32> {
+	let mut subnets = HashMap::new();
+	for p in peers.iter().filter(|p| p.is_connected() && p.info.direction == Direction::Outbound) {
+		*subnets.entry(subnet(&p.info.addr.ip())).or_insert(0) += 1;
+	}
+	subnets
+}
+
+// Resolves once the scheduler lets the next outbound dial go out, taking its
+// turn right away.
+fn pace_dial(scheduler: &Mutex<DialScheduler>,
+             h: &reactor::Handle)
+             -> Box<Future<Item = (), Error = Error>> {
+	let wait = scheduler.lock().unwrap_or_else(|e| e.into_inner()).pace(Instant::now());
+	if wait == Duration::new(0, 0) {
+		return Box::new(future::ok(()));
+	}
+	match reactor::Timeout::new(wait, h) {
+		Ok(timeout) => Box::new(timeout.map_err(Error::Connection)),
+		Err(e) => Box::new(future::err(Error::Connection(e))),
+	}
+}
+
+// What we learn of peers as we connect to them, to be written to the peer
+// store.
+enum StoreUpdate {
+	Connected(SocketAddr, Capabilities, String),
+	Unreachable(SocketAddr),
+	Seen(SocketAddr),
+}
+
+// The peer store we were given if any, along with the queue of the updates
+// for it. The updates get written on a thread of their own, so the event
+// loop never waits on the store.
+struct PeerBook {
+	store: Option<Arc<PeerStore>>,
+	updates: Mutex<Option<mpsc::SyncSender<StoreUpdate>>>,
+}
+
+impl PeerBook {
+	fn new(store: Option<Arc<PeerStore>>) -> PeerBook {
+		let updates = store.clone().and_then(write_updates);
+		PeerBook {
+			store: store,
+			updates: Mutex::new(updates),
+		}
+	}
+
+	// How good a bet dialing the address is according to the store, zero if
+	// we have no store or it knows nothing of the address.
+	fn quality(&self, addr: &SocketAddr) -> f64 {
+		self.store.as_ref().map(|s| s.peer_quality(addr)).unwrap_or(0.0)
+	}
+
+	// Queues the update to be written to the store, dropping it if the
+	// store is that far behind already.
+	fn record(&self, update: StoreUpdate) {
+		let updates = self.updates.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(ref updates) = *updates {
+			if let Err(mpsc::TrySendError::Full(_)) = updates.try_send(update) {
+				debug!("Peer store updates backed up, dropping one.");
+			}
+		}
+	}
+}
+
+// Starts writing the updates sent on the returned channel to the store, until
+// the channel gets dropped.
+fn write_updates(store: Arc<PeerStore>) -> Option<mpsc::SyncSender<StoreUpdate>> {
+	let (tx, rx) = mpsc::sync_channel(MAX_STORE_UPDATES);
+	let res = thread::Builder::new().name("p2p-store".to_string()).spawn(move || {
+		for update in rx {
+			let res = match update {
+				StoreUpdate::Connected(addr, capab, ua) => store.record_connected(addr, capab, &ua),
+				StoreUpdate::Unreachable(addr) => store.record_failure(addr),
+				StoreUpdate::Seen(addr) => store.record_seen(addr),
+			};
+			if let Err(e) = res {
+				warn!("Could not update the peer store: {:?}", e);
+			}
+		}
+	});
+	match res {
+		Ok(_) => Some(tx),
+		Err(e) => {
+			warn!("Could not start writing to the peer store: {:?}", e);
+			None
+		}
+	}
+}
+
+// Records a peer we just connected to in the peer store, under the address
+// its connection comes from. Peers that don't accept connections are left
+// out, there's no dialing them.
+fn record_connected(book: &PeerBook, info: &PeerInfo) {
+	if info.reachable {
+		book.record(StoreUpdate::Connected(info.node_addr(),
+		                                   info.capabilities,
+		                                   info.user_agent.clone()));
+	}
+}
+
+// Records an address we failed to reach in the peer store.
+fn record_unreachable(book: &PeerBook, addr: SocketAddr) {
+	book.record(StoreUpdate::Unreachable(addr));
+}
+
+// Remembers when a peer we got disconnected from was last seen, in the peer
+// store too.
+fn record_departure(departed: &Mutex<HashMap<SocketAddr, Instant>>,
+                    book: &PeerBook,
+                    info: &PeerInfo) {
+	book.record(StoreUpdate::Seen(info.node_addr()));
+	let mut departed = departed.lock().unwrap_or_else(|e| e.into_inner());
+	remember_departure(&mut departed, info.addr, Instant::now());
+}
+
+// Adds a departed peer to those remembered, which never grow past
+// MAX_DEPARTED: once full, those gone for too long are forgotten and, if
+// that didn't make room, the longest gone one.
+fn remember_departure(departed: &mut HashMap<SocketAddr, Instant>,
+                      addr: SocketAddr,
+                      now: Instant) {
+	if departed.len() >= MAX_DEPARTED && !departed.contains_key(&addr) {
+		let max_age = Duration::from_secs(DEPARTED_SECS);
+		departed.retain(|_, seen| *seen + max_age > now);
+		if departed.len() >= MAX_DEPARTED {
+			let oldest = departed.iter().min_by_key(|&(_, seen)| *seen).map(|(a, _)| *a);
+			if let Some(oldest) = oldest {
+				departed.remove(&oldest);
+			}
+		}
+	}
+	departed.insert(addr, now);
+}
+
+// Counts a failed handshake under the reason derived from its error.
+fn record_failure(failures: &Mutex<HashMap<HandshakeFailure, u64>>, e: &Error) {
+	debug!("Handshake failed: {:?}", e);
+	let mut failures = failures.lock().unwrap_or_else(|e| e.into_inner());
+	*failures.entry(HandshakeFailure::from_error(e)).or_insert(0) += 1;
+}
+
+// Adds a handshake failing on a message we couldn't decode to the misbehavior
+// score of the host, quarantining it once it reaches the ban score.
+fn score_handshake(scores: &Mutex<HostScores>,
+                   restrictions: &Mutex<Restrictions>,
+                   terms: Terms,
+                   ip: IpAddr,
+                   e: &Error,
+                   ban_score: u32) {
+	match *e {
+		Error::Serialization(_) if ban_score > 0 => {}
+		_ => return,
+	}
+	let now = Instant::now();
+	let mut scores = scores.lock().unwrap_or_else(|e| e.into_inner());
+	if scores.add(ip, Violation::MalformedHandshake.score(), now) >= ban_score {
+		warn!("Host {} reached the ban score with its handshakes, quarantined.", ip);
+		scores.remove(&ip);
+		quarantine_host(restrictions, terms, ip, "malformed handshakes", now);
+	}
+}
+
+// What a connected peer did so far, see Server::peer_stats.
+fn peer_stats(p: &Peer) -> PeerStats {
+	let (sent, received) = p.transmitted_bytes();
+	let (sent_msgs, received_msgs) = p.transmitted_msgs();
+	PeerStats {
+		id: p.info.id,
+		addr: p.info.addr,
+		direction: p.info.direction,
+		version: p.info.version,
+		user_agent: p.info.user_agent.clone(),
+		total_difficulty: p.total_difficulty(),
+		height: p.height(),
+		latency: p.latency(),
+		sent_bytes: sent,
+		received_bytes: received,
+		sent_msgs: sent_msgs,
+		received_msgs: received_msgs,
+		uptime_secs: p.uptime().as_secs(),
+	}
+}
+
+// Restricts the host a peer got banned for its messages connected from,
+// unless it's restricted already: quarantined for reaching the ban score and
+// banned for sending messages we couldn't make sense of. Returns the
+// restriction if it got one.
+fn restrict_misbehaving(restrictions: &Mutex<Restrictions>,
+                        terms: Terms,
+                        peer: &Peer,
+                        e: &Error)
+                        -> Option<BanEntry> {
+	let (severity, reason) = match *e {
+		Error::Misbehaving => (Severity::Quarantine, "ban score reached"),
+		Error::Serialization(_) => (Severity::Ban, "corrupted messages"),
+		_ => return None,
+	};
+	let now = Instant::now();
+	let mut restrictions = restrictions.lock().unwrap_or_else(|e| e.into_inner());
+	let ip = remote_ip(&peer.info);
+	if restrictions.contains(&ip, now) {
+		return None;
+	}
+	warn!("{} Restricted for its messages: {}.", peer.info.log_id, reason);
+	Some(restrictions.restrict(ip, severity, reason, terms, now).0)
+}
+
+// Handshake handler for the network we run on, advertising our services and
+// features, flagging peers as slow or reusing nonces and refusing skewed
+// clocks based on our configuration.
+fn new_handshake(config: &P2PConfig) -> Handshake {
+	Handshake::configured(config.services,
+	                      Duration::from_millis(config.slow_handshake_ms),
+	                      config.features,
+	                      config.duplicate_nonce)
+		.with_network(config.magic, config.genesis)
+		.with_unsolicited_blocks(config.unsolicited_blocks)
+		.with_oversized_addrs(config.oversized_addrs)
+		.with_orphan_blocks(config.orphan_blocks)
+		.with_dumps(config.dump_invalid_msgs)
+		.with_clock_tolerance(config.max_clock_skew_secs)
+		.with_ban_score(config.ban_score)
+}
+
+// Adds a timeout of the provided duration to a future
+fn with_timeout<T: 'static>(fut: Box<Future<Item = Result<T, ()>, Error = Error>>,
+                            timeout: Duration,
+                            h: &reactor::Handle)
+                            -> Box<Future<Item = T, Error = Error>> {
+	let timeout = reactor::Timeout::new(timeout, h).unwrap();
+	let timed = fut.select(timeout.map(Err).from_err())
+		.then(|res| {
+			match res {
+				Ok((Ok(inner), _timeout)) => Ok(inner),
+				Ok((_, _accept)) => Err(Error::Timeout),
+				Err((e, _other)) => Err(e),
+			}
+		});
+	Box::new(timed)
+}
+
+// Throttle for the writes to a new peer given the configured limits.
+fn new_throttle(bucket: &Option<Arc<TokenBucket>>,
+                peer_rate: u64,
+                send_timeout_secs: u64,
+                inbound: InboundLimits,
+                timer: &Option<Timer>)
+                -> Throttle {
+	match *timer {
+		Some(ref timer) if send_timeout_secs > 0 => {
+			Throttle::new(bucket.clone(), peer_rate, timer.clone())
+				.with_write_timeout(Duration::from_secs(send_timeout_secs))
+				.with_read_limit(ReadLimit::new(inbound, timer.clone()))
+		}
+		Some(ref timer) => {
+			Throttle::new(bucket.clone(), peer_rate, timer.clone())
+				.with_read_limit(ReadLimit::new(inbound, timer.clone()))
+		}
+		None => Throttle::unlimited(),
+	}
+}
+
+/// Tracks peer connections and disconnections over a sliding window, warning
+/// when there are too many of them.
+struct Churn {
+	window: Duration,
+	alarm: u32,
+	events: VecDeque<Instant>,
+}
+
+impl Churn {
+	fn new(window: Duration, alarm: u32) -> Churn {
+		Churn {
+			window: window,
+			alarm: alarm,
+			events: VecDeque::new(),
+		}
+	}
+
+	/// Records a connection or disconnection.
+	fn record(&mut self, now: Instant) {
+		self.events.push_back(now);
+		let churn = self.count(now);
+		if self.alarm > 0 && churn > self.alarm {
+			warn!("High peer churn, {} connections and disconnections in the last {}s.",
+			      churn,
+			      self.window.as_secs());
+		}
+	}
+
+	/// Number of connections and disconnections within the window.
+	fn count(&mut self, now: Instant) -> u32 {
+		while self.events.front().map_or(false, |t| now.duration_since(*t) > self.window) {
+			self.events.pop_front();
+		}
+		self.events.len() as u32
+	}
+}
+
+// Ping interval of a peer, with its prompt pongs in a row and when it was
+// last pinged, if not waiting for its pong.
+struct PingState {
+	interval: Duration,
+	prompt: u32,
+	last: Instant,
+	waiting: bool,
+	// pongs missed in a row
+	missed: u32,
+}
+
+/// When each peer is due for a ping. The interval of a peer doubles, up to
+/// the max, after a few prompt pongs in a row and halves, down to the min,
+/// after a slow one. A missed pong takes it right back to the min, too many
+/// in a row getting the peer deemed unresponsive.
+struct Pings {
+	min: Duration,
+	max: Duration,
+	slow: Duration,
+	peers: HashMap<PeerId, PingState>,
+}
+
+impl Pings {
+	fn new(config: &P2PConfig) -> Pings {
+		let min = Duration::from_secs(config.ping_interval_min_secs);
+		Pings {
+			min: min,
+			max: cmp::max(Duration::from_secs(config.ping_interval_max_secs), min),
+			slow: Duration::from_millis(config.ping_slow_ms),
+			peers: HashMap::new(),
+		}
+	}
+
+	/// Whether the peer is due for a ping, a peer we didn't know of yet being
+	/// first pinged after the min interval.
+	fn due(&mut self, id: PeerId, now: Instant) -> bool {
+		let min = self.min;
+		let state = self.peers.entry(id).or_insert_with(|| {
+			PingState {
+				interval: min,
+				prompt: 0,
+				last: now,
+				waiting: false,
+				missed: 0,
+			}
+		});
+		!state.waiting && now.duration_since(state.last) >= state.interval
+	}
+
+	/// Records a ping sent to the peer.
+	fn sent(&mut self, id: PeerId, now: Instant) {
+		if let Some(state) = self.peers.get_mut(&id) {
+			state.last = now;
+			state.waiting = true;
+		}
+	}
+
+	/// Records the pong of the peer, received now.
+	fn pong(&mut self, id: PeerId, now: Instant) {
+		let (min, max, slow) = (self.min, self.max, self.slow);
+		if let Some(state) = self.peers.get_mut(&id) {
+			state.waiting = false;
+			state.missed = 0;
+			if now.duration_since(state.last) > slow {
+				state.prompt = 0;
+				state.interval = cmp::max(state.interval / 2, min);
+				return;
+			}
+			state.prompt += 1;
+			if state.prompt >= PING_PROMPT_STREAK {
+				state.prompt = 0;
+				state.interval = cmp::min(state.interval * 2, max);
+			}
+		}
+	}
+
+	/// Records the pong of the peer as missed.
+	fn missed(&mut self, id: PeerId) {
+		if let Some(state) = self.peers.get_mut(&id) {
+			state.waiting = false;
+			state.prompt = 0;
+			state.interval = self.min;
+			state.missed += 1;
+		}
+	}
+
+	/// The peers that missed at least max pongs in a row.
+	fn unresponsive(&self, max: u32) -> Vec<PeerId> {
+		self.peers.iter().filter(|&(_, state)| state.missed >= max).map(|(id, _)| *id).collect()
+	}
+
+	/// Current ping interval of the peer.
+	fn interval(&self, id: PeerId) -> Option<Duration> {
+		self.peers.get(&id).map(|state| state.interval)
+	}
+
+	/// Forgets the peers not in the provided ones.
+	fn keep(&mut self, ids: &[PeerId]) {
+		let gone = self.peers.keys().filter(|id| !ids.contains(id)).cloned().collect::<Vec<_>>();
+		for id in gone {
+			self.peers.remove(&id);
+		}
+	}
+}
+
+/// Tracks the rate of inbound connections over one second windows.
+struct InboundRate {
+	start: Instant,
+	count: u32,
+	prev: u32,
+}
+
+impl InboundRate {
+	fn new(now: Instant) -> InboundRate {
+		InboundRate {
+			start: now,
+			count: 0,
+			prev: 0,
+		}
+	}
+
+	/// Records a new inbound connection, returning the current rate per second.
+	fn record(&mut self, now: Instant) -> u32 {
+		let elapsed = now.duration_since(self.start);
+		if elapsed >= Duration::from_secs(2) {
+			self.prev = 0;
+			self.count = 0;
+			self.start = now;
+		} else if elapsed >= Duration::from_secs(1) {
+			self.prev = self.count;
+			self.count = 0;
+			self.start = self.start + Duration::from_secs(1);
+		}
+		self.count += 1;
+		cmp::max(self.count, self.prev)
+	}
+}
+
+/// Handshakes in progress that got a peer slot, counted toward the peer
+/// limits along with the connected peers so concurrent handshakes can't take
+/// us past them.
+#[derive(Default)]
+struct PendingPeers {
+	inbound: u32,
+	// inbound ones from hosts that aren't preferred
+	regular_inbound: u32,
+	outbound: u32,
+}
+
+/// The slot of a handshake in progress, given up when dropped.
+struct PendingSlot {
+	pending: Arc<Mutex<PendingPeers>>,
+	direction: Direction,
+	regular: bool,
+}
+
+impl PendingSlot {
+	/// Reserves a slot in the provided direction if the provided check of the
+	/// handshakes already in progress says there's room, atomically with
+	/// other reservations.
+	fn reserve_if<F>(pending: &Arc<Mutex<PendingPeers>>,
+	                 direction: Direction,
+	                 regular: bool,
+	                 room: F)
+	                 -> Option<PendingSlot>
+		where F: FnOnce(&PendingPeers) -> bool
+	{
+		let mut p = pending.lock().unwrap_or_else(|e| e.into_inner());
+		if !room(&*p) {
+			return None;
+		}
+		match direction {
+			Direction::Inbound => {
+				p.inbound += 1;
+				if regular {
+					p.regular_inbound += 1;
+				}
+			}
+			Direction::Outbound => p.outbound += 1,
+		}
+		Some(PendingSlot {
+			pending: pending.clone(),
+			direction: direction,
+			regular: regular,
+		})
+	}
+}
+
+impl Drop for PendingSlot {
+	fn drop(&mut self) {
+		let mut p = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+		match self.direction {
+			Direction::Inbound => {
+				p.inbound -= 1;
+				if self.regular {
+					p.regular_inbound -= 1;
+				}
+			}
+			Direction::Outbound => p.outbound -= 1,
+		}
+	}
+}
+
+/// Bounds the number of outbound dials in flight, dials past the limit wait
+/// for a slot to free up in the order they were started. Only so many get to
+/// wait, those past it fail right away.
+struct DialLimit {
+	max: usize,
+	in_flight: usize,
+	waiting: VecDeque<oneshot::Sender<DialSlot>>,
+	max_waiting: usize,
+}
+
+impl DialLimit {
+	fn new(max: u32) -> DialLimit {
+		DialLimit {
+			max: cmp::max(max, 1) as usize,
+			in_flight: 0,
+			waiting: VecDeque::new(),
+			max_waiting: MAX_WAITING_DIALS,
+		}
+	}
+}
+
+/// One of the dial slots of a DialLimit, freed when dropped.
+struct DialSlot {
+	limit: Arc<Mutex<DialLimit>>,
+}
+
+impl DialSlot {
+	/// Resolves once a slot is available, only asking for one when first
+	/// polled.
+	fn acquire(limit: &Arc<Mutex<DialLimit>>) -> Box<Future<Item = DialSlot, Error = Error>> {
+		let limit = limit.clone();
+		Box::new(future::lazy(move || -> Box<Future<Item = DialSlot, Error = Error>> {
+			let mut l = limit.lock().unwrap_or_else(|e| e.into_inner());
+			if l.in_flight < l.max {
+				l.in_flight += 1;
+				return Box::new(future::ok(DialSlot { limit: limit.clone() }));
+			}
+			// dials given up on while waiting leave their place
+			if l.waiting.len() >= l.max_waiting {
+				l.waiting.retain(|tx| !tx.is_canceled());
+			}
+			if l.waiting.len() >= l.max_waiting {
+				debug!("Already {} dials waiting for a slot, not dialing.", l.waiting.len());
+				return Box::new(future::err(Error::TooManyDials));
+			}
+			let (tx, rx) = oneshot::channel();
+			l.waiting.push_back(tx);
+			Box::new(rx.map_err(|_| Error::ConnectionClose))
+		}))
+	}
+
+	/// Changes the number of slots, handing the new ones to the longest
+	/// waiting dials. Slots above a lowered maximum are retired as they free
+	/// up.
+	fn resize(limit: &Arc<Mutex<DialLimit>>, max: u32) {
+		let mut released = vec![];
+		{
+			let mut l = limit.lock().unwrap_or_else(|e| e.into_inner());
+			l.max = cmp::max(max, 1) as usize;
+			while l.in_flight < l.max {
+				match l.waiting.pop_front() {
+					Some(tx) => {
+						l.in_flight += 1;
+						released.push(tx);
+					}
+					None => break,
+				}
+			}
+		}
+		for tx in released {
+			let _ = tx.send(DialSlot { limit: limit.clone() });
+		}
+	}
+}
+
+impl Drop for DialSlot {
+	fn drop(&mut self) {
+		let next = {
+			let mut limit = self.limit.lock().unwrap_or_else(|e| e.into_inner());
+			let next = if limit.in_flight > limit.max {
+				None
+			} else {
+				limit.waiting.pop_front()
+			};
+			match next {
+				Some(tx) => tx,
+				None => {
+					limit.in_flight -= 1;
+					return;
+				}
+			}
+		};
+		// the slot goes over as is to the longest waiting dial, if that one got
+		// dropped already the slot comes back and gets dropped in turn
+		let _ = next.send(DialSlot { limit: self.limit.clone() });
+	}
+}
+
+/// Whether we ran out of file descriptors, with when and how many peers we
+/// had then. No connection gets opened until we're down on peers or some
+/// time passed.
+struct FdExhaustion {
+	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+	since: Mutex<Option<(Instant, usize)>>,
+}
+
+impl FdExhaustion {
+	fn new(peers: Arc<RwLock<Vec<Arc<Peer>>>>) -> FdExhaustion {
+		FdExhaustion {
+			peers: peers,
+			since: Mutex::new(None),
+		}
+	}
+
+	/// Records running out of file descriptors while doing what's provided,
+	/// warning about it the first time.
+	fn exhausted(&self, doing: &str) {
+		let count = self.peer_count();
+		let mut since = self.since.lock().unwrap_or_else(|e| e.into_inner());
+		if since.is_none() {
+			warn!("Out of file descriptors {} with {} peers, not opening connections for now. \
+			       Raise the open files limit (ulimit -n) or lower max_inbound_peers.",
+			      doing,
+			      count);
+		}
+		*since = Some((Instant::now(), count));
+	}
+
+	/// Whether we can open connections, that is we didn't run out of file
+	/// descriptors or some got freed since.
+	fn available(&self) -> bool {
+		let count = self.peer_count();
+		let resume_after = Duration::from_secs(FD_RESUME_SECS);
+		let mut since = self.since.lock().unwrap_or_else(|e| e.into_inner());
+		let resume = match *since {
+			Some((at, peers)) => count < peers || at.elapsed() >= resume_after,
+			None => return true,
+		};
+		if resume {
+			info!("Opening connections again after running out of file descriptors.");
+			*since = None;
+		}
+		resume
+	}
+
+	fn peer_count(&self) -> usize {
+		connected_count(&self.peers) as usize
+	}
+}
+
+/// A transaction we passed on along the dandelion stem, broadcast by us once
+/// its embargo ends unless we saw it broadcast meanwhile.
+struct Embargo {
+	tx: core::Transaction,
+	until: Instant,
+	// peers knowing of the transaction when we stemmed it
+	through: Vec<SocketAddr>,
+}
+
+/// Rotates the peers broadcasts go to when limited to a fanout, so they all
+/// get their share over successive broadcasts rather than always the same.
+struct BroadcastRotation {
+	round: u64,
+	// round of the latest broadcast each peer got
+	last_used: HashMap<PeerId, u64>,
+}
+
+impl BroadcastRotation {
+	fn new() -> BroadcastRotation {
+		BroadcastRotation {
+			round: 0,
+			last_used: HashMap::new(),
+		}
+	}
+
+	/// Picks the peers the next broadcast goes to among the candidates: the
+	/// ones always included, plus up to fanout others, those left out the
+	/// longest first. Peers that aren't candidates anymore are forgotten.
+	fn pick(&mut self, candidates: &[PeerId], always: &[PeerId], fanout: usize) -> Vec<PeerId> {
+		self.round += 1;
+		let mut others =
+			candidates.iter().filter(|id| !always.contains(id)).cloned().collect::<Vec<_>>();
+		// peers left out equally long are picked at random, at first all of them
+		rand::thread_rng().shuffle(&mut others);
+		others.sort_by_key(|id| self.last_used.get(id).cloned().unwrap_or(0));
+		others.truncate(fanout);
+
+		let picked = always.iter().cloned().chain(others).collect::<Vec<_>>();
+		let mut last_used = HashMap::new();
+		for id in candidates {
+			if let Some(round) = self.last_used.get(id) {
+				last_used.insert(*id, *round);
+			}
+		}
+		for id in &picked {
+			last_used.insert(*id, self.round);
+		}
+		self.last_used = last_used;
+		picked
+	}
+}
+
+// Random delay before greeting a new peer given the current inbound rate, zero
+// unless the rate is above the threshold.
+fn greeting_delay(rate: u32, threshold: u32, max_ms: u64) -> Duration {
+	if threshold == 0 || max_ms == 0 || rate <= threshold {
+		return Duration::new(0, 0);
+	}
+	Duration::from_millis(rand::thread_rng().gen_range(1, max_ms + 1))
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashSet;
+	use std::env;
+	use std::fs;
+	use std::io::{self, Read, Write};
+	use std::net::{self, SocketAddr};
+	use std::sync::{mpsc, Arc, Mutex, Once, ONCE_INIT, RwLock};
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use futures::stream;
+	use log;
+	use time;
+
+	use core::core;
+	use core::core::hash::{Hash, Hashed, ZERO_HASH};
+	use core::core::target::Difficulty;
+	use core::ser;
+	use msg::*;
+	use proxy::onion_addr;
+	use test_network::{free_port, poll_until, TestNetwork};
+	use types::*;
+	use super::*;
+
+	// Magic bytes of the network the test servers and handshakes run on.
+	fn magic() -> [u8; 2] {
+		P2PConfig::default().magic
+	}
+
+	#[test]
+	fn poisoned_peers_lock() {
+		let server = Server::new(UNKNOWN, P2PConfig::default(), Arc::new(DummyAdapter {}));
+
+		// poison the lock by panicking while holding it
+		let peers = server.peers.clone();
+		let res = thread::spawn(move || {
+				let _peers = peers.write().unwrap();
+				panic!("poisoning the peers lock");
+			})
+			.join();
+		assert!(res.is_err());
+		assert!(server.peers.is_poisoned());
+
+		// the server should still be able to serve
+		assert_eq!(server.peer_count(), 0);
+		assert!(server.most_work_peer().is_none());
+		assert!(server.connected_peers().is_empty());
+		assert_eq!(server.clean_peers().len(), 0);
+	}
+
+	#[test]
+	fn greeting_delay_engages() {
+		let zero = Duration::new(0, 0);
+		assert_eq!(greeting_delay(10, 50, 500), zero);
+		assert_eq!(greeting_delay(50, 50, 500), zero);
+		assert_eq!(greeting_delay(1000, 0, 500), zero);
+		for _ in 0..20 {
+			let delay = greeting_delay(51, 50, 500);
+			assert!(delay > zero && delay <= Duration::from_millis(500));
+		}
+	}
+
+	#[test]
+	fn broadcasts_rotated() {
+		let mut rotation = BroadcastRotation::new();
+		let ids = (1..6).map(PeerId).collect::<Vec<_>>();
+
+		// the preferred peer gets every broadcast, the others take turns
+		let first = rotation.pick(&ids, &[PeerId(5)], 2);
+		let second = rotation.pick(&ids, &[PeerId(5)], 2);
+		assert_eq!((first.len(), second.len()), (3, 3));
+		assert!(first.contains(&PeerId(5)) && second.contains(&PeerId(5)));
+		let mut taken = first.iter().chain(second.iter()).cloned().collect::<Vec<_>>();
+		taken.sort();
+		taken.dedup();
+		assert_eq!(taken, ids);
+
+		// a peer gone is forgotten, a new one goes first
+		let ids = vec![PeerId(2), PeerId(3), PeerId(6)];
+		assert_eq!(rotation.pick(&ids, &[], 1), vec![PeerId(6)]);
+		assert!(!rotation.last_used.contains_key(&PeerId(1)));
+	}
+
+	#[test]
+	fn inbound_rate_windows() {
+		let start = Instant::now();
+		let mut rate = InboundRate::new(start);
+		for n in 1..11 {
+			assert_eq!(rate.record(start), n);
+		}
+		// the previous window still counts for a second
+		assert_eq!(rate.record(start + Duration::from_millis(1500)), 10);
+		assert_eq!(rate.record(start + Duration::from_secs(5)), 1);
+	}
+
+	/// Adapter recording the peer connections and errors it's notified of, as
+	/// well as the heights of the blocks it validated.
+	struct RecordingAdapter {
+		connected: Mutex<Vec<Direction>>,
+		errors: Mutex<Vec<(SocketAddr, bool)>>,
+		disconnected: Mutex<Vec<SocketAddr>>,
+		validated: Mutex<Vec<u64>>,
+		// height of a block taking a long time to validate
+		slow_height: Option<u64>,
+		// height of a block the adapter panics on
+		panic_height: Option<u64>,
+		// height of blocks the adapter finds invalid
+		invalid_height: Option<u64>,
+		difficulty: Difficulty,
+		head: Hash,
+		height: u64,
+		services: Services,
+		// checkpoints reported to peers and those agreed by ours
+		reported: Vec<Checkpoint>,
+		agreed: Mutex<Vec<Checkpoint>>,
+		// chunks of the snapshot of our unspent outputs at our head
+		utxo_chunks: u32,
+		// transactions stemmed to us
+		stemmed: Mutex<Vec<Hash>>,
+		// transactions no longer kept from the public
+		fluffed: Mutex<Vec<Hash>>,
+		banned: Mutex<Vec<(SocketAddr, Severity)>>,
+		// host the adapter doesn't allow connections with
+		blocked: Option<IpAddr>,
+		// addresses supplied to bootstrap from
+		bootstrap: Vec<SocketAddr>,
+		// sources of the gossiped addresses received
+		sources: Mutex<Vec<SocketAddr>>,
+	}
+
+	impl RecordingAdapter {
+		fn new() -> RecordingAdapter {
+			RecordingAdapter {
+				connected: Mutex::new(vec![]),
+				errors: Mutex::new(vec![]),
+				disconnected: Mutex::new(vec![]),
+				validated: Mutex::new(vec![]),
+				slow_height: None,
+				panic_height: None,
+				invalid_height: None,
+				difficulty: Difficulty::one(),
+				head: ZERO_HASH,
+				height: 0,
+				services: ALL_SERVICES,
+				reported: vec![],
+				agreed: Mutex::new(vec![]),
+				utxo_chunks: 0,
+				stemmed: Mutex::new(vec![]),
+				fluffed: Mutex::new(vec![]),
+				banned: Mutex::new(vec![]),
+				blocked: None,
+				bootstrap: vec![],
+				sources: Mutex::new(vec![]),
+			}
+		}
+	}
+
+	impl NetAdapter for RecordingAdapter {
+		fn total_difficulty(&self) -> Difficulty {
+			self.difficulty.clone()
+		}
+		fn head_hash(&self) -> Hash {
+			self.head
+		}
+		fn head_height(&self) -> u64 {
+			self.height
+		}
+		fn checkpoints(&self) -> Vec<Checkpoint> {
+			self.reported.clone()
+		}
+		fn checkpoints_agreed(&self, checkpoints: Vec<Checkpoint>) {
+			*self.agreed.lock().unwrap() = checkpoints;
+		}
+		fn services(&self) -> Services {
+			self.services
+		}
+		fn transaction_received(&self, tx: core::Transaction) {}
+		fn stem_transaction_received(&self, tx: core::Transaction) {
+			self.stemmed.lock().unwrap().push(tx.hash());
+		}
+		fn transaction_fluffed(&self, tx: &core::Transaction) {
+			self.fluffed.lock().unwrap().push(tx.hash());
+		}
+		fn block_received(&self, b: core::Block, src: SocketAddr) -> BlockStatus {
+			if Some(b.header.height) == self.slow_height {
+				thread::sleep(Duration::from_secs(3));
+			}
+			if Some(b.header.height) == self.panic_height {
+				panic!("adapter failure on block {}", b.header.height);
+			}
+			if Some(b.header.height) == self.invalid_height {
+				return BlockStatus::Invalid;
+			}
+			self.validated.lock().unwrap().push(b.header.height);
+			BlockStatus::Accepted
+		}
+		fn headers_received(&self, bh: Vec<core::BlockHeader>) {}
+		fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
+			vec![]
+		}
+		fn get_block(&self, h: Hash) -> Option<core::Block> {
+			None
+		}
+		// a snapshot of empty chunks at our head
+		fn utxo_chunk(&self, horizon: Hash, chunk: u32) -> Option<(u32, Vec<core::Output>)> {
+			if horizon == self.head && chunk < self.utxo_chunks {
+				Some((self.utxo_chunks, vec![]))
+			} else {
+				None
+			}
+		}
+		fn has_block(&self, h: Hash) -> bool {
+			false
+		}
+		fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+			None
+		}
+		fn get_kernel_transaction(&self, k: &core::TxKernel) -> Option<core::Transaction> {
+			None
+		}
+		fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr> {
+			vec![]
+		}
+		fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>, src: SocketAddr) {
+			self.sources.lock().unwrap().push(src);
+		}
+		fn peer_connected(&self, pi: &PeerInfo) {
+			self.connected.lock().unwrap().push(pi.direction);
+		}
+		fn peer_error(&self, pi: &PeerInfo, err: &Error) {
+			let corrupted = match *err {
+				Error::Serialization(_) => true,
+				_ => false,
+			};
+			self.errors.lock().unwrap().push((pi.addr, corrupted));
+		}
+		fn peer_disconnected(&self, pi: &PeerInfo) {
+			self.disconnected.lock().unwrap().push(pi.addr);
+		}
+		fn peer_banned(&self, addr: SocketAddr, ban: &BanEntry) {
+			self.banned.lock().unwrap().push((addr, ban.severity));
+		}
+		fn peer_unbanned(&self, ip: IpAddr) {}
+		fn address_allowed(&self, addr: &SocketAddr) -> bool {
+			Some(addr.ip()) != self.blocked
+		}
+		fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+			self.bootstrap.clone()
+		}
+	}
+
+	fn raw_msg<W: ser::Writeable>(t: Type, body: &W) -> Vec<u8> {
+		let mut body_data = ser::ser_vec(body).unwrap();
+		let mut data = ser::ser_vec(&MsgHeader::new(magic(), t, body_data.len() as u64)).unwrap();
+		data.append(&mut body_data);
+		data
+	}
+
+	// Connects to the server at the provided address and goes through the
+	// handshake with plain blocking IO.
+	fn raw_handshake(addr: SocketAddr, sender_addr: SocketAddr) -> net::TcpStream {
+		send_hand(net::TcpStream::connect(addr).unwrap(), test_hand(addr, sender_addr))
+	}
+
+	// Same as raw_handshake, waiting for the provided delay after connecting
+	// before sending our hand advertising the provided services.
+	fn raw_handshake_with(addr: SocketAddr,
+	                      sender_addr: SocketAddr,
+	                      delay: Duration,
+	                      services: Services)
+	                      -> net::TcpStream {
+		let conn = net::TcpStream::connect(addr).unwrap();
+		thread::sleep(delay);
+		let mut hand = test_hand(addr, sender_addr);
+		hand.services = services;
+		send_hand(conn, hand)
+	}
+
+	// Hand of a test peer at sender_addr connecting to addr.
+	fn test_hand(addr: SocketAddr, sender_addr: SocketAddr) -> Hand {
+		Hand {
+			version: PROTOCOL_VERSION,
+			capabilities: UNKNOWN,
+			services: ALL_SERVICES,
+			nonce: 42,
+			total_difficulty: Difficulty::one(),
+			sender_addr: SockAddr(sender_addr),
+			receiver_addr: SockAddr(addr),
+			user_agent: "test".to_string(),
+			timestamp: Some(time::now_utc().to_timespec().sec),
+			min_version: MIN_PROTOCOL_VERSION,
+			genesis: Some(P2PConfig::default().genesis),
+		}
+	}
+
+	// Goes through the handshake on an already opened connection.
+	fn send_hand(conn: net::TcpStream, hand: Hand) -> net::TcpStream {
+		// raw messages don't carry checksums, and raw peers take full blocks
+		send_hand_with(conn, hand, ALL_FEATURES - CHECKSUMS - COMPACT_BLOCKS)
+	}
+
+	// Same as send_hand, negotiating the provided features.
+	fn send_hand_with(mut conn: net::TcpStream, hand: Hand, features: Features) -> net::TcpStream {
+		conn.write_all(&raw_msg(Type::Hand, &hand)).unwrap();
+		let mut shake_header = vec![0; HEADER_LEN as usize];
+		conn.read_exact(&mut shake_header).unwrap();
+		let shake_len = ser::deserialize::<MsgHeader>(&mut &shake_header[..]).unwrap().msg_len;
+		let mut shake = vec![0; shake_len as usize];
+		conn.read_exact(&mut shake).unwrap();
+
+		conn.write_all(&raw_msg(Type::Features, &Negotiation { features: features })).unwrap();
+		let mut theirs = vec![0; HEADER_LEN as usize + 4];
+		conn.read_exact(&mut theirs).unwrap();
+
+		// we connected so we confirm upgrades first
+		if features.intersects(UPGRADES) {
+			let upgrades = Negotiation { features: features & UPGRADES };
+			conn.write_all(&raw_msg(Type::Upgrade, &upgrades)).unwrap();
+			let mut confirmed = vec![0; HEADER_LEN as usize + 4];
+			conn.read_exact(&mut confirmed).unwrap();
+		}
+		conn
+	}
+
+	#[test]
+	fn peer_error_reported() {
+		let mut net = TestNetwork::empty();
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (_server, addr) = net.serve(P2PConfig::default(), adapter.clone());
+
+		// a peer completing the handshake and then sending a truncated block
+		let sender_addr: SocketAddr = "127.0.0.1:13506".parse().unwrap();
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, sender_addr);
+			let mut junk = ser::ser_vec(&MsgHeader::new(magic(), Type::Block, 3)).unwrap();
+			junk.extend_from_slice(&[0, 0, 0]);
+			conn.write_all(&junk).unwrap();
+			conn
+		});
+
+		assert!(net.wait_for(|| !adapter.errors.lock().unwrap().is_empty()));
+		assert_eq!(*adapter.errors.lock().unwrap(), vec![(sender_addr, true)]);
+	}
+
+	#[test]
+	fn broken_headers_banned() {
+		let mut net = TestNetwork::empty();
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(P2PConfig::default(), adapter.clone());
+
+		// a peer sending a batch of headers that doesn't chain
+		let sender_addr = SocketAddr::new(addr.ip(), 13803);
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, sender_addr);
+			let first = core::BlockHeader::default();
+			let mut second = core::BlockHeader::default();
+			second.height = 2;
+			let headers = Headers { headers: vec![first, second] };
+			conn.write_all(&raw_msg(Type::Headers, &headers)).unwrap();
+			conn
+		});
+		assert!(net.wait_for(|| server.is_banned(&sender_addr)));
+
+		// gets disconnected and its host banned, not to be let back in
+		assert!(net.wait_for(|| server.connected_peers().is_empty()));
+		assert_eq!(*adapter.errors.lock().unwrap(), vec![(sender_addr, true)]);
+		assert_eq!(*adapter.banned.lock().unwrap(), vec![(sender_addr, Severity::Ban)]);
+		let bans = server.list_bans();
+		assert_eq!(bans.len(), 1);
+		assert_eq!((bans[0].ip, bans[0].severity), (addr.ip(), Severity::Ban));
+
+		net.run_thread(move || {
+			let mut refused = net::TcpStream::connect(addr).unwrap();
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+		});
+		assert!(server.connected_peers().is_empty());
+	}
+
+	#[test]
+	fn peers_by_id() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let _conns = net.run_thread(move || {
+			(13644..13647)
+				.map(|port| raw_handshake(addr, SocketAddr::new(addr.ip(), port)))
+				.collect::<Vec<_>>()
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 3));
+
+		let peers = server.connected_peers();
+		let ids = peers.iter().map(|p| p.info.id).collect::<HashSet<_>>();
+		assert_eq!(ids.len(), 3);
+		for p in &peers {
+			assert_eq!(server.peer_by_id(p.info.id).unwrap().info.addr, p.info.addr);
+		}
+		let unused = PeerId(ids.iter().map(|id| id.0).max().unwrap() + 1000);
+		assert!(server.peer_by_id(unused).is_none());
+	}
+
+	#[test]
+	fn peer_snapshots_diffed() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let (staying, leaving, joining) = (SocketAddr::new(addr.ip(), 13678),
+		                                   SocketAddr::new(addr.ip(), 13679),
+		                                   SocketAddr::new(addr.ip(), 13680));
+		let _conns = net.run_thread(move || {
+			vec![raw_handshake(addr, staying), raw_handshake(addr, leaving)]
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+		let before = server.peer_snapshot();
+		assert_eq!(before.peers.iter().map(|p| p.addr).collect::<Vec<_>>(),
+		           vec![staying, leaving]);
+		assert!(before.peers.iter().all(|p| p.connected_at <= before.taken_at));
+
+		// one peer leaves while another one joins
+		assert!(server.disconnect_peer(leaving, true));
+		let _conn = net.run_thread(move || raw_handshake(addr, joining));
+		let joined = || {
+			let addrs = server.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+			addrs == vec![staying, joining]
+		};
+		assert!(net.wait_for(joined));
+		let after = server.peer_snapshot();
+
+		let diff = before.diff(&after);
+		assert_eq!(diff.joined.iter().map(|p| p.addr).collect::<Vec<_>>(), vec![joining]);
+		assert_eq!(diff.left, vec![before.peers[1].clone()]);
+		assert_eq!(after.diff(&after), PeerDiff { joined: vec![], left: vec![] });
+	}
+
+	#[test]
+	fn broadcast_fanout_spreads() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { broadcast_fanout: 2, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// the peers show increasing difficulties, the last two the most worked
+		let _conns = net.run_thread(move || {
+			(13684..13689)
+				.map(|port| {
+					let mut hand = test_hand(addr, SocketAddr::new(addr.ip(), port));
+					hand.total_difficulty = Difficulty::from_num((port - 13683) as u32);
+					send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+				})
+				.collect::<Vec<_>>()
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 5));
+		let peers = server.connected_peers();
+		let ids = peers.iter().map(|p| p.info.id).collect::<HashSet<_>>();
+		assert_eq!(ids.len(), 5);
+		let best = peers.iter()
+			.filter(|p| p.info.addr.port() >= 13687)
+			.map(|p| p.info.id)
+			.collect::<HashSet<_>>();
+		assert_eq!(best.len(), 2);
+
+		// each transaction only goes to two peers, all of them got one after three
+		let targeted = |server: &Server| {
+			let rotation = server.rotation.lock().unwrap_or_else(|e| e.into_inner());
+			let round = rotation.round;
+			ids.iter()
+				.cloned()
+				.filter(|id| rotation.last_used.get(id) == Some(&round))
+				.collect::<HashSet<_>>()
+		};
+		let mut covered = HashSet::new();
+		for fee in 1..4 {
+			let mut tx = core::Transaction::empty();
+			tx.fee = fee;
+			assert_eq!(server.broadcast_transaction(&tx).sent, 2);
+			covered.extend(targeted(&server));
+		}
+		assert_eq!(covered, ids);
+
+		// blocks always reach the most worked peers on top of two others
+		let mut covered = HashSet::new();
+		for height in 1..3 {
+			let mut b = core::Block::default();
+			b.header.height = height;
+			assert_eq!(server.broadcast_block(&b).sent, 4);
+			let used = targeted(&server);
+			assert!(best.is_subset(&used));
+			covered.extend(used);
+		}
+		assert_eq!(covered, ids);
+	}
+
+	#[test]
+	fn upgrade_confirmed() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let client = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let peer = net.run(client.connect_peer(addr, handle.clone())).unwrap().unwrap();
+
+		// both switched, and keep understanding each other
+		assert!(peer.info.features.contains(CHECKSUMS));
+		let info = net.run(peer.request_peer_info().unwrap()).unwrap();
+		assert_eq!(info.peer_count, 1);
+		assert!(server.connected_peers()[0].info.features.contains(CHECKSUMS));
+	}
+
+	#[test]
+	fn upgrade_fallback() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { features: ALL_FEATURES - CHECKSUMS, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let config = P2PConfig { features: ALL_FEATURES, ..P2PConfig::default() };
+		let client = TestNetwork::dialer(config, Arc::new(RecordingAdapter::new()));
+		let peer = net.run(client.connect_peer(addr, handle.clone())).unwrap().unwrap();
+
+		// the server doesn't know the upgrade, both stay on the old framing
+		assert_eq!(peer.info.features, ALL_FEATURES - CHECKSUMS);
+		let info = net.run(peer.request_peer_info().unwrap()).unwrap();
+		assert_eq!(info.peer_count, 1);
+		assert!(!server.connected_peers()[0].info.features.contains(CHECKSUMS));
+	}
+
+	#[test]
+	fn corrupted_message_not_banned() {
+		let mut net = TestNetwork::empty();
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(P2PConfig::default(), adapter.clone());
+
+		let sender_addr = SocketAddr::new(addr.ip(), 13642);
+		let _conn = net.run_thread(move || {
+			let conn = net::TcpStream::connect(addr).unwrap();
+			let mut conn = send_hand_with(conn, test_hand(addr, sender_addr), ALL_FEATURES);
+
+			// a ping along with its checksum gets answered, checksummed too
+			let mut ping = raw_msg(Type::Ping, &Empty {});
+			ping.extend_from_slice(&checksum(&[]));
+			conn.write_all(&ping).unwrap();
+			let mut pong = vec![0; HEADER_LEN as usize + CHECKSUM_LEN];
+			conn.read_exact(&mut pong).unwrap();
+			assert_eq!(pong[HEADER_LEN as usize..], checksum(&[])[..]);
+
+			// a well-formed message with a bit flipped after checksumming
+			let mut req = raw_msg(Type::GetPeerAddrs, &GetPeerAddrs { capabilities: UNKNOWN });
+			let sum = checksum(&req[HEADER_LEN as usize..]);
+			let last = req.len() - 1;
+			req[last] ^= 1;
+			req.extend_from_slice(&sum);
+			conn.write_all(&req).unwrap();
+			conn
+		});
+		let disconnected = || match server.find_peer(&sender_addr) {
+			PeerLookup::Disconnected { .. } => true,
+			_ => false,
+		};
+		assert!(net.wait_for(disconnected));
+
+		// disconnected over a transport error, not banned as corrupted
+		assert_eq!(*adapter.errors.lock().unwrap(), vec![(sender_addr, false)]);
+		assert!(Error::Checksum.is_transient());
+		assert!(server.list_bans().is_empty());
+	}
+
+	#[test]
+	fn peer_connected_direction() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let accepting = Arc::new(RecordingAdapter::new());
+		let (_server, addr) = net.serve(P2PConfig::default(), accepting.clone());
+
+		let connecting = Arc::new(RecordingAdapter::new());
+		let client = TestNetwork::dialer(P2PConfig::default(), connecting.clone());
+		handle.spawn(client.connect_peer(addr, handle.clone()).map(|_| ()).map_err(|_| ()));
+
+		let both = || {
+			!accepting.connected.lock().unwrap().is_empty() &&
+			!connecting.connected.lock().unwrap().is_empty()
+		};
+		assert!(net.wait_for(both));
+		assert_eq!(*accepting.connected.lock().unwrap(), vec![Direction::Inbound]);
+		assert_eq!(*connecting.connected.lock().unwrap(), vec![Direction::Outbound]);
+	}
+
+	#[test]
+	fn slow_handshake_flagged() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { slow_handshake_ms: 200, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let fast_addr: SocketAddr = "127.0.0.1:13513".parse().unwrap();
+		let slow_addr: SocketAddr = "127.0.0.1:13514".parse().unwrap();
+		let _conns = net.run_thread(move || {
+			let fast = raw_handshake(addr, fast_addr);
+			let slow =
+				raw_handshake_with(addr, slow_addr, Duration::from_millis(600), ALL_SERVICES);
+			(fast, slow)
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let mut flags = server.connected_peers()
+			.iter()
+			.map(|p| (p.info.addr, p.info.slow_handshake))
+			.collect::<Vec<_>>();
+		flags.sort();
+		assert_eq!(flags, vec![(fast_addr, false), (slow_addr, true)]);
+	}
+
+	#[test]
+	fn requests_routed_by_services() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// a pruned node serving headers and recent blocks and an archival one
+		let pruned_addr: SocketAddr = "127.0.0.1:13521".parse().unwrap();
+		let archival_addr: SocketAddr = "127.0.0.1:13522".parse().unwrap();
+		let _conns = net.run_thread(move || {
+			let no_delay = Duration::new(0, 0);
+			let pruned =
+				raw_handshake_with(addr, pruned_addr, no_delay, SERVES_HEADERS | SERVES_BLOCKS);
+			let archival = raw_handshake_with(addr, archival_addr, no_delay, ALL_SERVICES);
+			(pruned, archival)
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let offering = |needed| {
+			let mut addrs =
+				server.peers_offering(needed).iter().map(|p| p.info.addr).collect::<Vec<_>>();
+			addrs.sort();
+			addrs
+		};
+		assert_eq!(offering(SERVES_HEADERS), vec![pruned_addr, archival_addr]);
+		assert_eq!(offering(Services::for_block(100, 200)),
+		           vec![pruned_addr, archival_addr]);
+		assert_eq!(offering(Services::for_block(100, 100 + PRUNED_BLOCKS_HORIZON + 1)),
+		           vec![archival_addr]);
+		assert_eq!(offering(SERVES_MEMPOOL), vec![archival_addr]);
+
+		let ancient = Services::for_block(0, 2 * PRUNED_BLOCKS_HORIZON);
+		assert_eq!(server.random_peer_offering(ancient).unwrap().info.addr,
+		           archival_addr);
+		assert!(server.most_work_peer_offering(SERVES_BLOCKS).is_some());
+	}
+
+	#[test]
+	fn peers_by_version() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// an older peer, one at our version and a newer one, the version
+		// negotiated with the newer one being ours
+		let older = SocketAddr::new(addr.ip(), 13635);
+		let current = SocketAddr::new(addr.ip(), 13637);
+		let newer = SocketAddr::new(addr.ip(), 13638);
+		let _conns = net.run_thread(move || {
+			let with_version = |sender_addr, version| {
+				let mut hand = test_hand(addr, sender_addr);
+				hand.version = version;
+				send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+			};
+			(with_version(older, PROTOCOL_VERSION - 1),
+			 raw_handshake(addr, current),
+			 with_version(newer, PROTOCOL_VERSION + 1))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 3));
+
+		let with_version = |v| {
+			let mut addrs =
+				server.peers_with_min_version(v).iter().map(|p| p.info.addr).collect::<Vec<_>>();
+			addrs.sort();
+			addrs
+		};
+		assert_eq!(with_version(PROTOCOL_VERSION - 1), vec![older, current, newer]);
+		assert_eq!(with_version(PROTOCOL_VERSION), vec![current, newer]);
+		assert!(with_version(PROTOCOL_VERSION + 1).is_empty());
+		let most_work = server.most_work_peer_with_min_version(PROTOCOL_VERSION);
+		assert!(most_work.unwrap().info.addr != older);
+		assert!(server.most_work_peer_with_min_version(PROTOCOL_VERSION + 1).is_none());
+	}
+
+	// Messages the server received from its peer at the provided address, none
+	// when not connected to it.
+	fn received_msgs(server: &Server, addr: SocketAddr) -> u64 {
+		server.connected_peers()
+			.iter()
+			.find(|p| p.info.addr == addr)
+			.map(|p| p.transmitted_msgs().1)
+			.unwrap_or(0)
+	}
+
+	// Handshakes that failed on the server so far, whatever the reason.
+	fn failed_handshakes(server: &Server) -> u64 {
+		server.handshake_failures().values().sum()
+	}
+
+	// Opens a connection to the provided address from the provided local IP.
+	#[cfg(target_os = "linux")]
+	fn connect_from(ip: &str, addr: SocketAddr) -> net::TcpStream {
+		let builder = net2::TcpBuilder::new_v4().unwrap();
+		builder.bind(&format!("{}:0", ip)).unwrap();
+		builder.connect(addr).unwrap()
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn adapter_refuses_address() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let blocked: IpAddr = "127.0.0.2".parse().unwrap();
+		let adapter = RecordingAdapter { blocked: Some(blocked), ..RecordingAdapter::new() };
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(adapter));
+
+		// inbound from the blocked host gets dropped without a handshake
+		let dropped = net.run_thread(move || {
+			let mut conn = connect_from("127.0.0.2", addr);
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let sender_addr = SocketAddr::new(blocked, 13659);
+			let _ = conn.write_all(&raw_msg(Type::Hand, &test_hand(addr, sender_addr)));
+			match conn.read(&mut [0; 1]) {
+				Ok(0) => true,
+				Err(ref e) => e.kind() == io::ErrorKind::ConnectionReset,
+				Ok(_) => false,
+			}
+		});
+		assert!(dropped);
+		assert!(server.connected_peers().is_empty());
+
+		// outbound to it isn't even attempted
+		let dial = server.connect_peer(SocketAddr::new(blocked, 13659), handle.clone());
+		match net.run(dial) {
+			Err(Error::NotAllowed) => {}
+			_ => panic!("expected the dial to be refused"),
+		}
+		assert_eq!(server.dials_in_progress(), 0);
+	}
+
+	// Binding any address in 127.0.0.0/8 only works out of the box on Linux.
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn reserved_slots_for_preferred() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig {
+			max_inbound_peers: 2,
+			reserved_slots: 1,
+			preferred_peers: vec!["127.0.0.2".parse().unwrap()],
+			..P2PConfig::default()
+		};
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let regular: SocketAddr = "127.0.0.1:13541".parse().unwrap();
+		let preferred1: SocketAddr = "127.0.0.2:13542".parse().unwrap();
+		let preferred2: SocketAddr = "127.0.0.2:13543".parse().unwrap();
+		fn connected(server: &Server) -> Vec<SocketAddr> {
+			let mut addrs =
+				server.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+			addrs.sort();
+			addrs
+		}
+		let srv = server.clone();
+		let _conns = net.run_thread(move || {
+			let first = raw_handshake(addr, regular);
+			assert!(poll_until(|| connected(&srv) == vec![regular]));
+
+			// only the reserved slot is left, a second regular peer gets dropped
+			let mut refused = net::TcpStream::connect(addr).unwrap();
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+
+			// preferred peers get the reserved slot, then evict the regular peer
+			let second = send_hand(connect_from("127.0.0.2", addr), test_hand(addr, preferred1));
+			assert!(poll_until(|| connected(&srv).contains(&preferred1)));
+			let third = send_hand(connect_from("127.0.0.2", addr), test_hand(addr, preferred2));
+			(first, second, third)
+		});
+		assert!(net.wait_for(|| connected(&server) == vec![preferred1, preferred2]));
+	}
+
+	// Logger keeping all log lines around with their level so they can be
+	// checked.
+	struct CaptureLogger {
+		lines: Arc<Mutex<Vec<(log::LogLevel, String)>>>,
+	}
+	impl log::Log for CaptureLogger {
+		fn enabled(&self, metadata: &log::LogMetadata) -> bool {
+			true
+		}
+		fn log(&self, record: &log::LogRecord) {
+			let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+			lines.push((record.level(), format!("{}", record.args())));
+		}
+	}
+
+	// Lines logged by all tests so far. The logger can only be set once so
+	// it's shared, tests checking the logs are started with it in place. A
+	// test failing while looking at the lines doesn't keep the others from
+	// logging.
+	fn captured_logs() -> Arc<Mutex<Vec<(log::LogLevel, String)>>> {
+		lazy_static! {
+			static ref LINES: Arc<Mutex<Vec<(log::LogLevel, String)>>> =
+				Arc::new(Mutex::new(vec![]));
+		}
+		static INIT: Once = ONCE_INIT;
+		INIT.call_once(|| {
+			log::set_logger(|max_level| {
+					max_level.set(log::LogLevelFilter::Debug);
+					Box::new(CaptureLogger { lines: LINES.clone() })
+				})
+				.unwrap();
+		});
+		LINES.clone()
+	}
+
+	#[test]
+	fn peer_log_prefix() {
+		let lines = captured_logs();
+
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let conn = net.run_thread(move || raw_handshake(addr, "127.0.0.1:13545".parse().unwrap()));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+		let log_id = server.connected_peers()[0].info.log_id.clone();
+
+		// disconnect so the end of the lifecycle gets logged as well
+		drop(conn);
+		let prefix = format!("{} ", log_id);
+		let logged = || {
+			let lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+			lines.iter().any(|l| l.1.starts_with(&prefix) && l.1.contains("disconnected"))
+		};
+		assert!(net.wait_for(logged));
+
+		let lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+		let lines = lines.iter().map(|l| l.1.clone()).collect::<Vec<_>>();
+		let peer_lines = lines.iter().filter(|l| l.starts_with(&prefix)).collect::<Vec<_>>();
+		assert!(peer_lines.iter().any(|l| l.contains("Accepting")));
+		assert!(peer_lines.iter().any(|l| l.contains("Success handshake")));
+		assert!(peer_lines.iter().any(|l| l.contains("disconnected")));
+
+		// the id never shows up anywhere else than as the prefix
+		let id = prefix.trim().trim_matches(|c| c == '[' || c == ']').to_string();
+		assert!(lines.iter().filter(|l| l.contains(&id)).all(|l| l.starts_with(&prefix)));
+	}
+
+	#[test]
+	fn peer_connected_logged() {
+		let lines = captured_logs();
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+
+		let adapter = RecordingAdapter {
+			difficulty: Difficulty::from_num(77),
+			..RecordingAdapter::new()
+		};
+		let (listening, addr) = net.serve_with(FULL_NODE, P2PConfig::default(), Arc::new(adapter));
+		let (client, client_addr) = net.serve(P2PConfig::default(),
+		                                      Arc::new(RecordingAdapter::new()));
+		handle.spawn(client.connect_peer(addr, handle.clone()).map(|_| ()).map_err(|_| ()));
+		let connected = || {
+			client.connected_peers().len() == 1 && listening.connected_peers().len() == 1
+		};
+		assert!(net.wait_for(connected));
+
+		let outbound = client.connected_peers()[0].info.log_id.clone();
+		let inbound = listening.connected_peers()[0].info.log_id.clone();
+		let lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+		let connected = |log_id: &PeerLogId| {
+			let prefix = format!("{} Peer connected: ", log_id);
+			lines.iter()
+				.find(|&&(level, ref l)| level == log::LogLevel::Info && l.starts_with(&prefix))
+				.map(|l| l.1.clone())
+				.unwrap()
+		};
+
+		let line = connected(&outbound);
+		let fields = vec![format!("addr={}", addr),
+		                  "direction=Outbound".to_string(),
+		                  "version=1".to_string(),
+		                  format!("capabilities={:b}", FULL_NODE.bits()),
+		                  format!("services={:b}", ALL_SERVICES.bits()),
+		                  format!("user_agent={}", USER_AGENT),
+		                  "total_difficulty=77".to_string()];
+		for field in fields {
+			assert!(line.contains(&field), "{} missing from: {}", field, line);
+		}
+		let line = connected(&inbound);
+		let fields = vec![format!("addr={}", client_addr),
+		                  "direction=Inbound".to_string(),
+		                  "total_difficulty=1".to_string()];
+		for field in fields {
+			assert!(line.contains(&field), "{} missing from: {}", field, line);
+		}
+	}
+
+	#[test]
+	fn handshake_reset_quiet() {
+		use net2::TcpStreamExt;
+
+		let lines = captured_logs();
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let srv = server.clone();
+		net.run_thread(move || {
+			let conn = net::TcpStream::connect(addr).unwrap();
+			assert!(poll_until(|| srv.handshakes_in_progress() == 1));
+			// not lingering on close resets the connection instead of shutting
+			// it down cleanly
+			conn.set_linger(Some(Duration::from_secs(0))).unwrap();
+		});
+		let reset = || {
+			server.handshake_failures().get(&HandshakeFailure::ConnectionReset) == Some(&1)
+		};
+		assert!(net.wait_for(reset));
+		let reset = Error::Connection(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+		assert!(reset.is_transient());
+		assert!(!Error::WrongNetwork.is_transient());
+
+		// nothing above debug about it
+		let lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+		let loud = |&&(level, ref l): &&(log::LogLevel, String)| {
+			level <= log::LogLevel::Info && l.to_lowercase().contains("reset")
+		};
+		assert_eq!(lines.iter().filter(loud).count(), 0);
+	}
+
+	#[test]
+	fn invalid_msgs_dumped() {
+		let lines = captured_logs();
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { dump_invalid_msgs: true, ..P2PConfig::default() };
+		let dumping_adapter = Arc::new(RecordingAdapter::new());
+		let (_server, dumping) = net.serve(config, dumping_adapter.clone());
+		let quiet_adapter = Arc::new(RecordingAdapter::new());
+		let (_quiet_server, quiet) = net.serve(P2PConfig::default(), quiet_adapter.clone());
+
+		// a block request too short to hold a hash, and a message of unknown type
+		let bad_body = |body: &[u8]| {
+			let mut frame = ser::ser_vec(&MsgHeader::new(magic(), Type::GetBlock, 3)).unwrap();
+			frame.extend_from_slice(body);
+			frame
+		};
+		let mut unknown = raw_msg(Type::Ping, &Empty {});
+		unknown[2] = 0xff;
+		let sends = vec![(dumping, 13691, bad_body(&[0xde, 0xad, 0xbe])),
+		                 (dumping, 13692, unknown),
+		                 (quiet, 13693, bad_body(&[0xca, 0xfe, 0xba]))];
+		let _conns = net.run_thread(move || {
+			sends.into_iter()
+				.map(|(addr, port, data)| {
+					let mut conn = raw_handshake(addr, SocketAddr::new(addr.ip(), port));
+					conn.write_all(&data).unwrap();
+					conn
+				})
+				.collect::<Vec<_>>()
+		});
+		// all three get dropped for their messages
+		let dropped = || {
+			dumping_adapter.errors.lock().unwrap().len() == 2 &&
+			quiet_adapter.errors.lock().unwrap().len() == 1
+		};
+		assert!(net.wait_for(dropped));
+
+		let lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+		let dumped = format!("Undecodable message of type {}: {}",
+		                     Type::GetBlock as u8,
+		                     hex_dump(&bad_body(&[0xde, 0xad, 0xbe])));
+		assert!(lines.iter().any(|l| l.1 == dumped));
+		let header = "Undecodable message header of type 255: 1ec5ff";
+		assert!(lines.iter().any(|l| l.1.starts_with(header)));
+		assert!(!lines.iter().any(|l| l.1.contains("cafeba")));
+	}
+
+	#[test]
+	fn outbound_only() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (listening, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let config = P2PConfig { inbound_enabled: false, ..P2PConfig::default() };
+		let (client, client_addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+		handle.spawn(client.connect_peer(addr, handle.clone()).map(|_| ()).map_err(|_| ()));
+		let connected = || {
+			client.connected_peers().len() == 1 && listening.connected_peers().len() == 1
+		};
+		assert!(net.wait_for(connected));
+
+		// nothing listens on the outbound-only port
+		assert!(net::TcpStream::connect(client_addr).is_err());
+
+		// but it got connected, known by its connection rather than an address
+		let peers = listening.connected_peers();
+		assert!(!peers[0].info.reachable);
+		assert!(peers[0].info.addr.port() != 0);
+	}
+
+	#[test]
+	fn connect_any_moves_on() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (_listening, addr) = net.serve(P2PConfig::default(),
+		                                   Arc::new(RecordingAdapter::new()));
+
+		// nothing listens on the first addresses
+		let unreachable = SocketAddr::new(addr.ip(), free_port());
+		let addrs = vec![unreachable, SocketAddr::new(addr.ip(), free_port()), addr];
+		let client = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let peer = net.run(client.connect_any(addrs, handle.clone())).unwrap();
+		assert_eq!(peer.info.addr, addr);
+
+		match net.run(client.connect_any(vec![unreachable], handle.clone())) {
+			Err(Error::Connection(_)) => {}
+			res => panic!("unexpected connection result: {:?}", res.map(|p| p.info.addr)),
+		}
+		assert!(net.run(client.connect_any(vec![], handle.clone())).is_err());
+	}
+
+	#[test]
+	fn dial_preference_honored() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+
+		let port = free_port();
+		let v6 = SocketAddr::new("::1".parse().unwrap(), port);
+		let config = P2PConfig {
+			port: port,
+			extra_listeners: vec![v6],
+			..P2PConfig::default()
+		};
+		let v4 = SocketAddr::new(config.host, port);
+		let listening = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		handle.spawn(listening.start(handle.clone()).map_err(|_| ()));
+
+		// nothing listens on the last IPv6 address, racing falls back to IPv4
+		let unreachable = SocketAddr::new("::1".parse().unwrap(), free_port());
+		let cases = vec![(DialPreference::PreferV4, v6, v4),
+		                 (DialPreference::PreferV6, v6, v6),
+		                 (DialPreference::HappyEyeballs, v6, v6),
+		                 (DialPreference::HappyEyeballs, unreachable, v4)];
+		for (pref, other, expected) in cases {
+			let config = P2PConfig { dial_preference: pref, ..P2PConfig::default() };
+			let client = TestNetwork::dialer(config, Arc::new(RecordingAdapter::new()));
+			let peer = net.run(client.connect_any(vec![v4, other], handle.clone())).unwrap();
+			assert_eq!(peer.info.addr, expected, "with {:?}", pref);
+		}
+	}
+
+	#[test]
+	fn features_intersected() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (all, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let config = P2PConfig { features: TX_INV, ..P2PConfig::default() };
+		let (some, _) = net.serve(config, Arc::new(RecordingAdapter::new()));
+		handle.spawn(some.connect_peer(addr, handle.clone()).map(|_| ()).map_err(|_| ()));
+		let connected = || all.connected_peers().len() == 1 && some.connected_peers().len() == 1;
+		assert!(net.wait_for(connected));
+
+		// both sides only use what they both support
+		assert_eq!(all.connected_peers()[0].info.features, TX_INV);
+		assert_eq!(some.connected_peers()[0].info.features, TX_INV);
+	}
+
+	#[test]
+	fn services_and_difficulty_advertised() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+
+		// the chain only has headers, although blocks are allowed by the config
+		let config = P2PConfig { services: SERVES_HEADERS | SERVES_BLOCKS, ..P2PConfig::default() };
+		let adapter = RecordingAdapter {
+			difficulty: Difficulty::from_num(42),
+			services: SERVES_HEADERS | SERVES_MEMPOOL,
+			..RecordingAdapter::new()
+		};
+		let (headers, addr) = net.serve(config, Arc::new(adapter));
+
+		let (full, _) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		handle.spawn(full.connect_peer(addr, handle.clone()).map(|_| ()).map_err(|_| ()));
+		let connected = || {
+			full.connected_peers().len() == 1 && headers.connected_peers().len() == 1
+		};
+		assert!(net.wait_for(connected));
+
+		// what the chain offers, within what's configured
+		let peers = full.connected_peers();
+		let info = &peers[0].info;
+		assert_eq!(info.services, SERVES_HEADERS);
+		assert_eq!(info.total_difficulty, Difficulty::from_num(42));
+		assert_eq!(headers.connected_peers()[0].info.services, ALL_SERVICES);
+	}
+
+	// Binding any address in 127.0.0.0/8 only works out of the box on Linux.
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn useless_peers_dropped() {
+		let mut net = TestNetwork::empty();
+
+		// a node serving nothing, like a light client
+		let config = P2PConfig {
+			services: NO_SERVICES,
+			preferred_peers: vec!["127.0.0.2".parse().unwrap()],
+			..P2PConfig::default()
+		};
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let leech: SocketAddr = "127.0.0.1:13567".parse().unwrap();
+		let preferred: SocketAddr = "127.0.0.2:13568".parse().unwrap();
+		let _conn = net.run_thread(move || {
+			let hand = Hand { services: NO_SERVICES, ..test_hand(addr, leech) };
+			let mut useless = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+			useless.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+			assert_eq!(useless.read(&mut [0; 1]).unwrap(), 0);
+
+			// claiming the address of a preferred peer doesn't make one
+			let claimed = "127.0.0.2:13565".parse().unwrap();
+			let hand = Hand { services: NO_SERVICES, ..test_hand(addr, claimed) };
+			let mut spoofing = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+			spoofing.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+			assert_eq!(spoofing.read(&mut [0; 1]).unwrap(), 0);
+
+			// still kept when preferred
+			let hand = Hand { services: NO_SERVICES, ..test_hand(addr, preferred) };
+			send_hand(connect_from("127.0.0.2", addr), hand)
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		let connected = server.connected_peers();
+		assert_eq!(connected.len(), 1);
+		assert_eq!(connected[0].info.addr, preferred);
+		assert_eq!(server.handshake_failures().get(&HandshakeFailure::NoCommonServices),
+		           Some(&2));
+	}
+
+	#[test]
+	fn inbound_limit_lowered_live() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { max_inbound_peers: 4, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let first: SocketAddr = "127.0.0.1:13570".parse().unwrap();
+		let _first = net.run_thread(move || raw_handshake(addr, first));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		let limits = P2PConfigRuntime { max_inbound_peers: 1, ..server.runtime_config() };
+		server.update_config(limits.clone());
+		assert_eq!(server.runtime_config(), limits);
+
+		// the peer already connected stays, there's no room for another one
+		net.run_thread(move || {
+			let mut refused = net::TcpStream::connect(addr).unwrap();
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+		});
+
+		let connected = server.connected_peers();
+		assert_eq!(connected.len(), 1);
+		assert_eq!(connected[0].info.addr, first);
+	}
+
+	#[test]
+	fn peer_limits_enforced() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig {
+			max_inbound_peers: 2,
+			max_outbound_peers: 1,
+			..P2PConfig::default()
+		};
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+		let (_first, first) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let (_second, second) = net.serve(P2PConfig::default(),
+		                                  Arc::new(RecordingAdapter::new()));
+
+		// the third inbound peer gets dropped without a handshake
+		let srv = server.clone();
+		let _conns = net.run_thread(move || {
+			let first = raw_handshake(addr, "127.0.0.1:13707".parse().unwrap());
+			let second = raw_handshake(addr, "127.0.0.1:13708".parse().unwrap());
+			assert!(poll_until(|| srv.connected_peers().len() == 2));
+			let mut refused = net::TcpStream::connect(addr).unwrap();
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+			(first, second)
+		});
+		assert_eq!(server.connected_peers().len(), 2);
+
+		// and the second outbound one isn't even dialed
+		assert!(net.run(server.connect_peer(first, handle.clone())).unwrap().is_some());
+		assert!(net.run(server.connect_peer(second, handle.clone())).unwrap().is_none());
+		assert_eq!(server.connected_peers().len(), 3);
+		assert!(server.connected_peers().iter().all(|p| p.info.addr != second));
+	}
+
+	#[test]
+	fn pending_handshakes_take_slots() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { max_inbound_peers: 2, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// two connections still to send their hand hold both slots, the third
+		// one gets dropped right away
+		let srv = server.clone();
+		let _conns = net.run_thread(move || {
+			let first = net::TcpStream::connect(addr).unwrap();
+			let second = net::TcpStream::connect(addr).unwrap();
+			assert!(poll_until(|| pending_count(&srv.pending, Direction::Inbound) == 2));
+			let mut refused = net::TcpStream::connect(addr).unwrap();
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+			(send_hand(first, test_hand(addr, "127.0.0.1:13792".parse().unwrap())),
+			 send_hand(second, test_hand(addr, "127.0.0.1:13793".parse().unwrap())))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+		assert_eq!(pending_count(&server.pending, Direction::Inbound), 0);
+	}
+
+	// Binding any address in 127.0.0.0/8 only works out of the box on Linux.
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn worse_peers_evicted() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig {
+			max_inbound_peers: 1,
+			max_outbound_peers: 1,
+			..P2PConfig::default()
+		};
+		let adapter = RecordingAdapter { invalid_height: Some(7), ..RecordingAdapter::new() };
+		let (server, addr) = net.serve(config, Arc::new(adapter));
+
+		// a well behaved peer keeps its slot
+		let first: SocketAddr = "127.0.0.1:13739".parse().unwrap();
+		let second: SocketAddr = "127.0.0.2:13740".parse().unwrap();
+		let srv = server.clone();
+		let (_conn, silent) = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, first);
+			assert!(poll_until(|| srv.connected_peers().len() == 1));
+			let mut refused = connect_from("127.0.0.2", addr);
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+
+			// until it misbehaves, any host we know nothing bad of then stands better
+			let mut b = core::Block::default();
+			b.header.height = 7;
+			conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			let misbehaved = || srv.connected_peers().iter().any(|p| p.violation_count() > 0);
+			assert!(poll_until(misbehaved));
+			(conn, connect_from("127.0.0.2", addr))
+		});
+
+		// but it only gives way once the better one completes its handshake
+		let connected = |server: &Server| {
+			server.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>()
+		};
+		assert_eq!(connected(&server), vec![first]);
+		let _evicting = net.run_thread(move || send_hand(silent, test_hand(addr, second)));
+		assert!(net.wait_for(|| connected(&server) == vec![second]));
+
+		// the outbound slot taken, an address we know nothing about isn't dialed
+		let (_other, other_addr) = net.serve(P2PConfig::default(),
+		                                     Arc::new(RecordingAdapter::new()));
+		let unknown = SocketAddr::new(addr.ip(), free_port());
+		assert!(server.outbound_slot_for(&other_addr));
+		assert!(net.run(server.connect_peer(other_addr, handle.clone())).unwrap().is_some());
+		assert!(!server.outbound_slot_for(&unknown));
+		assert!(net.run(server.connect_peer(unknown, handle.clone())).unwrap().is_none());
+	}
+
+	#[test]
+	fn traffic_outlives_peers() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// two peers pinging once each then leaving
+		net.run_thread(move || for port in 13574..13576 {
+			let mut conn = raw_handshake(addr, SocketAddr::new(addr.ip(), port));
+			conn.write_all(&raw_msg(Type::Ping, &Empty {})).unwrap();
+			let mut pong = vec![0; HEADER_LEN as usize];
+			conn.read_exact(&mut pong).unwrap();
+		});
+
+		let ping_pongs = 2 * HEADER_LEN as u64;
+		assert!(net.wait_for(|| server.traffic_totals() == (ping_pongs, ping_pongs)));
+		assert!(net.wait_for(|| server.connected_peers().is_empty()));
+	}
+
+	#[test]
+	fn best_tip_announced() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		assert_eq!(server.best_tip(), None);
+
+		// two peers announcing tips of different difficulties
+		let tips = vec![(13700, 20), (13701, 10)]
+			.into_iter()
+			.map(|(port, diff)| {
+				let mut bh = core::BlockHeader::default();
+				bh.height = diff;
+				bh.total_difficulty = Difficulty::from_num(diff);
+				(port, bh)
+			})
+			.collect::<Vec<_>>();
+		let best = tips[0].1.hash();
+		let _conns = net.run_thread(move || {
+			tips.into_iter()
+				.map(|(port, bh)| {
+					let mut conn = raw_handshake(addr, SocketAddr::new(addr.ip(), port));
+					conn.write_all(&raw_msg(Type::Headers, &Headers { headers: vec![bh] }))
+						.unwrap();
+					conn
+				})
+				.collect::<Vec<_>>()
+		});
+
+		assert!(net.wait_for(|| server.best_tip() == Some((best, Difficulty::from_num(20)))));
+	}
+
+	#[test]
+	fn dials_paced() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { min_dial_interval_ms: 200, ..P2PConfig::default() };
+		let server = TestNetwork::dialer(config, Arc::new(RecordingAdapter::new()));
+
+		// a raw listener only noting when each dial comes in
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let target = listener.local_addr().unwrap();
+		for _ in 0..4 {
+			let dial = server.connect_peer(target, handle.clone());
+			handle.spawn(dial.map(|_| ()).map_err(|_| ()));
+		}
+		let accepted = net.run_thread(move || {
+			(0..4)
+				.map(|_| {
+					let (conn, _) = listener.accept().unwrap();
+					(Instant::now(), conn)
+				})
+				.collect::<Vec<_>>()
+		});
+
+		for pair in accepted.windows(2) {
+			// a little slack for the accepting thread being scheduled late
+			assert!(pair[1].0.duration_since(pair[0].0) >= Duration::from_millis(190));
+		}
+	}
+
+	#[test]
+	fn bootstrap_addrs_added() {
+		let config = P2PConfig { port: 13696, ..P2PConfig::default() };
+		let own = SocketAddr::new(config.host, config.port);
+		let seed: SocketAddr = "10.0.0.1:13414".parse().unwrap();
+		let supplied: SocketAddr = "10.0.0.2:13414".parse().unwrap();
+		let adapter = RecordingAdapter {
+			bootstrap: vec![seed, supplied, own],
+			..RecordingAdapter::new()
+		};
+		let server = Server::new(UNKNOWN, config, Arc::new(adapter));
+		assert_eq!(server.bootstrap_candidates(vec![seed]), vec![seed, supplied]);
+		assert_eq!(server.bootstrap_candidates(vec![]), vec![seed, supplied]);
+	}
+
+	#[test]
+	fn stats_reset() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// pings twice, then once more after the reset
+		let (reset_tx, reset_rx) = mpsc::channel();
+		fn ping_pong(conn: &mut net::TcpStream) {
+			conn.write_all(&raw_msg(Type::Ping, &Empty {})).unwrap();
+			let mut pong = vec![0; HEADER_LEN as usize];
+			conn.read_exact(&mut pong).unwrap();
+		}
+		let client = thread::spawn(move || {
+			let mut conn = raw_handshake(addr, SocketAddr::new(addr.ip(), 13695));
+			ping_pong(&mut conn);
+			ping_pong(&mut conn);
+			reset_rx.recv().unwrap();
+			ping_pong(&mut conn);
+			conn
+		});
+
+		let ping_pong = HEADER_LEN as u64;
+		assert!(net.wait_for(|| server.traffic_totals() == (2 * ping_pong, 2 * ping_pong)));
+		let peer = server.connected_peers()[0].clone();
+		assert_eq!(peer.transmitted_bytes(), (2 * ping_pong, 2 * ping_pong));
+		server.reset_stats();
+		assert_eq!(server.traffic_totals(), (0, 0));
+		assert_eq!(peer.transmitted_bytes(), (0, 0));
+
+		reset_tx.send(()).unwrap();
+		assert!(net.wait_for(|| server.traffic_totals() == (ping_pong, ping_pong)));
+		let _conn = client.join().unwrap();
+		assert_eq!(server.connected_peers().len(), 1);
+		assert_eq!(peer.transmitted_bytes(), (ping_pong, ping_pong));
+		// the same counters as in the info of the peer
+		assert_eq!(peer.info.traffic.totals(), (ping_pong, ping_pong));
+		assert_eq!(peer.info.traffic.msgs(), (1, 1));
+
+		peer.reset_stats();
+		assert_eq!(peer.transmitted_bytes(), (0, 0));
+		assert_eq!(server.traffic_totals(), (ping_pong, ping_pong));
+	}
+
+	#[test]
+	fn peer_stats_counted() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let peer_addr = SocketAddr::new(addr.ip(), 13718);
+		let (ping_tx, ping_rx) = mpsc::channel();
+		let client = thread::spawn(move || {
+			let mut conn = raw_handshake(addr, peer_addr);
+			while let Ok(()) = ping_rx.recv() {
+				conn.write_all(&raw_msg(Type::Ping, &Empty {})).unwrap();
+				let mut pong = vec![0; HEADER_LEN as usize];
+				conn.read_exact(&mut pong).unwrap();
+			}
+			conn
+		});
+		assert!(net.wait_for(|| server.peer_stats().len() == 1));
+		let before = server.peer_stats();
+		assert_eq!(before.len(), 1);
+		assert_eq!(before[0].addr, peer_addr);
+		assert_eq!(before[0].direction, Direction::Inbound);
+		assert_eq!(before[0].total_difficulty, Difficulty::one());
+		assert_eq!(before[0].uptime_secs, 0);
+
+		// each ping and its pong add up
+		for _ in 0..3 {
+			ping_tx.send(()).unwrap();
+		}
+		let ping_pong = HEADER_LEN as u64;
+		let pinged = before[0].sent_bytes + 3 * ping_pong;
+		assert!(net.wait_for(|| server.peer_stats()[0].sent_bytes == pinged));
+		let after = server.peer_stats();
+		assert_eq!(after[0].id, before[0].id);
+		assert_eq!(after[0].sent_bytes, before[0].sent_bytes + 3 * ping_pong);
+		assert_eq!(after[0].received_bytes, before[0].received_bytes + 3 * ping_pong);
+		assert_eq!(after[0].sent_msgs, before[0].sent_msgs + 3);
+		assert_eq!(after[0].received_msgs, before[0].received_msgs + 3);
+		assert_eq!(server.peer_info(&peer_addr), Some(after[0].clone()));
+		assert_eq!(server.peer_info(&SocketAddr::new(addr.ip(), 13719)), None);
+
+		drop(ping_tx);
+		let _conn = client.join().unwrap();
+	}
+
+	#[test]
+	fn inbound_limits_enforced() {
+		let mut net = TestNetwork::empty();
+		let limits = InboundLimits {
+			max_rate: 0,
+			max_msgs: 5,
+			burst_secs: 1,
+			excess: ExcessInbound::Throttle,
+		};
+		let config = P2PConfig { inbound_limits: limits, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a peer flooding us with pings has to wait past the burst
+		let throttled_addr = SocketAddr::new(addr.ip(), 13745);
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, throttled_addr);
+			for _ in 0..20 {
+				conn.write_all(&raw_msg(Type::Ping, &Empty {})).unwrap();
+			}
+			conn
+		});
+		fn received(server: &Server) -> Option<u64> {
+			server.peer_stats().first().map(|s| s.received_msgs)
+		}
+		assert!(net.wait_for(|| received(&server).map_or(false, |n| n >= 5)));
+		assert!(received(&server).unwrap() < 20);
+
+		// but stays connected and eventually gets all of them through
+		assert!(net.wait_for(|| received(&server) == Some(20)));
+		let stats = server.peer_stats();
+		assert_eq!(stats.len(), 1);
+		assert!(net.wait_for(|| server.peer_stats()[0].sent_msgs == 20));
+
+		let config = P2PConfig {
+			inbound_limits: InboundLimits { excess: ExcessInbound::Disconnect, ..limits },
+			..P2PConfig::default()
+		};
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// with the stricter policy, the same flood gets the peer disconnected
+		let flooding_addr = SocketAddr::new(addr.ip(), 13747);
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, flooding_addr);
+			for _ in 0..20 {
+				conn.write_all(&raw_msg(Type::Ping, &Empty {})).unwrap();
+			}
+			conn
+		});
+		let disconnected = || match server.find_peer(&flooding_addr) {
+			PeerLookup::Disconnected { .. } => true,
+			_ => false,
+		};
+		assert!(net.wait_for(disconnected));
+		assert!(server.connected_peers().is_empty());
+	}
+
+	fn tls_config(identity: &str) -> TlsConfig {
+		TlsConfig {
+			identity_path: format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), identity),
+			identity_password: "grin".to_string(),
+			trusted_cert_path: Some(format!("{}/tests/tls/peer.der", env!("CARGO_MANIFEST_DIR"))),
+			domain: "localhost".to_string(),
+			required: true,
+		}
+	}
+
+	#[test]
+	fn tls_connections() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let mut servers = vec![];
+		let mut addrs = vec![];
+		for identity in &["peer.p12", "peer.p12", "rogue.p12"] {
+			let config = P2PConfig { tls: Some(tls_config(identity)), ..P2PConfig::default() };
+			let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+			servers.push(server);
+			addrs.push(addr);
+		}
+
+		// both ends share the trusted certificate and tell they can encrypt,
+		// the connection gets secured
+		let peer = net.run(servers[1].connect_peer(addrs[0], handle.clone())).unwrap();
+		let peer = peer.unwrap();
+		assert_eq!(peer.info.addr, addrs[0]);
+		assert!(peer.info.capabilities.contains(ENCRYPTED));
+		assert!(net.wait_for(|| servers[0].connected_peers().len() == 1));
+
+		// a node presenting a certificate we don't trust is refused
+		match net.run(servers[0].connect_peer(addrs[2], handle.clone())) {
+			Err(Error::Tls(_)) => {}
+			_ => panic!("untrusted peer accepted"),
+		}
+		assert_eq!(servers[0].connected_peers().len(), 1);
+
+		// and so is one connecting to us with it, its certificate checked too
+		let rogue_failures = failed_handshakes(&servers[0]);
+		let _ = net.run(servers[2].connect_peer(addrs[0], handle.clone()));
+		assert!(net.wait_for(|| failed_handshakes(&servers[0]) > rogue_failures));
+		assert_eq!(servers[0].connected_peers().len(), 1);
+		assert!(servers[2].connected_peers().is_empty());
+
+		// a node that can't encrypt is refused when we require it, and stays in
+		// plaintext when we don't
+		let (plain, _) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let optional = P2PConfig {
+			tls: Some(TlsConfig { required: false, ..tls_config("peer.p12") }),
+			..P2PConfig::default()
+		};
+		let (optional, optional_addr) = net.serve(optional, Arc::new(RecordingAdapter::new()));
+		let plain_failures = failed_handshakes(&servers[0]);
+		let _ = net.run(plain.connect_peer(addrs[0], handle.clone()));
+		let peer = net.run(plain.connect_peer(optional_addr, handle.clone())).unwrap().unwrap();
+		assert!(peer.info.capabilities.contains(ENCRYPTED));
+		assert!(net.wait_for(|| {
+			failed_handshakes(&servers[0]) > plain_failures &&
+			optional.connected_peers().len() == 1
+		}));
+		assert_eq!(servers[0].connected_peers().len(), 1);
+		assert_eq!(plain.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>(),
+		           vec![optional_addr]);
+
+		// connecting again to a peer we secured our connection to, the
+		// connection gets secured before the handshake
+		let config = P2PConfig { tls: Some(tls_config("peer.p12")), ..P2PConfig::default() };
+		let again = TestNetwork::dialer(config, Arc::new(RecordingAdapter::new()));
+		let peer = net.run(again.connect_peer(addrs[0], handle.clone())).unwrap().unwrap();
+		assert!(net.wait_for(|| servers[0].connected_peers().len() == 2));
+		peer.stop();
+		assert!(net.wait_for(|| servers[0].connected_peers().len() == 1));
+		let ctx = again.tls.clone().unwrap().unwrap();
+		assert!(ctx.secured_before(&addrs[0]));
+		let peer = net.run(again.connect_peer(addrs[0], handle.clone())).unwrap().unwrap();
+		assert!(peer.info.capabilities.contains(ENCRYPTED));
+		assert!(net.wait_for(|| servers[0].connected_peers().len() == 2));
+
+		// even should it answer in plaintext, nobody in the middle being able to
+		// clear what it advertises
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let plaintext_addr = listener.local_addr().unwrap();
+		let (first_tx, first) = mpsc::channel();
+		thread::spawn(move || {
+			let (mut conn, _) = listener.accept().unwrap();
+			let mut buf = [0u8; 1];
+			conn.read_exact(&mut buf).unwrap();
+			first_tx.send(buf[0]).unwrap();
+		});
+		ctx.record_secured(plaintext_addr);
+		assert!(net.run(again.connect_peer(plaintext_addr, handle.clone())).is_err());
+		assert_eq!(first.recv().unwrap(), 0x16);
+
+		// and a server that can't load its identity doesn't start
+		let config = P2PConfig {
+			port: free_port(),
+			tls: Some(tls_config("missing.p12")),
+			..P2PConfig::default()
+		};
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		match net.run(server.start(handle.clone())) {
+			Err(Error::Tls(_)) => {}
+			_ => panic!("server started without its identity"),
+		}
+	}
+
+	#[test]
+	fn persistent_peers_maintained() {
+		let lines = captured_logs();
+
+		// a peer closing every connection right away, failing the handshake
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let peer = listener.local_addr().unwrap();
+		let attempts = Arc::new(Mutex::new(vec![]));
+		let recorded = attempts.clone();
+		thread::spawn(move || {
+			for conn in listener.incoming() {
+				drop(conn);
+				recorded.lock().unwrap_or_else(|e| e.into_inner()).push(Instant::now());
+			}
+		});
+		let attempt_count = || attempts.lock().unwrap_or_else(|e| e.into_inner()).len();
+
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let port = free_port();
+		let own = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+		let banned: SocketAddr = "127.0.0.3:13725".parse().unwrap();
+		let config = P2PConfig {
+			port: port,
+			persistent_peers: vec![peer, own, banned],
+			persistent_retry_ms: 100,
+			persistent_retry_max_ms: 400,
+			..P2PConfig::default()
+		};
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		server.restore_ban(banned.ip(), Severity::Ban, "test", None);
+		handle.spawn(server.start(handle.clone()).map_err(|_| ()));
+		assert!(net.wait_for(|| attempt_count() >= 4));
+
+		// retried with a doubling delay, up to the max
+		let times = attempts.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		let gaps = times.windows(2).map(|w| w[1].duration_since(w[0])).collect::<Vec<_>>();
+		assert!(gaps[0] >= Duration::from_millis(100));
+		assert!(gaps[1] >= Duration::from_millis(200));
+		assert!(gaps[2] >= Duration::from_millis(400) && gaps[2] < Duration::from_millis(700));
+
+		// while our own and banned addresses were given up on right away
+		let logs = lines.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		for msg in &[format!("Not maintaining a connection to {}, our own address.", own),
+		             format!("Not maintaining a connection to {}, not allowed.", banned)] {
+			assert_eq!(logs.iter().filter(|&&(_, ref l)| l == msg).count(), 1);
+		}
+
+		// and nothing is retried once stopped, for longer than the max delay
+		server.stop();
+		let stopped = attempt_count();
+		net.run_for(Duration::from_millis(600));
+		assert_eq!(attempt_count(), stopped);
+	}
+
+	#[test]
+	fn concurrent_connections_deduplicated() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let (_other_server, other) =
+			net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// our dial to the other server gets past its checks, then it connects
+		// to us before our handshake is over
+		let dial = server.connect_peer(other, handle.clone());
+		let _inbound = net.run_thread(move || raw_handshake(addr, other));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+		let inbound = server.connected_peers();
+		assert_eq!(inbound[0].info.direction, Direction::Inbound);
+
+		// the dial then gives us the peer we already had
+		let dialed = net.run(dial).unwrap().unwrap();
+		assert_eq!(dialed.info.id, inbound[0].info.id);
+		assert_eq!(server.peer_count(), 1);
+
+		// and a later inbound connection claiming the same address is closed
+		let closed = net.run_thread(move || {
+			let hand = Hand { nonce: 43, ..test_hand(addr, other) };
+			let mut conn = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let mut buf = [0; 1];
+			match conn.read(&mut buf) {
+				Ok(0) => true,
+				_ => false,
+			}
+		});
+		assert!(closed);
+		assert_eq!(server.connected_peers().len(), 1);
+		assert_eq!(server.connected_peers()[0].info.id, inbound[0].info.id);
+
+		// claiming it from another IP isn't the same peer, nor pushes it out
+		let _spoofing = net.run_thread(move || {
+			let hand = Hand { nonce: 44, ..test_hand(addr, other) };
+			send_hand(connect_from("127.0.0.2", addr), hand)
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+		let connected = server.connected_peers();
+		assert!(connected.iter().any(|p| p.info.id == inbound[0].info.id));
+	}
+
+	#[test]
+	fn peers_looked_up() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let (kept, banned): (SocketAddr, SocketAddr) =
+			("127.0.0.1:13729".parse().unwrap(), "127.0.0.2:13730".parse().unwrap());
+
+		// nothing to find on an empty server
+		assert!(server.connected_peers().is_empty());
+		assert!(server.get_peer(kept).is_none());
+
+		let _conns = net.run_thread(move || {
+			let first = raw_handshake(addr, kept);
+			let hand = Hand { nonce: 43, ..test_hand(addr, banned) };
+			(first, send_hand(connect_from("127.0.0.2", addr), hand))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		// a peer we got disconnected from is still around, but not connected
+		assert!(server.ban_peer(banned, Severity::Ban, "test"));
+		assert_eq!(server.peer_count(), 2);
+		let connected = server.connected_peers();
+		assert_eq!(connected.len(), 1);
+		assert_eq!(connected[0].info.addr, kept);
+		assert_eq!(server.get_peer(kept).unwrap().info.id, connected[0].info.id);
+		assert!(server.get_peer(banned).is_none());
+
+		// nor is an address we never connected to
+		assert!(server.get_peer("127.0.0.1:13731".parse().unwrap()).is_none());
+	}
+
+	#[test]
+	fn misbehaving_peers_quarantined() {
+		let mut net = TestNetwork::empty();
+		let adapter = RecordingAdapter { invalid_height: Some(7), ..RecordingAdapter::new() };
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(adapter));
+
+		// a peer sending invalid blocks
+		let peer_addr: SocketAddr = "127.0.0.2:13733".parse().unwrap();
+		let (send_tx, send_rx) = mpsc::channel();
+		let client = thread::spawn(move || {
+			let mut conn = send_hand(connect_from("127.0.0.2", addr), test_hand(addr, peer_addr));
+			let mut b = core::Block::default();
+			b.header.height = 7;
+			while let Ok(()) = send_rx.recv() {
+				conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			}
+			conn
+		});
+		send_tx.send(()).unwrap();
+		let first_score = Violation::InvalidBlock.score();
+		assert!(net.wait_for(|| server.misbehavior_score(&peer_addr) == first_score));
+		assert_eq!(server.connected_peers().len(), 1);
+
+		// reaches the ban score with the second one, its host isn't let back in
+		send_tx.send(()).unwrap();
+		assert!(net.wait_for(|| server.is_banned(&peer_addr)));
+		assert!(net.wait_for(|| server.connected_peers().is_empty()));
+		drop(send_tx);
+		let _conn = client.join().unwrap();
+		let refused = net.run_thread(move || {
+			let mut conn = connect_from("127.0.0.2", addr);
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let mut buf = [0; 1];
+			match conn.read(&mut buf) {
+				Ok(0) => true,
+				_ => false,
+			}
+		});
+		assert!(refused);
+
+		// a host whose handshakes we can't decode adds up to the ban score too
+		fn botched(addr: SocketAddr, n: usize) {
+			for _ in 0..n {
+				let mut conn = connect_from("127.0.0.3", addr);
+				conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+				conn.write_all(&raw_msg(Type::Hand, &Empty {})).unwrap();
+				let _ = conn.read(&mut [0; 1]);
+			}
+		}
+		let host: SocketAddr = "127.0.0.3:13734".parse().unwrap();
+		net.run_thread(move || botched(addr, 4));
+		let botched_score = 4 * Violation::MalformedHandshake.score();
+		assert!(net.wait_for(|| server.misbehavior_score(&host) == botched_score));
+		assert!(!server.is_banned(&host));
+		net.run_thread(move || botched(addr, 1));
+		assert!(net.wait_for(|| server.is_banned(&host)));
+	}
+
+	#[test]
+	fn duplicate_nonces() {
+		let mut net = TestNetwork::empty();
+		let (flagging, flag_addr) =
+			net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let config = P2PConfig { duplicate_nonce: DuplicateNonce::Reject, ..P2PConfig::default() };
+		let (rejecting, reject_addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// two peers at different addresses with the same nonce, for each server
+		let first: SocketAddr = "127.0.0.1:13588".parse().unwrap();
+		let second: SocketAddr = "127.0.0.1:13589".parse().unwrap();
+		let _conns = net.run_thread(move || {
+			let flagged = (raw_handshake(flag_addr, first), raw_handshake(flag_addr, second));
+			let accepted = raw_handshake(reject_addr, first);
+			// the rejecting server won't even reply
+			let mut rejected = net::TcpStream::connect(reject_addr).unwrap();
+			rejected.write_all(&raw_msg(Type::Hand, &test_hand(reject_addr, second))).unwrap();
+			assert_eq!(rejected.read(&mut [0; 1]).unwrap_or(0), 0);
+			(flagged, accepted)
+		});
+		assert!(net.wait_for(|| {
+			flagging.connected_peers().len() == 2 && rejecting.connected_peers().len() == 1
+		}));
+
+		let mut flagged = flagging.connected_peers()
+			.iter()
+			.map(|p| (p.info.addr, p.info.duplicate_nonce))
+			.collect::<Vec<_>>();
+		flagged.sort();
+		assert_eq!(flagged, vec![(first, false), (second, true)]);
+
+		let accepted = rejecting.connected_peers();
+		assert_eq!(accepted[0].info.addr, first);
+		assert_eq!(rejecting.handshake_failures().get(&HandshakeFailure::DuplicateNonce),
+		           Some(&1));
+	}
+
+	#[test]
+	fn for_each_connected_peer() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let mut conns = net.run_thread(move || {
+			(13591..13594)
+				.map(|port| {
+					let mut hand = test_hand(addr, SocketAddr::new(addr.ip(), port));
+					hand.total_difficulty = Difficulty::from_num(port as u32);
+					send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+				})
+				.collect::<Vec<_>>()
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 3));
+
+		// one peer leaves, it doesn't count anymore even before being cleaned up
+		drop(conns.pop());
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let mut addrs = vec![];
+		let mut total = Difficulty::from_num(0);
+		server.for_each_peer(|p| {
+			addrs.push(p.info.addr);
+			total = total.clone() + p.total_difficulty();
+		});
+		addrs.sort();
+
+		let peers = server.connected_peers();
+		let mut expected = peers.iter().map(|p| p.info.addr).collect::<Vec<_>>();
+		expected.sort();
+		assert_eq!(addrs, expected);
+		assert_eq!(addrs.len(), 2);
+		assert_eq!(total, Difficulty::from_num(13591 + 13592));
+	}
+
+	#[test]
+	fn dials_bounded() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { max_concurrent_dials: 3, ..P2PConfig::default() };
+		let (server, _) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a silent node accepting connections but never completing handshakes,
+		// so dials stay in flight until they time out
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let accepted = Arc::new(Mutex::new(vec![]));
+		let acc = accepted.clone();
+		thread::spawn(move || for conn in listener.incoming() {
+			acc.lock().unwrap_or_else(|e| e.into_inner()).push(conn.unwrap());
+		});
+		let accepted_count = || accepted.lock().unwrap_or_else(|e| e.into_inner()).len();
+
+		for _ in 0..10 {
+			handle.spawn(server.connect_peer(addr, handle.clone()).map(|_| ()).map_err(|_| ()));
+		}
+		assert!(net.wait_for(|| server.dials_in_progress() == 3 && accepted_count() == 3));
+	}
+
+	#[test]
+	fn waiting_dials_bounded() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let limit = Arc::new(Mutex::new(DialLimit { max_waiting: 2, ..DialLimit::new(1) }));
+		let waiting = || limit.lock().unwrap_or_else(|e| e.into_inner()).waiting.len();
+
+		// one dial in flight and two waiting, the next one fails
+		let slot = net.run(DialSlot::acquire(&limit)).unwrap();
+		let (tx, rx) = mpsc::channel();
+		for _ in 0..2 {
+			let tx = tx.clone();
+			handle.spawn(DialSlot::acquire(&limit).then(move |res| {
+				tx.send(res.is_ok()).unwrap();
+				Ok(())
+			}));
+		}
+		assert!(net.wait_for(|| waiting() == 2));
+		match net.run(DialSlot::acquire(&limit)) {
+			Err(Error::TooManyDials) => {}
+			_ => panic!("dial past the waiting limit didn't fail"),
+		}
+
+		// the waiting ones get their turn as the slot frees up
+		drop(slot);
+		assert!(net.wait_for(|| {
+			waiting() == 0 && limit.lock().unwrap_or_else(|e| e.into_inner()).in_flight == 0
+		}));
+		assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![true, true]);
+	}
+
+	#[test]
+	fn handshake_timeout_configured() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { handshake_timeout_secs: 1, ..P2PConfig::default() };
+		let (server, server_addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a silent node stalling the handshake of our dial
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		thread::spawn(move || {
+			let _conns = listener.incoming().collect::<Vec<_>>();
+		});
+		let start = Instant::now();
+		match net.run(server.connect_peer(addr, handle.clone())) {
+			Err(Error::Timeout) => {}
+			_ => panic!("stalled dial didn't time out"),
+		}
+		let elapsed = start.elapsed();
+		assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3));
+
+		// and a silent inbound peer never sending its hand
+		let (closed, elapsed) = net.run_thread(move || {
+			let mut conn = net::TcpStream::connect(server_addr).unwrap();
+			conn.set_read_timeout(Some(Duration::from_secs(4))).unwrap();
+			let start = Instant::now();
+			let mut buf = [0; 1];
+			let closed = match conn.read(&mut buf) {
+				Ok(0) => true,
+				_ => false,
+			};
+			(closed, start.elapsed())
+		});
+		assert!(closed);
+		assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(3));
+	}
+
+	#[test]
+	fn stop_cancels_dials() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { max_concurrent_dials: 1, ..P2PConfig::default() };
+		let (server, _) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a silent node, one dial in flight and another waiting for a slot
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		thread::spawn(move || {
+			let _conns = listener.incoming().collect::<Vec<_>>();
+		});
+		let cancelled = Arc::new(Mutex::new(vec![]));
+		for _ in 0..2 {
+			let cancelled = cancelled.clone();
+			handle.spawn(server.connect_peer(addr, handle.clone()).then(move |res| {
+				cancelled.lock().unwrap_or_else(|e| e.into_inner()).push(match res {
+					Err(Error::ConnectionClose) => true,
+					_ => false,
+				});
+				Ok(())
+			}));
+		}
+		let dials = server.dials.clone();
+		let waiting = || dials.lock().unwrap_or_else(|e| e.into_inner()).waiting.len();
+		assert!(net.wait_for(|| server.dials_in_progress() == 1 && waiting() == 1));
+		assert!(cancelled.lock().unwrap_or_else(|e| e.into_inner()).is_empty());
+
+		// both resolve well before the handshake timeout once stopped
+		let stopped = Instant::now();
+		server.stop();
+		assert!(net.wait_for(|| cancelled.lock().unwrap_or_else(|e| e.into_inner()).len() == 2));
+		assert!(stopped.elapsed() < Duration::from_secs(1));
+		assert_eq!(*cancelled.lock().unwrap_or_else(|e| e.into_inner()), vec![true, true]);
+		let dials = dials.lock().unwrap_or_else(|e| e.into_inner());
+		assert_eq!(dials.in_flight, 0);
+		assert!(dials.waiting.is_empty());
+	}
+
+	#[test]
+	fn stale_difficulty_passed_over() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { stale_difficulty_secs: 1, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a peer claiming a lot of work that never advances, then a lower one
+		// connecting once the first one's difficulty went stale
+		let stuck: SocketAddr = "127.0.0.1:13549".parse().unwrap();
+		let fresh: SocketAddr = "127.0.0.1:13550".parse().unwrap();
+		let _conns = net.run_thread(move || {
+			let mut hand = test_hand(addr, stuck);
+			hand.total_difficulty = Difficulty::from_num(1000);
+			let first = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+			thread::sleep(Duration::from_millis(1500));
+			let mut hand = test_hand(addr, fresh);
+			hand.total_difficulty = Difficulty::from_num(10);
+			let second = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+			(first, second)
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let best = server.most_work_peer().unwrap();
+		assert_eq!(best.info.addr, fresh);
+		assert!(best.uptime() < Duration::from_secs(1));
+	}
+
+	#[test]
+	fn most_work_excluding() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let addrs = (13577..13580)
+			.map(|port| SocketAddr::new(addr.ip(), port))
+			.collect::<Vec<_>>();
+		let peer_addrs = addrs.clone();
+		let _conns = net.run_thread(move || {
+			peer_addrs.iter()
+				.enumerate()
+				.map(|(n, peer_addr)| {
+					let mut hand = test_hand(addr, *peer_addr);
+					hand.total_difficulty = Difficulty::from_num(100 * (n as u32 + 1));
+					send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+				})
+				.collect::<Vec<_>>()
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 3));
+
+		let mut exclude = HashSet::new();
+		assert_eq!(server.most_work_peer_excluding(&exclude).unwrap().info.addr, addrs[2]);
+		exclude.insert(addrs[2]);
+		assert_eq!(server.most_work_peer_excluding(&exclude).unwrap().info.addr, addrs[1]);
+		exclude.insert(addrs[1]);
+		exclude.insert(addrs[0]);
+		assert!(server.most_work_peer_excluding(&exclude).is_none());
+	}
+
+	#[test]
+	fn most_work_peers_ranked() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let addrs = (13713..13717)
+			.map(|port| SocketAddr::new(addr.ip(), port))
+			.collect::<Vec<_>>();
+		let peer_addrs = addrs.clone();
+		let srv = server.clone();
+		let _conns = net.run_thread(move || {
+			let mut conns = peer_addrs.iter()
+				.zip(vec![100, 300, 200, 400])
+				.map(|(peer_addr, diff)| {
+					let mut hand = test_hand(addr, *peer_addr);
+					hand.total_difficulty = Difficulty::from_num(diff);
+					send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+				})
+				.collect::<Vec<_>>();
+			// the most worked peer leaves once connected
+			assert!(poll_until(|| srv.connected_peers().len() == 4));
+			conns.pop();
+			conns
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 3));
+
+		let ranked = |count| {
+			server.most_work_peers(count).iter().map(|p| p.info.addr).collect::<Vec<_>>()
+		};
+		assert_eq!(ranked(2), vec![addrs[1], addrs[2]]);
+		assert_eq!(ranked(10), vec![addrs[1], addrs[2], addrs[0]]);
+		assert!(ranked(0).is_empty());
+		assert_eq!(server.most_work_peer().unwrap().info.addr, addrs[1]);
+	}
+
+	#[test]
+	fn peer_info_round_trip() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let head = core::Block::default().hash();
+		let adapter = RecordingAdapter {
+			difficulty: Difficulty::from_num(42),
+			head: head,
+			..RecordingAdapter::new()
+		};
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(adapter));
+
+		let client = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let peer = net.run(client.connect_peer(addr, handle.clone())).unwrap().unwrap();
+
+		let info = net.run(peer.request_peer_info().unwrap()).unwrap();
+		assert_eq!(info.tip, head);
+		assert_eq!(info.total_difficulty, Difficulty::from_num(42));
+		assert_eq!(info.peer_count, 1);
+		assert!(info.uptime < 5);
+
+		// another peer connecting doesn't show when asking again right away
+		let _conn = net.run_thread(move || {
+			send_hand(net::TcpStream::connect(addr).unwrap(),
+			          test_hand(addr, SocketAddr::new(addr.ip(), 13596)))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let again = net.run(peer.request_peer_info().unwrap()).unwrap();
+		assert_eq!(again, info);
+	}
+
+	#[test]
+	fn known_transactions_not_announced() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let client = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let peer = net.run(client.connect_peer(addr, handle.clone())).unwrap().unwrap();
+		assert!(peer.info.features.contains(TX_INV));
+
+		// the client announces a transaction, only once
+		let tx = core::Transaction::empty();
+		assert_eq!(client.broadcast_transaction(&tx).sent, 1);
+		assert_eq!(client.broadcast_transaction(&tx).skipped, 1);
+		assert!(net.wait_for(|| {
+			server.connected_peers().iter().any(|p| p.knows_transaction(tx.hash()))
+		}));
+
+		// the server doesn't announce it back, the client told it about it
+		let stats = server.broadcast_transaction(&tx);
+		assert_eq!((stats.sent, stats.skipped), (0, 1));
+
+		let other = core::Transaction::new(vec![], vec![], 1);
+		assert_eq!(server.broadcast_transaction(&other).sent, 1);
+		assert_eq!(server.broadcast_transaction(&other).skipped, 1);
+	}
+
+	#[test]
+	fn silent_peer_unverified() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// a peer going silent after the handshake and one pinging us
+		let silent = SocketAddr::new(addr.ip(), 13632);
+		let pinging = SocketAddr::new(addr.ip(), 13633);
+		let _conns = net.run_thread(move || {
+			let quiet = raw_handshake(addr, silent);
+			let mut conn = raw_handshake(addr, pinging);
+			conn.write_all(&raw_msg(Type::Ping, &Empty {})).unwrap();
+			let mut pong = vec![0; HEADER_LEN as usize];
+			conn.read_exact(&mut pong).unwrap();
+			(quiet, conn)
+		});
+
+		assert!(net.wait_for(|| server.verified_peer_count() == 1));
+		assert_eq!(server.peer_count(), 2);
+		for p in server.connected_peers() {
+			assert_eq!(p.is_verified(), p.info.addr == pinging);
+		}
+	}
+
+	#[test]
+	fn sync_mode_suppresses_transactions() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (_server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let client = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		net.run(client.connect_peer(addr, handle.clone())).unwrap().unwrap();
+
+		// nothing relayed while syncing, not even counted as skipped
+		let tx = core::Transaction::empty();
+		client.set_sync_mode(true);
+		assert!(client.sync_mode());
+		let stats = client.broadcast_transaction(&tx);
+		assert_eq!((stats.sent, stats.skipped, stats.failed), (0, 0, 0));
+
+		// relayed as usual once done syncing
+		client.set_sync_mode(false);
+		assert_eq!(client.broadcast_transaction(&tx).sent, 1);
+	}
+
+	#[test]
+	fn own_transactions_queued_while_syncing() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (_server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let adapter = Arc::new(RecordingAdapter::new());
+		let client = TestNetwork::dialer(P2PConfig::default(), adapter.clone());
+		net.run(client.connect_peer(addr, handle.clone())).unwrap().unwrap();
+
+		// ours get queued rather than dropped while syncing
+		let stemmed = core::Transaction::empty();
+		let fluffed = core::Transaction::new(vec![], vec![], 1);
+		client.set_sync_mode(true);
+		assert_eq!(client.stem_transaction(&stemmed).sent, 0);
+		assert_eq!(client.fluff_transaction(&fluffed).sent, 0);
+		let p = client.connected_peers().pop().unwrap();
+		assert!(!p.knows_transaction(stemmed.hash()));
+		assert!(!p.knows_transaction(fluffed.hash()));
+
+		// relayed once done, without dandelion the stemmed one is broadcast
+		client.set_sync_mode(false);
+		assert!(p.knows_transaction(stemmed.hash()));
+		assert!(p.knows_transaction(fluffed.hash()));
+		assert_eq!(*adapter.fluffed.lock().unwrap_or_else(|e| e.into_inner()),
+		           vec![stemmed.hash()]);
+		assert!(client.queued_txs.lock().unwrap_or_else(|e| e.into_inner()).is_empty());
+	}
+
+	fn checkpoint(height: u64, n: u8) -> Checkpoint {
+		Checkpoint {
+			height: height,
+			hash: Hash([n; 32]),
+		}
+	}
+
+	#[test]
+	fn checkpoint_majority() {
+		let ip = "10.0.0.1".parse().unwrap();
+		let addrs = (0..4).map(|n| SocketAddr::new(ip, n)).collect::<Vec<_>>();
+		let reports = vec![(addrs[0], vec![checkpoint(10, 1), checkpoint(20, 2)]),
+		                   (addrs[1], vec![checkpoint(10, 1), checkpoint(20, 3)]),
+		                   (addrs[2], vec![checkpoint(10, 1)]),
+		                   (addrs[3], vec![checkpoint(10, 9), checkpoint(30, 4)])];
+		let (agreed, conflicting) = majority_checkpoints(&reports);
+
+		// no majority at height 20 and a single peer vouching for height 30
+		assert_eq!(agreed, vec![checkpoint(10, 1)]);
+		assert_eq!(conflicting.into_iter().collect::<Vec<_>>(), vec![addrs[3]]);
+	}
+
+	#[test]
+	fn checkpoints_collected() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let adapter = Arc::new(RecordingAdapter::new());
+		let server = TestNetwork::dialer(P2PConfig::default(), adapter.clone());
+
+		// two peers agree, the third one is on another fork
+		let mut addrs = vec![];
+		for fork in vec![1, 1, 2] {
+			let adapter = RecordingAdapter {
+				reported: vec![checkpoint(10, 1), checkpoint(20, fork)],
+				..RecordingAdapter::new()
+			};
+			let (_, addr) = net.serve(P2PConfig::default(), Arc::new(adapter));
+			net.run(server.connect_peer(addr, handle.clone())).unwrap();
+			addrs.push(addr);
+		}
+
+		let agreed = net.run(server.collect_checkpoints()).unwrap();
+		assert_eq!(agreed, vec![checkpoint(10, 1), checkpoint(20, 1)]);
+		assert_eq!(*adapter.agreed.lock().unwrap_or_else(|e| e.into_inner()), agreed);
+		for p in server.connected_peers() {
+			assert_eq!(p.is_conflicting(), p.info.addr == addrs[2]);
+		}
+		// the peer on the other fork isn't synced with
+		let ranked = server.most_work_peers(3).iter().map(|p| p.info.addr).collect::<Vec<_>>();
+		assert_eq!(ranked.len(), 2);
+		assert!(!ranked.contains(&addrs[2]));
+	}
+
+	#[test]
+	fn utxo_chunks_served() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let server = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// only peers advertising snapshots get asked for them
+		let mut peers = vec![];
+		for capab in vec![UTXO_SNAPSHOT, UNKNOWN] {
+			let adapter = RecordingAdapter {
+				head: Hash([7; 32]),
+				utxo_chunks: 2,
+				..RecordingAdapter::new()
+			};
+			let (_, addr) = net.serve_with(capab, P2PConfig::default(), Arc::new(adapter));
+			peers.push(net.run(server.connect_peer(addr, handle.clone())).unwrap().unwrap());
+		}
+		assert_eq!(server.peers_with_capabilities(UTXO_SNAPSHOT).len(), 1);
+		match peers[1].request_utxo_chunk(Hash([7; 32]), 0) {
+			Err(Error::UnsupportedMessage(Type::GetUtxoChunk)) => {}
+			r => panic!("unexpected result {:?}", r.map(|_| ())),
+		}
+
+		let chunk = net.run(peers[0].request_utxo_chunk(Hash([7; 32]), 1).unwrap()).unwrap();
+		assert_eq!((chunk.horizon, chunk.chunk, chunk.chunks), (Hash([7; 32]), 1, 2));
+		// nothing to send at a horizon the peer doesn't know
+		let chunk = net.run(peers[0].request_utxo_chunk(Hash([8; 32]), 0).unwrap()).unwrap();
+		assert_eq!(chunk.chunks, 0);
+		assert!(chunk.outputs.is_empty());
+	}
+
+	#[test]
+	fn dandelion_stem_then_fluff() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let dandelion = DandelionConfig {
+			fluff_percent: 0,
+			embargo_secs: 0,
+			..DandelionConfig::default()
+		};
+		let config = P2PConfig { dandelion: Some(dandelion), ..P2PConfig::default() };
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		// our only outbound peer is our stem, the inbound one isn't used
+		let stem_adapter = Arc::new(RecordingAdapter::new());
+		let (_stem, stem_addr) = net.serve(P2PConfig::default(), stem_adapter.clone());
+		net.run(server.connect_peer(stem_addr, handle.clone())).unwrap().unwrap();
+		let inbound = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		net.run(inbound.connect_peer(addr, handle.clone())).unwrap().unwrap();
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let tx = core::Transaction::empty();
+		assert_eq!(server.stem_transaction(&tx).sent, 1);
+		let stemmed = || stem_adapter.stemmed.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		assert!(net.wait_for(|| stemmed() == vec![tx.hash()]));
+		assert!(stem_adapter.fluffed.lock().unwrap_or_else(|e| e.into_inner()).is_empty());
+
+		// without anyone broadcasting it, we do once the embargo ends
+		assert!(net.wait_for(|| {
+			server.embargoes.lock().unwrap_or_else(|e| e.into_inner()).is_empty() &&
+			server.connected_peers().iter().all(|p| p.knows_transaction(tx.hash()))
+		}));
+		assert_eq!(*adapter.fluffed.lock().unwrap_or_else(|e| e.into_inner()),
+		           vec![tx.hash()]);
+
+		// stemmed to us, passed on along our stem
+		let other = core::Transaction::new(vec![], vec![], 1);
+		assert_eq!(server.relay_stem_transaction(&other).sent, 1);
+		let stats = server.broadcast_transaction(&other);
+		assert_eq!((stats.sent, stats.skipped), (1, 1));
+	}
+
+	#[test]
+	fn public_addr_majority() {
+		let server = Server::new(UNKNOWN, P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		assert_eq!(server.public_addr(), None);
+
+		let public: SocketAddr = "1.2.3.4:13414".parse().unwrap();
+		let other: SocketAddr = "5.6.7.8:13414".parse().unwrap();
+		let observer = |n: u8| IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, n));
+		for n in 0..3 {
+			record_observed(&server.observed_addrs, observer(n), public);
+		}
+		// a single host insisting only counts once
+		for _ in 0..5 {
+			record_observed(&server.observed_addrs, observer(3), other);
+		}
+		record_observed(&server.observed_addrs, observer(4), other);
+		record_observed(&server.observed_addrs, observer(5), "0.0.0.0:13414".parse().unwrap());
+		assert_eq!(server.public_addr(), Some(public));
+
+		// the hosts changing their mind tips it over
+		record_observed(&server.observed_addrs, observer(0), other);
+		record_observed(&server.observed_addrs, observer(1), other);
+		assert_eq!(server.public_addr(), Some(other));
+
+		// too few hosts agreeing on any address
+		let server = Server::new(UNKNOWN, P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		record_observed(&server.observed_addrs, observer(0), public);
+		record_observed(&server.observed_addrs, observer(1), public);
+		record_observed(&server.observed_addrs, observer(2), other);
+		assert_eq!(server.public_addr(), None);
+		record_observed(&server.observed_addrs, observer(3), public);
+		assert_eq!(server.public_addr(), Some(public));
+	}
+
+	// Accepts a connection and closes it first, which leaves the port in
+	// TIME_WAIT, then binds the same port again once the listener is gone.
+	#[test]
+	fn rebind_after_drop() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig::default();
+		let any_port = SocketAddr::new(config.host, 0);
+		let listener = net.run(bind_with_retry(&any_port, &config, &handle)).unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let mut client = net::TcpStream::connect(addr).unwrap();
+		let (accepted, incoming) = net.run(listener.incoming().into_future())
+			.map_err(|(e, _)| e)
+			.unwrap();
+		drop(accepted.unwrap());
+		assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+		drop(incoming);
+		drop(client);
+
+		let rebound = net.run(bind_with_retry(&addr, &config, &handle));
+		assert_eq!(rebound.unwrap().local_addr().unwrap(), addr);
+	}
+
+	#[test]
+	fn bind_failure_reported() {
+		let taken = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = taken.local_addr().unwrap().port();
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig {
+			port: port,
+			reuse_addr: false,
+			..P2PConfig::default()
+		};
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		match net.run(server.start(handle.clone())) {
+			Err(Error::Bind(addr, _)) => assert_eq!(addr.port(), port),
+			res => panic!("expected a bind error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn bind_retried_on_reactor() {
+		let taken = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig {
+			port: taken.local_addr().unwrap().port(),
+			reuse_addr: false,
+			..P2PConfig::default()
+		};
+		let addr = SocketAddr::new(config.host, config.port);
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		handle.spawn(server.start(handle.clone()).map_err(|e| panic!("{:?}", e)));
+
+		// the reactor keeps running timers while the port is still taken
+		net.run_for(Duration::from_millis(20));
+		drop(taken);
+		assert!(net.wait_for(|| net::TcpStream::connect(addr).is_ok()));
+	}
+
+	#[test]
+	fn sync_status_from_peers() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let adapter = RecordingAdapter {
+			difficulty: Difficulty::from_num(10),
+			..RecordingAdapter::new()
+		};
+		let server = TestNetwork::dialer(P2PConfig::default(), Arc::new(adapter));
+		assert_eq!(server.sync_status(), SyncStatus::NoPeers);
+		assert_eq!(server.sync_status().progress(), 0.0);
+
+		// a peer at parity, then one ahead
+		let mut addrs = vec![];
+		for &diff in &[10, 40] {
+			let adapter = RecordingAdapter {
+				difficulty: Difficulty::from_num(diff),
+				..RecordingAdapter::new()
+			};
+			let (_, addr) = net.serve(P2PConfig::default(), Arc::new(adapter));
+			net.run(server.connect_peer(addr, handle.clone())).unwrap();
+			if diff == 10 {
+				assert_eq!(server.sync_status(), SyncStatus::Synced);
+				assert_eq!(server.sync_status().progress(), 1.0);
+			}
+			addrs.push(addr);
+		}
+		assert_eq!(server.sync_status(),
+		           SyncStatus::Syncing {
+			           behind_by: Difficulty::from_num(30),
+			           progress: 0.25,
+		           });
+
+		// the peer we sync with rather than the one claiming the most work
+		server.set_sync_peer(Some(addrs[0]));
+		assert_eq!(server.sync_status(), SyncStatus::Synced);
+		server.set_sync_peer(Some("127.0.0.1:13699".parse().unwrap()));
+		assert_eq!(server.sync_status().progress(), 0.25);
+		server.set_sync_peer(None);
+		assert_eq!(server.sync_status().progress(), 0.25);
+	}
+
+	#[test]
+	fn outbound_subnets_diversified() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let server = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		assert_eq!(server.outbound_subnets(), 0);
+
+		// all our outbound peers in a single subnet
+		for _ in 0..2 {
+			let (_, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+			net.run(server.connect_peer(addr, handle.clone())).unwrap();
+		}
+		assert_eq!(server.outbound_subnets(), 1);
+		assert!(server.lacks_subnet_diversity());
+		assert_eq!(server.diversity_dials(), 3);
+
+		// a book dominated by that same subnet
+		let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+		let book = vec![addr("127.0.0.2:13414"),
+		                addr("127.0.0.3:13414"),
+		                addr("10.1.0.1:13414"),
+		                addr("127.0.0.4:13414"),
+		                addr("10.2.0.1:13414"),
+		                addr("10.1.0.2:13414")];
+		assert_eq!(server.diverse_candidates(book.clone()),
+		           vec![addr("10.1.0.1:13414"),
+		                addr("10.2.0.1:13414"),
+		                addr("10.1.0.2:13414"),
+		                addr("127.0.0.2:13414"),
+		                addr("127.0.0.3:13414"),
+		                addr("127.0.0.4:13414")]);
+		assert_eq!(server.new_subnet_candidates(book),
+		           vec![addr("10.1.0.1:13414"), addr("10.2.0.1:13414")]);
+	}
+
+	#[test]
+	fn own_addrs_never_dialed() {
+		let config = P2PConfig {
+			host: "0.0.0.0".parse().unwrap(),
+			port: 13651,
+			..P2PConfig::default()
+		};
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		let public: SocketAddr = "1.2.3.4:23414".parse().unwrap();
+		let interface: IpAddr = "192.168.1.20".parse().unwrap();
+		for n in 0..MIN_ADDR_OBSERVERS {
+			let observer = IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, n as u8));
+			record_observed(&server.observed_addrs, observer, public);
+		}
+		server.local_ips.lock().unwrap_or_else(|e| e.into_inner()).insert(interface);
+
+		// our own addresses ending up in the book, among actual peers
+		let others: Vec<SocketAddr> = vec!["5.6.7.8:13651".parse().unwrap(),
+		                                   "192.168.1.21:13651".parse().unwrap()];
+		let mut book = vec![public,
+		                    "127.0.0.1:13651".parse().unwrap(),
+		                    SocketAddr::new(interface, 13651)];
+		book.extend(others.iter().cloned());
+		assert_eq!(server.dial_candidates(book), others);
+		assert!(!server.is_own_addr(&SocketAddr::new(interface, 13652)));
+
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		assert!(net.run(server.connect_peer(public, handle)).unwrap().is_none());
+		assert_eq!(server.dials_in_progress(), 0);
+	}
+
+	#[test]
+	fn public_addr_from_handshake() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let _conn = net.run_thread(move || raw_handshake(addr, SocketAddr::new(addr.ip(), 13626)));
+		let observed = || {
+			server.observed_addrs.lock().unwrap_or_else(|e| e.into_inner()).get(&addr.ip()) ==
+			Some(&addr)
+		};
+		assert!(net.wait_for(observed));
+		assert_eq!(server.public_addr(), None);
+
+		// taken once other hosts agree
+		for n in 1..MIN_ADDR_OBSERVERS {
+			let observer = IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, n as u8));
+			record_observed(&server.observed_addrs, observer, addr);
+		}
+		assert_eq!(server.public_addr(), Some(addr));
+	}
+
+	#[test]
+	fn public_addr_from_outbound() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (_server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// the peer we connect to tells the IP it sees us at, with our port
+		let dialer = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let peer = net.run(dialer.connect_peer(addr, handle.clone())).unwrap().unwrap();
+		let public = SocketAddr::new(addr.ip(), dialer.config.port);
+		assert_eq!(peer.info.observed_addr, Some(public));
+		assert_eq!(dialer.observed_addrs.lock().unwrap_or_else(|e| e.into_inner()).get(&addr.ip()),
+		           Some(&public));
+		for n in 1..MIN_ADDR_OBSERVERS {
+			let observer = IpAddr::V4(net::Ipv4Addr::new(10, 0, 0, n as u8));
+			record_observed(&dialer.observed_addrs, observer, public);
+		}
+		assert_eq!(dialer.public_addr(), Some(public));
+
+		// a port mapped on our gateway wins, unless behind yet another NAT
+		let private = SocketAddr::new("192.168.0.2".parse().unwrap(), public.port());
+		*dialer.mapped_addr.lock().unwrap_or_else(|e| e.into_inner()) = Some(private);
+		assert_eq!(dialer.public_addr(), Some(public));
+		let mapped: SocketAddr = "1.2.3.4:23772".parse().unwrap();
+		*dialer.mapped_addr.lock().unwrap_or_else(|e| e.into_inner()) = Some(mapped);
+		assert_eq!(dialer.public_addr(), Some(mapped));
+		assert!(dialer.is_own_addr(&mapped));
+	}
+
+	#[test]
+	fn other_chain_refused() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// same magic bytes, but a chain starting with another genesis block
+		let config = P2PConfig { genesis: ZERO_HASH, ..P2PConfig::default() };
+		let dialer = TestNetwork::dialer(config, Arc::new(RecordingAdapter::new()));
+		assert!(net.run(dialer.connect_peer(addr, handle.clone())).is_err());
+		assert!(net.wait_for(|| {
+			server.handshake_failures().get(&HandshakeFailure::WrongNetwork) == Some(&1)
+		}));
+		assert_eq!(server.peer_count(), 0);
+	}
+
+	#[test]
+	fn oversized_addrs_banned() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig {
+			oversized_addrs: OversizedAddrs::Disconnect,
+			..P2PConfig::default()
+		};
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		let sender_addr = SocketAddr::new(addr.ip(), 13624);
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, sender_addr);
+			let peers = (0..(MAX_PEER_ADDRS + 1))
+				.map(|n| SockAddr(SocketAddr::new(addr.ip(), n as u16)))
+				.collect();
+			conn.write_all(&raw_msg(Type::PeerAddrs, &PeerAddrs { peers: peers })).unwrap();
+			conn
+		});
+		let errors = || adapter.errors.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		assert!(net.wait_for(|| !errors().is_empty() && server.connected_peers().is_empty()));
+
+		assert_eq!(errors(), vec![(sender_addr, true)]);
+		assert!(server.read_peers().is_empty());
+		assert!(server.clean_peers()[0].is_banned());
+	}
+
+	// Peers advertising addresses on other networks than the one they connect
+	// from are still the source of what they gossip under their real IP.
+	#[test]
+	fn addrs_source_not_advertised() {
+		let mut net = TestNetwork::empty();
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (_server, addr) = net.serve(P2PConfig::default(), adapter.clone());
+
+		let _conns = net.run_thread(move || {
+			let advertised = vec!["10.0.0.1:13951", "172.16.0.1:13952"];
+			advertised.into_iter()
+				.enumerate()
+				.map(|(n, sender_addr)| {
+					let mut hand = test_hand(addr, sender_addr.parse().unwrap());
+					hand.nonce = 43 + n as u64;
+					let mut conn = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+					let peers = vec![SockAddr("8.8.8.8:13414".parse().unwrap())];
+					conn.write_all(&raw_msg(Type::PeerAddrs, &PeerAddrs { peers: peers })).unwrap();
+					conn
+				})
+				.collect::<Vec<_>>()
+		});
+		let sources = || adapter.sources.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		assert!(net.wait_for(|| sources().len() == 2));
+
+		let sources = sources();
+		for src in &sources {
+			assert_eq!(src.ip(), addr.ip());
+		}
+		// both peers fall in the same bucket
+		assert_eq!(subnet(&sources[0].ip()), subnet(&sources[1].ip()));
+	}
+
+	#[test]
+	fn min_peers_awaited() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// one peer right away, another one a bit later
+		let client = thread::spawn(move || {
+			let first = raw_handshake(addr, SocketAddr::new(addr.ip(), 13621));
+			thread::sleep(Duration::from_millis(500));
+			let mut hand = test_hand(addr, SocketAddr::new(addr.ip(), 13622));
+			hand.nonce = 43;
+			(first, send_hand(net::TcpStream::connect(addr).unwrap(), hand))
+		});
+		let start = Instant::now();
+		assert!(net.run(server.await_min_peers(1, Duration::from_secs(5))).unwrap());
+		assert_eq!(server.peer_count(), 1);
+		assert!(net.run(server.await_min_peers(2, Duration::from_secs(5))).unwrap());
+		assert_eq!(server.peer_count(), 2);
+		assert!(start.elapsed() >= Duration::from_millis(500));
+		let _conns = client.join().unwrap();
+
+		// already there, or never getting there
+		assert!(net.run(server.await_min_peers(2, Duration::from_secs(5))).unwrap());
+		assert!(!net.run(server.await_min_peers(3, Duration::from_millis(300))).unwrap());
+	}
+
+	#[test]
+	fn disconnected_peers_not_counted() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { clean_peers_interval_secs: 0, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let (gone, _staying) = net.run_thread(move || {
+			let gone = raw_handshake(addr, SocketAddr::new(addr.ip(), 13805));
+			let mut hand = test_hand(addr, SocketAddr::new(addr.ip(), 13806));
+			hand.nonce = 43;
+			(gone, send_hand(net::TcpStream::connect(addr).unwrap(), hand))
+		});
+		assert!(net.wait_for(|| server.peer_count() == 2));
+
+		// a peer we lost connection to and didn't prune yet isn't counted,
+		// neither by us nor in what we tell others
+		drop(gone);
+		assert!(net.wait_for(|| server.peer_count() == 1));
+		assert_eq!(server.read_peers().len(), 2);
+		assert_eq!(server.local.peer_count(), 1);
+		assert_eq!(server.fds.peer_count(), 1);
+		assert!(!net.run(server.await_min_peers(2, Duration::from_millis(300))).unwrap());
+
+		// a new peer takes us back to two, not three
+		let client = thread::spawn(move || {
+			let mut hand = test_hand(addr, SocketAddr::new(addr.ip(), 13807));
+			hand.nonce = 44;
+			send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+		});
+		assert!(!net.run(server.await_min_peers(3, Duration::from_millis(500))).unwrap());
+		let _new = client.join().unwrap();
+		assert!(net.wait_for(|| server.peer_count() == 2));
+	}
+
+	#[test]
+	fn disconnected_peers_pruned() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { clean_peers_interval_secs: 1, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let conn = net.run_thread(move || raw_handshake(addr, "127.0.0.1:13617".parse().unwrap()));
+		drop(conn);
+		assert!(net.wait_for(|| server.peer_count() == 0));
+		assert_eq!(server.read_peers().len(), 1);
+
+		// gone without anyone calling clean_peers within the interval
+		assert!(net.wait_for(|| server.read_peers().is_empty()));
+	}
+
+	#[test]
+	fn errored_peer_removed() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { clean_peers_interval_secs: 0, ..P2PConfig::default() };
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		// a block that doesn't deserialize fails the peer run
+		let sender_addr = SocketAddr::new(addr.ip(), 13664);
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, sender_addr);
+			let mut garbage = ser::ser_vec(&MsgHeader::new(magic(), Type::Block, 3)).unwrap();
+			garbage.extend_from_slice(&[1, 2, 3]);
+			conn.write_all(&garbage).unwrap();
+			conn
+		});
+		let disconnected = || adapter.disconnected.lock().unwrap().clone();
+		assert!(net.wait_for(|| !disconnected().is_empty()));
+
+		// gone without any pruning, still reported as banned to clean_peers
+		assert!(server.read_peers().is_empty());
+		assert_eq!(*adapter.errors.lock().unwrap_or_else(|e| e.into_inner()),
+		           vec![(sender_addr, true)]);
+		assert_eq!(disconnected(), vec![sender_addr]);
+		let cleaned = server.clean_peers();
+		assert_eq!(cleaned.len(), 1);
+		assert!(cleaned[0].is_banned());
+	}
+
+	#[test]
+	fn adapter_panic_drops_peer() {
+		let mut net = TestNetwork::empty();
+		let adapter = Arc::new(RecordingAdapter {
+			panic_height: Some(1),
+			..RecordingAdapter::new()
+		});
+		let (server, addr) = net.serve(P2PConfig::default(), adapter.clone());
+		let errors = || adapter.errors.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+		// a peer sending the block the adapter panics on
+		let failing_addr = SocketAddr::new(addr.ip(), 13614);
+		let _failing = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, failing_addr);
+			let mut b = core::Block::default();
+			b.header.height = 1;
+			conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			conn
+		});
+		assert!(net.wait_for(|| !errors().is_empty() && server.connected_peers().is_empty()));
+		assert_eq!(errors(), vec![(failing_addr, false)]);
+
+		// the server keeps going for other peers
+		let other_addr = SocketAddr::new(addr.ip(), 13615);
+		let _other = net.run_thread(move || {
+			let mut hand = test_hand(addr, other_addr);
+			hand.nonce = 43;
+			let mut conn = send_hand(net::TcpStream::connect(addr).unwrap(), hand);
+			let mut b = core::Block::default();
+			b.header.height = 2;
+			conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			conn
+		});
+		let validated = || adapter.validated.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		assert!(net.wait_for(|| validated() == vec![2]));
+		assert_eq!(server.connected_peers().len(), 1);
+	}
+
+	#[test]
+	fn worker_panic_drops_peer() {
+		let mut net = TestNetwork::empty();
+		let adapter = Arc::new(RecordingAdapter {
+			panic_height: Some(1),
+			..RecordingAdapter::new()
+		});
+		let config = P2PConfig { block_workers: 1, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		// the block worker panics, the peer gets closed without sending more
+		let closed = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, SocketAddr::new(addr.ip(), 13777));
+			let mut b = core::Block::default();
+			b.header.height = 1;
+			conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let mut buf = [0; 64];
+			loop {
+				match conn.read(&mut buf) {
+					Ok(0) => return true,
+					Ok(_) => continue,
+					Err(_) => return false,
+				}
+			}
+		});
+		assert!(closed);
+		assert!(net.wait_for(|| server.connected_peers().is_empty()));
+		assert!(adapter.validated.lock().unwrap_or_else(|e| e.into_inner()).is_empty());
+	}
+
+	#[test]
+	fn stalled_peer_dropped() {
+		use net2::TcpStreamExt;
+
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { send_timeout_secs: 1, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let stalled_addr = SocketAddr::new(addr.ip(), 13601);
+		let reader_addr = SocketAddr::new(addr.ip(), 13602);
+		let (_stalled, mut reader) = net.run_thread(move || {
+			let stalled = net::TcpStream::connect(addr).unwrap();
+			stalled.set_recv_buffer_size(4096).unwrap();
+			let mut hand = test_hand(addr, stalled_addr);
+			hand.nonce = 1;
+			let stalled = send_hand(stalled, hand);
+			let mut hand = test_hand(addr, reader_addr);
+			hand.nonce = 2;
+			(stalled, send_hand(net::TcpStream::connect(addr).unwrap(), hand))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		// one peer reads everything we send, the other nothing at all
+		let received = Arc::new(Mutex::new(0));
+		let counter = received.clone();
+		thread::spawn(move || {
+			let mut buf = vec![0; 65536];
+			loop {
+				match reader.read(&mut buf) {
+					Ok(0) | Err(_) => break,
+					Ok(n) => *counter.lock().unwrap_or_else(|e| e.into_inner()) += n as u64,
+				}
+			}
+		});
+
+		// way more than the socket buffers of the stalled peer can hold
+		for height in 1..30001 {
+			let mut b = core::Block::default();
+			b.header.height = height;
+			server.broadcast_block(&b);
+		}
+
+		// the stalled peer gets dropped and the reader gets everything
+		assert!(net.wait_for(|| {
+			let peers = server.connected_peers();
+			peers.len() == 1 && peers[0].info.addr == reader_addr &&
+			peers[0].transmitted_bytes().0 == *received.lock().unwrap_or_else(|e| e.into_inner())
+		}));
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn quarantine_expires() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { quarantine_secs: 1, ..P2PConfig::default() };
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		let quarantined: SocketAddr = "127.0.0.2:13552".parse().unwrap();
+		let banned: SocketAddr = "127.0.0.3:13553".parse().unwrap();
+		let _conns = net.run_thread(move || {
+			(send_hand(connect_from("127.0.0.2", addr), test_hand(addr, quarantined)),
+			 send_hand(connect_from("127.0.0.3", addr), test_hand(addr, banned)))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		assert!(server.ban_peer(quarantined, Severity::Quarantine, "test"));
+		assert!(server.ban_peer(banned, Severity::Ban, "test"));
+		assert_eq!(*adapter.banned.lock().unwrap_or_else(|e| e.into_inner()),
+		           vec![(quarantined, Severity::Quarantine), (banned, Severity::Ban)]);
+		match net.run(server.connect_peer(quarantined, handle.clone())) {
+			Err(Error::Banned) => {}
+			_ => panic!("dialed a quarantined peer"),
+		}
+
+		let _conn = net.run_thread(move || {
+			// refused while quarantined
+			let mut refused = connect_from("127.0.0.2", addr);
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+
+			// back once the quarantine is over, the banned peer stays out
+			thread::sleep(Duration::from_millis(1200));
+			let back = send_hand(connect_from("127.0.0.2", addr), test_hand(addr, quarantined));
+			let mut refused = connect_from("127.0.0.3", addr);
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+			back
+		});
+		let connected = || server.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+		assert!(net.wait_for(|| connected() == vec![quarantined]));
+	}
+
+	#[test]
+	fn ban_expires() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { ban_secs: 1, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let banned: SocketAddr = "127.0.0.6:13781".parse().unwrap();
+		let _conn = net.run_thread(move || {
+			send_hand(connect_from("127.0.0.6", addr), test_hand(addr, banned))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+		assert!(server.ban_peer(banned, Severity::Ban, "test"));
+		assert!(server.list_bans()[0].expires_in.unwrap() <= Duration::from_secs(1));
+
+		let _conn = net.run_thread(move || {
+			// refused while banned
+			let mut refused = connect_from("127.0.0.6", addr);
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+
+			// and accepted again once the ban ran out
+			thread::sleep(Duration::from_millis(1200));
+			send_hand(connect_from("127.0.0.6", addr), test_hand(addr, banned))
+		});
+		let connected = || server.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+		assert!(net.wait_for(|| connected() == vec![banned]));
+		assert!(!server.is_banned(&banned));
+	}
+
+	#[test]
+	fn ban_keyed_on_connection_ip() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// connecting from one host while advertising the address of another
+		let advertised: SocketAddr = "127.0.0.5:13779".parse().unwrap();
+		let _conn = net.run_thread(move || {
+			send_hand(connect_from("127.0.0.4", addr), test_hand(addr, advertised))
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		// the host we got the connection from is banned, not the advertised one
+		assert!(server.ban_peer(advertised, Severity::Ban, "test"));
+		let bans = server.list_bans().iter().map(|b| b.ip).collect::<Vec<_>>();
+		assert_eq!(bans, vec!["127.0.0.4".parse::<IpAddr>().unwrap()]);
+		assert!(!server.is_banned(&advertised));
+
+		net.run_thread(move || {
+			let mut refused = connect_from("127.0.0.4", addr);
+			assert_eq!(refused.read(&mut [0; 1]).unwrap_or(0), 0);
+		});
+		assert!(server.connected_peers().is_empty());
+	}
+
+	#[test]
+	fn slow_validation_isolated() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { block_workers: 2, ..P2PConfig::default() };
+		let adapter = Arc::new(RecordingAdapter {
+			slow_height: Some(1),
+			..RecordingAdapter::new()
+		});
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		let slow_addr: SocketAddr = "127.0.0.1:13555".parse().unwrap();
+		let srv = server.clone();
+		let _conns = net.run_thread(move || {
+			let mut slow = raw_handshake(addr, slow_addr);
+			let mut fast = raw_handshake(addr, "127.0.0.1:13556".parse().unwrap());
+			let mut b = core::Block::default();
+			b.header.height = 1;
+			slow.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			assert!(poll_until(|| received_msgs(&srv, slow_addr) == 1));
+			b.header.height = 2;
+			fast.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			(slow, fast)
+		});
+
+		// the second peer's block went through while the first one's is still
+		// being validated
+		let validated = || adapter.validated.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		assert!(net.wait_for(|| !validated().is_empty()));
+		assert_eq!(validated(), vec![2]);
+	}
+
+	#[test]
+	fn peer_blocks_kept_in_order() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { block_workers: 2, ..P2PConfig::default() };
+		let adapter = Arc::new(RecordingAdapter {
+			slow_height: Some(1),
+			..RecordingAdapter::new()
+		});
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		let peer_addr: SocketAddr = "127.0.0.1:13783".parse().unwrap();
+		let _conn = net.run_thread(move || {
+			let mut conn = raw_handshake(addr, peer_addr);
+			let mut b = core::Block::default();
+			b.header.height = 1;
+			conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			b.header.height = 2;
+			conn.write_all(&raw_msg(Type::Block, &b)).unwrap();
+			conn
+		});
+		assert!(net.wait_for(|| received_msgs(&server, peer_addr) == 2));
+
+		// the idle worker doesn't take the second block ahead of the first
+		assert!(adapter.validated.lock().unwrap_or_else(|e| e.into_inner()).is_empty());
+	}
+
+	#[test]
+	fn find_peer_states() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let connected: SocketAddr = "127.0.0.1:13558".parse().unwrap();
+		let gone: SocketAddr = "127.0.0.1:13559".parse().unwrap();
+		let banned: SocketAddr = "10.0.0.9:13414".parse().unwrap();
+		let unknown: SocketAddr = "10.0.0.10:13414".parse().unwrap();
+		let _conn = net.run_thread(move || {
+			let conn = raw_handshake(addr, connected);
+			drop(raw_handshake(addr, gone));
+			conn
+		});
+		let departed = || match server.find_peer(&gone) {
+			PeerLookup::Disconnected { .. } => true,
+			_ => false,
+		};
+		assert!(net.wait_for(departed));
+		assert!(!server.ban_peer(banned, Severity::Ban, "test"));
+
+		match server.find_peer(&connected) {
+			PeerLookup::Connected(p) => assert_eq!(p.info.addr, connected),
+			_ => panic!("connected peer not found"),
+		}
+		match server.find_peer(&gone) {
+			PeerLookup::Disconnected { last_seen } => assert!(last_seen < Duration::from_secs(1)),
+			_ => panic!("disconnected peer not found"),
+		}
+		match server.find_peer(&banned) {
+			PeerLookup::Banned { severity, .. } => assert_eq!(severity, Severity::Ban),
+			_ => panic!("banned peer not found"),
+		}
+		match server.find_peer(&unknown) {
+			PeerLookup::Unknown => {}
+			_ => panic!("unknown peer found"),
+		}
+	}
+
+	#[test]
+	fn departed_capped() {
+		let start = Instant::now();
+		let addr = |i: usize| {
+			SocketAddr::new(IpAddr::V4(net::Ipv4Addr::new(10, 0, (i / 256) as u8, i as u8)), 13414)
+		};
+		let mut departed = HashMap::new();
+		for i in 0..MAX_DEPARTED {
+			remember_departure(&mut departed, addr(i), start + Duration::from_secs(i as u64));
+		}
+		assert_eq!(departed.len(), MAX_DEPARTED);
+
+		// a peer seen again doesn't take more room
+		remember_departure(&mut departed, addr(1), start + Duration::from_secs(2000));
+		assert_eq!(departed.len(), MAX_DEPARTED);
+
+		// a new one makes the longest gone peer forgotten
+		let later = start + Duration::from_secs(3000);
+		remember_departure(&mut departed, addr(MAX_DEPARTED), later);
+		assert_eq!(departed.len(), MAX_DEPARTED);
+		assert!(!departed.contains_key(&addr(0)));
+		assert!(departed.contains_key(&addr(1)));
+
+		// and once they're gone for long enough, all of them are
+		let much_later = later + Duration::from_secs(DEPARTED_SECS);
+		remember_departure(&mut departed, addr(MAX_DEPARTED + 1), much_later);
+		assert_eq!(departed.len(), 1);
+	}
+
+	#[test]
+	fn bans_listed() {
+		let config = P2PConfig {
+			quarantine_secs: 1,
+			ban_secs: 0,
+			..P2PConfig::default()
+		};
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		let banned: SocketAddr = "10.0.0.11:13414".parse().unwrap();
+		let quarantined: SocketAddr = "10.0.0.12:13414".parse().unwrap();
+		server.ban_peer(banned, Severity::Ban, "bad blocks");
+		server.ban_peer(quarantined, Severity::Quarantine, "test");
+
+		let bans = server.list_bans();
+		assert_eq!(bans.len(), 2);
+		assert_eq!(bans[1].reason, "bad blocks");
+		assert_eq!(bans[0].ip, quarantined.ip());
+		assert_eq!(bans[0].severity, Severity::Quarantine);
+		assert!(bans[0].expires_in.unwrap() <= Duration::from_secs(1));
+		assert_eq!(bans[1].ip, banned.ip());
+		assert_eq!(bans[1].expires_in, None);
+
+		// the quarantine runs out, the ban gets lifted by hand
+		assert!(poll_until(|| server.list_bans().len() == 1));
+		let bans = server.list_bans();
+		assert_eq!(bans.iter().map(|b| b.ip).collect::<Vec<_>>(), vec![banned.ip()]);
+		assert!(!server.unban(quarantined.ip()));
+		assert!(server.unban(banned.ip()));
+		assert!(server.list_bans().is_empty());
+		match server.find_peer(&banned) {
+			PeerLookup::Unknown => {}
+			_ => panic!("unbanned peer still restricted"),
+		}
+	}
+
+	#[test]
+	fn expired_bans_cleaned() {
+		let config = P2PConfig { quarantine_secs: 1, ..P2PConfig::default() };
+		let server = Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new()));
+		let banned: SocketAddr = "10.0.0.13:13414".parse().unwrap();
+		let quarantined: SocketAddr = "10.0.0.14:13414".parse().unwrap();
+		server.ban_peer(banned, Severity::Ban, "test");
+		server.ban_peer(quarantined, Severity::Quarantine, "test");
+		assert!(server.is_banned(&banned) && server.is_banned(&quarantined));
+		// any port of the host is kept away
+		assert!(server.is_banned(&"10.0.0.14:13415".parse().unwrap()));
+
+		assert!(poll_until(|| !server.is_banned(&quarantined)));
+		assert_eq!(server.restrictions().len(), 2);
+		server.clean_peers();
+		assert_eq!(server.restrictions().len(), 1);
+		assert!(server.is_banned(&banned) && !server.is_banned(&quarantined));
+	}
+
+	#[test]
+	fn broadcast_skips_known() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// the first peer sends us the block, so it already has it
+		let mut b = core::Block::default();
+		b.header.height = 1;
+		let block_msg = raw_msg(Type::Block, &b);
+		let sender_addr: SocketAddr = "127.0.0.1:13525".parse().unwrap();
+		let _conns = net.run_thread(move || {
+			let mut sender = raw_handshake(addr, sender_addr);
+			sender.write_all(&block_msg).unwrap();
+			let other = raw_handshake(addr, "127.0.0.1:13526".parse().unwrap());
+			(sender, other)
+		});
+		assert!(net.wait_for(|| received_msgs(&server, sender_addr) == 1));
+
+		let stats = server.broadcast_block(&b);
+		assert_eq!(stats,
+		           BroadcastStats {
+			           sent: 1,
+			           skipped: 1,
+			           failed: 0,
+			           warming_up: 0,
+		           });
+
+		// now both have it
+		let stats = server.broadcast_block(&b);
+		assert_eq!(stats,
+		           BroadcastStats {
+			           sent: 0,
+			           skipped: 2,
+			           failed: 0,
+			           warming_up: 0,
+		           });
+	}
+
+	#[test]
+	fn broadcast_after_warmup() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { broadcast_warmup_ms: 1000, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let _conn = net.run_thread(move || raw_handshake(addr, SocketAddr::new(addr.ip(), 13635)));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		// left out right after connecting, included once warmed up
+		let mut b = core::Block::default();
+		b.header.height = 1;
+		let stats = server.broadcast_block(&b);
+		assert_eq!((stats.sent, stats.warming_up), (0, 1));
+		let tx = core::Transaction::empty();
+		assert_eq!(server.broadcast_transaction(&tx).warming_up, 1);
+
+		let peer = server.connected_peers()[0].clone();
+		assert!(net.wait_for(|| server.warmed_up(&peer)));
+		let stats = server.broadcast_block(&b);
+		assert_eq!((stats.sent, stats.warming_up), (1, 0));
+		assert_eq!(server.broadcast_transaction(&tx).sent, 1);
+	}
+
+	#[test]
+	fn compact_block_fallback() {
+		use rand::os::OsRng;
+		use secp::{self, Secp256k1};
+		use secp::key::SecretKey;
+		use core::core::build::{self, input_rand, output_rand, with_fee};
+
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { features: ALL_FEATURES, ..P2PConfig::default() };
+		let adapter = Arc::new(RecordingAdapter::new());
+		let (server, addr) = net.serve(config, adapter.clone());
+
+		let sender_addr = SocketAddr::new(addr.ip(), 13785);
+		let conn = net.run_thread(move || {
+			let conn = net::TcpStream::connect(addr).unwrap();
+			send_hand_with(conn, test_hand(addr, sender_addr), ALL_FEATURES - CHECKSUMS)
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+		assert!(server.connected_peers()[0].info.features.contains(COMPACT_BLOCKS));
+
+		// a compact block with a transaction our pool doesn't have
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let skey = SecretKey::new(&secp, &mut OsRng::new().unwrap());
+		let mut tx = build::transaction(vec![input_rand(10), output_rand(9), with_fee(1)])
+			.map(|(tx, _)| tx)
+			.unwrap();
+		let b = core::Block::new(&core::BlockHeader::default(), vec![&mut tx], skey).unwrap();
+		let compact = raw_msg(Type::CompactBlock, &Compacted(&b));
+		let full = raw_msg(Type::Block, &b);
+		let hash = b.hash();
+
+		// gets asked for in full, and handed over once received
+		let mut conn = net.run_thread(move || {
+			let mut conn = conn;
+			conn.write_all(&compact).unwrap();
+			loop {
+				let mut header = vec![0; HEADER_LEN as usize];
+				conn.read_exact(&mut header).unwrap();
+				let header = ser::deserialize::<MsgHeader>(&mut &header[..]).unwrap();
+				let mut body = vec![0; header.msg_len as usize];
+				conn.read_exact(&mut body).unwrap();
+				if header.msg_type == Type::GetBlock {
+					assert_eq!(ser::deserialize::<Hash>(&mut &body[..]).unwrap(), hash);
+					return conn;
+				}
+			}
+		});
+		let validated = || adapter.validated.lock().unwrap_or_else(|e| e.into_inner()).clone();
+		assert!(validated().is_empty());
+
+		conn.write_all(&full).unwrap();
+		assert!(net.wait_for(|| validated() == vec![b.header.height]));
+	}
+
+	#[test]
+	fn block_requests_windowed() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { max_block_requests: 2, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let conn = net.run_thread(move || raw_handshake(addr, SocketAddr::new(addr.ip(), 13657)));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		// the peer gets picked until its window is full
+		let blocks = (1..4)
+			.map(|height| {
+				let mut b = core::Block::default();
+				b.header.height = height;
+				b
+			})
+			.collect::<Vec<_>>();
+		for b in &blocks[..2] {
+			let peer = server.random_peer_for_blocks(SERVES_BLOCKS).unwrap();
+			peer.send_block_request(b.hash()).unwrap();
+		}
+		let peer = server.connected_peers()[0].clone();
+		assert_eq!(peer.blocks_in_flight(), 2);
+		assert!(server.random_peer_for_blocks(SERVES_BLOCKS).is_none());
+
+		// answering the first request frees a slot
+		let (mut conn, header, body) = net.run_thread(move || {
+			let mut conn = conn;
+			let mut header = vec![0; HEADER_LEN as usize];
+			conn.read_exact(&mut header).unwrap();
+			let header = ser::deserialize::<MsgHeader>(&mut &header[..]).unwrap();
+			let mut body = vec![0; header.msg_len as usize];
+			conn.read_exact(&mut body).unwrap();
+			(conn, header, body)
+		});
+		assert_eq!(header.msg_type, Type::GetBlock);
+		let h = ser::deserialize::<Hash>(&mut &body[..]).unwrap();
+		let block = blocks.iter().find(|b| b.hash() == h).unwrap();
+
+		let mut reply = ser::ser_vec(block).unwrap();
+		let reply_header = MsgHeader::with_id(magic(), Type::Block, reply.len() as u64, header.id);
+		let mut data = ser::ser_vec(&reply_header).unwrap();
+		data.append(&mut reply);
+		conn.write_all(&data).unwrap();
+		assert!(net.wait_for(|| peer.blocks_in_flight() == 1));
+
+		// unless passed over, like when retrying a request it failed
+		let mut exclude = HashSet::new();
+		exclude.insert(peer.info.addr);
+		assert!(server.random_peer_for_blocks_excluding(SERVES_BLOCKS, &exclude).is_none());
+		let peer = server.random_peer_for_blocks(SERVES_BLOCKS).unwrap();
+		peer.send_block_request(blocks[2].hash()).unwrap();
+		assert!(server.random_peer_for_blocks(SERVES_BLOCKS).is_none());
+	}
+
+	#[test]
+	fn inbound_paused() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		let _first = net.run_thread(move || raw_handshake(addr, SocketAddr::new(addr.ip(), 13661)));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		// closed right away while paused, the connected peer staying
+		server.pause_inbound();
+		let closed = net.run_thread(move || {
+			let mut conn = net::TcpStream::connect(addr).unwrap();
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let _ = conn.write_all(&raw_msg(Type::Hand, &test_hand(addr, addr)));
+			match conn.read(&mut [0; 1]) {
+				Ok(0) => true,
+				Err(ref e) => e.kind() == io::ErrorKind::ConnectionReset,
+				Ok(_) => false,
+			}
+		});
+		assert!(closed);
+		assert_eq!(server.connected_peers().len(), 1);
+
+		server.resume_inbound();
+		let second_addr = SocketAddr::new(addr.ip(), 13662);
+		let _second = net.run_thread(move || raw_handshake(addr, second_addr));
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+	}
+
+	#[test]
+	fn capability_class_limited() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig {
+			inbound_capability_limits: vec![(FULL_NODE, 1)],
+			..P2PConfig::default()
+		};
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a full node takes the only slot of its class, the next one gets
+		// disconnected once the handshake is over
+		fn full_node(addr: SocketAddr, port: u16) -> net::TcpStream {
+			let mut hand = test_hand(addr, SocketAddr::new(addr.ip(), port));
+			hand.capabilities = FULL_NODE;
+			send_hand(net::TcpStream::connect(addr).unwrap(), hand)
+		}
+		let _first = net.run_thread(move || full_node(addr, 13672));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+		let closed = net.run_thread(move || {
+			let mut conn = full_node(addr, 13673);
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			conn.read(&mut [0; 1]).unwrap_or(0) == 0
+		});
+		assert!(closed);
+
+		// peers of another class are still welcome
+		let _other = net.run_thread(move || raw_handshake(addr, SocketAddr::new(addr.ip(), 13674)));
+		assert!(net.wait_for(|| server.connected_peers().len() == 2));
+
+		let mut connected =
+			server.connected_peers().iter().map(|p| p.info.addr.port()).collect::<Vec<_>>();
+		connected.sort();
+		assert_eq!(connected, vec![13672, 13674]);
+		assert_eq!(server.capability_counts(), vec![(FULL_NODE, 1)]);
+	}
+
+	#[test]
+	fn graceful_disconnect_flushes() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		// the peer reads all it gets until we close
+		let sender_addr = SocketAddr::new(addr.ip(), 13676);
+		let client = thread::spawn(move || {
+			let mut conn = raw_handshake(addr, sender_addr);
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let mut received = vec![];
+			loop {
+				let mut header = vec![0; HEADER_LEN as usize];
+				match conn.read_exact(&mut header) {
+					Ok(()) => {}
+					Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+					Err(e) => panic!("connection not closed: {:?}", e),
+				}
+				let header = ser::deserialize::<MsgHeader>(&mut &header[..]).unwrap();
+				conn.read_exact(&mut vec![0; header.msg_len as usize]).unwrap();
+				received.push(header.msg_type);
+			}
+			received
+		});
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		// a block queued right before disconnecting still goes out, nothing
+		// queued after does
+		let mut b = core::Block::default();
+		b.header.height = 1;
+		assert_eq!(server.broadcast_block(&b).sent, 1);
+		assert!(server.disconnect_peer(sender_addr, false));
+		b.header.height = 2;
+		assert_eq!(server.broadcast_block(&b).failed, 1);
+
+		let received = net.run_thread(move || client.join().unwrap());
+		assert_eq!(received, vec![Type::Block]);
+	}
+
+	#[test]
+	fn handshakes_bounded() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig { max_handshakes: 2, ..P2PConfig::default() };
+		let (server, addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// a flood of connections that never send their hand
+		let stalled = (0..5).map(|_| net::TcpStream::connect(addr).unwrap()).collect::<Vec<_>>();
+		assert!(net.wait_for(|| server.handshakes_in_progress() == 2));
+
+		// as they fail, the queued connections get their turn, still two at a time
+		drop(stalled);
+		assert!(net.wait_for(|| {
+			let in_progress = server.handshakes_in_progress();
+			assert!(in_progress <= 2);
+			in_progress == 0
+		}));
+	}
+
+	#[test]
+	fn failed_listener_isolated() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let fds = Arc::new(FdExhaustion::new(Arc::new(RwLock::new(vec![]))));
+		let a1: SocketAddr = "127.0.0.1:13515".parse().unwrap();
+		let a2: SocketAddr = "127.0.0.1:13516".parse().unwrap();
+		let down = io::Error::from_raw_os_error(EBADF);
+		let failing = stream::iter(vec![Ok(1), Err(down), Ok(2)]);
+		let healthy = stream::iter(vec![Ok(10), Ok(11), Ok(12)]);
+
+		let listeners = vec![isolate_listener(a1, failing, fds.clone(), handle.clone()),
+		                     isolate_listener(a2, healthy, fds, handle)];
+		let mut accepted = net.run(merge_listeners(listeners).collect()).unwrap();
+		accepted.sort();
+		assert_eq!(accepted, vec![1, 10, 11, 12]);
+	}
+
+	#[test]
+	fn transient_accept_errors_skipped() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let fds = Arc::new(FdExhaustion::new(Arc::new(RwLock::new(vec![]))));
+		let addr: SocketAddr = "127.0.0.1:13801".parse().unwrap();
+		let aborted = io::Error::new(io::ErrorKind::ConnectionAborted, "aborted");
+		let nobufs = io::Error::new(io::ErrorKind::Other, "no buffer space");
+		let incoming = stream::iter(vec![Ok(1), Err(aborted), Ok(2), Err(nobufs), Ok(3)]);
+
+		// the listener goes on accepting, only pausing on the unknown error
+		let start = Instant::now();
+		let listener = isolate_listener(addr, incoming, fds.clone(), handle);
+		assert_eq!(net.run(listener.collect()).unwrap(), vec![1, 2, 3]);
+		assert!(start.elapsed() >= Duration::from_millis(ACCEPT_ERROR_PAUSE_MS));
+		assert!(fds.available());
+	}
+
+	#[test]
+	fn fd_exhaustion_backs_off() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let fds = Arc::new(FdExhaustion::new(Arc::new(RwLock::new(vec![]))));
+		let addr: SocketAddr = "127.0.0.1:13681".parse().unwrap();
+		let emfile = || io::Error::from_raw_os_error(EMFILE);
+		let down = io::Error::from_raw_os_error(EBADF);
+		let incoming =
+			stream::iter(vec![Err(emfile()), Ok(1), Err(emfile()), Ok(2), Err(down), Ok(3)]);
+
+		// accepting pauses after each exhaustion rather than spinning, and goes on
+		let start = Instant::now();
+		let listener = isolate_listener(addr, incoming, fds.clone(), handle.clone());
+		assert_eq!(net.run(listener.collect()).unwrap(), vec![1, 2]);
+		assert!(start.elapsed() >= Duration::from_millis(2 * FD_ACCEPT_PAUSE_MS));
+		assert!(!fds.available());
+
+		// a server out of file descriptors doesn't even try dialing
+		let server = TestNetwork::dialer(P2PConfig::default(), Arc::new(DummyAdapter {}));
+		server.fds.exhausted("testing");
+		match net.run(server.connect_peer(addr, handle.clone())) {
+			Err(Error::TooManyOpenFiles) => {}
+			_ => panic!("expected the dial to be held off"),
+		}
+		assert_eq!(server.dials_in_progress(), 0);
+
+		// until we're down on peers
+		*fds.since.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), 1));
+		assert!(fds.available());
+		assert!(fds.available());
+	}
+
+	#[test]
+	fn extra_listener_accepts() {
+		let mut net = TestNetwork::empty();
+		let extra = SocketAddr::new("127.0.0.1".parse().unwrap(), free_port());
+		let config = P2PConfig { extra_listeners: vec![extra], ..P2PConfig::default() };
+		let (server, _) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let sender_addr: SocketAddr = "127.0.0.1:13519".parse().unwrap();
+		let _conn = net.run_thread(move || raw_handshake(extra, sender_addr));
+		assert!(net.wait_for(|| server.connected_peers().len() == 1));
+
+		let addrs = server.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+		assert_eq!(addrs, vec![sender_addr]);
+	}
+
+	#[test]
+	fn difficulty_ceiling() {
+		let max = Difficulty::max_with_bits(256);
+		let huge = Difficulty::max_with_bits(255 * 8);
+		let normal = Difficulty::from_num(u32::max_value());
+
+		assert!(huge > max && max > normal);
+		assert_eq!(cap_difficulty(huge.clone(), &max), max);
+		assert_eq!(cap_difficulty(max.clone(), &max), max);
+		assert_eq!(cap_difficulty(normal.clone(), &max), normal);
+
+		// clamped values still order correctly and can be added up
+		let mut diffs = vec![cap_difficulty(huge, &max), normal.clone(), Difficulty::one()];
+		diffs.sort();
+		assert_eq!(diffs, vec![Difficulty::one(), normal.clone(), max.clone()]);
+		assert!(max.clone() + max.clone() > max);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn outbound_bind_addr() {
+		let mut net = TestNetwork::empty();
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let bind_ip: IpAddr = "127.0.0.2".parse().unwrap();
+
+		let addr = listener.local_addr().unwrap();
+		let connect = connect_socket(&addr, Some(bind_ip), &net.handle());
+		let conn = net.run(connect).unwrap();
+		assert_eq!(conn.local_addr().unwrap().ip(), bind_ip);
+		let (_, remote) = listener.accept().unwrap();
+		assert_eq!(remote.ip(), bind_ip);
+	}
+
+	#[test]
+	fn churn_counted() {
+		let mut net = TestNetwork::empty();
+		let (server, addr) = net.serve(P2PConfig::default(), Arc::new(DummyAdapter {}));
+
+		// a peer connecting and disconnecting right away, 3 times over
+		let srv = server.clone();
+		net.run_thread(move || for n in 0..3 {
+			let conn = raw_handshake(addr, "127.0.0.1:13511".parse().unwrap());
+			drop(conn);
+			assert!(poll_until(|| srv.churn() == 2 * (n + 1)));
+		});
+
+		assert_eq!(server.churn(), 6);
+	}
+
+	#[test]
+	fn churn_window() {
+		let start = Instant::now();
+		let mut churn = Churn::new(Duration::from_secs(60), 0);
+		churn.record(start);
+		churn.record(start + Duration::from_secs(30));
+		assert_eq!(churn.count(start + Duration::from_secs(30)), 2);
+		assert_eq!(churn.count(start + Duration::from_secs(61)), 1);
+		assert_eq!(churn.count(start + Duration::from_secs(100)), 0);
+	}
+
+	#[test]
+	fn flaky_peer_quarantined() {
+		let mut net = TestNetwork::empty();
+		let config = P2PConfig {
+			flaky_threshold: 2,
+			flaky_backoff_secs: 10,
+			flaky_quarantine: 4,
+			..P2PConfig::default()
+		};
+		let (server, addr) = net.serve(config, Arc::new(DummyAdapter {}));
+
+		// the peer connects and drops right away, over and over, advertising
+		// the address of another host, each session counted along with churn
+		let flaky: SocketAddr = "127.0.0.8:13628".parse().unwrap();
+		let mut backoffs = vec![];
+		for n in 0..4 {
+			net.run_thread(move || {
+				drop(send_hand(connect_from("127.0.0.7", addr), test_hand(addr, flaky)))
+			});
+			assert!(net.wait_for(|| server.churn() == 2 * (n + 1)));
+			backoffs.push(server.backoff(&flaky));
+		}
+
+		// no backoff until the threshold, then doubling until quarantined
+		assert!(backoffs[0].is_none());
+		assert!(backoffs[1].unwrap() > Duration::from_secs(9));
+		assert!(backoffs[2].unwrap() > Duration::from_secs(19));
+		assert!(backoffs[3].is_none());
+		// the host it connected from is the one quarantined
+		match server.find_peer(&"127.0.0.7:13628".parse().unwrap()) {
+			PeerLookup::Banned { severity, .. } => assert_eq!(severity, Severity::Quarantine),
+			_ => panic!("flaky peer not quarantined"),
+		}
+		assert!(!server.is_banned(&flaky));
+	}
+
+	// Pings the peer as soon as it's due after the provided time, its pong
+	// coming back after the provided delay. Returns when the ping was sent.
+	fn answered_ping(pings: &mut Pings, id: PeerId, after: Instant, rtt: Duration) -> Instant {
+		let due = after + pings.interval(id).unwrap();
+		assert!(!pings.due(id, due - Duration::from_secs(1)));
+		assert!(pings.due(id, due));
+		pings.sent(id, due);
+		assert!(!pings.due(id, due + Duration::from_secs(60)));
+		pings.pong(id, due + rtt);
+		due
+	}
+
+	#[test]
+	fn ping_interval_adapts() {
+		let config = P2PConfig {
+			ping_interval_min_secs: 10,
+			ping_interval_max_secs: 30,
+			ping_slow_ms: 500,
+			..P2PConfig::default()
+		};
+		let mut pings = Pings::new(&config);
+		let id = PeerId(1);
+		let mut now = Instant::now();
+		assert!(!pings.due(id, now));
+		assert_eq!(pings.interval(id), Some(Duration::from_secs(10)));
+
+		// prompt pongs widen the interval once a few came in a row, up to the max
+		let prompt = Duration::from_millis(100);
+		for _ in 0..PING_PROMPT_STREAK {
+			now = answered_ping(&mut pings, id, now, prompt);
+		}
+		assert_eq!(pings.interval(id), Some(Duration::from_secs(20)));
+		for _ in 0..(2 * PING_PROMPT_STREAK) {
+			now = answered_ping(&mut pings, id, now, prompt);
+		}
+		assert_eq!(pings.interval(id), Some(Duration::from_secs(30)));
+
+		// a slow pong narrows it, a missed one takes it back to the min
+		now = answered_ping(&mut pings, id, now, Duration::from_secs(1));
+		assert_eq!(pings.interval(id), Some(Duration::from_secs(15)));
+		pings.sent(id, now);
+		pings.missed(id);
+		assert_eq!(pings.interval(id), Some(Duration::from_secs(10)));
+
+		// misses in a row get the peer deemed unresponsive, until it answers
+		assert_eq!(pings.unresponsive(1), vec![id]);
+		pings.sent(id, now);
+		pings.missed(id);
+		assert_eq!(pings.unresponsive(2), vec![id]);
+		assert!(pings.unresponsive(3).is_empty());
+		answered_ping(&mut pings, id, now, prompt);
+		assert!(pings.unresponsive(1).is_empty());
+
+		pings.keep(&[]);
+		assert_eq!(pings.interval(id), None);
+	}
+
+	#[test]
+	fn pongs_update_chain_status() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { ping_interval_min_secs: 1, ..P2PConfig::default() };
+		let (server, _) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		let adapter = RecordingAdapter {
+			difficulty: Difficulty::from_num(5),
+			height: 7,
+			..RecordingAdapter::new()
+		};
+		let (_peer, addr) = net.serve(P2PConfig::default(), Arc::new(adapter));
+		net.run(server.connect_peer(addr, handle.clone())).unwrap();
+
+		// nothing known of the height of the peer until it answered a ping
+		let p = server.connected_peers().pop().unwrap();
+		assert_eq!(p.height(), None);
+		assert_eq!(p.latency(), None);
+
+		assert!(net.wait_for(|| p.chain_status().is_some()));
+		assert_eq!(p.chain_status(),
+		           Some(ChainStatus {
+			           total_difficulty: Difficulty::from_num(5),
+			           height: 7,
+		           }));
+		assert!(p.latency().is_some());
+		assert_eq!(p.total_difficulty(), Difficulty::from_num(5));
+	}
+
+	// Fails to build unless the type is shared across threads safely.
+	fn shared<T: Send + Sync>() {}
+
+	#[test]
+	fn shared_across_threads() {
+		shared::<Server>();
+		shared::<Peer>();
+		shared::<Handshake>();
+	}
+
+	#[test]
+	fn stop_shared_idempotent() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { port: free_port(), ..P2PConfig::default() };
+		let addr = SocketAddr::new(config.host, config.port);
+		let server = Arc::new(Server::new(UNKNOWN, config, Arc::new(RecordingAdapter::new())));
+		let done = Arc::new(Mutex::new(None));
+		let finished = done.clone();
+		handle.spawn(server.start(handle.clone()).then(move |res| {
+			*finished.lock().unwrap_or_else(|e| e.into_inner()) = Some(res.is_ok());
+			Ok(())
+		}));
+		let done = || *done.lock().unwrap_or_else(|e| e.into_inner());
+
+		let client = thread::spawn(move || {
+			let mut conn = raw_handshake(addr, SocketAddr::new(addr.ip(), 13754));
+			conn.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+			let mut rest = vec![];
+			conn.read_to_end(&mut rest).unwrap();
+		});
+		assert!(net.wait_for(|| server.peer_count() == 1));
+
+		// stopped through shared references, from another thread as well
+		let shared = server.clone();
+		thread::spawn(move || shared.stop()).join().unwrap();
+		server.stop();
+		assert!(net.wait_for(|| done().is_some()));
+		assert_eq!(done(), Some(true));
+		net.run_thread(move || client.join().unwrap());
+		assert!(net.wait_for(|| server.connected_peers().is_empty()));
+		match net.run(server.connect_peer(addr, handle.clone())) {
+			Err(Error::ConnectionClose) => {}
+			res => panic!("dialed once stopped: {:?}", res.map(|_| ())),
+		}
+
+		// stopped before even starting
+		let server = TestNetwork::dialer(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+		server.stop();
+		net.run(server.start(handle.clone())).unwrap();
+	}
+
+	// Relays a single connection to the IPv4 address it asks the SOCKS5 proxy
+	// for, without authentication, returning that address.
+	fn relay_proxy(listener: net::TcpListener) -> thread::JoinHandle<SocketAddr> {
+		thread::spawn(move || {
+			let (mut conn, _) = listener.accept().unwrap();
+			let mut greeting = [0u8; 3];
+			conn.read_exact(&mut greeting).unwrap();
+			conn.write_all(&[5, 0]).unwrap();
+			let mut req = [0u8; 10];
+			conn.read_exact(&mut req).unwrap();
+			let port = (req[8] as u16) << 8 | req[9] as u16;
+			let target = format!("{}.{}.{}.{}:{}", req[4], req[5], req[6], req[7], port)
+				.parse::<SocketAddr>()
+				.unwrap();
+			let mut upstream = net::TcpStream::connect(target).unwrap();
+			conn.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+			let mut conn2 = conn.try_clone().unwrap();
+			let mut upstream2 = upstream.try_clone().unwrap();
+			thread::spawn(move || io::copy(&mut conn2, &mut upstream2));
+			thread::spawn(move || io::copy(&mut upstream, &mut conn));
+			target
+		})
+	}
+
+	#[test]
+	fn connects_through_proxy() {
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let (peer, addr) = net.serve(P2PConfig::default(), Arc::new(RecordingAdapter::new()));
+
+		let proxy = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let proxy_addr = proxy.local_addr().unwrap();
+		let relay = relay_proxy(proxy);
+		let config = P2PConfig {
+			proxy: Some(ProxyConfig {
+				addr: proxy_addr,
+				auth: None,
+				onion: None,
+			}),
+			..P2PConfig::default()
+		};
+		let (server, server_addr) = net.serve(config, Arc::new(RecordingAdapter::new()));
+
+		// the peer is known by the address we asked the proxy for, not the
+		// proxy's
+		let p = net.run(server.connect_peer(addr, handle.clone())).unwrap().unwrap();
+		assert_eq!(relay.join().unwrap(), addr);
+		assert_eq!(p.info.addr, addr);
+		assert_eq!(server.connected_peers().len(), 1);
+
+		// while it's told nothing of where to reach us
+		assert!(net.wait_for(|| peer.connected_peers().len() == 1));
+		let us = peer.connected_peers().pop().unwrap();
+		assert!(!us.info.reachable);
+		assert!(us.info.addr.port() != server_addr.port());
+
+		// onion services can't be reached without a proxy
+		let onion = onion_addr("expyuzz4wqqyqhjn.onion", 13414).unwrap();
+		match net.run(peer.connect_peer(onion, handle.clone())) {
+			Err(Error::NoProxy) => {}
+			res => panic!("onion service dialed without a proxy: {:?}", res.map(|_| ())),
+		}
+	}
+
+	#[test]
+	fn peers_recorded_in_store() {
+		let root = env::temp_dir().join("grin_p2p_server_store");
+		let _ = fs::remove_dir_all(&root);
+		let store = Arc::new(PeerStore::new(root.to_str().unwrap().to_string()).unwrap());
+		let mut net = TestNetwork::empty();
+		let handle = net.handle();
+		let config = P2PConfig { port: free_port(), ..P2PConfig::default() };
+		let addr = SocketAddr::new(config.host, config.port);
+		let server = Server::new(UNKNOWN, config, Arc::new(DummyAdapter {}));
+		let server = Arc::new(server.with_peer_store(store.clone()));
+		handle.spawn(server.start(handle.clone()).map_err(|_| ()));
+
+		// a peer connecting to us, and one known to the store we can't reach
+		let peer: SocketAddr = "127.0.0.1:13787".parse().unwrap();
+		let gone = SocketAddr::new(addr.ip(), free_port());
+		store.save_peer(&PeerData::new(gone, peer)).unwrap();
+		let client = thread::spawn(move || raw_handshake(addr, peer));
+		assert!(net.run(server.connect_peer(gone, handle.clone())).is_err());
+
+		// both get written soon after, off the event loop
+		assert!(net.wait_for(|| {
+			(store.get_peer(peer).map(|p| p.success_count).unwrap_or(0),
+			 store.get_peer(gone).unwrap().failure_count) == (1, 1)
+		}));
+		assert!(server.book.quality(&peer) > 0.0);
+		assert_eq!(server.book.quality(&gone), -1.0);
+		let _conn = client.join().unwrap();
+		drop(server);
+		fs::remove_dir_all(&root).unwrap();
+	}
 }
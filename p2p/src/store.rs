@@ -12,19 +12,39 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! Storage implementation for peer data.
+//! Storage implementation for peer data, along with what we learned of the
+//! peers connecting to them. Can be exported to and merged from JSON, to
+//! carry the learned peers over to another node.
 
-use std::net::SocketAddr;
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use num::FromPrimitive;
+use rand::{self, Rng};
+use serde_json;
+use time;
 
+use bans::BanEntry;
 use core::ser::{self, Readable, Writeable, Reader, Writer};
 use grin_store::{self, Error, to_key, option_to_not_found};
-use msg::SockAddr;
-use types::Capabilities;
+use msg::{SockAddr, read_addr};
+use types::{Capabilities, Severity, UNKNOWN};
 
 const STORE_SUBPATH: &'static str = "peers";
 
 const PEER_PREFIX: u8 = 'p' as u8;
+const BAN_PREFIX: u8 = 'b' as u8;
+
+// Version of the peer records we write. Its byte comes first, where the
+// unversioned records older releases wrote have the kind of their address,
+// 0 or 1.
+const PEER_DATA_VERSION: u8 = 2;
+
+// Seconds since a peer was last seen for recent_peers to still tell others
+// about it.
+const RECENT_PEER_SECS: i64 = 7 * 86400;
 
 /// Types of messages
 enum_from_primitive! {
@@ -47,39 +67,198 @@ pub struct PeerData {
 	pub user_agent: String,
 	/// State the peer has been detected with.
 	pub flags: State,
+	/// Peer that reported this address to us, the peer itself when we
+	/// connected to it directly.
+	pub source: SocketAddr,
+	/// When we last connected to the peer, in seconds since the epoch, zero
+	/// if we never did.
+	pub last_seen: i64,
+	/// How many times we managed to connect to the peer.
+	pub success_count: u32,
+	/// How many times we failed to reach the peer.
+	pub failure_count: u32,
+}
+
+impl PeerData {
+	/// A healthy peer we know nothing about yet, reported by source.
+	pub fn new(addr: SocketAddr, source: SocketAddr) -> PeerData {
+		PeerData {
+			addr: addr,
+			capabilities: UNKNOWN,
+			user_agent: "".to_string(),
+			flags: State::Healthy,
+			source: source,
+			last_seen: 0,
+			success_count: 0,
+			failure_count: 0,
+		}
+	}
+
+	// How good a bet dialing the peer is: positive if we connected to it
+	// before, zero if we never tried and negative if we only ever failed.
+	fn score(&self, now: i64) -> f64 {
+		if self.success_count == 0 {
+			return if self.unreachable() { -1.0 } else { 0.0 };
+		}
+		quality(self.success_count as u64, self.failure_count as u64, self.last_seen, now)
+	}
+
+	// Whether we only ever failed to reach the peer.
+	fn unreachable(&self) -> bool {
+		self.success_count == 0 && self.failure_count > 0
+	}
 }
 
 impl Writeable for PeerData {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u8(PEER_DATA_VERSION)?;
 		SockAddr(self.addr).write(writer)?;
 		ser_multiwrite!(writer,
 		                [write_u32, self.capabilities.bits()],
 		                [write_bytes, &self.user_agent],
 		                [write_u8, self.flags as u8]);
+		SockAddr(self.source).write(writer)?;
+		ser_multiwrite!(writer,
+		                [write_i64, self.last_seen],
+		                [write_u32, self.success_count],
+		                [write_u32, self.failure_count]);
 		Ok(())
 	}
 }
 
+/// Reads the current records as well as the unversioned ones of older
+/// releases, which may end after the state or after the source. What they
+/// lack is taken as never tried, the peer being its own source.
 impl Readable for PeerData {
 	fn read(reader: &mut Reader) -> Result<PeerData, ser::Error> {
-		let addr = SockAddr::read(reader)?;
+		let version = reader.read_u8()?;
+		let addr = match version {
+			PEER_DATA_VERSION => SockAddr::read(reader)?,
+			0 | 1 => read_addr(reader, version)?,
+			_ => return Err(ser::Error::CorruptedData),
+		};
 		let (capab, ua, fl) = ser_multiread!(reader, read_u32, read_vec, read_u8);
 		let user_agent = String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData)?;
 		let capabilities = Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData)?;
-		match State::from_u8(fl) {
-			Some(flags) => {
-				Ok(PeerData {
-					addr: addr.0,
-					capabilities: capabilities,
-					user_agent: user_agent,
-					flags: flags,
-				})
+		let flags = State::from_u8(fl).ok_or(ser::Error::CorruptedData)?;
+		let mut peer = PeerData::new(addr.0, addr.0);
+		peer.capabilities = capabilities;
+		peer.user_agent = user_agent;
+		peer.flags = flags;
+		if version != PEER_DATA_VERSION {
+			match legacy_field(SockAddr::read(reader))? {
+				Some(source) => peer.source = source.0,
+				None => return Ok(peer),
 			}
-			None => Err(ser::Error::CorruptedData),
+			match legacy_field(reader.read_i64())? {
+				Some(last_seen) => peer.last_seen = last_seen,
+				None => return Ok(peer),
+			}
+		} else {
+			peer.source = SockAddr::read(reader)?.0;
+			peer.last_seen = reader.read_i64()?;
+		}
+		let (successes, failures) = ser_multiread!(reader, read_u32, read_u32);
+		peer.success_count = successes;
+		peer.failure_count = failures;
+		Ok(peer)
+	}
+}
+
+// A field of an unversioned record, none if the record ends before it.
+fn legacy_field<T>(res: Result<T, ser::Error>) -> Result<Option<T>, ser::Error> {
+	match res {
+		Ok(field) => Ok(Some(field)),
+		Err(ser::Error::IOErr(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+// What gets exported of a peer, to seed another node with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ExportedPeer {
+	addr: SocketAddr,
+	last_seen: i64,
+	success_count: u32,
+	#[serde(default)]
+	failure_count: u32,
+}
+
+/// A ban or quarantine of a host, saved to restore it on restart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanData {
+	/// Host kept away.
+	pub ip: IpAddr,
+	/// Whether the host is banned or only quarantined.
+	pub severity: Severity,
+	/// Why the host got restricted.
+	pub reason: String,
+	/// When the restriction gets lifted, in seconds since the epoch, zero
+	/// if only lifted by hand.
+	pub until: i64,
+}
+
+impl BanData {
+	/// The ban or quarantine to save for a restriction, given the current
+	/// time in seconds since the epoch.
+	pub fn new(ban: &BanEntry, now: i64) -> BanData {
+		BanData {
+			ip: ban.ip,
+			severity: ban.severity,
+			reason: ban.reason.clone(),
+			until: ban.expires_in.map(|d| now + d.as_secs() as i64 + 1).unwrap_or(0),
+		}
+	}
+
+	/// Whether the restriction is over at the provided time.
+	pub fn expired(&self, now: i64) -> bool {
+		self.until > 0 && self.until <= now
+	}
+
+	/// How long until the restriction gets lifted, never if none.
+	pub fn expires_in(&self, now: i64) -> Option<Duration> {
+		if self.until > 0 {
+			Some(Duration::from_secs(cmp::max(self.until - now, 0) as u64))
+		} else {
+			None
 		}
 	}
 }
 
+impl Writeable for BanData {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		SockAddr(SocketAddr::new(self.ip, 0)).write(writer)?;
+		let severity = match self.severity {
+			Severity::Ban => 0,
+			Severity::Quarantine => 1,
+		};
+		ser_multiwrite!(writer,
+		                [write_u8, severity],
+		                [write_bytes, &self.reason],
+		                [write_i64, self.until]);
+		Ok(())
+	}
+}
+
+impl Readable for BanData {
+	fn read(reader: &mut Reader) -> Result<BanData, ser::Error> {
+		let addr = SockAddr::read(reader)?;
+		let (severity, reason, until) = ser_multiread!(reader, read_u8, read_vec, read_i64);
+		let reason = String::from_utf8(reason).map_err(|_| ser::Error::CorruptedData)?;
+		let severity = match severity {
+			0 => Severity::Ban,
+			1 => Severity::Quarantine,
+			_ => return Err(ser::Error::CorruptedData),
+		};
+		Ok(BanData {
+			ip: addr.0.ip(),
+			severity: severity,
+			reason: reason,
+			until: until,
+		})
+	}
+}
+
 /// Storage facility for peer data.
 pub struct PeerStore {
 	db: grin_store::Store,
@@ -97,7 +276,7 @@ impl PeerStore {
 		                p)
 	}
 
-	fn get_peer(&self, peer_addr: SocketAddr) -> Result<PeerData, Error> {
+	pub fn get_peer(&self, peer_addr: SocketAddr) -> Result<PeerData, Error> {
 		option_to_not_found(self.db.get_ser(&peer_key(peer_addr)[..]))
 	}
 
@@ -124,6 +303,175 @@ impl PeerStore {
 		peers
 	}
 
+	/// Up to count peers with the provided state and capabilities to dial
+	/// first: those we connected to reliably, often and lately, then those
+	/// we never tried in random order. Peers we only ever failed to reach
+	/// come last.
+	pub fn best_peers(&self, state: State, cap: Capabilities, count: usize) -> Vec<PeerData> {
+		let mut peers = self.db
+			.iter::<PeerData>(&to_key(PEER_PREFIX, &mut "".to_string().into_bytes()))
+			.filter(|p| p.flags == state && p.capabilities.contains(cap))
+			.collect::<Vec<_>>();
+		shuffle_and_rank(&mut peers);
+		peers.truncate(count);
+		peers
+	}
+
+	/// Selects up to count peers with the provided state and capabilities as
+	/// dial candidates. The selection is spread across the subnets of the
+	/// peers that reported the addresses and across those peers, so a single
+	/// source can't fill our connections with nodes of its choosing even if
+	/// it sent us most of our addresses. The best peers of each source get
+	/// picked first, those scoring the same in random order.
+	pub fn select_peers(&self, state: State, cap: Capabilities, count: usize) -> Vec<PeerData> {
+		let mut peers = self.db
+			.iter::<PeerData>(&to_key(PEER_PREFIX, &mut "".to_string().into_bytes()))
+			.filter(|p| p.flags == state && p.capabilities.contains(cap))
+			.collect::<Vec<_>>();
+		shuffle_and_rank(&mut peers);
+		spread_by_source(peers, count)
+	}
+
+	/// Records a successful connection to the peer at the provided address,
+	/// with what it told us of itself in the handshake. A peer new to the
+	/// store is its own source. A banned peer stays banned, getting through
+	/// again doesn't lift it.
+	pub fn record_connected(&self,
+	                        peer_addr: SocketAddr,
+	                        capabilities: Capabilities,
+	                        user_agent: &str)
+	                        -> Result<(), Error> {
+		let mut peer = match self.get_peer(peer_addr) {
+			Ok(peer) => peer,
+			Err(Error::NotFoundErr) => PeerData::new(peer_addr, peer_addr),
+			Err(e) => return Err(e),
+		};
+		peer.capabilities = capabilities;
+		peer.user_agent = user_agent.to_string();
+		if peer.flags == State::Dead {
+			peer.flags = State::Healthy;
+		}
+		peer.last_seen = time::now_utc().to_timespec().sec;
+		peer.success_count = peer.success_count.saturating_add(1);
+		self.save_peer(&peer)
+	}
+
+	/// Records the peer at the provided address as seen just now, typically as
+	/// we get disconnected from it. Addresses the store doesn't know are left
+	/// out of it.
+	pub fn record_seen(&self, peer_addr: SocketAddr) -> Result<(), Error> {
+		let mut peer = match self.get_peer(peer_addr) {
+			Ok(peer) => peer,
+			Err(Error::NotFoundErr) => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		peer.last_seen = time::now_utc().to_timespec().sec;
+		self.save_peer(&peer)
+	}
+
+	/// Records a failed attempt at reaching the peer at the provided address.
+	/// Addresses the store doesn't know are left out of it.
+	pub fn record_failure(&self, peer_addr: SocketAddr) -> Result<(), Error> {
+		let mut peer = match self.get_peer(peer_addr) {
+			Ok(peer) => peer,
+			Err(Error::NotFoundErr) => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		peer.failure_count = peer.failure_count.saturating_add(1);
+		self.save_peer(&peer)
+	}
+
+	/// How good a bet dialing the peer at the provided address is, as
+	/// best_peers ranks it: positive if we connected to it before, zero if we
+	/// know nothing about it and negative if we only ever failed to reach it.
+	pub fn peer_quality(&self, peer_addr: &SocketAddr) -> f64 {
+		match self.get_peer(*peer_addr) {
+			Ok(peer) => peer.score(time::now_utc().to_timespec().sec),
+			Err(_) => 0.0,
+		}
+	}
+
+	/// Up to count peers with the provided state and capabilities to tell
+	/// others about, among those we saw in the last RECENT_PEER_SECS, most
+	/// recently seen first. Picked at random among those so repeated
+	/// responses differ and don't reveal our whole book.
+	pub fn recent_peers(&self, state: State, cap: Capabilities, count: usize) -> Vec<PeerData> {
+		let since = time::now_utc().to_timespec().sec - RECENT_PEER_SECS;
+		let recent = self.db
+			.iter::<PeerData>(&to_key(PEER_PREFIX, &mut "".to_string().into_bytes()))
+			.filter(|p| p.flags == state && p.capabilities.contains(cap))
+			.filter(|p| p.last_seen >= since && !p.unreachable());
+		let mut peers = sample(recent, count);
+		peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+		peers
+	}
+
+	/// All the peers we managed to connect to as a JSON array of their
+	/// address, when they were last seen and how many times we connected and
+	/// failed to, most recently seen first.
+	pub fn export_peers(&self) -> String {
+		let mut peers = self.db
+			.iter::<PeerData>(&to_key(PEER_PREFIX, &mut "".to_string().into_bytes()))
+			.filter(|p| p.success_count > 0)
+			.map(|p| {
+				ExportedPeer {
+					addr: p.addr,
+					last_seen: p.last_seen,
+					success_count: p.success_count,
+					failure_count: p.failure_count,
+				}
+			})
+			.collect::<Vec<_>>();
+		peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+		serde_json::to_string(&peers).unwrap()
+	}
+
+	/// Merges peers from a JSON array like export_peers produces. Peers we
+	/// don't know yet get added, as their own source, and those we last saw
+	/// longer ago than the export did get refreshed, keeping our counts if
+	/// higher. Addresses the provided filter rejects are skipped. Returns
+	/// how many peers got added or refreshed.
+	pub fn import_peers<F>(&self, json: &str, accept: F) -> Result<usize, Error>
+		where F: Fn(&SocketAddr) -> bool
+	{
+		let imported: Vec<ExportedPeer> = serde_json::from_str(json).map_err(|e| {
+				debug!("Invalid peer import: {}", e);
+				Error::SerErr(ser::Error::CorruptedData)
+			})?;
+		let mut merged = 0;
+		for e in imported.into_iter().filter(|e| accept(&e.addr)) {
+			let mut peer = match self.get_peer(e.addr) {
+				Ok(ref peer) if peer.last_seen >= e.last_seen => continue,
+				Ok(peer) => peer,
+				Err(Error::NotFoundErr) => PeerData::new(e.addr, e.addr),
+				Err(err) => return Err(err),
+			};
+			peer.last_seen = e.last_seen;
+			peer.success_count = cmp::max(peer.success_count, e.success_count);
+			peer.failure_count = cmp::max(peer.failure_count, e.failure_count);
+			self.save_peer(&peer)?;
+			merged += 1;
+		}
+		Ok(merged)
+	}
+
+	/// Saves a ban or quarantine, replacing the one the host had if any.
+	pub fn save_ban(&self, b: &BanData) -> Result<(), Error> {
+		self.db.put_ser(&ban_key(b.ip)[..], b)
+	}
+
+	/// Deletes the saved ban or quarantine of the provided host.
+	pub fn delete_ban(&self, ip: IpAddr) -> Result<(), Error> {
+		self.db.delete(&ban_key(ip)[..])
+	}
+
+	/// All the saved bans and quarantines, including those that expired
+	/// since.
+	pub fn all_bans(&self) -> Vec<BanData> {
+		let prefix = to_key(BAN_PREFIX, &mut vec![]);
+		self.db.iter_prefix::<BanData>(&prefix[..], &prefix[..]).collect()
+	}
+
 	/// Convenience method to load a peer data, update its status and save it
 	/// back.
 	pub fn update_state(&self, peer_addr: SocketAddr, new_state: State) -> Result<(), Error> {
@@ -136,3 +484,433 @@ impl PeerStore {
 fn peer_key(peer_addr: SocketAddr) -> Vec<u8> {
 	to_key(PEER_PREFIX, &mut format!("{}", peer_addr).into_bytes())
 }
+
+fn ban_key(ip: IpAddr) -> Vec<u8> {
+	to_key(BAN_PREFIX, &mut format!("{}", ip).into_bytes())
+}
+
+// Sorts peers from the best bet to dial to the worst, peers scoring the same
+// keeping their order.
+fn rank_by_quality(peers: &mut Vec<PeerData>) {
+	let now = time::now_utc().to_timespec().sec;
+	peers.sort_by(|a, b| {
+		b.score(now).partial_cmp(&a.score(now)).unwrap_or(cmp::Ordering::Equal)
+	});
+}
+
+/// How good a bet a peer is given how many times we connected to it and
+/// failed to reach it, and when we last saw it. Needs at least one success.
+/// The share of successful attempts weighs the number of connections, the
+/// whole fading with the days since it was last seen.
+pub fn quality(successes: u64, failures: u64, last_seen: i64, now: i64) -> f64 {
+	let successes = successes as f64;
+	let reliability = successes / (successes + failures as f64);
+	let age_days = cmp::max(0, now - last_seen) as f64 / 86400.0;
+	reliability * successes.ln_1p() / (1.0 + age_days)
+}
+
+// Sorts peers as rank_by_quality does, those scoring the same in random order
+// rather than in the order the store iterates them.
+fn shuffle_and_rank(peers: &mut Vec<PeerData>) {
+	rand::thread_rng().shuffle(&mut peers[..]);
+	rank_by_quality(peers);
+}
+
+// Buckets peers by the subnet of their source and then by source, picking
+// up to count of them round-robin across buckets.
+fn spread_by_source(peers: Vec<PeerData>, count: usize) -> Vec<PeerData> {
+	let mut buckets: HashMap<Vec<u8>, HashMap<IpAddr, Vec<PeerData>>> = HashMap::new();
+	for p in peers {
+		let src = p.source.ip();
+		buckets.entry(subnet(&src))
+			.or_insert_with(HashMap::new)
+			.entry(src)
+			.or_insert_with(Vec::new)
+			.push(p);
+	}
+	let by_subnet = buckets.into_iter()
+		.map(|(_, sources)| interleave(sources.into_iter().map(|(_, ps)| ps).collect()))
+		.collect();
+	let mut selected = interleave(by_subnet);
+	selected.truncate(count);
+	selected
+}
+
+// Uniform random sample of up to count elements, making a single pass.
+fn sample<T, I>(items: I, count: usize) -> Vec<T>
+	where I: Iterator<Item = T>
+{
+	let mut rng = rand::thread_rng();
+	let mut sampled = Vec::with_capacity(count);
+	for (n, item) in items.enumerate() {
+		if n < count {
+			sampled.push(item);
+		} else {
+			let idx = rng.gen_range(0, n + 1);
+			if idx < count {
+				sampled[idx] = item;
+			}
+		}
+	}
+	sampled
+}
+
+/// Whether a peer address received from gossip is worth keeping: it must
+/// have a port and an IP we could dial, from a private range only if allowed.
+pub fn valid_peer_addr(addr: &SocketAddr, allow_private: bool) -> bool {
+	if addr.port() == 0 {
+		return false;
+	}
+	let dialable = match addr.ip() {
+		IpAddr::V4(ip) => !ip.is_unspecified() && !ip.is_multicast() && !ip.is_broadcast(),
+		IpAddr::V6(ip) => !ip.is_unspecified() && !ip.is_multicast(),
+	};
+	dialable && (allow_private || !private_ip(&addr.ip()))
+}
+
+/// Whether an IP is only reachable on a local network: loopback, private and
+/// link-local IPv4 ranges, and their IPv6 counterparts, unique local fc00::/7
+/// and link-local fe80::/10. IPv4 addresses mapped in IPv6 are checked as
+/// IPv4.
+pub fn private_ip(ip: &IpAddr) -> bool {
+	match *ip {
+		IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+		IpAddr::V6(ip) => {
+			let segments = ip.segments();
+			if segments[0..6] == [0, 0, 0, 0, 0, 0xffff] {
+				if let Some(ip) = ip.to_ipv4() {
+					return private_ip(&IpAddr::V4(ip));
+				}
+			}
+			ip.is_loopback() || segments[0] & 0xfe00 == 0xfc00 || segments[0] & 0xffc0 == 0xfe80
+		}
+	}
+}
+
+// Subnet an address belongs to, /16 for IPv4 and /32 for IPv6.
+pub fn subnet(ip: &IpAddr) -> Vec<u8> {
+	match *ip {
+		IpAddr::V4(ip) => ip.octets()[0..2].to_vec(),
+		IpAddr::V6(ip) => ip.octets()[0..4].to_vec(),
+	}
+}
+
+// Merges lists by taking one element of each in turn.
+fn interleave<T>(lists: Vec<Vec<T>>) -> Vec<T> {
+	let mut iters = lists.into_iter().map(|l| l.into_iter()).collect::<Vec<_>>();
+	let mut merged = vec![];
+	loop {
+		let len = merged.len();
+		for it in iters.iter_mut() {
+			if let Some(x) = it.next() {
+				merged.push(x);
+			}
+		}
+		if merged.len() == len {
+			return merged;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::env;
+	use std::fs;
+	use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+	use std::path::PathBuf;
+
+	use super::*;
+
+	fn peer(addr: &str, source: &str) -> PeerData {
+		PeerData::new(addr.parse().unwrap(), source.parse().unwrap())
+	}
+
+	fn tried(addr: &str, successes: u32, failures: u32, age_secs: i64) -> PeerData {
+		let mut p = peer(addr, addr);
+		p.success_count = successes;
+		p.failure_count = failures;
+		if successes > 0 {
+			p.last_seen = time::now_utc().to_timespec().sec - age_secs;
+		}
+		p
+	}
+
+	#[test]
+	fn ranked_by_quality() {
+		let mut peers = vec![tried("20.0.0.1:13414", 0, 3, 0),
+		                     tried("20.0.0.2:13414", 0, 0, 0),
+		                     tried("20.0.0.3:13414", 1, 0, 30 * 86400),
+		                     tried("20.0.0.4:13414", 3, 6, 60),
+		                     tried("20.0.0.5:13414", 3, 0, 60),
+		                     tried("20.0.0.6:13414", 0, 0, 0)];
+		rank_by_quality(&mut peers);
+		let ips = peers.iter().map(|p| p.addr.ip().to_string()).collect::<Vec<_>>();
+		assert_eq!(ips,
+		           vec!["20.0.0.5", "20.0.0.4", "20.0.0.3", "20.0.0.2", "20.0.0.6", "20.0.0.1"]);
+	}
+
+	#[test]
+	fn ties_shuffled() {
+		let book = || {
+			let mut peers = (0..100)
+				.map(|n| peer(&format!("20.0.{}.1:13414", n), "10.0.0.2:13414"))
+				.collect::<Vec<_>>();
+			peers.push(tried("30.0.0.1:13414", 1, 0, 60));
+			peers
+		};
+		let best: SocketAddr = "30.0.0.1:13414".parse().unwrap();
+		let (mut peers, mut again) = (book(), book());
+		shuffle_and_rank(&mut peers);
+		shuffle_and_rank(&mut again);
+		assert_eq!((peers[0].addr, again[0].addr), (best, best));
+
+		// the odds of 100 untried peers coming out in the same order are nil
+		let order = |ps: &Vec<PeerData>| ps.iter().map(|p| p.addr).collect::<Vec<_>>();
+		assert!(order(&peers) != order(&again));
+		let selected = spread_by_source(peers, 10);
+		assert!(selected.iter().any(|p| p.addr == best));
+	}
+
+	#[test]
+	fn peer_data_round_trip() {
+		let mut p = tried("20.0.0.1:13414", 4, 2, 60);
+		p.source = "10.0.0.2:13414".parse().unwrap();
+		let data = ser::ser_vec(&p).unwrap();
+		let read: PeerData = ser::deserialize(&mut &data[..]).unwrap();
+		assert_eq!((read.addr, read.source), (p.addr, p.source));
+		assert_eq!((read.last_seen, read.success_count, read.failure_count),
+		           (p.last_seen, 4, 2));
+	}
+
+	#[test]
+	fn unversioned_peer_data_read() {
+		// as the first releases wrote it
+		let addr: SocketAddr = "20.0.0.1:13414".parse().unwrap();
+		let mut data = ser::ser_vec(&SockAddr(addr)).unwrap();
+		data.extend(ser::ser_vec(&1u32).unwrap());
+		data.extend(ser::ser_vec(&4u64).unwrap());
+		data.extend_from_slice(b"grin");
+		data.push(State::Banned as u8);
+		let read: PeerData = ser::deserialize(&mut &data[..]).unwrap();
+		assert_eq!(read.addr, addr);
+		assert_eq!(read.source, read.addr);
+		assert_eq!((read.capabilities.bits(), read.user_agent.as_str()), (1, "grin"));
+		assert_eq!(read.flags, State::Banned);
+		assert_eq!((read.last_seen, read.success_count, read.failure_count), (0, 0, 0));
+
+		// then with the source of the address
+		data.extend(ser::ser_vec(&SockAddr("10.0.0.2:13414".parse().unwrap())).unwrap());
+		let read: PeerData = ser::deserialize(&mut &data[..]).unwrap();
+		assert_eq!(read.source.to_string(), "10.0.0.2:13414");
+		assert_eq!(read.success_count, 0);
+
+		// but not of a version we don't know
+		assert!(ser::deserialize::<PeerData>(&mut &[3u8][..]).is_err());
+	}
+
+	// A store of its own for the test, under a fresh directory to remove once
+	// done.
+	fn test_store(name: &str) -> (PeerStore, PathBuf) {
+		let root = env::temp_dir().join(format!("grin_p2p_store_{}", name));
+		let _ = fs::remove_dir_all(&root);
+		(PeerStore::new(root.to_str().unwrap().to_string()).unwrap(), root)
+	}
+
+	#[test]
+	fn connecting_keeps_ban() {
+		let (store, root) = test_store("ban");
+		let addr: SocketAddr = "20.0.0.1:13414".parse().unwrap();
+		let mut banned = peer("20.0.0.1:13414", "10.0.0.2:13414");
+		banned.flags = State::Banned;
+		store.save_peer(&banned).unwrap();
+		store.record_connected(addr, UNKNOWN, "grin").unwrap();
+		let read = store.get_peer(addr).unwrap();
+		assert_eq!((read.flags, read.success_count), (State::Banned, 1));
+
+		// a dead one is back though
+		store.update_state(addr, State::Dead).unwrap();
+		store.record_connected(addr, UNKNOWN, "grin").unwrap();
+		assert_eq!(store.get_peer(addr).unwrap().flags, State::Healthy);
+		drop(store);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn export_import_round_trip() {
+		let (store, root) = test_store("export");
+		let first: SocketAddr = "20.0.0.1:13414".parse().unwrap();
+		let second: SocketAddr = "20.0.0.2:13414".parse().unwrap();
+		store.record_connected(first, UNKNOWN, "grin").unwrap();
+		store.record_connected(first, UNKNOWN, "grin").unwrap();
+		store.record_connected(second, UNKNOWN, "grin").unwrap();
+		store.record_failure(second).unwrap();
+		// never reached, so not worth passing on
+		store.save_peer(&peer("20.0.0.3:13414", "10.0.0.2:13414")).unwrap();
+		let json = store.export_peers();
+
+		let (copy, copy_root) = test_store("import");
+		assert_eq!(copy.import_peers(&json, |_| true).unwrap(), 2);
+		for addr in vec![first, second] {
+			let (orig, read) = (store.get_peer(addr).unwrap(), copy.get_peer(addr).unwrap());
+			assert_eq!((read.last_seen, read.success_count, read.failure_count),
+			           (orig.last_seen, orig.success_count, orig.failure_count));
+		}
+		assert_eq!(copy.get_peer(first).unwrap().success_count, 2);
+		assert!(!copy.exists_peer("20.0.0.3:13414".parse().unwrap()).unwrap());
+
+		// importing the same again changes nothing
+		assert_eq!(copy.import_peers(&json, |_| true).unwrap(), 0);
+		assert!(copy.import_peers("not json", |_| true).is_err());
+		drop((store, copy));
+		fs::remove_dir_all(&root).unwrap();
+		fs::remove_dir_all(&copy_root).unwrap();
+	}
+
+	#[test]
+	fn import_keeps_fresher_local() {
+		let (store, root) = test_store("merge");
+		let addr = |n: u8| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(20, 0, 0, n)), 13414);
+		store.record_connected(addr(1), UNKNOWN, "grin").unwrap();
+		for _ in 0..3 {
+			store.record_connected(addr(2), UNKNOWN, "grin").unwrap();
+		}
+		let local = store.get_peer(addr(1)).unwrap().last_seen;
+		let fresh = local + 100;
+
+		let json = format!("[{{\"addr\":\"{}\",\"last_seen\":{},\"success_count\":1}},\
+		                    {{\"addr\":\"{}\",\"last_seen\":{},\"success_count\":7}},\
+		                    {{\"addr\":\"{}\",\"last_seen\":{},\"success_count\":1}},\
+		                    {{\"addr\":\"{}\",\"last_seen\":{},\"success_count\":1}}]",
+		                   addr(1),
+		                   local - 100,
+		                   addr(2),
+		                   fresh,
+		                   addr(3),
+		                   fresh,
+		                   addr(4),
+		                   fresh);
+		let merged = store.import_peers(&json, |a| a.ip() != addr(4).ip()).unwrap();
+		assert_eq!(merged, 2);
+
+		// our fresher peer stays, the older one gets refreshed keeping the
+		// higher count, the new one is added and the filtered one skipped
+		let first = store.get_peer(addr(1)).unwrap();
+		assert_eq!((first.last_seen, first.success_count), (local, 1));
+		let second = store.get_peer(addr(2)).unwrap();
+		assert_eq!((second.last_seen, second.success_count), (fresh, 7));
+		assert_eq!(store.get_peer(addr(3)).unwrap().source, addr(3));
+		assert!(!store.exists_peer(addr(4)).unwrap());
+		drop(store);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn recent_peers_skip_unreachable() {
+		let (store, root) = test_store("recent");
+		let now = time::now_utc().to_timespec().sec;
+		let mut peers = vec![tried("20.0.0.1:13414", 1, 0, 0),
+		                     tried("20.0.0.2:13414", 1, 1, 3600),
+		                     tried("20.0.0.3:13414", 0, 2, 0),
+		                     tried("20.0.0.4:13414", 0, 0, 0),
+		                     tried("20.0.0.5:13414", 3, 0, RECENT_PEER_SECS + 3600)];
+		peers[0].last_seen = now;
+		for p in &peers {
+			store.save_peer(p).unwrap();
+		}
+		let ips = |ps: Vec<PeerData>| {
+			ps.iter().map(|p| p.addr.ip().to_string()).collect::<Vec<_>>()
+		};
+		// only those seen lately, not those never tried or gone for too long
+		assert_eq!(ips(store.recent_peers(State::Healthy, UNKNOWN, 10)),
+		           vec!["20.0.0.1", "20.0.0.2"]);
+		assert_eq!(store.recent_peers(State::Healthy, UNKNOWN, 1).len(), 1);
+
+		// and those rank as the ones dialed first would, by quality
+		assert!(store.peer_quality(&peers[0].addr) > store.peer_quality(&peers[1].addr));
+		assert_eq!(store.peer_quality(&peers[3].addr), 0.0);
+		assert_eq!(store.peer_quality(&peers[2].addr), -1.0);
+		drop(store);
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn ban_data_round_trip() {
+		let ban = BanEntry {
+			ip: "20.0.0.1".parse().unwrap(),
+			severity: Severity::Quarantine,
+			reason: "test".to_string(),
+			since: Duration::from_secs(10),
+			expires_in: Some(Duration::from_secs(50)),
+		};
+		let b = BanData::new(&ban, 1000);
+		let data = ser::ser_vec(&b).unwrap();
+		let read: BanData = ser::deserialize(&mut &data[..]).unwrap();
+		assert_eq!(read, b);
+		assert!(!read.expired(1050));
+		assert!(read.expired(1051));
+		assert_eq!(read.expires_in(1031), Some(Duration::from_secs(20)));
+
+		let lasting = BanData::new(&BanEntry { expires_in: None, ..ban.clone() }, 1000);
+		assert!(!lasting.expired(1_000_000));
+		assert_eq!(lasting.expires_in(1000), None);
+	}
+
+	#[test]
+	fn selection_spread_across_sources() {
+		// a single source flooding us with addresses, along with a couple of
+		// honest ones, one of them in the same subnet as the flooder
+		let flooder: SocketAddr = "10.0.0.1:13414".parse().unwrap();
+		let mut peers = (0..100)
+			.map(|n| peer(&format!("10.1.{}.1:13414", n), "10.0.0.1:13414"))
+			.collect::<Vec<_>>();
+		peers.push(peer("20.0.0.1:13414", "10.0.0.2:13414"));
+		peers.push(peer("20.0.0.2:13414", "10.0.0.2:13414"));
+		peers.push(peer("30.0.0.1:13414", "172.16.0.1:13414"));
+		peers.push(peer("30.0.0.2:13414", "172.16.0.1:13414"));
+
+		let selected = spread_by_source(peers, 6);
+		assert_eq!(selected.len(), 6);
+		let flooded = selected.iter().filter(|p| p.source == flooder).count();
+		assert!(flooded <= 2, "{} of 6 picked from a single source", flooded);
+	}
+
+	#[test]
+	fn sample_bounded_and_varied() {
+		let book = (0..1000).collect::<Vec<u32>>();
+		let first = sample(book.clone().into_iter(), 200);
+		assert_eq!(first.len(), 200);
+		assert_eq!(sample(book.clone().into_iter().take(10), 200).len(), 10);
+
+		// the odds of drawing the same 200 out of 1000 twice are negligible
+		let second = sample(book.into_iter(), 200);
+		assert!(first != second);
+	}
+
+	#[test]
+	fn gossiped_addrs_validated() {
+		let addrs = ["20.0.0.1:13414",
+		             "[2001:db8::1]:13414",
+		             "192.168.0.1:13414",
+		             "[fd12:3456::1]:13414",
+		             "[fe80::1]:13414",
+		             "[::ffff:10.0.0.1]:13414",
+		             "20.0.0.1:0",
+		             "0.0.0.0:13414",
+		             "224.0.0.1:13414",
+		             "255.255.255.255:13414",
+		             "[::]:13414",
+		             "[ff02::1]:13414"];
+		let addrs = addrs.iter().map(|a| a.parse().unwrap()).collect::<Vec<SocketAddr>>();
+
+		let kept = addrs.iter().filter(|a| valid_peer_addr(a, true)).count();
+		assert_eq!(kept, 6);
+		let public = addrs.iter().filter(|a| valid_peer_addr(a, false)).cloned();
+		assert_eq!(public.collect::<Vec<_>>(), addrs[0..2].to_vec());
+	}
+
+	#[test]
+	fn selection_bounded() {
+		let peers = vec![peer("20.0.0.1:13414", "10.0.0.2:13414")];
+		assert_eq!(spread_by_source(peers, 6).len(), 1);
+	}
+}
@@ -0,0 +1,442 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Byte streams our peers talk over: a plain TCP socket, or one wrapped in
+//! TLS when configured so the traffic between nodes gets encrypted, both
+//! ends presenting a certificate the other has to trust. Connecting to a
+//! peer for the first time, the handshake runs in plaintext, both sides
+//! advertising whether they can encrypt, and the connection gets secured
+//! right after it when both can. Once we know a peer encrypts, our next
+//! connections to it get secured before the handshake, so nobody in the
+//! middle can clear what it advertises and keep us in plaintext.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Future};
+use native_tls::{self, Certificate, Pkcs12, TlsAcceptor, TlsConnector};
+use native_tls::backend::openssl::{TlsAcceptorBuilderExt, TlsStreamExt};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_PEER};
+use openssl::x509::X509;
+use tokio_core::io::Io;
+use tokio_core::net::TcpStream;
+use tokio_tls::{self, TlsAcceptorExt, TlsConnectorExt};
+
+use handshake::HandshakeStream;
+use types::{Capabilities, Error, TlsConfig, ENCRYPTED};
+
+// First byte of a TLS record carrying a handshake, telling a peer starts
+// with TLS rather than with our own handshake.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+// How many peers we remember having secured our connections to, and for how
+// long since the last time.
+const MAX_SECURED_PEERS: usize = 10000;
+const SECURED_PEER_SECS: u64 = 30 * 24 * 3600;
+
+/// A byte stream to a peer over its TCP socket. Each stream polls its own
+/// readiness, a TLS one possibly holding decrypted bytes while its socket
+/// has nothing left to read.
+pub trait PeerIo: Io {
+	/// The underlying socket.
+	fn socket(&self) -> &TcpStream;
+
+	/// Whether the stream runs over TLS.
+	fn is_tls(&self) -> bool;
+}
+
+impl PeerIo for TcpStream {
+	fn socket(&self) -> &TcpStream {
+		self
+	}
+
+	fn is_tls(&self) -> bool {
+		false
+	}
+}
+
+impl PeerIo for tokio_tls::TlsStream<PeerStream> {
+	fn socket(&self) -> &TcpStream {
+		self.get_ref().get_ref().socket()
+	}
+
+	fn is_tls(&self) -> bool {
+		true
+	}
+}
+
+// A socket we read the first byte of already, to tell whether the peer
+// starts with TLS, that byte read again first.
+struct Peeked {
+	first: Option<u8>,
+	conn: TcpStream,
+}
+
+impl Read for Peeked {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self.first.take() {
+			Some(b) if !buf.is_empty() => {
+				buf[0] = b;
+				Ok(1)
+			}
+			first => {
+				self.first = first;
+				self.conn.read(buf)
+			}
+		}
+	}
+}
+
+impl Write for Peeked {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.conn.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.conn.flush()
+	}
+}
+
+impl Io for Peeked {
+	fn poll_read(&mut self) -> Async<()> {
+		if self.first.is_some() {
+			Async::Ready(())
+		} else {
+			Io::poll_read(&mut self.conn)
+		}
+	}
+
+	fn poll_write(&mut self) -> Async<()> {
+		Io::poll_write(&mut self.conn)
+	}
+}
+
+impl PeerIo for Peeked {
+	fn socket(&self) -> &TcpStream {
+		&self.conn
+	}
+
+	fn is_tls(&self) -> bool {
+		false
+	}
+}
+
+/// Connection to a peer, over any stream to it, encrypted or not.
+pub struct PeerStream {
+	io: Box<PeerIo>,
+}
+
+impl PeerStream {
+	/// Connection over the provided stream.
+	pub fn new<S>(io: S) -> PeerStream
+		where S: PeerIo + 'static
+	{
+		PeerStream { io: Box::new(io) }
+	}
+
+	/// The underlying socket.
+	pub fn socket(&self) -> &TcpStream {
+		self.io.socket()
+	}
+
+	/// Address of the remote peer.
+	pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+		self.socket().peer_addr()
+	}
+
+	/// Our end of the connection.
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket().local_addr()
+	}
+
+	/// Whether the connection runs over TLS.
+	pub fn is_tls(&self) -> bool {
+		self.io.is_tls()
+	}
+}
+
+impl From<TcpStream> for PeerStream {
+	fn from(s: TcpStream) -> PeerStream {
+		PeerStream::new(s)
+	}
+}
+
+impl Read for PeerStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.io.read(buf)
+	}
+}
+
+impl Write for PeerStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.io.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.io.flush()
+	}
+}
+
+impl Io for PeerStream {
+	fn poll_read(&mut self) -> Async<()> {
+		self.io.poll_read()
+	}
+
+	fn poll_write(&mut self) -> Async<()> {
+		self.io.poll_write()
+	}
+}
+
+impl HandshakeStream for PeerStream {
+	fn peer_addr(&self) -> io::Result<SocketAddr> {
+		PeerStream::peer_addr(self)
+	}
+}
+
+/// What we need to secure the connections to our peers, both ways.
+pub struct TlsContext {
+	acceptor: TlsAcceptor,
+	connector: TlsConnector,
+	domain: String,
+	required: bool,
+	// the peers we secured our connections to, by the address we dialed,
+	// and when we last did
+	secured: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl TlsContext {
+	/// Loads our identity and the certificate our peers' certificates have to
+	/// be signed with, as the provided configuration has them. Our identity
+	/// gets presented both ways, as peers connecting to us have to present
+	/// theirs.
+	pub fn new(config: &TlsConfig) -> Result<TlsContext, Error> {
+		let identity = read_file(&config.identity_path)?;
+		let pkcs12 = || {
+			Pkcs12::from_der(&identity, &config.identity_password).map_err(|e| {
+				Error::Tls(format!("invalid identity {}: {}", config.identity_path, e))
+			})
+		};
+		let trusted = match config.trusted_cert_path {
+			Some(ref path) => Some((path, read_file(path)?)),
+			None => None,
+		};
+		let invalid_cert = |path: &str, e: &::std::fmt::Display| {
+			Error::Tls(format!("invalid certificate {}: {}", path, e))
+		};
+
+		let mut builder = TlsAcceptor::builder(pkcs12()?).map_err(tls_err)?;
+		{
+			let ctx = builder.builder_mut().builder_mut();
+			ctx.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT);
+			match trusted {
+				Some((path, ref der)) => {
+					let cert = X509::from_der(der).map_err(|e| invalid_cert(path, &e))?;
+					ctx.cert_store_mut().add_cert(cert).map_err(ssl_err)?;
+				}
+				None => ctx.set_default_verify_paths().map_err(ssl_err)?,
+			}
+		}
+		let acceptor = builder.build().map_err(tls_err)?;
+
+		let mut builder = TlsConnector::builder().map_err(tls_err)?;
+		builder.identity(pkcs12()?).map_err(tls_err)?;
+		if let Some((path, ref der)) = trusted {
+			let cert = Certificate::from_der(der).map_err(|e| invalid_cert(path, &e))?;
+			builder.add_root_certificate(cert).map_err(tls_err)?;
+		}
+		let connector = builder.build().map_err(tls_err)?;
+		Ok(TlsContext {
+			acceptor: acceptor,
+			connector: connector,
+			domain: config.domain.clone(),
+			required: config.required,
+			secured: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/// Whether we secured a connection we opened to the peer at the provided
+	/// address lately, its next ones to be secured before the handshake.
+	pub fn secured_before(&self, addr: &SocketAddr) -> bool {
+		let secured = self.secured.lock().unwrap_or_else(|e| e.into_inner());
+		match secured.get(addr) {
+			Some(t) => t.elapsed() < Duration::from_secs(SECURED_PEER_SECS),
+			None => false,
+		}
+	}
+
+	/// Remembers we secured a connection we opened to the peer at the
+	/// provided address, forgetting the peers secured longest ago when too
+	/// many are.
+	pub fn record_secured(&self, addr: SocketAddr) {
+		let mut secured = self.secured.lock().unwrap_or_else(|e| e.into_inner());
+		let expiry = Duration::from_secs(SECURED_PEER_SECS);
+		let expired = secured.iter()
+			.filter(|&(_, t)| t.elapsed() >= expiry)
+			.map(|(a, _)| *a)
+			.collect::<Vec<_>>();
+		for a in expired {
+			secured.remove(&a);
+		}
+		if secured.len() >= MAX_SECURED_PEERS && !secured.contains_key(&addr) {
+			let oldest = secured.iter().min_by_key(|&(_, t)| *t).map(|(a, _)| *a);
+			if let Some(oldest) = oldest {
+				secured.remove(&oldest);
+			}
+		}
+		secured.insert(addr, Instant::now());
+	}
+
+	/// Runs the TLS handshake of a peer that connected to us, which has to
+	/// present a certificate we trust for the configured domain, as the
+	/// peers we connect to do.
+	pub fn accept(&self, conn: PeerStream) -> Box<Future<Item = PeerStream, Error = Error>> {
+		let domain = self.domain.clone();
+		Box::new(self.acceptor
+			.accept_async(conn)
+			.map_err(tls_err)
+			.and_then(move |s| {
+				verify_domain(&s, &domain)?;
+				Ok(PeerStream::new(s))
+			}))
+	}
+
+	/// Runs the TLS handshake with a peer we connected to, which has to
+	/// present a certificate for the configured domain.
+	pub fn connect(&self, conn: PeerStream) -> Box<Future<Item = PeerStream, Error = Error>> {
+		Box::new(self.connector
+			.connect_async(&self.domain, conn)
+			.map(PeerStream::new)
+			.map_err(tls_err))
+	}
+}
+
+// Checks the certificate a peer that connected to us presented, trusted
+// already, was issued for the provided domain as one of its alternative
+// names.
+fn verify_domain(s: &tokio_tls::TlsStream<PeerStream>, domain: &str) -> Result<(), Error> {
+	let cert = match s.get_ref().raw_stream().ssl().peer_certificate() {
+		Some(cert) => cert,
+		None => return Err(Error::Tls("peer presented no certificate".to_string())),
+	};
+	let issued = cert.subject_alt_names()
+		.map(|names| names.iter().any(|n| n.dnsname() == Some(domain)))
+		.unwrap_or(false);
+	if !issued {
+		return Err(Error::Tls(format!("peer certificate not issued for {}", domain)));
+	}
+	Ok(())
+}
+
+/// Secures a connection we opened to the peer at the provided address
+/// before the handshake if we have a TLS context and secured one to it
+/// lately, the peer presenting its certificate before learning anything of
+/// us. Left in plaintext otherwise, for the handshake to tell whether the
+/// peer can encrypt.
+pub fn secure_known(tls: &Option<Arc<TlsContext>>,
+                    conn: TcpStream,
+                    addr: SocketAddr)
+                    -> Box<Future<Item = PeerStream, Error = Error>> {
+	let tls = match *tls {
+		Some(ref tls) if tls.secured_before(&addr) => tls.clone(),
+		_ => return Box::new(future::ok(PeerStream::from(conn))),
+	};
+	let connect = tls.connect(PeerStream::from(conn));
+	Box::new(connect.map(move |conn| {
+		tls.record_secured(addr);
+		conn
+	}))
+}
+
+/// Secures a connection a peer opened to us before the handshake if we have
+/// a TLS context and the peer starts with TLS, as it does once it knows we
+/// encrypt. Left in plaintext otherwise.
+pub fn accept_known(tls: &Option<Arc<TlsContext>>,
+                    conn: TcpStream)
+                    -> Box<Future<Item = PeerStream, Error = Error>> {
+	let tls = match *tls {
+		Some(ref tls) => tls.clone(),
+		None => return Box::new(future::ok(PeerStream::from(conn))),
+	};
+	Box::new(::tokio_core::io::read_exact(conn, [0u8; 1])
+		.map_err(Error::Connection)
+		.and_then(move |(conn, first)| {
+			let conn = PeerStream::new(Peeked {
+				first: Some(first[0]),
+				conn: conn,
+			});
+			if first[0] == TLS_HANDSHAKE_RECORD {
+				tls.accept(conn)
+			} else {
+				Box::new(future::ok(conn))
+			}
+		}))
+}
+
+/// Secures a connection once the handshake is done if we have a TLS context
+/// and the peer advertised it can encrypt too, the side that connected, to
+/// the provided address, starting the TLS handshake. A peer that can't
+/// encrypt gets refused if our configuration requires encryption, its
+/// connection stays in plaintext otherwise, as it does when we have no TLS
+/// context. Connections secured before the handshake are left as they are,
+/// and the peers we secured ours to remembered, see secure_known.
+pub fn secure(tls: &Option<Arc<TlsContext>>,
+              conn: PeerStream,
+              theirs: Capabilities,
+              dialed: Option<SocketAddr>)
+              -> Box<Future<Item = PeerStream, Error = Error>> {
+	let tls = match *tls {
+		Some(ref tls) => tls.clone(),
+		None => return Box::new(future::ok(conn)),
+	};
+	if conn.is_tls() {
+		return Box::new(future::ok(conn));
+	}
+	if !theirs.contains(ENCRYPTED) {
+		if tls.required {
+			return Box::new(future::err(Error::Tls("peer can't encrypt".to_string())));
+		}
+		return Box::new(future::ok(conn));
+	}
+	match dialed {
+		Some(addr) => {
+			let connect = tls.connect(conn);
+			Box::new(connect.map(move |conn| {
+				tls.record_secured(addr);
+				conn
+			}))
+		}
+		None => tls.accept(conn),
+	}
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+	let mut data = vec![];
+	File::open(path)
+		.and_then(|mut f| f.read_to_end(&mut data))
+		.map_err(|e| Error::Tls(format!("could not read {}: {}", path, e)))?;
+	Ok(data)
+}
+
+fn tls_err(e: native_tls::Error) -> Error {
+	Error::Tls(format!("{}", e))
+}
+
+fn ssl_err(e: ErrorStack) -> Error {
+	Error::Tls(format!("{}", e))
+}
@@ -0,0 +1,436 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Test harness running a small network of in-process servers on loopback,
+//! all driven by a single event loop. Servers listen on ports picked by the
+//! system so tests can run alongside each other, and tests wait for the state
+//! they expect rather than for a fixed time.
+
+use std::net::{self, IpAddr, SocketAddr};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use tokio_core::reactor;
+
+use core::core;
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::ser;
+use bans::BanEntry;
+use msg::{Checkpoint, is_kernel_of};
+use server::Server;
+use types::*;
+
+/// Adapter of a test node, keeping track of the blocks, headers and
+/// transactions it received and of the blocks it served. Blocks and
+/// transactions aren't Clone, they're kept serialized to serve them.
+pub struct TestNodeAdapter {
+	pub blocks: Mutex<Vec<(Hash, Vec<u8>)>>,
+	pub headers: Mutex<Vec<Hash>>,
+	pub served: Mutex<Vec<Hash>>,
+	pub txs: Mutex<Vec<(Hash, Vec<u8>)>>,
+}
+
+impl TestNodeAdapter {
+	fn new() -> TestNodeAdapter {
+		TestNodeAdapter {
+			blocks: Mutex::new(vec![]),
+			headers: Mutex::new(vec![]),
+			served: Mutex::new(vec![]),
+			txs: Mutex::new(vec![]),
+		}
+	}
+
+	/// Whether the node has the transaction with the provided hash.
+	pub fn has_transaction(&self, h: Hash) -> bool {
+		self.txs.lock().unwrap().iter().any(|&(th, _)| th == h)
+	}
+
+	// Keeps the transaction if we don't have it yet.
+	fn store_transaction(&self, tx: &core::Transaction) {
+		let h = tx.hash();
+		if !self.has_transaction(h) {
+			self.txs.lock().unwrap().push((h, ser::ser_vec(tx).unwrap()));
+		}
+	}
+
+	// Keeps the block if we don't have it yet.
+	fn store(&self, b: &core::Block) {
+		let h = b.hash();
+		if !self.has_block(h) {
+			self.blocks.lock().unwrap().push((h, ser::ser_vec(b).unwrap()));
+		}
+	}
+}
+
+impl NetAdapter for TestNodeAdapter {
+	fn total_difficulty(&self) -> Difficulty {
+		Difficulty::one()
+	}
+	fn head_hash(&self) -> Hash {
+		self.blocks.lock().unwrap().last().map(|&(h, _)| h).unwrap_or(ZERO_HASH)
+	}
+	fn head_height(&self) -> u64 {
+		let blocks = self.blocks.lock().unwrap();
+		blocks.last()
+			.map(|&(_, ref data)| ser::deserialize::<core::Block>(&mut &data[..]).unwrap())
+			.map(|b| b.header.height)
+			.unwrap_or(0)
+	}
+	fn checkpoints(&self) -> Vec<Checkpoint> {
+		vec![]
+	}
+	fn checkpoints_agreed(&self, checkpoints: Vec<Checkpoint>) {}
+	fn services(&self) -> Services {
+		ALL_SERVICES
+	}
+	fn transaction_received(&self, tx: core::Transaction) {
+		self.store_transaction(&tx);
+	}
+	fn block_received(&self, b: core::Block, src: SocketAddr) -> BlockStatus {
+		self.store(&b);
+		BlockStatus::Accepted
+	}
+	fn headers_received(&self, bh: Vec<core::BlockHeader>) {
+		self.headers.lock().unwrap().extend(bh.iter().map(|h| h.hash()));
+	}
+	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
+		vec![]
+	}
+	fn get_block(&self, h: Hash) -> Option<core::Block> {
+		let blocks = self.blocks.lock().unwrap();
+		let data = match blocks.iter().find(|&&(bh, _)| bh == h) {
+			Some(&(_, ref data)) => data,
+			None => return None,
+		};
+		self.served.lock().unwrap().push(h);
+		Some(ser::deserialize(&mut &data[..]).unwrap())
+	}
+	fn utxo_chunk(&self, horizon: Hash, chunk: u32) -> Option<(u32, Vec<core::Output>)> {
+		None
+	}
+	fn stem_transaction_received(&self, tx: core::Transaction) {}
+	fn transaction_fluffed(&self, tx: &core::Transaction) {}
+	fn peer_banned(&self, addr: SocketAddr, ban: &BanEntry) {}
+	fn peer_unbanned(&self, ip: IpAddr) {}
+	fn has_block(&self, h: Hash) -> bool {
+		self.blocks.lock().unwrap().iter().any(|&(bh, _)| bh == h)
+	}
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction> {
+		let txs = self.txs.lock().unwrap();
+		txs.iter()
+			.find(|&&(th, _)| th == h)
+			.map(|&(_, ref data)| ser::deserialize(&mut &data[..]).unwrap())
+	}
+	fn get_kernel_transaction(&self, k: &core::TxKernel) -> Option<core::Transaction> {
+		let txs = self.txs.lock().unwrap();
+		txs.iter()
+			.map(|&(_, ref data)| ser::deserialize::<core::Transaction>(&mut &data[..]).unwrap())
+			.find(|tx| is_kernel_of(k, tx))
+	}
+	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr> {
+		vec![]
+	}
+	fn peer_addrs_received(&self, peer_addrs: Vec<SocketAddr>, src: SocketAddr) {}
+	fn peer_connected(&self, pi: &PeerInfo) {}
+	fn peer_error(&self, pi: &PeerInfo, err: &Error) {}
+	fn peer_disconnected(&self, pi: &PeerInfo) {}
+	fn address_allowed(&self, addr: &SocketAddr) -> bool {
+		true
+	}
+	fn bootstrap_addrs(&self) -> Vec<SocketAddr> {
+		vec![]
+	}
+}
+
+/// How long waiting for the network to reach the state a test expects can
+/// take before the test fails.
+pub const TEST_TIMEOUT_SECS: u64 = 10;
+
+/// A loopback port free for a test server to listen on, as picked by the
+/// system binding port 0.
+pub fn free_port() -> u16 {
+	let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+	listener.local_addr().unwrap().port()
+}
+
+/// Blocks until the provided condition holds, for up to TEST_TIMEOUT_SECS,
+/// as blocking clients waiting on servers running on another thread do.
+/// Returns whether the condition was met.
+pub fn poll_until<F>(cond: F) -> bool
+	where F: Fn() -> bool
+{
+	let start = Instant::now();
+	while !cond() {
+		if start.elapsed() > Duration::from_secs(TEST_TIMEOUT_SECS) {
+			return false;
+		}
+		thread::sleep(Duration::from_millis(10));
+	}
+	true
+}
+
+/// A single server of the test network.
+pub struct TestNode {
+	pub addr: SocketAddr,
+	pub server: Arc<Server>,
+	pub adapter: Arc<TestNodeAdapter>,
+}
+
+/// A set of servers listening on loopback, the nodes with a test adapter
+/// along with those tests started with adapters of their own.
+pub struct TestNetwork {
+	pub nodes: Vec<TestNode>,
+	evtlp: reactor::Core,
+}
+
+impl TestNetwork {
+	/// An event loop without any server running on it yet.
+	pub fn empty() -> TestNetwork {
+		TestNetwork {
+			nodes: vec![],
+			evtlp: reactor::Core::new().unwrap(),
+		}
+	}
+
+	/// Starts n servers, not connected to each other yet.
+	pub fn new(n: u16) -> TestNetwork {
+		let mut net = TestNetwork::empty();
+		for _ in 0..n {
+			let adapter = Arc::new(TestNodeAdapter::new());
+			let (server, addr) = net.serve(P2PConfig::default(), adapter.clone());
+			net.nodes.push(TestNode {
+				addr: addr,
+				server: server,
+				adapter: adapter,
+			});
+		}
+		net
+	}
+
+	/// Starts n servers with each of them connected to all the others.
+	pub fn mesh(n: u16) -> TestNetwork {
+		let mut net = TestNetwork::new(n);
+		for i in 0..net.nodes.len() {
+			for j in (i + 1)..net.nodes.len() {
+				net.connect(i, j);
+			}
+		}
+		net
+	}
+
+	/// Starts a server with the provided config and adapter listening on a
+	/// free port, whatever port the config has. Returns the server along
+	/// with its address.
+	pub fn serve(&self, config: P2PConfig, adapter: Arc<NetAdapter>) -> (Arc<Server>, SocketAddr) {
+		self.serve_with(UNKNOWN, config, adapter)
+	}
+
+	/// Same as serve, the server having the provided capabilities.
+	pub fn serve_with(&self,
+	                  capab: Capabilities,
+	                  config: P2PConfig,
+	                  adapter: Arc<NetAdapter>)
+	                  -> (Arc<Server>, SocketAddr) {
+		let config = P2PConfig { port: free_port(), ..config };
+		let addr = SocketAddr::new(config.host, config.port);
+		let server = Arc::new(Server::new(capab, config, adapter));
+		let handle = self.evtlp.handle();
+		handle.spawn(server.start(handle.clone()).map_err(|e| panic!("{:?}", e)));
+		(server, addr)
+	}
+
+	/// A server with the provided config and adapter that isn't listening,
+	/// to connect to others, on a free port to advertise.
+	pub fn dialer(config: P2PConfig, adapter: Arc<NetAdapter>) -> Arc<Server> {
+		let config = P2PConfig { port: free_port(), ..config };
+		Arc::new(Server::new(UNKNOWN, config, adapter))
+	}
+
+	/// Handle of the event loop the servers run on.
+	pub fn handle(&self) -> reactor::Handle {
+		self.evtlp.handle()
+	}
+
+	/// Runs the event loop until the provided future completes.
+	pub fn run<F: Future>(&mut self, f: F) -> Result<F::Item, F::Error> {
+		self.evtlp.run(f)
+	}
+
+	/// Runs the event loop until the provided condition holds, for up to
+	/// TEST_TIMEOUT_SECS. Returns whether the condition was met.
+	pub fn wait_for<F>(&mut self, cond: F) -> bool
+		where F: Fn() -> bool
+	{
+		self.run_until(Duration::from_secs(TEST_TIMEOUT_SECS), |_| cond())
+	}
+
+	/// Runs the provided closure on a thread of its own while the event loop
+	/// keeps running, as blocking clients of the servers need. Returns what
+	/// the closure does, failing the test if it panics or times out.
+	pub fn run_thread<T, F>(&mut self, f: F) -> T
+		where T: Send + 'static,
+		      F: FnOnce() -> T + Send + 'static
+	{
+		let (tx, rx) = mpsc::channel();
+		thread::spawn(move || {
+			let _ = tx.send(f());
+		});
+		let start = Instant::now();
+		loop {
+			match rx.try_recv() {
+				Ok(res) => return res,
+				Err(mpsc::TryRecvError::Disconnected) => panic!("test thread failed"),
+				Err(mpsc::TryRecvError::Empty) => {}
+			}
+			if start.elapsed() > Duration::from_secs(TEST_TIMEOUT_SECS) {
+				panic!("test thread timed out");
+			}
+			self.run_for(Duration::from_millis(10));
+		}
+	}
+
+	/// Has the node at index from connect to the node at index to.
+	pub fn connect(&mut self, from: usize, to: usize) {
+		let handle = self.evtlp.handle();
+		let addr = self.nodes[to].addr;
+		let connect = self.nodes[from].server.connect_peer(addr, handle.clone());
+		handle.spawn(connect.map(|_| ()).map_err(|_| ()));
+	}
+
+	/// Runs the event loop for the provided duration.
+	pub fn run_for(&mut self, d: Duration) {
+		let timeout = reactor::Timeout::new(d, &self.evtlp.handle()).unwrap();
+		self.evtlp.run(timeout).unwrap();
+	}
+
+	/// Runs the event loop until the provided condition holds on the network,
+	/// giving up after the timeout. Returns whether the condition was met.
+	pub fn run_until<F>(&mut self, timeout: Duration, cond: F) -> bool
+		where F: Fn(&TestNetwork) -> bool
+	{
+		let start = Instant::now();
+		while !cond(self) {
+			if start.elapsed() > timeout {
+				return false;
+			}
+			self.run_for(Duration::from_millis(50));
+		}
+		true
+	}
+
+	/// Whether every node is connected to every other node.
+	pub fn fully_connected(&self) -> bool {
+		let n = self.nodes.len();
+		self.nodes.iter().all(|node| node.server.connected_peers().len() == n - 1)
+	}
+
+	/// Gives a block to the node at the provided index, without it telling
+	/// its peers.
+	pub fn give_block(&self, to: usize, b: &core::Block) {
+		self.nodes[to].adapter.store(b);
+	}
+
+	/// Broadcasts a block from the node at the provided index, which is then
+	/// considered to have it.
+	pub fn broadcast_block(&self, from: usize, b: &core::Block) {
+		self.give_block(from, b);
+		self.nodes[from].server.broadcast_block(b);
+	}
+
+	/// Announces the header of a block from the node at the provided index,
+	/// which is then considered to have the block.
+	pub fn broadcast_header(&self, from: usize, b: &core::Block) -> BroadcastStats {
+		self.give_block(from, b);
+		self.nodes[from].server.broadcast_header(&b.header)
+	}
+
+	/// Relays a transaction from the node at the provided index, which is
+	/// then considered to have it.
+	pub fn broadcast_transaction(&self, from: usize, tx: &core::Transaction) -> BroadcastStats {
+		self.nodes[from].adapter.store_transaction(tx);
+		self.nodes[from].server.broadcast_transaction(tx)
+	}
+
+	/// Whether all nodes have received the block with the provided hash.
+	pub fn converged_on(&self, h: Hash) -> bool {
+		self.nodes.iter().all(|node| node.adapter.has_block(h))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::Duration;
+
+	use core::core;
+	use core::core::hash::Hashed;
+	use super::*;
+
+	#[test]
+	fn mesh_propagates_block() {
+		let mut net = TestNetwork::mesh(3);
+		assert!(net.run_until(Duration::from_secs(5), |net| net.fully_connected()));
+
+		let mut b = core::Block::default();
+		b.header.height = 1;
+		net.broadcast_block(0, &b);
+		assert!(net.run_until(Duration::from_secs(5), |net| net.converged_on(b.hash())));
+	}
+
+	#[test]
+	fn header_announcement_pulls_missing_block() {
+		let mut net = TestNetwork::mesh(3);
+		assert!(net.run_until(Duration::from_secs(5), |net| net.fully_connected()));
+
+		// the last node already has the block, only the middle one lacks it
+		let mut b = core::Block::default();
+		b.header.height = 1;
+		let h = b.hash();
+		net.give_block(2, &b);
+		let stats = net.broadcast_header(0, &b);
+		assert_eq!((stats.sent, stats.skipped), (2, 0));
+		assert!(net.run_until(Duration::from_secs(5), |net| net.converged_on(h)));
+		let announced = |n: &TestNode| n.adapter.headers.lock().unwrap().contains(&h);
+		assert!(net.run_until(Duration::from_secs(5),
+		                      |net| net.nodes[1..].iter().all(&announced)));
+
+		// the block only went to the node that asked for it
+		net.run_for(Duration::from_millis(200));
+		assert_eq!(*net.nodes[0].adapter.served.lock().unwrap(), vec![h]);
+
+		// both peers now know it, so nothing gets announced again
+		let stats = net.broadcast_header(0, &b);
+		assert_eq!((stats.sent, stats.skipped), (0, 2));
+	}
+
+	#[test]
+	fn mesh_relays_transaction() {
+		let mut net = TestNetwork::mesh(3);
+		assert!(net.run_until(Duration::from_secs(5), |net| net.fully_connected()));
+
+		let tx = core::Transaction::new(vec![], vec![], 2);
+		let h = tx.hash();
+		let stats = net.broadcast_transaction(0, &tx);
+		assert_eq!((stats.sent, stats.skipped, stats.failed), (2, 0, 0));
+		let relayed = |n: &TestNode| n.adapter.has_transaction(h);
+		assert!(net.run_until(Duration::from_secs(5),
+		                      |net| net.nodes.iter().all(&relayed)));
+
+		// every peer now knows it, so it isn't relayed again
+		let stats = net.broadcast_transaction(0, &tx);
+		assert_eq!((stats.sent, stats.skipped), (0, 2));
+	}
+}
@@ -0,0 +1,392 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Outbound bandwidth limiting, both for each peer and across all of them,
+//! and limiting of what each peer sends us.
+
+use std::cmp;
+use std::iter;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future, Loop};
+use futures::stream::{self, Stream};
+use tokio_timer::Timer;
+
+use types::{Error, ExcessInbound, InboundLimits};
+
+// Longest single sleep on a timer in seconds, well within its wheel. Longer
+// waits are chained from sleeps that long, the timer failing them otherwise.
+const MAX_SLEEP_SECS: u64 = 60;
+
+/// Token bucket allowing a given rate of bytes per second, with bursts of up
+/// to a second worth of bytes unless configured otherwise.
+pub struct TokenBucket {
+	rate: u64,
+	// most tokens the bucket holds
+	capacity: f64,
+	// available tokens, negative when writes are waiting for the bucket to
+	// refill, and when they were last counted
+	state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+	/// A new full bucket allowing the provided rate, in bytes per second.
+	pub fn new(rate: u64, now: Instant) -> TokenBucket {
+		TokenBucket::with_burst(rate, 1, now)
+	}
+
+	/// Most bytes the bucket lets through at once.
+	pub fn capacity(&self) -> u64 {
+		self.capacity as u64
+	}
+
+	/// A new full bucket allowing the provided rate, with bursts of up to the
+	/// provided number of seconds worth of it.
+	pub fn with_burst(rate: u64, burst_secs: u64, now: Instant) -> TokenBucket {
+		let capacity = (rate * cmp::max(burst_secs, 1)) as f64;
+		TokenBucket {
+			rate: rate,
+			capacity: capacity,
+			state: Mutex::new((capacity, now)),
+		}
+	}
+
+	/// Takes the provided amount of bytes from the bucket, returning how long
+	/// to wait before sending them to stay under the rate.
+	pub fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+		let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+		let (tokens, last) = *state;
+		let elapsed = if now > last {
+			now.duration_since(last)
+		} else {
+			Duration::new(0, 0)
+		};
+		let refill = secs(elapsed) * self.rate as f64;
+		let tokens = (tokens + refill).min(self.capacity) - bytes as f64;
+		*state = (tokens, cmp::max(now, last));
+
+		if tokens >= 0.0 {
+			Duration::new(0, 0)
+		} else {
+			let wait = -tokens / self.rate as f64;
+			Duration::new(wait as u64, (wait.fract() * 1_000_000_000.0) as u32)
+		}
+	}
+}
+
+fn secs(d: Duration) -> f64 {
+	d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+/// Future resolving once the provided duration passed on the timer, however
+/// long it is, such as the wait of a large write on a slow limit.
+pub fn sleep(timer: &Timer, d: Duration) -> Box<Future<Item = (), Error = Error>> {
+	sleep_steps(timer, d, Duration::from_secs(MAX_SLEEP_SECS))
+}
+
+// Sleeps for the provided duration in steps of at most max.
+fn sleep_steps(timer: &Timer, d: Duration, max: Duration) -> Box<Future<Item = (), Error = Error>> {
+	Box::new(future::loop_fn((timer.clone(), d), move |(timer, left)| {
+		let step = cmp::min(left, max);
+		timer.sleep(step).from_err().map(move |_| {
+			if left > step {
+				Loop::Continue((timer, left - step))
+			} else {
+				Loop::Break(())
+			}
+		})
+	}))
+}
+
+/// Stream ticking every time the provided duration passed on the timer,
+/// however long it is.
+pub fn interval(timer: &Timer, d: Duration) -> Box<Stream<Item = (), Error = Error>> {
+	let timer = timer.clone();
+	let ticks = stream::iter(iter::repeat(Ok::<(), Error>(())));
+	Box::new(ticks.and_then(move |_| sleep(&timer, d)))
+}
+
+/// Limits applying to what a single peer sends us, in bytes and messages,
+/// each bucket allowing its own burst.
+pub struct ReadLimit {
+	bytes: Option<TokenBucket>,
+	msgs: Option<TokenBucket>,
+	excess: ExcessInbound,
+	// how far over the limits a peer can get before being disconnected
+	grace: Duration,
+	timer: Timer,
+}
+
+impl ReadLimit {
+	/// A limit enforcing the provided caps, none if they're all unset.
+	pub fn new(limits: InboundLimits, timer: Timer) -> Option<ReadLimit> {
+		if limits.unlimited() {
+			return None;
+		}
+		let now = Instant::now();
+		let bucket = |rate| {
+			if rate > 0 {
+				Some(TokenBucket::with_burst(rate, limits.burst_secs, now))
+			} else {
+				None
+			}
+		};
+		Some(ReadLimit {
+			bytes: bucket(limits.max_rate),
+			msgs: bucket(limits.max_msgs),
+			excess: limits.excess,
+			grace: Duration::from_secs(cmp::max(limits.burst_secs, 1)),
+			timer: timer,
+		})
+	}
+
+	/// Accounts for a message of the provided size we just read, returning
+	/// how long to hold off reading more for the peer to be back within the
+	/// limits. A message larger than a burst counts as a full burst, so a
+	/// single large block doesn't put the peer over.
+	pub fn delay(&self, bytes: u64, now: Instant) -> Duration {
+		let bytes = self.bytes.as_ref().map(|b| b.reserve(cmp::min(bytes, b.capacity()), now));
+		let msgs = self.msgs.as_ref().map(|b| b.reserve(1, now));
+		let zero = Duration::new(0, 0);
+		cmp::max(bytes.unwrap_or(zero), msgs.unwrap_or(zero))
+	}
+
+	/// Accounts for a message of the provided size we just read, resolving
+	/// once we can read more. Fails right away when the peer went too far
+	/// over the limits and gets disconnected for it.
+	pub fn wait(&self, bytes: u64) -> Result<Option<Box<Future<Item = (), Error = Error>>>, Error> {
+		let delay = self.delay(bytes, Instant::now());
+		if delay == Duration::new(0, 0) {
+			return Ok(None);
+		}
+		if self.excess == ExcessInbound::Disconnect && delay > self.grace {
+			return Err(Error::InboundRateExceeded);
+		}
+		Ok(Some(sleep(&self.timer, delay)))
+	}
+}
+
+/// Limits applying to the writes of a single peer: its own and, optionally,
+/// the one shared by all peers. The tighter of the two applies.
+pub struct Throttle {
+	global: Option<Arc<TokenBucket>>,
+	peer: Option<TokenBucket>,
+	timer: Option<Timer>,
+	// how long a single write can take
+	write_timeout: Option<Duration>,
+	// limits on what the peer sends us, taken by the reading side
+	read: Option<ReadLimit>,
+}
+
+impl Throttle {
+	/// A throttle letting everything through.
+	pub fn unlimited() -> Throttle {
+		Throttle {
+			global: None,
+			peer: None,
+			timer: None,
+			write_timeout: None,
+			read: None,
+		}
+	}
+
+	/// A throttle sharing the global bucket, if any, and limiting the peer
+	/// to its own rate in bytes per second, zero meaning no limit.
+	pub fn new(global: Option<Arc<TokenBucket>>, peer_rate: u64, timer: Timer) -> Throttle {
+		let peer = if peer_rate > 0 {
+			Some(TokenBucket::new(peer_rate, Instant::now()))
+		} else {
+			None
+		};
+		Throttle {
+			global: global,
+			peer: peer,
+			timer: Some(timer),
+			write_timeout: None,
+			read: None,
+		}
+	}
+
+	/// Same throttle, also limiting how long a single write can take before
+	/// failing with a timeout.
+	pub fn with_write_timeout(self, timeout: Duration) -> Throttle {
+		Throttle { write_timeout: Some(timeout), ..self }
+	}
+
+	/// Same throttle, also limiting what the peer sends us.
+	pub fn with_read_limit(self, read: Option<ReadLimit>) -> Throttle {
+		Throttle { read: read, ..self }
+	}
+
+	/// Takes the limits on what the peer sends us out, for the reading side
+	/// to enforce.
+	pub fn take_read_limit(&mut self) -> Option<ReadLimit> {
+		self.read.take()
+	}
+
+	/// Deadline a single write has to complete within, along with the timer
+	/// to enforce it, if any.
+	pub fn write_deadline(&self) -> Option<(Duration, Timer)> {
+		match (self.write_timeout, self.timer.as_ref()) {
+			(Some(timeout), Some(timer)) => Some((timeout, timer.clone())),
+			_ => None,
+		}
+	}
+
+	/// How long to wait before writing the provided amount of bytes.
+	pub fn delay(&self, bytes: u64, now: Instant) -> Duration {
+		let global = self.global.as_ref().map(|b| b.reserve(bytes, now));
+		let peer = self.peer.as_ref().map(|b| b.reserve(bytes, now));
+		let zero = Duration::new(0, 0);
+		cmp::max(global.unwrap_or(zero), peer.unwrap_or(zero))
+	}
+
+	/// Future resolving once the provided amount of bytes can be written.
+	pub fn wait(&self, bytes: u64) -> Box<Future<Item = (), Error = Error>> {
+		let delay = self.delay(bytes, Instant::now());
+		match self.timer {
+			Some(ref timer) if delay > Duration::new(0, 0) => sleep(timer, delay),
+			_ => Box::new(future::ok(())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::Arc;
+	use std::time::{Duration, Instant};
+
+	use tokio_timer::{self, Timer, TimerError};
+
+	use types::{ExcessInbound, InboundLimits};
+	use super::*;
+
+	#[test]
+	fn aggregate_cap() {
+		let now = Instant::now();
+		let global = Arc::new(TokenBucket::new(1000, now));
+		let peer1 = Throttle::new(Some(global.clone()), 0, Timer::default());
+		let peer2 = Throttle::new(Some(global.clone()), 0, Timer::default());
+
+		// both peers trying to push 5000 bytes each right away
+		let mut sent = 0;
+		for _ in 0..10 {
+			for peer in &[&peer1, &peer2] {
+				let delay = peer.delay(500, now);
+				sent += 500;
+				// by the time the write goes out, combined throughput stays
+				// within the rate and its initial burst
+				assert!(sent as f64 <= 1000.0 * secs(delay) + 1000.0 + 0.001);
+			}
+		}
+		let last = peer1.delay(500, now);
+		assert!(last >= Duration::from_secs(9));
+	}
+
+	#[test]
+	fn tighter_limit_applies() {
+		let now = Instant::now();
+		let global = Arc::new(TokenBucket::new(1000, now));
+		let peer = Throttle::new(Some(global), 100, Timer::default());
+
+		assert_eq!(peer.delay(100, now), Duration::new(0, 0));
+		// the peer's own bucket is empty while the global one isn't
+		let delay = peer.delay(100, now);
+		assert!(delay >= Duration::from_millis(999) && delay <= Duration::from_millis(1001));
+	}
+
+	#[test]
+	fn long_sleeps_chained() {
+		// a timer whose wheel only covers 160ms
+		let timer = tokio_timer::wheel()
+			.tick_duration(Duration::from_millis(10))
+			.num_slots(16)
+			.build();
+		match timer.sleep(Duration::from_millis(500)).wait() {
+			Err(TimerError::TooLong) => {}
+			_ => panic!("expected the timer to refuse the sleep"),
+		}
+
+		let start = Instant::now();
+		let step = Duration::from_millis(100);
+		sleep_steps(&timer, Duration::from_millis(500), step).wait().unwrap();
+		assert!(start.elapsed() >= Duration::from_millis(500));
+
+		let start = Instant::now();
+		let ticks = interval(&Timer::default(), Duration::from_millis(100)).take(3).collect();
+		assert_eq!(ticks.wait().unwrap().len(), 3);
+		assert!(start.elapsed() >= Duration::from_millis(300));
+	}
+
+	#[test]
+	fn burst_allowed() {
+		let now = Instant::now();
+		let bucket = TokenBucket::with_burst(100, 3, now);
+		assert_eq!(bucket.reserve(300, now), Duration::new(0, 0));
+		let delay = bucket.reserve(100, now);
+		assert!(delay >= Duration::from_millis(999) && delay <= Duration::from_millis(1001));
+	}
+
+	#[test]
+	fn reads_limited() {
+		let limits = InboundLimits {
+			max_rate: 0,
+			max_msgs: 10,
+			burst_secs: 2,
+			excess: ExcessInbound::Disconnect,
+		};
+		let now = Instant::now();
+		let limit = ReadLimit::new(limits, Timer::default()).unwrap();
+
+		// any size goes as long as there aren't too many messages
+		for _ in 0..20 {
+			assert_eq!(limit.delay(1_000_000, now), Duration::new(0, 0));
+		}
+		assert!(limit.delay(1, now) > Duration::new(0, 0));
+
+		// held off until more than a burst worth over, then disconnected
+		for _ in 0..19 {
+			assert!(limit.wait(1).unwrap().is_some());
+		}
+		assert!(limit.wait(1).is_err());
+
+		let unlimited = InboundLimits { max_msgs: 0, ..limits };
+		assert!(ReadLimit::new(unlimited, Timer::default()).is_none());
+	}
+
+	#[test]
+	fn large_message_not_disconnected() {
+		let limits = InboundLimits {
+			max_rate: 1000,
+			max_msgs: 0,
+			burst_secs: 1,
+			excess: ExcessInbound::Disconnect,
+		};
+		let now = Instant::now();
+		let limit = ReadLimit::new(limits, Timer::default()).unwrap();
+
+		// a message way over the burst only takes the burst
+		assert_eq!(limit.delay(1_000_000, now), Duration::new(0, 0));
+		let delay = limit.delay(500, now);
+		assert!(delay >= Duration::from_millis(499) && delay <= Duration::from_millis(501));
+		assert!(limit.wait(100).unwrap().is_some());
+	}
+
+	#[test]
+	fn unlimited() {
+		let peer = Throttle::unlimited();
+		assert_eq!(peer.delay(1_000_000_000, Instant::now()), Duration::new(0, 0));
+	}
+}
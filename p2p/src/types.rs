@@ -13,18 +13,29 @@
 // limitations under the License.
 
 use std::convert::From;
+use std::fmt;
 use std::io;
 use std::net::{SocketAddr, IpAddr};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
 
 use futures::Future;
-use tokio_core::net::TcpStream;
 use tokio_timer::TimerError;
 
 use core::core;
 use core::core::hash::Hash;
 use core::core::target::Difficulty;
+use core::genesis;
+use core::global::ChainTypes;
 use core::ser;
+use bans::BanEntry;
+use conn::Traffic;
+use msg::{ChainStatus, Checkpoint, PeerInfoResp, Type, UtxoChunk};
+use pool::BlockPool;
+use server::LocalStatus;
+use stream::PeerStream;
+use throttle::Throttle;
 
 /// Maximum number of hashes in a block header locator request
 pub const MAX_LOCATORS: u32 = 10;
@@ -38,12 +49,94 @@ pub const MAX_BLOCK_BODIES: u32 = 16;
 /// Maximum number of peer addresses a peer should ever send
 pub const MAX_PEER_ADDRS: u32 = 256;
 
+/// Maximum number of hashes in an inventory announcement or request
+pub const MAX_INV_HASHES: u32 = 512;
+
+/// Maximum number of checkpoints a peer should ever send
+pub const MAX_CHECKPOINTS: u32 = 64;
+
+/// Maximum number of unspent outputs in a chunk of a UTXO set snapshot, a
+/// few megabytes worth with their range proofs
+pub const MAX_UTXO_CHUNK: u32 = 1000;
+
+/// Maximum size of the blocks streamed back in a single response to a getdata,
+/// anything beyond goes in following responses
+pub const MAX_BLOCKS_RESPONSE_BYTES: usize = 4_000_000;
+
 #[derive(Debug)]
 pub enum Error {
 	Serialization(ser::Error),
 	Connection(io::Error),
 	ConnectionClose,
 	Timeout,
+	/// The remote peer sent a message with an unexpected magic number, it's
+	/// most likely running on another network.
+	WrongNetwork,
+	/// The remote peer speaks a protocol version we don't support.
+	ProtocolVersion(u32),
+	/// The handshake nonce is one of ours, we connected to ourselves.
+	SelfConnection,
+	/// The peer is banned or quarantined, we don't connect to it.
+	Banned,
+	/// Neither side of the connection serves anything to the other.
+	NoCommonServices,
+	/// The handshake nonce was recently used by a peer at another address.
+	DuplicateNonce,
+	/// The remote peer left too many of our requests unanswered.
+	TooManyRequests,
+	/// The adapter panicked handling a message from the remote peer.
+	AdapterPanic,
+	/// A message from the remote peer didn't match its checksum, it got
+	/// corrupted in transit.
+	Checksum,
+	/// The peer kept dropping soon after we connected, we're backing off.
+	Backoff,
+	/// We couldn't listen on the address, even after retrying.
+	Bind(SocketAddr, io::Error),
+	/// The adapter doesn't allow connecting to the peer's address.
+	NotAllowed,
+	/// All the inbound slots for peers with the provided capabilities are
+	/// taken.
+	CapabilityLimit(Capabilities),
+	/// All the slots for peers in the direction of the connection are taken,
+	/// and none of those peers stands worse than the new one.
+	NoSlot,
+	/// The clock of the remote peer is this many seconds ahead of ours,
+	/// behind if negative, beyond what we tolerate.
+	ClockSkew(i64),
+	/// We ran out of file descriptors, no connection gets opened until some
+	/// get freed.
+	TooManyOpenFiles,
+	/// The TLS handshake with the peer failed, like when its certificate
+	/// isn't one we trust, or our own TLS setup is broken.
+	Tls(String),
+	/// The remote peer committed enough violations to reach the ban score.
+	Misbehaving,
+	/// The remote peer sent us more than the inbound limits allow.
+	InboundRateExceeded,
+	/// The message isn't part of the protocol version negotiated with the
+	/// remote peer, or the peer didn't advertise the capabilities to handle
+	/// it.
+	UnsupportedMessage(Type),
+	/// The peer is an onion service and we have no proxy to reach it.
+	NoProxy,
+	/// Too many dials are already waiting for one of the concurrent dial
+	/// slots, see P2PConfig::max_concurrent_dials.
+	TooManyDials,
+}
+
+impl Error {
+	/// Whether the error is a common and benign network hiccup, like the peer
+	/// resetting the connection, so trying the peer again later is fine.
+	pub fn is_transient(&self) -> bool {
+		match *self {
+			Error::Connection(ref e) => {
+				e.kind() == io::ErrorKind::ConnectionReset || e.kind() == io::ErrorKind::BrokenPipe
+			}
+			Error::Checksum => true,
+			_ => false,
+		}
+	}
 }
 
 impl From<ser::Error> for Error {
@@ -62,24 +155,589 @@ impl From<TimerError> for Error {
 	}
 }
 
-/// Configuration for the peer-to-peer server.
-#[derive(Debug, Clone, Copy)]
+/// Reasons for a handshake with a remote peer to fail, counted by the server
+/// to help debugging connectivity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+	/// The handshake didn't complete in time.
+	Timeout,
+	/// The peer is on another network.
+	WrongNetwork,
+	/// The peer speaks a protocol version we don't support.
+	IncompatibleVersion,
+	/// We connected to ourselves.
+	SelfConnection,
+	/// The peer had nothing to offer us, nor we to it.
+	NoCommonServices,
+	/// The connection got reset midway, a transient failure.
+	ConnectionReset,
+	/// The peer reused the nonce of a peer at another address.
+	DuplicateNonce,
+	/// The clock of the peer is too far off ours.
+	ClockSkew,
+	/// The TLS handshake failed.
+	Tls,
+	/// Any other connection or serialization error.
+	Other,
+}
+
+impl HandshakeFailure {
+	/// Classifies the error a failed handshake produced.
+	pub fn from_error(e: &Error) -> HandshakeFailure {
+		match *e {
+			Error::Timeout => HandshakeFailure::Timeout,
+			Error::WrongNetwork => HandshakeFailure::WrongNetwork,
+			Error::ProtocolVersion(_) => HandshakeFailure::IncompatibleVersion,
+			Error::SelfConnection => HandshakeFailure::SelfConnection,
+			Error::NoCommonServices => HandshakeFailure::NoCommonServices,
+			Error::DuplicateNonce => HandshakeFailure::DuplicateNonce,
+			Error::ClockSkew(_) => HandshakeFailure::ClockSkew,
+			Error::Tls(_) => HandshakeFailure::Tls,
+			_ if e.is_transient() => HandshakeFailure::ConnectionReset,
+			_ => HandshakeFailure::Other,
+		}
+	}
+}
+
+/// Outcome of sending a block or transaction to a single peer.
+#[derive(Debug)]
+pub enum SendOutcome {
+	/// The block or transaction was queued for sending.
+	Sent,
+	/// Not sent, the peer is known to have it already.
+	SkippedAlreadyHave,
+	/// Sending failed.
+	Failed(Error),
+}
+
+/// How a block or transaction broadcast went across all our peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BroadcastStats {
+	pub sent: u32,
+	pub skipped: u32,
+	pub failed: u32,
+	/// Peers left out as they only just connected.
+	pub warming_up: u32,
+}
+
+/// What to do with a peer whose handshake nonce we recently saw coming from
+/// another address, a sign of a single node posing as many or of a
+/// misconfiguration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateNonce {
+	/// Accept the peer, flagging it in its info.
+	Flag,
+	/// Refuse the peer.
+	Reject,
+}
+
+/// Which address family to dial first when a peer can be reached over both
+/// IPv4 and IPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialPreference {
+	/// Try the IPv4 addresses first.
+	PreferV4,
+	/// Try the IPv6 addresses first.
+	PreferV6,
+	/// Race both families, the IPv6 addresses getting a short head start.
+	HappyEyeballs,
+}
+
+/// What to do with the blocks a peer pushes without us asking for them, a
+/// quick way to learn about a new tip but also to spam us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsolicitedBlocks {
+	/// Process them like any other block.
+	Accept,
+	/// Process up to the provided number per minute from each peer, dropping
+	/// the others.
+	RateLimited(u32),
+	/// Only process their header, asking for the block if still missing.
+	HeadersFirst,
+}
+
+/// What to do with a block whose parent we don't know, a sign the peer that
+/// sent it is ahead of us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanBlocks {
+	/// Ask the peer for the block we miss to connect it, its parent unless
+	/// we keep that one as an orphan too.
+	RequestParent,
+	/// Ask the peer for the headers following our head, to fill the whole gap
+	/// at once, along with the blocks we miss.
+	RequestHeaders,
+	/// Only count the orphan.
+	Ignore,
+}
+
+/// What to do with a peer sending more than MAX_PEER_ADDRS addresses at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizedAddrs {
+	/// Keep the first MAX_PEER_ADDRS addresses, ignoring the others.
+	Truncate,
+	/// Take it as a protocol violation, banning and disconnecting the peer.
+	Disconnect,
+}
+
+/// How the protocol of every peer frames its messages and handles what the
+/// peer sends us, the same for all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolConfig {
+	/// Magic bytes starting every message, see P2PConfig::magic.
+	pub magic: [u8; 2],
+	/// What to do with the blocks peers push to us.
+	pub unsolicited_blocks: UnsolicitedBlocks,
+	/// What to do with peers sending too many addresses.
+	pub oversized_addrs: OversizedAddrs,
+	/// What to do with the blocks whose parent we don't know.
+	pub orphan_blocks: OrphanBlocks,
+	/// Whether the messages we can't decode get logged in hex.
+	pub dumps: bool,
+	/// Misbehavior score at which a peer gets banned, zero for never.
+	pub ban_score: u32,
+}
+
+impl Default for ProtocolConfig {
+	fn default() -> ProtocolConfig {
+		ProtocolConfig {
+			magic: ChainTypes::Testnet.params().magic,
+			unsolicited_blocks: UnsolicitedBlocks::Accept,
+			oversized_addrs: OversizedAddrs::Truncate,
+			orphan_blocks: OrphanBlocks::RequestParent,
+			dumps: false,
+			ban_score: 0,
+		}
+	}
+}
+
+/// What to do with a peer sending us more than the inbound limits allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExcessInbound {
+	/// Hold off reading from the peer until it's back within the limits.
+	Throttle,
+	/// Disconnect the peer once it's more than a burst worth over the limits,
+	/// holding off reading from it until then.
+	Disconnect,
+}
+
+/// Caps on what any single peer can send us, zero for no cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InboundLimits {
+	/// Bytes per second.
+	pub max_rate: u64,
+	/// Messages per second.
+	pub max_msgs: u64,
+	/// Seconds worth of either a peer can send in a burst before the caps
+	/// kick in, at least one.
+	pub burst_secs: u64,
+	pub excess: ExcessInbound,
+}
+
+impl InboundLimits {
+	/// Whether none of the caps is set.
+	pub fn unlimited(&self) -> bool {
+		self.max_rate == 0 && self.max_msgs == 0
+	}
+}
+
+/// How the connections to our peers get secured with TLS. Identities are
+/// PKCS#12 archives, bundling our certificate with its private key.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+	/// Path of the PKCS#12 archive with our certificate and key.
+	pub identity_path: String,
+	/// Password the archive is encrypted with.
+	pub identity_password: String,
+	/// Path of a DER certificate the certificates of our peers have to be
+	/// signed with, like a self-signed one shared by all our nodes, whether we
+	/// connect to them or they connect to us. The system's trusted roots are
+	/// used if not set.
+	pub trusted_cert_path: Option<String>,
+	/// Name the certificates of our peers have to be issued for, both ways.
+	pub domain: String,
+	/// Whether peers that can't encrypt get refused. Otherwise the
+	/// connections to them stay in plaintext.
+	pub required: bool,
+}
+
+/// SOCKS5 proxy our outbound connections go through, like the one Tor runs.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+	/// Address the proxy listens on.
+	pub addr: SocketAddr,
+	/// User name and password to authenticate with, if the proxy requires
+	/// them.
+	pub auth: Option<(String, String)>,
+	/// Address of our own onion service, advertised to the peers we connect
+	/// to. They're told nothing of where to reach us otherwise, our real
+	/// address staying hidden behind the proxy.
+	pub onion: Option<SocketAddr>,
+}
+
+/// Dandelion relay of new transactions, hiding which node they come from:
+/// they first go along a stem, each node passing them on to a single peer,
+/// before one of them broadcasts them to all (the fluff).
+#[derive(Debug, Clone)]
+pub struct DandelionConfig {
+	/// Seconds we keep stemming transactions to the same peer, before picking
+	/// another one among our outbound peers.
+	pub epoch_secs: u64,
+	/// Chance in percent that a transaction stemmed to us gets broadcast
+	/// rather than passed on, setting how long stems get.
+	pub fluff_percent: u8,
+	/// Seconds after which a transaction we passed on along the stem gets
+	/// broadcast by us, unless we saw it broadcast meanwhile, so a peer
+	/// dropping it doesn't lose it. Each transaction waits a random time
+	/// between one and two times this.
+	pub embargo_secs: u64,
+}
+
+impl Default for DandelionConfig {
+	fn default() -> DandelionConfig {
+		DandelionConfig {
+			epoch_secs: 600,
+			fluff_percent: 10,
+			embargo_secs: 30,
+		}
+	}
+}
+
+/// Configuration for the peer-to-peer server. The fields also found in
+/// P2PConfigRuntime can be changed while the server runs, the others only
+/// apply on start.
+#[derive(Debug, Clone)]
 pub struct P2PConfig {
 	pub host: IpAddr,
 	pub port: u16,
+	/// Magic bytes starting every message we exchange with our peers, those
+	/// of the network we run on.
+	pub magic: [u8; 2],
+	/// Hash of the genesis block of our chain, peers telling another one in
+	/// their handshake being on another network.
+	pub genesis: Hash,
+	/// Whether we accept inbound connections at all. When disabled nothing is
+	/// bound and we only connect out, advertising no address to our peers.
+	pub inbound_enabled: bool,
+	/// Additional addresses to accept peer connections on. A listener failing
+	/// gets dropped without affecting the others.
+	pub extra_listeners: Vec<SocketAddr>,
+	/// Services we advertise to our peers.
+	pub services: Services,
+	/// Path of a Unix domain socket accepting line-based admin commands, no
+	/// control listener is started if not set.
+	pub control_socket: Option<String>,
+	/// Secures the connections to and from the peers that can encrypt too
+	/// with TLS, as negotiated in the handshake. Plaintext if not set.
+	pub tls: Option<TlsConfig>,
+	/// Proxy all the connections we open go through, which also makes onion
+	/// services reachable. The bind_addr is ignored then.
+	pub proxy: Option<ProxyConfig>,
+	/// Sets SO_REUSEADDR on the listener so a quick restart isn't refused
+	/// while the port is still in TIME_WAIT.
+	pub reuse_addr: bool,
+	/// Sets SO_REUSEPORT on the listener, only available on Unix.
+	pub reuse_port: bool,
+	/// Rate of inbound connections per second above which we wait a small
+	/// random delay before greeting new peers, smoothing reconnect storms. Zero
+	/// disables the delay.
+	pub greeting_delay_rate: u32,
+	/// Maximum delay before greeting a new peer, in milliseconds.
+	pub greeting_delay_max: u64,
+	/// Cap on the bytes per second sent to all peers combined, zero for no
+	/// limit.
+	pub max_outbound_rate: u64,
+	/// Cap on the bytes per second sent to any single peer, zero for no limit.
+	pub max_peer_outbound_rate: u64,
+	/// Caps on the bytes and messages per second any single peer can send us.
+	pub inbound_limits: InboundLimits,
+	/// Seconds a single write to a peer can take before we give up on a peer
+	/// not reading fast enough and disconnect it, zero for no limit.
+	pub send_timeout_secs: u64,
+	/// Milliseconds the messages queued for a peer we disconnect from
+	/// gracefully get to go out before the connection is closed anyway.
+	pub close_flush_ms: u64,
+	/// Seconds between the automatic prunings of the peers we lost connection
+	/// to, zero to leave it to explicit calls to clean_peers.
+	pub clean_peers_interval_secs: u64,
+	/// Bounds in seconds of the interval each peer gets pinged at, widening
+	/// as it answers promptly and narrowing when it doesn't. A zero minimum
+	/// turns pings off.
+	pub ping_interval_min_secs: u64,
+	pub ping_interval_max_secs: u64,
+	/// Time in milliseconds after which a pong counts as slow.
+	pub ping_slow_ms: u64,
+	/// Pongs a peer can miss in a row before clean_peers disconnects it,
+	/// zero to keep peers however many they miss.
+	pub ping_max_missed: u32,
+	/// Number of bits the total difficulty advertised by a peer can take,
+	/// anything above gets clamped to the largest value that fits.
+	pub max_difficulty_bits: usize,
+	/// Local address our outbound connections originate from, left to the
+	/// routing table if not set.
+	pub bind_addr: Option<IpAddr>,
+	/// Window over which peer connections and disconnections are counted to
+	/// measure churn, in seconds.
+	pub churn_window: u64,
+	/// Number of connections and disconnections within the churn window above
+	/// which a warning gets logged, zero to never warn.
+	pub churn_alarm: u32,
+	/// Sessions with a peer lasting less than this many seconds count as the
+	/// peer failing soon after connecting.
+	pub flaky_session_secs: u64,
+	/// Short sessions in a row after which we back off from connecting to a
+	/// peer, zero to never back off.
+	pub flaky_threshold: u32,
+	/// First backoff from a flaky peer in seconds, doubling with each further
+	/// short session.
+	pub flaky_backoff_secs: u64,
+	/// Short sessions in a row after which a flaky peer gets quarantined.
+	pub flaky_quarantine: u32,
+	/// Duration of a successful handshake above which the peer is flagged as
+	/// slow, in milliseconds.
+	pub slow_handshake_ms: u64,
+	/// Seconds an inbound or outbound peer gets to complete the handshake,
+	/// connecting included when dialing, before we give up on it. To raise on
+	/// high latency links like Tor.
+	pub handshake_timeout_secs: u64,
+	/// Maximum difference in seconds between the clock of a peer and ours,
+	/// told during the handshake, peers further off get refused. Zero to
+	/// accept any clock.
+	pub max_clock_skew_secs: u64,
+	/// Maximum number of inbound handshakes in progress at once, we stop
+	/// accepting new connections until one completes. At least one.
+	pub max_handshakes: usize,
+	/// Maximum number of inbound peers, including the reserved slots. When
+	/// they're all taken, a host scoring better than the worst inbound peer
+	/// evicts it, other connections get dropped.
+	pub max_inbound_peers: u32,
+	/// Maximum number of outbound peers. When they're all connected, an
+	/// address scoring better than the worst outbound peer evicts it, other
+	/// dials are turned down until one of them disconnects.
+	pub max_outbound_peers: u32,
+	/// Number of inbound slots only preferred peers can fill. When all slots
+	/// are taken, a preferred peer connecting evicts a non-preferred one.
+	pub reserved_slots: u32,
+	/// Addresses of peers allowed in the reserved slots.
+	pub preferred_peers: Vec<IpAddr>,
+	/// Peers we always try to stay connected to, dialed on start and again
+	/// whenever the connection fails or drops.
+	pub persistent_peers: Vec<SocketAddr>,
+	/// Time in milliseconds before dialing a persistent peer again after
+	/// failing to, doubling with each failure until the connection holds.
+	pub persistent_retry_ms: u64,
+	/// Longest time in milliseconds between two attempts to dial a
+	/// persistent peer.
+	pub persistent_retry_max_ms: u64,
+	/// Time in milliseconds after the handshake during which a peer is left
+	/// out of block and transaction broadcasts, giving it time to settle.
+	/// Preferred peers are never left out.
+	pub broadcast_warmup_ms: u64,
+	/// Number of peers each block, header or transaction broadcast goes to,
+	/// on top of the preferred peers which always get it, as do our most
+	/// worked peers for blocks and headers. Peers take turns, those left out
+	/// the longest going first. Zero for all our peers.
+	pub broadcast_fanout: u32,
+	/// Maximum number of addresses sent in response to a single peer
+	/// addresses request, at most MAX_PEER_ADDRS.
+	pub max_gossip_addrs: u32,
+	/// Time in seconds after which a peer whose total difficulty hasn't
+	/// increased is passed over when looking for the most worked peer, zero
+	/// to never consider peers stale.
+	pub stale_difficulty_secs: u64,
+	/// Time in seconds a quarantined peer is kept away for before being
+	/// allowed back.
+	pub quarantine_secs: u64,
+	/// Time in seconds a banned peer is kept away for, zero to keep it away
+	/// until lifted with Server::unban.
+	pub ban_secs: u64,
+	/// Number of worker threads handing received blocks to the adapter, zero
+	/// to hand them right away from the event loop.
+	pub block_workers: u32,
+	/// Number of received blocks, or batches of blocks, waiting for each
+	/// block worker before the reads of its peers sending more get paused.
+	pub block_queue_size: u32,
+	/// Whether addresses in private ranges gossiped by our peers are kept, to
+	/// turn off on public networks.
+	pub allow_private_addrs: bool,
+	/// Optional protocol features we support and advertise to our peers.
+	/// Compact blocks are left out by default, they're only worth it to an
+	/// adapter with a transaction pool to rebuild them from.
+	pub features: Features,
+	/// Maximum number of outbound dials in flight at once, from opening the
+	/// connection to the end of the handshake. Further dials wait their turn.
+	pub max_concurrent_dials: u32,
+	/// Minimum time in milliseconds between two outbound dials, so many
+	/// addresses to dial at once don't go out in a burst looking like a port
+	/// scan. Zero dials as soon as a dial slot is free.
+	pub min_dial_interval_ms: u64,
+	/// Inbound peer limits by capability class, peers advertising exactly the
+	/// capabilities of a class counting towards its limit. Once it's reached,
+	/// further peers of the class get disconnected right after the handshake.
+	pub inbound_capability_limits: Vec<(Capabilities, u32)>,
+	/// Number of distinct subnets (/16 for IPv4) our outbound peers should
+	/// come from, so they can't all be controlled by a single party. Below
+	/// that, more peers get dialed in the subnets we're missing.
+	pub min_outbound_subnets: u32,
+	/// Maximum number of block requests in flight to a single peer when
+	/// picking one to download blocks from, zero for no limit. The peer gets
+	/// picked again as its blocks come back.
+	pub max_block_requests: u32,
+	/// What to do with inbound peers reusing the handshake nonce of a peer at
+	/// another address.
+	pub duplicate_nonce: DuplicateNonce,
+	/// Address family dialed first when connecting to any of several
+	/// addresses of both families.
+	pub dial_preference: DialPreference,
+	/// What to do with the blocks peers push without us asking for them.
+	pub unsolicited_blocks: UnsolicitedBlocks,
+	/// What to do with peers sending more than MAX_PEER_ADDRS addresses.
+	pub oversized_addrs: OversizedAddrs,
+	/// What to do with the blocks peers send us whose parent we don't know.
+	pub orphan_blocks: OrphanBlocks,
+	/// Whether the messages peers send us that we can't decode get logged,
+	/// with their type and a hex dump of up to MAX_DUMP_LEN bytes. Verbose
+	/// and showing whatever the peers sent, only meant for debugging.
+	pub dump_invalid_msgs: bool,
+	/// Misbehavior score at which a peer gets disconnected and its host
+	/// quarantined, the score adding up the weights of the peer's protocol
+	/// violations, see Violation::score. Zero never bans.
+	pub ban_score: u32,
+	/// Relays our new transactions, and those stemmed to us, along a
+	/// dandelion stem before they get broadcast. Broadcast right away if not
+	/// set.
+	pub dandelion: Option<DandelionConfig>,
+	/// Whether our listening port gets mapped on the gateway of our local
+	/// network with NAT-PMP or UPnP, renewed while we run, so peers outside
+	/// of it can connect to us. The external address the gateway gives us is
+	/// the one we advertise then.
+	pub port_mapping: bool,
+	/// Gateway asked for the port mapping over NAT-PMP, the one of the
+	/// default route if not set.
+	pub nat_gateway: Option<IpAddr>,
 }
 
 /// Default address for peer-to-peer connections.
 impl Default for P2PConfig {
 	fn default() -> P2PConfig {
 		let ipaddr = "127.0.0.1".parse().unwrap();
+		let params = ChainTypes::Testnet.params();
 		P2PConfig {
 			host: ipaddr,
-			port: 13414,
+			port: params.p2p_port,
+			magic: params.magic,
+			genesis: genesis::genesis_for(&params).hash(),
+			inbound_enabled: true,
+			extra_listeners: vec![],
+			services: ALL_SERVICES,
+			control_socket: None,
+			tls: None,
+			proxy: None,
+			reuse_addr: true,
+			reuse_port: false,
+			greeting_delay_rate: 50,
+			greeting_delay_max: 500,
+			max_outbound_rate: 0,
+			max_peer_outbound_rate: 0,
+			inbound_limits: InboundLimits {
+				max_rate: 0,
+				max_msgs: 0,
+				burst_secs: 5,
+				excess: ExcessInbound::Throttle,
+			},
+			send_timeout_secs: 30,
+			close_flush_ms: 2000,
+			clean_peers_interval_secs: 30,
+			ping_interval_min_secs: 10,
+			ping_interval_max_secs: 120,
+			ping_slow_ms: 1000,
+			ping_max_missed: 3,
+			max_difficulty_bits: 256,
+			bind_addr: None,
+			churn_window: 60,
+			churn_alarm: 100,
+			flaky_session_secs: 30,
+			flaky_threshold: 3,
+			flaky_backoff_secs: 60,
+			flaky_quarantine: 6,
+			slow_handshake_ms: 2000,
+			handshake_timeout_secs: 5,
+			max_clock_skew_secs: 600,
+			max_handshakes: 64,
+			max_inbound_peers: 64,
+			max_outbound_peers: 32,
+			reserved_slots: 0,
+			preferred_peers: vec![],
+			persistent_peers: vec![],
+			persistent_retry_ms: 5000,
+			persistent_retry_max_ms: 300_000,
+			broadcast_warmup_ms: 0,
+			broadcast_fanout: 0,
+			max_gossip_addrs: 200,
+			stale_difficulty_secs: 600,
+			quarantine_secs: 3600,
+			ban_secs: 86400,
+			block_workers: 0,
+			block_queue_size: 32,
+			allow_private_addrs: true,
+			features: ALL_FEATURES - COMPACT_BLOCKS,
+			max_concurrent_dials: 8,
+			min_dial_interval_ms: 0,
+			inbound_capability_limits: vec![],
+			min_outbound_subnets: 4,
+			max_block_requests: 4,
+			duplicate_nonce: DuplicateNonce::Flag,
+			dial_preference: DialPreference::PreferV4,
+			unsolicited_blocks: UnsolicitedBlocks::Accept,
+			oversized_addrs: OversizedAddrs::Truncate,
+			orphan_blocks: OrphanBlocks::RequestParent,
+			dump_invalid_msgs: false,
+			ban_score: 100,
+			dandelion: None,
+			port_mapping: false,
+			nat_gateway: None,
 		}
 	}
 }
 
+impl P2PConfig {
+	/// The part of the configuration that can be changed while running.
+	pub fn runtime(&self) -> P2PConfigRuntime {
+		P2PConfigRuntime {
+			max_inbound_peers: self.max_inbound_peers,
+			max_outbound_peers: self.max_outbound_peers,
+			reserved_slots: self.reserved_slots,
+			max_concurrent_dials: self.max_concurrent_dials,
+			greeting_delay_rate: self.greeting_delay_rate,
+			greeting_delay_max: self.greeting_delay_max,
+			max_peer_outbound_rate: self.max_peer_outbound_rate,
+			churn_alarm: self.churn_alarm,
+			stale_difficulty_secs: self.stale_difficulty_secs,
+			quarantine_secs: self.quarantine_secs,
+			ban_secs: self.ban_secs,
+		}
+	}
+}
+
+/// Limits of the peer-to-peer server that can be changed while it runs
+/// through Server::update_config, see P2PConfig for what each of them
+/// means. Peers already connected are left alone: a lower peer limit only
+/// refuses new connections and a new rate cap only applies to peers
+/// connecting afterwards, provided some rate cap was set on start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct P2PConfigRuntime {
+	pub max_inbound_peers: u32,
+	pub max_outbound_peers: u32,
+	pub reserved_slots: u32,
+	pub max_concurrent_dials: u32,
+	pub greeting_delay_rate: u32,
+	pub greeting_delay_max: u64,
+	pub max_peer_outbound_rate: u64,
+	pub churn_alarm: u32,
+	pub stale_difficulty_secs: u64,
+	pub quarantine_secs: u64,
+	pub ban_secs: u64,
+}
+
 bitflags! {
   /// Options for what type of interaction a peer supports
   pub flags Capabilities: u32 {
@@ -92,57 +750,407 @@ bitflags! {
     const UTXO_HIST = 0b00000010,
     /// Can provide a list of healthy peers
     const PEER_LIST = 0b00000100,
+    /// Can encrypt the connection with TLS once the handshake is done, or
+    /// before it when connected to by a peer knowing we can.
+    const ENCRYPTED = 0b00001000,
+    /// Can send the UTXO set at a recent horizon block, in chunks, for new
+    /// nodes to sync from instead of downloading every full block.
+    const UTXO_SNAPSHOT = 0b00010000,
+
+    const FULL_NODE = FULL_HIST.bits | UTXO_HIST.bits | PEER_LIST.bits | UTXO_SNAPSHOT.bits,
+  }
+}
+
+bitflags! {
+  /// Granular services a peer offers to others, requests only get routed to
+  /// peers offering what they need.
+  pub flags Services: u32 {
+    /// Doesn't serve anything.
+    const NO_SERVICES = 0b00000000,
+    /// Serves block headers.
+    const SERVES_HEADERS = 0b00000001,
+    /// Serves full blocks, at least the recent ones.
+    const SERVES_BLOCKS = 0b00000010,
+    /// Serves transactions from its pool.
+    const SERVES_MEMPOOL = 0b00000100,
+    /// Keeps all full blocks, including those a pruned node discarded.
+    const ARCHIVAL = 0b00001000,
+
+    const ALL_SERVICES = SERVES_HEADERS.bits | SERVES_BLOCKS.bits | SERVES_MEMPOOL.bits |
+      ARCHIVAL.bits,
+  }
+}
 
-    const FULL_NODE = FULL_HIST.bits | UTXO_HIST.bits | PEER_LIST.bits,
+bitflags! {
+  /// Optional protocol behaviors, negotiated right after the handshake so new
+  /// ones can be rolled out without bumping the protocol version. Only those
+  /// both sides support are used.
+  pub flags Features: u32 {
+    /// No optional behavior.
+    const NO_FEATURES = 0b00000000,
+    /// Requested blocks can be sent back together in a single message.
+    const BLOCK_BATCHES = 0b00000001,
+    /// Transactions can be announced and requested by hash.
+    const TX_INV = 0b00000010,
+    /// Every message body is followed by its checksum.
+    const CHECKSUMS = 0b00000100,
+    /// Blocks can be relayed compact, rebuilt from the pool transactions.
+    const COMPACT_BLOCKS = 0b00001000,
+    /// Transactions can be relayed along a dandelion stem.
+    const DANDELION = 0b00010000,
+
+    const ALL_FEATURES = BLOCK_BATCHES.bits | TX_INV.bits | CHECKSUMS.bits |
+                         COMPACT_BLOCKS.bits | DANDELION.bits,
+    /// Features changing how messages are framed, only switched to once both
+    /// sides confirmed them.
+    const UPGRADES = CHECKSUMS.bits,
   }
 }
 
+/// Number of blocks behind the head a pruned node is still expected to have
+/// in full.
+pub const PRUNED_BLOCKS_HORIZON: u64 = 1440;
+
+impl Services {
+	/// Services needed from a peer to get the full block at the provided
+	/// height, given the height of our head. Blocks past the pruning horizon
+	/// can only come from archival nodes.
+	pub fn for_block(height: u64, head: u64) -> Services {
+		if head.saturating_sub(height) > PRUNED_BLOCKS_HORIZON {
+			SERVES_BLOCKS | ARCHIVAL
+		} else {
+			SERVES_BLOCKS
+		}
+	}
+
+	/// Whether all the needed services are offered.
+	pub fn offers(&self, needed: Services) -> bool {
+		self.contains(needed)
+	}
+}
+
+/// Whether a connection was initiated by the remote peer or by us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+	Inbound,
+	Outbound,
+}
+
+/// How harshly a misbehaving peer is kept away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+	/// Kept away for the configured ban time, or until lifted by hand.
+	Ban,
+	/// Neither dialed nor accepted for a while, then allowed back.
+	Quarantine,
+}
+
+/// Kind of protocol violation a peer committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+	/// A message listing more items than allowed.
+	Oversized,
+	/// A message or message header we couldn't decode.
+	Undecodable,
+	/// A batch of headers not chaining up.
+	BrokenHeaderChain,
+	/// A page of headers asked as the one following the previous page that
+	/// connects to nothing we asked from.
+	HeadersPageBreak,
+	/// A message body not matching its checksum.
+	BadChecksum,
+	/// More than MAX_PEER_ADDRS addresses at once, truncated.
+	OversizedAddrs,
+	/// More unsolicited blocks than allowed per minute.
+	UnsolicitedFlood,
+	/// A block the adapter found invalid.
+	InvalidBlock,
+	/// A handshake message we couldn't decode.
+	MalformedHandshake,
+	/// A total difficulty claimed in a pong the headers sent since fell
+	/// short of.
+	UnbackedDifficulty,
+}
+
+impl Violation {
+	/// How much the violation adds to the misbehavior score of a peer, the
+	/// peer being banned once its score reaches the configured ban score.
+	/// Corruption in transit or an address list a bit long can be honest
+	/// mistakes, invalid blocks hardly.
+	pub fn score(&self) -> u32 {
+		match *self {
+			Violation::BadChecksum => 5,
+			Violation::OversizedAddrs => 10,
+			Violation::HeadersPageBreak => 10,
+			Violation::UnbackedDifficulty => 10,
+			Violation::UnsolicitedFlood => 10,
+			Violation::Oversized => 20,
+			Violation::Undecodable => 20,
+			Violation::MalformedHandshake => 20,
+			Violation::BrokenHeaderChain => 50,
+			Violation::InvalidBlock => 50,
+		}
+	}
+}
+
+/// What the adapter made of a block one of our peers sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+	/// The block is valid, whether we already had it or not.
+	Accepted,
+	/// Its parent is unknown to us. The block we miss to connect it, its
+	/// parent or one further down when we keep the orphans in between.
+	Orphan(Hash),
+	/// The block is invalid, the peer misbehaved sending it.
+	Invalid,
+	/// We couldn't tell, the block not fitting on our chain or our store
+	/// failing. The peer neither gets credit nor blame for it.
+	Unprocessed,
+}
+
+/// A protocol violation of a peer and when it was committed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ViolationRecord {
+	pub violation: Violation,
+	/// Seconds since the epoch.
+	pub at: i64,
+}
+
+static NEXT_PEER_SEQ: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Identifier of a peer connection that stays the same whatever the address
+/// of the peer, unique until we restart. Assigned in sequence as connections
+/// open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PeerId(pub usize);
+
+impl fmt::Display for PeerId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+/// Short identifier of a peer connection, prefixed to all the log lines about
+/// it so a single peer's lifecycle can be followed. Made of the remote address
+/// and the peer id, as the same address can connect several times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerLogId {
+	label: String,
+	id: PeerId,
+}
+
+impl PeerLogId {
+	/// New identifier for a connection with the provided remote address.
+	pub fn new(addr: SocketAddr) -> PeerLogId {
+		let id = PeerId(NEXT_PEER_SEQ.fetch_add(1, Ordering::Relaxed));
+		PeerLogId {
+			label: format!("{}#{}", addr, id),
+			id: id,
+		}
+	}
+
+	/// Id of the peer connection.
+	pub fn peer_id(&self) -> PeerId {
+		self.id
+	}
+}
+
+impl fmt::Display for PeerLogId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "[{}]", self.label)
+	}
+}
+
 /// General information about a connected peer that's useful to other modules.
 #[derive(Debug)]
 pub struct PeerInfo {
+	/// Identifies the peer connection, unlike its address.
+	pub id: PeerId,
 	pub capabilities: Capabilities,
+	pub services: Services,
 	pub user_agent: String,
+	/// Latest protocol version the peer advertised, it may support features
+	/// newer than what we speak with it.
 	pub version: u32,
+	/// Protocol version we speak with the peer, the latest both sides
+	/// support.
+	pub negotiated_version: u32,
 	pub addr: SocketAddr,
+	/// Address at the other end of our connection to the peer, with the IP
+	/// the peer really connects from whatever it advertises.
+	pub remote_addr: Option<SocketAddr>,
 	pub total_difficulty: Difficulty,
+	pub direction: Direction,
+	/// Whether the handshake took longer than the configured threshold.
+	pub slow_handshake: bool,
+	/// Prefix of the log lines about this peer.
+	pub log_id: PeerLogId,
+	/// Whether the peer accepts connections at its address, outbound-only
+	/// peers don't advertise any.
+	pub reachable: bool,
+	/// Optional features both the peer and us support.
+	pub features: Features,
+	/// Whether the peer's handshake nonce was recently used by a peer at
+	/// another address.
+	pub duplicate_nonce: bool,
+	/// Address the peer sees us at, as told in its handshake. When we
+	/// connected to it, the IP it sees our connection coming from with the
+	/// port we advertised.
+	pub observed_addr: Option<SocketAddr>,
+	/// How many seconds the clock of the peer was ahead of ours during the
+	/// handshake, negative if behind, zero if its version doesn't tell.
+	pub clock_skew: i64,
+	/// Whether the peer sent us any message since the handshake, showing it
+	/// didn't go silent right after. Set by the protocol.
+	pub verified: Arc<AtomicBool>,
+	/// Status of its chain the peer claimed in its latest pong, none until
+	/// it answered a ping with one. Dropped by the protocol once the peer's
+	/// headers fall short of it.
+	pub chain_status: Arc<Mutex<Option<ChainStatus>>>,
+	/// Bytes and messages sent to and received from the peer since the
+	/// handshake, or the last reset of the stats. Counted by the connection.
+	pub traffic: Arc<Traffic>,
 }
 
+impl PeerInfo {
+	/// Address to reach the peer at: the IP its connection really comes
+	/// from, with the port it advertises. The advertised address if we
+	/// couldn't tell.
+	pub fn node_addr(&self) -> SocketAddr {
+		SocketAddr::new(self.remote_addr.map(|r| r.ip()).unwrap_or(self.addr.ip()),
+		                self.addr.port())
+	}
+}
+
+/// Pending ping of ours, resolving to the round trip time and the status of
+/// the chain of the remote peer once it answers, if it sent one.
+pub type PingFuture = Box<Future<Item = (Duration, Option<ChainStatus>), Error = Error>>;
+
 /// A given communication protocol agreed upon between 2 peers (usually
 /// ourselves and a remote) after handshake. This trait is necessary to allow
 /// protocol negotiation as it gets upgraded to multiple versions.
-pub trait Protocol {
+pub trait Protocol: Sync + Send {
 	/// Starts handling protocol communication, the connection) is expected to
 	/// be  known already, usually passed during construction. Will typically
 	/// block so needs to be called withing a coroutine. Should also be called
 	/// only once.
 	fn handle(&self,
-	          conn: TcpStream,
-	          na: Arc<NetAdapter>)
+	          conn: PeerStream,
+	          na: Arc<NetAdapter>,
+	          throttle: Throttle,
+	          blocks: Option<Arc<BlockPool>>,
+	          traffic: Arc<Traffic>,
+	          local: Arc<LocalStatus>)
 	          -> Box<Future<Item = (), Error = Error>>;
 
 	/// Sends a ping message to the remote peer.
 	fn send_ping(&self) -> Result<(), Error>;
 
+	/// Pings the remote peer with the status of our chain, resolving once its
+	/// pong comes back to the round trip time and the status of its chain,
+	/// if it sent one.
+	fn ping(&self, status: ChainStatus) -> Result<PingFuture, Error>;
+
 	/// Relays a block to the remote peer.
 	fn send_block(&self, b: &core::Block) -> Result<(), Error>;
 
+	/// Relays a block to the remote peer without the transactions it should
+	/// have in its pool already, leaving it to rebuild the block.
+	fn send_compact_block(&self, b: &core::Block) -> Result<(), Error>;
+
 	/// Relays a transaction to the remote peer.
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
 
+	/// Passes a transaction on to the remote peer along a dandelion stem.
+	fn send_stem_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
+
+	/// Announces a block by its hash to the remote peer, which will ask for
+	/// it if it doesn't have it.
+	fn send_block_inv(&self, h: Hash) -> Result<(), Error>;
+
+	/// Announces a block by its header to the remote peer, which will ask for
+	/// the full block if it doesn't have it.
+	fn send_header(&self, bh: &core::BlockHeader) -> Result<(), Error>;
+
+	/// Announces a transaction by its hash to the remote peer, which will ask
+	/// for it if it doesn't have it.
+	fn send_transaction_inv(&self, h: Hash) -> Result<(), Error>;
+
 	/// Sends a request for block headers based on the provided block locator.
 	fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error>;
 
 	/// Sends a request for a block from its hash.
 	fn send_block_request(&self, h: Hash) -> Result<(), Error>;
 
+	/// Number of block requests sent to the remote peer still waiting for
+	/// their block.
+	fn blocks_in_flight(&self) -> usize;
+
 	/// Sends a request for some peer addresses.
 	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error>;
 
+	/// Asks the remote peer what it reports about itself, resolving to its
+	/// response. Asked again too soon, peers repeat their previous answer.
+	fn send_peer_info_request(&self)
+	                          -> Result<Box<Future<Item = PeerInfoResp, Error = Error>>, Error>;
+
+	/// Asks the remote peer for checkpoints along its chain, resolving to
+	/// those it reports.
+	fn send_checkpoints_request(&self)
+		-> Result<Box<Future<Item = Vec<Checkpoint>, Error = Error>>, Error>;
+
+	/// Asks the remote peer for the chunk with the provided index of its UTXO
+	/// set snapshot at the horizon block, resolving to the chunk it sends.
+	fn send_utxo_chunk_request(&self,
+	                           horizon: Hash,
+	                           chunk: u32)
+	                           -> Result<Box<Future<Item = UtxoChunk, Error = Error>>, Error>;
+
 	/// How many bytes have been sent/received to/from the remote peer.
 	fn transmitted_bytes(&self) -> (u64, u64);
 
+	/// How many messages have been sent/received to/from the remote peer.
+	fn transmitted_msgs(&self) -> (u64, u64);
+
+	/// How many orphan blocks (with a parent we don't know) the remote peer
+	/// sent us.
+	fn orphan_count(&self) -> u64;
+
+	/// Zeroes the bytes transmitted and the orphan blocks counted so far.
+	fn reset_stats(&self);
+
+	/// Latest protocol violations of the remote peer, oldest first.
+	fn violations(&self) -> Vec<ViolationRecord>;
+
+	/// Number of protocol violations of the remote peer, including those too
+	/// old to still be listed.
+	fn violation_count(&self) -> u64;
+
+	/// Misbehavior score of the remote peer, adding up its violations.
+	fn misbehavior_score(&self) -> u32;
+
+	/// Highest total difficulty the remote peer showed us since the handshake,
+	/// along with when it last increased.
+	fn total_difficulty(&self) -> (Difficulty, Instant);
+
+	/// Hash and total difficulty of the block or header the remote peer
+	/// showed the highest total difficulty with, none until it showed one.
+	fn tip(&self) -> Option<(Hash, Difficulty)>;
+
+	/// Whether the remote peer is known to have the block, either because it
+	/// sent it to us or because we already sent it.
+	fn knows_block(&self, h: Hash) -> bool;
+
+	/// Whether the remote peer is known to have the transaction, because it
+	/// sent or announced it to us or because we already sent it.
+	fn knows_transaction(&self, h: Hash) -> bool;
+
 	/// Close the connection to the remote peer.
 	fn close(&self);
+
+	/// Close the connection to the remote peer once the messages already
+	/// queued for it went out, or after the timeout.
+	fn close_gracefully(&self, timeout: Duration);
 }
 
 /// Bridge between the networking layer and the rest of the system. Handles the
@@ -152,11 +1160,41 @@ pub trait NetAdapter: Sync + Send {
 	/// Current height of our chain.
 	fn total_difficulty(&self) -> Difficulty;
 
+	/// Hash of the block at the head of our chain.
+	fn head_hash(&self) -> Hash;
+
+	/// Height of the block at the head of our chain.
+	fn head_height(&self) -> u64;
+
+	/// Checkpoints along our chain we report to peers asking for them, at
+	/// most MAX_CHECKPOINTS.
+	fn checkpoints(&self) -> Vec<Checkpoint>;
+
+	/// Checkpoints a majority of our peers agree on, forks not going through
+	/// them can be dropped early.
+	fn checkpoints_agreed(&self, checkpoints: Vec<Checkpoint>);
+
+	/// Services we can offer at our current total difficulty given what our
+	/// chain holds, a pruned chain can't serve archival blocks for example.
+	/// Advertised in handshakes along with the total difficulty, within the
+	/// configured services.
+	fn services(&self) -> Services;
+
 	/// A valid transaction has been received from one of our peers
 	fn transaction_received(&self, tx: core::Transaction);
 
-	/// A block has been received from one of our peers
-	fn block_received(&self, b: core::Block);
+	/// A transaction has been stemmed to us by one of our peers, to be passed
+	/// on along the stem with Server::relay_stem_transaction if valid.
+	fn stem_transaction_received(&self, tx: core::Transaction);
+
+	/// A transaction stemmed to us, or by us, is getting broadcast at the end
+	/// of its stem or of its embargo, no longer to be kept from the public.
+	fn transaction_fluffed(&self, tx: &core::Transaction);
+
+	/// A block has been received from the peer at the provided address. Tells
+	/// whether the block got accepted, is an orphan or is invalid, the latter
+	/// counting as a violation of the peer.
+	fn block_received(&self, b: core::Block, src: SocketAddr) -> BlockStatus;
 
 	/// A set of block header has been received, typically in response to a
 	/// block
@@ -171,13 +1209,59 @@ pub trait NetAdapter: Sync + Send {
 	/// Gets a full block by its hash.
 	fn get_block(&self, h: Hash) -> Option<core::Block>;
 
+	/// Chunk with the provided index of the unspent outputs of our chain
+	/// right after the horizon block, at most MAX_UTXO_CHUNK of them, along
+	/// with the number of chunks. None if the horizon isn't on our chain or
+	/// is too far back for us.
+	fn utxo_chunk(&self, horizon: Hash, chunk: u32) -> Option<(u32, Vec<core::Output>)>;
+
+	/// Whether we already have the full block with the provided hash.
+	fn has_block(&self, h: Hash) -> bool;
+
+	/// Gets a transaction from the pool by its hash, if we have it.
+	fn get_transaction(&self, h: Hash) -> Option<core::Transaction>;
+
+	/// Gets the transaction from the pool the provided kernel belongs to, if
+	/// we have it, to rebuild a compact block.
+	fn get_kernel_transaction(&self, k: &core::TxKernel) -> Option<core::Transaction>;
+
 	/// Find good peers we know with the provided capability and return their
 	/// addresses.
 	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<SocketAddr>;
 
-	/// A list of peers has been received from one of our peers.
-	fn peer_addrs_received(&self, Vec<SocketAddr>);
+	/// A list of peers has been received from one of our peers, whose address
+	/// is provided as the source, with the IP its connection comes from
+	/// rather than the one it advertises.
+	fn peer_addrs_received(&self, Vec<SocketAddr>, SocketAddr);
 
-	/// Network successfully connected to a peer.
+	/// Network successfully connected to a peer, either accepted or initiated
+	/// by us as told by the direction of the peer info.
 	fn peer_connected(&self, &PeerInfo);
+
+	/// A peer errored out while running our protocol, called before it gets
+	/// removed from our peers.
+	fn peer_error(&self, &PeerInfo, &Error);
+
+	/// Network lost its connection to a peer, whichever end closed it. Comes
+	/// after peer_error when the peer errored out.
+	fn peer_disconnected(&self, &PeerInfo);
+
+	/// The host of the peer at the provided address just got banned or
+	/// quarantined, on our request or for reaching the ban score. Saving
+	/// the ban lets it be restored with Server::restore_ban on restart.
+	fn peer_banned(&self, addr: SocketAddr, ban: &BanEntry);
+
+	/// The ban or quarantine of the provided host got lifted by hand, or
+	/// dropped to make room for newer ones.
+	fn peer_unbanned(&self, ip: IpAddr);
+
+	/// Whether we may connect to or accept a connection from the provided
+	/// address, consulted before our own bans. Lets blocklists or other
+	/// reputation sources be plugged in.
+	fn address_allowed(&self, addr: &SocketAddr) -> bool;
+
+	/// Addresses to bootstrap from along with our seeds, consulted each time
+	/// we bootstrap so a discovery service or the like can supply them at
+	/// runtime.
+	fn bootstrap_addrs(&self) -> Vec<SocketAddr>;
 }
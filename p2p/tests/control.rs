@@ -0,0 +1,111 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_p2p as p2p;
+extern crate tokio_core;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+use tokio_core::reactor::Core;
+
+// Starts the control listener of an idle server and issues a couple commands
+// from two clients connected at once, checking the replies.
+#[test]
+fn control_list() {
+  let evtlp = Core::new().unwrap();
+  let net_adapter = Arc::new(p2p::DummyAdapter{});
+  let server = Arc::new(p2p::Server::new(p2p::UNKNOWN, p2p::P2PConfig::default(), net_adapter));
+
+  let path = "target/p2p-control-test.sock".to_string();
+  p2p::start_control(server.clone(), path.clone(), evtlp.remote()).unwrap();
+
+  let mut stream = UnixStream::connect(&path).unwrap();
+  let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+  // no peers, the reply is only the terminating empty line
+  stream.write_all(b"list\n").unwrap();
+  let mut line = String::new();
+  reader.read_line(&mut line).unwrap();
+  assert_eq!(line, "\n");
+
+  stream.write_all(b"foo\n").unwrap();
+  line.clear();
+  reader.read_line(&mut line).unwrap();
+  assert_eq!(line, "unknown command: foo\n");
+  // terminating empty line
+  line.clear();
+  reader.read_line(&mut line).unwrap();
+
+  // a second client is answered while the first one is still connected
+  let mut other = UnixStream::connect(&path).unwrap();
+  let mut other_reader = BufReader::new(other.try_clone().unwrap());
+  other.write_all(b"stats\n").unwrap();
+  line.clear();
+  other_reader.read_line(&mut line).unwrap();
+  assert_eq!(line, "\n");
+
+  other.write_all(b"stats 127.0.0.1:13801\n").unwrap();
+  line.clear();
+  other_reader.read_line(&mut line).unwrap();
+  assert_eq!(line, "not connected to 127.0.0.1:13801\n");
+
+  // and the first one is still served
+  stream.write_all(b"stats\n").unwrap();
+  line.clear();
+  reader.read_line(&mut line).unwrap();
+  assert_eq!(line, "\n");
+}
+
+// Only the owner can use the socket, and bans apply to hosts we aren't
+// connected to as well.
+#[test]
+fn control_owner_only() {
+  let evtlp = Core::new().unwrap();
+  let net_adapter = Arc::new(p2p::DummyAdapter{});
+  let server = Arc::new(p2p::Server::new(p2p::UNKNOWN, p2p::P2PConfig::default(), net_adapter));
+
+  let path = "target/p2p-control-mode-test.sock".to_string();
+  // a socket left over by a previous start is replaced
+  p2p::start_control(server.clone(), path.clone(), evtlp.remote()).unwrap();
+  p2p::start_control(server.clone(), path.clone(), evtlp.remote()).unwrap();
+  let mode = fs::metadata(&path).unwrap().permissions().mode();
+  assert_eq!(mode & 0o777, 0o600);
+
+  let mut stream = UnixStream::connect(&path).unwrap();
+  let mut reader = BufReader::new(stream.try_clone().unwrap());
+  stream.write_all(b"ban 127.0.0.1:13802\n").unwrap();
+  let mut line = String::new();
+  reader.read_line(&mut line).unwrap();
+  assert_eq!(line, "banned 127.0.0.1:13802 (was not connected)\n");
+  assert_eq!(server.list_bans().len(), 1);
+}
+
+// Anything but a socket at the path is left alone.
+#[test]
+fn control_keeps_other_files() {
+  let evtlp = Core::new().unwrap();
+  let net_adapter = Arc::new(p2p::DummyAdapter{});
+  let server = Arc::new(p2p::Server::new(p2p::UNKNOWN, p2p::P2PConfig::default(), net_adapter));
+
+  let path = "target/p2p-control-file-test.sock".to_string();
+  fs::File::create(&path).unwrap().write_all(b"not a socket").unwrap();
+  assert!(p2p::start_control(server, path.clone(), evtlp.remote()).is_err());
+  let mut content = String::new();
+  fs::File::open(&path).unwrap().read_to_string(&mut content).unwrap();
+  assert_eq!(content, "not a socket");
+}
@@ -52,7 +52,13 @@ fn peer_handshake() {
     let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
     let socket = TcpStream::connect(&addr, &phandle).map_err(|e| p2p::Error::Connection(e));
     socket.and_then(move |socket| {
-      Peer::connect(socket, p2p::UNKNOWN, Difficulty::one(), my_addr, &p2p::handshake::Handshake::new())
+      Peer::connect(socket,
+                    addr,
+                    p2p::UNKNOWN,
+                    Difficulty::one(),
+                    p2p::ALL_SERVICES,
+                    my_addr,
+                    &p2p::handshake::Handshake::new())
 		}).and_then(move |(socket, peer)| {
       rhandle.spawn(peer.run(socket, net_adapter.clone()).map_err(|e| {
         panic!("Client run failed: {:?}", e);
@@ -0,0 +1,96 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate futures;
+extern crate grin_p2p as p2p;
+extern crate tokio_core;
+
+use std::io::Write;
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use tokio_core::reactor::{self, Core};
+
+// A loopback port free for a server to listen on, as picked by the system.
+fn free_port() -> u16 {
+  let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+  listener.local_addr().unwrap().port()
+}
+
+// Connects a peer on another network and a silent peer to a server, checking
+// both failures are counted under their respective reasons.
+#[test]
+fn handshake_failure_reasons() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let p2p_conf = p2p::P2PConfig{port: free_port(), ..p2p::P2PConfig::default()};
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+
+  let server = p2p::Server::new(p2p::UNKNOWN, p2p_conf, Arc::new(p2p::DummyAdapter{}));
+  handle.spawn(server.start(handle.clone()).map_err(|_| ()));
+
+  // bad magic number, as if from another network
+  let mut wrong_net = net::TcpStream::connect(addr).unwrap();
+  wrong_net.write_all(&[0u8; 15]).unwrap();
+  // never sends its hand
+  let _silent = net::TcpStream::connect(addr).unwrap();
+
+  // the silent one only fails once the handshake times out
+  let start = Instant::now();
+  while server.handshake_failures().get(&p2p::HandshakeFailure::Timeout).is_none() {
+    assert!(start.elapsed() < Duration::from_secs(15), "handshake never timed out");
+    let tick = reactor::Timeout::new(Duration::from_millis(50), &handle).unwrap();
+    evtlp.run(tick).unwrap();
+  }
+
+  let failures = server.handshake_failures();
+  assert_eq!(failures.get(&p2p::HandshakeFailure::WrongNetwork), Some(&1));
+  assert_eq!(failures.get(&p2p::HandshakeFailure::Timeout), Some(&1));
+}
+
+// Connects synchronously to a running server, then to a dummy listener that
+// never completes the handshake.
+#[test]
+fn connect_blocking() {
+  let p2p_conf = p2p::P2PConfig{port: free_port(), ..p2p::P2PConfig::default()};
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  thread::spawn(move || {
+    let mut evtlp = Core::new().unwrap();
+    let server = p2p::Server::new(p2p::UNKNOWN, p2p_conf, Arc::new(p2p::DummyAdapter{}));
+    evtlp.run(server.start(evtlp.handle())).unwrap();
+  });
+  // waits for the server to listen
+  let start = Instant::now();
+  while net::TcpStream::connect(addr).is_err() {
+    assert!(start.elapsed() < Duration::from_secs(10), "server never listened");
+    thread::sleep(Duration::from_millis(10));
+  }
+
+  let client_conf = p2p::P2PConfig{port: free_port(), ..p2p::P2PConfig::default()};
+  let client = p2p::Server::new(p2p::UNKNOWN, client_conf, Arc::new(p2p::DummyAdapter{}));
+  let peer = client.connect_peer_blocking(addr, Duration::from_secs(2)).unwrap();
+  assert_eq!(peer.info.addr, addr);
+  assert_eq!(client.peer_count(), 0);
+
+  // accepts the connection but never answers our hand
+  let dummy = net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let dummy_addr = dummy.local_addr().unwrap();
+  match client.connect_peer_blocking(dummy_addr, Duration::from_millis(500)) {
+    Err(p2p::Error::Timeout) => {}
+    _ => panic!("expected a timeout"),
+  }
+}
@@ -0,0 +1,34 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The transaction pool, keeping the valid transactions we received until
+//! they get mined, and handing the best paying ones to the miner.
+
+#![deny(non_upper_case_globals)]
+#![deny(non_camel_case_types)]
+#![deny(non_snake_case)]
+#![deny(unused_mut)]
+#![warn(missing_docs)]
+
+#[macro_use]
+extern crate log;
+
+extern crate grin_core as core;
+extern crate secp256k1zkp as secp;
+
+pub mod pool;
+pub mod types;
+
+pub use pool::TransactionPool;
+pub use types::{BlockChain, PoolAdapter, NoopPoolAdapter, PoolConfig, PoolError, tx_weight};
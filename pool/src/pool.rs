@@ -0,0 +1,725 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The pool of valid transactions waiting to be mined. Transactions can
+//! spend the outputs of other pool transactions, the pool keeping track of
+//! those dependencies so a transaction never gets mined before the one it
+//! depends on.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use core::core::{Block, Transaction};
+use core::core::hash::{Hash, Hashed};
+use secp::{self, Secp256k1};
+use secp::pedersen::Commitment;
+
+use types::{BlockChain, PoolAdapter, PoolConfig, PoolError, tx_weight};
+
+// How many of the last blocks reconciled we remember the transactions they
+// took out of the pool of, to put them back should a reorg rewind the block.
+const MAX_MINED_BLOCKS: usize = 50;
+
+// Seconds a stem transaction is kept at most, long after its embargo ended
+// should nobody have broadcast it.
+const STEM_EXPIRY_SECS: u64 = 600;
+
+// A transaction of the pool along with its weight.
+struct PoolEntry {
+	tx: Transaction,
+	weight: usize,
+}
+
+// Fee a transaction pays per weight unit, ordered without dividing.
+#[derive(Clone, Copy, Debug)]
+struct FeeRate {
+	fee: u64,
+	weight: usize,
+}
+
+impl PartialEq for FeeRate {
+	fn eq(&self, other: &FeeRate) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for FeeRate {}
+
+impl PartialOrd for FeeRate {
+	fn partial_cmp(&self, other: &FeeRate) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for FeeRate {
+	fn cmp(&self, other: &FeeRate) -> Ordering {
+		(self.fee * other.weight as u64).cmp(&(other.fee * self.weight as u64))
+	}
+}
+
+/// Pool of the transactions we validated against our chain and the other
+/// pool transactions, until a block includes them or conflicts with them.
+pub struct TransactionPool<T> {
+	config: PoolConfig,
+	txs: HashMap<Hash, PoolEntry>,
+	// outputs the pool transactions create, with the one creating each
+	outputs: HashMap<Commitment, Hash>,
+	// outputs the pool transactions spend, with the one spending each
+	spent: HashMap<Commitment, Hash>,
+	// the pool transactions from the one paying the least per weight unit
+	by_fee_rate: BTreeSet<(FeeRate, Hash)>,
+	// sum of the weights of the pool transactions
+	weight: usize,
+	// transactions the last blocks took out of the pool, by block hash
+	mined: VecDeque<(Hash, Vec<Transaction>)>,
+	// transactions relayed along the dandelion stem, kept apart and shown to
+	// nobody until they get broadcast, with when they came and the outputs
+	// they create and spend
+	stem: HashMap<Hash, (Transaction, Instant)>,
+	stem_outputs: HashMap<Commitment, Hash>,
+	stem_spent: HashMap<Commitment, Hash>,
+	// context validating every transaction, costly to create for each
+	secp: Secp256k1,
+	blockchain: Arc<T>,
+	adapter: Arc<PoolAdapter>,
+}
+
+impl<T: BlockChain> TransactionPool<T> {
+	/// Creates an empty pool validating against the provided chain.
+	pub fn new(config: PoolConfig,
+	           blockchain: Arc<T>,
+	           adapter: Arc<PoolAdapter>)
+	           -> TransactionPool<T> {
+		TransactionPool {
+			config: config,
+			txs: HashMap::new(),
+			outputs: HashMap::new(),
+			spent: HashMap::new(),
+			by_fee_rate: BTreeSet::new(),
+			weight: 0,
+			mined: VecDeque::new(),
+			stem: HashMap::new(),
+			stem_outputs: HashMap::new(),
+			stem_spent: HashMap::new(),
+			secp: Secp256k1::with_caps(secp::ContextFlag::Commit),
+			blockchain: blockchain,
+			adapter: adapter,
+		}
+	}
+
+	/// Number of transactions in the pool.
+	pub fn size(&self) -> usize {
+		self.txs.len()
+	}
+
+	/// Number of transactions in the stem pool, see add_to_stempool.
+	pub fn stem_size(&self) -> usize {
+		self.stem.len()
+	}
+
+	/// Total weight of the pool transactions.
+	pub fn total_weight(&self) -> usize {
+		self.weight
+	}
+
+	/// Whether the pool has the transaction with the provided hash.
+	pub fn contains(&self, h: &Hash) -> bool {
+		self.txs.contains_key(h)
+	}
+
+	/// The pool transaction with the provided hash.
+	pub fn get(&self, h: &Hash) -> Option<&Transaction> {
+		self.txs.get(h).map(|e| &e.tx)
+	}
+
+	/// A pool transaction matching the provided predicate, like the one
+	/// having a given kernel.
+	pub fn find<P>(&self, pred: P) -> Option<&Transaction>
+		where P: Fn(&Transaction) -> bool
+	{
+		self.txs.values().map(|e| &e.tx).find(|tx| pred(tx))
+	}
+
+	/// Validates a transaction and adds it to the pool. Its inputs have to
+	/// spend outputs either unspent on our chain or created by another pool
+	/// transaction, that no other pool transaction spends already. When the
+	/// pool is full, the transaction paying the least per weight unit gets
+	/// evicted for this one, if this one pays more. A transaction of the stem
+	/// pool moves over, now that it's been broadcast.
+	pub fn add_to_memory_pool(&mut self, tx: Transaction) -> Result<Hash, PoolError> {
+		let h = tx.hash();
+		if self.txs.contains_key(&h) {
+			return Err(PoolError::AlreadyInPool);
+		}
+		for input in &tx.inputs {
+			let c = input.commitment();
+			if let Some(other) = self.spent.get(&c) {
+				return Err(PoolError::DoubleSpend(*other));
+			}
+			if !self.outputs.contains_key(&c) && !self.blockchain.is_unspent(&c) {
+				return Err(PoolError::MissingOutput(c));
+			}
+		}
+		for output in &tx.outputs {
+			let c = output.commitment();
+			if self.outputs.contains_key(&c) || self.blockchain.is_unspent(&c) {
+				return Err(PoolError::DuplicateOutput(c));
+			}
+		}
+		tx.validate(&self.secp).map_err(PoolError::Invalid)?;
+
+		let entry = PoolEntry {
+			weight: tx_weight(&tx),
+			tx: tx,
+		};
+		if self.txs.len() >= self.config.max_pool_size {
+			self.make_room(&entry)?;
+		}
+		for input in &entry.tx.inputs {
+			self.spent.insert(input.commitment(), h);
+		}
+		for output in &entry.tx.outputs {
+			self.outputs.insert(output.commitment(), h);
+		}
+		self.by_fee_rate.insert((fee_rate(&entry), h));
+		self.weight += entry.weight;
+		self.adapter.tx_accepted(&entry.tx);
+		self.txs.insert(h, entry);
+		self.remove_stem(&h);
+		Ok(h)
+	}
+
+	/// Validates a transaction relayed along the dandelion stem and keeps it
+	/// in the stem pool, apart from the pool: it's neither served to peers
+	/// nor mined until it gets broadcast and added with add_to_memory_pool,
+	/// so nobody can trace the stem by asking for it. Its inputs can spend
+	/// outputs of our chain, of the pool or of other stem transactions. Stem
+	/// transactions nobody broadcast expire after a while.
+	pub fn add_to_stempool(&mut self, tx: Transaction) -> Result<Hash, PoolError> {
+		let h = tx.hash();
+		if self.txs.contains_key(&h) || self.stem.contains_key(&h) {
+			return Err(PoolError::AlreadyInPool);
+		}
+		let expired = self.stem
+			.iter()
+			.filter(|&(_, &(_, since))| since.elapsed() >= Duration::from_secs(STEM_EXPIRY_SECS))
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		for h in expired {
+			self.remove_stem(&h);
+		}
+		if self.stem.len() >= self.config.max_pool_size {
+			return Err(PoolError::StemPoolFull);
+		}
+		for input in &tx.inputs {
+			let c = input.commitment();
+			if let Some(other) = self.spent.get(&c).or(self.stem_spent.get(&c)) {
+				return Err(PoolError::DoubleSpend(*other));
+			}
+			if !self.stem_outputs.contains_key(&c) && !self.outputs.contains_key(&c) &&
+			   !self.blockchain.is_unspent(&c) {
+				return Err(PoolError::MissingOutput(c));
+			}
+		}
+		for output in &tx.outputs {
+			let c = output.commitment();
+			if self.stem_outputs.contains_key(&c) || self.outputs.contains_key(&c) ||
+			   self.blockchain.is_unspent(&c) {
+				return Err(PoolError::DuplicateOutput(c));
+			}
+		}
+		tx.validate(&self.secp).map_err(PoolError::Invalid)?;
+
+		for input in &tx.inputs {
+			self.stem_spent.insert(input.commitment(), h);
+		}
+		for output in &tx.outputs {
+			self.stem_outputs.insert(output.commitment(), h);
+		}
+		self.stem.insert(h, (tx, Instant::now()));
+		Ok(h)
+	}
+
+	/// The transactions to include in the next block we mine, best paying
+	/// first, their total weight under the provided maximum. A transaction
+	/// always comes after the pool transactions it depends on.
+	pub fn prepare_mineable_transactions(&self, max_weight: usize) -> Vec<Transaction> {
+		let mut candidates = self.txs.iter().collect::<Vec<_>>();
+		candidates.sort_by(|a, b| fee_rate_cmp(b.1, a.1));
+
+		let mut included = HashSet::new();
+		let mut mineable = vec![];
+		let mut weight = 0;
+		// another pass picks up the transactions whose parents made it in
+		// after them
+		loop {
+			let mut progress = false;
+			for &(h, e) in &candidates {
+				if included.contains(h) || weight + e.weight > max_weight {
+					continue;
+				}
+				let ready = e.tx.inputs.iter().all(|i| match self.outputs.get(&i.commitment()) {
+					Some(parent) => included.contains(parent),
+					None => true,
+				});
+				if ready {
+					included.insert(*h);
+					weight += e.weight;
+					mineable.push(e.tx.clone());
+					progress = true;
+				}
+			}
+			if !progress {
+				break;
+			}
+		}
+		mineable
+	}
+
+	/// Drops the transactions a new block of our chain included or conflicts
+	/// with, along with those depending on a conflicting one. Returns the
+	/// hashes of the dropped transactions.
+	pub fn reconcile_block(&mut self, b: &Block) -> Vec<Hash> {
+		let spent = b.inputs.iter().map(|i| i.commitment()).collect::<HashSet<_>>();
+		let created = b.outputs.iter().map(|o| o.commitment()).collect::<HashSet<_>>();
+		let conflicts = |tx: &Transaction| {
+			tx.inputs.iter().any(|i| spent.contains(&i.commitment())) ||
+			tx.outputs.iter().any(|o| created.contains(&o.commitment()))
+		};
+		let mut removed = self.txs
+			.iter()
+			.filter(|&(_, e)| conflicts(&e.tx))
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		let mut txs = removed.iter().filter_map(|h| self.remove(h)).collect::<Vec<_>>();
+
+		// the stem transactions the block mined or conflicts with won't ever
+		// make it in either
+		let stale = self.stem
+			.iter()
+			.filter(|&(_, &(ref tx, _))| conflicts(tx))
+			.map(|(h, _)| *h)
+			.collect::<Vec<_>>();
+		for h in stale {
+			self.remove_stem(&h);
+		}
+
+		// those spending what a conflicting transaction created are left
+		// spending outputs nobody has, and so on down their descendants
+		let (orphaned, orphaned_txs) = self.remove_orphans();
+		removed.extend(orphaned);
+		txs.extend(orphaned_txs);
+		if removed.len() > 0 {
+			debug!("Block {} took {} transactions out of the pool.", b.hash(), removed.len());
+		}
+
+		if self.mined.len() >= MAX_MINED_BLOCKS {
+			self.mined.pop_front();
+		}
+		self.mined.push_back((b.hash(), txs));
+		removed
+	}
+
+	/// Puts back the transactions the provided block took out of the pool,
+	/// now that a reorg rewound it, and drops those left spending outputs
+	/// the block created. When rewinding several blocks, the oldest goes
+	/// first so the transactions depending on those of an older block find
+	/// them in the pool. Returns the hashes of the transactions back in.
+	pub fn rewind_block(&mut self, b: &Block) -> Vec<Hash> {
+		let h = b.hash();
+		let pos = self.mined.iter().position(|&(bh, _)| bh == h);
+		let mut txs = match pos.and_then(|pos| self.mined.remove(pos)) {
+			Some((_, txs)) => txs,
+			None => vec![],
+		};
+		self.remove_orphans();
+
+		// the transactions of a block can depend on each other, another pass
+		// picks up those coming before their parent
+		let mut added = vec![];
+		loop {
+			let before = txs.len();
+			let mut left = vec![];
+			for tx in txs {
+				match self.add_to_memory_pool(tx.clone()) {
+					Ok(h) => added.push(h),
+					Err(PoolError::MissingOutput(_)) => left.push(tx),
+					Err(_) => {}
+				}
+			}
+			txs = left;
+			if txs.is_empty() || txs.len() == before {
+				break;
+			}
+		}
+		if added.len() > 0 {
+			debug!("Rewound block {} put {} transactions back in the pool.", h, added.len());
+		}
+		added
+	}
+
+	// Drops the transactions spending outputs neither the chain nor the
+	// pool have, down to their descendants. Returns their hashes and the
+	// transactions themselves.
+	fn remove_orphans(&mut self) -> (Vec<Hash>, Vec<Transaction>) {
+		let mut removed = vec![];
+		let mut txs = vec![];
+		loop {
+			let orphaned = self.txs
+				.iter()
+				.filter(|&(_, e)| {
+					e.tx.inputs.iter().any(|i| {
+						let c = i.commitment();
+						!self.outputs.contains_key(&c) && !self.blockchain.is_unspent(&c)
+					})
+				})
+				.map(|(h, _)| *h)
+				.collect::<Vec<_>>();
+			if orphaned.is_empty() {
+				break;
+			}
+			txs.extend(orphaned.iter().filter_map(|h| self.remove(h)));
+			removed.extend(orphaned);
+		}
+		(removed, txs)
+	}
+
+	// Evicts the transaction paying the least per weight unit, should the
+	// provided one pay more. Only transactions no other depends on can go,
+	// nor those the provided one depends on. Walks the pool from the lowest
+	// paying transaction, stopping at the first paying as much as the
+	// provided one.
+	fn make_room(&mut self, entry: &PoolEntry) -> Result<(), PoolError> {
+		let rate = fee_rate(entry);
+		let parents = entry.tx
+			.inputs
+			.iter()
+			.filter_map(|i| self.outputs.get(&i.commitment()))
+			.cloned()
+			.collect::<HashSet<_>>();
+		let lowest = self.by_fee_rate
+			.iter()
+			.take_while(|&&(r, _)| r < rate)
+			.map(|&(_, h)| h)
+			.find(|h| {
+				!parents.contains(h) &&
+				!self.txs[h].tx.outputs.iter().any(|o| self.spent.contains_key(&o.commitment()))
+			});
+		match lowest {
+			Some(h) => {
+				debug!("Pool full, evicting transaction {}.", h);
+				self.remove(&h);
+				Ok(())
+			}
+			None => Err(PoolError::LowFee),
+		}
+	}
+
+	fn remove_stem(&mut self, h: &Hash) -> Option<Transaction> {
+		self.stem.remove(h).map(|(tx, _)| {
+			for input in &tx.inputs {
+				self.stem_spent.remove(&input.commitment());
+			}
+			for output in &tx.outputs {
+				self.stem_outputs.remove(&output.commitment());
+			}
+			tx
+		})
+	}
+
+	fn remove(&mut self, h: &Hash) -> Option<Transaction> {
+		self.txs.remove(h).map(|e| {
+			self.by_fee_rate.remove(&(fee_rate(&e), *h));
+			self.weight -= e.weight;
+			for input in &e.tx.inputs {
+				self.spent.remove(&input.commitment());
+			}
+			for output in &e.tx.outputs {
+				self.outputs.remove(&output.commitment());
+			}
+			e.tx
+		})
+	}
+}
+
+// Fee the transaction of a pool entry pays per weight unit.
+fn fee_rate(e: &PoolEntry) -> FeeRate {
+	FeeRate {
+		fee: e.tx.fee,
+		weight: e.weight,
+	}
+}
+
+// Compares the fees two transactions pay per weight unit.
+fn fee_rate_cmp(a: &PoolEntry, b: &PoolEntry) -> Ordering {
+	fee_rate(a).cmp(&fee_rate(b))
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashSet;
+	use std::sync::{Arc, Mutex, RwLock};
+
+	use core::core::{Block, Transaction};
+	use core::core::build::{self, input, output, with_fee};
+	use core::core::hash::{Hash, Hashed};
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+	use secp::pedersen::Commitment;
+	use types::{BlockChain, NoopPoolAdapter, PoolAdapter, PoolConfig, PoolError, tx_weight};
+	use super::*;
+
+	struct DummyChain {
+		unspent: RwLock<HashSet<Commitment>>,
+	}
+
+	impl DummyChain {
+		fn with_outputs(outputs: Vec<(u64, u8)>) -> DummyChain {
+			let unspent = outputs.iter().map(|&(v, k)| commit(v, k)).collect();
+			DummyChain { unspent: RwLock::new(unspent) }
+		}
+	}
+
+	impl BlockChain for DummyChain {
+		fn is_unspent(&self, commit: &Commitment) -> bool {
+			self.unspent.read().unwrap().contains(commit)
+		}
+	}
+
+	struct RecordingAdapter {
+		accepted: Mutex<Vec<Hash>>,
+	}
+
+	impl PoolAdapter for RecordingAdapter {
+		fn tx_accepted(&self, tx: &Transaction) {
+			self.accepted.lock().unwrap().push(tx.hash());
+		}
+	}
+
+	fn key(n: u8) -> SecretKey {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		SecretKey::from_slice(&secp, &[n; 32]).unwrap()
+	}
+
+	fn commit(value: u64, k: u8) -> Commitment {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		secp.commit(value, key(k)).unwrap()
+	}
+
+	// Spends the output of the provided value and key, paying the fee and the
+	// rest to an output with the other key.
+	fn spend(value: u64, from: u8, to: u8, fee: u64) -> Transaction {
+		let rest = value - fee;
+		build::transaction(vec![input(value, key(from)), output(rest, key(to)), with_fee(fee)])
+			.map(|(tx, _)| tx)
+			.unwrap()
+	}
+
+	fn pool_of(size: usize, chain: Arc<DummyChain>) -> TransactionPool<DummyChain> {
+		TransactionPool::new(PoolConfig { max_pool_size: size },
+		                     chain,
+		                     Arc::new(NoopPoolAdapter {}))
+	}
+
+	#[test]
+	fn notifies_accepted() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1)]));
+		let adapter = Arc::new(RecordingAdapter { accepted: Mutex::new(vec![]) });
+		let mut pool = TransactionPool::new(PoolConfig::default(), chain, adapter.clone());
+
+		let tx = spend(10, 1, 2, 1);
+		let h = pool.add_to_memory_pool(tx.clone()).unwrap();
+		assert!(pool.add_to_memory_pool(tx).is_err());
+		assert!(pool.add_to_memory_pool(spend(10, 3, 4, 1)).is_err());
+		assert_eq!(*adapter.accepted.lock().unwrap(), vec![h]);
+	}
+
+	#[test]
+	fn tracks_dependencies() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1)]));
+		let mut pool = pool_of(10, chain);
+
+		// the child can't go in before its parent
+		match pool.add_to_memory_pool(spend(9, 2, 3, 2)) {
+			Err(PoolError::MissingOutput(c)) => assert_eq!(c, commit(9, 2)),
+			r => panic!("unexpected result {:?}", r),
+		}
+		let tx = spend(10, 1, 2, 1);
+		let parent = pool.add_to_memory_pool(tx.clone()).unwrap();
+		pool.add_to_memory_pool(spend(9, 2, 3, 2)).unwrap();
+		assert_eq!(pool.size(), 2);
+
+		match pool.add_to_memory_pool(tx) {
+			Err(PoolError::AlreadyInPool) => {}
+			r => panic!("unexpected result {:?}", r),
+		}
+		match pool.add_to_memory_pool(spend(10, 1, 4, 2)) {
+			Err(PoolError::DoubleSpend(h)) => assert_eq!(h, parent),
+			r => panic!("unexpected result {:?}", r),
+		}
+		match pool.add_to_memory_pool(spend(10, 5, 6, 2)) {
+			Err(PoolError::MissingOutput(_)) => {}
+			r => panic!("unexpected result {:?}", r),
+		}
+	}
+
+	#[test]
+	fn evicts_lowest_fee() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1), (10, 2), (10, 3)]));
+		let mut pool = pool_of(2, chain);
+
+		let low = pool.add_to_memory_pool(spend(10, 1, 4, 1)).unwrap();
+		let high = pool.add_to_memory_pool(spend(10, 2, 5, 3)).unwrap();
+		let mid = pool.add_to_memory_pool(spend(10, 3, 6, 2)).unwrap();
+		assert_eq!(pool.size(), 2);
+		assert!(!pool.contains(&low));
+		assert!(pool.contains(&high) && pool.contains(&mid));
+
+		// paying no more than the worst left, it doesn't make it in
+		match pool.add_to_memory_pool(spend(10, 1, 4, 2)) {
+			Err(PoolError::LowFee) => {}
+			r => panic!("unexpected result {:?}", r),
+		}
+		assert_eq!(pool.size(), 2);
+	}
+
+	#[test]
+	fn evicts_no_parent() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1), (10, 4), (10, 6)]));
+		let mut pool = pool_of(3, chain);
+
+		let parent = pool.add_to_memory_pool(spend(10, 1, 2, 1)).unwrap();
+		let child = pool.add_to_memory_pool(spend(9, 2, 3, 5)).unwrap();
+		let other = pool.add_to_memory_pool(spend(10, 4, 5, 2)).unwrap();
+
+		// the parent pays the least but its child would be left orphaned
+		let tx = spend(10, 6, 7, 3);
+		let weight = tx_weight(&tx);
+		let h = pool.add_to_memory_pool(tx).unwrap();
+		assert_eq!(pool.size(), 3);
+		assert!(pool.contains(&parent) && pool.contains(&child) && pool.contains(&h));
+		assert!(!pool.contains(&other));
+		assert_eq!(pool.total_weight(), 3 * weight);
+	}
+
+	#[test]
+	fn mineable_and_reconciled() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1), (10, 5)]));
+		let mut pool = pool_of(10, chain.clone());
+
+		let parent = spend(10, 1, 2, 1);
+		let p = pool.add_to_memory_pool(parent.clone()).unwrap();
+		let c = pool.add_to_memory_pool(spend(9, 2, 3, 5)).unwrap();
+		let other = pool.add_to_memory_pool(spend(10, 5, 6, 3)).unwrap();
+		let other_child = pool.add_to_memory_pool(spend(7, 6, 7, 2)).unwrap();
+
+		// the best paying child waits for its parent
+		let hashes = |txs: Vec<Transaction>| txs.iter().map(|tx| tx.hash()).collect::<Vec<_>>();
+		let weight = pool.total_weight();
+		assert_eq!(hashes(pool.prepare_mineable_transactions(weight)),
+		           vec![other, other_child, p, c]);
+		let tx_weight = weight / 4;
+		assert_eq!(hashes(pool.prepare_mineable_transactions(2 * tx_weight)),
+		           vec![other, other_child]);
+
+		// a block including the parent
+		let mut b = Block::default();
+		b.inputs = parent.inputs.clone();
+		b.outputs = parent.outputs.clone();
+		{
+			let mut unspent = chain.unspent.write().unwrap();
+			unspent.remove(&commit(10, 1));
+			unspent.insert(commit(9, 2));
+		}
+		assert_eq!(pool.reconcile_block(&b), vec![p]);
+		assert!(pool.contains(&c));
+
+		// a block double spending the other one, its child goes with it
+		let mut b = Block::default();
+		b.inputs = spend(10, 5, 8, 1).inputs;
+		chain.unspent.write().unwrap().remove(&commit(10, 5));
+		let removed = pool.reconcile_block(&b);
+		assert_eq!(removed, vec![other, other_child]);
+		assert_eq!(pool.size(), 1);
+	}
+
+	#[test]
+	fn stem_kept_apart() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1), (10, 5)]));
+		let mut pool = pool_of(10, chain.clone());
+
+		// stemmed transactions, one spending the other, nobody gets to see
+		let parent = spend(10, 1, 2, 1);
+		let p = pool.add_to_stempool(parent.clone()).unwrap();
+		let c = pool.add_to_stempool(spend(9, 2, 3, 2)).unwrap();
+		assert_eq!((pool.size(), pool.stem_size()), (0, 2));
+		assert!(pool.get(&p).is_none() && pool.find(|tx| tx.hash() == c).is_none());
+		assert!(pool.prepare_mineable_transactions(1000000).is_empty());
+		match pool.add_to_stempool(spend(10, 1, 4, 2)) {
+			Err(PoolError::DoubleSpend(h)) => assert_eq!(h, p),
+			r => panic!("unexpected result {:?}", r),
+		}
+
+		// broadcast, the parent moves over to the pool
+		assert_eq!(pool.add_to_memory_pool(parent.clone()).unwrap(), p);
+		assert_eq!((pool.size(), pool.stem_size()), (1, 1));
+		assert!(pool.get(&p).is_some());
+		match pool.add_to_stempool(parent) {
+			Err(PoolError::AlreadyInPool) => {}
+			r => panic!("unexpected result {:?}", r),
+		}
+
+		// a block double spending a stem transaction drops it
+		pool.add_to_stempool(spend(10, 5, 6, 1)).unwrap();
+		let mut b = Block::default();
+		b.inputs = spend(10, 5, 8, 1).inputs;
+		chain.unspent.write().unwrap().remove(&commit(10, 5));
+		pool.reconcile_block(&b);
+		assert_eq!(pool.stem_size(), 1);
+	}
+
+	#[test]
+	fn rewound_back_in() {
+		let chain = Arc::new(DummyChain::with_outputs(vec![(10, 1)]));
+		let mut pool = pool_of(10, chain.clone());
+
+		let parent = spend(10, 1, 2, 1);
+		let child = spend(9, 2, 3, 2);
+		let p = pool.add_to_memory_pool(parent.clone()).unwrap();
+		let c = pool.add_to_memory_pool(child.clone()).unwrap();
+
+		// a block mining both, which a reorg then rewinds
+		let mut b = Block::default();
+		b.inputs = parent.inputs.clone();
+		b.outputs = child.outputs.clone();
+		{
+			let mut unspent = chain.unspent.write().unwrap();
+			unspent.remove(&commit(10, 1));
+			unspent.insert(commit(7, 3));
+		}
+		assert_eq!(pool.reconcile_block(&b).len(), 2);
+		assert_eq!(pool.size(), 0);
+		{
+			let mut unspent = chain.unspent.write().unwrap();
+			unspent.insert(commit(10, 1));
+			unspent.remove(&commit(7, 3));
+		}
+		let back = pool.rewind_block(&b).into_iter().collect::<HashSet<_>>();
+		assert_eq!(back, vec![p, c].into_iter().collect::<HashSet<_>>());
+		assert!(pool.contains(&p) && pool.contains(&c));
+		assert!(pool.rewind_block(&b).is_empty());
+	}
+}
@@ -0,0 +1,83 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types the transaction pool works with and the interface it requires to
+//! the chain.
+
+use core::consensus;
+use core::core::Transaction;
+use core::core::hash::Hash;
+use secp;
+use secp::pedersen::Commitment;
+
+/// Configuration of the transaction pool.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+	/// Maximum number of transactions the pool holds. Once full, the ones
+	/// paying the least fee per weight unit make room for better ones.
+	pub max_pool_size: usize,
+}
+
+impl Default for PoolConfig {
+	fn default() -> PoolConfig {
+		PoolConfig { max_pool_size: 50_000 }
+	}
+}
+
+/// What the pool needs to know of the chain to validate transactions.
+pub trait BlockChain: Send + Sync {
+	/// Whether the output with the provided commitment is unspent on our
+	/// chain.
+	fn is_unspent(&self, commit: &Commitment) -> bool;
+}
+
+/// Notified of the transactions making it to the pool, whoever submitted
+/// them.
+pub trait PoolAdapter: Send + Sync {
+	/// The pool validated and accepted the transaction.
+	fn tx_accepted(&self, tx: &Transaction);
+}
+
+/// Adapter ignoring all notifications.
+pub struct NoopPoolAdapter {}
+impl PoolAdapter for NoopPoolAdapter {
+	fn tx_accepted(&self, tx: &Transaction) {}
+}
+
+/// Why a transaction didn't make it to the pool.
+#[derive(Debug)]
+pub enum PoolError {
+	/// The transaction signature or one of its range proofs is invalid.
+	Invalid(secp::Error),
+	/// We have the transaction in the pool already.
+	AlreadyInPool,
+	/// The transaction spends an output the pool transaction with the
+	/// provided hash spends already.
+	DoubleSpend(Hash),
+	/// An input spends an output neither our chain nor the pool has.
+	MissingOutput(Commitment),
+	/// An output duplicates one our chain or the pool has.
+	DuplicateOutput(Commitment),
+	/// The pool is full of transactions paying better fees.
+	LowFee,
+	/// The stem pool holds as many transactions as the pool can.
+	StemPoolFull,
+}
+
+/// Weight of a transaction in the block it ends up in, its inputs, outputs
+/// and kernel all counted.
+pub fn tx_weight(tx: &Transaction) -> usize {
+	tx.inputs.len() * consensus::INPUT_WEIGHT + tx.outputs.len() * consensus::OUTPUT_WEIGHT +
+	consensus::KERNEL_WEIGHT
+}
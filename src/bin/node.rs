@@ -14,5 +14,101 @@
 
 //! Main for building the binary of a Grin peer-to-peer node.
 
+extern crate env_logger;
+extern crate futures;
+extern crate grin_grin as grin;
+extern crate tokio_core;
+
+use std::env;
+use std::process;
+
+use futures::future;
+use tokio_core::reactor;
+
 fn main() {
+	env_logger::init().unwrap();
+
+	let mut config_file = None;
+	let mut network = None;
+	let mut dir = None;
+	let mut port = None;
+	let mut seeds = vec![];
+	let mut webhooks = vec![];
+	let mut mine = false;
+	let mut map_port = false;
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--config" => config_file = Some(flag_value(&mut args, &arg)),
+			"--network" => network = Some(flag_value(&mut args, &arg)),
+			"--dir" => dir = Some(flag_value(&mut args, &arg)),
+			"--port" => port = Some(parse_port(&flag_value(&mut args, &arg))),
+			"--seed" => seeds.push(flag_value(&mut args, &arg)),
+			"--webhook" => webhooks.push(flag_value(&mut args, &arg)),
+			"--mine" => mine = true,
+			"--map-port" => map_port = true,
+			_ => usage(&format!("unknown argument {}", arg)),
+		}
+	}
+
+	// the command line has the last word over the configuration file, its
+	// network replacing the file's before the rest of the file applies
+	let network = network.map(|name| {
+		grin::ChainTypes::from_name(&name)
+			.unwrap_or_else(|| usage(&format!("unknown network {}", name)))
+	});
+	let mut config = match config_file {
+		Some(path) => {
+			grin::ServerConfig::from_toml_file(&path, network).unwrap_or_else(|e| {
+				println!("Invalid configuration: {:?}", e);
+				process::exit(1)
+			})
+		}
+		None => {
+			let mut config = grin::ServerConfig::default();
+			if let Some(chain_type) = network {
+				config.set_network(chain_type);
+			}
+			config
+		}
+	};
+	if let Some(dir) = dir {
+		config.db_root = dir;
+	}
+	if let Some(port) = port {
+		config.p2p_config.port = port;
+	}
+	if map_port {
+		config.p2p_config.port_mapping = true;
+	}
+	if !seeds.is_empty() {
+		config.seeding_type = grin::Seeding::List(seeds);
+	}
+	config.webhook_urls.extend(webhooks);
+
+	let mut evtlp = reactor::Core::new().unwrap();
+	let server = grin::Server::future(config, &evtlp.handle()).unwrap_or_else(|e| {
+		println!("Failed to start the server: {:?}", e);
+		process::exit(1)
+	});
+	if mine {
+		server.start_miner();
+	}
+	evtlp.run(future::empty::<(), ()>()).unwrap();
+}
+
+fn flag_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> String {
+	args.next().unwrap_or_else(|| usage(&format!("missing value for {}", flag)))
+}
+
+fn parse_port(s: &str) -> u16 {
+	s.parse().unwrap_or_else(|_| usage(&format!("invalid port {}", s)))
+}
+
+fn usage(msg: &str) -> ! {
+	println!("{}", msg);
+	println!("Usage: node [--config <file>] [--network mainnet|testnet|usernet] [--dir <dir>]");
+	println!("            [--port <port>] [--map-port] [--seed <address>]... [--webhook <url>]...");
+	println!("            [--mine]");
+	process::exit(1)
 }
@@ -0,0 +1,86 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Main for building the wallet binary. Keeps our outputs in the data file
+//! of a directory, receives transactions from other wallets and sends to
+//! them.
+//!
+//! wallet [--dir <dir>] [--node <url>] [--addr <address>] <command>
+//!
+//! with the commands:
+//!
+//! * receive: runs the receiver listening for the transactions of senders
+//! * send <amount> --dest <url> [--fee <fee>]: sends the amount to the
+//!   wallet receiver at the URL, pushing the transaction to our node
+//! * info: lists our outputs and balance
+
+extern crate env_logger;
+extern crate grin_wallet as wallet;
+
+use std::env;
+use std::process;
+
+const DEFAULT_FEE: u64 = 10;
+
+fn main() {
+	env_logger::init().unwrap();
+
+	let mut config = wallet::WalletConfig::default();
+	let mut args = env::args().skip(1);
+	let mut command = vec![];
+	let mut dest = None;
+	let mut fee = DEFAULT_FEE;
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--dir" => config.data_file_dir = flag_value(&mut args, &arg),
+			"--node" => config.check_node_api_http_addr = flag_value(&mut args, &arg),
+			"--addr" => config.api_http_addr = flag_value(&mut args, &arg),
+			"--dest" => dest = Some(flag_value(&mut args, &arg)),
+			"--fee" => fee = parse_amount(&flag_value(&mut args, &arg)),
+			_ => command.push(arg),
+		}
+	}
+
+	let res = match command.first().map(|c| c.as_str()) {
+		Some("receive") => wallet::start_receiver(&config),
+		Some("send") if command.len() == 2 => {
+			let amount = parse_amount(&command[1]);
+			let dest = dest.unwrap_or_else(|| usage("send needs the --dest of the receiver"));
+			wallet::issue_send_tx(&config, amount, fee, dest)
+		}
+		Some("info") => wallet::show_info(&config),
+		_ => usage("unknown command"),
+	};
+	if let Err(e) = res {
+		println!("Wallet command failed: {:?}", e);
+		process::exit(1);
+	}
+}
+
+fn flag_value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> String {
+	args.next().unwrap_or_else(|| usage(&format!("missing value for {}", flag)))
+}
+
+fn parse_amount(s: &str) -> u64 {
+	s.parse().unwrap_or_else(|_| usage(&format!("invalid amount {}", s)))
+}
+
+fn usage(msg: &str) -> ! {
+	println!("{}", msg);
+	println!("Usage: wallet [--dir <dir>] [--node <url>] [--addr <address>] <command>");
+	println!("  receive");
+	println!("  send <amount> --dest <url> [--fee <fee>]");
+	println!("  info");
+	process::exit(1)
+}
@@ -146,9 +146,16 @@ impl Store {
 	/// provided
 	/// key.
 	pub fn iter<T: ser::Readable>(&self, from: &[u8]) -> SerIterator<T> {
+		self.iter_prefix(from, &[])
+	}
+
+	/// Produces an iterator of the `Readable` types moving forward from the
+	/// provided key, as long as their keys start with the provided prefix.
+	pub fn iter_prefix<T: ser::Readable>(&self, from: &[u8], prefix: &[u8]) -> SerIterator<T> {
 		let db = self.rdb.read().unwrap();
 		SerIterator {
 			iter: db.iterator(IteratorMode::From(from, Direction::Forward)),
+			prefix: prefix.to_vec(),
 			_marker: PhantomData,
 		}
 	}
@@ -187,6 +194,12 @@ impl<'a> Batch<'a> {
 		}
 	}
 
+	/// Deletes a key from the db as part of the batch.
+	pub fn delete(mut self, key: &[u8]) -> Result<Batch<'a>, Error> {
+		self.batch.delete(key)?;
+		Ok(self)
+	}
+
 	/// Writes the batch to RocksDb.
 	pub fn write(self) -> Result<(), Error> {
 		self.store.write(self.batch)
@@ -199,6 +212,7 @@ pub struct SerIterator<T>
 	where T: ser::Readable
 {
 	iter: DBIterator,
+	prefix: Vec<u8>,
 	_marker: PhantomData<T>,
 }
 
@@ -209,8 +223,12 @@ impl<T> Iterator for SerIterator<T>
 
 	fn next(&mut self) -> Option<T> {
 		let next = self.iter.next();
+		let prefix = &self.prefix;
 		next.and_then(|r| {
-			let (_, v) = r;
+			let (k, v) = r;
+			if !k.starts_with(prefix) {
+				return None;
+			}
 			ser::deserialize(&mut &v[..]).ok()
 		})
 	}
@@ -0,0 +1,59 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hex encoding and decoding of bytes, for what gets exchanged as text like
+//! the JSON of our HTTP APIs.
+
+use std::fmt::Write;
+
+/// Encodes the provided bytes as a lowercase hex string.
+pub fn to_hex(bytes: &[u8]) -> String {
+	let mut s = String::with_capacity(bytes.len() * 2);
+	for byte in bytes {
+		write!(&mut s, "{:02x}", byte).unwrap();
+	}
+	s
+}
+
+/// Decodes a hex string, with or without its 0x prefix, into bytes.
+pub fn from_hex(hex_str: &str) -> Result<Vec<u8>, String> {
+	let hex_str = hex_str.trim();
+	let hex_str = if hex_str.starts_with("0x") {
+		&hex_str[2..]
+	} else {
+		hex_str
+	};
+	let digits = hex_str.chars()
+		.map(|c| c.to_digit(16))
+		.collect::<Option<Vec<u32>>>()
+		.ok_or_else(|| format!("invalid hex string {}", hex_str))?;
+	if digits.len() % 2 != 0 {
+		return Err(format!("odd length hex string {}", hex_str));
+	}
+	Ok(digits.chunks(2).map(|d| (d[0] * 16 + d[1]) as u8).collect())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn hex_roundtrip() {
+		assert_eq!(to_hex(&[0, 10, 255]), "000aff");
+		assert_eq!(from_hex("000aff").unwrap(), vec![0, 10, 255]);
+		assert_eq!(from_hex("0x000AFF").unwrap(), vec![0, 10, 255]);
+		assert!(from_hex("0a0").is_err());
+		assert!(from_hex("zz").is_err());
+	}
+}
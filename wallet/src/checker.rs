@@ -0,0 +1,70 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks our outputs against the chain of the node.
+
+use api::{self, ApiError, OutputView};
+use types::{Error, OutputStatus, WalletConfig, WalletData};
+
+/// Asks the node whether the outputs we built made it to its chain yet,
+/// marking the ones it has as unspent, and whether the ones we locked for a
+/// transaction we couldn't tell the node took got spent, marking the ones it
+/// doesn't have anymore as spent.
+pub fn refresh_outputs(config: &WalletConfig) -> Result<(), Error> {
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		for out in wallet_data.outputs
+			.iter_mut()
+			.filter(|out| out.status == OutputStatus::Unconfirmed ||
+			              out.status == OutputStatus::Locked) {
+			let url = format!("{}/v1/chain/utxos/{}",
+			                  config.check_node_api_http_addr,
+			                  out.commit);
+			let unspent = match api::client::get::<OutputView>(&url) {
+				Ok(_) => true,
+				Err(ApiError::NotFound(_)) => false,
+				Err(e) => return Err(Error::Api(e)),
+			};
+			if let Some(status) = refreshed_status(&out.status, unspent) {
+				info!("Output {} of value {} now {:?}.", out.commit, out.value, status);
+				out.status = status;
+			}
+		}
+		Ok(())
+	})
+}
+
+// The new status of one of our outputs given whether the node has it
+// unspent, if it changed.
+fn refreshed_status(status: &OutputStatus, unspent: bool) -> Option<OutputStatus> {
+	match *status {
+		OutputStatus::Unconfirmed if unspent => Some(OutputStatus::Unspent),
+		OutputStatus::Locked if !unspent => Some(OutputStatus::Spent),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn statuses_refreshed() {
+		assert_eq!(refreshed_status(&OutputStatus::Unconfirmed, true),
+		           Some(OutputStatus::Unspent));
+		assert_eq!(refreshed_status(&OutputStatus::Unconfirmed, false), None);
+		assert_eq!(refreshed_status(&OutputStatus::Locked, true), None);
+		assert_eq!(refreshed_status(&OutputStatus::Locked, false), Some(OutputStatus::Spent));
+		assert_eq!(refreshed_status(&OutputStatus::Unspent, false), None);
+	}
+}
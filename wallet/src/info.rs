@@ -0,0 +1,41 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Summary of what the wallet holds.
+
+use checker;
+use types::{Error, OutputStatus, WalletConfig, WalletData};
+
+/// Prints our outputs and balance, after checking with the node which ones
+/// got confirmed.
+pub fn show_info(config: &WalletConfig) -> Result<(), Error> {
+	if let Err(e) = checker::refresh_outputs(config) {
+		warn!("Could not check our outputs with the node: {:?}", e);
+	}
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		println!("Outputs:");
+		for out in &wallet_data.outputs {
+			println!("  {} {:>16} {:?}", out.commit, out.value, out.status);
+		}
+		let unconfirmed = wallet_data.outputs
+			.iter()
+			.filter(|out| out.status == OutputStatus::Unconfirmed)
+			.map(|out| out.value)
+			.sum::<u64>();
+		println!("Balance: {} spendable, {} unconfirmed.",
+		         wallet_data.balance(),
+		         unconfirmed);
+		Ok(())
+	})
+}
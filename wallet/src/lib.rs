@@ -0,0 +1,49 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The wallet, keeping the outputs we own in a data file and building
+//! transactions with other wallets: the receiver listens for the partial
+//! transactions senders post and completes them with its output.
+
+#![deny(non_upper_case_globals)]
+#![deny(non_camel_case_types)]
+#![deny(non_snake_case)]
+#![deny(unused_mut)]
+#![warn(missing_docs)]
+
+#[macro_use]
+extern crate log;
+extern crate iron;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+extern crate grin_api as api;
+extern crate grin_core as core;
+extern crate grin_util as util;
+extern crate secp256k1zkp as secp;
+
+mod checker;
+mod info;
+mod receiver;
+mod sender;
+mod types;
+
+pub use checker::refresh_outputs;
+pub use info::show_info;
+pub use receiver::{start_receiver, WalletReceiver};
+pub use sender::issue_send_tx;
+pub use types::{Error, OutputData, OutputStatus, PartialTx, WalletConfig, WalletData};
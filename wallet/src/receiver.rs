@@ -0,0 +1,102 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The receiving end of transactions. As MimbleWimble transactions are built
+//! interactively, the receiver runs an HTTP listener senders post their
+//! partial transaction to. It adds its output, signs the transaction with
+//! the total blinding factor and returns it to the sender, which pushes it
+//! to the network.
+
+use std::io::Read;
+
+use iron::{Request, Response, IronResult, IronError, status};
+use iron::method::Method;
+use iron::middleware::Handler;
+use rand;
+use serde_json;
+
+use api::{self, ApiError, ApiServer, TxWrapper};
+use core::core::Transaction;
+use core::core::build;
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+use types::{self, Error, OutputData, OutputStatus, PartialTx, WalletConfig, WalletData};
+
+/// Completes the partial transactions posted at /v1/receive/transaction,
+/// recording the output it adds in the wallet.
+pub struct WalletReceiver {
+	/// Configuration of the wallet we receive to
+	pub config: WalletConfig,
+}
+
+impl Handler for WalletReceiver {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let partial: PartialTx = serde_json::from_reader(req.body.by_ref())
+			.map_err(|e| IronError::new(e, status::BadRequest))?;
+		match receive_transaction(&self.config, partial) {
+			Ok(tx) => api::json_response(&TxWrapper::new(&tx)),
+			Err(e) => {
+				warn!("Could not complete the transaction we got: {:?}", e);
+				let err = match e {
+					Error::Format(msg) => ApiError::Argument(msg),
+					Error::Secp(e) => ApiError::Argument(format!("invalid transaction: {:?}", e)),
+					e => ApiError::Internal(format!("{:?}", e)),
+				};
+				Err(err.into())
+			}
+		}
+	}
+}
+
+/// Runs the wallet receiver HTTP listener at the configured address. Blocks
+/// for as long as the listener runs.
+pub fn start_receiver(config: &WalletConfig) -> Result<(), Error> {
+	let mut apis = ApiServer::new("/v1".to_string());
+	apis.register_handler(Method::Post,
+	                      "/receive/transaction",
+	                      WalletReceiver { config: config.clone() });
+	info!("Starting the wallet receiver at {}.", config.api_http_addr);
+	apis.start(&config.api_http_addr[..])
+		.map_err(|e| Error::Api(ApiError::Internal(format!("receiver failed: {}", e))))
+}
+
+// Adds our output for the amount to the partial transaction and signs it
+// with the sum of the sender blinding factors and ours. The output only gets
+// recorded once the full transaction checks out.
+pub fn receive_transaction(config: &WalletConfig,
+                           partial: PartialTx)
+                           -> Result<Transaction, Error> {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let blind_sum = types::secret_key(&secp, &partial.blind_sum)?;
+	let wrapper = TxWrapper { tx_hex: partial.tx_hex };
+	let tx = wrapper.tx().map_err(|e| Error::Format(e.to_string()))?;
+	if partial.amount == 0 {
+		return Err(Error::Format("nothing to receive".to_string()));
+	}
+
+	let mut rng = rand::OsRng::new().unwrap();
+	let skey = SecretKey::new(&secp, &mut rng);
+	let (final_tx, _) = build::transaction(vec![build::initial_tx(tx),
+	                                            build::with_excess(blind_sum),
+	                                            build::output(partial.amount, skey)])?;
+	final_tx.validate(&secp)?;
+
+	let out = OutputData::new(&secp, partial.amount, skey, OutputStatus::Unconfirmed)?;
+	info!("Receiving output {} of value {}.", out.commit, out.value);
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.append_output(out);
+		Ok(())
+	})?;
+	Ok(final_tx)
+}
@@ -0,0 +1,266 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sending end of transactions: builds the partial transaction spending
+//! our outputs, has the receiver complete it and pushes the result to the
+//! node.
+
+use rand;
+
+use api::{self, TxWrapper};
+use checker;
+use core::core::{Input, Output, Transaction};
+use core::core::build;
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+use types::{Error, OutputData, OutputStatus, PartialTx, WalletConfig, WalletData};
+use util;
+
+/// Sends the amount to the wallet receiver listening at the provided URL,
+/// paying the fee. Our spent outputs and the change only get confirmed in
+/// the wallet once the node took the transaction. Should pushing it to the
+/// node fail, the node may have taken it all the same: the spent outputs
+/// stay locked and the change unconfirmed until refresh_outputs finds out.
+pub fn issue_send_tx(config: &WalletConfig,
+                     amount: u64,
+                     fee: u64,
+                     dest: String)
+                     -> Result<(), Error> {
+	if let Err(e) = checker::refresh_outputs(config) {
+		warn!("Could not check our outputs with the node: {:?}", e);
+	}
+
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let (partial, spent, change) =
+		WalletData::with_wallet(&config.data_file_dir,
+		                        |wallet_data| build_send_tx(&secp, wallet_data, amount, fee))?;
+
+	// nobody but the receiver saw the transaction yet, it's safe to forget
+	let completed = match complete(&dest, &partial) {
+		Ok(completed) => completed,
+		Err(e) => {
+			WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.set_status(&spent, OutputStatus::Unspent);
+				wallet_data.remove(&change);
+				Ok(())
+			})?;
+			return Err(e);
+		}
+	};
+
+	if let Err(e) = push(config, &completed) {
+		warn!("Could not push transaction of {} to {}, its outputs wait for the node to \
+		       confirm or not: {:?}",
+		      amount,
+		      dest,
+		      e);
+		return Err(e);
+	}
+	info!("Transaction of {} to {} pushed to the node.", amount, dest);
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.set_status(&spent, OutputStatus::Spent);
+		Ok(())
+	})
+}
+
+// Builds our side of the transaction with enough of our outputs and a change
+// output when there's change, locking the former and recording the latter.
+// Returns what to post to the receiver with the commitments of the spent and
+// change outputs.
+fn build_send_tx(secp: &Secp256k1,
+                 wallet_data: &mut WalletData,
+                 amount: u64,
+                 fee: u64)
+                 -> Result<(PartialTx, Vec<String>, Vec<String>), Error> {
+	let total_amount = amount.checked_add(fee)
+		.ok_or_else(|| Error::Format(format!("invalid amount {} and fee {}", amount, fee)))?;
+	let coins = wallet_data.select(total_amount)
+		.ok_or_else(|| Error::NotEnoughFunds(wallet_data.balance()))?;
+
+	let mut parts = vec![build::with_fee(fee)];
+	let mut total = 0;
+	for coin in &coins {
+		parts.push(build::input(coin.value, coin.key(secp)?));
+		total += coin.value;
+	}
+	let mut change = vec![];
+	if total > total_amount {
+		let mut rng = rand::OsRng::new().unwrap();
+		let change_key = SecretKey::new(secp, &mut rng);
+		parts.push(build::output(total - total_amount, change_key));
+		change.push(OutputData::new(secp,
+		                            total - total_amount,
+		                            change_key,
+		                            OutputStatus::Unconfirmed)?);
+	}
+	let (tx, blind_sum) = build::transaction(parts)?;
+
+	let spent = coins.iter().map(|coin| coin.commit.clone()).collect::<Vec<_>>();
+	let change_commits = change.iter().map(|out| out.commit.clone()).collect::<Vec<_>>();
+	wallet_data.set_status(&spent, OutputStatus::Locked);
+	for out in change {
+		wallet_data.append_output(out);
+	}
+
+	let partial = PartialTx {
+		amount: amount,
+		blind_sum: util::to_hex(&blind_sum[..]),
+		tx_hex: TxWrapper::new(&tx).tx_hex,
+	};
+	Ok((partial, spent, change_commits))
+}
+
+// Posts our partial transaction to the receiver, returning the completed
+// one it sends back.
+fn complete(dest: &str, partial: &PartialTx) -> Result<TxWrapper, Error> {
+	let url = format!("{}/v1/receive/transaction", dest.trim_right_matches('/'));
+	let wrapper: TxWrapper = api::client::post_json(&url, partial)?;
+	let tx = wrapper.tx()?;
+	check_completed(partial, &tx)?;
+	Ok(wrapper)
+}
+
+// Pushes a completed transaction to our node.
+fn push(config: &WalletConfig, wrapper: &TxWrapper) -> Result<(), Error> {
+	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr);
+	api::client::post(&url, wrapper)?;
+	Ok(())
+}
+
+// The receiver can only have added an output to what we sent, keeping our
+// inputs, outputs and fee.
+fn check_completed(partial: &PartialTx, tx: &Transaction) -> Result<(), Error> {
+	let ours = TxWrapper { tx_hex: partial.tx_hex.clone() }.tx()?;
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	tx.validate(&secp)?;
+
+	let has_input = |i: &Input| tx.inputs.iter().any(|j| i.0 == j.0);
+	let has_output = |o: &Output| tx.outputs.iter().any(|p| o.commit == p.commit);
+	let inputs_kept = tx.inputs.len() == ours.inputs.len() && ours.inputs.iter().all(has_input);
+	let outputs_kept = tx.outputs.len() == ours.outputs.len() + 1 &&
+	                   ours.outputs.iter().all(has_output);
+	if !inputs_kept || !outputs_kept || tx.fee != ours.fee {
+		return Err(Error::Format("receiver changed our transaction".to_string()));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use std::env;
+	use std::fs;
+	use std::thread;
+	use std::time::Duration;
+
+	use super::*;
+	use receiver;
+
+	// Both sides of a transaction, minus the HTTP in between.
+	#[test]
+	fn send_receive() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let mut rng = rand::OsRng::new().unwrap();
+		let coin = OutputData::new(&secp,
+		                           100,
+		                           SecretKey::new(&secp, &mut rng),
+		                           OutputStatus::Unspent)
+			.unwrap();
+		let mut sender = WalletData { outputs: vec![coin] };
+
+		match build_send_tx(&secp, &mut sender, 100, 5) {
+			Err(Error::NotEnoughFunds(100)) => {}
+			r => panic!("unexpected {:?}", r),
+		}
+		let (partial, spent, change) = build_send_tx(&secp, &mut sender, 60, 5).unwrap();
+		assert_eq!(spent.len(), 1);
+		assert_eq!(change.len(), 1);
+		assert_eq!(sender.balance(), 0);
+		assert_eq!(sender.outputs[0].status, OutputStatus::Locked);
+		assert_eq!(sender.outputs[1].value, 35);
+
+		let dir = env::temp_dir().join("grin_wallet_send_receive");
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let config = WalletConfig {
+			data_file_dir: dir.to_str().unwrap().to_string(),
+			..WalletConfig::default()
+		};
+		let tx = receiver::receive_transaction(&config, partial.clone()).unwrap();
+		check_completed(&partial, &tx).unwrap();
+		assert_eq!(tx.outputs.len(), 2);
+
+		let received = WalletData::with_wallet(&config.data_file_dir,
+		                                       |wallet_data| Ok(wallet_data.outputs.clone()))
+			.unwrap();
+		assert_eq!(received.len(), 1);
+		assert_eq!(received[0].value, 60);
+		assert_eq!(received[0].status, OutputStatus::Unconfirmed);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	fn wallet_config(name: &str, outputs: Vec<OutputData>) -> WalletConfig {
+		let dir = env::temp_dir().join(name);
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let config = WalletConfig {
+			data_file_dir: dir.to_str().unwrap().to_string(),
+			// nothing listening, pushing to the node fails
+			check_node_api_http_addr: "http://127.0.0.1:13815".to_string(),
+			..WalletConfig::default()
+		};
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.outputs = outputs;
+				Ok(())
+			})
+			.unwrap();
+		config
+	}
+
+	fn statuses(config: &WalletConfig) -> Vec<(u64, OutputStatus)> {
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+				Ok(wallet_data.outputs.iter().map(|out| (out.value, out.status.clone())).collect())
+			})
+			.unwrap()
+	}
+
+	#[test]
+	fn failed_sends() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let mut rng = rand::OsRng::new().unwrap();
+		let coin = OutputData::new(&secp,
+		                           100,
+		                           SecretKey::new(&secp, &mut rng),
+		                           OutputStatus::Unspent)
+			.unwrap();
+		let receiver = WalletConfig {
+			api_http_addr: "127.0.0.1:13814".to_string(),
+			..wallet_config("grin_wallet_failed_sends_receiver", vec![])
+		};
+		thread::spawn(move || {
+			let _ = receiver::start_receiver(&receiver);
+		});
+		thread::sleep(Duration::from_millis(500));
+
+		// the receiver unreachable, nobody saw the transaction and it's forgotten
+		let config = wallet_config("grin_wallet_failed_sends", vec![coin]);
+		assert!(issue_send_tx(&config, 60, 5, "http://127.0.0.1:13816".to_string()).is_err());
+		assert_eq!(statuses(&config), vec![(100, OutputStatus::Unspent)]);
+
+		// completed but not pushed, as far as we know the node could have it
+		assert!(issue_send_tx(&config, 60, 5, "http://127.0.0.1:13814".to_string()).is_err());
+		assert_eq!(statuses(&config),
+		           vec![(100, OutputStatus::Locked), (35, OutputStatus::Unconfirmed)]);
+		fs::remove_dir_all(&config.data_file_dir).unwrap();
+	}
+}
@@ -0,0 +1,332 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What the wallet keeps in its data file and exchanges with other wallets
+//! and with the node.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+
+use api;
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+use util;
+
+const DAT_FILE: &'static str = "wallet.dat";
+// Permissions of the data file, read and written by its owner only.
+#[cfg(unix)]
+const DAT_FILE_MODE: u32 = 0o600;
+const LOCK_FILE: &'static str = "wallet.lock";
+
+// How many times and how long apart we try taking the lock of the wallet
+// data another process holds.
+const LOCK_ATTEMPTS: u32 = 30;
+const LOCK_RETRY_MS: u64 = 100;
+
+/// Wallet errors, mostly wrappers around those of the underlying modules.
+#[derive(Debug)]
+pub enum Error {
+	/// Not enough unspent outputs to pay for the transaction, with the
+	/// total we have.
+	NotEnoughFunds(u64),
+	/// Error building or validating a transaction.
+	Secp(secp::Error),
+	/// The wallet data file couldn't be read or written.
+	WalletData(String),
+	/// A transaction, key or amount we got doesn't make sense.
+	Format(String),
+	/// The node or the other wallet failed our request.
+	Api(api::ApiError),
+}
+
+impl From<secp::Error> for Error {
+	fn from(e: secp::Error) -> Error {
+		Error::Secp(e)
+	}
+}
+
+impl From<api::ApiError> for Error {
+	fn from(e: api::ApiError) -> Error {
+		Error::Api(e)
+	}
+}
+
+/// Configuration of the wallet.
+#[derive(Debug, Clone)]
+pub struct WalletConfig {
+	/// Address the receiver listens at for the transactions of senders
+	pub api_http_addr: String,
+	/// Base URL of the API of the node we check our outputs with and push
+	/// our transactions to
+	pub check_node_api_http_addr: String,
+	/// Directory the wallet data file lives in. The file holds the keys to
+	/// our outputs unencrypted, readable and writable by its owner only.
+	pub data_file_dir: String,
+}
+
+impl Default for WalletConfig {
+	fn default() -> WalletConfig {
+		WalletConfig {
+			api_http_addr: "127.0.0.1:13417".to_string(),
+			check_node_api_http_addr: "http://127.0.0.1:13415".to_string(),
+			data_file_dir: ".".to_string(),
+		}
+	}
+}
+
+/// Lifecycle of an output we own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum OutputStatus {
+	/// Built but not seen on the chain yet.
+	Unconfirmed,
+	/// On the chain, ready to spend.
+	Unspent,
+	/// Spent by a transaction we're still building.
+	Locked,
+	/// Spent by a transaction we pushed.
+	Spent,
+}
+
+/// An output we own, with what it takes to spend it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputData {
+	/// Hex of the output commitment
+	pub commit: String,
+	/// Hex of the blinding factor, the key to spend the output. Kept in
+	/// plaintext in the data file, which only its owner can read.
+	pub blinding: String,
+	/// Value of the output
+	pub value: u64,
+	/// Where the output is at
+	pub status: OutputStatus,
+}
+
+impl OutputData {
+	/// Records the output of the provided value and blinding factor.
+	pub fn new(secp: &Secp256k1,
+	           value: u64,
+	           blinding: SecretKey,
+	           status: OutputStatus)
+	           -> Result<OutputData, Error> {
+		let commit = secp.commit(value, blinding)?;
+		Ok(OutputData {
+			commit: util::to_hex(commit.bytes()),
+			blinding: util::to_hex(&blinding[..]),
+			value: value,
+			status: status,
+		})
+	}
+
+	/// The blinding factor of the output.
+	pub fn key(&self, secp: &Secp256k1) -> Result<SecretKey, Error> {
+		secret_key(secp, &self.blinding)
+	}
+}
+
+/// What a sender posts to the receiver: its partial transaction, with its
+/// inputs, change and fee, and the sum of its blinding factors so the
+/// receiver can sign the transaction once it added its output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartialTx {
+	/// Value the receiver gets
+	pub amount: u64,
+	/// Hex of the sum of the sender blinding factors, its outputs minus its
+	/// inputs
+	pub blind_sum: String,
+	/// Hex of the binary serialization of the partial transaction
+	pub tx_hex: String,
+}
+
+/// The outputs we own, as kept in the data file of the wallet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletData {
+	/// All our outputs, spent ones included
+	pub outputs: Vec<OutputData>,
+}
+
+impl WalletData {
+	/// Runs the provided closure on the wallet data of the directory and
+	/// saves the data back when it succeeds. A lock file keeps other
+	/// processes using the same wallet away meanwhile.
+	pub fn with_wallet<T, F>(data_file_dir: &str, f: F) -> Result<T, Error>
+		where F: FnOnce(&mut WalletData) -> Result<T, Error>
+	{
+		let dir = Path::new(data_file_dir);
+		let lock_path = dir.join(LOCK_FILE);
+		acquire_lock(&lock_path)?;
+
+		let data_path = dir.join(DAT_FILE);
+		let res = WalletData::read_or_create(&data_path).and_then(|mut wdata| {
+			let res = f(&mut wdata)?;
+			wdata.write(&data_path)?;
+			Ok(res)
+		});
+
+		if let Err(e) = fs::remove_file(&lock_path) {
+			error!("Could not remove wallet lock file {:?}: {}", lock_path, e);
+		}
+		res
+	}
+
+	/// Adds an output to the wallet.
+	pub fn append_output(&mut self, out: OutputData) {
+		self.outputs.push(out);
+	}
+
+	/// Total value of our unspent outputs.
+	pub fn balance(&self) -> u64 {
+		self.outputs
+			.iter()
+			.filter(|out| out.status == OutputStatus::Unspent)
+			.map(|out| out.value)
+			.sum()
+	}
+
+	/// Picks unspent outputs adding up to at least the provided amount,
+	/// largest first to keep transactions small.
+	pub fn select(&self, amount: u64) -> Option<Vec<OutputData>> {
+		let mut unspent = self.outputs
+			.iter()
+			.filter(|out| out.status == OutputStatus::Unspent)
+			.cloned()
+			.collect::<Vec<_>>();
+		unspent.sort_by(|a, b| b.value.cmp(&a.value));
+
+		let mut selected = vec![];
+		let mut total = 0;
+		for out in unspent {
+			if total >= amount {
+				break;
+			}
+			total += out.value;
+			selected.push(out);
+		}
+		if total >= amount { Some(selected) } else { None }
+	}
+
+	/// Sets the status of the outputs with the provided commitments.
+	pub fn set_status(&mut self, commits: &[String], status: OutputStatus) {
+		for out in self.outputs.iter_mut().filter(|out| commits.contains(&out.commit)) {
+			out.status = status.clone();
+		}
+	}
+
+	/// Forgets the outputs with the provided commitments.
+	pub fn remove(&mut self, commits: &[String]) {
+		self.outputs.retain(|out| !commits.contains(&out.commit));
+	}
+
+	fn read_or_create(data_path: &Path) -> Result<WalletData, Error> {
+		if !data_path.exists() {
+			return Ok(WalletData { outputs: vec![] });
+		}
+		let file = File::open(data_path).map_err(|e| wallet_data_err(data_path, e))?;
+		serde_json::from_reader(file).map_err(|e| {
+			Error::WalletData(format!("Invalid wallet data in {:?}: {}", data_path, e))
+		})
+	}
+
+	// The data file holds the blinding factors of our outputs in plaintext,
+	// only its owner gets to read it, a file created before included.
+	fn write(&self, data_path: &Path) -> Result<(), Error> {
+		let mut file = owner_only(OpenOptions::new().write(true).create(true).truncate(true))
+			.open(data_path)
+			.map_err(|e| wallet_data_err(data_path, e))?;
+		restrict_permissions(&file).map_err(|e| wallet_data_err(data_path, e))?;
+		serde_json::to_writer_pretty(&mut file, self)
+			.map_err(|e| Error::WalletData(format!("Could not write {:?}: {}", data_path, e)))
+	}
+}
+
+/// Decodes a hex encoded secret key.
+pub fn secret_key(secp: &Secp256k1, hex: &str) -> Result<SecretKey, Error> {
+	let bin = util::from_hex(hex).map_err(Error::Format)?;
+	Ok(SecretKey::from_slice(secp, &bin[..])?)
+}
+
+// The lock file is created atomically, whoever manages to create it holds
+// the lock until removing it.
+fn acquire_lock(lock_path: &Path) -> Result<(), Error> {
+	for _ in 0..LOCK_ATTEMPTS {
+		match OpenOptions::new().write(true).create_new(true).open(lock_path) {
+			Ok(_) => return Ok(()),
+			Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+				thread::sleep(Duration::from_millis(LOCK_RETRY_MS));
+			}
+			Err(e) => return Err(wallet_data_err(lock_path, e)),
+		}
+	}
+	Err(Error::WalletData(format!("Wallet locked by another process, remove {:?} if it isn't",
+	                              lock_path)))
+}
+
+#[cfg(unix)]
+fn owner_only(options: &mut OpenOptions) -> &mut OpenOptions {
+	options.mode(DAT_FILE_MODE)
+}
+
+#[cfg(not(unix))]
+fn owner_only(options: &mut OpenOptions) -> &mut OpenOptions {
+	options
+}
+
+#[cfg(unix)]
+fn restrict_permissions(file: &File) -> io::Result<()> {
+	file.set_permissions(fs::Permissions::from_mode(DAT_FILE_MODE))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_file: &File) -> io::Result<()> {
+	Ok(())
+}
+
+fn wallet_data_err(path: &Path, e: io::Error) -> Error {
+	Error::WalletData(format!("Could not open {:?}: {}", path, e))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+	use std::env;
+	use std::io::Write;
+
+	use super::*;
+
+	#[test]
+	fn data_file_owner_only() {
+		let dir = env::temp_dir().join("grin_wallet_owner_only");
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let data_file_dir = dir.to_str().unwrap();
+		let path = dir.join(DAT_FILE);
+		let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+
+		WalletData::with_wallet(data_file_dir, |_| Ok(())).unwrap();
+		assert_eq!(mode(), 0o600);
+
+		// one left readable by anyone gets restricted on the next write
+		let mut file = File::create(&path).unwrap();
+		file.write_all(b"{\"outputs\": []}").unwrap();
+		file.set_permissions(fs::Permissions::from_mode(0o644)).unwrap();
+		WalletData::with_wallet(data_file_dir, |_| Ok(())).unwrap();
+		assert_eq!(mode(), 0o600);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}